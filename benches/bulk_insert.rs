@@ -0,0 +1,58 @@
+//! 100k-row inserts, one `insert_row` call per row against a single `insert_rows` batch.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rand::distributions::{Alphanumeric, DistString};
+use std::cell::Cell;
+use vddb::{Database, Value};
+
+const ROWS: usize = 100_000;
+
+fn rows() -> Vec<Vec<Value>> {
+    (0..ROWS)
+        .map(|id| vec![Value::Int32(id as i32), Value::String(format!("name{}", id % 1000)), Value::Int32((id % 100) as i32)])
+        .collect()
+}
+
+fn bulk_insert(c: &mut Criterion) {
+    let data_dir = format!("bench_data_{}", Alphanumeric.sample_string(&mut rand::thread_rng(), 8));
+    let db = Database::open(&data_dir).unwrap();
+    let storage = db.storage();
+    // Every iteration fills a table of its own
+    let tables = Cell::new(0);
+    let new_table = || {
+        tables.set(tables.get() + 1);
+        let table = format!("T{}", tables.get());
+        db.execute(&format!("CREATE TABLE {} (ID INT, Name TEXT, Score INT)", table)).unwrap();
+        (table, rows())
+    };
+
+    let mut group = c.benchmark_group("bulk_insert");
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.sample_size(10);
+    group.bench_function("insert_row", |b| {
+        b.iter_batched(
+            new_table,
+            |(table, rows)| {
+                let storage = storage.read().unwrap();
+                for row in rows {
+                    storage.insert_row(&table, &[], row).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("insert_rows", |b| {
+        b.iter_batched(
+            new_table,
+            |(table, rows)| storage.write().unwrap().insert_rows(&table, &[], rows).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+
+    drop((storage, db));
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+criterion_group!(benches, bulk_insert);
+criterion_main!(benches);
@@ -0,0 +1,119 @@
+//! Throughput of SELECTs spread over several tables, run from one thread and from one thread
+//! per table, and of SELECTs and INSERTs on one table while another thread works on a
+//! different one. Each thread has its own `QueryEngine`, as server connections do, so the
+//! only thing they share is the storage.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::distributions::{Alphanumeric, DistString};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use vddb::{Database, Durability, QueryEngine, SharedStorage};
+
+const TABLES: usize = 4;
+const ROWS_PER_TABLE: usize = 20_000;
+const QUERIES_PER_ITERATION: usize = 16;
+
+fn setup(data_dir: &str) -> Database {
+    let db = Database::open(data_dir).unwrap();
+    let csv = format!("{}/rows.csv", data_dir);
+    let mut file = std::fs::File::create(&csv).unwrap();
+    writeln!(file, "ID,Name,Score").unwrap();
+    for id in 0..ROWS_PER_TABLE {
+        writeln!(file, "{},name{},{}", id, id, id % 1000).unwrap();
+    }
+    drop(file);
+    for table in 0..TABLES {
+        db.execute(&format!("CREATE TABLE T{} (ID INT, Name TEXT, Score INT)", table)).unwrap();
+        db.execute(&format!("COPY T{} FROM '{}'", table, csv)).unwrap();
+    }
+    db.execute("CREATE TABLE Log (Seq INT, Entry TEXT)").unwrap();
+    db
+}
+
+// Runs QUERIES_PER_ITERATION selects split evenly over `threads` threads, thread i reading table i % TABLES
+fn run_selects(storage: &SharedStorage, threads: usize) {
+    std::thread::scope(|scope| {
+        for thread in 0..threads {
+            let storage = Arc::clone(storage);
+            scope.spawn(move || {
+                let mut engine = QueryEngine::new(storage);
+                let sql = format!("SELECT ID, Name FROM T{} WHERE Score > 500", thread % TABLES);
+                for _ in 0..QUERIES_PER_ITERATION / threads {
+                    let query = engine.prepare(&sql).unwrap();
+                    assert!(!engine.execute_query(query).unwrap().rows.is_empty());
+                }
+            });
+        }
+    });
+}
+
+const SELECT: &str = "SELECT ID, Name FROM T0 WHERE Score > 500";
+const INSERT: &str = "INSERT INTO Log VALUES (1, 'entry')";
+
+// Times `count` runs of `timed` while another thread, if `beside` is given, keeps running that
+// statement on its own engine
+fn time_beside(storage: &SharedStorage, timed: &str, beside: Option<&str>, count: u64) -> Duration {
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        if let Some(sql) = beside {
+            let mut engine = QueryEngine::new(Arc::clone(storage));
+            let stop = &stop;
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let query = engine.prepare(sql).unwrap();
+                    engine.execute_query(query).unwrap();
+                }
+            });
+        }
+        let mut engine = QueryEngine::new(Arc::clone(storage));
+        let start = Instant::now();
+        for _ in 0..count {
+            let query = engine.prepare(timed).unwrap();
+            engine.execute_query(query).unwrap();
+        }
+        let elapsed = start.elapsed();
+        stop.store(true, Ordering::Relaxed);
+        elapsed
+    })
+}
+
+fn concurrent_reads(c: &mut Criterion) {
+    let data_dir = format!("bench_data_{}", Alphanumeric.sample_string(&mut rand::thread_rng(), 8));
+    let db = setup(&data_dir);
+    let storage = db.storage();
+
+    let mut group = c.benchmark_group("concurrent_reads");
+    group.throughput(Throughput::Elements(QUERIES_PER_ITERATION as u64));
+    group.sample_size(10);
+    for threads in [1, TABLES] {
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &threads| {
+            b.iter(|| run_selects(&storage, threads))
+        });
+    }
+    group.finish();
+
+    // Selects on T0 and single-row inserts into Log, alone and with the other running beside
+    // them. Each insert waits for its WAL write to be fsync'd. Neither touches the other's
+    // table, so with a lock per table neither should wait for the other.
+    storage.write().unwrap().set_durability(Durability::Full).unwrap();
+    let mut group = c.benchmark_group("other_table_busy");
+    group.throughput(Throughput::Elements(1));
+    group.sample_size(10);
+    for (name, timed, beside) in [
+        ("select", SELECT, None),
+        ("select_beside_inserts", SELECT, Some(INSERT)),
+        ("insert", INSERT, None),
+        ("insert_beside_selects", INSERT, Some(SELECT)),
+    ] {
+        group.bench_function(name, |b| b.iter_custom(|count| time_beside(&storage, timed, beside, count)));
+    }
+    group.finish();
+
+    drop((storage, db));
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+criterion_group!(benches, concurrent_reads);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+//! SELECTs filtered serially and in parallel over tables of a few row counts around
+//! `DEFAULT_PARALLEL_THRESHOLD`, to show where handing a batch to rayon starts to pay. SELECT
+//! filters at most one scan batch (1024 rows) at a time, so larger tables only repeat it.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::distributions::{Alphanumeric, DistString};
+use vddb::query::planner::DEFAULT_PARALLEL_THRESHOLD;
+use vddb::{Database, QueryEngine, Value};
+
+const ROW_COUNTS: [usize; 5] = [
+    DEFAULT_PARALLEL_THRESHOLD / 8,
+    DEFAULT_PARALLEL_THRESHOLD / 4,
+    DEFAULT_PARALLEL_THRESHOLD / 2,
+    DEFAULT_PARALLEL_THRESHOLD,
+    DEFAULT_PARALLEL_THRESHOLD * 8,
+];
+
+fn setup(data_dir: &str) -> Database {
+    let db = Database::open(data_dir).unwrap();
+    for rows in ROW_COUNTS {
+        db.execute(&format!("CREATE TABLE T{} (ID INT, Label TEXT)", rows)).unwrap();
+        let values = (0..rows as i32).map(|id| vec![Value::Int32(id), Value::String(format!("label{}", id % 7))]).collect();
+        let storage = db.storage();
+        let mut storage = storage.write().unwrap();
+        storage.insert_rows(&format!("T{}", rows), &[], values).unwrap();
+        storage.checkpoint().unwrap();
+    }
+    db
+}
+
+fn parallel_threshold(c: &mut Criterion) {
+    let data_dir = format!("bench_data_{}", Alphanumeric.sample_string(&mut rand::thread_rng(), 8));
+    let db = setup(&data_dir);
+    let mut engine = QueryEngine::new(db.storage());
+
+    let mut group = c.benchmark_group("parallel_threshold");
+    for rows in ROW_COUNTS {
+        group.throughput(Throughput::Elements(rows as u64));
+        // An OR across columns cannot be pushed down, so every row goes through the filter loop
+        let sql = format!("SELECT ID, CAST(ID AS TEXT) FROM T{} WHERE ID < 10 OR Label = 'label3'", rows);
+        for (path, threshold) in [("serial", usize::MAX), ("parallel", 0)] {
+            engine.set_parallel_threshold(threshold);
+            group.bench_with_input(BenchmarkId::new(path, rows), &sql, |b, sql| {
+                b.iter(|| {
+                    let query = engine.prepare(sql).unwrap();
+                    engine.execute_query(query).unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+
+    drop((engine, db));
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+criterion_group!(benches, parallel_threshold);
+criterion_main!(benches);
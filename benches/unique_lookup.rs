@@ -0,0 +1,65 @@
+//! Point lookups on a UNIQUE column, answered by its implicit index, against the same lookups
+//! on an unindexed column, which scan it. Queries go straight to a `QueryEngine`, leaving out
+//! the audit log write `Database::execute` adds to every statement.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rand::distributions::{Alphanumeric, DistString};
+use std::cell::Cell;
+use vddb::{Database, QueryEngine, Value};
+
+const ROWS: i32 = 100_000;
+const BLOCK_ROWS: usize = 1000;
+
+fn setup(data_dir: &str) -> Database {
+    let db = Database::open(data_dir).unwrap();
+    db.execute("CREATE TABLE Accounts (ID INT PRIMARY KEY, Email TEXT UNIQUE, Handle TEXT)").unwrap();
+    // Scrambled so block min/max cannot narrow the scan of the unindexed column
+    let rows: Vec<Vec<Value>> = (0..ROWS)
+        .map(|id| {
+            let key = (id * 7919) % ROWS;
+            vec![Value::Int32(id), Value::String(format!("user{}@example.com", key)), Value::String(format!("user{}", key))]
+        })
+        .collect();
+    let storage = db.storage();
+    let mut storage = storage.write().unwrap();
+    // Flushed a block at a time, as rows inserted one by one would be
+    for block in rows.chunks(BLOCK_ROWS) {
+        storage.insert_rows("Accounts", &[], block.to_vec()).unwrap();
+        storage.checkpoint().unwrap();
+    }
+    drop(storage);
+    db
+}
+
+fn unique_lookup(c: &mut Criterion) {
+    let data_dir = format!("bench_data_{}", Alphanumeric.sample_string(&mut rand::thread_rng(), 8));
+    let db = setup(&data_dir);
+    let mut engine = QueryEngine::new(db.storage());
+    // Each iteration looks up another key, so no two in a row hit the same rows
+    let next_key = Cell::new(0);
+    let key = || {
+        next_key.set((next_key.get() + 13) % ROWS);
+        next_key.get()
+    };
+
+    let mut group = c.benchmark_group("unique_lookup");
+    group.bench_function("unique_index", |b| {
+        b.iter(|| {
+            let query = engine.prepare(&format!("SELECT ID FROM Accounts WHERE Email = 'user{}@example.com'", key())).unwrap();
+            assert_eq!(engine.execute_query(query).unwrap().rows.len(), 1);
+        })
+    });
+    group.bench_function("column_scan", |b| {
+        b.iter(|| {
+            let query = engine.prepare(&format!("SELECT ID FROM Accounts WHERE Handle = 'user{}'", key())).unwrap();
+            assert_eq!(engine.execute_query(query).unwrap().rows.len(), 1);
+        })
+    });
+    group.finish();
+
+    drop((engine, db));
+    let _ = std::fs::remove_dir_all(&data_dir);
+}
+
+criterion_group!(benches, unique_lookup);
+criterion_main!(benches);
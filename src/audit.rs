@@ -0,0 +1,106 @@
+use crate::query::{Query, QueryResult};
+use crate::types::{DbError, Value};
+use chrono::Local;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const AUDIT_FILE: &str = "audit.log";
+
+/// Append-only record of statements that change data, tables, users or permissions, kept
+/// apart from the general log so lowering the log level cannot silence it.
+///
+/// Each line is a JSON object with `timestamp` (RFC 3339), `user` (null when nobody is logged
+/// in), `operation`, `tables`, `statement`, `rows` (rows affected, or returned for reads; null
+/// when unknown) and, for statements that failed, `error`. Passwords are masked in `statement`.
+#[derive(Debug)]
+pub struct AuditLog {
+    // Statements are recorded under a shared storage lock, so appends take this one
+    file: Mutex<File>,
+    path: PathBuf,
+    audit_reads: bool,
+}
+
+impl AuditLog {
+    pub fn open(data_dir: &str) -> Result<Self, DbError> {
+        let path = Path::new(data_dir).join(AUDIT_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            path,
+            audit_reads: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn audits_reads(&self) -> bool {
+        self.audit_reads
+    }
+
+    /// Also records SELECTs when `audit_reads` is set. Off by default.
+    pub fn set_audit_reads(&mut self, audit_reads: bool) {
+        self.audit_reads = audit_reads;
+    }
+
+    /// Appends a record for `query` if it is audited: always for statements that change
+    /// something, and for reads only when `audits_reads` is on. Transaction control is never recorded.
+    pub fn record(
+        &self,
+        user: Option<&str>,
+        statement: &str,
+        query: &Query,
+        outcome: Result<&QueryResult, &DbError>,
+    ) -> Result<(), DbError> {
+        let operation = match query.operation() {
+            Some(operation) if !query.is_read_only() || self.audit_reads => operation,
+            _ => return Ok(()),
+        };
+        let mut entry = serde_json::json!({
+            "timestamp": Local::now().to_rfc3339(),
+            "user": user,
+            "operation": operation,
+            "tables": query.tables(),
+            "statement": mask_password(statement, query),
+            "rows": outcome.ok().and_then(|result| affected_rows(query, result)),
+        });
+        if let Err(e) = outcome {
+            entry["error"] = serde_json::Value::String(e.to_string());
+        }
+        let mut file = self.file.lock().map_err(|e| DbError::AuditError(e.to_string()))?;
+        writeln!(file, "{}", entry)
+            .and_then(|_| file.flush())
+            .map_err(|e| DbError::AuditError(format!("Cannot write {}: {}", self.path.display(), e)))
+    }
+}
+
+fn affected_rows(query: &Query, result: &QueryResult) -> Option<usize> {
+    match query {
+        // An upsert reports its inserted and updated counts; a DO NOTHING skip affects no rows
+        Query::Insert { on_conflict: Some(_), .. } => {
+            result.rows.first().map(|row| row.iter().map(|v| if let Value::Int32(n) = v { *n as usize } else { 0 }).sum())
+        }
+        Query::Insert { rows, .. } => Some(rows.len()),
+        Query::Delete { .. } => match result.rows.first().and_then(|row| row.first()) {
+            Some(Value::Int32(count)) => Some(*count as usize),
+            _ => None,
+        },
+        _ if query.is_read_only() => Some(result.rows.len()),
+        _ => None,
+    }
+}
+
+fn mask_password(statement: &str, query: &Query) -> String {
+    match query {
+        Query::CreateUser { .. } | Query::Login { .. } => {
+            match statement.to_ascii_uppercase().find("PASSWORD") {
+                Some(pos) => format!("{}PASSWORD '***'", &statement[..pos]),
+                None => statement.to_string(),
+            }
+        }
+        _ => statement.to_string(),
+    }
+}
\ No newline at end of file
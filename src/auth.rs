@@ -0,0 +1,184 @@
+use crate::query::Privilege;
+use crate::types::{DbError, User};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const USERS_FILE: &str = "users.json";
+pub const GRANTS_FILE: &str = "grants.json";
+/// Role allowed to run every statement. The first user created gets it.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Accounts that can log in and the permissions granted to roles, persisted as JSON in the
+/// data directory.
+#[derive(Debug)]
+pub struct UserStore {
+    path: PathBuf,
+    grants_path: PathBuf,
+    users: BTreeMap<String, User>,
+    // Permission strings held by each role, in the shape `SecurityContext::permissions` expects
+    permissions: HashMap<String, Vec<String>>,
+}
+
+impl UserStore {
+    pub fn open(data_dir: &str) -> Result<Self, DbError> {
+        let path = Path::new(data_dir).join(USERS_FILE);
+        let grants_path = Path::new(data_dir).join(GRANTS_FILE);
+        Ok(UserStore {
+            users: read_json(&path)?,
+            permissions: read_json(&grants_path)?,
+            path,
+            grants_path,
+        })
+    }
+
+    pub fn permissions(&self) -> &HashMap<String, Vec<String>> {
+        &self.permissions
+    }
+
+    pub fn grant(&mut self, privilege: &Privilege) -> Result<(), DbError> {
+        match privilege {
+            Privilege::Permission { permission, role } => {
+                let held = self.permissions.entry(role.clone()).or_default();
+                if !held.contains(permission) {
+                    held.push(permission.clone());
+                }
+                write_json(&self.grants_path, &self.permissions)
+            }
+            Privilege::Role { role, username } => {
+                let user = self.user_mut(username)?;
+                if !user.roles.contains(role) {
+                    user.roles.push(role.clone());
+                }
+                write_json(&self.path, &self.users)
+            }
+        }
+    }
+
+    pub fn revoke(&mut self, privilege: &Privilege) -> Result<(), DbError> {
+        match privilege {
+            Privilege::Permission { permission, role } => {
+                if let Some(held) = self.permissions.get_mut(role) {
+                    held.retain(|p| p != permission);
+                    if held.is_empty() {
+                        self.permissions.remove(role);
+                    }
+                }
+                write_json(&self.grants_path, &self.permissions)
+            }
+            Privilege::Role { role, username } => {
+                let admins = self.users.values().filter(|u| u.roles.iter().any(|r| r == ADMIN_ROLE)).count();
+                let user = self.user_mut(username)?;
+                if role == ADMIN_ROLE && admins == 1 && user.roles.contains(role) {
+                    return Err(DbError::ValidationError(format!("Cannot revoke {} from the last admin", ADMIN_ROLE)));
+                }
+                user.roles.retain(|r| r != role);
+                write_json(&self.path, &self.users)
+            }
+        }
+    }
+
+    /// Points permissions granted on `table` at its new name.
+    pub fn rename_table(&mut self, table: &str, new_name: &str) -> Result<(), DbError> {
+        let suffix = format!(":{}", table);
+        let mut renamed = false;
+        for permission in self.permissions.values_mut().flatten() {
+            if let Some(operation) = permission.strip_suffix(&suffix) {
+                *permission = format!("{}:{}", operation, new_name);
+                renamed = true;
+            }
+        }
+        if renamed {
+            write_json(&self.grants_path, &self.permissions)?;
+        }
+        Ok(())
+    }
+
+    fn user_mut(&mut self, username: &str) -> Result<&mut User, DbError> {
+        self.users
+            .get_mut(username)
+            .ok_or_else(|| DbError::ValidationError(format!("User {} not found", username)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn get(&self, username: &str) -> Option<&User> {
+        self.users.get(username)
+    }
+
+    /// Stores a new user with an argon2 hash of `password`.
+    pub fn create_user(&mut self, username: &str, password: &str) -> Result<&User, DbError> {
+        if username.is_empty() || !username.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(DbError::ValidationError(format!("Invalid user name '{}'", username)));
+        }
+        if password.is_empty() {
+            return Err(DbError::ValidationError("Password cannot be empty".to_string()));
+        }
+        if self.users.contains_key(username) {
+            return Err(DbError::ValidationError(format!("User {} already exists", username)));
+        }
+        let roles = if self.users.is_empty() { vec![ADMIN_ROLE.to_string()] } else { Vec::new() };
+        let user = User {
+            username: username.to_string(),
+            password_hash: hash_password(password)?,
+            roles,
+        };
+        self.users.insert(username.to_string(), user);
+        if let Err(e) = write_json(&self.path, &self.users) {
+            self.users.remove(username);
+            return Err(e);
+        }
+        Ok(&self.users[username])
+    }
+
+    /// Returns the user if `password` is theirs. Unknown users and wrong passwords fail with
+    /// the same error, and both pay for a hash, so a failed login does not reveal which it was.
+    pub fn authenticate(&self, username: &str, password: &str) -> Result<User, DbError> {
+        let failed = || DbError::AuthenticationError("Invalid user name or password".to_string());
+        match self.users.get(username) {
+            Some(user) if verify_password(password, &user.password_hash) => Ok(user.clone()),
+            Some(_) => Err(failed()),
+            None => {
+                hash_password(password)?;
+                Err(failed())
+            }
+        }
+    }
+}
+
+fn read_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T, DbError> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let json = fs::read_to_string(path)?;
+    serde_json::from_str(&json).map_err(|e| DbError::SerializationError(format!("Invalid {}: {}", path.display(), e)))
+}
+
+// Written to a temporary file first so a crash never leaves a half-written file
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), DbError> {
+    let json = serde_json::to_string_pretty(value).map_err(|e| DbError::SerializationError(e.to_string()))?;
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, json)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn hash_password(password: &str) -> Result<String, DbError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| DbError::AuthenticationError(format!("Cannot hash password: {}", e)))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+        .unwrap_or(false)
+}
\ No newline at end of file
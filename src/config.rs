@@ -0,0 +1,189 @@
+use crate::logging::Logger;
+use crate::metrics::init_metrics;
+use crate::storage::cache::DEFAULT_COLUMN_CACHE_CAPACITY;
+use crate::storage::encryption::Passphrase;
+use crate::storage::wal::Durability;
+use crate::transaction::IsolationLevel;
+use crate::types::DbError;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Configuration file looked for in the data directory.
+pub const CONFIG_FILE: &str = "vddb.toml";
+pub const DEFAULT_DATA_DIR: &str = "./data";
+
+/// Settings read at startup. Each comes from the configuration file if it sets it, else from
+/// its environment variable, else from its default:
+///
+/// | key               | variable              | default                                  |
+/// |-------------------|-----------------------|------------------------------------------|
+/// | `data_dir`        | `VDDB_DATA_DIR`       | `./data`                                 |
+/// | `log_level`       | `VDDB_LOG_LEVEL`      | `RUST_LOG`, or `info` when logging to a file |
+/// | `log_path`        | `VDDB_LOG_PATH`       | none: log to stderr                      |
+/// | `metrics_addr`    | `VDDB_METRICS_ADDR`   | none: no metrics endpoint                |
+/// | `threads`         | `VDDB_THREADS`        | one per CPU                              |
+/// | `cache_capacity`  | `VDDB_CACHE_CAPACITY` | `DEFAULT_COLUMN_CACHE_CAPACITY` columns  |
+/// | `isolation_level` | `VDDB_ISOLATION_LEVEL`| `read_committed`                         |
+/// | `durability`      | `VDDB_DURABILITY`     | `normal`; see `Durability`               |
+/// | `encryption_passphrase` | `VDDB_ENCRYPTION_PASSPHRASE` | none: ENCRYPTED columns cannot be read or written |
+///
+/// The encryption passphrase is better given in the environment than in the file, which
+/// would hold it in plaintext beside the data it protects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub data_dir: String,
+    pub log_level: Option<LevelFilter>,
+    pub log_path: Option<PathBuf>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub threads: Option<usize>,
+    pub cache_capacity: usize,
+    pub isolation_level: IsolationLevel,
+    pub durability: Durability,
+    pub encryption_passphrase: Option<Passphrase>,
+}
+
+// The file as written; unknown keys are errors so that a misspelled setting is not ignored
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    data_dir: Option<String>,
+    log_level: Option<String>,
+    log_path: Option<String>,
+    metrics_addr: Option<String>,
+    threads: Option<usize>,
+    cache_capacity: Option<usize>,
+    isolation_level: Option<String>,
+    durability: Option<String>,
+    encryption_passphrase: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: DEFAULT_DATA_DIR.to_string(),
+            log_level: None,
+            log_path: None,
+            metrics_addr: None,
+            threads: None,
+            cache_capacity: DEFAULT_COLUMN_CACHE_CAPACITY,
+            isolation_level: IsolationLevel::default(),
+            durability: Durability::default(),
+            encryption_passphrase: None,
+        }
+    }
+}
+
+fn config_error(message: String) -> DbError {
+    DbError::ConfigurationError(message)
+}
+
+// `value` from the file, else environment variable `var`, parsed; None when neither sets it
+fn setting<T: FromStr>(key: &str, value: Option<String>, var: &str) -> Result<Option<T>, DbError> {
+    let (value, source) = match value {
+        Some(value) => (value, key),
+        None => match std::env::var(var) {
+            Ok(value) => (value, var),
+            Err(_) => return Ok(None),
+        },
+    };
+    value
+        .trim()
+        .parse::<T>()
+        .map(Some)
+        .map_err(|_| config_error(format!("Invalid {} '{}'", source, value)))
+}
+
+fn parse_isolation_level(text: &str) -> Result<IsolationLevel, DbError> {
+    match text.trim().to_ascii_lowercase().replace(' ', "_").as_str() {
+        "read_committed" => Ok(IsolationLevel::ReadCommitted),
+        "serializable" => Ok(IsolationLevel::Serializable),
+        _ => Err(config_error(format!(
+            "Invalid isolation_level '{}': expected read_committed or serializable",
+            text
+        ))),
+    }
+}
+
+impl Config {
+    /// Reads the configuration file at `path`, which must exist.
+    pub fn load(path: &Path) -> Result<Config, DbError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| config_error(format!("Cannot read {}: {}", path.display(), e)))?;
+        Config::parse(&text, path)
+    }
+
+    /// The configuration of the database in `data_dir`: its `vddb.toml` if it has one, with
+    /// environment variables and defaults filling the rest. `data_dir` itself always wins.
+    pub fn for_data_dir(data_dir: &str) -> Result<Config, DbError> {
+        let path = Path::new(data_dir).join(CONFIG_FILE);
+        let mut config = if path.exists() { Config::load(&path)? } else { Config::parse("", &path)? };
+        config.data_dir = data_dir.to_string();
+        Ok(config)
+    }
+
+    fn parse(text: &str, path: &Path) -> Result<Config, DbError> {
+        let file: ConfigFile =
+            toml::from_str(text).map_err(|e| config_error(format!("Malformed {}: {}", path.display(), e)))?;
+        let defaults = Config::default();
+        let number = |key: &str, value: Option<usize>, var: &str| setting::<usize>(key, value.map(|v| v.to_string()), var);
+        let isolation_level = match setting::<String>("isolation_level", file.isolation_level, "VDDB_ISOLATION_LEVEL")? {
+            Some(level) => parse_isolation_level(&level)?,
+            None => defaults.isolation_level,
+        };
+        let durability = match setting::<String>("durability", file.durability, "VDDB_DURABILITY")? {
+            Some(level) => level.parse()?,
+            None => defaults.durability,
+        };
+        let threads = number("threads", file.threads, "VDDB_THREADS")?;
+        if threads == Some(0) {
+            return Err(config_error("threads must be at least 1".to_string()));
+        }
+        Ok(Config {
+            data_dir: setting("data_dir", file.data_dir, "VDDB_DATA_DIR")?.unwrap_or(defaults.data_dir),
+            log_level: setting("log_level", file.log_level, "VDDB_LOG_LEVEL")?,
+            log_path: setting("log_path", file.log_path, "VDDB_LOG_PATH")?,
+            metrics_addr: setting("metrics_addr", file.metrics_addr, "VDDB_METRICS_ADDR")?,
+            threads,
+            cache_capacity: number("cache_capacity", file.cache_capacity, "VDDB_CACHE_CAPACITY")?
+                .unwrap_or(defaults.cache_capacity),
+            isolation_level,
+            durability,
+            encryption_passphrase: setting(
+                "encryption_passphrase",
+                file.encryption_passphrase,
+                "VDDB_ENCRYPTION_PASSPHRASE",
+            )?,
+        })
+    }
+
+    /// Applies the settings that belong to the process rather than to one database: logging,
+    /// the size of the worker thread pool and the metrics endpoint. Call once, before opening
+    /// a database.
+    pub fn init_runtime(&self) -> Result<(), DbError> {
+        match (&self.log_path, self.log_level) {
+            (Some(path), level) => {
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    fs::create_dir_all(dir)?;
+                }
+                Logger::init(path, level.unwrap_or(LevelFilter::Info))
+                    .map_err(|e| config_error(format!("Cannot log to {}: {}", path.display(), e)))?;
+            }
+            (None, Some(level)) => env_logger::Builder::new().filter_level(level).init(),
+            (None, None) => env_logger::init(),
+        }
+        if let Some(threads) = self.threads {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build_global()
+                .map_err(|e| config_error(format!("Cannot start {} worker threads: {}", threads, e)))?;
+        }
+        if let Some(addr) = self.metrics_addr {
+            init_metrics(addr).map_err(|e| config_error(format!("Cannot serve metrics on {}: {}", addr, e)))?;
+        }
+        Ok(())
+    }
+}
\ No newline at end of file
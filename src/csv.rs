@@ -0,0 +1,131 @@
+use crate::types::{DataType, DbError, Value};
+use ordered_float::OrderedFloat;
+use std::io::Write;
+
+// Formats a value as a CSV field per RFC 4180: NULL becomes an empty field, and fields
+// containing commas, quotes or line breaks are quoted with embedded quotes doubled.
+pub fn format_field(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::Int32(i) => i.to_string(),
+        Value::Int64(i) => i.to_string(),
+        Value::Float32(f) => f.0.to_string(),
+        Value::Timestamp(ms) => Value::format_timestamp(*ms),
+        // Quote empty strings so they read back as strings rather than NULL
+        Value::String(s) if s.is_empty() => return "\"\"".to_string(),
+        Value::String(s) => s.clone(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+pub fn write_record<W: Write>(writer: &mut W, values: &[Value]) -> Result<(), DbError> {
+    let fields: Vec<String> = values.iter().map(format_field).collect();
+    writer.write_all(fields.join(",").as_bytes())?;
+    writer.write_all(b"\r\n")?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct CsvRecord {
+    // 1-based line on which the record starts
+    pub line: usize,
+    // Unquoted empty fields are None so they can be loaded as NULL
+    pub fields: Vec<Option<String>>,
+}
+
+pub fn parse_records(input: &str) -> Result<Vec<CsvRecord>, DbError> {
+    let mut records = Vec::new();
+    let mut chars = input.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start_line = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        loop {
+            match chars.next() {
+                Some('"') if field.is_empty() && !quoted => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') if chars.peek() == Some(&'"') => {
+                                chars.next();
+                                field.push('"');
+                            }
+                            Some('"') => break,
+                            Some(c) => {
+                                if c == '\n' {
+                                    line += 1;
+                                }
+                                field.push(c);
+                            }
+                            None => {
+                                return Err(DbError::InvalidData(format!(
+                                    "line {}: unterminated quoted field",
+                                    start_line
+                                )))
+                            }
+                        }
+                    }
+                }
+                Some(',') => {
+                    fields.push(finish_field(&mut field, &mut quoted));
+                }
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') | None => {
+                    line += 1;
+                    fields.push(finish_field(&mut field, &mut quoted));
+                    break;
+                }
+                Some(c) => field.push(c),
+            }
+        }
+        // Skip blank lines
+        if fields.len() > 1 || fields[0].is_some() {
+            records.push(CsvRecord { line: start_line, fields });
+        }
+    }
+    Ok(records)
+}
+
+fn finish_field(field: &mut String, quoted: &mut bool) -> Option<String> {
+    let value = std::mem::take(field);
+    let was_quoted = std::mem::replace(quoted, false);
+    if value.is_empty() && !was_quoted {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+pub fn parse_field(field: Option<&str>, data_type: &DataType) -> Result<Value, String> {
+    let text = match field {
+        Some(text) => text,
+        None => return Ok(Value::Null),
+    };
+    match data_type {
+        DataType::Int32 => text
+            .trim()
+            .parse::<i32>()
+            .map(Value::Int32)
+            .map_err(|_| format!("cannot parse '{}' as Int32", text)),
+        DataType::Int64 => text
+            .trim()
+            .parse::<i64>()
+            .map(Value::Int64)
+            .map_err(|_| format!("cannot parse '{}' as Int64", text)),
+        DataType::Float32 => text
+            .trim()
+            .parse::<f32>()
+            .map(|f| Value::Float32(OrderedFloat(f)))
+            .map_err(|_| format!("cannot parse '{}' as Float32", text)),
+        DataType::String => Ok(Value::String(text.to_string())),
+        DataType::Timestamp => Value::parse_timestamp(text)
+            .map_err(|_| format!("cannot parse '{}' as Timestamp", text)),
+        DataType::Null => Ok(Value::Null),
+    }
+}
\ No newline at end of file
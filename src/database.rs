@@ -0,0 +1,180 @@
+use crate::config::Config;
+use crate::plugins::PluginManager;
+use crate::query::parser::bind_params;
+use crate::query::QueryResult;
+use crate::schema::Schema;
+use crate::storage::feed::ChangeRecord;
+use crate::storage::SharedStorage;
+use crate::transaction::TransactionManager;
+use crate::types::{DbError, SecurityContext, Value};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+/// An embedded database handle for running SQL from Rust code without the REPL.
+///
+/// `Database` is `Send + Sync` and can be shared between threads behind an `Arc`. Every
+/// statement runs as its own transaction unless `BEGIN` has opened one: callers take the
+/// transaction manager lock for the duration of a statement, and the storage lock is taken
+/// inside it, so concurrent statements are serialized rather than interleaved. An open
+/// transaction belongs to the handle, not to the thread that began it. The storage lock
+/// itself is a read-write lock: handles and server connections sharing one `StorageManager`
+/// can read at the same time, while writes wait for exclusive access.
+pub struct Database {
+    storage: SharedStorage,
+    tx_manager: Mutex<TransactionManager>,
+    plugin_manager: Mutex<PluginManager>,
+}
+
+impl Database {
+    /// Opens (or creates) the database stored in `data_dir`, recovering from the WAL if needed.
+    /// Settings come from the directory's `vddb.toml` if it has one; see `Config`.
+    pub fn open(data_dir: &str) -> Result<Self, DbError> {
+        Self::open_with_config(&Config::for_data_dir(data_dir)?)
+    }
+
+    /// Opens (or creates) the database in `config.data_dir` with the settings of `config`.
+    pub fn open_with_config(config: &Config) -> Result<Self, DbError> {
+        let (_schema, storage, tx_manager, plugin_manager) = crate::create_database(config)?;
+        Ok(Database {
+            storage,
+            tx_manager: Mutex::new(tx_manager),
+            plugin_manager: Mutex::new(plugin_manager),
+        })
+    }
+
+    /// Parses (or finds in the plan cache) and runs a single SQL statement, calling the plugins' query hooks around it.
+    /// A failing `before_query` hook stops the statement; an `after_query` failure is only logged.
+    pub fn execute(&self, sql: &str) -> Result<QueryResult, DbError> {
+        let query = self.tx_manager.lock().map_err(|_| lock_poisoned("transaction manager"))?.prepare(sql)?;
+        self.plugin_manager.lock().map_err(|_| lock_poisoned("plugin manager"))?.before_query(sql)?;
+        let result = {
+            let mut tx_manager = self.tx_manager.lock().map_err(|_| lock_poisoned("transaction manager"))?;
+            let mut tx = tx_manager.begin_transaction();
+            tx.add_query(query.clone());
+            let outcome = tx_manager.commit_transaction_results(tx).map(|mut results| results.pop().unwrap_or_default());
+            // Failed statements are audited too, with their error
+            let user = tx_manager.security_context().and_then(|s| s.current_user.as_ref()).map(|u| u.username.clone());
+            self.storage
+                .read()
+                .map_err(|_| lock_poisoned("storage"))?
+                .audit_log()
+                .record(user.as_deref(), sql, &query, outcome.as_ref())?;
+            outcome?
+        };
+        let plugins = self.plugin_manager.lock().map_err(|_| lock_poisoned("plugin manager"))?;
+        if let Err(e) = plugins.after_query(sql, result.rows.len()) {
+            log::error!("{}", e);
+        }
+        Ok(result)
+    }
+
+    /// Runs a statement with `?` placeholders bound, in order, to `params`. Prefer this to
+    /// formatting values into the SQL text yourself.
+    pub fn execute_params(&self, sql: &str, params: &[Value]) -> Result<QueryResult, DbError> {
+        self.execute(&bind_params(sql, params)?)
+    }
+
+    /// Runs a statement and returns its rows.
+    pub fn query(&self, sql: &str) -> Result<Vec<Vec<Value>>, DbError> {
+        self.execute(sql).map(|result| result.rows)
+    }
+
+    /// Applies the migrations in `dir` not yet applied; see `migrate::run_migrations`. Returns
+    /// the names of the files applied, and cannot start while a transaction is open.
+    pub fn migrate(&self, dir: &str) -> Result<Vec<String>, DbError> {
+        if self.tx_manager.lock().map_err(|_| lock_poisoned("transaction manager"))?.in_transaction() {
+            return Err(DbError::MigrationError("Cannot migrate while a transaction is open".to_string()));
+        }
+        let outcome = crate::migrate::run_migrations(&self.storage, Path::new(dir), |sql| self.execute(sql));
+        // A failed migration may have left its own BEGIN open; its files are already restored
+        if outcome.is_err() && self.tx_manager.lock().map_err(|_| lock_poisoned("transaction manager"))?.in_transaction() {
+            self.execute("ROLLBACK")?;
+        }
+        outcome
+    }
+
+    /// A snapshot of the current schema.
+    pub fn schema(&self) -> Result<Schema, DbError> {
+        let storage = self.storage.read().map_err(|_| lock_poisoned("storage"))?;
+        Ok(storage.current_schema())
+    }
+
+    /// Checks every later statement against `security`; `None` (the default) trusts the caller.
+    pub fn set_security_context(&self, security: Option<SecurityContext>) -> Result<(), DbError> {
+        self.tx_manager
+            .lock()
+            .map_err(|_| lock_poisoned("transaction manager"))?
+            .set_security_context(security);
+        Ok(())
+    }
+
+    /// Sets the batch size from which SELECT filters rows in parallel; smaller batches run on
+    /// the calling thread. Defaults to `DEFAULT_PARALLEL_THRESHOLD`.
+    pub fn set_parallel_threshold(&self, threshold: usize) -> Result<(), DbError> {
+        self.tx_manager
+            .lock()
+            .map_err(|_| lock_poisoned("transaction manager"))?
+            .set_parallel_threshold(threshold);
+        Ok(())
+    }
+
+    /// Makes a SELECT or JOIN that would return more than `max_rows` rows fail with
+    /// `ResourceExhausted` instead; `None` removes the cap. Defaults to `VDDB_MAX_RESULT_ROWS`.
+    pub fn set_max_result_rows(&self, max_rows: Option<usize>) -> Result<(), DbError> {
+        self.tx_manager
+            .lock()
+            .map_err(|_| lock_poisoned("transaction manager"))?
+            .set_max_result_rows(max_rows);
+        Ok(())
+    }
+
+    /// Makes a SELECT or JOIN still running after `timeout` stop and fail with `TimeoutError`;
+    /// `None` (unless `VDDB_STATEMENT_TIMEOUT` is set) lets statements run as long as they take.
+    pub fn set_statement_timeout(&self, timeout: Option<std::time::Duration>) -> Result<(), DbError> {
+        self.tx_manager
+            .lock()
+            .map_err(|_| lock_poisoned("transaction manager"))?
+            .set_statement_timeout(timeout);
+        Ok(())
+    }
+
+    /// A flag that stops the statement running on this handle when set from another thread,
+    /// failing it with `Cancelled`; see `QueryEngine::cancel_handle`. Fetch it before running
+    /// the statement, since the transaction manager stays locked while it runs.
+    pub fn cancel_handle(&self) -> Result<Arc<AtomicBool>, DbError> {
+        Ok(self.tx_manager.lock().map_err(|_| lock_poisoned("transaction manager"))?.cancel_handle())
+    }
+
+    /// Receives every committed insert and delete from now on; see `StorageManager::subscribe_changes`.
+    pub fn subscribe_changes(
+        &self,
+        capacity: usize,
+    ) -> Result<crossbeam::channel::Receiver<Result<ChangeRecord, DbError>>, DbError> {
+        Ok(self.storage.write().map_err(|_| lock_poisoned("storage"))?.subscribe_changes(capacity))
+    }
+
+    /// Flushes everything to disk for a clean exit: rolls back a transaction left open, then
+    /// checkpoints and syncs the storage (see `StorageManager::shutdown`) and flushes the log.
+    pub fn shutdown(&self) -> Result<(), DbError> {
+        if self.tx_manager.lock().map_err(|_| lock_poisoned("transaction manager"))?.in_transaction() {
+            log::warn!("Rolling back the transaction still open at shutdown");
+            self.execute("ROLLBACK")?;
+        }
+        self.storage.write().map_err(|_| lock_poisoned("storage"))?.shutdown()?;
+        log::logger().flush();
+        Ok(())
+    }
+
+    pub fn storage(&self) -> SharedStorage {
+        Arc::clone(&self.storage)
+    }
+
+    pub fn plugin_manager(&self) -> &Mutex<PluginManager> {
+        &self.plugin_manager
+    }
+}
+
+fn lock_poisoned(what: &str) -> DbError {
+    DbError::ConcurrencyError(format!("{} lock poisoned by a panicked thread", what))
+}
\ No newline at end of file
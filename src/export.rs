@@ -0,0 +1,88 @@
+use crate::schema::Table;
+use crate::types::{DataType, DbError, Value};
+use parquet::basic::{LogicalType, Repetition, TimeUnit, Type as PhysicalType};
+use parquet::data_type::{ByteArray, ByteArrayType, FloatType, Int32Type, Int64Type};
+use parquet::format::MilliSeconds;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use std::fs::File;
+use std::sync::Arc;
+
+fn parquet_error(e: parquet::errors::ParquetError) -> DbError {
+    DbError::SerializationError(e.to_string())
+}
+
+/// Writes `columns` (one `Vec` per column of `table`, in table order) as a Parquet file with a
+/// single row group, column by column.
+///
+/// Types map as INT -> INT32, BIGINT -> INT64, FLOAT -> FLOAT, TEXT -> BYTE_ARRAY annotated as a UTF-8
+/// string and TIMESTAMP -> INT64 annotated as UTC milliseconds. Every column is OPTIONAL, with NULLs written as Parquet nulls. Columns of the
+/// internal NULL type have no Parquet equivalent and fail with `SerializationError`.
+pub fn write_parquet(path: &str, table: &Table, columns: &[Vec<Value>]) -> Result<(), DbError> {
+    let mut fields = Vec::with_capacity(table.columns.len());
+    for col in &table.columns {
+        let builder = match col.data_type {
+            DataType::Int32 => Type::primitive_type_builder(&col.name, PhysicalType::INT32),
+            DataType::Int64 => Type::primitive_type_builder(&col.name, PhysicalType::INT64),
+            DataType::Float32 => Type::primitive_type_builder(&col.name, PhysicalType::FLOAT),
+            DataType::String => Type::primitive_type_builder(&col.name, PhysicalType::BYTE_ARRAY)
+                .with_logical_type(Some(LogicalType::String)),
+            DataType::Timestamp => Type::primitive_type_builder(&col.name, PhysicalType::INT64).with_logical_type(Some(
+                LogicalType::Timestamp { is_adjusted_to_u_t_c: true, unit: TimeUnit::MILLIS(MilliSeconds {}) },
+            )),
+            DataType::Null => {
+                return Err(DbError::SerializationError(format!(
+                    "Column {}.{} has type NULL, which Parquet export does not support",
+                    table.name, col.name
+                )))
+            }
+        };
+        fields.push(Arc::new(builder.with_repetition(Repetition::OPTIONAL).build().map_err(parquet_error)?));
+    }
+    let schema = Type::group_type_builder(&table.name).with_fields(fields).build().map_err(parquet_error)?;
+
+    let file = File::create(path)?;
+    let mut writer = SerializedFileWriter::new(file, Arc::new(schema), Arc::new(WriterProperties::builder().build()))
+        .map_err(parquet_error)?;
+    let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+    for (col, values) in table.columns.iter().zip(columns) {
+        let mut column_writer = row_group
+            .next_column()
+            .map_err(parquet_error)?
+            .ok_or_else(|| DbError::SerializationError(format!("No Parquet column for {}", col.name)))?;
+        // Definition level 1 marks a present value, 0 a null
+        let def_levels: Vec<i16> = values.iter().map(|v| if v.is_null() { 0 } else { 1 }).collect();
+        let written = match col.data_type {
+            DataType::Int32 => {
+                let present: Vec<i32> = values.iter().filter_map(|v| match v { Value::Int32(i) => Some(*i), _ => None }).collect();
+                column_writer.typed::<Int32Type>().write_batch(&present, Some(&def_levels), None)
+            }
+            DataType::Int64 => {
+                let present: Vec<i64> = values.iter().filter_map(|v| match v { Value::Int64(i) => Some(*i), _ => None }).collect();
+                column_writer.typed::<Int64Type>().write_batch(&present, Some(&def_levels), None)
+            }
+            DataType::Float32 => {
+                let present: Vec<f32> = values.iter().filter_map(|v| match v { Value::Float32(f) => Some(f.0), _ => None }).collect();
+                column_writer.typed::<FloatType>().write_batch(&present, Some(&def_levels), None)
+            }
+            DataType::Timestamp => {
+                let present: Vec<i64> = values.iter().filter_map(|v| match v { Value::Timestamp(ms) => Some(*ms), _ => None }).collect();
+                column_writer.typed::<Int64Type>().write_batch(&present, Some(&def_levels), None)
+            }
+            DataType::String => {
+                let present: Vec<ByteArray> = values
+                    .iter()
+                    .filter_map(|v| match v { Value::String(s) => Some(ByteArray::from(s.as_str())), _ => None })
+                    .collect();
+                column_writer.typed::<ByteArrayType>().write_batch(&present, Some(&def_levels), None)
+            }
+            DataType::Null => unreachable!("rejected while building the schema"),
+        };
+        written.map_err(parquet_error)?;
+        column_writer.close().map_err(parquet_error)?;
+    }
+    row_group.close().map_err(parquet_error)?;
+    writer.close().map_err(parquet_error)?;
+    Ok(())
+}
\ No newline at end of file
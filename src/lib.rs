@@ -1,27 +1,51 @@
+pub mod audit;
+pub mod auth;
+pub mod config;
+pub mod csv;
+pub mod database;
+pub mod export;
 pub mod query;
 pub mod repl;
 pub mod schema;
+pub mod server;
 pub mod storage;
 pub mod transaction;
 pub mod types;
+pub mod logging;
+pub mod migrate;
 pub mod metrics;
 pub mod plugins;
 
+pub use config::Config;
 pub use query::{Aggregation, Condition, Query, planner::QueryEngine};
-pub use repl::Repl;
+pub use database::Database;
+pub use repl::{QueryResult, Repl};
 pub use schema::{Column, Schema, Table};
-use std::sync::{Arc, Mutex};
-pub use storage::StorageManager;
-pub use transaction::{Transaction, TransactionManager};
-pub use types::{CompressionType, DataType, DbError, Value, SecurityContext, User};
+pub use server::{serve, Server};
+use std::sync::{Arc, RwLock};
+pub use storage::{wal::Durability, SharedStorage, StorageManager};
+pub use transaction::{IsolationLevel, Transaction, TransactionManager};
+pub use types::{CompressionType, DataType, DbError, ErrorCategory, Value, SecurityContext, User};
 pub use metrics::{QueryMetrics, init_metrics};
-pub use plugins::{Plugin, PluginManager};
+pub use plugins::{Plugin, PluginManager, StatsPlugin};
 
-pub fn create_database(name: &str) -> Result<(Schema, Arc<Mutex<StorageManager>>, TransactionManager, PluginManager), DbError> {
-    let schema = Schema::new_schema(name)?;
-    let storage_manager = Arc::new(Mutex::new(StorageManager::new(name, schema.clone())?));
-    let transaction_manager = TransactionManager::new(Arc::clone(&storage_manager))?;
-    let plugin_manager = PluginManager::new();
+/// Opens (or creates) the database in `config.data_dir` with the per-database settings of `config`.
+pub fn create_database(config: &Config) -> Result<(Schema, SharedStorage, TransactionManager, PluginManager), DbError> {
+    let name = config.data_dir.as_str();
+    let schema = Schema::load(name)?;
+    let mut storage = StorageManager::new(name, schema)?;
+    storage.set_cache_capacity(config.cache_capacity);
+    storage.set_durability(config.durability)?;
+    if let Some(passphrase) = &config.encryption_passphrase {
+        storage.set_encryption_passphrase(passphrase)?;
+    }
+    storage.recover()?;
+    let schema = storage.schema().clone();
+    let storage_manager = Arc::new(RwLock::new(storage));
+    let mut transaction_manager = TransactionManager::new(Arc::clone(&storage_manager))?;
+    transaction_manager.set_isolation_level(config.isolation_level);
+    let mut plugin_manager = PluginManager::open(name);
+    plugin_manager.register_plugin(Box::new(StatsPlugin::new(Arc::clone(&storage_manager))))?;
 
     Ok((schema, storage_manager, transaction_manager, plugin_manager))
 }
@@ -32,29 +56,131 @@ mod tests {
     use ordered_float::OrderedFloat;
     use rand::distributions::{Alphanumeric, DistString};
     use std::fs;
+    use std::sync::Mutex;
+
+    // Counts the bytes each thread allocates, so a test can compare what two reads cost
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocated_bytes() -> usize {
+        ALLOCATED.with(|allocated| allocated.get())
+    }
+
+    // A fresh data directory that is removed when the test ends, even if it panics
+    struct TestDir(String);
+
+    impl TestDir {
+        fn new(test_name: &str) -> Self {
+            TestDir(format!("test_data_{}_{}", test_name, Alphanumeric.sample_string(&mut rand::thread_rng(), 8)))
+        }
+    }
+
+    impl std::ops::Deref for TestDir {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl AsRef<std::path::Path> for TestDir {
+        fn as_ref(&self) -> &std::path::Path {
+            self.0.as_ref()
+        }
+    }
+
+    impl std::fmt::Display for TestDir {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A database in its own TestDir; the database is dropped before the directory is removed
+    struct TestDb {
+        db: Option<Database>,
+        dir: TestDir,
+    }
+
+    impl TestDb {
+        fn open(dir: TestDir) -> Self {
+            let db = Database::open(&dir).unwrap();
+            TestDb { db: Some(db), dir }
+        }
+
+        fn dir(&self) -> &str {
+            &self.dir
+        }
+
+        // Drops the database without shutting it down, like a crash would
+        fn close(&mut self) {
+            self.db = None;
+        }
+
+        fn reopen(&mut self) {
+            self.close();
+            self.db = Some(Database::open(&self.dir).unwrap());
+        }
+    }
+
+    impl std::ops::Deref for TestDb {
+        type Target = Database;
+
+        fn deref(&self) -> &Database {
+            self.db.as_ref().expect("test database is closed")
+        }
+    }
+
+    fn test_db(test_name: &str) -> TestDb {
+        TestDb::open(TestDir::new(test_name))
+    }
 
     fn setup_test_db(
         test_name: &str,
     ) -> Result<
         (
-            String,
+            TestDir,
             Schema,
-            Arc<Mutex<StorageManager>>,
+            SharedStorage,
             TransactionManager,
             PluginManager,
         ),
         DbError,
     > {
-        let random_suffix = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
-        let data_dir = format!("test_data_{}_{}", test_name, random_suffix);
-        let (schema, storage, tx_manager, plugin_manager) = create_database(&data_dir)?;
+        let data_dir = TestDir::new(test_name);
+        let (schema, storage, tx_manager, plugin_manager) = create_database(&Config::for_data_dir(&data_dir)?)?;
         Ok((data_dir, schema, storage, tx_manager, plugin_manager))
     }
 
-    fn cleanup_test_db(data_dir: &String) {
-        if fs::metadata(data_dir).is_ok() {
-            fs::remove_dir_all(data_dir).unwrap();
+    // Runs `statements` in one transaction and returns the rows they selected
+    fn run_sql(tx_manager: &mut TransactionManager, statements: &[&str]) -> Result<Vec<Vec<Value>>, DbError> {
+        let mut tx = tx_manager.begin_transaction();
+        for sql in statements {
+            tx.add_query(query::parser::parse_query(sql)?);
         }
+        tx_manager.commit_transaction(tx)
     }
 
     #[test]
@@ -62,25 +188,78 @@ mod tests {
         let (data_dir, _schema, _storage, _tx_manager, _plugin_manager) = setup_test_db("wal_creation").unwrap();
         assert!(fs::metadata(format!("{}/wal", data_dir)).is_ok());
         assert!(fs::metadata(format!("{}/wal/wal.log", data_dir)).is_ok());
-        cleanup_test_db(&data_dir);
+    }
+
+    #[test]
+    fn test_wal_recovery_after_torn_flush() {
+        let (data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("wal_recovery").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::CreateTable {
+            table: "Test".to_string(),
+            columns: vec![
+                Column::new("ID", DataType::Int32),
+                Column::new("Value", DataType::String),
+            ],
+        });
+        for (id, value) in [(1, "a"), (2, "b"), (3, "c")] {
+            tx.add_query(Query::Insert {
+                table: "Test".to_string(),
+                columns: vec![],
+                rows: vec![vec![Value::Int32(id), Value::String(value.to_string())]],
+                on_conflict: None,
+            });
+        }
+        tx_manager.commit_transaction(tx).unwrap();
+
+        // Simulate a crash after the ID column was flushed but before the Value column was
+        {
+            let storage_guard = storage.write().unwrap();
+            let mut table = storage_guard.write_table("Test").unwrap();
+            let id_store = table.columns.get_mut("ID").unwrap();
+            id_store
+                .append(&[Value::Int32(1), Value::Int32(2), Value::Int32(3)], CompressionType::Rle)
+                .unwrap();
+        }
+        drop(tx_manager);
+        drop(storage);
+
+        let (_schema, storage, mut tx_manager, _plugin_manager) = create_database(&Config::for_data_dir(&data_dir).unwrap()).unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::Select {
+            table: Some("Test".to_string()),
+            columns: vec!["ID".to_string(), "Value".to_string()],
+            condition: None,
+            order_by: Vec::new(),
+        });
+        let results = tx_manager.commit_transaction(tx).unwrap();
+        assert_eq!(
+            results,
+            vec![
+                vec![Value::Int32(1), Value::String("a".to_string())],
+                vec![Value::Int32(2), Value::String("b".to_string())],
+                vec![Value::Int32(3), Value::String("c".to_string())],
+            ]
+        );
+        assert_eq!(storage.read().unwrap().schema().get_table("Test").unwrap().row_count, 3);
+        assert_eq!(fs::metadata(format!("{}/wal/wal.log", data_dir)).unwrap().len(), 0);
     }
 
     #[test]
     fn test_create_table() {
-        let (data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("create_table").unwrap();
+        let (_data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("create_table").unwrap();
         let query = Query::CreateTable {
             table: "Employees".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Name".to_string(), DataType::String),
-                ("Salary".to_string(), DataType::Float32),
+                Column::new("ID", DataType::Int32),
+                Column::new("Name", DataType::String),
+                Column::new("Salary", DataType::Float32),
             ],
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(query);
         tx_manager.commit_transaction(tx).unwrap();
 
-        let storage_guard = storage.lock().unwrap();
+        let storage_guard = storage.write().unwrap();
         let schema = storage_guard.schema();
         assert!(schema.get_table("Employees").is_some());
         let table = schema.get_table("Employees").unwrap();
@@ -91,19 +270,17 @@ mod tests {
         assert_eq!(table.columns[1].data_type, DataType::String);
         assert_eq!(table.columns[2].name, "Salary");
         assert_eq!(table.columns[2].data_type, DataType::Float32);
-
-        cleanup_test_db(&data_dir);
     }
 
     #[test]
     fn test_insert_and_select() {
-        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("insert_select").unwrap();
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("insert_select").unwrap();
         let create_query = Query::CreateTable {
             table: "Employees".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Name".to_string(), DataType::String),
-                ("Salary".to_string(), DataType::Float32),
+                Column::new("ID", DataType::Int32),
+                Column::new("Name", DataType::String),
+                Column::new("Salary", DataType::Float32),
             ],
         };
         let mut tx = tx_manager.begin_transaction();
@@ -112,20 +289,23 @@ mod tests {
 
         let insert_query = Query::Insert {
             table: "Employees".to_string(),
-            values: vec![
+            columns: vec![],
+            rows: vec![vec![
                 Value::Int32(1),
                 Value::String("Alice".to_string()),
                 Value::Float32(OrderedFloat(1000.0)),
-            ],
+            ]],
+            on_conflict: None,
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(insert_query);
         tx_manager.commit_transaction(tx).unwrap();
 
         let select_query = Query::Select {
-            table: "Employees".to_string(),
+            table: Some("Employees".to_string()),
             columns: vec!["Name".to_string(), "Salary".to_string()],
             condition: Some(Condition::Equal("ID".to_string(), Value::Int32(1))),
+            order_by: Vec::new(),
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(select_query);
@@ -139,18 +319,16 @@ mod tests {
                 Value::Float32(OrderedFloat(1000.0))
             ]
         );
-
-        cleanup_test_db(&data_dir);
     }
 
     #[test]
     fn test_aggregation() {
-        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("aggregation").unwrap();
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("aggregation").unwrap();
         let create_query = Query::CreateTable {
             table: "Sales".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Amount".to_string(), DataType::Float32),
+                Column::new("ID", DataType::Int32),
+                Column::new("Amount", DataType::Float32),
             ],
         };
         let mut tx = tx_manager.begin_transaction();
@@ -160,15 +338,21 @@ mod tests {
         let inserts = vec![
             Query::Insert {
                 table: "Sales".to_string(),
-                values: vec![Value::Int32(1), Value::Float32(OrderedFloat(100.0))],
+                columns: vec![],
+                rows: vec![vec![Value::Int32(1), Value::Float32(OrderedFloat(100.0))]],
+                on_conflict: None,
             },
             Query::Insert {
                 table: "Sales".to_string(),
-                values: vec![Value::Int32(2), Value::Float32(OrderedFloat(200.0))],
+                columns: vec![],
+                rows: vec![vec![Value::Int32(2), Value::Float32(OrderedFloat(200.0))]],
+                on_conflict: None,
             },
             Query::Insert {
                 table: "Sales".to_string(),
-                values: vec![Value::Int32(3), Value::Float32(OrderedFloat(300.0))],
+                columns: vec![],
+                rows: vec![vec![Value::Int32(3), Value::Float32(OrderedFloat(300.0))]],
+                on_conflict: None,
             },
         ];
         let mut tx = tx_manager.begin_transaction();
@@ -187,6 +371,7 @@ mod tests {
                 Aggregation::Max("Amount".to_string()),
             ],
             condition: None,
+            group_by: Vec::new(),
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(agg_query);
@@ -203,25 +388,99 @@ mod tests {
                 Value::Float32(OrderedFloat(300.0)),
             ]
         );
+    }
+
+    #[test]
+    fn test_cast_expression() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("cast").unwrap();
+        let create_query = Query::CreateTable {
+            table: "Employees".to_string(),
+            columns: vec![
+                Column::new("ID", DataType::Int32),
+                Column::new("Name", DataType::String),
+                Column::new("Salary", DataType::Float32),
+            ],
+        };
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(create_query);
+        tx.add_query(Query::Insert {
+            table: "Employees".to_string(),
+            columns: vec![],
+            rows: vec![vec![
+                Value::Int32(7),
+                Value::String("42".to_string()),
+                Value::Float32(OrderedFloat(1500.75)),
+            ]],
+            on_conflict: None,
+        });
+        tx_manager.commit_transaction(tx).unwrap();
+
+        let select_query = Query::Select {
+            table: Some("Employees".to_string()),
+            columns: vec![
+                "CAST(ID AS TEXT)".to_string(),
+                "CAST(Name AS INT)".to_string(),
+                "CAST(Salary AS INT)".to_string(),
+                "CAST(ID AS FLOAT)".to_string(),
+                "CAST('2.5' AS FLOAT)".to_string(),
+            ],
+            condition: None,
+            order_by: Vec::new(),
+        };
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(select_query);
+        let results = tx_manager.commit_transaction(tx).unwrap();
+        assert_eq!(
+            results,
+            vec![vec![
+                Value::String("7".to_string()),
+                Value::Int32(42),
+                Value::Int32(1500),
+                Value::Float32(OrderedFloat(7.0)),
+                Value::Float32(OrderedFloat(2.5)),
+            ]]
+        );
 
-        cleanup_test_db(&data_dir);
+        // Strings that are not numbers cannot be cast
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::Insert {
+            table: "Employees".to_string(),
+            columns: vec![],
+            rows: vec![vec![
+                Value::Int32(8),
+                Value::String("Bob".to_string()),
+                Value::Float32(OrderedFloat(900.0)),
+            ]],
+            on_conflict: None,
+        });
+        tx.add_query(Query::Select {
+            table: Some("Employees".to_string()),
+            columns: vec!["CAST(Name AS INT)".to_string()],
+            condition: Some(Condition::Equal("ID".to_string(), Value::Int32(8))),
+            order_by: Vec::new(),
+        });
+        let result = tx_manager.commit_transaction(tx);
+        assert!(matches!(
+            result,
+            Err(DbError::InvalidData(ref s)) if s.contains("Cannot cast 'Bob' to Int32")
+        ));
     }
 
     #[test]
     fn test_join() {
-        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("join").unwrap();
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("join").unwrap();
         let create_employees = Query::CreateTable {
             table: "Employees".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Name".to_string(), DataType::String),
+                Column::new("ID", DataType::Int32),
+                Column::new("Name", DataType::String),
             ],
         };
         let create_departments = Query::CreateTable {
             table: "Departments".to_string(),
             columns: vec![
-                ("DeptID".to_string(), DataType::Int32),
-                ("DeptName".to_string(), DataType::String),
+                Column::new("DeptID", DataType::Int32),
+                Column::new("DeptName", DataType::String),
             ],
         };
         let mut tx = tx_manager.begin_transaction();
@@ -232,21 +491,29 @@ mod tests {
         let insert_employees = vec![
             Query::Insert {
                 table: "Employees".to_string(),
-                values: vec![Value::Int32(1), Value::String("Alice".to_string())],
+                columns: vec![],
+                rows: vec![vec![Value::Int32(1), Value::String("Alice".to_string())]],
+                on_conflict: None,
             },
             Query::Insert {
                 table: "Employees".to_string(),
-                values: vec![Value::Int32(2), Value::String("Bob".to_string())],
+                columns: vec![],
+                rows: vec![vec![Value::Int32(2), Value::String("Bob".to_string())]],
+                on_conflict: None,
             },
         ];
         let insert_departments = vec![
             Query::Insert {
                 table: "Departments".to_string(),
-                values: vec![Value::Int32(1), Value::String("HR".to_string())],
+                columns: vec![],
+                rows: vec![vec![Value::Int32(1), Value::String("HR".to_string())]],
+                on_conflict: None,
             },
             Query::Insert {
                 table: "Departments".to_string(),
-                values: vec![Value::Int32(2), Value::String("IT".to_string())],
+                columns: vec![],
+                rows: vec![vec![Value::Int32(2), Value::String("IT".to_string())]],
+                on_conflict: None,
             },
         ];
         let mut tx = tx_manager.begin_transaction();
@@ -287,94 +554,108 @@ mod tests {
                 ],
             ]
         );
-
-        cleanup_test_db(&data_dir);
     }
 
     #[test]
-    fn test_transaction_commit_rollback() {
-        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) =
-            setup_test_db("tx_commit_rollback").unwrap();
+    fn test_transaction_commit() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("tx_commit").unwrap();
         let create_query = Query::CreateTable {
             table: "Test".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Value".to_string(), DataType::String),
+                Column::new("ID", DataType::Int32),
+                Column::new("Value", DataType::String),
             ],
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(create_query);
         tx_manager.commit_transaction(tx).unwrap();
 
-        // Test commit
         let insert_query = Query::Insert {
             table: "Test".to_string(),
-            values: vec![Value::Int32(1), Value::String("Committed".to_string())],
+            columns: vec![],
+            rows: vec![vec![Value::Int32(1), Value::String("Committed".to_string())]],
+            on_conflict: None,
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(insert_query);
         tx_manager.commit_transaction(tx).unwrap();
 
         let select_query = Query::Select {
-            table: "Test".to_string(),
+            table: Some("Test".to_string()),
             columns: vec!["Value".to_string()],
             condition: None,
+            order_by: Vec::new(),
         };
         let mut tx = tx_manager.begin_transaction();
-        tx.add_query(select_query.clone());
+        tx.add_query(select_query);
         let results = tx_manager.commit_transaction(tx).unwrap();
         assert_eq!(results, vec![vec![Value::String("Committed".to_string())]]);
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("tx_rollback").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::CreateTable {
+            table: "Test".to_string(),
+            columns: vec![
+                Column::new("ID", DataType::Int32),
+                Column::new("Value", DataType::String),
+            ],
+        });
+        tx_manager.commit_transaction(tx).unwrap();
 
-        // Test rollback
         let insert_query = Query::Insert {
             table: "Test".to_string(),
-            values: vec![Value::Int32(2), Value::String("RolledBack".to_string())],
+            columns: vec![],
+            rows: vec![vec![Value::Int32(2), Value::String("RolledBack".to_string())]],
+            on_conflict: None,
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(insert_query);
         tx_manager.rollback_transaction(tx).unwrap();
 
         let mut tx = tx_manager.begin_transaction();
-        tx.add_query(select_query);
+        tx.add_query(Query::Select {
+            table: Some("Test".to_string()),
+            columns: vec!["Value".to_string()],
+            condition: None,
+            order_by: Vec::new(),
+        });
         let results = tx_manager.commit_transaction(tx).unwrap();
-        assert_eq!(results, vec![vec![Value::String("Committed".to_string())]]);
-
-        cleanup_test_db(&data_dir);
+        assert!(results.is_empty());
     }
 
     #[test]
-    fn test_error_handling() {
-        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) =
-            setup_test_db("error_handling").unwrap();
-        let create_query = Query::CreateTable {
-            table: "Test".to_string(),
-            columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Value".to_string(), DataType::String),
-            ],
-        };
+    fn test_insert_type_mismatch() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("type_mismatch").unwrap();
         let mut tx = tx_manager.begin_transaction();
-        tx.add_query(create_query);
+        tx.add_query(query::parser::parse_query("CREATE TABLE Test (ID INT, Value STRING)").unwrap());
         tx_manager.commit_transaction(tx).unwrap();
 
-        // Test type mismatch
         let insert_query = Query::Insert {
             table: "Test".to_string(),
-            values: vec![
+            columns: vec![],
+            rows: vec![vec![
                 Value::String("Invalid".to_string()),
                 Value::String("Test".to_string()),
-            ],
+            ]],
+            on_conflict: None,
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(insert_query);
         let result = tx_manager.commit_transaction(tx);
-        assert!(matches!(result, Err(DbError::TypeMismatch)));
+        assert!(matches!(result, Err(DbError::TypeMismatch(_))));
+    }
 
-        // Test missing table
+    #[test]
+    fn test_select_missing_table() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("missing_table").unwrap();
         let select_query = Query::Select {
-            table: "NonExistent".to_string(),
+            table: Some("NonExistent".to_string()),
             columns: vec!["ID".to_string()],
             condition: None,
+            order_by: Vec::new(),
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(select_query);
@@ -383,11 +664,20 @@ mod tests {
             result,
             Err(DbError::InvalidData(ref s)) if s.contains("Table NonExistent not found")
         ));
+    }
+
+    #[test]
+    fn test_insert_column_count() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("column_count").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("CREATE TABLE Test (ID INT, Value STRING)").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
 
-        // Test invalid column count
         let insert_query = Query::Insert {
             table: "Test".to_string(),
-            values: vec![Value::Int32(1)],
+            columns: vec![],
+            rows: vec![vec![Value::Int32(1)]],
+            on_conflict: None,
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(insert_query);
@@ -396,19 +686,17 @@ mod tests {
             result,
             Err(DbError::InvalidData(ref s)) if s.contains("Expected 2 columns, got 1")
         ));
-
-        cleanup_test_db(&data_dir);
     }
 
     #[test]
     fn test_delete() {
-        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("delete").unwrap();
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("delete").unwrap();
         let create_query = Query::CreateTable {
             table: "Employees".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Name".to_string(), DataType::String),
-                ("Salary".to_string(), DataType::Float32),
+                Column::new("ID", DataType::Int32),
+                Column::new("Name", DataType::String),
+                Column::new("Salary", DataType::Float32),
             ],
         };
         let mut tx = tx_manager.begin_transaction();
@@ -418,19 +706,23 @@ mod tests {
         let inserts = vec![
             Query::Insert {
                 table: "Employees".to_string(),
-                values: vec![
+                columns: vec![],
+                rows: vec![vec![
                     Value::Int32(1),
                     Value::String("Alice".to_string()),
                     Value::Float32(OrderedFloat(1000.0)),
-                ],
+                ]],
+                on_conflict: None,
             },
             Query::Insert {
                 table: "Employees".to_string(),
-                values: vec![
+                columns: vec![],
+                rows: vec![vec![
                     Value::Int32(2),
                     Value::String("Bob".to_string()),
                     Value::Float32(OrderedFloat(1500.0)),
-                ],
+                ]],
+                on_conflict: None,
             },
         ];
         let mut tx = tx_manager.begin_transaction();
@@ -448,9 +740,10 @@ mod tests {
         tx_manager.commit_transaction(tx).unwrap();
 
         let select_query = Query::Select {
-            table: "Employees".to_string(),
+            table: Some("Employees".to_string()),
             columns: vec!["Name".to_string(), "Salary".to_string()],
             condition: None,
+            order_by: Vec::new(),
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(select_query);
@@ -464,8 +757,6 @@ mod tests {
                 Value::Float32(OrderedFloat(1500.0))
             ]
         );
-
-        cleanup_test_db(&data_dir);
     }
 
     #[test]
@@ -474,8 +765,8 @@ mod tests {
         let create_query = Query::CreateTable {
             table: "Test".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Value".to_string(), DataType::String),
+                Column::new("ID", DataType::Int32),
+                Column::new("Value", DataType::String),
             ],
         };
         let mut tx = tx_manager.begin_transaction();
@@ -484,7 +775,9 @@ mod tests {
 
         let insert_query = Query::Insert {
             table: "Test".to_string(),
-            values: vec![Value::Int32(1), Value::String("Test".to_string())],
+            columns: vec![],
+            rows: vec![vec![Value::Int32(1), Value::String("Test".to_string())]],
+            on_conflict: None,
         };
         let mut tx = tx_manager.begin_transaction();
         tx.add_query(insert_query);
@@ -497,72 +790,4210 @@ mod tests {
         tx.add_query(drop_query);
         tx_manager.commit_transaction(tx).unwrap();
 
-        let storage_guard = storage.lock().unwrap();
+        let storage_guard = storage.write().unwrap();
         let schema = storage_guard.schema();
         assert!(schema.get_table("Test").is_none());
         assert!(!fs::metadata(format!("{}/columns/Test_ID", data_dir)).is_ok());
         assert!(!fs::metadata(format!("{}/indexes/Test_ID.idx", data_dir)).is_ok());
-
-        cleanup_test_db(&data_dir);
     }
 
     #[test]
-    fn test_transaction_queries() {
-        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("tx_queries").unwrap();
-
-        // Create table
-        let create_query = Query::CreateTable {
-            table: "Test".to_string(),
+    fn test_alter_table_drop_column() {
+        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("drop_column").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::CreateTable {
+            table: "Users".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Value".to_string(), DataType::String),
+                Column::new("ID", DataType::Int32),
+                Column::new("Age", DataType::Int32),
+                Column::new("City", DataType::String),
             ],
-        };
-        let mut tx = tx_manager.begin_transaction();
-        tx.add_query(create_query);
+        });
+        tx.add_query(Query::Insert {
+            table: "Users".to_string(),
+            columns: vec![],
+            rows: vec![vec![Value::Int32(1), Value::Int32(30), Value::String("Oslo".to_string())]],
+            on_conflict: None,
+        });
         tx_manager.commit_transaction(tx).unwrap();
 
-        // Start transaction, insert, and commit
         let mut tx = tx_manager.begin_transaction();
-        tx.add_query(Query::Insert {
-            table: "Test".to_string(),
-            values: vec![Value::Int32(1), Value::String("Committed".to_string())],
-        });
+        tx.add_query(query::parser::parse_query("ALTER TABLE Users DROP COLUMN Age").unwrap());
         tx_manager.commit_transaction(tx).unwrap();
+        assert!(fs::metadata(format!("{}/columns/Users_Age.dat", data_dir)).is_err());
 
-        // Start transaction, insert, and rollback
         let mut tx = tx_manager.begin_transaction();
-        tx.add_query(Query::Insert {
-            table: "Test".to_string(),
-            values: vec![Value::Int32(2), Value::String("RolledBack".to_string())],
+        tx.add_query(Query::Select {
+            table: Some("Users".to_string()),
+            columns: vec![],
+            condition: None,
+            order_by: Vec::new(),
         });
-        tx_manager.rollback_transaction(tx).unwrap();
+        let results = tx_manager.commit_transaction(tx).unwrap();
+        assert_eq!(results, vec![vec![Value::Int32(1), Value::String("Oslo".to_string())]]);
 
-        // Verify only the committed row is present
-        let select_query = Query::Select {
-            table: "Test".to_string(),
-            columns: vec!["Value".to_string()],
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::Select {
+            table: Some("Users".to_string()),
+            columns: vec!["Age".to_string()],
             condition: None,
-        };
+            order_by: Vec::new(),
+        });
+        let result = tx_manager.commit_transaction(tx);
+        assert!(matches!(
+            result,
+            Err(DbError::InvalidData(ref s)) if s.contains("Column Users.Age not found")
+        ));
+    }
+
+    #[test]
+    fn test_alter_table_drop_indexed_column() {
+        let (_data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("drop_indexed").unwrap();
+        run_sql(&mut tx_manager, &["CREATE TABLE Users (ID INT, City STRING)", "INSERT INTO Users VALUES (1, 'Oslo')"])
+            .unwrap();
+
+        // Indexed columns cannot be dropped
         let mut tx = tx_manager.begin_transaction();
-        tx.add_query(select_query);
+        tx.add_query(query::parser::parse_query("ALTER TABLE Users DROP COLUMN ID").unwrap());
+        assert!(matches!(tx_manager.commit_transaction(tx), Err(DbError::SchemaError(_))));
+        assert_eq!(storage.read().unwrap().schema().get_table("Users").unwrap().columns.len(), 2);
+
+        // Nor can a column an index covers, until the index is dropped
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("CREATE INDEX users_city ON Users (City)").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("ALTER TABLE Users DROP COLUMN City").unwrap());
+        assert!(matches!(
+            tx_manager.commit_transaction(tx),
+            Err(DbError::SchemaError(ref s)) if s.contains("users_city")
+        ));
+        assert_eq!(storage.read().unwrap().schema().get_table("Users").unwrap().indexes.len(), 1);
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("DROP INDEX users_city ON Users").unwrap());
+        tx.add_query(query::parser::parse_query("ALTER TABLE Users DROP COLUMN City").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
+        assert_eq!(storage.read().unwrap().schema().get_table("Users").unwrap().columns.len(), 1);
+    }
+
+    #[test]
+    fn test_not_null_constraint() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("not_null").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(
+            query::parser::parse_query("CREATE TABLE People (ID INT PRIMARY KEY, Name STRING NOT NULL, Nick STRING)")
+                .unwrap(),
+        );
+        tx.add_query(query::parser::parse_query("INSERT INTO People (ID, Name) VALUES (1, 'Ada')").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
+
+        // Name is required but missing from the column list
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("INSERT INTO People (ID, Nick) VALUES (2, 'bob')").unwrap());
+        let result = tx_manager.commit_transaction(tx);
+        assert!(matches!(
+            result,
+            Err(DbError::ValidationError(ref s)) if s.contains("People.Name")
+        ));
+
+        // Primary keys are implicitly NOT NULL
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("INSERT INTO People VALUES (NULL, 'Eve', 'e')").unwrap());
+        let result = tx_manager.commit_transaction(tx);
+        assert!(matches!(
+            result,
+            Err(DbError::ValidationError(ref s)) if s.contains("People.ID")
+        ));
+    }
+
+    #[test]
+    fn test_nulls_survive_checkpoint() {
+        let (_data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("null_checkpoint").unwrap();
+        run_sql(
+            &mut tx_manager,
+            &["CREATE TABLE People (ID INT PRIMARY KEY, Name STRING NOT NULL, Nick STRING)", "INSERT INTO People (ID, Name) VALUES (1, 'Ada')"],
+        )
+        .unwrap();
+
+        // NULLs survive a flush to the column files
+        storage.write().unwrap().checkpoint().unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::Select {
+            table: Some("People".to_string()),
+            columns: vec![],
+            condition: None,
+            order_by: Vec::new(),
+        });
         let results = tx_manager.commit_transaction(tx).unwrap();
-        assert_eq!(results, vec![vec![Value::String("Committed".to_string())]]);
+        assert_eq!(
+            results,
+            vec![vec![Value::Int32(1), Value::String("Ada".to_string()), Value::Null]]
+        );
+    }
 
-        cleanup_test_db(&data_dir);
+    #[test]
+    fn test_unique_constraints() {
+        let (_data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("unique").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(
+            query::parser::parse_query("CREATE TABLE Accounts (Code INT PRIMARY KEY, Email STRING UNIQUE, Note STRING)")
+                .unwrap(),
+        );
+        tx.add_query(query::parser::parse_query("INSERT INTO Accounts VALUES (1, 'a@x.io', 'first')").unwrap());
+        tx.add_query(query::parser::parse_query("INSERT INTO Accounts (Code, Note) VALUES (2, 'no email')").unwrap());
+        tx.add_query(query::parser::parse_query("INSERT INTO Accounts (Code, Note) VALUES (3, 'no email')").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
+        storage.write().unwrap().checkpoint().unwrap();
+
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("INSERT INTO Accounts VALUES (1, 'b@x.io', 'dup key')").unwrap());
+        let result = tx_manager.commit_transaction(tx);
+        assert!(matches!(
+            result,
+            Err(DbError::ValidationError(ref s)) if s.contains("Accounts.Code") && s.contains('1')
+        ));
+
+        // Deleted values can be reused
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::Delete {
+            table: "Accounts".to_string(),
+            condition: Some(Condition::Equal("Code".to_string(), Value::Int32(1))),
+        });
+        tx.add_query(query::parser::parse_query("INSERT INTO Accounts VALUES (5, 'a@x.io', 'reused')").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
+        assert_eq!(storage.read().unwrap().row_count("Accounts"), 3);
+    }
+
+    #[test]
+    fn test_unique_constraints_survive_restart() {
+        let (data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("unique_restart").unwrap();
+        run_sql(
+            &mut tx_manager,
+            &["CREATE TABLE Accounts (Code INT PRIMARY KEY, Email STRING UNIQUE)", "INSERT INTO Accounts VALUES (1, 'a@x.io')"],
+        )
+        .unwrap();
+        storage.write().unwrap().checkpoint().unwrap();
+        drop(tx_manager);
+        drop(storage);
+
+        let (_schema, _storage, mut tx_manager, _plugin_manager) = create_database(&Config::for_data_dir(&data_dir).unwrap()).unwrap();
+        let result = run_sql(&mut tx_manager, &["INSERT INTO Accounts VALUES (4, 'a@x.io')"]);
+        assert!(matches!(
+            result,
+            Err(DbError::ValidationError(ref s)) if s.contains("a@x.io")
+        ));
+    }
+
+    #[test]
+    fn test_check_constraints() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("check").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(
+            query::parser::parse_query(
+                "CREATE TABLE Staff (ID INT, Age INT CHECK (Age >= 0 AND Age < 150), Level INT NOT NULL CHECK (Level > 0))",
+            )
+            .unwrap(),
+        );
+        tx.add_query(query::parser::parse_query("INSERT INTO Staff VALUES (1, 30, 2)").unwrap());
+        tx.add_query(query::parser::parse_query("INSERT INTO Staff VALUES (2, NULL, 1)").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
+
+        for insert in ["INSERT INTO Staff VALUES (3, -1, 2)", "INSERT INTO Staff VALUES (4, 40, 0)"] {
+            let mut tx = tx_manager.begin_transaction();
+            tx.add_query(query::parser::parse_query(insert).unwrap());
+            let result = tx_manager.commit_transaction(tx);
+            assert!(matches!(result, Err(DbError::ValidationError(ref s)) if s.contains("CHECK")));
+        }
+
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("CREATE TABLE Bad (ID INT CHECK (Missing > 0))").unwrap());
+        assert!(matches!(tx_manager.commit_transaction(tx), Err(DbError::SchemaError(_))));
+    }
+
+    // Authors 1 and 2 with a book each; Herbert's book (11) is on loan
+    fn create_library(tx_manager: &mut TransactionManager) {
+        run_sql(
+            tx_manager,
+            &[
+                "CREATE TABLE Authors (AuthorID INT PRIMARY KEY, Name STRING)",
+                "CREATE TABLE Books (BookID INT PRIMARY KEY, AuthorID INT REFERENCES Authors(AuthorID) ON DELETE CASCADE)",
+                "CREATE TABLE Loans (LoanID INT, BookID INT REFERENCES Books (BookID))",
+                "INSERT INTO Authors VALUES (1, 'Le Guin')",
+                "INSERT INTO Authors VALUES (2, 'Herbert')",
+                "INSERT INTO Books VALUES (10, 1)",
+                "INSERT INTO Books VALUES (11, 2)",
+                "INSERT INTO Loans VALUES (100, 11)",
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_foreign_keys() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("foreign_keys").unwrap();
+        create_library(&mut tx_manager);
+
+        // Dangling reference
+        let result = run_sql(&mut tx_manager, &["INSERT INTO Books VALUES (12, 3)"]);
+        assert!(matches!(result, Err(DbError::ValidationError(ref s)) if s.contains("Authors.AuthorID")));
+
+        // A referenced table cannot be dropped
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::DropTable { table: "Authors".to_string() });
+        assert!(matches!(tx_manager.commit_transaction(tx), Err(DbError::SchemaError(_))));
+    }
+
+    #[test]
+    fn test_foreign_key_restrict() {
+        let (_data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("fk_restrict").unwrap();
+        create_library(&mut tx_manager);
+
+        // Herbert's book is on loan, and Loans restricts deletes
+        let result = run_sql(&mut tx_manager, &["DELETE FROM Authors WHERE AuthorID = 2"]);
+        assert!(matches!(result, Err(DbError::ValidationError(ref s)) if s.contains("Loans.BookID")));
+        assert_eq!(storage.read().unwrap().schema().get_table("Books").unwrap().row_count, 2);
+    }
+
+    #[test]
+    fn test_foreign_key_cascade() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("fk_cascade").unwrap();
+        create_library(&mut tx_manager);
+
+        // Le Guin's book cascades away with her
+        let results = run_sql(&mut tx_manager, &["DELETE FROM Authors WHERE AuthorID = 1", "SELECT * FROM Books"]).unwrap();
+        // One author deleted; the cascade isn't counted
+        assert_eq!(results, vec![vec![Value::Int32(1)], vec![Value::Int32(11), Value::Int32(2)]]);
+    }
+
+    #[test]
+    fn test_foreign_key_cascade_in_transaction() {
+        let db = test_db("fk_tx");
+        db.execute("CREATE TABLE p (id INT PRIMARY KEY)").unwrap();
+        db.execute("CREATE TABLE c (id INT PRIMARY KEY, parent INT REFERENCES p(id) ON DELETE CASCADE)").unwrap();
+        db.execute("CREATE TABLE r (id INT, child INT REFERENCES c(id))").unwrap();
+        db.execute("INSERT INTO p VALUES (1), (2), (3)").unwrap();
+        db.execute("INSERT INTO c VALUES (10, 1), (11, 2), (12, 3)").unwrap();
+        db.execute("INSERT INTO r VALUES (100, 12)").unwrap();
+        let ids = |table: &str| db.query(&format!("SELECT id FROM {}", table)).unwrap();
+
+        // The cascade is visible before COMMIT and rolls back with the parent delete
+        db.execute("BEGIN").unwrap();
+        db.execute("DELETE FROM p WHERE id = 2").unwrap();
+        assert_eq!(ids("p"), vec![vec![Value::Int32(1)], vec![Value::Int32(3)]]);
+        assert_eq!(ids("c"), vec![vec![Value::Int32(10)], vec![Value::Int32(12)]]);
+        db.execute("ROLLBACK").unwrap();
+        assert_eq!(ids("c").len(), 3);
+
+        // A RESTRICT further down rejects the delete and buffers none of it
+        db.execute("BEGIN").unwrap();
+        let result = db.execute("DELETE FROM p WHERE id = 3");
+        assert!(matches!(result, Err(DbError::ValidationError(ref s)) if s.contains("r.child")));
+        assert_eq!(ids("c").len(), 3);
+        db.execute("DELETE FROM p WHERE id = 2").unwrap();
+        db.execute("COMMIT").unwrap();
+        assert_eq!(ids("p"), vec![vec![Value::Int32(1)], vec![Value::Int32(3)]]);
+        assert_eq!(ids("c"), vec![vec![Value::Int32(10)], vec![Value::Int32(12)]]);
+    }
+
+    #[test]
+    fn test_self_referencing_cascade_in_transaction() {
+        let db = test_db("fk_self");
+        db.execute("CREATE TABLE Parts (ID INT PRIMARY KEY, Parent INT REFERENCES Parts(ID) ON DELETE CASCADE)").unwrap();
+        db.execute("INSERT INTO Parts VALUES (1, NULL), (2, 1), (3, 2), (4, NULL)").unwrap();
+
+        db.execute("BEGIN").unwrap();
+        db.execute("DELETE FROM Parts WHERE ID = 1").unwrap();
+        assert_eq!(db.query("SELECT ID FROM Parts").unwrap(), vec![vec![Value::Int32(4)]]);
+        db.execute("COMMIT").unwrap();
+        assert_eq!(db.query("SELECT ID FROM Parts").unwrap(), vec![vec![Value::Int32(4)]]);
+    }
+
+    #[test]
+    fn test_column_defaults() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("defaults").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(
+            query::parser::parse_query(
+                "CREATE TABLE Tasks (ID INT, Status STRING NOT NULL DEFAULT 'to do', Priority INT DEFAULT 3, Owner STRING NOT NULL)",
+            )
+            .unwrap(),
+        );
+        tx.add_query(query::parser::parse_query("INSERT INTO Tasks (ID, Owner) VALUES (1, 'sam')").unwrap());
+        tx.add_query(Query::Select {
+            table: Some("Tasks".to_string()),
+            columns: vec![],
+            condition: None,
+            order_by: Vec::new(),
+        });
+        let results = tx_manager.commit_transaction(tx).unwrap();
+        assert_eq!(
+            results,
+            vec![vec![
+                Value::Int32(1),
+                Value::String("to do".to_string()),
+                Value::Int32(3),
+                Value::String("sam".to_string()),
+            ]]
+        );
+
+        // Owner has neither a value nor a default
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("INSERT INTO Tasks (ID) VALUES (2)").unwrap());
+        let result = tx_manager.commit_transaction(tx);
+        assert!(matches!(result, Err(DbError::ValidationError(ref s)) if s.contains("Tasks.Owner")));
+
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("CREATE TABLE Bad (ID INT DEFAULT 'one')").unwrap());
+        assert!(matches!(tx_manager.commit_transaction(tx), Err(DbError::SchemaError(_))));
+    }
+
+    #[test]
+    fn test_embedded_database() {
+        let db = test_db("embedded");
+        db.execute("CREATE TABLE Events (ID INT, Kind STRING)").unwrap();
+
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let db = &*db;
+                scope.spawn(move || {
+                    for i in 0..5 {
+                        db.execute(&format!("INSERT INTO Events VALUES ({}, 'thread{}')", t * 10 + i, t))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let rows = db.query("SELECT Kind FROM Events WHERE ID >= 30").unwrap();
+        assert_eq!(rows.len(), 5);
+        assert!(rows.iter().all(|row| row == &vec![Value::String("thread3".to_string())]));
+        assert_eq!(db.schema().unwrap().get_table("Events").unwrap().row_count, 20);
+        assert!(matches!(db.execute("SELEC nonsense"), Err(DbError::QueryError(_))));
+    }
+
+    #[test]
+    fn test_csv_export() {
+        let result = QueryResult::new(
+            vec!["ID".to_string(), "Text".to_string(), "Score".to_string()],
+            vec![
+                vec![Value::Int32(1), Value::String("plain".to_string()), Value::Float32(OrderedFloat(2.5))],
+                vec![Value::Int32(-2), Value::String("a, \"quoted\"\nline".to_string()), Value::Null],
+            ],
+        );
+        let mut out = Vec::new();
+        result.to_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ID,Text,Score\r\n1,plain,2.5\r\n-2,\"a, \"\"quoted\"\"\nline\",\r\n"
+        );
+    }
+
+    #[test]
+    fn test_table_output() {
+        let result = QueryResult::new(
+            vec!["ID".to_string(), "City".to_string()],
+            vec![
+                vec![Value::Int32(7), Value::String("Zürich".to_string())],
+                vec![Value::Int32(12), Value::String("東京都庁前".to_string())],
+            ],
+        );
+        assert_eq!(
+            result.to_table(8),
+            "+----+---------+\n\
+             | ID | City    |\n\
+             +----+---------+\n\
+             |  7 | Zürich  |\n\
+             | 12 | 東京都… |\n\
+             +----+---------+\n\
+             (2 rows)"
+        );
+    }
+
+    #[test]
+    fn test_json_output() {
+        let (_data_dir, _schema, storage, _tx_manager, _plugin_manager) = setup_test_db("json_output").unwrap();
+        let mut engine = QueryEngine::new(Arc::clone(&storage));
+        for sql in [
+            "CREATE TABLE Items (ID INT, Label STRING, Price FLOAT)",
+            "INSERT INTO Items VALUES (1, 'say \"hi\"', 1.5)",
+            "INSERT INTO Items (ID) VALUES (2)",
+        ] {
+            engine.execute(query::parser::parse_query(sql).unwrap()).unwrap();
+        }
+        let result = engine
+            .execute_query(query::parser::parse_query("SELECT Price, ID, Label FROM Items").unwrap())
+            .unwrap();
+        assert_eq!(
+            result.to_json().unwrap(),
+            r#"[{"Price":1.5,"ID":1,"Label":"say \"hi\""},{"Price":null,"ID":2,"Label":null}]"#
+        );
+
+        let result = engine
+            .execute_query(query::parser::parse_query("SELECT COUNT(*), MAX(ID) FROM Items").unwrap())
+            .unwrap();
+        assert_eq!(result.columns, vec!["COUNT(*)".to_string(), "MAX(ID)".to_string()]);
+    }
+
+    #[test]
+    fn test_csv_import() {
+        let (data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("csv_import").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("CREATE TABLE Cities (ID INT, Name STRING, Area FLOAT)").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
+
+        let good = format!("{}/cities.csv", data_dir);
+        fs::write(&good, "Name,ID,Area\r\n\"Oslo, NO\",1,454.0\r\nBergen,2,\r\n").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query(&format!("COPY Cities FROM '{}'", good)).unwrap());
+        tx.add_query(Query::Select {
+            table: Some("Cities".to_string()),
+            columns: vec![],
+            condition: None,
+            order_by: Vec::new(),
+        });
+        let results = tx_manager.commit_transaction(tx).unwrap();
+        assert_eq!(
+            results,
+            vec![
+                vec![Value::Int32(1), Value::String("Oslo, NO".to_string()), Value::Float32(OrderedFloat(454.0))],
+                vec![Value::Int32(2), Value::String("Bergen".to_string()), Value::Null],
+            ]
+        );
+        assert!(storage.write().unwrap().import_csv("Cities", &good.replace("cities", "missing")).is_err());
+    }
+
+    #[test]
+    fn test_csv_import_errors() {
+        let db = test_db("csv_errors");
+        db.execute("CREATE TABLE Cities (ID INT, Name STRING, Area FLOAT)").unwrap();
+        let storage = db.storage();
+
+        // A bad value on line 3 rejects the whole file
+        let bad = format!("{}/bad.csv", db.dir());
+        fs::write(&bad, "ID,Name\n3,Trondheim\nfour,Tromso\n").unwrap();
+        let result = storage.write().unwrap().import_csv("Cities", &bad);
+        assert!(matches!(result, Err(DbError::InvalidData(ref s)) if s.starts_with("line 3:")));
+
+        let ragged = format!("{}/ragged.csv", db.dir());
+        fs::write(&ragged, "ID,Name\n3,Trondheim,extra\n").unwrap();
+        let result = storage.write().unwrap().import_csv("Cities", &ragged);
+        assert!(matches!(result, Err(DbError::InvalidData(ref s)) if s.starts_with("line 2:")));
+        assert_eq!(storage.read().unwrap().schema().get_table("Cities").unwrap().row_count, 0);
+    }
+
+    #[test]
+    fn test_csv_import_rolls_back() {
+        let db = test_db("csv_rollback");
+        db.execute("CREATE TABLE Codes (Code INT UNIQUE)").unwrap();
+        let storage = db.storage();
+
+        // A constraint failure midway rolls back the rows already loaded
+        let dup = format!("{}/dup.csv", db.dir());
+        fs::write(&dup, "Code\n1\n2\n1\n").unwrap();
+        let result = storage.write().unwrap().import_csv("Codes", &dup);
+        assert!(matches!(result, Err(DbError::InvalidData(ref s)) if s.starts_with("line 4:")));
+        assert_eq!(storage.read().unwrap().read_column("Codes", "Code", None).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let input = "CREATE TABLE T (ID INT);\n INSERT INTO T VALUES (1)\n;;  ; SELECT * FROM T WHERE Name = 'a;b';  ";
+        assert_eq!(
+            query::parser::split_statements(input),
+            vec![
+                "CREATE TABLE T (ID INT)",
+                "INSERT INTO T VALUES (1)",
+                "SELECT * FROM T WHERE Name = 'a;b'",
+            ]
+        );
+        assert!(query::parser::split_statements(" ; ").is_empty());
+    }
+
+    #[test]
+    fn test_parameter_binding() {
+        let db = test_db("params");
+        db.execute("CREATE TABLE Notes (ID INT, Body TEXT)").unwrap();
+
+        let body = "it's; -- not /* a */ comment";
+        db.execute_params("INSERT INTO Notes VALUES (?, ?)", &[Value::Int32(1), Value::String(body.to_string())]).unwrap();
+        assert_eq!(
+            db.query("SELECT Body FROM Notes WHERE ID = 1").unwrap(),
+            vec![vec![Value::String(body.to_string())]]
+        );
+        // A `?` inside a literal is text, not a placeholder
+        assert_eq!(
+            query::parser::bind_params("SELECT * FROM Notes WHERE Body = '?' AND ID = ?", &[Value::Int32(2)]).unwrap(),
+            "SELECT * FROM Notes WHERE Body = '?' AND ID = 2"
+        );
+        assert!(matches!(db.execute_params("INSERT INTO Notes VALUES (?, ?)", &[Value::Int32(2)]), Err(DbError::QueryError(_))));
+        assert!(matches!(db.execute_params("SELECT * FROM Notes", &[Value::Int32(2)]), Err(DbError::QueryError(_))));
+
+        // sanitize_sql only shapes text for display; stored strings are untouched by it
+        assert_eq!(types::sanitize_sql(body), "it''s  not  a  comment");
+        assert_eq!(db.query("SELECT Body FROM Notes").unwrap(), vec![vec![Value::String(body.to_string())]]);
+    }
+
+    #[test]
+    fn test_repl_completion() {
+        let (_data_dir, _schema, storage, mut tx_manager, _plugin_manager) = setup_test_db("completion").unwrap();
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(query::parser::parse_query("CREATE TABLE Orders (OrderID INT, Owner STRING)").unwrap());
+        tx.add_query(query::parser::parse_query("CREATE TABLE Owners (ID INT)").unwrap());
+        tx_manager.commit_transaction(tx).unwrap();
+
+        let storage = storage.write().unwrap();
+        let complete = |line: &str| repl::complete_sql(storage.schema(), line, line.len());
+        assert_eq!(complete("sel"), (0, vec!["select".to_string()]));
+        assert_eq!(complete("SELECT * FROM Ord"), (14, vec!["Orders".to_string()]));
+        assert_eq!(complete("SELECT * FROM O"), (14, vec!["Orders".to_string(), "Owners".to_string()]));
+        assert_eq!(
+            complete("SELECT Ow"),
+            (7, vec!["Owners".to_string()])
+        );
+        assert_eq!(
+            complete("SELECT * FROM Orders WHERE Ow"),
+            (27, vec!["Owner".to_string(), "Owners".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_sql_dump() {
+        let source = test_db("dump");
+        source.execute("CREATE TABLE Teams (ID INT PRIMARY KEY, Name STRING NOT NULL DEFAULT 'n/a')").unwrap();
+        source
+            .execute("CREATE TABLE Players (ID INT, Team INT REFERENCES Teams(ID) ON DELETE CASCADE, Score FLOAT CHECK (Score >= 0.0))")
+            .unwrap();
+        source.execute("INSERT INTO Teams VALUES (1, 'O''Brien, Ltd; est. 1999')").unwrap();
+        source.execute("INSERT INTO Teams (ID) VALUES (2)").unwrap();
+        source.execute("INSERT INTO Players VALUES (7, 1, 3.0)").unwrap();
+        source.execute("INSERT INTO Players VALUES (8, NULL, 0.25)").unwrap();
+
+        let dump = source.storage().read().unwrap().dump_sql(None).unwrap();
+        assert_eq!(
+            dump,
+            "CREATE TABLE Teams (ID INT PRIMARY KEY, Name TEXT NOT NULL DEFAULT 'n/a');\n\
+             INSERT INTO Teams VALUES (1, 'O''Brien, Ltd; est. 1999');\n\
+             INSERT INTO Teams VALUES (2, 'n/a');\n\
+             CREATE TABLE Players (ID INT, Team INT REFERENCES Teams(ID) ON DELETE CASCADE, Score FLOAT CHECK (Score >= 0.0));\n\
+             INSERT INTO Players VALUES (7, 1, 3.0);\n\
+             INSERT INTO Players VALUES (8, NULL, 0.25);\n"
+        );
+        assert!(source.storage().read().unwrap().dump_sql(Some("Teams")).unwrap().starts_with("CREATE TABLE Teams"));
+        assert!(source.storage().read().unwrap().dump_sql(Some("Missing")).is_err());
+
+        // Replaying the dump recreates the same database
+        let copy = test_db("restore");
+        for statement in query::parser::split_statements(&dump) {
+            copy.execute(statement).unwrap();
+        }
+        assert_eq!(copy.storage().read().unwrap().dump_sql(None).unwrap(), dump);
+    }
+
+    #[test]
+    fn test_explain() {
+        let db = test_db("explain");
+        db.execute("CREATE TABLE People (ID INT, Name STRING, Age INT)").unwrap();
+        db.execute("CREATE TABLE Pets (ID INT, Owner INT)").unwrap();
+        for i in 0..3 {
+            db.execute(&format!("INSERT INTO People VALUES ({}, 'p{}', {})", i, i, 20 + i)).unwrap();
+        }
+        db.execute("INSERT INTO Pets VALUES (1, 2)").unwrap();
+
+        let plan = db.execute("EXPLAIN SELECT Name FROM People WHERE Age > 18").unwrap();
+        assert_eq!(plan.columns, vec!["QUERY PLAN".to_string()]);
+        let lines = |result: QueryResult| -> Vec<String> { result.rows.iter().map(|row| row[0].to_string()).collect() };
+        assert_eq!(
+            lines(plan),
+            vec![
+                "Project [Name]",
+                "  -> Parallel filter: Age > 18",
+                "    -> Column scan on People reading [Name, Age] (est. 3 rows), skipping blocks by min/max on Age > 18",
+            ]
+        );
+        assert_eq!(
+            lines(db.execute("EXPLAIN SELECT People.Name, Pets.ID FROM People JOIN Pets ON People.ID = Pets.Owner").unwrap()),
+            vec![
+                "Project [People.Name, Pets.ID]",
+                "  -> Nested loop join on People.ID = Pets.Owner, parallel over People rows (est. at most 3 rows)",
+                "    -> Column scan on People reading [ID, Name] (est. 3 rows)",
+                "    -> Column scan on Pets reading [Owner, ID] (est. 1 rows)",
+            ]
+        );
+        // Nothing is executed
+        assert_eq!(db.schema().unwrap().get_table("People").unwrap().row_count, 3);
+        assert!(matches!(db.execute("EXPLAIN DROP TABLE People"), Err(DbError::QueryError(_))));
+    }
+
+    #[test]
+    fn test_transaction_rollback_discards_writes() {
+        let db = test_db("tx_semantics");
+        db.execute("CREATE TABLE Accounts (ID INT PRIMARY KEY, Balance INT)").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (1, 100)").unwrap();
+        let before = db.storage().read().unwrap().dump_sql(None).unwrap();
+
+        // The transaction sees its own writes; ROLLBACK discards them
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (2, 50)").unwrap();
+        db.execute("DELETE FROM Accounts WHERE ID = 1").unwrap();
+        assert_eq!(db.query("SELECT ID, Balance FROM Accounts").unwrap(), vec![vec![Value::Int32(2), Value::Int32(50)]]);
+        assert_eq!(db.query("SELECT COUNT(*) FROM Accounts").unwrap(), vec![vec![Value::Int32(1)]]);
+        assert!(matches!(db.execute("BEGIN"), Err(DbError::TransactionError(_))));
+        assert!(matches!(db.execute("DROP TABLE Accounts"), Err(DbError::TransactionError(_))));
+        db.execute("ROLLBACK").unwrap();
+        assert_eq!(db.storage().read().unwrap().dump_sql(None).unwrap(), before);
+        assert!(matches!(db.execute("ROLLBACK"), Err(DbError::TransactionError(_))));
+    }
+
+    #[test]
+    fn test_transaction_commit_applies_writes() {
+        let db = test_db("tx_commit_writes");
+        db.execute("CREATE TABLE Accounts (ID INT PRIMARY KEY, Balance INT)").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (1, 100)").unwrap();
+
+        // COMMIT applies every buffered write
+        db.execute("START TRANSACTION").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (2, 50)").unwrap();
+        db.execute("DELETE FROM Accounts WHERE ID = 1").unwrap();
+        db.execute("COMMIT").unwrap();
+        assert_eq!(db.query("SELECT ID FROM Accounts").unwrap(), vec![vec![Value::Int32(2)]]);
+    }
+
+    #[test]
+    fn test_transaction_failed_commit() {
+        let mut db = test_db("tx_failed_commit");
+        db.execute("CREATE TABLE Accounts (ID INT PRIMARY KEY, Balance INT)").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (2, 50)").unwrap();
+
+        // A write that fails at COMMIT undoes the ones applied before it
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (3, 10)").unwrap();
+        db.execute("DELETE FROM Accounts WHERE ID = 2").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (3, 20)").unwrap();
+        assert!(matches!(db.execute("INSERT INTO Accounts VALUES (NULL, 1)"), Err(DbError::ValidationError(_))));
+        assert!(db.execute("COMMIT").is_err());
+        assert_eq!(db.query("SELECT ID, Balance FROM Accounts").unwrap(), vec![vec![Value::Int32(2), Value::Int32(50)]]);
+
+        // Nothing from the failed transaction is replayed from the WAL
+        db.reopen();
+        assert_eq!(db.query("SELECT ID FROM Accounts").unwrap(), vec![vec![Value::Int32(2)]]);
+    }
+
+    #[test]
+    fn test_interrupted_commit_rolled_back() {
+        let mut db = test_db("interrupted_commit");
+        db.execute("CREATE TABLE p (id INT PRIMARY KEY)").unwrap();
+        db.execute("CREATE TABLE c (id INT PRIMARY KEY, parent INT REFERENCES p(id) ON DELETE CASCADE)").unwrap();
+        db.execute("INSERT INTO p VALUES (1), (2)").unwrap();
+        db.execute("INSERT INTO c VALUES (10, 1), (20, 2)").unwrap();
+
+        // Simulate a crash partway through a commit: p got a row flushed, and only one column
+        // of c was rewritten for the cascade
+        {
+            let shared = db.storage();
+            let mut storage = shared.write().unwrap();
+            storage.checkpoint().unwrap();
+            let schema = storage.schema().clone();
+            storage::undo::UndoImage::create(db.dir(), &schema, &["p".to_string(), "c".to_string()], true).unwrap();
+            storage.insert_row("p", &[], vec![Value::Int32(3)]).unwrap();
+            storage.checkpoint().unwrap();
+            let mut table = storage.write_table("c").unwrap();
+            table.columns.get_mut("parent").unwrap().rewrite(&[Value::Int32(2)], CompressionType::Rle, 8192).unwrap();
+        }
+        db.reopen();
+        assert_eq!(db.query("SELECT id FROM p").unwrap(), vec![vec![Value::Int32(1)], vec![Value::Int32(2)]]);
+        assert_eq!(
+            db.query("SELECT id, parent FROM c").unwrap(),
+            vec![vec![Value::Int32(10), Value::Int32(1)], vec![Value::Int32(20), Value::Int32(2)]]
+        );
+        assert!(!std::path::Path::new(&format!("{}/{}", db.dir(), storage::undo::UNDO_DIR)).exists());
+
+        // The unique index was rebuilt from the restored rows, and writes work again
+        assert!(db.execute("INSERT INTO p VALUES (2)").is_err());
+        db.execute("INSERT INTO p VALUES (3)").unwrap();
+        db.execute("DELETE FROM p WHERE id = 1").unwrap();
+        assert_eq!(db.query("SELECT id FROM c").unwrap(), vec![vec![Value::Int32(20)]]);
+    }
+
+    #[test]
+    fn test_half_built_undo_image_ignored() {
+        let mut db = test_db("half_built_undo");
+        db.execute("CREATE TABLE t (id INT)").unwrap();
+        db.execute("INSERT INTO t VALUES (1)").unwrap();
+        // A crash before the image was complete leaves only its staging directory
+        fs::create_dir_all(format!("{}/{}.tmp/columns", db.dir(), storage::undo::UNDO_DIR)).unwrap();
+        db.execute("INSERT INTO t VALUES (2)").unwrap();
+        db.reopen();
+        assert_eq!(db.query("SELECT id FROM t").unwrap(), vec![vec![Value::Int32(1)], vec![Value::Int32(2)]]);
+        assert!(!std::path::Path::new(&format!("{}/{}.tmp", db.dir(), storage::undo::UNDO_DIR)).exists());
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let db = test_db("savepoints");
+        db.execute("CREATE TABLE Steps (ID INT)").unwrap();
+        let ids = |db: &Database| db.query("SELECT ID FROM Steps").unwrap().into_iter().map(|row| row[0].clone()).collect::<Vec<_>>();
+
+        assert!(matches!(db.execute("SAVEPOINT a"), Err(DbError::TransactionError(_))));
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Steps VALUES (1)").unwrap();
+        db.execute("SAVEPOINT a").unwrap();
+        db.execute("INSERT INTO Steps VALUES (2)").unwrap();
+        db.execute("SAVEPOINT b").unwrap();
+        db.execute("INSERT INTO Steps VALUES (3)").unwrap();
+
+        db.execute("ROLLBACK TO SAVEPOINT a").unwrap();
+        assert_eq!(ids(&db), vec![Value::Int32(1)]);
+        // Savepoints taken after the target are gone; the target itself can be reused
+        assert!(matches!(db.execute("ROLLBACK TO b"), Err(DbError::TransactionError(_))));
+        db.execute("INSERT INTO Steps VALUES (4)").unwrap();
+        db.execute("ROLLBACK TO a").unwrap();
+        db.execute("INSERT INTO Steps VALUES (5)").unwrap();
+        assert!(matches!(db.execute("ROLLBACK TO SAVEPOINT missing"), Err(DbError::TransactionError(_))));
+        db.execute("COMMIT").unwrap();
+        assert_eq!(ids(&db), vec![Value::Int32(1), Value::Int32(5)]);
+    }
+
+    #[test]
+    fn test_read_committed_isolation() {
+        let db = test_db("read_committed");
+        db.execute("CREATE TABLE Seats (ID INT)").unwrap();
+        let mut alice = QueryEngine::new(db.storage());
+        let mut bob = QueryEngine::new(db.storage());
+        let run = |engine: &mut QueryEngine, sql: &str| engine.execute(query::parser::parse_query(sql).unwrap());
+
+        // Read Committed: uncommitted rows stay private, concurrent commits don't conflict
+        run(&mut alice, "BEGIN").unwrap();
+        run(&mut alice, "INSERT INTO Seats VALUES (1)").unwrap();
+        assert_eq!(run(&mut bob, "SELECT ID FROM Seats").unwrap(), Vec::<Vec<Value>>::new());
+        run(&mut bob, "INSERT INTO Seats VALUES (2)").unwrap();
+        assert_eq!(run(&mut alice, "SELECT ID FROM Seats").unwrap(), vec![vec![Value::Int32(2)], vec![Value::Int32(1)]]);
+        run(&mut alice, "COMMIT").unwrap();
+        assert_eq!(db.query("SELECT ID FROM Seats").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_serializable_isolation() {
+        let db = test_db("serializable");
+        db.execute("CREATE TABLE Seats (ID INT)").unwrap();
+        db.execute("INSERT INTO Seats VALUES (1), (2)").unwrap();
+        let mut alice = QueryEngine::new(db.storage());
+        let mut bob = QueryEngine::new(db.storage());
+        let run = |engine: &mut QueryEngine, sql: &str| engine.execute(query::parser::parse_query(sql).unwrap());
+
+        // A concurrent write to the same table aborts the later commit
+        run(&mut alice, "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE").unwrap();
+        run(&mut alice, "BEGIN").unwrap();
+        run(&mut alice, "INSERT INTO Seats VALUES (3)").unwrap();
+        assert!(matches!(run(&mut alice, "SET TRANSACTION ISOLATION LEVEL READ COMMITTED"), Err(DbError::TransactionError(_))));
+        run(&mut bob, "BEGIN").unwrap();
+        run(&mut bob, "DELETE FROM Seats WHERE ID = 1").unwrap();
+        run(&mut bob, "COMMIT").unwrap();
+        assert!(matches!(run(&mut alice, "COMMIT"), Err(DbError::ConcurrencyError(_))));
+        assert!(!alice.in_transaction());
+        assert_eq!(db.query("SELECT ID FROM Seats").unwrap(), vec![vec![Value::Int32(2)]]);
+
+        // Without a conflicting writer the serializable transaction commits
+        run(&mut alice, "BEGIN").unwrap();
+        run(&mut alice, "INSERT INTO Seats VALUES (3)").unwrap();
+        run(&mut alice, "COMMIT").unwrap();
+        assert_eq!(alice.default_isolation_level(), IsolationLevel::Serializable);
+        assert!(matches!(
+            query::parser::parse_query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ"),
+            Err(DbError::QueryError(_))
+        ));
+    }
+
+    #[test]
+    fn test_delete_count() {
+        let db = test_db("delete_count");
+        db.execute("CREATE TABLE Logs (ID INT, Level INT)").unwrap();
+        for (id, level) in [(1, 1), (2, 3), (3, 3), (4, 2)] {
+            db.execute(&format!("INSERT INTO Logs VALUES ({}, {})", id, level)).unwrap();
+        }
+
+        let result = db.execute("DELETE FROM Logs WHERE Level = 3").unwrap();
+        assert_eq!(result.columns, vec!["DELETED".to_string()]);
+        assert_eq!(result.rows, vec![vec![Value::Int32(2)]]);
+        assert_eq!(db.query("DELETE FROM Logs WHERE Level = 9").unwrap(), vec![vec![Value::Int32(0)]]);
+
+        // Inside a transaction the count covers the rows the transaction can see
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Logs VALUES (5, 1)").unwrap();
+        assert_eq!(db.query("DELETE FROM Logs WHERE Level = 1").unwrap(), vec![vec![Value::Int32(2)]]);
+        assert_eq!(db.query("DELETE FROM Logs").unwrap(), vec![vec![Value::Int32(1)]]);
+        db.execute("COMMIT").unwrap();
+        assert_eq!(db.query("SELECT ID FROM Logs").unwrap(), Vec::<Vec<Value>>::new());
+    }
+
+    #[test]
+    fn test_table_size() {
+        let db = test_db("table_size");
+        db.execute("CREATE TABLE Events (ID INT, Name TEXT)").unwrap();
+        assert_eq!(db.storage().read().unwrap().table_size("Events").0, 0);
+        for id in 1..=3 {
+            db.execute(&format!("INSERT INTO Events VALUES ({}, 'event')", id)).unwrap();
+        }
+
+        let handle = db.storage();
+        let mut storage = handle.write().unwrap();
+        assert_eq!(storage.table_size("Events").0, 3);
+        storage.checkpoint().unwrap();
+        let (rows, bytes) = storage.table_size("Events");
+        assert_eq!(rows, 3);
+        assert!(bytes > 0);
+        storage.drop_table("Events").unwrap();
+        assert_eq!(storage.table_size("Events"), (0, 0));
+    }
+
+    #[test]
+    fn test_column_cache() {
+        let db = test_db("column_cache");
+        db.execute("CREATE TABLE Items (ID INT, Price INT)").unwrap();
+        db.execute("INSERT INTO Items VALUES (1, 10)").unwrap();
+        db.execute("INSERT INTO Items VALUES (2, 20)").unwrap();
+
+        let handle = db.storage();
+        let mut storage = handle.write().unwrap();
+        storage.checkpoint().unwrap();
+        let (hits, misses) = storage.cache_stats();
+        assert_eq!(storage.read_column("Items", "Price", None).unwrap(), vec![Value::Int32(10), Value::Int32(20)]);
+        assert_eq!(storage.read_column("Items", "Price", None).unwrap(), vec![Value::Int32(10), Value::Int32(20)]);
+        assert_eq!(storage.cache_stats(), (hits + 1, misses + 1));
+        drop(storage);
+
+        // Writes invalidate the cached column, so the new row is never hidden
+        db.execute("INSERT INTO Items VALUES (3, 30)").unwrap();
+        handle.write().unwrap().checkpoint().unwrap();
+        db.execute("DELETE FROM Items WHERE ID = 1").unwrap();
+        assert_eq!(db.query("SELECT Price FROM Items").unwrap(), vec![vec![Value::Int32(20)], vec![Value::Int32(30)]]);
+
+        // With room for a single column, reading another evicts the first
+        let mut storage = handle.write().unwrap();
+        storage.set_cache_capacity(1);
+        storage.read_column("Items", "Price", None).unwrap();
+        storage.read_column("Items", "ID", None).unwrap();
+        let (hits, misses) = storage.cache_stats();
+        storage.read_column("Items", "Price", None).unwrap();
+        assert_eq!(storage.cache_stats(), (hits, misses + 1));
+    }
+
+    #[test]
+    fn test_authorization() {
+        let db = test_db("authorization");
+        db.execute("CREATE TABLE Accounts (ID INT, Balance INT)").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (1, 100)").unwrap();
+
+        let mut security = SecurityContext::new();
+        security.permissions.insert("reader".to_string(), vec!["select".to_string()]);
+        security.current_user = Some(User {
+            username: "alice".to_string(),
+            password_hash: String::new(),
+            roles: vec!["reader".to_string()],
+        });
+        db.set_security_context(Some(security.clone())).unwrap();
+        assert_eq!(db.query("SELECT Balance FROM Accounts").unwrap(), vec![vec![Value::Int32(100)]]);
+        assert!(db.execute("EXPLAIN SELECT Balance FROM Accounts").is_ok());
+        assert!(matches!(db.execute("INSERT INTO Accounts VALUES (2, 50)"), Err(DbError::AuthorizationError(_))));
+        assert!(matches!(db.execute("DROP TABLE Accounts"), Err(DbError::AuthorizationError(_))));
+        // Transaction control needs no permission
+        db.execute("BEGIN").unwrap();
+        db.execute("ROLLBACK").unwrap();
+
+        // Sessions that have not logged in may only read, and only when allowed to
+        security.current_user = None;
+        db.set_security_context(Some(security.clone())).unwrap();
+        assert!(matches!(db.execute("SELECT * FROM Accounts"), Err(DbError::AuthorizationError(_))));
+        security.allow_anonymous_reads = true;
+        db.set_security_context(Some(security)).unwrap();
+        assert!(db.execute("SELECT * FROM Accounts").is_ok());
+        assert!(matches!(db.execute("DELETE FROM Accounts"), Err(DbError::AuthorizationError(_))));
+
+        db.set_security_context(None).unwrap();
+        db.execute("INSERT INTO Accounts VALUES (2, 50)").unwrap();
+    }
+
+    #[test]
+    fn test_create_user() {
+        let mut db = test_db("users");
+        db.execute("CREATE USER alice PASSWORD 'it''s secret'").unwrap();
+        db.execute("CREATE USER bob PASSWORD 'hunter2'").unwrap();
+        assert!(matches!(db.execute("CREATE USER bob PASSWORD 'again'"), Err(DbError::ValidationError(_))));
+        assert!(db.execute("CREATE USER carol").is_err());
+
+        // Only a hash is stored, and the first user is the admin
+        let stored = fs::read_to_string(std::path::Path::new(db.dir()).join(auth::USERS_FILE)).unwrap();
+        assert!(!stored.contains("hunter2"));
+        let alice = db.storage().read().unwrap().users().get("alice").cloned().unwrap();
+        assert_eq!(alice.roles, vec![auth::ADMIN_ROLE.to_string()]);
+        assert!(db.storage().read().unwrap().users().get("bob").unwrap().roles.is_empty());
+
+        // Users persist across restarts
+        db.reopen();
+        assert!(db.execute("LOGIN bob PASSWORD 'hunter2'").is_ok());
+    }
+
+    #[test]
+    fn test_login() {
+        let db = test_db("login");
+        db.execute("CREATE TABLE Secrets (ID INT)").unwrap();
+        db.execute("CREATE USER alice PASSWORD 'it''s secret'").unwrap();
+        db.execute("CREATE USER bob PASSWORD 'hunter2'").unwrap();
+
+        // A wrong password and an unknown user fail the same way
+        let wrong_password = db.execute("LOGIN alice PASSWORD 'guess'").unwrap_err();
+        let unknown_user = db.execute("LOGIN mallory PASSWORD 'guess'").unwrap_err();
+        assert!(matches!(wrong_password, DbError::AuthenticationError(_)));
+        assert_eq!(wrong_password.to_string(), unknown_user.to_string());
+
+        // bob has no permissions yet; alice is an admin
+        db.execute("LOGIN bob PASSWORD 'hunter2'").unwrap();
+        assert!(matches!(db.execute("SELECT * FROM Secrets"), Err(DbError::AuthorizationError(_))));
+        db.execute("LOGIN alice PASSWORD 'it''s secret'").unwrap();
+        db.execute("INSERT INTO Secrets VALUES (1)").unwrap();
+    }
+
+    #[test]
+    fn test_grant_revoke() {
+        let mut db = test_db("grants");
+        db.execute("CREATE TABLE Users (ID INT)").unwrap();
+        db.execute("CREATE TABLE Salaries (ID INT)").unwrap();
+        db.execute("CREATE USER root PASSWORD 'admin-pw'").unwrap();
+        db.execute("CREATE USER alice PASSWORD 'alice-pw'").unwrap();
+        db.execute("LOGIN root PASSWORD 'admin-pw'").unwrap();
+        db.execute("GRANT SELECT ON Users TO analyst").unwrap();
+        db.execute("GRANT ROLE analyst TO alice").unwrap();
+        assert!(db.execute("GRANT ROLE analyst TO nobody").is_err());
+        assert!(matches!(db.execute("GRANT UPDATE ON Users TO analyst"), Err(DbError::QueryError(_))));
+        assert!(matches!(db.execute("REVOKE ROLE admin FROM root"), Err(DbError::ValidationError(_))));
+
+        // Permissions are scoped to the table they were granted on
+        db.execute("LOGIN alice PASSWORD 'alice-pw'").unwrap();
+        assert!(db.execute("SELECT * FROM Users").is_ok());
+        assert!(matches!(db.execute("SELECT * FROM Salaries"), Err(DbError::AuthorizationError(_))));
+        assert!(matches!(db.execute("INSERT INTO Users VALUES (1)"), Err(DbError::AuthorizationError(_))));
+        assert!(matches!(
+            db.execute("SELECT Users.ID, Salaries.ID FROM Users JOIN Salaries ON Users.ID = Salaries.ID"),
+            Err(DbError::AuthorizationError(_))
+        ));
+        // Only admins manage permissions, whatever else they were granted
+        assert!(matches!(db.execute("GRANT SELECT ON Salaries TO analyst"), Err(DbError::AuthorizationError(_))));
+
+        // Grants persist, and REVOKE takes them back
+        db.reopen();
+        db.execute("LOGIN root PASSWORD 'admin-pw'").unwrap();
+        db.execute("GRANT INSERT ON * TO analyst").unwrap();
+        db.execute("REVOKE SELECT ON Users FROM analyst").unwrap();
+        db.execute("LOGIN alice PASSWORD 'alice-pw'").unwrap();
+        assert!(db.execute("INSERT INTO Salaries VALUES (1)").is_ok());
+        assert!(matches!(db.execute("SELECT * FROM Users"), Err(DbError::AuthorizationError(_))));
+        db.execute("LOGIN root PASSWORD 'admin-pw'").unwrap();
+        db.execute("REVOKE ROLE analyst FROM alice").unwrap();
+        db.execute("LOGIN alice PASSWORD 'alice-pw'").unwrap();
+        assert!(matches!(db.execute("INSERT INTO Salaries VALUES (2)"), Err(DbError::AuthorizationError(_))));
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let db = test_db("audit");
+        db.execute("CREATE TABLE Orders (ID INT, Total INT)").unwrap();
+        db.execute("INSERT INTO Orders VALUES (1, 10)").unwrap();
+        db.execute("INSERT INTO Orders VALUES (2, 20)").unwrap();
+        db.execute("SELECT * FROM Orders").unwrap();
+        db.execute("DELETE FROM Orders WHERE Total > 5").unwrap();
+        assert!(db.execute("DROP TABLE Missing").is_err());
+        db.execute("CREATE USER auditor PASSWORD 'top-secret'").unwrap();
+        db.execute("LOGIN auditor PASSWORD 'top-secret'").unwrap();
+        db.storage().write().unwrap().audit_log_mut().set_audit_reads(true);
+        db.execute("SELECT ID FROM Orders").unwrap();
+
+        let contents = fs::read_to_string(std::path::Path::new(db.dir()).join(audit::AUDIT_FILE)).unwrap();
+        assert!(!contents.contains("top-secret"));
+        let entries: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let operations: Vec<&str> = entries.iter().map(|e| e["operation"].as_str().unwrap()).collect();
+        // Reads and LOGIN are left out until read auditing is switched on
+        assert_eq!(operations, vec!["create_table", "insert", "insert", "delete", "drop_table", "create_user", "select"]);
+        assert_eq!(entries[3]["statement"], "DELETE FROM Orders WHERE Total > 5");
+        assert_eq!(entries[3]["tables"], serde_json::json!(["Orders"]));
+        assert_eq!(entries[3]["rows"], 2);
+        assert!(entries[3]["user"].is_null());
+        assert!(entries[4]["error"].is_string());
+        assert_eq!(entries[5]["statement"], "CREATE USER auditor PASSWORD '***'");
+        assert_eq!(entries[6]["user"], "auditor");
+        assert!(entries[6]["timestamp"].is_string());
+    }
+
+    // Serves `db` on a free local port from a background thread
+    fn start_server(db: &Database, max_connections: usize) -> std::net::SocketAddr {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), db.storage()).unwrap().with_max_connections(max_connections);
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+        addr
+    }
+
+    fn connect(addr: std::net::SocketAddr) -> (std::net::TcpStream, std::io::BufReader<std::net::TcpStream>) {
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        (stream.try_clone().unwrap(), std::io::BufReader::new(stream))
+    }
+
+    fn read_line(reader: &mut std::io::BufReader<std::net::TcpStream>) -> String {
+        use std::io::BufRead;
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[test]
+    fn test_tcp_server() {
+        use std::io::Write;
+
+        let db = test_db("server");
+        db.execute("CREATE TABLE Items (ID INT, Name TEXT)").unwrap();
+        let (mut alice, mut alice_in) = connect(start_server(&db, 2));
+        writeln!(alice, "INSERT INTO Items VALUES (1, 'one'); SELECT * FROM Items").unwrap();
+        assert_eq!(read_line(&mut alice_in), "[]");
+        assert_eq!(read_line(&mut alice_in), r#"[{"ID":1,"Name":"one"}]"#);
+
+        // Errors come back as a JSON line and the connection stays open
+        writeln!(alice, "SELEC nonsense").unwrap();
+        let error: serde_json::Value = serde_json::from_str(&read_line(&mut alice_in)).unwrap();
+        assert_eq!(error["error"]["code"], "QUERY_ERROR");
+        assert!(error["error"]["message"].as_str().unwrap().starts_with("Query Error"));
+        alice.write_all(b"\xff\xfe\n").unwrap();
+        assert!(read_line(&mut alice_in).contains("Network Error"));
+        writeln!(alice, "SELECT ID FROM Items").unwrap();
+        assert_eq!(read_line(&mut alice_in), r#"[{"ID":1}]"#);
+    }
+
+    #[test]
+    fn test_tcp_server_connections() {
+        use std::io::Write;
+
+        let db = test_db("server_connections");
+        db.execute("CREATE TABLE Items (ID INT, Name TEXT)").unwrap();
+        db.execute("INSERT INTO Items VALUES (1, 'one')").unwrap();
+        let addr = start_server(&db, 2);
+        let (mut alice, mut alice_in) = connect(addr);
+
+        // Each connection has its own transaction
+        let (mut bob, mut bob_in) = connect(addr);
+        writeln!(bob, "BEGIN; INSERT INTO Items VALUES (2, 'two')").unwrap();
+        assert_eq!(read_line(&mut bob_in), "[]");
+        assert_eq!(read_line(&mut bob_in), "[]");
+        writeln!(alice, "SELECT ID FROM Items").unwrap();
+        assert_eq!(read_line(&mut alice_in), r#"[{"ID":1}]"#);
+
+        // Past the limit a connection is refused with an error line
+        let (_carol, mut carol_in) = connect(addr);
+        assert!(read_line(&mut carol_in).contains("Too many connections"));
+
+        // An uncommitted transaction is discarded when its client disconnects
+        drop((bob, bob_in));
+        writeln!(alice, "SELECT ID FROM Items").unwrap();
+        assert_eq!(read_line(&mut alice_in), r#"[{"ID":1}]"#);
+    }
+
+    #[test]
+    fn test_tcp_server_idle_timeout() {
+        use std::io::Write;
+
+        let db = test_db("server_idle");
+        let server = Server::bind("127.0.0.1:0".parse().unwrap(), db.storage())
+            .unwrap()
+            .with_max_connections(1)
+            .with_idle_timeout(Some(std::time::Duration::from_millis(200)));
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+
+        // An idle client is disconnected, and its slot goes to the next one once its thread ends
+        let (_alice, mut alice_in) = connect(addr);
+        assert_eq!(read_line(&mut alice_in), "");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        loop {
+            let (mut bob, mut bob_in) = connect(addr);
+            // A refused connection may already be closed
+            let _ = writeln!(bob, "SELECT * FROM information_schema.tables");
+            let response = read_line(&mut bob_in);
+            if response == "[]" {
+                break;
+            }
+            assert!(response.contains("Too many connections") && std::time::Instant::now() < deadline, "{}", response);
+        }
+    }
+
+    #[test]
+    fn test_change_feed() {
+        use storage::feed::ChangeRecord;
+
+        let db = test_db("change_feed");
+        db.execute("CREATE TABLE Authors (ID INT PRIMARY KEY, Name TEXT)").unwrap();
+        db.execute("CREATE TABLE Books (ID INT, Author INT REFERENCES Authors(ID) ON DELETE CASCADE)").unwrap();
+        let changes = db.subscribe_changes(16).unwrap();
+
+        db.execute("INSERT INTO Authors VALUES (1, 'Le Guin')").unwrap();
+        db.execute("INSERT INTO Books VALUES (10, 1)").unwrap();
+        assert_eq!(
+            changes.try_recv().unwrap().unwrap(),
+            ChangeRecord::Insert { table: "Authors".to_string(), row: vec![Value::Int32(1), Value::String("Le Guin".to_string())] }
+        );
+        assert_eq!(
+            changes.try_recv().unwrap().unwrap(),
+            ChangeRecord::Insert { table: "Books".to_string(), row: vec![Value::Int32(10), Value::Int32(1)] }
+        );
+
+        // Cascaded deletes are reported for the child table too
+        db.execute("DELETE FROM Authors WHERE ID = 1").unwrap();
+        let mut deletes: Vec<ChangeRecord> = changes.try_iter().map(|c| c.unwrap()).collect();
+        deletes.sort_by_key(|c| format!("{:?}", c));
+        assert_eq!(
+            deletes,
+            vec![
+                ChangeRecord::Delete { table: "Authors".to_string(), rows: vec![vec![Value::Int32(1), Value::String("Le Guin".to_string())]] },
+                ChangeRecord::Delete { table: "Books".to_string(), rows: vec![vec![Value::Int32(10), Value::Int32(1)]] },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_change_feed_transactions() {
+        use storage::feed::ChangeRecord;
+
+        let db = test_db("change_feed_tx");
+        db.execute("CREATE TABLE Authors (ID INT PRIMARY KEY, Name TEXT)").unwrap();
+        let changes = db.subscribe_changes(16).unwrap();
+
+        // Transactions publish on COMMIT only
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Authors VALUES (2, 'Butler')").unwrap();
+        assert!(changes.try_recv().is_err());
+        db.execute("COMMIT").unwrap();
+        assert!(matches!(changes.try_recv().unwrap().unwrap(), ChangeRecord::Insert { .. }));
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Authors VALUES (3, 'Jemisin')").unwrap();
+        db.execute("ROLLBACK").unwrap();
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_change_feed_lagging_subscriber() {
+        let db = test_db("change_feed_lag");
+        db.execute("CREATE TABLE Authors (ID INT PRIMARY KEY, Name TEXT)").unwrap();
+        let changes = db.subscribe_changes(16).unwrap();
+
+        // A subscriber that stops reading is cut off instead of growing without bound
+        let lagging = db.subscribe_changes(2).unwrap();
+        for id in 4..8 {
+            db.execute(&format!("INSERT INTO Authors VALUES ({}, 'Author')", id)).unwrap();
+        }
+        let received: Vec<_> = lagging.try_iter().collect();
+        assert_eq!(received.len(), 3);
+        assert!(matches!(received[2], Err(DbError::ReplicationError(_))));
+        assert_eq!(changes.try_iter().count(), 4);
+    }
+
+    #[test]
+    fn test_export_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use parquet::record::Field;
+
+        let db = test_db("export_parquet");
+        db.execute("CREATE TABLE Users (ID INT, Score FLOAT, Name TEXT)").unwrap();
+        db.execute("INSERT INTO Users VALUES (1, 2.5, 'Ada')").unwrap();
+        db.execute("INSERT INTO Users VALUES (2, NULL, NULL)").unwrap();
+        db.execute("INSERT INTO Users VALUES (NULL, 4.0, 'Grace')").unwrap();
+
+        let path = format!("{}/users.parquet", db.dir());
+        let result = db.execute(&format!("EXPORT TABLE Users TO '{}'", path)).unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(3)]]);
+
+        let reader = SerializedFileReader::new(fs::File::open(&path).unwrap()).unwrap();
+        let rows: Vec<Vec<Field>> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().get_column_iter().map(|(_, field)| field.clone()).collect())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec![Field::Int(1), Field::Float(2.5), Field::Str("Ada".to_string())],
+                vec![Field::Int(2), Field::Null, Field::Null],
+                vec![Field::Null, Field::Float(4.0), Field::Str("Grace".to_string())],
+            ]
+        );
+
+        assert!(db.execute("EXPORT TABLE Missing TO 'missing.parquet'").is_err());
+        assert!(db.execute("EXPORT Users TO 'users.parquet'").is_err());
+    }
+
+    #[test]
+    fn test_read_columns() {
+        let mut db = test_db("read_columns");
+        db.execute("CREATE TABLE Users (ID INT, Name TEXT, Age INT)").unwrap();
+        db.execute("INSERT INTO Users VALUES (1, 'Ada', 36)").unwrap();
+        db.execute("INSERT INTO Users VALUES (2, 'Grace', 45)").unwrap();
+
+        let handle = db.storage();
+        let storage = handle.read().unwrap();
+        let names = vec!["Name".to_string(), "Age".to_string(), "Name".to_string()];
+        let columns = storage.read_columns("Users", &names, None).unwrap();
+        assert_eq!(columns.len(), 2);
+        assert_eq!(columns["Name"], vec![Value::String("Ada".to_string()), Value::String("Grace".to_string())]);
+        assert_eq!(columns["Age"], storage.read_column("Users", "Age", None).unwrap());
+        assert!(storage.read_columns("Users", &["Missing".to_string()], None).is_err());
+        drop(storage);
+        drop(handle);
+
+        // Aggregates over several columns read them together
+        db.reopen();
+        let result = db.execute("SELECT SUM(Age), MAX(Name), COUNT(*) FROM Users").unwrap();
+        assert_eq!(result.rows[0][1], Value::String("Grace".to_string()));
+    }
+
+    #[test]
+    fn test_predicate_pushdown() {
+        let db = test_db("predicate_pushdown");
+        db.execute("CREATE TABLE Events (ID INT, Name TEXT)").unwrap();
+        let handle = db.storage();
+        let mut storage = handle.write().unwrap();
+        let columns = vec!["ID".to_string(), "Name".to_string()];
+        // One block per checkpoint, each covering its own ID range
+        for block in 0..8 {
+            for i in block * 200..(block + 1) * 200 {
+                storage
+                    .insert_row("Events", &columns, vec![Value::Int32(i), Value::String(format!("event{}", i))])
+                    .unwrap();
+            }
+            storage.checkpoint().unwrap();
+        }
+        storage.insert_row("Events", &columns, vec![Value::Int32(1600), Value::String("pending".to_string())]).unwrap();
+
+        let predicate = Condition::Equal("ID".to_string(), Value::Int32(777));
+        let before = allocated_bytes();
+        let matched = storage.read_columns_where("Events", &columns, &predicate).unwrap();
+        let pushed_down = allocated_bytes() - before;
+        assert_eq!(matched["Name"], vec![Value::String("event777".to_string())]);
+        let before = allocated_bytes();
+        let full = storage.read_columns("Events", &columns, None).unwrap();
+        let full_scan = allocated_bytes() - before;
+        assert_eq!(full["ID"].len(), 1601);
+        assert!(pushed_down * 4 < full_scan, "pushed down {} bytes vs full scan {}", pushed_down, full_scan);
+
+        let pending = Condition::GreaterThanOrEqual("ID".to_string(), Value::Int32(1599));
+        let matched = storage.read_columns_where("Events", &columns, &pending).unwrap();
+        assert_eq!(matched["ID"], vec![Value::Int32(1599), Value::Int32(1600)]);
+        assert!(storage.read_columns_where("Events", &columns, &Condition::Or(
+            Box::new(Condition::Equal("ID".to_string(), Value::Int32(1))),
+            Box::new(Condition::Equal("Name".to_string(), Value::String("x".to_string()))),
+        )).is_err());
+        drop(storage);
+
+        // The equality branch is pushed down and the rest is checked by the planner
+        let and = Condition::And(
+            Box::new(Condition::GreaterThan("Name".to_string(), Value::String("a".to_string()))),
+            Box::new(Condition::Equal("ID".to_string(), Value::Int32(5))),
+        );
+        assert!(matches!(query::pushdown_predicate(&and), Some(Condition::Equal(col, _)) if col == "ID"));
+        let result = db.execute("SELECT Name FROM Events WHERE ID < 300 AND Name = 'event250'").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::String("event250".to_string())]]);
+        let result = db.execute("SELECT COUNT(*) FROM Events WHERE ID >= 1500").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(101)]]);
+    }
+
+    #[test]
+    fn test_predicate_pushdown_decodes_matching_blocks() {
+        let db = test_db("pushdown_blocks");
+        db.execute("CREATE TABLE Events (ID INT, Name TEXT)").unwrap();
+        let handle = db.storage();
+        let mut storage = handle.write().unwrap();
+        let columns = vec!["ID".to_string(), "Name".to_string()];
+        for block in 0..8 {
+            for i in block * 200..(block + 1) * 200 {
+                storage
+                    .insert_row("Events", &columns, vec![Value::Int32(i), Value::String(format!("event{}", i))])
+                    .unwrap();
+            }
+            storage.checkpoint().unwrap();
+        }
+        let decoded = |storage: &StorageManager| {
+            let table = storage.read_table("Events").unwrap();
+            (table.columns["ID"].blocks_decoded(), table.columns["Name"].blocks_decoded())
+        };
+        let before = decoded(&storage);
+
+        // Only the block whose range holds 777 is decoded, in both columns
+        let predicate = Condition::Equal("ID".to_string(), Value::Int32(777));
+        let matched = storage.read_columns_where("Events", &columns, &predicate).unwrap();
+        assert_eq!(matched["ID"], vec![Value::Int32(777)]);
+        assert_eq!(matched["Name"], vec![Value::String("event777".to_string())]);
+        let after = decoded(&storage);
+        assert_eq!((after.0 - before.0, after.1 - before.1), (1, 1));
+
+        // A range over two blocks decodes those two
+        let predicate = Condition::GreaterThanOrEqual("ID".to_string(), Value::Int32(1250));
+        let matched = storage.read_columns_where("Events", &columns, &predicate).unwrap();
+        assert_eq!(matched["ID"].len(), 350);
+        let last = decoded(&storage);
+        assert_eq!((last.0 - after.0, last.1 - after.1), (2, 2));
+    }
+
+    #[test]
+    fn test_column_scan_iterator() {
+        let db = test_db("column_scan");
+        db.execute("CREATE TABLE Readings (ID INT, Value FLOAT)").unwrap();
+        let handle = db.storage();
+        let mut storage = handle.write().unwrap();
+        let columns = vec!["ID".to_string(), "Value".to_string()];
+        for block in 0..3 {
+            for i in block * 1000..(block + 1) * 1000 {
+                storage
+                    .insert_row("Readings", &columns, vec![Value::Int32(i), Value::Float32(OrderedFloat(i as f32 / 2.0))])
+                    .unwrap();
+            }
+            storage.checkpoint().unwrap();
+        }
+        storage.insert_row("Readings", &columns, vec![Value::Int32(3000), Value::Null]).unwrap();
+
+        let streamed = storage.read_table("Readings").unwrap().read_column_iter("Value").collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(streamed, storage.read_column("Readings", "Value", None).unwrap());
+        assert_eq!(streamed.len(), 3001);
+        assert_eq!(streamed[3000], Value::Null);
+
+        // Taking a few values decodes only the first block
+        storage.set_cache_capacity(0);
+        let before = allocated_bytes();
+        let first: Vec<Value> = storage.read_table("Readings").unwrap().read_column_iter("ID").take(3).map(|v| v.unwrap()).collect();
+        let streamed_bytes = allocated_bytes() - before;
+        let before = allocated_bytes();
+        storage.read_column("Readings", "ID", None).unwrap();
+        let collected_bytes = allocated_bytes() - before;
+        assert_eq!(first, vec![Value::Int32(0), Value::Int32(1), Value::Int32(2)]);
+        assert!(streamed_bytes * 2 < collected_bytes, "streamed {} bytes vs collected {}", streamed_bytes, collected_bytes);
+
+        let table = storage.read_table("Readings").unwrap();
+        let mut missing = table.read_column_iter("Missing");
+        assert!(matches!(missing.next(), Some(Err(DbError::InvalidData(_)))));
+        assert!(missing.next().is_none());
+        drop(missing);
+        drop(table);
+        drop(storage);
+
+        // SELECT streams in batches across block boundaries
+        let result = db.execute("SELECT ID FROM Readings").unwrap();
+        assert_eq!(result.rows.len(), 3001);
+        assert_eq!(result.rows[2048], vec![Value::Int32(2048)]);
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let mut db = test_db("vacuum");
+        db.execute("CREATE TABLE Users (ID INT, Name TEXT)").unwrap();
+        for i in 0..20 {
+            db.execute(&format!("INSERT INTO Users VALUES ({}, 'user{}')", i, i)).unwrap();
+            db.storage().write().unwrap().checkpoint().unwrap();
+        }
+        db.execute("DELETE FROM Users WHERE ID >= 10").unwrap();
+        for i in 10..15 {
+            db.execute(&format!("INSERT INTO Users VALUES ({}, 'user{}')", i, i)).unwrap();
+            db.storage().write().unwrap().checkpoint().unwrap();
+        }
+        // Bytes no block refers to, as a trimmed torn flush leaves behind
+        let name_file = storage::column::ColumnStore::path(db.dir(), "Users", "Name");
+        let mut file = fs::OpenOptions::new().append(true).open(&name_file).unwrap();
+        std::io::Write::write_all(&mut file, &[0u8; 4096]).unwrap();
+        drop(file);
+
+        let before = db.execute("SELECT ID, Name FROM Users").unwrap().rows;
+        let result = db.execute("VACUUM Users").unwrap();
+        assert_eq!(result.columns, vec!["RECLAIMED_BYTES".to_string()]);
+        assert!(matches!(result.rows[0][0], Value::Int32(reclaimed) if reclaimed >= 4096));
+        assert_eq!(db.execute("SELECT ID, Name FROM Users").unwrap().rows, before);
+        assert_eq!(db.execute("SELECT Name FROM Users WHERE ID = 12").unwrap().rows, vec![vec![Value::String("user12".to_string())]]);
+        assert!(db.execute("VACUUM Missing").is_err());
+        db.reopen();
+        assert_eq!(db.execute("SELECT ID, Name FROM Users").unwrap().rows, before);
+        assert_eq!(db.storage().read().unwrap().schema().get_table("Users").unwrap().row_count, 15);
+    }
+
+    #[test]
+    fn test_verify_integrity() {
+        let db = test_db("verify");
+        db.execute("CREATE TABLE Users (ID INT, Name TEXT)").unwrap();
+        db.execute("INSERT INTO Users VALUES (1, 'Ada')").unwrap();
+        db.execute("INSERT INTO Users VALUES (2, 'Grace')").unwrap();
+        db.storage().write().unwrap().checkpoint().unwrap();
+
+        let result = db.execute("VERIFY Users").unwrap();
+        assert_eq!(result.columns, vec!["COLUMN".to_string(), "ROWS".to_string(), "STATUS".to_string()]);
+        assert_eq!(
+            result.rows,
+            vec![
+                vec![Value::String("ID".to_string()), Value::Int32(2), Value::String("ok".to_string())],
+                vec![Value::String("Name".to_string()), Value::Int32(2), Value::String("ok".to_string())],
+            ]
+        );
+
+        // Flip a byte on disk, as silent corruption would
+        let name_file = storage::column::ColumnStore::path(db.dir(), "Users", "Name");
+        let mut bytes = fs::read(&name_file).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&name_file, bytes).unwrap();
+
+        let report = db.storage().read().unwrap().verify_integrity("Users").unwrap();
+        assert!(matches!(report[0], (_, Ok(2))));
+        assert!(matches!(&report[1], (name, Err(DbError::IntegrityError(msg))) if name == "Name" && msg.contains("checksum")));
+        let result = db.execute("VERIFY Users").unwrap();
+        assert_eq!(result.rows[1][1], Value::Null);
+        assert!(db.execute("VERIFY Missing").is_err());
+    }
+
+    #[test]
+    fn test_timestamp_type() {
+        let db = test_db("timestamp");
+        db.execute("CREATE TABLE Events (ID INT, At TIMESTAMP, Logged TIMESTAMP DEFAULT NOW())").unwrap();
+        db.execute("INSERT INTO Events (ID, At) VALUES (1, TIMESTAMP '2024-01-01T00:00:00Z')").unwrap();
+        db.execute("INSERT INTO Events (ID, At) VALUES (2, TIMESTAMP '2024-03-15T12:30:00.250+02:00')").unwrap();
+        db.execute("INSERT INTO Events (ID, At) VALUES (3, NULL)").unwrap();
+        db.storage().write().unwrap().checkpoint().unwrap();
+
+        let result = db.execute("SELECT ID, At FROM Events WHERE At > TIMESTAMP '2024-02-01T00:00:00Z'").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(2), Value::Timestamp(1710498600250)]]);
+        assert_eq!(result.rows[0][1].to_string(), "2024-03-15T10:30:00.250Z");
+        let result = db.execute("SELECT MIN(At) FROM Events").unwrap();
+        assert_eq!(result.rows[0][0], Value::Timestamp(1704067200000));
+
+        // DEFAULT NOW() is evaluated per insert
+        let before = chrono::Utc::now().timestamp_millis();
+        let logged = db.execute("SELECT Logged FROM Events WHERE ID = 3").unwrap().rows[0][0].clone();
+        assert!(matches!(logged, Value::Timestamp(ms) if ms <= chrono::Utc::now().timestamp_millis() && ms > before - 60_000));
+
+        assert_eq!(Value::Timestamp(1704067200000).to_sql_literal(), "TIMESTAMP '2024-01-01T00:00:00Z'");
+        assert_eq!(Value::Timestamp(42).serialize(), 42i64.to_le_bytes().to_vec());
+        assert_eq!(Value::deserialize(&DataType::Timestamp, &42i64.to_le_bytes()).unwrap(), Value::Timestamp(42));
+        assert!(db.execute("INSERT INTO Events (ID, At) VALUES (4, TIMESTAMP 'yesterday')").is_err());
+        assert!(db.execute("CREATE TABLE Bad (ID INT DEFAULT NOW())").is_err());
+        assert!(db
+            .storage()
+            .read()
+            .unwrap()
+            .dump_sql(Some("Events"))
+            .unwrap()
+            .contains("Logged TIMESTAMP DEFAULT NOW()"));
+    }
+
+    #[test]
+    fn test_default_now_per_insert() {
+        let mut db = test_db("default_now");
+        db.execute("CREATE TABLE Log (ID INT, Level INT DEFAULT 1, CreatedAt TIMESTAMP DEFAULT NOW())").unwrap();
+        db.execute("INSERT INTO Log (ID) VALUES (1)").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        db.execute("INSERT INTO Log (ID) VALUES (2)").unwrap();
+
+        // The expression default survives a reopen and is still evaluated per row
+        db.reopen();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        db.execute("INSERT INTO Log (ID, Level) VALUES (3, 2)").unwrap();
+        let rows = db.execute("SELECT ID, Level, CreatedAt FROM Log").unwrap().rows;
+        let created: Vec<i64> = rows
+            .iter()
+            .map(|row| match row[2] {
+                Value::Timestamp(ms) => ms,
+                ref other => panic!("expected a timestamp, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(created.len(), 3);
+        assert!(created[0] < created[1] && created[1] < created[2]);
+        assert_eq!(rows.iter().map(|row| row[1].clone()).collect::<Vec<_>>(), vec![Value::Int32(1), Value::Int32(1), Value::Int32(2)]);
+
+        let err = db.execute("CREATE TABLE Bad (Name TEXT DEFAULT NOW())").unwrap_err();
+        assert!(matches!(err, DbError::SchemaError(_)));
+    }
+
+    #[test]
+    fn test_group_concat() {
+        let db = test_db("group_concat");
+        db.execute("CREATE TABLE Users (ID INT, Name TEXT, City TEXT)").unwrap();
+        db.execute("INSERT INTO Users (ID, Name, City) VALUES (1, 'Ann', 'Oslo')").unwrap();
+        db.execute("INSERT INTO Users (ID, Name, City) VALUES (2, 'Bob', 'Rome')").unwrap();
+        db.execute("INSERT INTO Users (ID, Name, City) VALUES (3, NULL, 'Oslo')").unwrap();
+        db.execute("INSERT INTO Users (ID, Name, City) VALUES (4, 'Cy', 'Oslo')").unwrap();
+
+        let result = db.execute("SELECT GROUP_CONCAT(Name) FROM Users").unwrap();
+        assert_eq!(result.columns, vec!["GROUP_CONCAT(Name)"]);
+        assert_eq!(result.rows, vec![vec![Value::String("Ann,Bob,Cy".to_string())]]);
+        let result = db.execute("SELECT GROUP_CONCAT(Name, '; '), COUNT(*) FROM Users WHERE City = 'Oslo'").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::String("Ann; Cy".to_string()), Value::Int32(3)]]);
+        let result = db.execute("SELECT GROUP_CONCAT(Name) FROM Users WHERE City = 'Paris'").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Null]]);
+
+        assert!(matches!(db.execute("SELECT GROUP_CONCAT(ID) FROM Users"), Err(DbError::TypeMismatch(_))));
+        assert!(db.execute("SELECT GROUP_CONCAT(Name, 1) FROM Users").is_err());
+
+        // One string per group, each joined in row order
+        let result = db.execute("SELECT City, GROUP_CONCAT(Name) FROM Users GROUP BY City").unwrap();
+        assert_eq!(result.columns, vec!["City", "GROUP_CONCAT(Name)"]);
+        assert_eq!(
+            result.rows,
+            vec![
+                vec![Value::String("Oslo".to_string()), Value::String("Ann,Cy".to_string())],
+                vec![Value::String("Rome".to_string()), Value::String("Bob".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_by() {
+        let db = test_db("group_by");
+        db.execute("CREATE TABLE Sales (ID INT, Region TEXT, Rep TEXT, Amount INT)").unwrap();
+        db.execute(
+            "INSERT INTO Sales VALUES (1, 'north', 'ann', 10), (2, 'south', 'bob', 5), (3, 'north', 'cy', 7), \
+             (4, NULL, 'dee', 1), (5, 'north', 'ann', 3), (6, NULL, 'eve', NULL)",
+        )
+        .unwrap();
+        let text = |s: &str| Value::String(s.to_string());
+
+        // Groups come in the order they first appear; NULL keys form a group of their own
+        let result = db.execute("SELECT Region, COUNT(*), SUM(Amount), MAX(Amount) FROM Sales GROUP BY Region").unwrap();
+        assert_eq!(result.columns, vec!["Region", "COUNT(*)", "SUM(Amount)", "MAX(Amount)"]);
+        assert_eq!(
+            result.rows,
+            vec![
+                vec![text("north"), Value::Int32(3), Value::Int64(20), Value::Int32(10)],
+                vec![text("south"), Value::Int32(1), Value::Int64(5), Value::Int32(5)],
+                vec![Value::Null, Value::Int32(2), Value::Int64(1), Value::Int32(1)],
+            ]
+        );
+
+        // Several keys, a key left out of the select list, and a WHERE applied before grouping
+        assert_eq!(
+            db.query("SELECT Rep, Region, COUNT(*) FROM Sales WHERE Amount > 2 GROUP BY Region, Rep").unwrap(),
+            vec![
+                vec![text("ann"), text("north"), Value::Int32(2)],
+                vec![text("bob"), text("south"), Value::Int32(1)],
+                vec![text("cy"), text("north"), Value::Int32(1)],
+            ]
+        );
+        assert_eq!(db.query("SELECT COUNT(*) FROM Sales GROUP BY Rep").unwrap().len(), 5);
+        assert_eq!(db.query("SELECT region FROM Sales GROUP BY REGION").unwrap().len(), 3);
+        // No matching rows gives no groups
+        assert!(db.query("SELECT Region, COUNT(*) FROM Sales WHERE ID > 100 GROUP BY Region").unwrap().is_empty());
+        assert_eq!(
+            db.query("EXPLAIN SELECT Region, COUNT(*) FROM Sales GROUP BY Region").unwrap()[0],
+            vec![text("Aggregate [Region, COUNT(*)] grouped by [Region]")]
+        );
+
+        for sql in [
+            "SELECT Rep, COUNT(*) FROM Sales GROUP BY Region",
+            "SELECT Rep, COUNT(*) FROM Sales",
+            "SELECT * FROM Sales GROUP BY Region",
+            "SELECT 1 GROUP BY Region",
+            "SELECT Region FROM Sales GROUP BY",
+        ] {
+            assert!(matches!(db.execute(sql), Err(DbError::QueryError(_))), "{}", sql);
+        }
+        assert!(db.execute("SELECT COUNT(*) FROM Sales GROUP BY Missing").is_err());
+    }
+
+    #[test]
+    fn test_min_max_empty_input() {
+        let db = test_db("min_max_empty");
+        db.execute("CREATE TABLE Tags (ID INT, Label TEXT)").unwrap();
+
+        let result = db.execute("SELECT MIN(Label), MAX(Label), MIN(ID), COUNT(*) FROM Tags").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Null, Value::Null, Value::Null, Value::Int32(0)]]);
+
+        db.execute("INSERT INTO Tags (ID, Label) VALUES (1, NULL)").unwrap();
+        db.execute("INSERT INTO Tags (ID, Label) VALUES (2, 'beta')").unwrap();
+        db.execute("INSERT INTO Tags (ID, Label) VALUES (3, 'alpha')").unwrap();
+        let result = db.execute("SELECT MIN(Label), MAX(Label) FROM Tags WHERE ID > 1").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::String("alpha".to_string()), Value::String("beta".to_string())]]);
+        // Only NULLs match
+        let result = db.execute("SELECT MIN(Label) FROM Tags WHERE ID = 1").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Null]]);
+    }
+
+    #[test]
+    fn test_unique_index_lookup() {
+        let db = test_db("unique_lookup");
+        db.execute("CREATE TABLE Accounts (ID INT PRIMARY KEY, Email TEXT UNIQUE, Handle TEXT)").unwrap();
+        let columns = vec!["ID".to_string(), "Email".to_string(), "Handle".to_string()];
+        let num_rows = 5000;
+        {
+            let handle = db.storage();
+            let mut storage = handle.write().unwrap();
+            for i in 0..num_rows {
+                // Scrambled so block min/max cannot narrow the scan of the unindexed column
+                let key = (i * 7919) % num_rows;
+                let values = vec![
+                    Value::Int32(i),
+                    Value::String(format!("user{}@example.com", key)),
+                    Value::String(format!("user{}", key)),
+                ];
+                storage.insert_row("Accounts", &columns, values).unwrap();
+                if i % 100 == 99 {
+                    storage.checkpoint().unwrap();
+                }
+            }
+        }
+        // The last row is still pending, so the lookup covers unflushed rows too
+        db.execute("INSERT INTO Accounts (ID, Email, Handle) VALUES (5000, 'late@example.com', 'late')").unwrap();
+
+        let result = db.execute("SELECT ID, Handle FROM Accounts WHERE Email = 'user42@example.com'").unwrap();
+        let scanned = db.execute("SELECT ID, Email FROM Accounts WHERE Handle = 'user42'").unwrap();
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0][0], scanned.rows[0][0]);
+        assert_eq!(result.rows[0][1], Value::String("user42".to_string()));
+        let result = db.execute("SELECT Handle FROM Accounts WHERE ID = 5000").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::String("late".to_string())]]);
+        assert!(db.execute("SELECT ID FROM Accounts WHERE Email = 'nobody'").unwrap().rows.is_empty());
+        let plan = db.execute("EXPLAIN SELECT Handle FROM Accounts WHERE ID = 7").unwrap();
+        assert_eq!(plan.rows[2][0], Value::String("    -> Unique index lookup on Accounts reading [Handle, ID]".to_string()));
+        let plan = db.execute("EXPLAIN SELECT ID FROM Accounts WHERE Email = 'user7@example.com'").unwrap();
+        assert_eq!(plan.rows[2][0], Value::String("    -> Unique index lookup on Accounts reading [ID, Email]".to_string()));
+
+        // Deleting shifts row numbers; lookups must follow
+        db.execute("DELETE FROM Accounts WHERE ID < 10").unwrap();
+        let result = db.execute("SELECT ID FROM Accounts WHERE ID = 10").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(10)]]);
+
+        // Within a transaction that wrote to the table, uncommitted rows still show up
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Accounts (ID, Email, Handle) VALUES (6000, 'tx@example.com', 'tx')").unwrap();
+        let result = db.execute("SELECT Handle FROM Accounts WHERE ID = 6000").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::String("tx".to_string())]]);
+        db.execute("ROLLBACK").unwrap();
+    }
+
+    #[test]
+    fn test_insert_on_conflict() {
+        let db = test_db("upsert");
+        db.execute("CREATE TABLE Users (ID INT PRIMARY KEY, Name TEXT, Visits INT)").unwrap();
+        db.execute("CREATE TABLE Orders (OrderID INT, UserID INT REFERENCES Users(ID))").unwrap();
+        db.execute("INSERT INTO Users (ID, Name, Visits) VALUES (1, 'a', 1)").unwrap();
+        db.execute("INSERT INTO Users (ID, Name, Visits) VALUES (2, 'b', 1)").unwrap();
+
+        let result = db.execute("INSERT INTO Users (ID, Name, Visits) VALUES (1, 'x', 5) ON CONFLICT (ID) DO NOTHING").unwrap();
+        assert_eq!(result.columns, vec!["INSERTED", "UPDATED"]);
+        assert_eq!(result.rows, vec![vec![Value::Int32(0), Value::Int32(0)]]);
+        let result = db
+            .execute("INSERT INTO Users (ID, Name, Visits) VALUES (2, 'bee', 5) ON CONFLICT (ID) DO UPDATE SET Name = EXCLUDED.Name, Visits = 9")
+            .unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(0), Value::Int32(1)]]);
+        let result = db.execute("INSERT INTO Users (ID, Name) VALUES (3, 'c') on conflict(ID) do update set Name = 'never'").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(1), Value::Int32(0)]]);
+
+        // Row order is kept: the update happens in place
+        let rows = db.execute("SELECT ID, Name, Visits FROM Users").unwrap().rows;
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Int32(1), Value::String("a".to_string()), Value::Int32(1)],
+                vec![Value::Int32(2), Value::String("bee".to_string()), Value::Int32(9)],
+                vec![Value::Int32(3), Value::String("c".to_string()), Value::Null],
+            ]
+        );
+
+        // The conflict column must be UNIQUE or a PRIMARY KEY
+        assert!(matches!(
+            db.execute("INSERT INTO Users (ID, Name) VALUES (4, 'd') ON CONFLICT (Name) DO NOTHING"),
+            Err(DbError::ValidationError(_))
+        ));
+        // Updates cannot create duplicates or orphan child rows
+        assert!(db.execute("INSERT INTO Users (ID) VALUES (1) ON CONFLICT (ID) DO UPDATE SET ID = 2").is_err());
+        db.execute("INSERT INTO Orders (OrderID, UserID) VALUES (10, 1)").unwrap();
+        assert!(db.execute("INSERT INTO Users (ID) VALUES (1) ON CONFLICT (ID) DO UPDATE SET ID = 7").is_err());
+        assert!(db.execute("INSERT INTO Users (ID) VALUES (1) ON CONFLICT (ID, Name) DO NOTHING").is_err());
+        assert!(db.execute("INSERT INTO Users (ID) VALUES (1) ON CONFLICT (ID) DO SOMETHING").is_err());
+        assert_eq!(db.execute("SELECT COUNT(*) FROM Users").unwrap().rows, vec![vec![Value::Int32(3)]]);
+    }
+
+    #[test]
+    fn test_multi_row_upsert() {
+        let mut db = test_db("multi_row_upsert");
+        db.execute("CREATE TABLE Stock (SKU INT PRIMARY KEY, Qty INT, Label TEXT UNIQUE)").unwrap();
+        db.execute("INSERT INTO Stock VALUES (1, 10, 'a'), (2, 20, 'b')").unwrap();
+        let stock = |db: &TestDb| db.query("SELECT SKU, Qty FROM Stock").unwrap();
+        let row = |sku: i32, qty: i32| vec![Value::Int32(sku), Value::Int32(qty)];
+
+        // Rows are taken in order, so a later row updates one inserted or updated before it
+        let result = db
+            .execute("INSERT INTO Stock VALUES (2, 5, 'b'), (3, 1, 'c'), (3, 2, 'c'), (2, 5, 'b') ON CONFLICT (SKU) DO UPDATE SET Qty = Qty + EXCLUDED.Qty")
+            .unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(1), Value::Int32(3)]]);
+        assert_eq!(stock(&db), vec![row(1, 10), row(2, 30), row(3, 3)]);
+        let result = db.execute("INSERT INTO Stock VALUES (1, 0, 'a'), (4, 4, 'd'), (4, 9, 'd') ON CONFLICT (SKU) DO NOTHING").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(1), Value::Int32(0)]]);
+
+        // A failing row leaves every other row of the statement unapplied
+        assert!(db
+            .execute("INSERT INTO Stock VALUES (1, 1, 'a'), (5, 5, 'e'), (2, 1, 'b') ON CONFLICT (SKU) DO UPDATE SET Label = 'a'")
+            .is_err());
+        assert_eq!(stock(&db), vec![row(1, 10), row(2, 30), row(3, 3), row(4, 4)]);
+        db.reopen();
+        assert_eq!(stock(&db), vec![row(1, 10), row(2, 30), row(3, 3), row(4, 4)]);
+    }
+
+    #[test]
+    fn test_upsert_in_transaction() {
+        let mut db = test_db("upsert_tx");
+        db.execute("CREATE TABLE Stock (SKU INT PRIMARY KEY, Qty INT)").unwrap();
+        db.execute("INSERT INTO Stock VALUES (1, 10)").unwrap();
+        let stock = |db: &TestDb| db.query("SELECT SKU, Qty FROM Stock").unwrap();
+        let row = |sku: i32, qty: i32| vec![Value::Int32(sku), Value::Int32(qty)];
+
+        // The transaction sees its own upserts, which others see only once it commits
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Stock VALUES (2, 1)").unwrap();
+        let result = db.execute("INSERT INTO Stock VALUES (1, 5), (2, 5), (3, 5) ON CONFLICT (SKU) DO UPDATE SET Qty = EXCLUDED.Qty + Qty").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(1), Value::Int32(2)]]);
+        assert_eq!(stock(&db), vec![row(1, 15), row(2, 6), row(3, 5)]);
+        let mut other = QueryEngine::new(db.storage());
+        assert_eq!(other.execute(query::parser::parse_query("SELECT SKU, Qty FROM Stock").unwrap()).unwrap(), vec![row(1, 10)]);
+        db.execute("COMMIT").unwrap();
+        assert_eq!(stock(&db), vec![row(1, 15), row(2, 6), row(3, 5)]);
+
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Stock VALUES (1, 0) ON CONFLICT (SKU) DO UPDATE SET Qty = 0").unwrap();
+        db.execute("ROLLBACK").unwrap();
+        db.reopen();
+        assert_eq!(stock(&db), vec![row(1, 15), row(2, 6), row(3, 5)]);
+    }
+
+    #[test]
+    fn test_rename_table_and_column() {
+        let mut db = test_db("rename");
+        db.execute("CREATE TABLE Users (ID INT PRIMARY KEY, Name TEXT, Age INT CHECK (Age >= 0))").unwrap();
+        db.execute("CREATE TABLE Orders (OrderID INT, UserID INT REFERENCES Users(ID))").unwrap();
+        db.execute("INSERT INTO Users (ID, Name, Age) VALUES (1, 'Age', 30)").unwrap();
+        db.execute("INSERT INTO Orders (OrderID, UserID) VALUES (10, 1)").unwrap();
+        db.execute("INSERT INTO Users (ID, Name, Age) VALUES (2, 'Bo', 41)").unwrap(); // still pending
+
+        db.execute("ALTER TABLE Users RENAME TO Members").unwrap();
+        assert!(db.execute("SELECT ID FROM Users").is_err());
+        let rows = db.execute("SELECT ID, Name FROM Members").unwrap().rows;
+        assert_eq!(rows.len(), 2);
+        // The foreign key follows the table
+        assert!(db.execute("INSERT INTO Orders (OrderID, UserID) VALUES (11, 2)").is_ok());
+        assert!(db.execute("INSERT INTO Orders (OrderID, UserID) VALUES (12, 9)").is_err());
+        assert!(db.execute("DELETE FROM Members WHERE ID = 1").is_err());
+
+        db.execute("ALTER TABLE Members RENAME COLUMN Age TO Years").unwrap();
+        assert!(db.execute("SELECT Age FROM Members").is_err());
+        assert_eq!(
+            db.execute("SELECT Years FROM Members WHERE Name = 'Age'").unwrap().rows,
+            vec![vec![Value::Int32(30)]]
+        );
+        // The CHECK now tests the renamed column, and quoted literals were left alone
+        assert!(db.execute("INSERT INTO Members (ID, Name, Years) VALUES (3, 'Cy', -1)").is_err());
+        db.execute("ALTER TABLE Members RENAME ID TO MemberID").unwrap();
+        assert!(db.execute("INSERT INTO Members (MemberID, Name, Years) VALUES (1, 'Dup', 5)").is_err());
+        assert_eq!(
+            db.execute("SELECT Name FROM Members WHERE MemberID = 2").unwrap().rows,
+            vec![vec![Value::String("Bo".to_string())]]
+        );
+
+        assert!(matches!(db.execute("ALTER TABLE Members RENAME TO Orders"), Err(DbError::SchemaError(_))));
+        assert!(matches!(db.execute("ALTER TABLE Members RENAME COLUMN Name TO Years"), Err(DbError::SchemaError(_))));
+        assert!(db.execute("ALTER TABLE Members RENAME COLUMN Missing TO Other").is_err());
+
+        // Everything survives a reopen under the new names
+        db.reopen();
+        assert_eq!(db.execute("SELECT COUNT(*) FROM Members").unwrap().rows, vec![vec![Value::Int32(2)]]);
+        assert_eq!(
+            db.execute("SELECT Years FROM Members WHERE MemberID = 2").unwrap().rows,
+            vec![vec![Value::Int32(41)]]
+        );
+        let sql = db.storage().read().unwrap().dump_sql(None).unwrap();
+        assert!(sql.contains("REFERENCES Members(MemberID)"));
+        assert!(sql.contains("CHECK (Years >= 0)"));
+        assert!(!std::path::Path::new(&format!("{}/columns/Users_ID.dat", db.dir())).exists());
+    }
+
+    #[test]
+    fn test_is_null_predicates() {
+        let db = test_db("is_null");
+        db.execute("CREATE TABLE Contacts (ID INT, Email TEXT, Age INT)").unwrap();
+        db.execute("INSERT INTO Contacts (ID, Email, Age) VALUES (1, 'a@x.org', 30)").unwrap();
+        db.execute("INSERT INTO Contacts (ID, Email, Age) VALUES (2, NULL, 40)").unwrap();
+        db.storage().write().unwrap().checkpoint().unwrap();
+        db.execute("INSERT INTO Contacts (ID, Email, Age) VALUES (3, NULL, NULL)").unwrap();
+        db.execute("INSERT INTO Contacts (ID, Email, Age) VALUES (4, 'd@x.org', 50)").unwrap();
+
+        let ids = |sql: &str| -> Vec<Value> { db.execute(sql).unwrap().rows.into_iter().map(|row| row[0].clone()).collect() };
+        assert_eq!(ids("SELECT ID FROM Contacts WHERE Email IS NULL"), vec![Value::Int32(2), Value::Int32(3)]);
+        assert_eq!(ids("SELECT ID FROM Contacts WHERE Email is not null"), vec![Value::Int32(1), Value::Int32(4)]);
+        // Equality with NULL never matches
+        assert!(ids("SELECT ID FROM Contacts WHERE Email = NULL").is_empty());
+        assert_eq!(ids("SELECT ID FROM Contacts WHERE Email IS NULL AND Age > 35"), vec![Value::Int32(2)]);
+        assert_eq!(ids("SELECT ID FROM Contacts WHERE Age IS NULL OR ID = 1"), vec![Value::Int32(1), Value::Int32(3)]);
+        assert_eq!(
+            db.execute("SELECT COUNT(*) FROM Contacts WHERE Age IS NOT NULL").unwrap().rows,
+            vec![vec![Value::Int32(3)]]
+        );
+        let plan = db.execute("EXPLAIN SELECT ID FROM Contacts WHERE Email IS NOT NULL").unwrap();
+        assert_eq!(plan.rows[1][0], Value::String("  -> Parallel filter: Email IS NOT NULL".to_string()));
+        assert!(db.execute("SELECT ID FROM Contacts WHERE Email IS 5").is_err());
+
+        assert_eq!(db.execute("DELETE FROM Contacts WHERE Email IS NULL").unwrap().rows, vec![vec![Value::Int32(2)]]);
+        assert_eq!(ids("SELECT ID FROM Contacts"), vec![Value::Int32(1), Value::Int32(4)]);
+    }
+
+    #[test]
+    fn test_validate_without_executing() {
+        let db = test_db("validate");
+        db.execute("CREATE TABLE Orders (ID INT PRIMARY KEY, Item TEXT NOT NULL, Qty INT)").unwrap();
+        db.execute("INSERT INTO Orders (ID, Item, Qty) VALUES (1, 'pen', 3)").unwrap();
+        let engine = QueryEngine::new(db.storage());
+        let validate = |sql: &str| engine.validate(&query::parser::parse_query(sql).unwrap());
+
+        assert_eq!(
+            validate("DELETE FROM Orders WHERE Qty > 2 AND Item = 'pen'").unwrap(),
+            vec!["Table: Orders".to_string(), "Condition: (Qty > 2 AND Item = 'pen')".to_string()]
+        );
+        assert_eq!(
+            validate("SELECT * FROM Orders").unwrap(),
+            vec!["Table: Orders".to_string(), "Columns: ID, Item, Qty".to_string()]
+        );
+        assert_eq!(validate("EXPLAIN SELECT SUM(Qty) FROM Orders").unwrap()[1], "Columns: SUM(Qty)");
+        assert!(validate("INSERT INTO Orders (ID, Item) VALUES (2, 'ink')").is_ok());
+
+        assert!(matches!(validate("DELETE FROM Missing"), Err(DbError::InvalidData(_))));
+        assert!(matches!(validate("DELETE FROM Orders WHERE Price > 2"), Err(DbError::InvalidData(_))));
+        assert!(matches!(validate("DELETE FROM Orders WHERE Qty = 'three'"), Err(DbError::TypeMismatch(_))));
+        assert!(validate("SELECT Qty, Price FROM Orders").is_err());
+        assert!(validate("SELECT SUM(Item) FROM Orders").is_err());
+        assert!(matches!(validate("INSERT INTO Orders (ID, Qty) VALUES (2, 5)"), Err(DbError::ValidationError(_))));
+        assert!(validate("INSERT INTO Orders (ID, Item, Qty) VALUES (2, 'ink', 'many')").is_err());
+        assert!(validate("ALTER TABLE Orders DROP COLUMN Price").is_err());
+        assert!(validate("CREATE TABLE Orders (ID INT)").is_err());
+
+        // Nothing ran: the table and its row are untouched
+        assert!(validate("DROP TABLE Orders").is_ok());
+        assert!(validate("DELETE FROM Orders").is_ok());
+        assert_eq!(db.query("SELECT ID FROM Orders").unwrap(), vec![vec![Value::Int32(1)]]);
+    }
+
+    #[test]
+    fn test_result_headers_without_rows() {
+        let db = test_db("headers");
+        db.execute("CREATE TABLE Books (ID INT, Title TEXT, Price FLOAT)").unwrap();
+        db.execute("INSERT INTO Books (ID, Title, Price) VALUES (1, 'Dune', 9.5)").unwrap();
+
+        let empty = db.execute("SELECT * FROM Books WHERE ID > 5").unwrap();
+        assert_eq!(empty.columns, vec!["ID", "Title", "Price"]);
+        assert!(empty.rows.is_empty());
+        assert_eq!(empty.to_json().unwrap(), "[]");
+        let projected = db.execute("SELECT Title, CAST(Price AS INT) FROM Books WHERE Title = 'Emma'").unwrap();
+        assert_eq!(projected.columns, vec!["Title", "CAST(Price AS INT)"]);
+        assert!(projected.to_table(20).starts_with("+-------+"));
+        assert_eq!(
+            db.execute("SELECT MIN(Price), COUNT(*) FROM Books WHERE ID = 7").unwrap(),
+            QueryResult::new(vec!["MIN(Price)".to_string(), "COUNT(*)".to_string()], vec![vec![Value::Null, Value::Int32(0)]])
+        );
+        // Statements that cannot produce rows still have no headers
+        assert_eq!(db.execute("INSERT INTO Books (ID, Title, Price) VALUES (2, 'Emma', 7.0)").unwrap(), QueryResult::default());
+    }
+
+    #[test]
+    fn test_select_without_from() {
+        let db = test_db("no_from");
+
+        let result = db.execute("SELECT 1, 'hi', CAST('2.5' AS FLOAT), NULL").unwrap();
+        assert_eq!(result.columns, vec!["1", "'hi'", "CAST('2.5' AS FLOAT)", "NULL"]);
+        assert_eq!(
+            result.rows,
+            vec![vec![Value::Int32(1), Value::String("hi".to_string()), Value::Float32(OrderedFloat(2.5)), Value::Null]]
+        );
+        assert_eq!(db.query("SELECT CAST(42 AS TEXT)").unwrap(), vec![vec![Value::String("42".to_string())]]);
+        assert_eq!(
+            db.query("EXPLAIN SELECT 1").unwrap(),
+            vec![vec![Value::String("Project [1]".to_string())], vec![Value::String("  -> Single row without a table".to_string())]]
+        );
+
+        assert!(matches!(db.execute("SELECT Price"), Err(DbError::QueryError(_))));
+        assert!(matches!(db.execute("SELECT CAST(Price AS INT)"), Err(DbError::QueryError(_))));
+        assert!(matches!(db.execute("SELECT *"), Err(DbError::QueryError(_))));
+        assert!(matches!(db.execute("SELECT COUNT(*)"), Err(DbError::QueryError(_))));
+        assert!(matches!(db.execute("SELECT 1 WHERE 1 = 1"), Err(DbError::QueryError(_))));
+    }
+
+    #[test]
+    fn test_arithmetic_expressions() {
+        let db = test_db("arithmetic");
+        let value = |sql: &str| db.query(sql).unwrap()[0][0].clone();
+
+        let result = db.execute("SELECT 1 + 2").unwrap();
+        assert_eq!(result.columns, vec!["1 + 2"]);
+        assert_eq!(result.rows, vec![vec![Value::Int32(3)]]);
+        // Multiplication binds tighter, and operators of one precedence group from the left
+        assert_eq!(value("SELECT 2 + 3 * 4"), Value::Int32(14));
+        assert_eq!(value("SELECT (2 + 3) * 4"), Value::Int32(20));
+        assert_eq!(value("SELECT 10 - 4 - 3"), Value::Int32(3));
+        assert_eq!(value("SELECT 7 / 2"), Value::Int32(3));
+        assert_eq!(value("SELECT 7 % 4"), Value::Int32(3));
+        assert_eq!(value("SELECT 5 - -2"), Value::Int32(7));
+        assert_eq!(value("SELECT 1.5e-1 * 2"), Value::Float32(OrderedFloat(0.3)));
+        assert_eq!(value("SELECT 1 + 2.5"), Value::Float32(OrderedFloat(3.5)));
+        assert_eq!(value("SELECT 2147483648 + 1"), Value::Int64(2147483649));
+        assert_eq!(value("SELECT 1 + NULL"), Value::Null);
+
+        assert!(matches!(db.execute("SELECT 1 / 0"), Err(DbError::QueryError(ref s)) if s.contains("Division by zero")));
+        assert!(matches!(db.execute("SELECT 2147483647 + 1"), Err(DbError::QueryError(ref s)) if s.contains("overflow")));
+        assert!(matches!(db.execute("SELECT 'a' + 1"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.execute("SELECT 'a' + 'b'"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.execute("SELECT 1 +"), Err(DbError::QueryError(_))));
+
+        // Columns take part like any operand
+        db.execute("CREATE TABLE Items (ID INT, Price FLOAT, Qty INT)").unwrap();
+        db.execute("INSERT INTO Items VALUES (1, 2.5, 4), (2, 1.0, NULL)").unwrap();
+        assert_eq!(
+            db.query("SELECT ID, Price * Qty FROM Items").unwrap(),
+            vec![vec![Value::Int32(1), Value::Float32(OrderedFloat(10.0))], vec![Value::Int32(2), Value::Null]]
+        );
+        assert!(matches!(db.execute("SELECT Qty + 1"), Err(DbError::QueryError(_))));
+    }
+
+    #[test]
+    fn test_scalar_functions() {
+        let db = test_db("scalar_functions");
+        let value = |sql: &str| db.query(sql).unwrap()[0][0].clone();
+        let text = |s: &str| Value::String(s.to_string());
+
+        let result = db.execute("SELECT UPPER('hi')").unwrap();
+        assert_eq!(result.columns, vec!["UPPER('hi')"]);
+        assert_eq!(result.rows, vec![vec![text("HI")]]);
+        assert_eq!(value("SELECT lower('Hi There')"), text("hi there"));
+        assert_eq!(value("SELECT TRIM('  x ')"), text("x"));
+        assert_eq!(value("SELECT LENGTH('héllo')"), Value::Int32(5));
+        assert_eq!(value("SELECT ABS(-3) + LENGTH(UPPER('ab'))"), Value::Int32(5));
+        assert_eq!(value("SELECT UPPER(NULL)"), Value::Null);
+
+        assert!(matches!(db.execute("SELECT UPPER(1)"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.execute("SELECT ABS('x')"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.execute("SELECT UPPER('a', 'b')"), Err(DbError::QueryError(_))));
+        assert!(matches!(
+            db.execute("SELECT REVERSE('hi')"),
+            Err(DbError::QueryError(ref s)) if s.contains("Unsupported function REVERSE")
+        ));
+
+        db.execute("CREATE TABLE Users (ID INT, Name TEXT)").unwrap();
+        db.execute("INSERT INTO Users VALUES (1, 'ada')").unwrap();
+        assert_eq!(db.query("SELECT UPPER(Name) FROM Users").unwrap(), vec![vec![text("ADA")]]);
+    }
+
+    #[test]
+    fn test_negative_literals() {
+        let db = test_db("negative");
+        db.execute("CREATE TABLE Accounts (ID INT, Balance INT DEFAULT -1 CHECK (Balance >= -2147483648), Rate FLOAT)").unwrap();
+        db.execute("INSERT INTO Accounts (ID, Balance, Rate) VALUES (1, -50, -0.25)").unwrap();
+        db.execute("INSERT INTO Accounts VALUES (2, -2147483648, -1.5)").unwrap();
+        db.execute("INSERT INTO Accounts (ID, Balance, Rate) VALUES (3, -0, 2.0)").unwrap();
+        db.execute("INSERT INTO Accounts (ID, Rate) VALUES (4, 0.5)").unwrap();
+
+        let ids = |sql: &str| -> Vec<Value> { db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect() };
+        assert_eq!(ids("SELECT ID FROM Accounts WHERE Balance = -50"), vec![Value::Int32(1)]);
+        assert_eq!(ids("SELECT ID FROM Accounts WHERE Balance = 0"), vec![Value::Int32(3)]);
+        assert_eq!(ids("SELECT ID FROM Accounts WHERE Balance <= -2147483648"), vec![Value::Int32(2)]);
+        assert_eq!(ids("SELECT ID FROM Accounts WHERE Balance = -1"), vec![Value::Int32(4)]);
+        assert_eq!(ids("SELECT ID FROM Accounts WHERE Rate < -0.5"), vec![Value::Int32(2)]);
+        assert_eq!(ids("SELECT ID FROM Accounts WHERE Rate > -0.5 AND Balance < 0"), vec![Value::Int32(1), Value::Int32(4)]);
+
+        assert_eq!(
+            db.query("SELECT -7, -0.5, CAST(-3 AS TEXT), +2").unwrap(),
+            vec![vec![
+                Value::Int32(-7),
+                Value::Float32(OrderedFloat(-0.5)),
+                Value::String("-3".to_string()),
+                Value::Int32(2)
+            ]]
+        );
+        // One past i32::MIN does not fit
+        assert!(db.execute("INSERT INTO Accounts VALUES (5, -2147483649, 0.0)").is_err());
+        assert!(db.execute("SELECT ID FROM Accounts WHERE Balance = --5").is_err());
+    }
+
+    #[test]
+    fn test_integer_sum_overflow() {
+        let mut db = test_db("int_sum");
+        db.execute("CREATE TABLE Transfers (ID BIGINT PRIMARY KEY, Amount INT, Total BIGINT DEFAULT 0 CHECK (Total >= 0))").unwrap();
+        for id in 1..=3 {
+            db.execute(&format!("INSERT INTO Transfers (ID, Amount, Total) VALUES ({}, 2000000000, 4000000000)", id)).unwrap();
+        }
+        db.execute("INSERT INTO Transfers (ID, Amount) VALUES (4, -5)").unwrap();
+        db.storage().write().unwrap().checkpoint().unwrap();
+        db.execute("INSERT INTO Transfers VALUES (5, 1, 9223372036854775807)").unwrap();
+
+        // The INT column's total no longer fits in INT but does in BIGINT
+        assert_eq!(
+            db.query("SELECT SUM(Amount), AVG(Amount) FROM Transfers WHERE Total < 9223372036854775807").unwrap(),
+            vec![vec![Value::Int64(5_999_999_995), Value::Float32(OrderedFloat(1_499_999_998.75))]]
+        );
+        assert_eq!(
+            db.query("SELECT SUM(Total) FROM Transfers WHERE ID <= 4").unwrap(),
+            vec![vec![Value::Int64(12_000_000_000)]]
+        );
+        assert!(matches!(db.execute("SELECT SUM(Total) FROM Transfers"), Err(DbError::InvalidData(_))));
+        assert_eq!(db.query("SELECT SUM(Amount) FROM Transfers WHERE ID > 9").unwrap(), vec![vec![Value::Int64(0)]]);
+
+        // INT literals compare with and fill BIGINT columns
+        let ids = |sql: &str| -> Vec<Value> { db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect() };
+        assert_eq!(ids("SELECT ID FROM Transfers WHERE ID = 2"), vec![Value::Int64(2)]);
+        assert_eq!(ids("SELECT ID FROM Transfers WHERE Total = 0"), vec![Value::Int64(4)]);
+        assert_eq!(ids("SELECT ID FROM Transfers WHERE Total > 4000000000"), vec![Value::Int64(5)]);
+        assert!(db.execute("INSERT INTO Transfers (ID, Amount) VALUES (2, 1)").is_err());
+        assert!(db.execute("INSERT INTO Transfers (ID, Total) VALUES (6, -1)").is_err());
+        assert!(db.execute("INSERT INTO Transfers (ID, Amount) VALUES (6, 3000000000)").is_err());
+
+        assert_eq!(db.query("SELECT CAST(ID AS INT) FROM Transfers WHERE ID = 1").unwrap(), vec![vec![Value::Int32(1)]]);
+        assert!(db.execute("SELECT CAST(Total AS INT) FROM Transfers WHERE ID = 1").is_err());
+        let schema = db.schema().unwrap();
+        assert_eq!(schema.get_table("Transfers").unwrap().columns[2].to_sql(), "Total BIGINT DEFAULT 0 CHECK (Total >= 0)");
+        db.reopen();
+        assert_eq!(
+            db.query("SELECT Total FROM Transfers WHERE ID >= 4").unwrap(),
+            vec![vec![Value::Int64(0)], vec![Value::Int64(i64::MAX)]]
+        );
+    }
+
+    #[test]
+    fn test_parallel_threshold() {
+        let db = test_db("parallel_threshold");
+        for (table, rows) in [("Small", 16), ("Large", 20_000)] {
+            db.execute(&format!("CREATE TABLE {} (ID INT, Label TEXT)", table)).unwrap();
+            let handle = db.storage();
+            let mut storage = handle.write().unwrap();
+            let values = (0..rows).map(|i| vec![Value::Int32(i), Value::String(format!("label{}", i % 7))]).collect();
+            storage.insert_rows(table, &[], values).unwrap();
+            storage.checkpoint().unwrap();
+        }
+
+        // An OR across columns cannot be pushed down, so every row goes through the filter loop.
+        // Where serial filtering stops paying is measured by benches/parallel_threshold.rs.
+        let mut engine = QueryEngine::new(db.storage());
+        assert_eq!(engine.parallel_threshold(), query::planner::DEFAULT_PARALLEL_THRESHOLD);
+        for table in ["Small", "Large"] {
+            let sql = format!("SELECT ID, CAST(ID AS TEXT) FROM {} WHERE ID < 10 OR Label = 'label3'", table);
+            let mut results = Vec::new();
+            for threshold in [usize::MAX, 0] {
+                engine.set_parallel_threshold(threshold);
+                results.push(engine.execute(query::parser::parse_query(&sql).unwrap()).unwrap());
+            }
+            assert!(!results[0].is_empty());
+            assert_eq!(results[0], results[1]);
+        }
+        assert_eq!(
+            db.query("SELECT ID FROM Small WHERE ID < 2 OR Label = 'label0'").unwrap(),
+            vec![vec![Value::Int32(0)], vec![Value::Int32(1)], vec![Value::Int32(7)], vec![Value::Int32(14)]]
+        );
+    }
+
+    #[test]
+    fn test_parallel_select_first_error() {
+        let db = test_db("first_error");
+        db.execute("CREATE TABLE Codes (ID INT, Code TEXT)").unwrap();
+        let columns = vec!["ID".to_string(), "Code".to_string()];
+        {
+            let handle = db.storage();
+            let mut storage = handle.write().unwrap();
+            for i in 0..4096 {
+                // Rows 1500 and every 100th after 2000 hold codes that are not numbers
+                let code = if i == 1500 || (i > 2000 && i % 100 == 0) { format!("bad{}", i) } else { i.to_string() };
+                storage.insert_row("Codes", &columns, vec![Value::Int32(i), Value::String(code)]).unwrap();
+            }
+            storage.checkpoint().unwrap();
+        }
+        db.set_parallel_threshold(0).unwrap();
+
+        for _ in 0..20 {
+            let result = db.execute("SELECT ID, CAST(Code AS INT) FROM Codes WHERE ID >= 0 OR Code = 'x'");
+            assert!(matches!(result, Err(DbError::InvalidData(msg)) if msg == "Cannot cast 'bad1500' to Int32"));
+        }
+        let result = db.execute("SELECT CAST(Code AS INT) FROM Codes WHERE ID < 1500 AND ID > 1497").unwrap();
+        assert_eq!(result.rows, vec![vec![Value::Int32(1498)], vec![Value::Int32(1499)]]);
+    }
+
+    #[test]
+    fn test_information_schema() {
+        let db = test_db("catalog");
+        db.execute("CREATE TABLE Users (ID INT PRIMARY KEY, Name TEXT NOT NULL)").unwrap();
+        db.execute("CREATE TABLE Orders (ID INT PRIMARY KEY, UserID INT REFERENCES Users(ID) ON DELETE CASCADE, Qty INT DEFAULT 1 CHECK (Qty > 0), Code TEXT UNIQUE)").unwrap();
+        db.execute("INSERT INTO Users (ID, Name) VALUES (1, 'ann')").unwrap();
+        db.execute("INSERT INTO Users (ID, Name) VALUES (2, 'bob')").unwrap();
+
+        let tables = db.execute("SELECT * FROM information_schema.tables").unwrap();
+        assert_eq!(tables.columns, vec!["table_name", "column_count", "row_count"]);
+        assert_eq!(
+            tables.rows,
+            vec![
+                vec![Value::String("Orders".to_string()), Value::Int32(4), Value::Int64(0)],
+                vec![Value::String("Users".to_string()), Value::Int32(2), Value::Int64(2)],
+            ]
+        );
+        // The INT literal is widened to the BIGINT row_count
+        assert_eq!(
+            db.query("SELECT table_name FROM information_schema.tables WHERE row_count > 1").unwrap(),
+            vec![vec![Value::String("Users".to_string())]]
+        );
+
+        let text = |s: &str| Value::String(s.to_string());
+        let columns = db
+            .query("SELECT column_name, ordinal_position, data_type, is_nullable, column_default, column_key, check_clause, foreign_key, indexes FROM information_schema.columns WHERE table_name = 'Orders'")
+            .unwrap();
+        assert_eq!(
+            columns,
+            vec![
+                vec![text("ID"), Value::Int32(1), text("INT"), text("NO"), Value::Null, text("PRIMARY KEY"), Value::Null, Value::Null, text("hash, unique")],
+                vec![text("UserID"), Value::Int32(2), text("INT"), text("YES"), Value::Null, Value::Null, Value::Null, text("Users(ID) ON DELETE CASCADE"), Value::Null],
+                vec![text("Qty"), Value::Int32(3), text("INT"), text("YES"), text("1"), Value::Null, text("Qty > 0"), Value::Null, Value::Null],
+                vec![text("Code"), Value::Int32(4), text("TEXT"), text("YES"), Value::Null, text("UNIQUE"), Value::Null, Value::Null, text("unique")],
+            ]
+        );
+        assert_eq!(
+            db.query("SELECT column_name FROM INFORMATION_SCHEMA.COLUMNS WHERE is_nullable = 'NO' AND table_name = 'Users'").unwrap(),
+            vec![vec![text("ID")], vec![text("Name")]]
+        );
+
+        // A created table shows up at once; unknown catalog columns fail like any other
+        db.execute("CREATE TABLE Tags (Label TEXT)").unwrap();
+        assert_eq!(db.query("SELECT table_name FROM information_schema.tables").unwrap().len(), 3);
+        assert!(matches!(db.query("SELECT owner FROM information_schema.tables"), Err(DbError::InvalidData(_))));
+
+        let engine = QueryEngine::new(db.storage());
+        let validate = |sql: &str| engine.validate(&query::parser::parse_query(sql).unwrap());
+        assert_eq!(validate("SELECT table_name FROM information_schema.tables").unwrap()[0], "Table: information_schema.tables");
+        let plan = db.query("EXPLAIN SELECT column_name FROM information_schema.columns WHERE table_name = 'Tags'").unwrap();
+        assert_eq!(plan.last().unwrap()[0], text("    -> Catalog read of information_schema.columns built from the schema"));
+    }
+
+    #[test]
+    fn test_schema_catalog_versioning() {
+        let mut db = test_db("catalog_version");
+        let catalog_path = format!("{}/{}", db.dir(), schema::CATALOG_FILE);
+        let version = |db: &Database| db.storage().read().unwrap().schema().version();
+        assert_eq!(version(&db), 0);
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY, Label TEXT)").unwrap();
+        db.execute("CREATE TABLE Tags (Name TEXT)").unwrap();
+        db.execute("ALTER TABLE Items RENAME COLUMN Label TO Title").unwrap();
+        db.execute("DROP TABLE Tags").unwrap();
+        // Row changes and failed DDL leave the version alone
+        db.execute("INSERT INTO Items (ID, Title) VALUES (1, 'pen')").unwrap();
+        assert!(db.execute("CREATE TABLE Items (ID INT)").is_err());
+        assert_eq!(version(&db), 4);
+        assert!(!std::path::Path::new(&format!("{}.tmp", catalog_path)).exists());
+
+        db.reopen();
+        assert_eq!(version(&db), 4);
+        assert_eq!(db.query("SELECT Title FROM Items").unwrap(), vec![vec![Value::String("pen".to_string())]]);
+    }
+
+    #[test]
+    fn test_schema_catalog_format_version() {
+        let mut db = test_db("catalog_format");
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY, Title TEXT)").unwrap();
+        db.close();
+        let catalog_path = format!("{}/{}", db.dir(), schema::CATALOG_FILE);
+        let catalog: serde_json::Value = serde_json::from_str(&fs::read_to_string(&catalog_path).unwrap()).unwrap();
+        assert_eq!(catalog["format_version"], schema::CATALOG_FORMAT_VERSION);
+
+        // A catalog from before versioning holds only the table map
+        fs::write(&catalog_path, catalog["tables"].to_string()).unwrap();
+        let legacy = Schema::load(db.dir()).unwrap();
+        assert_eq!(legacy.version(), 0);
+        assert!(legacy.get_table("Items").is_some_and(|t| t.get_column("Title").is_some()));
+
+        // A catalog written by a newer build is refused
+        let mut newer = catalog.clone();
+        newer["format_version"] = serde_json::json!(schema::CATALOG_FORMAT_VERSION + 1);
+        fs::write(&catalog_path, newer.to_string()).unwrap();
+        assert!(matches!(Database::open(db.dir()), Err(DbError::VersionError(_))));
+    }
+
+    // Moves the files of `table.column` back to the names they had before column files were
+    // scoped per table, when the metadata did not record the table either
+    fn to_legacy_column_layout(data_dir: &str, table: &str, column: &str) {
+        let data = storage::column::ColumnStore::path(data_dir, table, column);
+        fs::rename(&data, format!("{}/columns/{}.dat", data_dir, column)).unwrap();
+        let metadata = schema::metadata::BlockMetadata::path(data_dir, table, column);
+        let mut json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&metadata).unwrap()).unwrap();
+        json.as_object_mut().unwrap().remove("table_name");
+        fs::write(format!("{}/metadata/{}.json", data_dir, column), json.to_string()).unwrap();
+        fs::remove_file(&metadata).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_column_layout_is_migrated() {
+        let mut db = test_db("legacy_layout");
+        db.execute("CREATE TABLE Users (ID INT, Name TEXT)").unwrap();
+        db.execute("INSERT INTO Users (ID, Name) VALUES (1, 'Ada'), (2, 'Grace')").unwrap();
+        db.storage().write().unwrap().checkpoint().unwrap();
+        db.close();
+        to_legacy_column_layout(db.dir(), "Users", "ID");
+        to_legacy_column_layout(db.dir(), "Users", "Name");
+        // A build without the migration left empty per-table files beside the old ones
+        fs::write(storage::column::ColumnStore::path(db.dir(), "Users", "Name"), "").unwrap();
+
+        db.reopen();
+        let rows = db.query("SELECT ID, Name FROM Users").unwrap();
+        assert_eq!(rows, vec![
+            vec![Value::Int32(1), Value::String("Ada".to_string())],
+            vec![Value::Int32(2), Value::String("Grace".to_string())],
+        ]);
+        assert!(!std::path::Path::new(&format!("{}/columns/ID.dat", db.dir())).exists());
+        assert!(!std::path::Path::new(&format!("{}/metadata/Name.json", db.dir())).exists());
+        db.execute("INSERT INTO Users (ID, Name) VALUES (3, 'Alan')").unwrap();
+        db.storage().write().unwrap().checkpoint().unwrap();
+        db.reopen();
+        assert_eq!(db.query("SELECT COUNT(*) FROM Users").unwrap(), vec![vec![Value::Int32(3)]]);
+    }
+
+    #[test]
+    fn test_legacy_shared_column_files_are_refused() {
+        let mut db = test_db("legacy_shared");
+        db.execute("CREATE TABLE Users (ID INT, Name TEXT)").unwrap();
+        db.execute("CREATE TABLE Orders (ID INT, Total INT)").unwrap();
+        db.execute("INSERT INTO Users (ID, Name) VALUES (1, 'Ada')").unwrap();
+        db.storage().write().unwrap().checkpoint().unwrap();
+        db.close();
+        to_legacy_column_layout(db.dir(), "Users", "ID");
+
+        // Both tables wrote ID to the same file, so its rows cannot be assigned to either
+        match Database::open(db.dir()) {
+            Err(DbError::VersionError(message)) => assert!(message.contains("column ID"), "{}", message),
+            other => panic!("expected a VersionError, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_migrations() {
+        let migrations_dir = TestDir::new("migrations");
+        fs::create_dir_all(&migrations_dir).unwrap();
+        let write = |name: &str, sql: &str| fs::write(format!("{}/{}", migrations_dir, name), sql).unwrap();
+        write("0001_users.sql", "CREATE TABLE Users (ID INT PRIMARY KEY, Name TEXT);\nINSERT INTO Users (ID, Name) VALUES (1, 'ann');");
+        write("0002_orders.sql", "-- Orders belong to users\nCREATE TABLE Orders (ID INT PRIMARY KEY, UserID INT REFERENCES Users(ID));");
+        write("README.txt", "not a migration");
+
+        let db = test_db("migrate");
+        assert_eq!(db.migrate(&migrations_dir).unwrap(), vec!["0001_users.sql", "0002_orders.sql"]);
+        // Applied versions are recorded, so running again does nothing
+        assert!(db.migrate(&migrations_dir).unwrap().is_empty());
+        assert_eq!(
+            db.query("SELECT version, name FROM schema_migrations").unwrap(),
+            vec![
+                vec![Value::Int32(1), Value::String("0001_users.sql".to_string())],
+                vec![Value::Int32(2), Value::String("0002_orders.sql".to_string())],
+            ]
+        );
+
+        // A failing migration is undone as a whole, DDL included
+        write("0003_tags.sql", "CREATE TABLE Tags (Label TEXT);\nINSERT INTO Users (ID, Name) VALUES (2, 'bob');\nINSERT INTO Users (ID, Name) VALUES (1, 'dup');");
+        match db.migrate(&migrations_dir) {
+            Err(DbError::MigrationError(msg)) => assert!(msg.starts_with("0003_tags.sql:"), "{}", msg),
+            other => panic!("expected a migration error, got {:?}", other),
+        }
+        assert!(db.schema().unwrap().get_table("Tags").is_none());
+        assert_eq!(db.query("SELECT Name FROM Users").unwrap(), vec![vec![Value::String("ann".to_string())]]);
+        assert_eq!(db.query("SELECT COUNT(*) FROM schema_migrations").unwrap(), vec![vec![Value::Int32(2)]]);
+        assert!(!std::path::Path::new(&format!("{}/migration_backup", db.dir())).exists());
+        db.execute("INSERT INTO Orders (ID, UserID) VALUES (10, 1)").unwrap();
+
+        write("0003_tags.sql", "CREATE TABLE Tags (Label TEXT);\nINSERT INTO Users (ID, Name) VALUES (2, 'bob');");
+        assert_eq!(db.migrate(&migrations_dir).unwrap(), vec!["0003_tags.sql"]);
+        assert_eq!(db.query("SELECT COUNT(*) FROM Users").unwrap(), vec![vec![Value::Int32(2)]]);
+        assert_eq!(db.query("SELECT UserID FROM Orders").unwrap(), vec![vec![Value::Int32(1)]]);
+
+        write("cleanup.sql", "DROP TABLE Tags;");
+        assert!(matches!(db.migrate(&migrations_dir), Err(DbError::MigrationError(_))));
+        assert!(matches!(db.migrate("no_such_migrations_dir"), Err(DbError::MigrationError(_))));
+    }
+
+    #[test]
+    fn test_case_insensitive_identifiers() {
+        let db = test_db("identifiers");
+        db.execute("create table Users (ID int primary key, Name text not null)").unwrap();
+        db.execute("Insert Into users (id, NAME) Values (1, 'ann')").unwrap();
+        db.execute("INSERT INTO USERS (Id, name) VALUES (2, 'Sales AND Marketing from HQ')").unwrap();
+
+        // Keywords and unquoted names match in any case; names keep the case they were declared with
+        let rows = db.query("Select id, name from users where ID >= 1 and name = 'ann'").unwrap();
+        assert_eq!(rows, vec![vec![Value::Int32(1), Value::String("ann".to_string())]]);
+        // Keywords inside string literals are not keywords
+        let rows = db.query("SELECT ID FROM Users WHERE Name = 'Sales AND Marketing from HQ'").unwrap();
+        assert_eq!(rows, vec![vec![Value::Int32(2)]]);
+        assert_eq!(db.query("select count(*) from USERS where id > 0").unwrap(), vec![vec![Value::Int32(2)]]);
+        assert_eq!(db.schema().unwrap().get_table("users").unwrap().name, "Users");
+
+        // Double-quoted names match exactly
+        assert_eq!(db.query("SELECT \"ID\" FROM \"Users\" WHERE ID = 2").unwrap(), vec![vec![Value::Int32(2)]]);
+        assert!(db.query("SELECT ID FROM \"users\"").is_err());
+
+        // Names that differ only in case collide
+        assert!(matches!(db.execute("CREATE TABLE users (ID INT)"), Err(DbError::InvalidData(_))));
+        assert!(matches!(db.execute("CREATE TABLE Pairs (A INT, a INT)"), Err(DbError::SchemaError(_))));
+
+        db.execute("delete from users where id = 1").unwrap();
+        assert_eq!(db.query("SELECT COUNT(*) FROM Users").unwrap(), vec![vec![Value::Int32(1)]]);
+    }
+
+    #[test]
+    fn test_quoted_identifiers() {
+        let db = test_db("quoted");
+        // Keywords and names with spaces must be quoted, and keep their exact spelling
+        assert!(matches!(db.execute("CREATE TABLE Orders (ID INT, order INT)"), Err(DbError::ValidationError(_))));
+        assert!(matches!(db.execute("CREATE TABLE Bad-Name (ID INT)"), Err(DbError::ValidationError(_))));
+        assert!(matches!(db.execute("CREATE TABLE \"\" (ID INT)"), Err(DbError::ValidationError(_))));
+        assert!(matches!(db.execute("CREATE TABLE \"a/b\" (ID INT)"), Err(DbError::ValidationError(_))));
+        db.execute("CREATE TABLE \"Order Lines\" (ID INT PRIMARY KEY, \"order\" INT, \"first name\" TEXT)").unwrap();
+        let table = db.schema().unwrap().get_table("\"Order Lines\"").unwrap().clone();
+        assert_eq!(table.name, "Order Lines");
+        assert_eq!(table.columns.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["ID", "order", "first name"]);
+
+        db.execute("INSERT INTO \"Order Lines\" (ID, \"order\", \"first name\") VALUES (1, 7, 'ann')").unwrap();
+        db.execute("INSERT INTO \"Order Lines\" VALUES (2, 8, 'bob')").unwrap();
+        let rows = db.query("SELECT \"first name\", \"order\" FROM \"Order Lines\" WHERE \"order\" > 7").unwrap();
+        assert_eq!(rows, vec![vec![Value::String("bob".to_string()), Value::Int32(8)]]);
+        assert_eq!(db.query("SELECT SUM(\"order\") FROM \"Order Lines\"").unwrap(), vec![vec![Value::Int64(15)]]);
+        // Quoted names resolve exactly
+        assert!(db.query("SELECT \"First Name\" FROM \"Order Lines\"").is_err());
+        assert!(db.query("SELECT ID FROM \"order lines\"").is_err());
+
+        db.execute("ALTER TABLE \"Order Lines\" RENAME COLUMN \"first name\" TO \"given name\"").unwrap();
+        db.execute("DELETE FROM \"Order Lines\" WHERE \"given name\" = 'ann'").unwrap();
+        assert_eq!(db.query("SELECT \"given name\" FROM \"Order Lines\"").unwrap(), vec![vec![Value::String("bob".to_string())]]);
+        // The schema writes such names back quoted
+        assert_eq!(
+            db.schema().unwrap().get_table("\"Order Lines\"").unwrap().create_sql(),
+            "CREATE TABLE \"Order Lines\" (ID INT PRIMARY KEY, \"order\" INT, \"given name\" TEXT);"
+        );
+        db.execute("DROP TABLE \"Order Lines\"").unwrap();
+    }
+
+    #[test]
+    fn test_quoted_column_headers() {
+        let db = test_db("quoted_headers");
+        db.execute("CREATE TABLE \"users\" (ID INT, \"name\" TEXT, \"first name\" TEXT)").unwrap();
+        db.execute("CREATE TABLE Orders (ID INT, UserID INT)").unwrap();
+        db.execute("INSERT INTO users VALUES (1, 'ann', 'Ann')").unwrap();
+        db.execute("INSERT INTO Orders VALUES (10, 1)").unwrap();
+
+        // Headers name the columns, not the quotes they were selected with
+        let result = db.execute("SELECT \"name\", \"first name\", ID FROM \"users\"").unwrap();
+        assert_eq!(result.columns, vec!["name", "first name", "ID"]);
+        let result = db
+            .execute("SELECT \"users\".\"name\", Orders.ID FROM \"users\" JOIN Orders ON \"users\".ID = Orders.UserID")
+            .unwrap();
+        assert_eq!(result.columns, vec!["users.name", "Orders.ID"]);
+        // Expressions are shown as written
+        let result = db.execute("SELECT CAST(\"name\" AS TEXT) FROM \"users\"").unwrap();
+        assert_eq!(result.columns, vec!["CAST(\"name\" AS TEXT)"]);
+    }
+
+    #[test]
+    fn test_max_result_rows() {
+        let db = test_db("max_rows");
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY, Owner INT)").unwrap();
+        db.execute("CREATE TABLE Owners (ID INT PRIMARY KEY)").unwrap();
+        for i in 0..3000 {
+            db.execute(&format!("INSERT INTO Items (ID, Owner) VALUES ({}, {})", i, i % 2)).unwrap();
+        }
+        db.execute("INSERT INTO Owners (ID) VALUES (0)").unwrap();
+        db.execute("INSERT INTO Owners (ID) VALUES (1)").unwrap();
+
+        db.set_max_result_rows(Some(2000)).unwrap();
+        assert!(matches!(db.query("SELECT * FROM Items"), Err(DbError::ResourceExhausted(_))));
+        assert!(matches!(
+            db.query("SELECT Owners.ID, Items.ID FROM Owners JOIN Items ON Owners.ID = Items.Owner"),
+            Err(DbError::ResourceExhausted(_))
+        ));
+        // Results within the cap, and aggregates over any number of rows, are unaffected
+        assert_eq!(db.query("SELECT ID FROM Items WHERE ID >= 1500").unwrap().len(), 1500);
+        assert_eq!(db.query("SELECT COUNT(*) FROM Items").unwrap(), vec![vec![Value::Int32(3000)]]);
+
+        // A statement can change the cap for the session; 0 removes it
+        db.execute("SET max_result_rows = 10").unwrap();
+        assert!(matches!(db.query("SELECT ID FROM Items WHERE ID >= 1500"), Err(DbError::ResourceExhausted(_))));
+        db.execute("SET max_result_rows = 0").unwrap();
+        assert_eq!(db.query("SELECT * FROM Items").unwrap().len(), 3000);
+        assert!(db.execute("SET max_result_rows = lots").is_err());
+    }
+
+    #[test]
+    fn test_statement_timeout() {
+        let db = test_db("timeout");
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY, Owner INT)").unwrap();
+        db.execute("CREATE TABLE Owners (ID INT PRIMARY KEY)").unwrap();
+        for i in 0..100 {
+            db.execute(&format!("INSERT INTO Items (ID, Owner) VALUES ({}, {})", i, i % 2)).unwrap();
+        }
+        db.execute("INSERT INTO Owners (ID) VALUES (0)").unwrap();
+
+        // A deadline that has passed by the first batch stops the statement with no rows
+        db.set_statement_timeout(Some(std::time::Duration::from_nanos(1))).unwrap();
+        assert!(matches!(db.query("SELECT * FROM Items"), Err(DbError::TimeoutError(_))));
+        assert!(matches!(
+            db.query("SELECT Owners.ID, Items.ID FROM Owners JOIN Items ON Owners.ID = Items.Owner"),
+            Err(DbError::TimeoutError(_))
+        ));
+
+        db.execute("SET statement_timeout = 60000").unwrap();
+        assert_eq!(db.query("SELECT * FROM Items").unwrap().len(), 100);
+        db.execute("SET statement_timeout = 0").unwrap();
+        assert_eq!(
+            db.query("SELECT Owners.ID, Items.ID FROM Owners JOIN Items ON Owners.ID = Items.Owner").unwrap().len(),
+            50
+        );
+        assert!(db.execute("SET statement_timeout = soon").is_err());
+    }
+
+    #[test]
+    fn test_order_by_nulls() {
+        let db = test_db("order_by");
+        db.execute("CREATE TABLE Scores (ID INT PRIMARY KEY, Team TEXT, Points INT)").unwrap();
+        for (id, team, points) in [(1, "'b'", "10"), (2, "'a'", "NULL"), (3, "'a'", "30"), (4, "NULL", "20"), (5, "'b'", "NULL")] {
+            db.execute(&format!("INSERT INTO Scores (ID, Team, Points) VALUES ({}, {}, {})", id, team, points)).unwrap();
+        }
+        let ids = |sql: &str| -> Vec<i32> {
+            db.query(sql)
+                .unwrap()
+                .into_iter()
+                .map(|row| match row[0] {
+                    Value::Int32(id) => id,
+                    ref other => panic!("unexpected {:?}", other),
+                })
+                .collect()
+        };
+
+        // NULLs are last ascending and first descending unless placed explicitly; ties keep storage order
+        assert_eq!(ids("SELECT ID FROM Scores ORDER BY Points"), vec![1, 4, 3, 2, 5]);
+        assert_eq!(ids("SELECT ID FROM Scores ORDER BY Points DESC"), vec![2, 5, 3, 4, 1]);
+        assert_eq!(ids("SELECT ID FROM Scores ORDER BY Points ASC NULLS FIRST"), vec![2, 5, 1, 4, 3]);
+        assert_eq!(ids("SELECT ID FROM Scores ORDER BY Points DESC NULLS LAST"), vec![3, 4, 1, 2, 5]);
+        // Later keys break ties, and keys need not be selected
+        assert_eq!(ids("select ID from Scores where ID > 1 order by team nulls first, points desc"), vec![4, 2, 3, 5]);
+        assert_eq!(db.query("SELECT Points FROM Scores WHERE Team = 'b' ORDER BY ID DESC").unwrap(), vec![vec![Value::Null], vec![Value::Int32(10)]]);
+
+        let plan = db.query("EXPLAIN SELECT ID FROM Scores ORDER BY Points DESC").unwrap();
+        assert_eq!(plan[1], vec![Value::String("  -> Sort: Points DESC NULLS FIRST".to_string())]);
+        assert!(db.query("SELECT ID FROM Scores ORDER BY Missing").is_err());
+        assert!(db.query("SELECT ID FROM Scores ORDER BY Points NULLS SOMEWHERE").is_err());
+        assert!(db.query("SELECT COUNT(*) FROM Scores ORDER BY Points").is_err());
+    }
+
+    #[test]
+    fn test_nan_in_aggregates_and_order_by() {
+        let db = test_db("nan");
+        db.execute("CREATE TABLE Readings (ID INT PRIMARY KEY, Level FLOAT)").unwrap();
+        // CSV is the one way to store a NaN
+        let csv = format!("{}/readings.csv", db.dir());
+        fs::write(&csv, "ID,Level\n1,2.5\n2,NaN\n3,\n4,0.5\n").unwrap();
+        db.execute(&format!("COPY Readings FROM '{}'", csv)).unwrap();
+
+        // NaN is skipped like NULL, so it cannot poison SUM and AVG or win MAX
+        let float = |f: f32| Value::Float32(OrderedFloat(f));
+        assert_eq!(
+            db.query("SELECT SUM(Level), AVG(Level), MIN(Level), MAX(Level), COUNT(*) FROM Readings").unwrap(),
+            vec![vec![float(3.0), float(1.5), float(0.5), float(2.5), Value::Int32(4)]]
+        );
+        db.execute("DELETE FROM Readings WHERE ID = 1").unwrap();
+        db.execute("DELETE FROM Readings WHERE ID = 4").unwrap();
+        let only_nan = db.query("SELECT MAX(Level), COUNT(*) FROM Readings").unwrap();
+        assert_eq!(only_nan, vec![vec![Value::Null, Value::Int32(2)]]);
+
+        // NaN sorts after every number in both directions; NULLs go where NULLS puts them
+        db.execute("INSERT INTO Readings (ID, Level) VALUES (1, 2.5)").unwrap();
+        db.execute("INSERT INTO Readings (ID, Level) VALUES (4, 0.5)").unwrap();
+        let ids = |sql: &str| db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect::<Vec<_>>();
+        let ints = |ids: &[i32]| ids.iter().map(|&id| Value::Int32(id)).collect::<Vec<_>>();
+        assert_eq!(ids("SELECT ID FROM Readings ORDER BY Level"), ints(&[4, 1, 2, 3]));
+        assert_eq!(ids("SELECT ID FROM Readings ORDER BY Level DESC NULLS LAST"), ints(&[1, 4, 2, 3]));
+        assert_eq!(ids("SELECT ID FROM Readings ORDER BY Level DESC"), ints(&[3, 1, 4, 2]));
+    }
+
+    #[test]
+    fn test_config_file() {
+        let data_dir = TestDir::new("config");
+        fs::create_dir_all(&data_dir).unwrap();
+
+        // Settings in vddb.toml are picked up, and the data directory opened always wins
+        fs::write(
+            data_dir.as_ref().join(config::CONFIG_FILE),
+            "data_dir = \"elsewhere\"\ncache_capacity = 3\nisolation_level = \"Serializable\"\n",
+        )
+        .unwrap();
+        let config = Config::for_data_dir(&data_dir).unwrap();
+        assert_eq!(config.data_dir, &*data_dir);
+        assert_eq!(config.cache_capacity, 3);
+        assert_eq!(config.isolation_level, IsolationLevel::Serializable);
+        let (_schema, _storage, tx_manager, _plugin_manager) = create_database(&config).unwrap();
+        assert_eq!(tx_manager.isolation_level(), IsolationLevel::Serializable);
+    }
+
+    #[test]
+    fn test_config_file_errors() {
+        let data_dir = TestDir::new("config_errors");
+        fs::create_dir_all(&data_dir).unwrap();
+        let path = data_dir.as_ref().join(config::CONFIG_FILE);
+
+        // Malformed files, bad values and unknown keys are configuration errors
+        for text in [
+            "cache_capacity = ",
+            "cache_capacity = \"lots\"",
+            "isolation_level = \"snapshot\"",
+            "metrics_addr = \"localhost\"",
+            "threads = 0",
+            "cache_size = 10",
+        ] {
+            fs::write(&path, text).unwrap();
+            assert!(matches!(Config::for_data_dir(&data_dir), Err(DbError::ConfigurationError(_))), "{}", text);
+            assert!(matches!(Database::open(&data_dir), Err(DbError::ConfigurationError(_))), "{}", text);
+        }
+        assert!(matches!(
+            Config::load(&data_dir.as_ref().join("missing.toml")),
+            Err(DbError::ConfigurationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_shutdown_flushes_and_checkpoints() {
+        let mut db = test_db("shutdown");
+        let wal = std::path::Path::new(db.dir()).join("wal").join("wal.log");
+        db.execute("CREATE TABLE t (id INT, name TEXT)").unwrap();
+        db.execute("INSERT INTO t VALUES (1, 'a')").unwrap();
+        db.execute("INSERT INTO t VALUES (2, 'b')").unwrap();
+        assert!(fs::metadata(&wal).unwrap().len() > 0);
+
+        // A transaction left open is rolled back, and committed rows leave the WAL for the column files
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO t VALUES (3, 'c')").unwrap();
+        db.shutdown().unwrap();
+        assert_eq!(fs::metadata(&wal).unwrap().len(), 0);
+        assert_eq!(db.query("SELECT id FROM t").unwrap().len(), 2);
+
+        db.reopen();
+        assert_eq!(
+            db.query("SELECT id, name FROM t ORDER BY id").unwrap(),
+            vec![
+                vec![Value::Int32(1), Value::String("a".to_string())],
+                vec![Value::Int32(2), Value::String("b".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let db = test_db("backup");
+        let backup_dir = TestDir::new("backup_copy");
+        db.execute("CREATE TABLE t (id INT, name TEXT)").unwrap();
+        for i in 0..10 {
+            db.execute(&format!("INSERT INTO t VALUES ({}, 'row{}')", i, i)).unwrap();
+        }
+
+        // A writer keeps inserting while the backup runs; the copy still holds whole rows only
+        let result = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 10..200 {
+                    db.execute(&format!("INSERT INTO t VALUES ({}, 'row{}')", i, i)).unwrap();
+                }
+            });
+            db.execute(&format!("BACKUP TO '{}'", backup_dir)).unwrap()
+        });
+        assert_eq!(result.columns, vec!["BACKED_UP_BYTES".to_string()]);
+        assert!(matches!(result.rows[0][0], Value::Int64(bytes) if bytes > 0));
+
+        let copy = Database::open(&backup_dir).unwrap();
+        let rows = copy.query("SELECT id, name FROM t ORDER BY id").unwrap();
+        assert!((10..=200).contains(&rows.len()));
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(row, &vec![Value::Int32(i as i32), Value::String(format!("row{}", i))]);
+        }
+        drop(copy);
+
+        // Restoring replaces every table with the ones in the backup
+        db.execute("DROP TABLE t").unwrap();
+        db.execute("CREATE TABLE other (id INT)").unwrap();
+        db.execute(&format!("RESTORE FROM '{}'", backup_dir)).unwrap();
+        assert_eq!(db.query("SELECT id, name FROM t ORDER BY id").unwrap(), rows);
+        assert!(matches!(db.query("SELECT id FROM other"), Err(DbError::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_backup_and_restore_errors() {
+        let db = test_db("backup_errors");
+        let backup_dir = TestDir::new("backup_errors_copy");
+        db.execute("CREATE TABLE t (id INT)").unwrap();
+        db.execute(&format!("BACKUP TO '{}'", backup_dir)).unwrap();
+
+        // Backups never overwrite or nest in the data directory, and only backups can be restored
+        assert!(matches!(db.execute(&format!("BACKUP TO '{}'", backup_dir)), Err(DbError::BackupError(_))));
+        assert!(matches!(db.execute(&format!("BACKUP TO '{}/copy'", db.dir())), Err(DbError::BackupError(_))));
+        assert!(matches!(db.execute(&format!("RESTORE FROM '{}/columns'", db.dir())), Err(DbError::RecoveryError(_))));
+        db.execute("BEGIN").unwrap();
+        assert!(matches!(
+            db.execute(&format!("RESTORE FROM '{}'", backup_dir)),
+            Err(DbError::TransactionError(_))
+        ));
+        db.execute("ROLLBACK").unwrap();
+    }
+
+    fn insert_ids(db: &Database, ids: std::ops::Range<i32>) {
+        for i in ids {
+            db.execute(&format!("INSERT INTO t VALUES ({})", i)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_incremental_backup() {
+        let db = test_db("incr");
+        let full = TestDir::new("incr_full");
+        db.execute("CREATE TABLE t (id INT)").unwrap();
+        assert!(matches!(db.execute(&format!("BACKUP INCREMENTAL TO '{}'", full)), Err(DbError::BackupError(_))));
+        insert_ids(&db, 0..3);
+        db.execute(&format!("BACKUP TO '{}'", full)).unwrap();
+
+        // Each incremental backup adds the next numbered segment, and restore replays them in order
+        insert_ids(&db, 3..5);
+        let result = db.execute(&format!("BACKUP INCREMENTAL TO '{}'", full)).unwrap();
+        assert!(matches!(result.rows[0][0], Value::Int64(bytes) if bytes > 0));
+        insert_ids(&db, 5..6);
+        db.execute(&format!("BACKUP INCREMENTAL TO '{}'", full)).unwrap();
+        insert_ids(&db, 6..7);
+        let segments = full.as_ref().join(storage::SEGMENTS_DIR);
+        assert!(segments.join("000001.wal").exists() && segments.join("000002.wal").exists());
+        db.execute(&format!("RESTORE FROM '{}'", full)).unwrap();
+        assert_eq!(db.query("SELECT id FROM t").unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_incremental_backup_after_restore() {
+        let db = test_db("incr_restore");
+        let full = TestDir::new("incr_restore_full");
+        db.execute("CREATE TABLE t (id INT)").unwrap();
+        insert_ids(&db, 0..3);
+        db.execute(&format!("BACKUP TO '{}'", full)).unwrap();
+        insert_ids(&db, 3..5);
+        db.execute(&format!("BACKUP INCREMENTAL TO '{}'", full)).unwrap();
+        insert_ids(&db, 5..6);
+        db.execute(&format!("BACKUP INCREMENTAL TO '{}'", full)).unwrap();
+        db.execute(&format!("RESTORE FROM '{}'", full)).unwrap();
+
+        // A restore starts a new chain, and a missing segment stops a restore before it begins
+        assert!(matches!(db.execute(&format!("BACKUP INCREMENTAL TO '{}'", full)), Err(DbError::BackupError(_))));
+        fs::remove_file(full.as_ref().join(storage::SEGMENTS_DIR).join("000001.wal")).unwrap();
+        assert!(matches!(db.execute(&format!("RESTORE FROM '{}'", full)), Err(DbError::BackupError(_))));
+        assert_eq!(db.query("SELECT id FROM t").unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_incremental_backup_continues_latest_full() {
+        let db = test_db("incr_latest");
+        let (full, second) = (TestDir::new("incr_latest_full"), TestDir::new("incr_latest_second"));
+        db.execute("CREATE TABLE t (id INT)").unwrap();
+        insert_ids(&db, 0..3);
+        db.execute(&format!("BACKUP TO '{}'", full)).unwrap();
+
+        // Only the latest full backup can be continued, and only while nothing but inserts happened
+        db.execute(&format!("BACKUP TO '{}'", second)).unwrap();
+        assert!(matches!(db.execute(&format!("BACKUP INCREMENTAL TO '{}'", full)), Err(DbError::BackupError(_))));
+        insert_ids(&db, 3..5);
+        db.execute("DELETE FROM t WHERE id = 0").unwrap();
+        assert!(matches!(db.execute(&format!("BACKUP INCREMENTAL TO '{}'", second)), Err(DbError::BackupError(_))));
+    }
+
+    fn articles_test_db(test_name: &str) -> TestDb {
+        let db = test_db(test_name);
+        db.execute("CREATE TABLE Articles (ID INT PRIMARY KEY, Body TEXT)").unwrap();
+        db.execute("INSERT INTO Articles (ID, Body) VALUES (1, 'Rust is a systems language.')").unwrap();
+        db.execute("INSERT INTO Articles (ID, Body) VALUES (2, 'A columnar database, written in RUST!')").unwrap();
+        db.execute("INSERT INTO Articles (ID, Body) VALUES (3, 'Go and databases')").unwrap();
+        db.execute("INSERT INTO Articles (ID, Body) VALUES (4, NULL)").unwrap();
+        db
+    }
+
+    fn first_column(db: &Database, sql: &str) -> Vec<Value> {
+        db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect()
+    }
+
+    #[test]
+    fn test_fulltext_index_and_match() {
+        let db = articles_test_db("fulltext");
+        let ids = |sql: &str| first_column(&db, sql);
+
+        // MATCH works without an index too, and gives the same rows with one
+        let unindexed = ids("SELECT ID FROM Articles WHERE Body MATCH 'rust database'");
+        assert_eq!(unindexed, vec![Value::Int32(2)]);
+        db.execute("CREATE FULLTEXT INDEX ON Articles(Body)").unwrap();
+        assert_eq!(ids("SELECT ID FROM Articles WHERE Body MATCH 'rust database'"), unindexed);
+        assert_eq!(ids("SELECT ID FROM Articles WHERE Body MATCH 'RUST'"), vec![Value::Int32(1), Value::Int32(2)]);
+        assert_eq!(
+            ids("SELECT ID FROM Articles WHERE Body MATCH ANY 'go database'"),
+            vec![Value::Int32(2), Value::Int32(3)]
+        );
+        let plan = db.execute("EXPLAIN SELECT ID FROM Articles WHERE Body MATCH 'rust'").unwrap();
+        assert_eq!(plan.rows[2][0], Value::String("    -> Full-text index lookup on Articles reading [ID, Body]".to_string()));
+    }
+
+    #[test]
+    fn test_fulltext_index_writes() {
+        let db = articles_test_db("fulltext_writes");
+        db.execute("CREATE FULLTEXT INDEX ON Articles(Body)").unwrap();
+
+        // Writes keep the index current
+        db.execute("INSERT INTO Articles (ID, Body) VALUES (5, 'Rust database internals')").unwrap();
+        db.execute("DELETE FROM Articles WHERE ID = 2").unwrap();
+        assert_eq!(first_column(&db, "SELECT ID FROM Articles WHERE Body MATCH 'rust database'"), vec![Value::Int32(5)]);
+    }
+
+    #[test]
+    fn test_fulltext_index_errors() {
+        let db = articles_test_db("fulltext_errors");
+        db.execute("CREATE FULLTEXT INDEX ON Articles(Body)").unwrap();
+        let dup = db.execute("CREATE FULLTEXT INDEX Articles_Body_fulltext ON Articles (Body)").unwrap_err();
+        assert!(matches!(dup, DbError::IndexError(_)));
+
+        // Only text columns can be indexed or matched
+        db.execute("CREATE TABLE Scores (ID INT)").unwrap();
+        let not_text = db.execute("CREATE FULLTEXT INDEX ON Scores (ID)").unwrap_err();
+        assert!(matches!(not_text, DbError::IndexError(_)));
+        let engine = QueryEngine::new(db.storage());
+        let not_text = engine.validate(&query::parser::parse_query("SELECT ID FROM Scores WHERE ID MATCH 'x'").unwrap());
+        assert!(matches!(not_text, Err(DbError::ValidationError(_))));
+    }
+
+    #[test]
+    fn test_fulltext_index_persists() {
+        let mut db = articles_test_db("fulltext_persists");
+        db.execute("CREATE FULLTEXT INDEX ON Articles(Body)").unwrap();
+
+        // The index is on disk and comes back with the database
+        db.close();
+        assert!(std::path::Path::new(&format!("{}/indexes/Articles_Articles_Body_fulltext.fts", db.dir())).exists());
+        db.reopen();
+        assert_eq!(first_column(&db, "SELECT ID FROM Articles WHERE Body MATCH 'systems'"), vec![Value::Int32(1)]);
+        let dump = db.storage().read().unwrap().dump_sql(Some("Articles")).unwrap();
+        assert!(dump.ends_with("CREATE FULLTEXT INDEX Articles_Body_fulltext ON Articles (Body);\n"));
+    }
+
+    #[test]
+    fn test_composite_index() {
+        let mut db = test_db("composite");
+        db.execute("CREATE TABLE Orders (ID INT, Customer INT, Status TEXT, Total INT)").unwrap();
+        for i in 0..300 {
+            let status = ["open", "paid", "shipped"][i % 3];
+            db.execute(&format!("INSERT INTO Orders (ID, Customer, Status, Total) VALUES ({}, {}, '{}', {})", i, i % 10, status, i))
+                .unwrap();
+        }
+        db.execute("INSERT INTO Orders (ID, Customer, Status, Total) VALUES (300, 3, NULL, 0)").unwrap();
+        let ids = |sql: &str| -> Vec<Value> { db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect() };
+        let both = "SELECT ID FROM Orders WHERE Customer = 3 AND Status = 'paid'";
+        let scanned = ids(both);
+        assert_eq!(scanned.len(), 10);
+
+        db.execute("CREATE INDEX orders_customer_status ON Orders (Customer, Status)").unwrap();
+        let plan = |sql: &str| db.execute(&format!("EXPLAIN {}", sql)).unwrap().rows[2][0].clone();
+        assert_eq!(ids(both), scanned);
+        assert_eq!(plan(both), Value::String("    -> Composite index lookup on Orders reading [ID, Customer, Status]".to_string()));
+        // A prefix uses the index, with the rest of the condition checked on its rows
+        let prefix = "SELECT ID FROM Orders WHERE Customer = 3 AND Total > 250";
+        assert_eq!(ids(prefix), vec![Value::Int32(253), Value::Int32(263), Value::Int32(273), Value::Int32(283), Value::Int32(293)]);
+        assert!(matches!(plan(prefix), Value::String(line) if line.starts_with("    -> Composite index lookup")));
+        assert_eq!(ids("SELECT ID FROM Orders WHERE Customer = 3 AND Status IS NULL"), vec![Value::Int32(300)]);
+        // Without the first column, or under an OR, the index cannot help
+        for sql in ["SELECT ID FROM Orders WHERE Status = 'paid'", "SELECT ID FROM Orders WHERE Customer = 3 OR Status = 'paid'"] {
+            assert!(matches!(plan(sql), Value::String(line) if line.starts_with("    -> Column scan")), "{}", sql);
+        }
+
+        // Writes keep the index current, also across a reopen
+        db.execute("INSERT INTO Orders (ID, Customer, Status, Total) VALUES (301, 3, 'paid', 5)").unwrap();
+        db.execute("DELETE FROM Orders WHERE ID = 13").unwrap();
+        let mut expected: Vec<Value> = scanned.into_iter().filter(|id| *id != Value::Int32(13)).collect();
+        expected.push(Value::Int32(301));
+        assert_eq!(ids(both), expected);
+        let dup = db.execute("CREATE INDEX orders_customer_status ON Orders (Total)").unwrap_err();
+        assert!(matches!(dup, DbError::IndexError(_)));
+        assert!(matches!(db.execute("CREATE INDEX ON Orders (Total)"), Err(DbError::QueryError(_))));
+        db.reopen();
+        let ids = |sql: &str| -> Vec<Value> { db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect() };
+        assert_eq!(ids(both), expected);
+    }
+
+    #[test]
+    fn test_drop_and_list_indexes() {
+        let db = test_db("drop_index");
+        db.execute("CREATE TABLE Posts (ID INT PRIMARY KEY, Slug TEXT UNIQUE, Author INT, Body TEXT)").unwrap();
+        db.execute("INSERT INTO Posts (ID, Slug, Author, Body) VALUES (1, 'hello', 7, 'Hello world')").unwrap();
+        db.execute("CREATE INDEX posts_author ON Posts (Author)").unwrap();
+        db.execute("CREATE FULLTEXT INDEX ON Posts (Body)").unwrap();
+        let text = |s: &str| Value::String(s.to_string());
+        let listed = db.query("SELECT index_name, index_type, column_names, is_implicit FROM information_schema.indexes WHERE table_name = 'Posts'").unwrap();
+        assert_eq!(
+            listed,
+            vec![
+                vec![text("Posts_ID_hash"), text("hash"), text("ID"), text("YES")],
+                vec![text("Posts_ID_key"), text("unique"), text("ID"), text("YES")],
+                vec![text("Posts_Slug_key"), text("unique"), text("Slug"), text("YES")],
+                vec![text("posts_author"), text("composite"), text("Author"), text("NO")],
+                vec![text("Posts_Body_fulltext"), text("fulltext"), text("Body"), text("NO")],
+            ]
+        );
+
+        let explain = |sql: &str| db.execute(&format!("EXPLAIN {}", sql)).unwrap().rows[2][0].clone();
+        let by_author = "SELECT ID FROM Posts WHERE Author = 7";
+        assert!(matches!(explain(by_author), Value::String(line) if line.contains("Composite index lookup")));
+        db.execute("DROP INDEX POSTS_AUTHOR ON Posts").unwrap();
+        assert!(matches!(explain(by_author), Value::String(line) if line.contains("Column scan")));
+        assert_eq!(db.query(by_author).unwrap(), vec![vec![Value::Int32(1)]]);
+        let index_file = format!("{}/indexes/Posts_Posts_Body_fulltext.fts", db.dir());
+        assert!(std::path::Path::new(&index_file).exists());
+        db.execute("DROP INDEX Posts_Body_fulltext ON Posts").unwrap();
+        assert!(!std::path::Path::new(&index_file).exists());
+        assert_eq!(db.query("SELECT ID FROM Posts WHERE Body MATCH 'hello'").unwrap(), vec![vec![Value::Int32(1)]]);
+
+        let missing = db.execute("DROP INDEX posts_author ON Posts").unwrap_err();
+        assert!(matches!(missing, DbError::IndexError(ref msg) if msg.contains("not found")), "{}", missing);
+        let constraint = db.execute("DROP INDEX Posts_Slug_key ON Posts").unwrap_err();
+        assert!(matches!(constraint, DbError::IndexError(ref msg) if msg.contains("UNIQUE")), "{}", constraint);
+        assert!(db.execute("INSERT INTO Posts (ID, Slug, Author, Body) VALUES (2, 'hello', 7, 'Again')").is_err());
+        assert_eq!(db.query("SELECT index_name FROM information_schema.indexes WHERE is_implicit = 'NO'").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_plan_cache_invalidated_by_ddl() {
+        let db = test_db("plan_cache");
+        let mut engine = QueryEngine::new(db.storage());
+        let run = |engine: &mut QueryEngine, sql: &str| {
+            let query = engine.prepare(sql)?;
+            engine.execute_query(query)
+        };
+        run(&mut engine, "CREATE TABLE Notes (ID INT PRIMARY KEY, Body TEXT)").unwrap();
+        run(&mut engine, "CREATE TABLE Tags (ID INT PRIMARY KEY)").unwrap();
+        run(&mut engine, "INSERT INTO Notes (ID, Body) VALUES (1, 'first')").unwrap();
+        let select = "SELECT Body FROM Notes WHERE id = 1";
+        let before = engine.plan_cache_stats();
+        assert_eq!(run(&mut engine, select).unwrap().rows, vec![vec![Value::String("first".to_string())]]);
+        run(&mut engine, "SELECT  Body\n FROM Notes WHERE id = 1;").unwrap();
+        run(&mut engine, "SELECT ID FROM Tags").unwrap();
+        assert_eq!(engine.plan_cache_stats(), (before.0 + 1, before.1 + 2));
+
+        // Dropping Notes forgets its plans but keeps those of other tables
+        run(&mut engine, "DROP TABLE Notes").unwrap();
+        let before = engine.plan_cache_stats();
+        assert!(run(&mut engine, select).is_err());
+        run(&mut engine, "SELECT ID FROM Tags").unwrap();
+        assert_eq!(engine.plan_cache_stats(), (before.0 + 1, before.1 + 1));
+
+        run(&mut engine, "CREATE TABLE notes (id INT PRIMARY KEY, body TEXT)").unwrap();
+        run(&mut engine, "INSERT INTO notes (id, body) VALUES (1, 'second')").unwrap();
+        assert_eq!(run(&mut engine, "SELECT body FROM Notes WHERE id = 1").unwrap().rows, vec![vec![Value::String("second".to_string())]]);
+    }
+
+    #[test]
+    fn test_aggregate_result_types() {
+        let db = test_db("agg_types");
+        db.execute("CREATE TABLE Stock (ID INT PRIMARY KEY, Qty INT, Big BIGINT, Price FLOAT, Name TEXT)").unwrap();
+        db.execute("INSERT INTO Stock VALUES (1, 3, 10, 1.5, 'bolt')").unwrap();
+        db.execute("INSERT INTO Stock VALUES (2, 4, 20, 2.5, 'nut')").unwrap();
+
+        // SUM over integers stays integral as BIGINT; AVG is always FLOAT
+        let row = db.query("SELECT SUM(Qty), SUM(Big), AVG(Qty), AVG(Big), SUM(Price), AVG(Price) FROM Stock").unwrap().remove(0);
+        assert_eq!(
+            row,
+            vec![
+                Value::Int64(7),
+                Value::Int64(30),
+                Value::Float32(OrderedFloat(3.5)),
+                Value::Float32(OrderedFloat(15.0)),
+                Value::Float32(OrderedFloat(4.0)),
+                Value::Float32(OrderedFloat(2.0)),
+            ]
+        );
+        let empty = db.query("SELECT SUM(Qty), SUM(Price) FROM Stock WHERE ID > 9").unwrap();
+        assert_eq!(empty, vec![vec![Value::Int64(0), Value::Float32(OrderedFloat(0.0))]]);
+        assert_eq!(Aggregation::Sum("Qty".to_string()).result_type(&DataType::Int32), Some(DataType::Int64));
+        assert_eq!(Aggregation::Avg("Qty".to_string()).result_type(&DataType::Int32), Some(DataType::Float32));
+        assert_eq!(Aggregation::Sum("Name".to_string()).result_type(&DataType::String), None);
+        let text_sum = db.execute("SELECT SUM(Name) FROM Stock").unwrap_err();
+        assert!(matches!(text_sum, DbError::InvalidData(ref msg) if msg.contains("SUM(Name)")), "{}", text_sum);
+    }
+
+    #[test]
+    fn test_repl_history_file() {
+        let (data_dir, schema, storage, tx_manager, plugin_manager) = setup_test_db("repl_history").unwrap();
+        let history = format!("{}/shell/history", data_dir);
+        // Only this test sets these, so other tests' shells are unaffected
+        std::env::set_var(repl::HISTORY_FILE_ENV, &history);
+        std::env::set_var(repl::HISTORY_SIZE_ENV, "3");
+        let mut shell = Repl::new(schema.clone(), Arc::clone(&storage), tx_manager, plugin_manager).unwrap();
+        for line in ["SELECT 1;", "SELECT 2;", "SELECT 2;", "CREATE USER bob PASSWORD 'secret';", "SELECT 3;", "SELECT 4;"] {
+            shell.record_history(line);
+        }
+        shell.save_history().unwrap();
+        drop(shell);
+
+        // The newest three lines survive, without the repeat or the password
+        let saved = fs::read_to_string(&history).unwrap();
+        let entries: Vec<&str> = saved.lines().filter(|line| line.starts_with("SELECT")).collect();
+        assert_eq!(entries, vec!["SELECT 2;", "SELECT 3;", "SELECT 4;"]);
+
+        let tx_manager = TransactionManager::new(Arc::clone(&storage)).unwrap();
+        let mut shell = Repl::new(schema, storage, tx_manager, PluginManager::open(&data_dir)).unwrap();
+        shell.record_history("SELECT 5;");
+        shell.save_history().unwrap();
+        let saved = fs::read_to_string(&history).unwrap();
+        assert_eq!(saved.lines().filter(|line| line.starts_with("SELECT")).collect::<Vec<_>>(), vec!["SELECT 3;", "SELECT 4;", "SELECT 5;"]);
+
+        std::env::set_var(repl::HISTORY_SIZE_ENV, "many");
+        let (_other_dir, schema, storage, tx_manager, plugin_manager) = setup_test_db("repl_history").unwrap();
+        assert!(matches!(Repl::new(schema, storage, tx_manager, plugin_manager), Err(DbError::ConfigurationError(_))));
+        std::env::remove_var(repl::HISTORY_FILE_ENV);
+        std::env::remove_var(repl::HISTORY_SIZE_ENV);
+    }
+
+    #[test]
+    fn test_repl_source_file() {
+        let (data_dir, schema, storage, tx_manager, plugin_manager) = setup_test_db("repl_source").unwrap();
+        let mut shell = Repl::new(schema, Arc::clone(&storage), tx_manager, plugin_manager).unwrap();
+        let setup = format!("{}/setup.sql", data_dir);
+        fs::write(
+            &setup,
+            "-- Notes schema; kept in version control\n\
+             CREATE TABLE Notes (ID INT PRIMARY KEY, Body TEXT);\n\
+             BEGIN;\n\
+             INSERT INTO Notes VALUES (1, 'a -- not a comment; really'); -- the first note\n\
+             INSERT INTO Notes\n  VALUES (2, 'two');\n\
+             COMMIT;\n",
+        )
+        .unwrap();
+        shell.handle_source_command(&format!("'{}'", setup)).unwrap();
+        let mut engine = QueryEngine::new(Arc::clone(&storage));
+        let bodies = |engine: &mut QueryEngine| {
+            engine.execute_query(query::parser::parse_query("SELECT Body FROM Notes").unwrap()).unwrap().rows
+        };
+        assert_eq!(
+            bodies(&mut engine),
+            vec![vec![Value::String("a -- not a comment; really".to_string())], vec![Value::String("two".to_string())]]
+        );
+
+        // Statements before the failing one stay applied; those after it never run
+        let broken = format!("{}/broken.sql", data_dir);
+        fs::write(&broken, "INSERT INTO Notes VALUES (3, 'three');\n\nINSERT INTO Nowhere VALUES (4);\nINSERT INTO Notes VALUES (5, 'five');\n").unwrap();
+        let err = shell.handle_source_command(&broken).unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+        assert_eq!(bodies(&mut engine).len(), 3);
+        assert!(shell.handle_source_command(&format!("{}/missing.sql", data_dir)).is_err());
+
+        assert_eq!(
+            query::parser::split_statements_with_lines("SELECT 1;\n\n  SELECT\n2; ;SELECT ';\n'"),
+            vec![(1, "SELECT 1"), (3, "SELECT\n2"), (4, "SELECT ';\n'")]
+        );
+        drop(shell);
+    }
+
+    #[test]
+    fn test_sql_comments() {
+        let db = test_db("sql_comments");
+        db.execute("CREATE TABLE Notes ( -- one row per note\n ID INT PRIMARY KEY, /* the text */ Body TEXT)").unwrap();
+        db.execute("INSERT INTO Notes VALUES (1, 'keep -- this /* too */') /* trailing */").unwrap();
+        db.execute("INSERT INTO Notes VALUES (2, 'it''s -- data') -- trailing").unwrap();
+        let body = |s: &str| vec![Value::String(s.to_string())];
+        assert_eq!(
+            db.query("SELECT Body /* mid-statement */ FROM Notes\n-- a whole line\nWHERE ID = 1 -- at the end").unwrap(),
+            vec![body("keep -- this /* too */")]
+        );
+        assert_eq!(db.query("SELECT Body FROM/**/Notes WHERE ID = 2").unwrap(), vec![body("it's -- data")]);
+        assert_eq!(db.query("SELECT ID FROM \"Notes\" WHERE Body = '/*' OR ID = 2 /* ' */").unwrap().len(), 1);
+        assert!(matches!(db.execute("SELECT ID FROM Notes /* never closed"), Err(DbError::QueryError(_))));
+        assert!(matches!(db.execute("-- only a comment"), Err(DbError::QueryError(_))));
+
+        // Line breaks survive, so script line numbers still count comment lines
+        assert_eq!(
+            query::parser::strip_comments("/* a\nb */SELECT 1; -- x\n'--'").unwrap(),
+            "\n SELECT 1; \n'--'"
+        );
+    }
+
+    #[test]
+    fn test_numeric_literals() {
+        let db = test_db("numeric_literals");
+        let literal = |sql: &str| match query::parser::parse_expr(sql).unwrap() {
+            query::Expr::Literal(value) => value,
+            other => panic!("{} parsed as {:?}", sql, other),
+        };
+        let float = |f: f32| Value::Float32(OrderedFloat(f));
+        assert_eq!(literal("1e10"), float(1e10));
+        assert_eq!(literal(".5"), float(0.5));
+        assert_eq!(literal("5."), float(5.0));
+        assert_eq!(literal("-2.5E+2"), float(-250.0));
+        assert_eq!(literal("1_000"), Value::Int32(1000));
+        assert_eq!(literal("1_000.000_5"), float(1000.0005));
+        assert_eq!(literal("3_000_000_000"), Value::Int64(3_000_000_000));
+
+        db.execute("CREATE TABLE Measures (ID INT PRIMARY KEY, Amount BIGINT, Ratio FLOAT)").unwrap();
+        db.execute("INSERT INTO Measures VALUES (1_000, 1_000_000_000_000, 1.5e-3)").unwrap();
+        db.execute("INSERT INTO Measures VALUES (2, 7, .5)").unwrap();
+        assert_eq!(
+            db.query("SELECT ID, Amount FROM Measures WHERE Ratio < 1e-2").unwrap(),
+            vec![vec![Value::Int32(1000), Value::Int64(1_000_000_000_000)]]
+        );
+        for malformed in ["1__0", "1_", "1e", "1.5e+", "1_.5", "1e_5", "1.2.3", "1e99"] {
+            let err = db.execute(&format!("SELECT ID FROM Measures WHERE Ratio = {}", malformed)).unwrap_err();
+            assert!(matches!(err, DbError::QueryError(_)), "{}: {}", malformed, err);
+        }
+    }
+
+    #[test]
+    fn test_insert_type_checking() {
+        let db = test_db("insert_types");
+        db.execute("CREATE TABLE Kinds (ID INT PRIMARY KEY, Big BIGINT, Ratio FLOAT, Label TEXT, At TIMESTAMP)").unwrap();
+
+        // INT widens to BIGINT and to FLOAT
+        db.execute("INSERT INTO Kinds VALUES (1, 5, 2, 'a', TIMESTAMP '2024-01-01T00:00:00Z')").unwrap();
+        assert_eq!(
+            db.query("SELECT Big, Ratio FROM Kinds WHERE ID = 1").unwrap(),
+            vec![vec![Value::Int64(5), Value::Float32(OrderedFloat(2.0))]]
+        );
+        db.execute("INSERT INTO Kinds (ID, Label) VALUES (2, 'b') ON CONFLICT (ID) DO NOTHING").unwrap();
+        db.execute("INSERT INTO Kinds (ID, Label) VALUES (2, 'b') ON CONFLICT (ID) DO UPDATE SET Ratio = 7").unwrap();
+        assert_eq!(db.query("SELECT Ratio FROM Kinds WHERE ID = 2").unwrap(), vec![vec![Value::Float32(OrderedFloat(7.0))]]);
+
+        // Nothing narrows, and unrelated types never convert
+        let rejected = [
+            "INSERT INTO Kinds (ID) VALUES (1.5)",
+            "INSERT INTO Kinds (ID) VALUES (3_000_000_000)",
+            "INSERT INTO Kinds (ID, Ratio) VALUES (3, 3_000_000_000)",
+            "INSERT INTO Kinds (ID, Big) VALUES (3, 2.5)",
+            "INSERT INTO Kinds (ID, Label) VALUES (3, 5)",
+            "INSERT INTO Kinds (ID, Big) VALUES (3, 'five')",
+            "INSERT INTO Kinds (ID, At) VALUES (3, 5)",
+            "INSERT INTO Kinds (ID) VALUES (2) ON CONFLICT (ID) DO UPDATE SET Big = 1.5",
+        ];
+        for sql in rejected {
+            assert!(matches!(db.execute(sql), Err(DbError::TypeMismatch(_))), "{}", sql);
+        }
+        let err = db.execute("INSERT INTO Kinds (ID, Label) VALUES (3, 42)").unwrap_err();
+        assert_eq!(err.to_string(), "Type Mismatch: Column Kinds.Label expects TEXT, got INT value 42");
+        assert_eq!(db.query("SELECT ID FROM Kinds").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_session_settings() {
+        let db = test_db("session_settings");
+        let show = |sql: &str| db.execute(sql).map(|result| (result.columns, result.rows));
+        let text = |s: &str| vec![vec![Value::String(s.to_string())]];
+        db.execute("SET statement_timeout = 5000").unwrap();
+        db.execute("SET max_result_rows TO 20").unwrap();
+        db.execute("SET transaction_isolation = 'serializable'").unwrap();
+        assert_eq!(show("SHOW statement_timeout").unwrap(), (vec!["statement_timeout".to_string()], text("5000")));
+        assert_eq!(show("show MAX_RESULT_ROWS").unwrap().1, text("20"));
+        assert_eq!(show("SHOW TRANSACTION ISOLATION LEVEL").unwrap().1, text("serializable"));
+        db.execute("SET TRANSACTION ISOLATION LEVEL READ COMMITTED").unwrap();
+        assert_eq!(show("SHOW transaction_isolation").unwrap().1, text("read committed"));
+        assert!(matches!(db.execute("SET nonsense = 1"), Err(DbError::ConfigurationError(_))));
+        assert!(matches!(db.execute("SHOW nonsense"), Err(DbError::ConfigurationError(_))));
+        assert!(matches!(db.execute("SET statement_timeout = soon"), Err(DbError::ConfigurationError(_))));
+        assert!(matches!(db.execute("SET statement_timeout"), Err(DbError::QueryError(_))));
+    }
+
+    #[test]
+    fn test_repl_settings() {
+        let (_data_dir, schema, storage, tx_manager, plugin_manager) = setup_test_db("repl_settings").unwrap();
+        // The shell owns its display settings and passes the engine's through
+        let mut shell = Repl::new(schema, storage, tx_manager, plugin_manager).unwrap();
+        shell.execute_query("SET format = json").unwrap();
+        shell.execute_query("SET timing TO on").unwrap();
+        shell.execute_query("SET max_result_rows = 3").unwrap();
+        assert_eq!(shell.show_setting("format").unwrap(), "json");
+        assert_eq!(shell.show_setting("timing").unwrap(), "on");
+        assert_eq!(shell.show_setting("max_result_rows").unwrap(), "3");
+        shell.execute_query("SHOW max_width").unwrap();
+        assert!(matches!(shell.execute_query("SET format = xml"), Err(DbError::ConfigurationError(_))));
+        let unknown = shell.execute_query("SET colour = on").unwrap_err();
+        assert!(matches!(unknown, DbError::ConfigurationError(ref msg) if msg.contains("safe_delete") && msg.contains("statement_timeout")), "{}", unknown);
+    }
+
+    #[test]
+    fn test_autocommit_off() {
+        let db = test_db("autocommit");
+        let mut other = QueryEngine::new(db.storage());
+        let count = |engine: &mut QueryEngine| {
+            engine.execute_query(query::parser::parse_query("SELECT ID FROM Items").unwrap()).unwrap().rows.len()
+        };
+        assert_eq!(db.query("SHOW autocommit").unwrap(), vec![vec![Value::String("on".to_string())]]);
+        db.execute("SET autocommit = off").unwrap();
+
+        // Schema changes still run on their own
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY)").unwrap();
+        db.execute("INSERT INTO Items VALUES (1)").unwrap();
+        assert_eq!(db.query("SELECT ID FROM Items").unwrap().len(), 1);
+        assert_eq!(count(&mut other), 0);
+        assert!(db.execute("BEGIN").is_err());
+        db.execute("COMMIT").unwrap();
+        assert_eq!(count(&mut other), 1);
+
+        // The next write begins a new transaction, which ROLLBACK discards
+        db.execute("INSERT INTO Items VALUES (2)").unwrap();
+        db.execute("ROLLBACK").unwrap();
+        assert_eq!(count(&mut other), 1);
+        assert!(matches!(db.execute("COMMIT"), Err(DbError::TransactionError(_))));
+
+        // Turning autocommit back on leaves an open transaction to its COMMIT
+        db.execute("INSERT INTO Items VALUES (3)").unwrap();
+        db.execute("SET autocommit = on").unwrap();
+        assert_eq!(count(&mut other), 1);
+        db.execute("COMMIT").unwrap();
+        db.execute("INSERT INTO Items VALUES (4)").unwrap();
+        assert_eq!(count(&mut other), 3);
+        assert!(matches!(db.execute("SET autocommit = sometimes"), Err(DbError::ConfigurationError(_))));
+    }
+
+    #[test]
+    fn test_repl_prompt_state() {
+        let (_data_dir, schema, storage, tx_manager, plugin_manager) = setup_test_db("repl_prompt").unwrap();
+        let mut shell = Repl::new(schema, storage, tx_manager, plugin_manager).unwrap();
+        shell.run_statement("CREATE TABLE Items (ID INT PRIMARY KEY)").unwrap();
+        assert_eq!(shell.prompt(), "vddb> ");
+        assert!(shell.run_statement("INSERT INTO Missing VALUES (1)").is_err());
+        assert_eq!(shell.prompt(), "vddb> ");
+
+        shell.run_statement("BEGIN").unwrap();
+        assert_eq!(shell.prompt(), "vddb*> ");
+        assert!(shell.run_statement("INSERT INTO Items VALUES ('one')").is_err());
+        assert_eq!(shell.prompt(), "vddb!> ");
+        shell.run_statement("INSERT INTO Items VALUES (1)").unwrap();
+        assert_eq!(shell.prompt(), "vddb*> ");
+        shell.run_statement("ROLLBACK").unwrap();
+        assert_eq!(shell.prompt(), "vddb> ");
+
+        shell.run_statement("SET prompt = 'db> '").unwrap();
+        shell.run_statement("SET prompt_transaction = 'db(tx)> '").unwrap();
+        assert_eq!(shell.prompt(), "db> ");
+        shell.run_statement("BEGIN").unwrap();
+        assert_eq!(shell.prompt(), "db(tx)> ");
+        assert_eq!(shell.show_setting("prompt_failed").unwrap(), "vddb!> ");
+    }
+
+    #[test]
+    fn test_cancel_statement() {
+        use std::sync::atomic::Ordering;
+        let db = test_db("cancel");
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY, Owner INT)").unwrap();
+        db.execute("CREATE TABLE Owners (ID INT PRIMARY KEY)").unwrap();
+        for i in 0..10 {
+            db.execute(&format!("INSERT INTO Items (ID, Owner) VALUES ({}, {})", i, i % 2)).unwrap();
+        }
+        db.execute("INSERT INTO Owners (ID) VALUES (0)").unwrap();
+        let cancel = db.cancel_handle().unwrap();
+
+        // A cancelled scan returns no partial rows, and the flag only stops one statement
+        cancel.store(true, Ordering::SeqCst);
+        assert!(matches!(db.query("SELECT * FROM Items"), Err(DbError::Cancelled(_))));
+        assert!(!cancel.load(Ordering::SeqCst));
+        assert_eq!(db.query("SELECT * FROM Items").unwrap().len(), 10);
+
+        cancel.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            db.query("SELECT Owners.ID, Items.ID FROM Owners JOIN Items ON Owners.ID = Items.Owner"),
+            Err(DbError::Cancelled(_))
+        ));
+        assert_eq!(
+            db.query("SELECT Owners.ID, Items.ID FROM Owners JOIN Items ON Owners.ID = Items.Owner").unwrap().len(),
+            5
+        );
+
+        // Inside a transaction a cancelled read leaves its buffered writes alone
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Items (ID, Owner) VALUES (10, 0)").unwrap();
+        cancel.store(true, Ordering::SeqCst);
+        assert!(matches!(db.query("SELECT * FROM Items"), Err(DbError::Cancelled(_))));
+        db.execute("COMMIT").unwrap();
+        assert_eq!(db.query("SELECT * FROM Items").unwrap().len(), 11);
+    }
+
+    #[test]
+    fn test_error_categories() {
+        let text = || "failed".to_string();
+        assert_eq!(DbError::IoError(std::io::Error::other("disk")).category(), ErrorCategory::Io);
+        assert_eq!(DbError::BackupError(text()).category(), ErrorCategory::Io);
+        assert_eq!(DbError::TypeMismatch(text()).category(), ErrorCategory::Query);
+        assert_eq!(DbError::QueryError(text()).category(), ErrorCategory::Query);
+        assert_eq!(DbError::ValidationError(text()).category(), ErrorCategory::Query);
+        assert_eq!(DbError::SchemaError(text()).category(), ErrorCategory::Schema);
+        assert_eq!(DbError::IndexError(text()).category(), ErrorCategory::Schema);
+        assert_eq!(DbError::TransactionError(text()).category(), ErrorCategory::Transaction);
+        assert_eq!(DbError::AuthorizationError(text()).category(), ErrorCategory::Security);
+        assert_eq!(DbError::DataPrivacyViolationError(text()).category(), ErrorCategory::Security);
+        assert_eq!(DbError::ResourceExhausted(text()).category(), ErrorCategory::Resource);
+        assert_eq!(DbError::Cancelled(text()).category(), ErrorCategory::Resource);
+        assert_eq!(DbError::IntegrityError(text()).category(), ErrorCategory::Internal);
+        assert_eq!(DbError::PluginError(text()).category(), ErrorCategory::Internal);
+
+        assert!(DbError::TimeoutError(text()).is_retryable());
+        assert!(DbError::IoError(std::io::Error::from(std::io::ErrorKind::Interrupted)).is_retryable());
+        assert!(!DbError::IoError(std::io::Error::from(std::io::ErrorKind::NotFound)).is_retryable());
+        assert!(!DbError::Cancelled(text()).is_retryable());
+        assert!(!DbError::ConcurrencyError(text()).is_retryable());
+
+        // Errors from real statements land where a caller would look for them
+        let db = test_db("error_categories");
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY)").unwrap();
+        assert_eq!(db.execute("INSERT INTO Items VALUES ('one')").unwrap_err().category(), ErrorCategory::Query);
+        assert_eq!(db.execute("COMMIT").unwrap_err().category(), ErrorCategory::Transaction);
+        assert_eq!(db.execute("SELEC * FROM Items").unwrap_err().category(), ErrorCategory::Query);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let mismatch = DbError::TypeMismatch("Column Items.ID expects INT".to_string());
+        assert_eq!(mismatch.code(), "TYPE_MISMATCH");
+        assert_eq!(DbError::IoError(std::io::Error::other("disk")).code(), "IO_ERROR");
+        assert_eq!(DbError::ResourceExhausted(String::new()).code(), "RESOURCE_EXHAUSTED");
+        assert_eq!(DbError::Cancelled(String::new()).code(), "CANCELLED");
+        assert_eq!(DbError::DataPrivacyViolationError(String::new()).code(), "DATA_PRIVACY_VIOLATION_ERROR");
+
+        // The JSON carries the code with the same message Display writes
+        assert_eq!(
+            mismatch.to_json(),
+            serde_json::json!({ "code": "TYPE_MISMATCH", "message": "Type Mismatch: Column Items.ID expects INT" })
+        );
+        assert_eq!(mismatch.to_string(), "Type Mismatch: Column Items.ID expects INT");
+    }
+
+    #[test]
+    fn test_numeric_comparison_coercion() {
+        let db = test_db("numeric_compare");
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY, Qty INT, Price FLOAT, Name TEXT)").unwrap();
+        for (id, qty, price) in [(1, 5, 2.5), (2, 3, 4.0), (3, 5, 3.0)] {
+            db.execute(&format!("INSERT INTO Items VALUES ({}, {}, {:.1}, 'item{}')", id, qty, price, id)).unwrap();
+        }
+        let ids = |sql: &str| -> Vec<Value> { db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect() };
+
+        // An INT column against a FLOAT literal compares by value, also through the primary key
+        assert_eq!(ids("SELECT ID FROM Items WHERE Qty = 5.0 ORDER BY ID"), vec![Value::Int32(1), Value::Int32(3)]);
+        assert_eq!(ids("SELECT ID FROM Items WHERE ID = 2.0"), vec![Value::Int32(2)]);
+        assert!(ids("SELECT ID FROM Items WHERE Qty = 4.5").is_empty());
+        assert_eq!(ids("SELECT ID FROM Items WHERE Qty < 4.5"), vec![Value::Int32(2)]);
+        // And a FLOAT column against an INT literal
+        assert_eq!(ids("SELECT ID FROM Items WHERE Price > 3 ORDER BY ID"), vec![Value::Int32(2)]);
+        assert_eq!(ids("SELECT ID FROM Items WHERE Price >= 3 ORDER BY ID"), vec![Value::Int32(2), Value::Int32(3)]);
+        assert_eq!(db.query("SELECT COUNT(*) FROM Items WHERE Price <= 3").unwrap(), vec![vec![Value::Int32(2)]]);
+
+        // A number never compares with a string
+        assert!(matches!(db.query("SELECT ID FROM Items WHERE Qty = 'five'"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.query("SELECT ID FROM Items WHERE Name > 3"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.execute("DELETE FROM Items WHERE Price < 'cheap'"), Err(DbError::TypeMismatch(_))));
+        assert_eq!(ids("SELECT ID FROM Items").len(), 3);
+    }
+
+    // Items 10, 20, 30 and 40, with rowids 1 to 4
+    fn items_test_db(test_name: &str) -> TestDb {
+        let db = test_db(test_name);
+        db.execute("CREATE TABLE Items (ID INT PRIMARY KEY, Name TEXT)").unwrap();
+        for (id, name) in [(10, "a"), (20, "b"), (30, "c"), (40, "d")] {
+            db.execute(&format!("INSERT INTO Items VALUES ({}, '{}')", id, name)).unwrap();
+        }
+        db
+    }
+
+    fn rowids(ids: &[i64]) -> Vec<Vec<Value>> {
+        ids.iter().map(|&id| vec![Value::Int64(id)]).collect()
+    }
+
+    #[test]
+    fn test_rowid() {
+        let db = items_test_db("rowid");
+
+        // SELECT * leaves the rowid out; naming it reads it, in any case
+        assert_eq!(db.execute("SELECT * FROM Items").unwrap().columns, vec!["ID".to_string(), "Name".to_string()]);
+        assert_eq!(db.query("SELECT ROWID FROM Items").unwrap(), rowids(&[1, 2, 3, 4]));
+        assert_eq!(
+            db.query("SELECT rowid, Name FROM Items WHERE ROWID = 3").unwrap(),
+            vec![vec![Value::Int64(3), Value::String("c".to_string())]]
+        );
+    }
+
+    #[test]
+    fn test_rowid_not_reused() {
+        let mut db = items_test_db("rowid_reuse");
+
+        // Deleting by rowid removes exactly that row; the others keep theirs, and a deleted
+        // rowid is not handed out again, even after VACUUM and a reopen
+        db.execute("DELETE FROM Items WHERE ROWID = 2").unwrap();
+        db.execute("VACUUM Items").unwrap();
+        assert_eq!(db.query("SELECT ROWID FROM Items").unwrap(), rowids(&[1, 3, 4]));
+        db.execute("DELETE FROM Items WHERE ROWID >= 4").unwrap();
+        db.reopen();
+        db.execute("INSERT INTO Items VALUES (50, 'e')").unwrap();
+        assert_eq!(db.query("SELECT ROWID, ID FROM Items WHERE ID = 50").unwrap(), vec![vec![Value::Int64(5), Value::Int32(50)]]);
+    }
+
+    #[test]
+    fn test_rowid_in_transaction() {
+        let db = items_test_db("rowid_tx");
+
+        // Rows inserted in an open transaction get theirs at COMMIT
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Items VALUES (60, 'f')").unwrap();
+        assert_eq!(db.query("SELECT ROWID FROM Items WHERE ID = 60").unwrap(), vec![vec![Value::Null]]);
+        db.execute("COMMIT").unwrap();
+        assert_eq!(db.query("SELECT ROWID FROM Items WHERE ID = 60").unwrap(), rowids(&[5]));
+    }
+
+    #[test]
+    fn test_rowid_is_reserved() {
+        let db = items_test_db("rowid_reserved");
+
+        // ROWID is not a column to declare, insert, index, drop or rename
+        assert!(matches!(db.execute("CREATE TABLE Other (ROWID INT)"), Err(DbError::SchemaError(_))));
+        assert!(db.execute("INSERT INTO Items (ID, ROWID) VALUES (70, 9)").is_err());
+        assert!(db.execute("CREATE INDEX items_rowid ON Items (ROWID)").is_err());
+        assert!(db.execute("ALTER TABLE Items DROP COLUMN ROWID").is_err());
+        assert!(db.execute("ALTER TABLE Items RENAME COLUMN Name TO RowId").is_err());
+        assert_eq!(db.query("SELECT ROWID FROM Items").unwrap(), rowids(&[1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_streaming_aggregate() {
+        let db = test_db("stream_agg");
+        db.execute("CREATE TABLE Readings (ID INT PRIMARY KEY, Sensor TEXT, Level INT)").unwrap();
+        // Enough rows to span several scan batches, with a NULL every 7th level
+        for i in 0..2500 {
+            let level = if i % 7 == 0 { "NULL".to_string() } else { (i % 100).to_string() };
+            db.execute(&format!("INSERT INTO Readings VALUES ({}, 's{}', {})", i, i % 3, level)).unwrap();
+        }
+        let levels: Vec<i64> = (0..2500).filter(|i| i % 7 != 0).map(|i| (i % 100) as i64).collect();
+
+        let rows = db
+            .query("SELECT COUNT(*), SUM(Level), MIN(Level), MAX(Level), AVG(Level) FROM Readings")
+            .unwrap();
+        let avg = levels.iter().sum::<i64>() as f64 / levels.len() as f64;
+        assert_eq!(
+            rows,
+            vec![vec![
+                Value::Int32(2500),
+                Value::Int64(levels.iter().sum()),
+                Value::Int32(0),
+                Value::Int32(99),
+                Value::Float32(OrderedFloat(avg as f32)),
+            ]]
+        );
+
+        // A condition picks the rows that feed the totals, across batches too
+        let rows = db.query("SELECT COUNT(*), SUM(ID) FROM Readings WHERE Level = 50").unwrap();
+        let matching: Vec<i64> = (0..2500).filter(|i| i % 7 != 0 && i % 100 == 50).collect();
+        assert_eq!(rows, vec![vec![Value::Int32(matching.len() as i32), Value::Int64(matching.iter().sum())]]);
+        assert_eq!(
+            db.query("SELECT GROUP_CONCAT(Sensor, '') FROM Readings WHERE ID < 5").unwrap(),
+            vec![vec![Value::String("s0s1s2s0s1".to_string())]]
+        );
+
+        // Aggregates sharing a column come back in the order they were written
+        assert_eq!(
+            db.query("SELECT MAX(Level), COUNT(*), MIN(ID), MIN(Level), MAX(ID) FROM Readings WHERE Level > 97").unwrap(),
+            vec![vec![Value::Int32(99), Value::Int32(42), Value::Int32(99), Value::Int32(98), Value::Int32(2498)]]
+        );
+    }
+
+    #[test]
+    fn test_count_in_transaction() {
+        let db = items_test_db("count_tx");
+
+        // COUNT(*) sees the rows an open transaction has written
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Items VALUES (50, 'e')").unwrap();
+        assert_eq!(db.query("SELECT COUNT(*) FROM Items").unwrap(), vec![vec![Value::Int32(5)]]);
+        db.execute("ROLLBACK").unwrap();
+        assert_eq!(db.query("SELECT COUNT(*) FROM Items").unwrap(), vec![vec![Value::Int32(4)]]);
+    }
+
+    #[test]
+    fn test_aggregate_index_lookup() {
+        let db = test_db("agg_index");
+        db.execute("CREATE TABLE Orders (ID INT PRIMARY KEY, Status TEXT, Total INT)").unwrap();
+        for i in 0..300 {
+            let status = ["open", "paid", "shipped"][i % 3];
+            db.execute(&format!("INSERT INTO Orders VALUES ({}, '{}', {})", i, status, i)).unwrap();
+        }
+        let count = "SELECT COUNT(*) FROM Orders WHERE Status = 'open'";
+        let sum = "SELECT SUM(Total) FROM Orders WHERE Status = 'open' AND Total < 30";
+        let scanned = (db.query(count).unwrap(), db.query(sum).unwrap());
+        assert_eq!(scanned.0, vec![vec![Value::Int32(100)]]);
+        assert_eq!(scanned.1, vec![vec![Value::Int64(135)]]);
+
+        db.execute("CREATE INDEX orders_status ON Orders (Status)").unwrap();
+        let plan = |sql: &str| -> Vec<Value> {
+            db.execute(&format!("EXPLAIN {}", sql)).unwrap().rows.into_iter().map(|row| row[0].clone()).collect()
+        };
+        // An equality the index answers in full is counted from the index alone
+        assert_eq!(db.query(count).unwrap(), scanned.0);
+        assert_eq!(
+            plan(count)[1],
+            Value::String("  -> Composite index count on Orders where Status = 'open'".to_string())
+        );
+        // Otherwise the index picks the rows and the rest of the condition is checked on them
+        assert_eq!(db.query(sum).unwrap(), scanned.1);
+        assert_eq!(
+            plan(sum)[2],
+            Value::String("    -> Composite index lookup on Orders reading [Total, Status]".to_string())
+        );
+        assert_eq!(db.query("SELECT MAX(Total) FROM Orders WHERE ID = 42").unwrap(), vec![vec![Value::Int32(42)]]);
+        assert!(matches!(&plan("SELECT COUNT(*) FROM Orders WHERE Total > 5")[2], Value::String(line) if line.starts_with("    -> Column scan")));
+        assert!(matches!(&plan("SELECT COUNT(*) FROM Orders")[1], Value::String(line) if line.starts_with("  -> Row count of Orders")));
+
+        // Deletes keep the count current; uncommitted rows fall back to a scan that sees them
+        db.execute("DELETE FROM Orders WHERE ID = 3").unwrap();
+        assert_eq!(db.query(count).unwrap(), vec![vec![Value::Int32(99)]]);
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Orders VALUES (300, 'open', 1)").unwrap();
+        assert_eq!(db.query(count).unwrap(), vec![vec![Value::Int32(100)]]);
+        db.execute("ROLLBACK").unwrap();
+        assert_eq!(db.query(count).unwrap(), vec![vec![Value::Int32(99)]]);
+    }
+
+    #[test]
+    fn test_exists_subquery() {
+        let db = test_db("exists");
+        db.execute("CREATE TABLE Users (ID INT PRIMARY KEY, Name TEXT)").unwrap();
+        db.execute("CREATE TABLE Orders (ID INT PRIMARY KEY, UserID INT, Status TEXT)").unwrap();
+        for (id, name) in [(1, "ann"), (2, "bob"), (3, "cy")] {
+            db.execute(&format!("INSERT INTO Users VALUES ({}, '{}')", id, name)).unwrap();
+        }
+        db.execute("INSERT INTO Orders VALUES (10, 1, 'paid')").unwrap();
+        let ids = |sql: &str| -> Vec<Value> { db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect() };
+        let all = vec![Value::Int32(1), Value::Int32(2), Value::Int32(3)];
+
+        assert_eq!(ids("SELECT ID FROM Users WHERE EXISTS (SELECT 1 FROM Orders WHERE Status = 'paid')"), all);
+        assert_eq!(ids("SELECT ID FROM Users WHERE EXISTS (SELECT ID FROM Orders WHERE Status = 'open')"), vec![]);
+        assert_eq!(ids("SELECT ID FROM Users WHERE not exists (SELECT * FROM Orders WHERE Status = 'open')"), all);
+        // The answer combines with the column tests around it
+        assert_eq!(
+            ids("SELECT ID FROM Users WHERE ID > 1 AND EXISTS (SELECT 1 FROM Orders WHERE UserID = 1 AND Status = 'paid')"),
+            vec![Value::Int32(2), Value::Int32(3)]
+        );
+        assert_eq!(
+            ids("SELECT ID FROM Users WHERE ID = 2 OR NOT EXISTS (SELECT 1 FROM Orders)"),
+            vec![Value::Int32(2)]
+        );
+        // An aggregate subquery always has a row, even over no matches
+        assert_eq!(ids("SELECT COUNT(*) FROM Users WHERE EXISTS (SELECT COUNT(*) FROM Orders WHERE UserID = 9)"), vec![Value::Int32(3)]);
+        assert_eq!(ids("SELECT COUNT(*) FROM Users WHERE NOT EXISTS (SELECT 1 FROM Orders)"), vec![Value::Int32(0)]);
+
+        // Rows an open transaction has written count for its subqueries
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Orders VALUES (11, 2, 'open')").unwrap();
+        assert_eq!(ids("SELECT ID FROM Users WHERE EXISTS (SELECT 1 FROM Orders WHERE Status = 'open')"), all);
+        db.execute("ROLLBACK").unwrap();
+
+        db.execute("DELETE FROM Users WHERE ID = 3 AND NOT EXISTS (SELECT 1 FROM Orders WHERE UserID = 3)").unwrap();
+        assert_eq!(ids("SELECT ID FROM Users"), vec![Value::Int32(1), Value::Int32(2)]);
+        let plan = db.execute("EXPLAIN SELECT ID FROM Users WHERE NOT EXISTS (SELECT 1 FROM Orders WHERE Status = 'open')").unwrap();
+        assert_eq!(
+            plan.rows[1][0],
+            Value::String("  -> Parallel filter: NOT EXISTS (SELECT 1 FROM Orders WHERE Status = 'open')".to_string())
+        );
+
+        assert!(matches!(db.execute("SELECT ID FROM Users WHERE EXISTS (SELECT 1 FROM Missing)"), Err(DbError::InvalidData(_))));
+        assert!(matches!(db.execute("SELECT ID FROM Users WHERE EXISTS (DELETE FROM Orders)"), Err(DbError::QueryError(_))));
+        assert!(matches!(db.execute("SELECT ID FROM Users WHERE EXISTS SELECT 1 FROM Orders"), Err(DbError::QueryError(_))));
+        assert_eq!(ids("SELECT COUNT(*) FROM Orders"), vec![Value::Int32(1)]);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let db = test_db("coalesce");
+        db.execute("CREATE TABLE People (ID INT PRIMARY KEY, Nick TEXT, Name TEXT, Score INT, Bonus FLOAT)").unwrap();
+        db.execute("INSERT INTO People VALUES (1, 'al', 'Alice', 10, 0.5)").unwrap();
+        db.execute("INSERT INTO People VALUES (2, NULL, 'Bob', NULL, 2.5)").unwrap();
+        db.execute("INSERT INTO People VALUES (3, NULL, NULL, NULL, NULL)").unwrap();
+        let column = |sql: &str| -> Vec<Value> { db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect() };
+        let text = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(
+            column("SELECT COALESCE(Nick, Name, 'anonymous') FROM People"),
+            vec![text("al"), text("Bob"), text("anonymous")]
+        );
+        assert_eq!(column("SELECT IFNULL(Nick, Name) FROM People"), vec![text("al"), text("Bob"), Value::Null]);
+        assert_eq!(column("SELECT coalesce(Score, NULL) FROM People"), vec![Value::Int32(10), Value::Null, Value::Null]);
+        // Numbers widen to the widest argument's type, whichever argument a row takes
+        assert_eq!(
+            column("SELECT COALESCE(Score, Bonus, 0) FROM People"),
+            vec![
+                Value::Float32(OrderedFloat(10.0)),
+                Value::Float32(OrderedFloat(2.5)),
+                Value::Float32(OrderedFloat(0.0)),
+            ]
+        );
+        let result = db.execute("SELECT ID, IFNULL(Nick, '-') FROM People WHERE ID > 1").unwrap();
+        assert_eq!(result.columns, vec!["ID".to_string(), "IFNULL(Nick, '-')".to_string()]);
+        assert_eq!(result.rows, vec![vec![Value::Int32(2), text("-")], vec![Value::Int32(3), text("-")]]);
+        assert_eq!(db.query("SELECT COALESCE(NULL, 7)").unwrap(), vec![vec![Value::Int32(7)]]);
+
+        assert!(matches!(db.execute("SELECT COALESCE(Score, Name) FROM People"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.execute("SELECT COALESCE(NULL, 1, 'x')"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.execute("SELECT IFNULL(Nick) FROM People"), Err(DbError::QueryError(_))));
+    }
+
+    #[test]
+    fn test_case_when() {
+        let db = test_db("case");
+        db.execute("CREATE TABLE Users (ID INT PRIMARY KEY, Age INT, Score FLOAT)").unwrap();
+        for (id, age, score) in [(1, "12", "1.5"), (2, "18", "NULL"), (3, "40", "9.0"), (4, "NULL", "4.0")] {
+            db.execute(&format!("INSERT INTO Users VALUES ({}, {}, {})", id, age, score)).unwrap();
+        }
+        let column = |sql: &str| -> Vec<Value> { db.query(sql).unwrap().into_iter().map(|row| row[0].clone()).collect() };
+        let text = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(
+            column("SELECT CASE WHEN Age >= 18 THEN 'adult' ELSE 'minor' END FROM Users"),
+            vec![text("minor"), text("adult"), text("adult"), text("minor")]
+        );
+        // Branches are tried in order; without ELSE a row no branch takes is NULL
+        assert_eq!(
+            column("SELECT case when Age < 13 then 'child' when Age < 20 then 'teen' when Age IS NOT NULL AND Age > 30 then 'adult' end FROM Users"),
+            vec![text("child"), text("teen"), text("adult"), Value::Null]
+        );
+        // Numeric branches widen to their common type
+        assert_eq!(
+            column("SELECT CASE WHEN Score IS NULL THEN 0 ELSE Score END FROM Users WHERE ID < 3"),
+            vec![Value::Float32(OrderedFloat(1.5)), Value::Float32(OrderedFloat(0.0))]
+        );
+        assert_eq!(
+            column("SELECT CASE WHEN ID = 1 THEN COALESCE(Age, 0) ELSE (CASE WHEN Age > 20 THEN 1 ELSE 2 END) END FROM Users"),
+            vec![Value::Int32(12), Value::Int32(2), Value::Int32(1), Value::Int32(2)]
+        );
+
+        assert!(matches!(
+            db.execute("SELECT CASE WHEN Age > 1 THEN 'x' ELSE 1 END FROM Users"),
+            Err(DbError::TypeMismatch(_))
+        ));
+        assert!(matches!(db.execute("SELECT CASE WHEN Age > 'x' THEN 1 END FROM Users"), Err(DbError::TypeMismatch(_))));
+        assert!(matches!(db.execute("SELECT CASE WHEN Missing > 1 THEN 1 END FROM Users"), Err(DbError::InvalidData(_))));
+        for sql in ["SELECT CASE WHEN Age > 1 THEN 1 FROM Users", "SELECT CASE Age WHEN 1 THEN 1 END FROM Users"] {
+            assert!(matches!(db.execute(sql), Err(DbError::QueryError(_))), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let db = test_db("concurrent_readers");
+        db.execute("CREATE TABLE Users (ID INT, Name TEXT)").unwrap();
+        db.execute("CREATE TABLE Orders (ID INT, Item TEXT)").unwrap();
+        db.execute("INSERT INTO Users VALUES (1, 'Ada')").unwrap();
+
+        let handle = db.storage();
+        let guard = handle.read().unwrap();
+        let users = guard.read_table("Users").unwrap();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::scope(|scope| {
+            let db = &*db;
+            // A SELECT goes through while another reader holds the storage lock and the table's
+            let reader = sender.clone();
+            scope.spawn(move || reader.send(db.query("SELECT Name FROM Users").map(|rows| rows.len())).unwrap());
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(10)).unwrap().unwrap(), 1);
+
+            // So does an INSERT into another table
+            let writer = sender.clone();
+            scope.spawn(move || writer.send(db.execute("INSERT INTO Orders VALUES (1, 'book')").map(|_| 1)).unwrap());
+            assert!(receiver.recv_timeout(Duration::from_secs(10)).unwrap().is_ok());
+
+            // An INSERT into the table waits for its reader to let go
+            scope.spawn(move || sender.send(db.execute("INSERT INTO Users VALUES (2, 'Grace')").map(|_| 1)).unwrap());
+            assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+            drop(users);
+            assert!(receiver.recv_timeout(Duration::from_secs(10)).unwrap().is_ok());
+        });
+        drop(guard);
+        assert_eq!(db.query("SELECT ID FROM Users").unwrap().len(), 2);
+        assert_eq!(db.query("SELECT ID FROM Orders").unwrap().len(), 1);
+    }
+
+    fn parts_test_db(test_name: &str) -> TestDb {
+        let db = test_db(test_name);
+        db.execute("CREATE TABLE Parts (PartID INT PRIMARY KEY, Name TEXT UNIQUE, Parent INT REFERENCES Parts(PartID))").unwrap();
+        db
+    }
+
+    #[test]
+    fn test_bulk_insert() {
+        let db = parts_test_db("bulk_insert");
+
+        // A row may reference one earlier in the same statement
+        db.execute("INSERT INTO Parts VALUES (1, 'frame', NULL), (2, 'wheel', 1), (3, 'spoke', 2)").unwrap();
+        db.execute("INSERT INTO Parts (PartID, Name) VALUES (4, 'seat'),(5, 'bell')").unwrap();
+        assert_eq!(db.query("SELECT PartID FROM Parts").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_bulk_insert_is_atomic() {
+        let db = parts_test_db("bulk_atomic");
+        db.execute("INSERT INTO Parts VALUES (1, 'frame', NULL)").unwrap();
+
+        // Any failing row rejects the whole statement
+        for sql in [
+            "INSERT INTO Parts VALUES (6, 'chain', NULL), (7, 'chain', NULL)",
+            "INSERT INTO Parts VALUES (6, 'chain', NULL), (6, 'pedal', NULL)",
+            "INSERT INTO Parts VALUES (6, 'chain', NULL), (7, 'pedal', 99)",
+            "INSERT INTO Parts VALUES (6, 'chain', NULL), (7, 'frame', NULL)",
+            "INSERT INTO Parts VALUES (6, 'chain', NULL), ('x', 'pedal', NULL)",
+        ] {
+            assert!(db.execute(sql).is_err(), "{}", sql);
+        }
+        assert_eq!(db.query("SELECT PartID FROM Parts").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_bulk_insert_in_transaction() {
+        let db = parts_test_db("bulk_tx");
+
+        // Inside a transaction the rows are applied together at COMMIT
+        db.execute("BEGIN").unwrap();
+        db.execute("INSERT INTO Parts VALUES (6, 'chain', NULL), (7, 'pedal', 6)").unwrap();
+        assert_eq!(db.query("SELECT PartID FROM Parts").unwrap().len(), 2);
+        db.execute("COMMIT").unwrap();
+        assert_eq!(
+            db.query("SELECT Name FROM Parts WHERE Parent = 6").unwrap(),
+            vec![vec![Value::String("pedal".to_string())]]
+        );
+    }
+
+    #[test]
+    fn test_insert_rows_batch() {
+        let mut db = parts_test_db("insert_rows");
+        let handle = db.storage();
+        let rows: Vec<Vec<Value>> = (8..2008).map(|id| vec![Value::Int32(id), Value::String(format!("part{}", id))]).collect();
+        let columns = vec!["PartID".to_string(), "Name".to_string()];
+        assert_eq!(handle.write().unwrap().insert_rows("Parts", &columns, rows).unwrap(), 2000);
+        assert_eq!(handle.read().unwrap().row_count("Parts"), 2000);
+
+        // Full buffers were flushed at once, but truncating the WAL waits for the next statement
+        assert!(handle.read().unwrap().checkpoint_due());
+        db.execute("INSERT INTO Parts VALUES (2008, 'part2008', NULL)").unwrap();
+        assert!(!handle.read().unwrap().checkpoint_due());
+        drop(handle);
+
+        // The batch survives a reopen
+        db.reopen();
+        assert_eq!(db.query("SELECT COUNT(*) FROM Parts").unwrap(), vec![vec![Value::Int32(2001)]]);
+    }
+
+    #[test]
+    fn test_bulk_insert_saves_counts_at_checkpoint() {
+        let mut db = test_db("bulk_counts");
+        db.execute("CREATE TABLE Items (ID INT, Name TEXT)").unwrap();
+        let saved = || {
+            let catalog: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(format!("{}/{}", db.dir(), schema::CATALOG_FILE)).unwrap()).unwrap();
+            let table = &catalog["tables"]["Items"];
+            (table["row_count"].as_u64().unwrap(), table["next_rowid"].as_u64().unwrap())
+        };
+        let (rows, next_rowid) = saved();
+
+        // Batches only reach the WAL and memory; the catalog is written once, at checkpoint
+        db.execute("INSERT INTO Items VALUES (1, 'a'), (2, 'b')").unwrap();
+        db.execute("INSERT INTO Items VALUES (3, 'c')").unwrap();
+        assert_eq!(saved(), (rows, next_rowid));
+        db.storage().write().unwrap().checkpoint().unwrap();
+        assert_eq!(saved(), (3, 4));
+
+        // After a crash the counts of unsaved batches are rebuilt from the WAL
+        db.execute("INSERT INTO Items VALUES (4, 'd'), (5, 'e')").unwrap();
+        assert_eq!(saved(), (3, 4));
+        db.reopen();
+        assert_eq!(db.storage().read().unwrap().schema().get_table("Items").unwrap().row_count, 5);
+        db.execute("INSERT INTO Items VALUES (6, 'f')").unwrap();
+        let rowids: Vec<Value> = (1..=6).map(Value::Int64).collect();
+        assert_eq!(db.query("SELECT ROWID FROM Items").unwrap(), rowids.into_iter().map(|v| vec![v]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_durability() {
+        let data_dir = TestDir::new("durability");
+        fs::create_dir_all(&data_dir).unwrap();
+        let path = data_dir.as_ref().join(config::CONFIG_FILE);
+        assert_eq!(Config::for_data_dir(&data_dir).unwrap().durability, Durability::Normal);
+        fs::write(&path, "durability = \"sometimes\"").unwrap();
+        assert!(matches!(Config::for_data_dir(&data_dir), Err(DbError::ConfigurationError(_))));
+        fs::write(&path, "durability = \"Full\"").unwrap();
+        assert_eq!(Config::for_data_dir(&data_dir).unwrap().durability, Durability::Full);
+
+        let mut db = TestDb::open(data_dir);
+        assert_eq!(db.query("SHOW durability").unwrap(), vec![vec![Value::String("full".to_string())]]);
+        db.execute("CREATE TABLE Events (ID INT)").unwrap();
+        db.execute("SET durability = off").unwrap();
+        db.execute("INSERT INTO Events VALUES (1)").unwrap();
+        db.storage().write().unwrap().checkpoint().unwrap();
+
+        // Reopening takes the configured level again, with every row intact
+        db.reopen();
+        assert_eq!(db.storage().read().unwrap().durability(), Durability::Full);
+        assert_eq!(db.query("SELECT ID FROM Events").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_durability_setting() {
+        let db = test_db("durability_setting");
+        db.execute("CREATE TABLE Events (ID INT, Kind TEXT)").unwrap();
+        for (level, id) in [("off", 1), ("normal", 2), ("full", 3)] {
+            db.execute(&format!("SET durability = {}", level)).unwrap();
+            assert_eq!(db.storage().read().unwrap().durability().to_string(), level);
+            db.execute(&format!("INSERT INTO Events VALUES ({}, '{}')", id, level)).unwrap();
+            db.storage().write().unwrap().checkpoint().unwrap();
+        }
+        assert!(matches!(db.execute("SET durability = sometimes"), Err(DbError::ConfigurationError(_))));
+
+        // The level is database-wide, so only an admin may change it
+        db.execute("CREATE USER root PASSWORD 'admin-pw'").unwrap();
+        db.execute("CREATE USER alice PASSWORD 'alice-pw'").unwrap();
+        db.execute("LOGIN alice PASSWORD 'alice-pw'").unwrap();
+        assert!(matches!(db.execute("SET durability = off"), Err(DbError::AuthorizationError(_))));
+        assert!(db.execute("SHOW durability").is_ok());
+        db.execute("LOGIN root PASSWORD 'admin-pw'").unwrap();
+        db.execute("SET durability = normal").unwrap();
+        assert_eq!(db.query("SELECT ID FROM Events").unwrap().len(), 3);
+    }
+
+    // A database whose config sets `passphrase`, with a Secrets table holding three rows
+    fn encrypted_test_db(test_name: &str, passphrase: &str) -> TestDb {
+        let data_dir = TestDir::new(test_name);
+        fs::create_dir_all(&data_dir).unwrap();
+        fs::write(data_dir.as_ref().join(config::CONFIG_FILE), format!("encryption_passphrase = \"{}\"", passphrase)).unwrap();
+        let db = TestDb::open(data_dir);
+        db.execute("CREATE TABLE Secrets (ID INT, Note TEXT ENCRYPTED, Score INT ENCRYPTED)").unwrap();
+        db.execute("INSERT INTO Secrets VALUES (1, 'launch-code-alpha', 10), (2, NULL, 20), (3, 'launch-code-gamma', 30)")
+            .unwrap();
+        db
+    }
+
+    #[test]
+    fn test_encryption_needs_passphrase() {
+        // Without a passphrase no table may declare an encrypted column
+        let db = test_db("encryption_off");
+        assert!(matches!(
+            db.execute("CREATE TABLE Secrets (ID INT, Note TEXT ENCRYPTED)"),
+            Err(DbError::SecurityError(_))
+        ));
+    }
+
+    #[test]
+    fn test_column_encryption() {
+        let db = encrypted_test_db("encryption", "correct horse");
+        let contains = |file: &str, text: &str| {
+            let bytes = fs::read(format!("{}/{}", db.dir(), file)).unwrap();
+            bytes.windows(text.len()).any(|w| w == text.as_bytes())
+        };
+        assert!(!format!("{:?}", Config::for_data_dir(db.dir()).unwrap()).contains("correct horse"));
+        assert!(db.schema().unwrap().get_table("Secrets").unwrap().create_sql().contains("Note TEXT ENCRYPTED"));
+        assert!(!contains("wal/wal.log", "launch-code-alpha"));
+        db.storage().write().unwrap().checkpoint().unwrap();
+        assert!(!contains("columns/Secrets_Note.dat", "launch-code-alpha"));
+        assert!(!contains(storage::encryption::ENCRYPTION_FILE, "correct horse"));
+        // Blocks keep no range, so conditions on an encrypted column still find every match
+        assert_eq!(
+            db.query("SELECT ID, Note FROM Secrets WHERE Score > 15").unwrap(),
+            vec![
+                vec![Value::Int32(2), Value::Null],
+                vec![Value::Int32(3), Value::String("launch-code-gamma".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encrypted_columns_are_not_indexed() {
+        let db = encrypted_test_db("encryption_index", "correct horse");
+        // Nothing keyed or indexed may rest on an encrypted column
+        assert!(matches!(db.execute("CREATE INDEX secrets_note ON Secrets (Note)"), Err(DbError::IndexError(_))));
+        assert!(matches!(
+            db.execute("CREATE TABLE Keys (ID INT, Token TEXT UNIQUE ENCRYPTED)"),
+            Err(DbError::SchemaError(_))
+        ));
+        db.execute("CREATE TABLE Hidden (ID INT ENCRYPTED, Name TEXT ENCRYPTED)").unwrap();
+        assert!(db.storage().read().unwrap().list_indexes("Hidden").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_encryption_passphrase_on_reopen() {
+        let mut db = encrypted_test_db("encryption_reopen", "correct horse");
+        db.storage().write().unwrap().checkpoint().unwrap();
+        db.close();
+        let path = std::path::Path::new(db.dir()).join(config::CONFIG_FILE);
+
+        // A wrong passphrase is refused outright; a missing one only locks the encrypted columns
+        fs::write(&path, "encryption_passphrase = \"wrong horse\"").unwrap();
+        assert!(matches!(Database::open(db.dir()), Err(DbError::SecurityError(_))));
+        fs::remove_file(&path).unwrap();
+        db.reopen();
+        assert_eq!(db.query("SELECT ID FROM Secrets").unwrap().len(), 3);
+        assert!(matches!(db.query("SELECT Note FROM Secrets"), Err(DbError::SecurityError(_))));
+        assert!(matches!(db.query("SELECT ID FROM Secrets WHERE Score > 15"), Err(DbError::SecurityError(_))));
+        assert!(matches!(db.execute("INSERT INTO Secrets VALUES (4, 'x', 40)"), Err(DbError::SecurityError(_))));
+
+        fs::write(&path, "encryption_passphrase = \"correct horse\"").unwrap();
+        db.reopen();
+        assert_eq!(
+            db.query("SELECT Note FROM Secrets WHERE ID = 1").unwrap(),
+            vec![vec![Value::String("launch-code-alpha".to_string())]]
+        );
+    }
+
+    #[test]
+    fn test_plugin_loading_errors() {
+        let data_dir = TestDir::new("plugin_load");
+        fs::create_dir_all(&data_dir).unwrap();
+        let mut plugins = PluginManager::new();
+
+        let missing = data_dir.as_ref().join("missing.so");
+        assert!(matches!(plugins.load_plugin(&missing), Err(DbError::PluginError(_))));
+        let not_a_library = data_dir.as_ref().join("plugin.so");
+        fs::write(&not_a_library, "not a shared library").unwrap();
+        assert!(matches!(plugins.load_plugin(&not_a_library), Err(DbError::PluginError(_))));
+        assert!(plugins.list_plugins().is_empty());
+    }
+
+    #[test]
+    fn test_plugin_missing_saved_path_is_kept() {
+        let data_dir = TestDir::new("plugin_missing");
+        fs::create_dir_all(&data_dir).unwrap();
+        let missing = data_dir.as_ref().join("missing.so");
+
+        // A registered library that no longer loads is skipped at startup but kept on the list
+        let registry = data_dir.as_ref().join(plugins::PLUGIN_REGISTRY_FILE);
+        fs::write(&registry, serde_json::to_string(&vec![&missing]).unwrap()).unwrap();
+        let db = TestDb::open(data_dir);
+        let mut plugins = db.plugin_manager().lock().unwrap();
+        assert_eq!(plugins.registered_paths(), std::slice::from_ref(&missing));
+        assert_eq!(plugins.list_plugins(), vec![("stats", "1.0.0")]);
+        plugins.unregister_plugin("stats").unwrap();
+        assert_eq!(plugins.registered_paths(), std::slice::from_ref(&missing));
+    }
+
+    // Error-level log lines of every test, kept so a test can check what was logged
+    struct CapturingLogger;
+
+    static CAPTURED_ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Error
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                CAPTURED_ERRORS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_errors() {
+        static CAPTURING_LOGGER: CapturingLogger = CapturingLogger;
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURING_LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Error);
+        });
+    }
+
+    fn logged_error_containing(text: &str) -> bool {
+        CAPTURED_ERRORS.lock().unwrap().iter().any(|line| line.contains(text))
+    }
+
+    // Registers the example plugin as if loaded from `path`; the library handle is libc, since
+    // the tests have no plugin library to load
+    fn register_example_library(plugins: &mut PluginManager, path: &std::path::Path) -> String {
+        let library = unsafe { libloading::Library::new("libc.so.6") }.unwrap();
+        plugins.register_library(Box::new(plugins::ExamplePlugin::new()), path, library).unwrap()
+    }
+
+    fn saved_plugin_paths(data_dir: &str) -> Vec<std::path::PathBuf> {
+        let registry = std::path::Path::new(data_dir).join(plugins::PLUGIN_REGISTRY_FILE);
+        serde_json::from_str(&fs::read_to_string(registry).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_plugin_paths_are_saved() {
+        let data_dir = TestDir::new("plugin_saved");
+        fs::create_dir_all(&data_dir).unwrap();
+        let library = data_dir.as_ref().join("libexample.so");
+        fs::write(&library, "stands in for a plugin library").unwrap();
+        let mut plugins = PluginManager::open(&data_dir);
+
+        assert_eq!(register_example_library(&mut plugins, &library), "example");
+        let saved = fs::canonicalize(&library).unwrap();
+        assert_eq!(plugins.plugin_path("example"), Some(saved.as_path()));
+        assert_eq!(plugins.registered_paths(), std::slice::from_ref(&saved));
+        assert_eq!(saved_plugin_paths(&data_dir), vec![saved]);
+    }
+
+    #[test]
+    fn test_plugin_bad_saved_path_is_skipped() {
+        capture_errors();
+        let data_dir = TestDir::new("plugin_skipped");
+        fs::create_dir_all(&data_dir).unwrap();
+        let library = data_dir.as_ref().join("libexample.so");
+        fs::write(&library, "not a shared library").unwrap();
+        let mut plugins = PluginManager::open(&data_dir);
+        register_example_library(&mut plugins, &library);
+        drop(plugins);
+
+        // The saved path no longer loads: the error is logged and the other plugins still start
+        let saved = fs::canonicalize(&library).unwrap();
+        let db = TestDb::open(data_dir);
+        let plugins = db.plugin_manager().lock().unwrap();
+        assert_eq!(plugins.list_plugins(), vec![("stats", "1.0.0")]);
+        assert_eq!(plugins.registered_paths(), std::slice::from_ref(&saved));
+        assert!(logged_error_containing(&format!("Cannot load {}", saved.display())));
+    }
+
+    #[test]
+    fn test_plugin_unregister_forgets_path() {
+        let data_dir = TestDir::new("plugin_forget");
+        fs::create_dir_all(&data_dir).unwrap();
+        let library = data_dir.as_ref().join("libexample.so");
+        fs::write(&library, "stands in for a plugin library").unwrap();
+        let mut plugins = PluginManager::open(&data_dir);
+        register_example_library(&mut plugins, &library);
+        assert_eq!(saved_plugin_paths(&data_dir).len(), 1);
+
+        plugins.unregister_plugin("example").unwrap();
+        assert!(plugins.registered_paths().is_empty());
+        assert!(saved_plugin_paths(&data_dir).is_empty());
+        assert_eq!(plugins.plugin_path("example"), None);
+        drop(plugins);
+
+        // Nothing is loaded for it on the next start
+        let plugins = PluginManager::open(&data_dir);
+        assert!(plugins.registered_paths().is_empty());
+        assert!(plugins.list_plugins().is_empty());
+    }
+
+    struct AuditPlugin {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        panic_on: Option<&'static str>,
+    }
+
+    impl Plugin for AuditPlugin {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn version(&self) -> &str {
+            "1.0.0"
+        }
+
+        fn initialize(&mut self) -> Result<(), DbError> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> Result<(), DbError> {
+            Ok(())
+        }
+
+        fn execute(&self, _command: &str, _args: &[Value]) -> Result<Value, DbError> {
+            Ok(Value::Null)
+        }
+
+        fn before_query(&self, sql: &str) {
+            if self.panic_on.is_some_and(|word| sql.contains(word)) {
+                panic!("refusing {}", sql);
+            }
+            self.log.lock().unwrap().push(format!("before {}", sql));
+        }
+
+        fn after_query(&self, sql: &str, rows: usize) {
+            self.log.lock().unwrap().push(format!("after {} -> {}", sql, rows));
+        }
+    }
+
+    #[test]
+    fn test_plugin_query_hooks() {
+        let db = test_db("plugin_hooks");
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let audit = AuditPlugin { name: "audit", log: Arc::clone(&log), panic_on: Some("Secrets") };
+        db.plugin_manager().lock().unwrap().register_plugin(Box::new(audit)).unwrap();
+
+        db.execute("CREATE TABLE Notes (ID INT)").unwrap();
+        db.execute("INSERT INTO Notes VALUES (1)").unwrap();
+        db.execute("SELECT ID FROM Notes").unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "before CREATE TABLE Notes (ID INT)",
+                "after CREATE TABLE Notes (ID INT) -> 0",
+                "before INSERT INTO Notes VALUES (1)",
+                "after INSERT INTO Notes VALUES (1) -> 0",
+                "before SELECT ID FROM Notes",
+                "after SELECT ID FROM Notes -> 1",
+            ]
+        );
+
+        // A panicking hook becomes an error and the statement doesn't run
+        let result = db.execute("CREATE TABLE Secrets (ID INT)");
+        assert!(matches!(result, Err(DbError::PluginError(ref s)) if s.contains("audit panicked in before_query")));
+        assert!(db.schema().unwrap().get_table("Secrets").is_none());
+        assert_eq!(db.query("SELECT ID FROM Notes").unwrap(), vec![vec![Value::Int32(1)]]);
+    }
+
+    #[test]
+    fn test_stats_plugin() {
+        let db = test_db("stats");
+        db.execute("CREATE TABLE Readings (ID INT, Temp FLOAT, Site STRING)").unwrap();
+        for (id, temp, site) in [(1, "3.5", "'north'"), (2, "NULL", "'south'"), (3, "-1.5", "'north'"), (4, "3.5", "NULL")] {
+            db.execute(&format!("INSERT INTO Readings VALUES ({}, {}, {})", id, temp, site)).unwrap();
+        }
+
+        let stats = StatsPlugin::new(db.storage());
+        let temp = stats.analyze("Readings", "Temp").unwrap();
+        assert_eq!(temp.columns, vec!["min", "max", "distinct", "nulls", "mean"]);
+        assert_eq!(
+            temp.rows,
+            vec![vec![
+                Value::Float32(OrderedFloat(-1.5)),
+                Value::Float32(OrderedFloat(3.5)),
+                Value::Int32(2),
+                Value::Int32(1),
+                Value::Float32(OrderedFloat(5.5 / 3.0)),
+            ]]
+        );
+        let site = stats.analyze("Readings", "Site").unwrap();
+        assert_eq!(
+            site.rows,
+            vec![vec![
+                Value::String("north".to_string()),
+                Value::String("south".to_string()),
+                Value::Int32(2),
+                Value::Int32(1),
+                Value::Null,
+            ]]
+        );
+        assert!(matches!(stats.analyze("Readings", "Missing"), Err(DbError::InvalidData(_))));
+
+        // Registered with every database and reachable through the plugin manager
+        let args = [Value::String("Readings".to_string()), Value::String("ID".to_string())];
+        let json = db.plugin_manager().lock().unwrap().execute_plugin("stats", "analyze", &args).unwrap();
+        assert_eq!(json, Value::String(r#"[{"min":1,"max":4,"distinct":4,"nulls":0,"mean":2.5}]"#.to_string()));
+    }
+
+    #[test]
+    fn test_log_rotation() {
+        use log::Log;
+        let data_dir = TestDir::new("log_rotation");
+        fs::create_dir_all(&data_dir).unwrap();
+        let log_path = data_dir.as_ref().join("vddb.log");
+        let logger = logging::Logger::new(&log_path, log::LevelFilter::Info, 200, 2).unwrap();
+        for i in 0..12 {
+            logger.log(&log::Record::builder().level(log::Level::Info).args(format_args!("entry {:02}", i)).build());
+        }
+
+        let archive = |n: usize| fs::read_to_string(format!("{}.{}", log_path.display(), n));
+        let current = fs::read_to_string(&log_path).unwrap();
+        assert!(current.len() <= 200 && current.contains("entry 11"));
+        assert!(archive(1).unwrap().len() <= 200);
+        assert!(archive(2).is_ok());
+        assert!(archive(3).is_err());
+        // Nothing is lost between the current file and the newest archive
+        let last = current.lines().next().unwrap().split("entry ").nth(1).unwrap().parse::<usize>().unwrap();
+        assert!(archive(1).unwrap().contains(&format!("entry {:02}", last - 1)));
+    }
+
+    #[test]
+    fn test_json_log_format() {
+        use log::Log;
+        let data_dir = TestDir::new("json_log");
+        fs::create_dir_all(&data_dir).unwrap();
+        let log_path = data_dir.as_ref().join("vddb.log");
+        let logger = logging::Logger::new(&log_path, log::LevelFilter::Info, logging::DEFAULT_MAX_LOG_BYTES, 1)
+            .unwrap()
+            .with_format(logging::LogFormat::Json);
+        logger.log(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("vddb::storage")
+                .file(Some("src/storage/mod.rs"))
+                .line(Some(42))
+                .args(format_args!("disk \"{}\" nearly full", "data"))
+                .build(),
+        );
+
+        let line = fs::read_to_string(&log_path).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(entry["level"], "WARN");
+        assert_eq!(entry["file"], "src/storage/mod.rs");
+        assert_eq!(entry["line"], 42);
+        assert_eq!(entry["target"], "vddb::storage");
+        assert_eq!(entry["message"], "disk \"data\" nearly full");
+        assert!(chrono::DateTime::parse_from_rfc3339(entry["timestamp"].as_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_repl_highlighting() {
+        assert_eq!(
+            repl::highlight_sql("select Name from T2 where Age > -4.5 and Name = 'it''s 9'"),
+            "\x1b[1;34mselect\x1b[0m Name \x1b[1;34mfrom\x1b[0m T2 \x1b[1;34mwhere\x1b[0m Age > \x1b[33m-4.5\x1b[0m \
+             \x1b[1;34mand\x1b[0m Name = \x1b[32m'it'\x1b[0m\x1b[32m's 9'\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_transaction_queries() {
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("tx_queries").unwrap();
+
+        // Create table
+        let create_query = Query::CreateTable {
+            table: "Test".to_string(),
+            columns: vec![
+                Column::new("ID", DataType::Int32),
+                Column::new("Value", DataType::String),
+            ],
+        };
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(create_query);
+        tx_manager.commit_transaction(tx).unwrap();
+
+        // Start transaction, insert, and commit
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::Insert {
+            table: "Test".to_string(),
+            columns: vec![],
+            rows: vec![vec![Value::Int32(1), Value::String("Committed".to_string())]],
+            on_conflict: None,
+        });
+        tx_manager.commit_transaction(tx).unwrap();
+
+        // Start transaction, insert, and rollback
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(Query::Insert {
+            table: "Test".to_string(),
+            columns: vec![],
+            rows: vec![vec![Value::Int32(2), Value::String("RolledBack".to_string())]],
+            on_conflict: None,
+        });
+        tx_manager.rollback_transaction(tx).unwrap();
+
+        // Verify only the committed row is present
+        let select_query = Query::Select {
+            table: Some("Test".to_string()),
+            columns: vec!["Value".to_string()],
+            condition: None,
+            order_by: Vec::new(),
+        };
+        let mut tx = tx_manager.begin_transaction();
+        tx.add_query(select_query);
+        let results = tx_manager.commit_transaction(tx).unwrap();
+        assert_eq!(results, vec![vec![Value::String("Committed".to_string())]]);
     }
 
     #[test]
     fn test_index_performance() -> Result<(), DbError> {
         use rayon::prelude::*;
-        let (data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("index_perf")?;
+        let (_data_dir, _schema, _storage, mut tx_manager, _plugin_manager) = setup_test_db("index_perf")?;
         
         // Create table with indexed columns
         let create_query = Query::CreateTable {
             table: "Performance".to_string(),
             columns: vec![
-                ("ID".to_string(), DataType::Int32),
-                ("Value".to_string(), DataType::Int32),
+                Column::new("ID", DataType::Int32),
+                Column::new("Value", DataType::Int32),
             ],
         };
         let mut tx = tx_manager.begin_transaction();
@@ -585,10 +5016,12 @@ mod tests {
                 for i in chunk {
                     tx.add_query(Query::Insert {
                         table: "Performance".to_string(),
-                        values: vec![
+                        columns: vec![],
+                        rows: vec![vec![
                             Value::Int32(*i),
                             Value::Int32(*i * 2),
-                        ],
+                        ]],
+                        on_conflict: None,
                     });
                 }
                 let _ = {
@@ -605,9 +5038,10 @@ mod tests {
         let mut tx = tx_manager.begin_transaction();
         for i in 1..=1000 {
             tx.add_query(Query::Select {
-                table: "Performance".to_string(),
+                table: Some("Performance".to_string()),
                 columns: vec!["Value".to_string()],
                 condition: Some(Condition::Equal("ID".to_string(), Value::Int32(i))),
+                order_by: Vec::new(),
             });
         }
         tx_manager.commit_transaction(tx)?;
@@ -619,12 +5053,13 @@ mod tests {
         let mut tx = tx_manager.begin_transaction();
         for i in 1..=100 {
             tx.add_query(Query::Select {
-                table: "Performance".to_string(),
+                table: Some("Performance".to_string()),
                 columns: vec!["Value".to_string()],
                 condition: Some(Condition::And(
                     Box::new(Condition::GreaterThanOrEqual("ID".to_string(), Value::Int32(i))),
                     Box::new(Condition::LessThanOrEqual("ID".to_string(), Value::Int32(i + 100))),
                 )),
+                order_by: Vec::new(),
             });
         }
         tx_manager.commit_transaction(tx)?;
@@ -632,7 +5067,6 @@ mod tests {
         println!("Range lookup time: {:?}", range_time);
 
         // Cleanup
-        cleanup_test_db(&data_dir);
         Ok(())
     }
 }
@@ -1,30 +1,114 @@
-use log::{Level, LevelFilter, Metadata, Record};
+use log::{error, info, warn, LevelFilter, Metadata, Record};
 use chrono::Local;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
+pub const DEFAULT_MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_LOG_ARCHIVES: usize = 5;
+
+// The open log file and its size, guarded together so rotation can't race a write
+struct LogFile {
+    file: File,
+    path: PathBuf,
+    size: u64,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            file,
+            path: path.to_path_buf(),
+            size,
+        })
+    }
+
+    // vddb.log -> vddb.log.1 -> vddb.log.2 ..., dropping whatever falls past `max_archives`
+    fn rotate(&mut self, max_archives: usize) -> Result<(), std::io::Error> {
+        let archive = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if max_archives == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..max_archives).rev() {
+                if archive(n).exists() {
+                    fs::rename(archive(n), archive(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, archive(1))?;
+        }
+        *self = LogFile::open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Layout of each line written to the log file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `[timestamp] LEVEL [file:line] target - message`
+    #[default]
+    Text,
+    /// One JSON object per line with `timestamp` (RFC 3339), `level`, `file`, `line`,
+    /// `target` and `message` fields.
+    Json,
+}
+
 pub struct Logger {
-    file: Mutex<File>,
+    file: Mutex<LogFile>,
     level: LevelFilter,
+    max_bytes: u64,
+    max_archives: usize,
+    format: LogFormat,
 }
 
 impl Logger {
-    pub fn new(log_path: &Path, level: LevelFilter) -> Result<Self, std::io::Error> {
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(log_path)?;
-
+    /// Appends to `log_path`, rotating it once it would grow past `max_bytes` and keeping at
+    /// most `max_archives` rotated files (`vddb.log.1` being the newest).
+    pub fn new(log_path: &Path, level: LevelFilter, max_bytes: u64, max_archives: usize) -> Result<Self, std::io::Error> {
         Ok(Logger {
-            file: Mutex::new(file),
+            file: Mutex::new(LogFile::open(log_path)?),
             level,
+            max_bytes,
+            max_archives,
+            format: LogFormat::default(),
         })
     }
 
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn format_entry(&self, record: &Record) -> String {
+        let now = Local::now();
+        match self.format {
+            LogFormat::Text => format!(
+                "[{}] {} [{}:{}] {} - {}\n",
+                now.format("%Y-%m-%d %H:%M:%S%.3f"),
+                record.level(),
+                record.file().unwrap_or("unknown"),
+                record.line().unwrap_or(0),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Json => {
+                let entry = serde_json::json!({
+                    "timestamp": now.to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "file": record.file(),
+                    "line": record.line(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                format!("{}\n", entry)
+            }
+        }
+    }
+
     pub fn init(log_path: &Path, level: LevelFilter) -> Result<(), Box<dyn std::error::Error>> {
-        let logger = Logger::new(log_path, level)?;
+        let logger = Logger::new(log_path, level, DEFAULT_MAX_LOG_BYTES, DEFAULT_LOG_ARCHIVES)?;
         log::set_boxed_logger(Box::new(logger))?;
         log::set_max_level(level);
         Ok(())
@@ -38,28 +122,25 @@ impl log::Log for Logger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let now = Local::now();
-            let timestamp = now.format("%Y-%m-%d %H:%M:%S%.3f");
-            
-            let log_entry = format!(
-                "[{}] {} [{}:{}] {} - {}\n",
-                timestamp,
-                record.level(),
-                record.file().unwrap_or("unknown"),
-                record.line().unwrap_or(0),
-                record.target(),
-                record.args()
-            );
-
-            if let Ok(mut file) = self.file.lock() {
-                let _ = file.write_all(log_entry.as_bytes());
+            let log_entry = self.format_entry(record);
+            if let Ok(mut log_file) = self.file.lock() {
+                let entry_size = log_entry.len() as u64;
+                if log_file.size > 0 && log_file.size + entry_size > self.max_bytes {
+                    if let Err(e) = log_file.rotate(self.max_archives) {
+                        // Keep logging to the current file rather than losing the entry
+                        eprintln!("Failed to rotate {}: {}", log_file.path.display(), e);
+                    }
+                }
+                if log_file.file.write_all(log_entry.as_bytes()).is_ok() {
+                    log_file.size += entry_size;
+                }
             }
         }
     }
 
     fn flush(&self) {
-        if let Ok(mut file) = self.file.lock() {
-            let _ = file.flush();
+        if let Ok(mut log_file) = self.file.lock() {
+            let _ = log_file.file.flush();
         }
     }
 }
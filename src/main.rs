@@ -1,17 +1,60 @@
 use std::env;
-use vddb::{create_database, Repl, DbError};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
+use vddb::{create_database, serve, Config, Repl, DbError};
 use log::{info, error};
 
 fn main() -> Result<(), DbError> {
-    // Initialize logging
-    env_logger::init();
+    // Settings come from `--config <path>` if given, else from vddb.toml in the data directory
+    let args: Vec<String> = env::args().collect();
+    let config = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => {
+            let path = args
+                .get(i + 1)
+                .ok_or_else(|| DbError::ConfigurationError("--config needs a file path".to_string()))?;
+            Config::load(Path::new(path))?
+        }
+        None => {
+            let data_dir = env::var("VDDB_DATA_DIR").unwrap_or_else(|_| vddb::config::DEFAULT_DATA_DIR.to_string());
+            Config::for_data_dir(&data_dir)?
+        }
+    };
+    config.init_runtime()?;
     info!("Starting VDDB application");
 
-    // Get data directory from environment or use default
-    let data_dir = env::var("VDDB_DATA_DIR").unwrap_or_else(|_| "./data".to_string());
-    
     // Create database with all components
-    let (schema, storage, tx_manager, plugin_manager) = create_database(&data_dir)?;
+    let (schema, storage, tx_manager, plugin_manager) = create_database(&config)?;
+
+    // On SIGTERM flush everything to disk before exiting; the storage lock makes this wait for
+    // a statement that is still running
+    let mut signals = Signals::new([SIGTERM])?;
+    let signal_storage = Arc::clone(&storage);
+    std::thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            info!("Received SIGTERM, shutting down");
+            match signal_storage.write() {
+                Ok(mut storage) => {
+                    if let Err(e) = storage.shutdown() {
+                        error!("Shutdown failed: {}", e);
+                    }
+                }
+                Err(e) => error!("Shutdown failed: {}", e),
+            }
+            log::logger().flush();
+            std::process::exit(0);
+        }
+    });
+
+    // Serve remote clients instead of starting the shell, e.g. VDDB_LISTEN=127.0.0.1:5433
+    if let Ok(listen) = env::var("VDDB_LISTEN") {
+        let addr: SocketAddr = listen
+            .parse()
+            .map_err(|e| DbError::NetworkError(format!("Invalid VDDB_LISTEN address {}: {}", listen, e)))?;
+        return serve(addr, storage);
+    }
     
     // Create and run REPL
     let mut repl = Repl::new(schema, storage, tx_manager, plugin_manager)?;
@@ -1,7 +1,9 @@
 use metrics::{counter, gauge, histogram};
+use std::net::SocketAddr;
 use std::time::Instant;
 use metrics_exporter_prometheus::PrometheusBuilder;
 
+#[derive(Debug)]
 pub struct QueryMetrics {
     start_time: Instant,
 }
@@ -31,16 +33,22 @@ impl QueryMetrics {
         gauge!("memory.usage", bytes as f64);
     }
 
+    pub fn record_table_size(&self, table: &str, rows: u64, bytes: u64) {
+        gauge!("table.rows", rows as f64, "table" => table.to_string());
+        gauge!("table.bytes", bytes as f64, "table" => table.to_string());
+    }
+
     pub fn record_cache_hits(&self, hits: u64, misses: u64) {
         counter!("cache.hits", hits);
         counter!("cache.misses", misses);
     }
 }
 
-pub fn init_metrics() -> Result<(), Box<dyn std::error::Error>> {
+/// Serves Prometheus metrics over HTTP on `addr`.
+pub fn init_metrics(addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
     let builder = PrometheusBuilder::new();
     builder
-        .with_http_listener(([127, 0, 0, 1], 9000))
+        .with_http_listener(addr)
         .install()?;
     Ok(())
 } 
\ No newline at end of file
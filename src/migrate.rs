@@ -0,0 +1,109 @@
+use crate::query::parser::{split_statements, strip_comments};
+use crate::query::QueryResult;
+use crate::storage::SharedStorage;
+use crate::types::{DbError, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Table recording the migrations already applied, one row per version.
+pub const MIGRATIONS_TABLE: &str = "schema_migrations";
+// Copy of the data directory taken before each migration, inside the data directory itself
+const BACKUP_DIR: &str = "migration_backup";
+
+/// A migration file: `<version>_<description>.sql`, e.g. `0002_add_orders.sql`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub version: i32,
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// The `.sql` files in `dir`, ordered by the version number each name starts with. Other files
+/// are ignored; a `.sql` file without a version, or two files with the same one, are errors.
+pub fn list_migrations(dir: &Path) -> Result<Vec<Migration>, DbError> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| DbError::MigrationError(format!("Cannot read migrations in {}: {}", dir.display(), e)))?;
+    let mut migrations: Vec<Migration> = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("sql") {
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let digits: String = name.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let version = digits
+            .parse::<i32>()
+            .map_err(|_| DbError::MigrationError(format!("{}: name must start with a version number", name)))?;
+        if let Some(other) = migrations.iter().find(|m| m.version == version) {
+            return Err(DbError::MigrationError(format!("{} and {} have the same version {}", other.name, name, version)));
+        }
+        migrations.push(Migration { version, name, path });
+    }
+    migrations.sort_by_key(|m| m.version);
+    Ok(migrations)
+}
+
+/// Applies, in version order, the migrations in `dir` not yet recorded in `schema_migrations`,
+/// running each statement through `execute`. Returns the names of the migrations applied.
+///
+/// Each migration is all or nothing. DDL cannot run inside BEGIN, so rather than a transaction
+/// the database's files are copied before a migration starts and restored if any of its
+/// statements fails; the error is a `MigrationError` naming the file. Migrations applied
+/// before it stay applied. Files may hold `--` and `/* */` comments.
+pub fn run_migrations(
+    storage: &SharedStorage,
+    dir: &Path,
+    mut execute: impl FnMut(&str) -> Result<QueryResult, DbError>,
+) -> Result<Vec<String>, DbError> {
+    let migrations = list_migrations(dir)?;
+    let lock = || storage.write().map_err(|e| DbError::ConcurrencyError(e.to_string()));
+    let (has_table, backup) = {
+        let storage = lock()?;
+        (
+            storage.schema().get_table(MIGRATIONS_TABLE).is_some(),
+            Path::new(storage.data_dir()).join(BACKUP_DIR),
+        )
+    };
+    if !has_table {
+        execute(&format!(
+            "CREATE TABLE {} (version INT PRIMARY KEY, name TEXT NOT NULL, applied_at TIMESTAMP DEFAULT NOW())",
+            MIGRATIONS_TABLE
+        ))?;
+    }
+    let applied: Vec<Value> = execute(&format!("SELECT version FROM {}", MIGRATIONS_TABLE))?
+        .rows
+        .into_iter()
+        .filter_map(|row| row.into_iter().next())
+        .collect();
+
+    let mut ran = Vec::new();
+    for migration in migrations.into_iter().filter(|m| !applied.contains(&Value::Int32(m.version))) {
+        let sql = fs::read_to_string(&migration.path)?;
+        let body = strip_comments(&sql).map_err(|e| DbError::MigrationError(format!("{}: {}", migration.name, e)))?;
+        let record = format!(
+            "INSERT INTO {} (version, name) VALUES ({}, {})",
+            MIGRATIONS_TABLE,
+            migration.version,
+            Value::String(migration.name.clone()).to_sql_literal()
+        );
+
+        if backup.exists() {
+            fs::remove_dir_all(&backup)?;
+        }
+        lock()?.backup_to(&backup)?;
+        let outcome = split_statements(&body)
+            .into_iter()
+            .chain(std::iter::once(record.as_str()))
+            .try_for_each(|statement| execute(statement).map(|_| ()));
+        if let Err(e) = outcome {
+            log::warn!("Migration {} failed, restoring the database: {}", migration.name, e);
+            lock()?.restore_backup(&backup)?;
+            fs::remove_dir_all(&backup)?;
+            return Err(DbError::MigrationError(format!("{}: {}", migration.name, e)));
+        }
+        fs::remove_dir_all(&backup)?;
+        log::info!("Applied migration {}", migration.name);
+        ran.push(migration.name);
+    }
+    Ok(ran)
+}
\ No newline at end of file
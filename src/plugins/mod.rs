@@ -1,6 +1,44 @@
-use crate::types::{DbError, Value};
+use crate::logging::log_error;
+use crate::query::planner::QueryEngine;
+use crate::query::{Aggregation, Query, QueryResult};
+use crate::storage::SharedStorage;
+use crate::types::{DataType, DbError, Value};
+use libloading::{Library, Symbol};
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::ffi::{c_char, CStr};
+use std::path::{Path, PathBuf};
+
+/// Version a plugin library must be built against; checked before anything else is called.
+pub const PLUGIN_ABI_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+/// `extern "C" fn() -> *const c_char` returning the library's `PLUGIN_ABI_VERSION`.
+pub const PLUGIN_VERSION_SYMBOL: &[u8] = b"vddb_plugin_version";
+/// `extern "C" fn() -> *mut Box<dyn Plugin>` constructing the plugin.
+pub const PLUGIN_CONSTRUCTOR_SYMBOL: &[u8] = b"vddb_create_plugin";
+/// JSON list, in the data directory, of plugin libraries to load at startup.
+pub const PLUGIN_REGISTRY_FILE: &str = "plugins.json";
+
+/// Exports the entry points `PluginManager::load_plugin` looks for from a `cdylib`:
+///
+/// ```ignore
+/// vddb::declare_plugin!(MyPlugin::new);
+/// ```
+#[macro_export]
+macro_rules! declare_plugin {
+    ($constructor:path) => {
+        #[no_mangle]
+        pub extern "C" fn vddb_plugin_version() -> *const std::ffi::c_char {
+            $crate::plugins::PLUGIN_ABI_VERSION.as_ptr() as *const std::ffi::c_char
+        }
+
+        #[no_mangle]
+        pub extern "C" fn vddb_create_plugin() -> *mut Box<dyn $crate::plugins::Plugin> {
+            let plugin: Box<dyn $crate::plugins::Plugin> = Box::new($constructor());
+            Box::into_raw(Box::new(plugin))
+        }
+    };
+}
 
 pub trait Plugin: Send + Sync {
     fn name(&self) -> &str;
@@ -8,19 +46,134 @@ pub trait Plugin: Send + Sync {
     fn initialize(&mut self) -> Result<(), DbError>;
     fn shutdown(&mut self) -> Result<(), DbError>;
     fn execute(&self, command: &str, args: &[Value]) -> Result<Value, DbError>;
+
+    /// Called with each statement's SQL before it runs.
+    fn before_query(&self, _sql: &str) {}
+
+    /// Called after a statement ran successfully, with the number of rows it returned.
+    fn after_query(&self, _sql: &str, _rows: usize) {}
+}
+
+// A shared library backing a loaded plugin; it must outlive the plugin's code
+struct LoadedLibrary {
+    path: PathBuf,
+    _library: Library,
 }
 
 pub struct PluginManager {
+    // Declared before `libraries` so plugins are dropped while their code is still mapped
     plugins: HashMap<String, Box<dyn Plugin>>,
+    libraries: HashMap<String, LoadedLibrary>,
+    // Where loaded library paths are persisted; None for a manager not tied to a database
+    registry_path: Option<PathBuf>,
+    registered_paths: Vec<PathBuf>,
 }
 
 impl PluginManager {
     pub fn new() -> Self {
         PluginManager {
             plugins: HashMap::new(),
+            libraries: HashMap::new(),
+            registry_path: None,
+            registered_paths: Vec::new(),
         }
     }
 
+    /// A plugin manager for the database in `data_dir` that reloads the libraries recorded by
+    /// earlier `load_plugin` calls. A library that fails to load is logged and skipped, but
+    /// stays registered so it is tried again on the next start.
+    pub fn open(data_dir: &str) -> Self {
+        let mut manager = PluginManager::new();
+        let registry_path = Path::new(data_dir).join(PLUGIN_REGISTRY_FILE);
+        let registered_paths = read_registry(&registry_path).unwrap_or_else(|e| {
+            log_error(&e);
+            Vec::new()
+        });
+        for path in &registered_paths {
+            if let Err(e) = manager.load_plugin(path) {
+                log_error(&e);
+            }
+        }
+        manager.registry_path = Some(registry_path);
+        manager.registered_paths = registered_paths;
+        manager
+    }
+
+    fn save_registry(&self) -> Result<(), DbError> {
+        if let Some(registry_path) = &self.registry_path {
+            let json = serde_json::to_string_pretty(&self.registered_paths)
+                .map_err(|e| DbError::SerializationError(e.to_string()))?;
+            std::fs::write(registry_path, json)?;
+        }
+        Ok(())
+    }
+
+    pub fn registered_paths(&self) -> &[PathBuf] {
+        &self.registered_paths
+    }
+
+    /// Loads a plugin from a shared library built with `declare_plugin!` and registers it.
+    /// Returns the plugin's name.
+    ///
+    /// Loading runs the library's initialization code, so only load libraries you trust.
+    pub fn load_plugin(&mut self, path: &Path) -> Result<String, DbError> {
+        let load_error = |e: libloading::Error| DbError::PluginError(format!("Cannot load {}: {}", path.display(), e));
+        // SAFETY: the caller vouches for the library; symbols are checked for the expected
+        // version before the constructor is called.
+        let library = unsafe { Library::new(path) }.map_err(load_error)?;
+        let version = unsafe {
+            let version_fn: Symbol<unsafe extern "C" fn() -> *const c_char> =
+                library.get(PLUGIN_VERSION_SYMBOL).map_err(load_error)?;
+            CStr::from_ptr(version_fn()).to_string_lossy().into_owned()
+        };
+        let expected = PLUGIN_ABI_VERSION.trim_end_matches('\0');
+        if version != expected {
+            return Err(DbError::PluginError(format!(
+                "{} was built for vddb {}, but this is vddb {}",
+                path.display(),
+                version,
+                expected
+            )));
+        }
+        let plugin = unsafe {
+            let constructor: Symbol<unsafe extern "C" fn() -> *mut Box<dyn Plugin>> =
+                library.get(PLUGIN_CONSTRUCTOR_SYMBOL).map_err(load_error)?;
+            let raw = constructor();
+            if raw.is_null() {
+                return Err(DbError::PluginError(format!("{} returned no plugin", path.display())));
+            }
+            *Box::from_raw(raw)
+        };
+        self.register_library(plugin, path, library)
+    }
+
+    // Registers a plugin constructed from `library`, loaded from `path`, and records the path
+    // for the next start
+    pub(crate) fn register_library(&mut self, plugin: Box<dyn Plugin>, path: &Path, library: Library) -> Result<String, DbError> {
+        let name = plugin.name().to_string();
+        self.register_plugin(plugin)?;
+        // Record an absolute path so the database reloads it whatever the working directory
+        let path = std::fs::canonicalize(path)?;
+        self.libraries.insert(
+            name.clone(),
+            LoadedLibrary {
+                path: path.clone(),
+                _library: library,
+            },
+        );
+        log::info!("Loaded plugin {} from {}", name, path.display());
+        if !self.registered_paths.contains(&path) {
+            self.registered_paths.push(path);
+            self.save_registry()?;
+        }
+        Ok(name)
+    }
+
+    /// Path of the shared library a plugin was loaded from, if it was loaded dynamically.
+    pub fn plugin_path(&self, name: &str) -> Option<&Path> {
+        self.libraries.get(name).map(|lib| lib.path.as_path())
+    }
+
     pub fn register_plugin(&mut self, mut plugin: Box<dyn Plugin>) -> Result<(), DbError> {
         let name = plugin.name().to_string();
         if self.plugins.contains_key(&name) {
@@ -33,8 +186,14 @@ impl PluginManager {
 
     pub fn unregister_plugin(&mut self, name: &str) -> Result<(), DbError> {
         if let Some(mut plugin) = self.plugins.remove(name) {
-            plugin.shutdown()?;
-            Ok(())
+            let result = plugin.shutdown();
+            drop(plugin);
+            // A dynamically loaded plugin is also dropped from the startup list
+            if let Some(library) = self.libraries.remove(name) {
+                self.registered_paths.retain(|p| p != &library.path);
+                self.save_registry()?;
+            }
+            result
         } else {
             Err(DbError::ConfigurationError(format!("Plugin {} not found", name)))
         }
@@ -47,6 +206,32 @@ impl PluginManager {
             .execute(command, args)
     }
 
+    /// Runs every plugin's `before_query` hook. A panicking hook is caught and reported as a
+    /// `PluginError` naming the plugin.
+    pub fn before_query(&self, sql: &str) -> Result<(), DbError> {
+        self.run_hooks("before_query", |plugin| plugin.before_query(sql))
+    }
+
+    /// Runs every plugin's `after_query` hook, catching panics like `before_query`.
+    pub fn after_query(&self, sql: &str, rows: usize) -> Result<(), DbError> {
+        self.run_hooks("after_query", |plugin| plugin.after_query(sql, rows))
+    }
+
+    fn run_hooks(&self, hook: &str, call: impl Fn(&dyn Plugin)) -> Result<(), DbError> {
+        for (name, plugin) in &self.plugins {
+            let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| call(plugin.as_ref())));
+            if let Err(panic) = outcome {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                return Err(DbError::PluginError(format!("{} panicked in {}: {}", name, hook, message)));
+            }
+        }
+        Ok(())
+    }
+
     pub fn list_plugins(&self) -> Vec<(&str, &str)> {
         self.plugins
             .iter()
@@ -55,6 +240,15 @@ impl PluginManager {
     }
 }
 
+fn read_registry(path: &Path) -> Result<Vec<PathBuf>, DbError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let json = std::fs::read_to_string(path)?;
+    serde_json::from_str(&json)
+        .map_err(|e| DbError::SerializationError(format!("Invalid plugin registry {}: {}", path.display(), e)))
+}
+
 // Example plugin implementation
 pub struct ExamplePlugin {
     name: String,
@@ -93,4 +287,88 @@ impl Plugin for ExamplePlugin {
             _ => Err(DbError::QueryError(format!("Unknown command: {}", command))),
         }
     }
-} 
\ No newline at end of file
+} 
+
+/// Built-in plugin profiling a column: `PLUGIN stats analyze <table> <column>`.
+pub struct StatsPlugin {
+    storage: SharedStorage,
+}
+
+impl StatsPlugin {
+    pub fn new(storage: SharedStorage) -> Self {
+        StatsPlugin { storage }
+    }
+
+    /// One row with the column's min, max, distinct and null counts, and mean. Min, max and
+    /// mean ignore NULLs and are NULL when there is nothing to aggregate; mean is NULL for
+    /// non-numeric columns.
+    pub fn analyze(&self, table: &str, column: &str) -> Result<QueryResult, DbError> {
+        let (data_type, values) = {
+            let storage = self.storage.read().map_err(|e| DbError::ConcurrencyError(e.to_string()))?;
+            let data_type = storage
+                .schema()
+                .get_table(table)
+                .and_then(|t| t.get_column(column))
+                .map(|c| c.data_type.clone())
+                .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table, column)))?;
+            (data_type, storage.read_column(table, column, None)?)
+        };
+        let null_count = values.iter().filter(|v| v.is_null()).count();
+        let distinct = values.iter().filter(|v| !v.is_null()).collect::<HashSet<_>>().len();
+
+        let numeric = matches!(data_type, DataType::Int32 | DataType::Int64 | DataType::Float32);
+        let mut aggregations = vec![Aggregation::Min(column.to_string()), Aggregation::Max(column.to_string())];
+        if numeric {
+            aggregations.push(Aggregation::Avg(column.to_string()));
+        }
+        let mut aggregates = QueryEngine::new(Arc::clone(&self.storage))
+            .execute(Query::SelectAggregate {
+                table: table.to_string(),
+                aggregations,
+                condition: None,
+                group_by: Vec::new(),
+            })?
+            .pop()
+            .unwrap_or_default();
+        if distinct == 0 {
+            aggregates.iter_mut().for_each(|v| *v = Value::Null);
+        }
+        let mut aggregates = aggregates.into_iter();
+        let mut next = || aggregates.next().unwrap_or(Value::Null);
+        let (min, max, mean) = (next(), next(), next());
+
+        Ok(QueryResult::new(
+            ["min", "max", "distinct", "nulls", "mean"].iter().map(|c| c.to_string()).collect(),
+            vec![vec![min, max, Value::Int32(distinct as i32), Value::Int32(null_count as i32), mean]],
+        ))
+    }
+}
+
+impl Plugin for StatsPlugin {
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn version(&self) -> &str {
+        "1.0.0"
+    }
+
+    fn initialize(&mut self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    // Returns the statistics as a JSON array holding a single object
+    fn execute(&self, command: &str, args: &[Value]) -> Result<Value, DbError> {
+        match (command, args) {
+            ("analyze", [Value::String(table), Value::String(column)]) => {
+                Ok(Value::String(self.analyze(table, column)?.to_json()?))
+            }
+            ("analyze", _) => Err(DbError::QueryError("Usage: analyze <table> <column>".to_string())),
+            _ => Err(DbError::QueryError(format!("Unknown command: {}", command))),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,146 @@
+use crate::query::Query;
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_PLAN_CACHE_CAPACITY: usize = 128;
+
+#[derive(Debug)]
+struct CachedPlan {
+    query: Query,
+    // Tables the query names, so DDL on one of them can drop it
+    tables: Vec<String>,
+}
+
+/// Least-recently-used cache of parsed statements with their names resolved, keyed by the
+/// statement text as `normalize_statement` returns it.
+#[derive(Debug)]
+pub struct PlanCache {
+    capacity: usize,
+    entries: HashMap<String, CachedPlan>,
+    // Keys ordered from least to most recently used
+    order: VecDeque<String>,
+    hits: u64,
+    misses: u64,
+}
+
+impl PlanCache {
+    pub fn new(capacity: usize) -> Self {
+        PlanCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Query> {
+        match self.entries.get(key) {
+            Some(plan) => {
+                let query = plan.query.clone();
+                self.touch(key);
+                self.hits += 1;
+                Some(query)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: String, query: Query) {
+        if self.capacity == 0 {
+            return;
+        }
+        let tables = query.tables().into_iter().map(str::to_string).collect();
+        if self.entries.insert(key.clone(), CachedPlan { query, tables }).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        self.evict();
+    }
+
+    /// Drops every plan naming `table`, matched the way unquoted identifiers are.
+    pub fn invalidate_table(&mut self, table: &str) {
+        self.entries
+            .retain(|_, plan| !plan.tables.iter().any(|t| t.eq_ignore_ascii_case(table)));
+        let entries = &self.entries;
+        self.order.retain(|key| entries.contains_key(key));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Hits and misses since the cache was created.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(pos).unwrap();
+            self.order.push_back(key);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(key) => {
+                    self.entries.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// `sql` with runs of whitespace outside quotes collapsed to one space and trailing
+/// semicolons removed, so statements differing only in layout share a plan.
+pub fn normalize_statement(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    let mut quote = None;
+    let mut pending_space = false;
+    for c in sql.trim().trim_end_matches(|c: char| c == ';' || c.is_whitespace()).chars() {
+        match quote {
+            Some(q) => {
+                normalized.push(c);
+                if c == q {
+                    quote = None;
+                }
+            }
+            None if c.is_whitespace() => pending_space = true,
+            None => {
+                if pending_space {
+                    normalized.push(' ');
+                    pending_space = false;
+                }
+                if c == '\'' || c == '"' {
+                    quote = Some(c);
+                }
+                normalized.push(c);
+            }
+        }
+    }
+    normalized
+}
@@ -0,0 +1,135 @@
+use crate::schema::{Column, OnDelete, Table};
+use crate::storage::StorageManager;
+use crate::types::{DataType, Value};
+use std::collections::HashMap;
+
+pub const TABLES: &str = "information_schema.tables";
+pub const COLUMNS: &str = "information_schema.columns";
+pub const INDEXES: &str = "information_schema.indexes";
+
+/// The definition of a read-only catalog table describing the schema, matched case-insensitively.
+///
+/// `information_schema.tables` has a row per table: `table_name`, `column_count` and `row_count`.
+/// `information_schema.columns` has a row per column: `table_name`, `column_name`,
+/// `ordinal_position` (from 1), `data_type`, `is_nullable` (`YES` or `NO`), `column_default`,
+/// `column_key` (`PRIMARY KEY` or `UNIQUE`), `check_clause`, `foreign_key` and `indexes`, the
+/// last four NULL when the column has none.
+/// `information_schema.indexes` has a row per index: `table_name`, `index_name`, `index_type`
+/// (`hash`, `unique`, `fulltext` or `composite`), `column_names` (comma-separated, in key order)
+/// and `is_implicit` (`YES` for the indexes kept without CREATE INDEX, which cannot be dropped).
+pub fn catalog_table(name: &str) -> Option<Table> {
+    let text = |name: &str| Column::new(name, DataType::String);
+    let columns = if name.eq_ignore_ascii_case(TABLES) {
+        vec![
+            text("table_name").not_null(),
+            Column::new("column_count", DataType::Int32).not_null(),
+            Column::new("row_count", DataType::Int64).not_null(),
+        ]
+    } else if name.eq_ignore_ascii_case(COLUMNS) {
+        vec![
+            text("table_name").not_null(),
+            text("column_name").not_null(),
+            Column::new("ordinal_position", DataType::Int32).not_null(),
+            text("data_type").not_null(),
+            text("is_nullable").not_null(),
+            text("column_default"),
+            text("column_key"),
+            text("check_clause"),
+            text("foreign_key"),
+            text("indexes"),
+        ]
+    } else if name.eq_ignore_ascii_case(INDEXES) {
+        vec![
+            text("table_name").not_null(),
+            text("index_name").not_null(),
+            text("index_type").not_null(),
+            text("column_names").not_null(),
+            text("is_implicit").not_null(),
+        ]
+    } else {
+        return None;
+    };
+    Some(Table {
+        name: name.to_ascii_lowercase(),
+        columns,
+        row_count: 0,
+        indexes: Vec::new(),
+        next_rowid: 0,
+    })
+}
+
+/// The contents of catalog table `table`, by column name, ordered by table name and then by
+/// column position. Built afresh from the schema on every call.
+pub fn read_catalog(storage: &StorageManager, table: &Table) -> HashMap<String, Vec<Value>> {
+    let mut tables: Vec<&Table> = storage.schema().tables().collect();
+    tables.sort_by(|a, b| a.name.cmp(&b.name));
+    let rows: Vec<Vec<Value>> = if table.name == TABLES {
+        tables
+            .iter()
+            .map(|t| {
+                vec![
+                    Value::String(t.name.clone()),
+                    Value::Int32(t.columns.len() as i32),
+                    Value::Int64(storage.table_size(&t.name).0 as i64),
+                ]
+            })
+            .collect()
+    } else if table.name == INDEXES {
+        tables
+            .iter()
+            .flat_map(|t| storage.list_indexes(&t.name).unwrap_or_default().into_iter().map(move |index| (*t, index)))
+            .map(|(t, index)| {
+                vec![
+                    Value::String(t.name.clone()),
+                    Value::String(index.name),
+                    Value::String(index.kind.to_string()),
+                    Value::String(index.columns.join(", ")),
+                    Value::String(if index.implicit { "YES" } else { "NO" }.to_string()),
+                ]
+            })
+            .collect()
+    } else {
+        let optional = |text: Option<String>| text.map_or(Value::Null, Value::String);
+        tables
+            .iter()
+            .flat_map(|t| t.columns.iter().enumerate().map(move |(i, col)| (*t, i, col)))
+            .map(|(t, i, col)| {
+                let key = if col.is_primary_key {
+                    Some("PRIMARY KEY".to_string())
+                } else if col.is_unique {
+                    Some("UNIQUE".to_string())
+                } else {
+                    None
+                };
+                let foreign_key = col.references.as_ref().map(|fk| {
+                    let cascade = if fk.on_delete == OnDelete::Cascade { " ON DELETE CASCADE" } else { "" };
+                    format!("{}({}){}", fk.reference_table, fk.reference_column, cascade)
+                });
+                let indexes = storage.column_indexes(&t.name, &col.name);
+                vec![
+                    Value::String(t.name.clone()),
+                    Value::String(col.name.clone()),
+                    Value::Int32(i as i32 + 1),
+                    Value::String(col.data_type.sql_name().to_string()),
+                    Value::String(if col.accepts_null() { "YES" } else { "NO" }.to_string()),
+                    optional(col.default_value.as_ref().map(|d| d.to_sql())),
+                    optional(key),
+                    optional(col.check.clone()),
+                    optional(foreign_key),
+                    optional((!indexes.is_empty()).then(|| indexes.join(", "))),
+                ]
+            })
+            .collect()
+    };
+
+    let mut columns: HashMap<String, Vec<Value>> =
+        table.columns.iter().map(|c| (c.name.clone(), Vec::with_capacity(rows.len()))).collect();
+    for row in rows {
+        for (col, value) in table.columns.iter().zip(row) {
+            if let Some(values) = columns.get_mut(&col.name) {
+                values.push(value);
+            }
+        }
+    }
+    columns
+}
\ No newline at end of file
@@ -1,49 +1,24 @@
-use crate::query::Condition;
+use crate::query::{BinaryOp, Condition, Expr, ScalarFunction};
 use crate::schema::metadata::BlockInfo;
 use crate::types::{DbError, Value};
+use crate::storage::index::tokenize;
+use ordered_float::OrderedFloat;
 
 pub fn evaluate_condition_block(condition: &Condition, column_name: &str, block: &BlockInfo) -> bool {
     match condition {
         Condition::Equal(col, val) if col == column_name => {
-            match (&block.min, &block.max, val) {
-                (Value::Int32(min), Value::Int32(max), Value::Int32(v)) => min <= v && v <= max,
-                (Value::Float32(min), Value::Float32(max), Value::Float32(v)) => min <= v && v <= max,
-                (Value::String(min), Value::String(max), Value::String(v)) => min <= v && v <= max,
-                _ => false,
-            }
-        }
-        Condition::GreaterThan(col, val) if col == column_name => {
-            match (&block.max, val) {
-                (Value::Int32(max), Value::Int32(v)) => max > v,
-                (Value::Float32(max), Value::Float32(v)) => max > v,
-                (Value::String(max), Value::String(v)) => max > v,
-                _ => false,
-            }
-        }
-        Condition::LessThan(col, val) if col == column_name => {
-            match (&block.min, val) {
-                (Value::Int32(min), Value::Int32(v)) => min < v,
-                (Value::Float32(min), Value::Float32(v)) => min < v,
-                (Value::String(min), Value::String(v)) => min < v,
-                _ => false,
-            }
-        }
-        Condition::LessThanOrEqual(col, val) if col == column_name => {
-            match (&block.max, val) {
-                (Value::Int32(max), Value::Int32(v)) => max <= v,
-                (Value::Float32(max), Value::Float32(v)) => max <= v,
-                (Value::String(max), Value::String(v)) => max <= v,
-                _ => false,
-            }
+            compare_values(&block.min, val).is_some_and(|o| o.is_le())
+                && compare_values(&block.max, val).is_some_and(|o| o.is_ge())
         }
+        Condition::GreaterThan(col, val) if col == column_name => compare_values(&block.max, val).is_some_and(|o| o.is_gt()),
+        Condition::LessThan(col, val) if col == column_name => compare_values(&block.min, val).is_some_and(|o| o.is_lt()),
+        Condition::LessThanOrEqual(col, val) if col == column_name => compare_values(&block.min, val).is_some_and(|o| o.is_le()),
         Condition::GreaterThanOrEqual(col, val) if col == column_name => {
-            match (&block.min, val) {
-                (Value::Int32(min), Value::Int32(v)) => min >= v,
-                (Value::Float32(min), Value::Float32(v)) => min >= v,
-                (Value::String(min), Value::String(v)) => min >= v,
-                _ => false,
-            }
+            compare_values(&block.max, val).is_some_and(|o| o.is_ge())
         }
+        // Blocks record where their NULLs are, so these never need a min/max
+        Condition::IsNull { column, negated: false } if column == column_name => !block.nulls.is_empty(),
+        Condition::IsNull { column, negated: true } if column == column_name => block.nulls.len() < block.row_count,
         Condition::And(left, right) => {
             evaluate_condition_block(left, column_name, block)
                 && evaluate_condition_block(right, column_name, block)
@@ -56,65 +31,200 @@ pub fn evaluate_condition_block(condition: &Condition, column_name: &str, block:
     }
 }
 
+/// How a stored value orders against a comparison's literal: values of one type by that type,
+/// INT, BIGINT and FLOAT by numeric value. `None`, which matches nothing, for NULL and for
+/// values that cannot be compared, such as a number and a string.
+pub fn compare_values(value: &Value, literal: &Value) -> Option<std::cmp::Ordering> {
+    if value.is_null() || literal.is_null() {
+        return None;
+    }
+    value.partial_cmp(literal)
+}
+
 pub fn evaluate_condition_row(
     condition: &Condition,
     column_values: &std::collections::HashMap<String, Vec<Value>>,
     row_index: usize,
 ) -> Result<bool, DbError> {
     match condition {
-        Condition::Equal(col, val) => {
+        Condition::Equal(col, _)
+        | Condition::GreaterThan(col, _)
+        | Condition::LessThan(col, _)
+        | Condition::LessThanOrEqual(col, _)
+        | Condition::GreaterThanOrEqual(col, _)
+        | Condition::IsNull { column: col, .. }
+        | Condition::Match { column: col, .. } => {
             let values = column_values
                 .get(col)
                 .ok_or_else(|| DbError::QueryError(format!("Column {} not found in condition evaluation", col)))?;
-            Ok(values.get(row_index).map_or(false, |v| v == val))
+            Ok(values.get(row_index).is_some_and(|v| evaluate_condition_value(condition, v)))
         }
-        Condition::GreaterThan(col, val) => {
+        Condition::Exists { .. } => Err(DbError::QueryError(format!(
+            "{} must be evaluated before the rows it filters",
+            condition
+        ))),
+        Condition::And(left, right) => Ok(evaluate_condition_row(left, column_values, row_index)?
+            && evaluate_condition_row(right, column_values, row_index)?),
+        Condition::Or(left, right) => Ok(evaluate_condition_row(left, column_values, row_index)?
+            || evaluate_condition_row(right, column_values, row_index)?),
+    }
+}
+
+/// Evaluates a condition against a single value, ignoring the column names it mentions. Only
+/// meaningful for conditions on one column, such as a predicate pushed down into a column scan.
+pub fn evaluate_condition_value(condition: &Condition, value: &Value) -> bool {
+    let compare = |val: &Value| compare_values(value, val);
+    match condition {
+        // NULL never compares equal, not even to NULL
+        Condition::Equal(_, val) => compare(val).is_some_and(|o| o.is_eq()),
+        Condition::GreaterThan(_, val) => compare(val).is_some_and(|o| o.is_gt()),
+        Condition::LessThan(_, val) => compare(val).is_some_and(|o| o.is_lt()),
+        Condition::LessThanOrEqual(_, val) => compare(val).is_some_and(|o| o.is_le()),
+        Condition::GreaterThanOrEqual(_, val) => compare(val).is_some_and(|o| o.is_ge()),
+        Condition::IsNull { negated, .. } => value.is_null() != *negated,
+        Condition::Match { terms, any, .. } => match value {
+            Value::String(text) => {
+                let words: std::collections::HashSet<String> = tokenize(text).into_iter().collect();
+                if *any {
+                    terms.iter().any(|term| words.contains(term))
+                } else {
+                    terms.iter().all(|term| words.contains(term))
+                }
+            }
+            _ => false,
+        },
+        // Folded away by the planner before any value is read
+        Condition::Exists { .. } => false,
+        Condition::And(left, right) => evaluate_condition_value(left, value) && evaluate_condition_value(right, value),
+        Condition::Or(left, right) => evaluate_condition_value(left, value) || evaluate_condition_value(right, value),
+    }
+}
+
+pub fn evaluate_expr(
+    expr: &Expr,
+    column_values: &std::collections::HashMap<String, Vec<Value>>,
+    row_index: usize,
+) -> Result<Value, DbError> {
+    match expr {
+        Expr::Column(col) => {
             let values = column_values
                 .get(col)
-                .ok_or_else(|| DbError::QueryError(format!("Column {} not found in condition evaluation", col)))?;
-            Ok(values.get(row_index).map_or(false, |v| match (v, val) {
-                (Value::Int32(a), Value::Int32(b)) => a > b,
-                (Value::Float32(a), Value::Float32(b)) => a > b,
-                (Value::String(a), Value::String(b)) => a > b,
-                _ => false,
-            }))
+                .ok_or_else(|| DbError::QueryError(format!("Column {} not found in expression evaluation", col)))?;
+            values
+                .get(row_index)
+                .cloned()
+                .ok_or_else(|| DbError::InvalidData(format!("Row {} out of bounds for column {}", row_index, col)))
         }
-        Condition::LessThan(col, val) => {
-            let values = column_values
-                .get(col)
-                .ok_or_else(|| DbError::QueryError(format!("Column {} not found in condition evaluation", col)))?;
-            Ok(values.get(row_index).map_or(false, |v| match (v, val) {
-                (Value::Int32(a), Value::Int32(b)) => a < b,
-                (Value::Float32(a), Value::Float32(b)) => a < b,
-                (Value::String(a), Value::String(b)) => a < b,
-                _ => false,
-            }))
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Cast { expr, target } => evaluate_expr(expr, column_values, row_index)?.cast(target),
+        // Arguments after the first non-NULL one are not evaluated
+        Expr::Coalesce(args) => {
+            for arg in args {
+                let value = evaluate_expr(arg, column_values, row_index)?;
+                if !value.is_null() {
+                    return Ok(value);
+                }
+            }
+            Ok(Value::Null)
         }
-        Condition::LessThanOrEqual(col, val) => {
-            let values = column_values
-                .get(col)
-                .ok_or_else(|| DbError::QueryError(format!("Column {} not found in condition evaluation", col)))?;
-            Ok(values.get(row_index).map_or(false, |v| match (v, val) {
-                (Value::Int32(a), Value::Int32(b)) => a <= b,
-                (Value::Float32(a), Value::Float32(b)) => a <= b,
-                (Value::String(a), Value::String(b)) => a <= b,
-                _ => false,
-            }))
+        Expr::Case { branches, otherwise } => {
+            for (condition, result) in branches {
+                if evaluate_condition_row(condition, column_values, row_index)? {
+                    return evaluate_expr(result, column_values, row_index);
+                }
+            }
+            match otherwise {
+                Some(otherwise) => evaluate_expr(otherwise, column_values, row_index),
+                None => Ok(Value::Null),
+            }
         }
-        Condition::GreaterThanOrEqual(col, val) => {
-            let values = column_values
-                .get(col)
-                .ok_or_else(|| DbError::QueryError(format!("Column {} not found in condition evaluation", col)))?;
-            Ok(values.get(row_index).map_or(false, |v| match (v, val) {
-                (Value::Int32(a), Value::Int32(b)) => a >= b,
-                (Value::Float32(a), Value::Float32(b)) => a >= b,
-                (Value::String(a), Value::String(b)) => a >= b,
-                _ => false,
-            }))
+        Expr::Binary { op, left, right } => {
+            let left = evaluate_expr(left, column_values, row_index)?;
+            let right = evaluate_expr(right, column_values, row_index)?;
+            evaluate_binary(*op, &left, &right)
         }
-        Condition::And(left, right) => Ok(evaluate_condition_row(left, column_values, row_index)?
-            && evaluate_condition_row(right, column_values, row_index)?),
-        Condition::Or(left, right) => Ok(evaluate_condition_row(left, column_values, row_index)?
-            || evaluate_condition_row(right, column_values, row_index)?),
+        Expr::Function { function, args } => {
+            let args = args
+                .iter()
+                .map(|arg| evaluate_expr(arg, column_values, row_index))
+                .collect::<Result<Vec<_>, _>>()?;
+            evaluate_function(*function, &args)
+        }
+    }
+}
+
+// Integer operands stay integers, failing on overflow; any FLOAT operand makes the result FLOAT
+fn evaluate_binary(op: BinaryOp, left: &Value, right: &Value) -> Result<Value, DbError> {
+    if left.is_null() || right.is_null() {
+        return Ok(Value::Null);
+    }
+    let overflow = || DbError::QueryError(format!("Integer overflow in {} {} {}", left, op.symbol(), right));
+    if matches!(op, BinaryOp::Divide | BinaryOp::Modulo) && right.as_f64() == Some(0.0) {
+        return Err(DbError::QueryError(format!("Division by zero in {} {} {}", left, op.symbol(), right)));
+    }
+    match (left, right) {
+        (Value::Int32(a), Value::Int32(b)) => {
+            let result = match op {
+                BinaryOp::Add => a.checked_add(*b),
+                BinaryOp::Subtract => a.checked_sub(*b),
+                BinaryOp::Multiply => a.checked_mul(*b),
+                BinaryOp::Divide => a.checked_div(*b),
+                BinaryOp::Modulo => a.checked_rem(*b),
+            };
+            result.map(Value::Int32).ok_or_else(overflow)
+        }
+        (Value::Int32(_) | Value::Int64(_), Value::Int32(_) | Value::Int64(_)) => {
+            let bigint = |value: &Value| match value {
+                Value::Int32(i) => *i as i64,
+                Value::Int64(i) => *i,
+                _ => 0,
+            };
+            let (a, b) = (bigint(left), bigint(right));
+            let result = match op {
+                BinaryOp::Add => a.checked_add(b),
+                BinaryOp::Subtract => a.checked_sub(b),
+                BinaryOp::Multiply => a.checked_mul(b),
+                BinaryOp::Divide => a.checked_div(b),
+                BinaryOp::Modulo => a.checked_rem(b),
+            };
+            result.map(Value::Int64).ok_or_else(overflow)
+        }
+        _ => match (left.as_f64(), right.as_f64()) {
+            (Some(a), Some(b)) if !matches!(left, Value::Timestamp(_)) && !matches!(right, Value::Timestamp(_)) => {
+                let result = match op {
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Subtract => a - b,
+                    BinaryOp::Multiply => a * b,
+                    BinaryOp::Divide => a / b,
+                    BinaryOp::Modulo => a % b,
+                };
+                Ok(Value::Float32(OrderedFloat(result as f32)))
+            }
+            _ => Err(DbError::TypeMismatch(format!(
+                "Cannot apply {} to {} and {}",
+                op.symbol(),
+                left.to_sql_literal(),
+                right.to_sql_literal()
+            ))),
+        },
+    }
+}
+
+fn evaluate_function(function: ScalarFunction, args: &[Value]) -> Result<Value, DbError> {
+    let [arg] = args else {
+        return Err(DbError::QueryError(format!("{} takes 1 argument, got {}", function.name(), args.len())));
+    };
+    let mismatch = || DbError::TypeMismatch(format!("{} cannot take {}", function.name(), arg.to_sql_literal()));
+    let overflow = || DbError::QueryError(format!("Integer overflow in {}({})", function.name(), arg));
+    match (function, arg) {
+        (_, Value::Null) => Ok(Value::Null),
+        (ScalarFunction::Upper, Value::String(s)) => Ok(Value::String(s.to_uppercase())),
+        (ScalarFunction::Lower, Value::String(s)) => Ok(Value::String(s.to_lowercase())),
+        (ScalarFunction::Trim, Value::String(s)) => Ok(Value::String(s.trim().to_string())),
+        (ScalarFunction::Length, Value::String(s)) => Ok(Value::Int32(s.chars().count() as i32)),
+        (ScalarFunction::Abs, Value::Int32(i)) => i.checked_abs().map(Value::Int32).ok_or_else(overflow),
+        (ScalarFunction::Abs, Value::Int64(i)) => i.checked_abs().map(Value::Int64).ok_or_else(overflow),
+        (ScalarFunction::Abs, Value::Float32(f)) => Ok(Value::Float32(OrderedFloat(f.0.abs()))),
+        _ => Err(mismatch()),
     }
 }
\ No newline at end of file
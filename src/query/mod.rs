@@ -1,6 +1,13 @@
-use crate::types::{DataType, Value};
+use crate::schema::{Column, IndexDef};
+use crate::transaction::IsolationLevel;
+use crate::types::{DataType, DbError, Value};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
+pub mod cache;
+pub mod catalog;
 pub mod evaluator;
 pub mod parser;
 pub mod planner;
@@ -12,10 +19,89 @@ pub enum Condition {
     LessThan(String, Value),
     LessThanOrEqual(String, Value),
     GreaterThanOrEqual(String, Value),
+    // `column IS NULL`, or `column IS NOT NULL` when negated
+    IsNull { column: String, negated: bool },
+    // `column MATCH 'words'`: the text contains every one of `terms` (already tokenized), or any
+    // of them with `MATCH ANY`
+    Match { column: String, terms: Vec<String>, any: bool },
+    // `EXISTS (SELECT ...)`, or `NOT EXISTS` when negated: whether the subquery returns any row.
+    // Subqueries are uncorrelated, so the planner runs one once per statement and folds the
+    // answer into the rest of the condition before any row is read.
+    Exists { subquery: Box<Query>, negated: bool },
     And(Box<Condition>, Box<Condition>),
     Or(Box<Condition>, Box<Condition>),
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Expr {
+    Column(String),
+    Literal(Value),
+    Cast { expr: Box<Expr>, target: DataType },
+    // `COALESCE(a, b, ...)`, and `IFNULL(a, b)`: the first argument that is not NULL
+    Coalesce(Vec<Expr>),
+    // `CASE WHEN cond THEN expr ... [ELSE expr] END`: the expression of the first branch whose
+    // condition holds, else `otherwise`, else NULL
+    Case { branches: Vec<(Condition, Expr)>, otherwise: Option<Box<Expr>> },
+    // `left + right` and the other arithmetic operators; NULL when either side is
+    Binary { op: BinaryOp, left: Box<Expr>, right: Box<Expr> },
+    // `UPPER(expr)` and the other built-in scalar functions
+    Function { function: ScalarFunction, args: Vec<Expr> },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+impl BinaryOp {
+    pub fn symbol(&self) -> char {
+        match self {
+            BinaryOp::Add => '+',
+            BinaryOp::Subtract => '-',
+            BinaryOp::Multiply => '*',
+            BinaryOp::Divide => '/',
+            BinaryOp::Modulo => '%',
+        }
+    }
+}
+
+/// Built-in functions of one value, usable in projections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScalarFunction {
+    Upper,
+    Lower,
+    Trim,
+    Length,
+    Abs,
+}
+
+impl ScalarFunction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "UPPER" => Some(ScalarFunction::Upper),
+            "LOWER" => Some(ScalarFunction::Lower),
+            "TRIM" => Some(ScalarFunction::Trim),
+            "LENGTH" => Some(ScalarFunction::Length),
+            "ABS" => Some(ScalarFunction::Abs),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScalarFunction::Upper => "UPPER",
+            ScalarFunction::Lower => "LOWER",
+            ScalarFunction::Trim => "TRIM",
+            ScalarFunction::Length => "LENGTH",
+            ScalarFunction::Abs => "ABS",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Aggregation {
     Count,
@@ -23,19 +109,158 @@ pub enum Aggregation {
     Avg(String),
     Min(String),
     Max(String),
+    // Column and separator (a comma when None)
+    GroupConcat(String, Option<String>),
+    // A GROUP BY column named in the select list: its value in the group
+    GroupKey(String),
+}
+
+impl Aggregation {
+    // Column header used for the aggregate in query results
+    pub fn label(&self) -> String {
+        match self {
+            Aggregation::Count => "COUNT(*)".to_string(),
+            Aggregation::Sum(col) => format!("SUM({})", col),
+            Aggregation::Avg(col) => format!("AVG({})", col),
+            Aggregation::Min(col) => format!("MIN({})", col),
+            Aggregation::Max(col) => format!("MAX({})", col),
+            Aggregation::GroupConcat(col, None) => format!("GROUP_CONCAT({})", col),
+            Aggregation::GroupConcat(col, Some(separator)) => {
+                format!("GROUP_CONCAT({}, {})", col, Value::String(separator.clone()).to_sql_literal())
+            }
+            Aggregation::GroupKey(col) => col.clone(),
+        }
+    }
+
+    // Column the aggregate reads; None for COUNT(*), which only needs the row count
+    pub fn column(&self) -> Option<&str> {
+        match self {
+            Aggregation::Count => None,
+            Aggregation::Sum(col)
+            | Aggregation::Avg(col)
+            | Aggregation::Min(col)
+            | Aggregation::Max(col)
+            | Aggregation::GroupConcat(col, _)
+            | Aggregation::GroupKey(col) => Some(col),
+        }
+    }
+
+    /// Type of the aggregate's result over a column of `column_type`, or `None` when it does not
+    /// apply to that type. SUM keeps integers integral, widened to BIGINT so a total of INTs
+    /// cannot overflow; AVG is a ratio and always FLOAT.
+    pub fn result_type(&self, column_type: &DataType) -> Option<DataType> {
+        match (self, column_type) {
+            (Aggregation::Count, _) => Some(DataType::Int32),
+            (Aggregation::Sum(_), DataType::Int32 | DataType::Int64) => Some(DataType::Int64),
+            (Aggregation::Sum(_), DataType::Float32) => Some(DataType::Float32),
+            (Aggregation::Avg(_), DataType::Int32 | DataType::Int64 | DataType::Float32) => Some(DataType::Float32),
+            (Aggregation::Sum(_) | Aggregation::Avg(_), _) => None,
+            (Aggregation::Min(_) | Aggregation::Max(_), column_type) => Some(column_type.clone()),
+            (Aggregation::GroupConcat(..), DataType::String) => Some(DataType::String),
+            (Aggregation::GroupConcat(..), _) => None,
+            (Aggregation::GroupKey(_), column_type) => Some(column_type.clone()),
+        }
+    }
+
+    pub fn column_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Aggregation::Count => None,
+            Aggregation::Sum(col)
+            | Aggregation::Avg(col)
+            | Aggregation::Min(col)
+            | Aggregation::Max(col)
+            | Aggregation::GroupConcat(col, _)
+            | Aggregation::GroupKey(col) => Some(col),
+        }
+    }
+}
+
+/// One key of `ORDER BY column [ASC|DESC] [NULLS FIRST|LAST]`. Without NULLS FIRST or LAST,
+/// NULLs sort as if larger than every value: last in ascending order, first in descending.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OrderKey {
+    pub column: String,
+    pub descending: bool,
+    pub nulls_first: bool,
+}
+
+impl OrderKey {
+    pub fn new(column: &str, descending: bool) -> Self {
+        OrderKey {
+            column: column.to_string(),
+            descending,
+            nulls_first: descending,
+        }
+    }
+
+    /// How rows holding `a` and `b` in this key's column are ordered. NULLs go where the key
+    /// places them rather than comparing as the smallest value, and NaN follows every number
+    /// whether the key is ascending or descending.
+    pub fn compare(&self, a: &Value, b: &Value) -> std::cmp::Ordering {
+        use std::cmp::Ordering;
+        match (a.is_null(), b.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) if self.nulls_first => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (false, true) if self.nulls_first => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => match (a.is_nan(), b.is_nan()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) if self.descending => b.cmp(a),
+                (false, false) => a.cmp(b),
+            },
+        }
+    }
+}
+
+/// `ON CONFLICT (column) DO ...` on an INSERT: what to do when the row's value for a UNIQUE
+/// or PRIMARY KEY column is already taken.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OnConflict {
+    pub column: String,
+    pub action: ConflictAction,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ConflictAction {
+    DoNothing,
+    // `SET column = expr, ...`, where `EXCLUDED.<column>` is the value the insert proposed
+    DoUpdate(Vec<(String, Expr)>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AlterAction {
+    DropColumn(String),
+    RenameTable(String),
+    RenameColumn { from: String, to: String },
+}
+
+/// What a GRANT gives (or a REVOKE takes back).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Privilege {
+    // Permission string such as "select:users", or "select" for every table, given to a role
+    Permission { permission: String, role: String },
+    Role { role: String, username: String },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Query {
     Select {
-        table: String,
+        // None for `SELECT <expressions>` without FROM, which yields a single row
+        table: Option<String>,
         columns: Vec<String>,
         condition: Option<Condition>,
+        // Sort keys, most significant first; empty keeps the order rows are stored in
+        order_by: Vec<OrderKey>,
     },
     SelectAggregate {
         table: String,
         aggregations: Vec<Aggregation>,
         condition: Option<Condition>,
+        // GROUP BY columns; empty aggregates the whole table into one row
+        group_by: Vec<String>,
     },
     Join {
         left_table: String,
@@ -47,11 +272,23 @@ pub enum Query {
     },
     Insert {
         table: String,
-        values: Vec<Value>,
+        // Target columns; empty when values are given positionally
+        columns: Vec<String>,
+        // One or more rows of values, inserted as a batch
+        rows: Vec<Vec<Value>>,
+        on_conflict: Option<OnConflict>,
     },
     CreateTable {
         table: String,
-        columns: Vec<(String, DataType)>,
+        columns: Vec<Column>,
+    },
+    CreateIndex {
+        table: String,
+        index: IndexDef,
+    },
+    DropIndex {
+        table: String,
+        name: String,
     },
     Delete {
         table: String,
@@ -60,9 +297,224 @@ pub enum Query {
     DropTable {
         table: String,
     },
+    AlterTable {
+        table: String,
+        action: AlterAction,
+    },
+    CopyFrom {
+        table: String,
+        path: String,
+    },
+    // Writes the whole table to a Parquet file
+    ExportTable {
+        table: String,
+        path: String,
+    },
+    // Rewrites the table's column files to reclaim space
+    Vacuum {
+        table: String,
+    },
+    // Checks the table's column files for corruption
+    Verify {
+        table: String,
+    },
+    // Describes how the inner SELECT would run instead of running it
+    Explain(Box<Query>),
     StartTransaction,
     Commit,
     Rollback,
+    Savepoint(String),
+    RollbackToSavepoint(String),
+    SetIsolationLevel(IsolationLevel),
+    CreateUser {
+        username: String,
+        password: String,
+    },
+    // Verifies the password and makes the user current for the session
+    Login {
+        username: String,
+        password: String,
+    },
+    Grant(Privilege),
+    Revoke(Privilege),
+    // `SET name = value` for the session; the value is checked by whoever owns the setting
+    Set {
+        name: String,
+        value: String,
+    },
+    // `SHOW name`: the session setting's current value
+    Show(String),
+    // Copies the whole database to a new directory, or with `incremental` adds the rows
+    // inserted since to a full backup made that way
+    Backup {
+        path: String,
+        incremental: bool,
+    },
+    // Replaces the whole database with a copy made by BACKUP
+    Restore {
+        path: String,
+    },
+}
+
+impl Query {
+    /// Permission a user needs to run the query, or `None` for transaction control, which
+    /// anyone may run.
+    pub fn operation(&self) -> Option<&'static str> {
+        match self {
+            // Reads no table, so needs no permission
+            Query::Select { table: None, .. } => None,
+            Query::Select { .. } | Query::SelectAggregate { .. } | Query::Join { .. } | Query::ExportTable { .. } | Query::Verify { .. } => {
+                Some("select")
+            }
+            Query::Insert { .. } | Query::CopyFrom { .. } => Some("insert"),
+            Query::Delete { .. } => Some("delete"),
+            Query::CreateTable { .. } => Some("create_table"),
+            Query::DropTable { .. } => Some("drop_table"),
+            Query::AlterTable { .. } | Query::Vacuum { .. } | Query::CreateIndex { .. } | Query::DropIndex { .. } => Some("alter_table"),
+            Query::CreateUser { .. } => Some("create_user"),
+            Query::Grant(_) | Query::Revoke(_) => Some("grant"),
+            // Unlike the session settings, durability applies to every session
+            Query::Set { name, .. } if name == "durability" => Some("configure"),
+            Query::Backup { .. } => Some("backup"),
+            Query::Restore { .. } => Some("restore"),
+            Query::Explain(inner) => inner.operation(),
+            Query::StartTransaction
+            | Query::Commit
+            | Query::Rollback
+            | Query::Savepoint(_)
+            | Query::RollbackToSavepoint(_)
+            | Query::SetIsolationLevel(_)
+            | Query::Set { .. }
+            | Query::Show(_)
+            | Query::Login { .. } => None,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.operation(), None | Some("select"))
+    }
+
+    /// The WHERE condition of a query that has one.
+    pub fn condition(&self) -> Option<&Condition> {
+        match self {
+            Query::Select { condition, .. }
+            | Query::SelectAggregate { condition, .. }
+            | Query::Join { condition, .. }
+            | Query::Delete { condition, .. } => condition.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Tables the query reads or writes.
+    pub fn tables(&self) -> Vec<&str> {
+        match self {
+            Query::Select { table: Some(table), .. }
+            | Query::SelectAggregate { table, .. }
+            | Query::Insert { table, .. }
+            | Query::CreateTable { table, .. }
+            | Query::CreateIndex { table, .. }
+            | Query::DropIndex { table, .. }
+            | Query::Delete { table, .. }
+            | Query::DropTable { table }
+            | Query::Vacuum { table }
+            | Query::Verify { table }
+            | Query::AlterTable { table, .. }
+            | Query::CopyFrom { table, .. }
+            | Query::ExportTable { table, .. } => vec![table.as_str()],
+            Query::Join { left_table, right_table, .. } => vec![left_table.as_str(), right_table.as_str()],
+            Query::Explain(inner) => inner.tables(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for OrderKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} NULLS {}",
+            self.column,
+            if self.descending { "DESC" } else { "ASC" },
+            if self.nulls_first { "FIRST" } else { "LAST" }
+        )
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Equal(col, v) => write!(f, "{} = {}", col, v.to_sql_literal()),
+            Condition::GreaterThan(col, v) => write!(f, "{} > {}", col, v.to_sql_literal()),
+            Condition::LessThan(col, v) => write!(f, "{} < {}", col, v.to_sql_literal()),
+            Condition::LessThanOrEqual(col, v) => write!(f, "{} <= {}", col, v.to_sql_literal()),
+            Condition::GreaterThanOrEqual(col, v) => write!(f, "{} >= {}", col, v.to_sql_literal()),
+            Condition::IsNull { column, negated: false } => write!(f, "{} IS NULL", column),
+            Condition::IsNull { column, negated: true } => write!(f, "{} IS NOT NULL", column),
+            Condition::Match { column, terms, any } => {
+                let text = Value::String(terms.join(" ")).to_sql_literal();
+                write!(f, "{} MATCH {}{}", column, if *any { "ANY " } else { "" }, text)
+            }
+            Condition::Exists { subquery, negated } => {
+                write!(f, "{}EXISTS ({})", if *negated { "NOT " } else { "" }, select_sql(subquery))
+            }
+            Condition::And(left, right) => write!(f, "({} AND {})", left, right),
+            Condition::Or(left, right) => write!(f, "({} OR {})", left, right),
+        }
+    }
+}
+
+impl Condition {
+    /// The subqueries of the EXISTS tests in the condition, outermost first.
+    pub fn subqueries(&self) -> Vec<&Query> {
+        match self {
+            Condition::Exists { subquery, .. } => vec![subquery],
+            Condition::And(left, right) | Condition::Or(left, right) => {
+                let mut subqueries = left.subqueries();
+                subqueries.extend(right.subqueries());
+                subqueries
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+// SQL text of a SELECT, as it appears inside EXISTS (...)
+fn select_sql(query: &Query) -> String {
+    let filter = |condition: &Option<Condition>| condition.as_ref().map(|c| format!(" WHERE {}", c)).unwrap_or_default();
+    match query {
+        Query::Select { table, columns, condition, order_by } => {
+            let columns = if columns.is_empty() { "*".to_string() } else { columns.join(", ") };
+            let mut sql = format!("SELECT {}", columns);
+            if let Some(table) = table {
+                sql.push_str(&format!(" FROM {}{}", table, filter(condition)));
+            }
+            if !order_by.is_empty() {
+                let keys = order_by.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+                sql.push_str(&format!(" ORDER BY {}", keys.join(", ")));
+            }
+            sql
+        }
+        Query::SelectAggregate { table, aggregations, condition, group_by } => {
+            let labels = aggregations.iter().map(|a| a.label()).collect::<Vec<_>>();
+            let mut sql = format!("SELECT {} FROM {}{}", labels.join(", "), table, filter(condition));
+            if !group_by.is_empty() {
+                sql.push_str(&format!(" GROUP BY {}", group_by.join(", ")));
+            }
+            sql
+        }
+        Query::Join { left_table, right_table, left_column, right_column, columns, condition } => format!(
+            "SELECT {} FROM {} JOIN {} ON {}.{} = {}.{}{}",
+            columns.join(", "),
+            left_table,
+            right_table,
+            left_table,
+            left_column,
+            right_table,
+            right_column,
+            filter(condition)
+        ),
+        other => format!("{:?}", other),
+    }
 }
 
 pub fn collect_condition_columns(condition: &Condition) -> std::collections::HashSet<String> {
@@ -72,13 +524,449 @@ pub fn collect_condition_columns(condition: &Condition) -> std::collections::Has
         Condition::GreaterThan(col, _) | 
         Condition::LessThan(col, _) | 
         Condition::LessThanOrEqual(col, _) | 
-        Condition::GreaterThanOrEqual(col, _) => {
+        Condition::GreaterThanOrEqual(col, _) |
+        Condition::IsNull { column: col, .. } |
+        Condition::Match { column: col, .. } => {
             columns.insert(col.clone());
         }
+        // The subquery reads its own table, not the outer one
+        Condition::Exists { .. } => {}
         Condition::And(left, right) | Condition::Or(left, right) => {
             columns.extend(collect_condition_columns(left));
             columns.extend(collect_condition_columns(right));
         }
     }
     columns
+}
+
+/// Widens the literals of `condition` to the types of the columns they are compared with, so
+/// an INT literal can be compared with a BIGINT column. A `Table.column` name is looked up in
+/// that table only; columns missing from `tables` are left for execution to report.
+/// The name `tables` declare the column `column` identifies with, keeping a `Table.` prefix
+/// (itself resolved) when there is one. `None` when no table has such a column.
+pub fn resolve_column(column: &str, tables: &[&crate::schema::Table]) -> Option<String> {
+    match column.split_once('.') {
+        Some((table, name)) => tables
+            .iter()
+            .filter(|t| crate::schema::identifier_matches(&t.name, table))
+            .find_map(|t| t.get_column(name).map(|c| format!("{}.{}", t.name, c.name))),
+        None => tables.iter().find_map(|t| t.get_column(column)).map(|c| c.name.clone()),
+    }
+}
+
+/// Rewrites the columns `condition` tests to the names `tables` declare them with, so that
+/// evaluation can look them up by those names. Unknown columns are left for the caller to report.
+pub fn resolve_condition(condition: &mut Condition, tables: &[&crate::schema::Table]) {
+    match condition {
+        Condition::Equal(col, _)
+        | Condition::GreaterThan(col, _)
+        | Condition::LessThan(col, _)
+        | Condition::LessThanOrEqual(col, _)
+        | Condition::GreaterThanOrEqual(col, _)
+        | Condition::IsNull { column: col, .. }
+        | Condition::Match { column: col, .. } => {
+            if let Some(resolved) = resolve_column(col, tables) {
+                *col = resolved;
+            }
+        }
+        // Resolved against its own table when it runs
+        Condition::Exists { .. } => {}
+        Condition::And(left, right) | Condition::Or(left, right) => {
+            resolve_condition(left, tables);
+            resolve_condition(right, tables);
+        }
+    }
+}
+
+/// Like `resolve_condition`, for the columns an expression reads.
+pub fn resolve_expr(expr: &mut Expr, tables: &[&crate::schema::Table]) {
+    match expr {
+        Expr::Column(col) => {
+            if let Some(resolved) = resolve_column(col, tables) {
+                *col = resolved;
+            }
+        }
+        Expr::Literal(_) => {}
+        Expr::Cast { expr, .. } => resolve_expr(expr, tables),
+        Expr::Coalesce(args) => {
+            for arg in args {
+                resolve_expr(arg, tables);
+            }
+        }
+        Expr::Case { branches, otherwise } => {
+            for (condition, result) in branches {
+                resolve_condition(condition, tables);
+                resolve_expr(result, tables);
+            }
+            if let Some(otherwise) = otherwise {
+                resolve_expr(otherwise, tables);
+            }
+        }
+        Expr::Binary { left, right, .. } => {
+            resolve_expr(left, tables);
+            resolve_expr(right, tables);
+        }
+        Expr::Function { args, .. } => {
+            for arg in args {
+                resolve_expr(arg, tables);
+            }
+        }
+    }
+}
+
+/// The type of `expr`'s values over `tables`, `None` when it is only ever NULL or reads a
+/// column `tables` lack (left for execution to report). COALESCE arguments and CASE branches of
+/// different numeric types are cast to the widest of them (INT, then BIGINT, then FLOAT) so that
+/// every row's result has that type; ones with no type in common are a `TypeMismatch`. The
+/// literals of CASE conditions are widened as `widen_condition` does.
+pub fn widen_expr(expr: &mut Expr, tables: &[&crate::schema::Table]) -> Result<Option<DataType>, DbError> {
+    match expr {
+        Expr::Column(col) => Ok(match col.split_once('.') {
+            Some((table, name)) => tables.iter().filter(|t| t.name == table).find_map(|t| t.get_column(name)),
+            None => tables.iter().find_map(|t| t.get_column(col)),
+        }
+        .map(|c| c.data_type.clone())),
+        Expr::Literal(value) => Ok((!value.is_null()).then(|| value.data_type())),
+        Expr::Cast { expr, target } => {
+            widen_expr(expr, tables)?;
+            Ok(Some(target.clone()))
+        }
+        Expr::Coalesce(args) => widen_to_common_type(args.iter_mut().collect(), tables, "COALESCE arguments"),
+        Expr::Case { branches, otherwise } => {
+            let mut results = Vec::with_capacity(branches.len() + 1);
+            for (condition, result) in branches.iter_mut() {
+                widen_condition(condition, tables)?;
+                results.push(result);
+            }
+            results.extend(otherwise.as_deref_mut());
+            widen_to_common_type(results, tables, "CASE branches")
+        }
+        Expr::Binary { op, left, right } => {
+            let what = format!("Operands of {}", op.symbol());
+            let common = widen_to_common_type(vec![&mut **left, &mut **right], tables, &what)?;
+            match common {
+                Some(data_type) if !matches!(data_type, DataType::Int32 | DataType::Int64 | DataType::Float32) => {
+                    Err(DbError::TypeMismatch(format!("{} must be numbers, not {}", what, data_type.sql_name())))
+                }
+                common => Ok(common),
+            }
+        }
+        Expr::Function { function, args } => {
+            let mut arg_types = Vec::with_capacity(args.len());
+            for arg in args.iter_mut() {
+                arg_types.push(widen_expr(arg, tables)?);
+            }
+            let arg_type = arg_types.into_iter().next().flatten();
+            let expected = match function {
+                ScalarFunction::Upper | ScalarFunction::Lower | ScalarFunction::Trim | ScalarFunction::Length => "TEXT",
+                ScalarFunction::Abs => "a number",
+            };
+            let accepted = arg_type.as_ref().is_none_or(|t| match function {
+                ScalarFunction::Abs => matches!(t, DataType::Int32 | DataType::Int64 | DataType::Float32),
+                _ => *t == DataType::String,
+            });
+            if !accepted {
+                return Err(DbError::TypeMismatch(format!(
+                    "{} takes {}, not {}",
+                    function.name(),
+                    expected,
+                    arg_type.map_or("NULL", |t| t.sql_name())
+                )));
+            }
+            Ok(match function {
+                ScalarFunction::Length => Some(DataType::Int32),
+                ScalarFunction::Abs => arg_type,
+                _ => Some(DataType::String),
+            })
+        }
+    }
+}
+
+// Casts each of `exprs` whose type differs from their common type to it, and returns that type.
+// `what` names the expressions in the error when they have none.
+fn widen_to_common_type(
+    exprs: Vec<&mut Expr>,
+    tables: &[&crate::schema::Table],
+    what: &str,
+) -> Result<Option<DataType>, DbError> {
+    let rank = |data_type: &DataType| match data_type {
+        DataType::Int32 => Some(0),
+        DataType::Int64 => Some(1),
+        DataType::Float32 => Some(2),
+        _ => None,
+    };
+    let mut common: Option<DataType> = None;
+    let mut typed = Vec::with_capacity(exprs.len());
+    for expr in exprs {
+        let expr_type = widen_expr(expr, tables)?;
+        if let (Some(current), Some(next)) = (&common, &expr_type) {
+            common = Some(match (rank(current), rank(next)) {
+                (Some(a), Some(b)) if b > a => next.clone(),
+                (Some(_), Some(_)) => current.clone(),
+                _ if current == next => current.clone(),
+                _ => {
+                    return Err(DbError::TypeMismatch(format!(
+                        "{} have no common type: {} and {}",
+                        what,
+                        current.sql_name(),
+                        next.sql_name()
+                    )))
+                }
+            });
+        } else if common.is_none() {
+            common = expr_type.clone();
+        }
+        typed.push((expr, expr_type));
+    }
+    if let Some(common) = &common {
+        for (expr, expr_type) in typed {
+            if expr_type.is_some_and(|t| t != *common) {
+                let inner = std::mem::replace(expr, Expr::Literal(Value::Null));
+                *expr = Expr::Cast { expr: Box::new(inner), target: common.clone() };
+            }
+        }
+    }
+    Ok(common)
+}
+
+/// Converts each comparison's literal to its column's type where that keeps its value: INT
+/// literals widen to BIGINT and FLOAT, and whole FLOATs compared with an integer column become
+/// integers. Comparing a number with a string is a `TypeMismatch`.
+pub fn widen_condition(condition: &mut Condition, tables: &[&crate::schema::Table]) -> Result<(), DbError> {
+    match condition {
+        Condition::Equal(col, value)
+        | Condition::GreaterThan(col, value)
+        | Condition::LessThan(col, value)
+        | Condition::LessThanOrEqual(col, value)
+        | Condition::GreaterThanOrEqual(col, value) => {
+            let column = match col.split_once('.') {
+                Some((table, name)) => tables.iter().filter(|t| t.name == table).find_map(|t| t.get_column(name)),
+                None => tables.iter().find_map(|t| t.get_column(col)),
+            };
+            let Some(column) = column else { return Ok(()) };
+            let numeric = |data_type: &DataType| matches!(data_type, DataType::Int32 | DataType::Int64 | DataType::Float32);
+            let (value_type, column_type) = (value.data_type(), &column.data_type);
+            if (numeric(&value_type) && *column_type == DataType::String)
+                || (value_type == DataType::String && numeric(column_type))
+            {
+                return Err(DbError::TypeMismatch(format!(
+                    "Cannot compare {} of type {:?} with {}",
+                    col,
+                    column_type,
+                    value.to_sql_literal()
+                )));
+            }
+            *value = match (std::mem::replace(value, Value::Null).widen_to(column_type), column_type) {
+                // Kept whole so it can be looked up in the column's indexes like any other integer
+                (Value::Float32(f), DataType::Int32) if f.0.fract() == 0.0 && f.0.abs() < i32::MAX as f32 => {
+                    Value::Int32(f.0 as i32)
+                }
+                (Value::Float32(f), DataType::Int64) if f.0.fract() == 0.0 && f.0.abs() < i64::MAX as f32 => {
+                    Value::Int64(f.0 as i64)
+                }
+                (value, _) => value,
+            };
+            Ok(())
+        }
+        Condition::IsNull { .. } | Condition::Match { .. } | Condition::Exists { .. } => Ok(()),
+        Condition::And(left, right) | Condition::Or(left, right) => {
+            widen_condition(left, tables)?;
+            widen_condition(right, tables)
+        }
+    }
+}
+
+/// The part of `condition` that can be evaluated inside a single column scan: the whole
+/// condition when it tests one column, otherwise the cheapest single-column branch of an AND
+/// (equality first). An OR across columns cannot be split, so nothing is pushed down.
+pub fn pushdown_predicate(condition: &Condition) -> Option<&Condition> {
+    // Lower is more selective
+    fn cost(condition: &Condition) -> u8 {
+        match condition {
+            Condition::Equal(..) => 0,
+            Condition::And(left, right) => cost(left).min(cost(right)),
+            Condition::Or(..) => 2,
+            _ => 1,
+        }
+    }
+    if collect_condition_columns(condition).len() == 1 {
+        return Some(condition);
+    }
+    match condition {
+        Condition::And(left, right) => match (pushdown_predicate(left), pushdown_predicate(right)) {
+            (Some(l), Some(r)) => Some(if cost(r) < cost(l) { r } else { l }),
+            (l, r) => l.or(r),
+        },
+        _ => None,
+    }
+}
+
+pub fn collect_expr_columns(expr: &Expr) -> std::collections::HashSet<String> {
+    let mut columns = std::collections::HashSet::new();
+    match expr {
+        Expr::Column(col) => {
+            columns.insert(col.clone());
+        }
+        Expr::Literal(_) => {}
+        Expr::Cast { expr, .. } => {
+            columns.extend(collect_expr_columns(expr));
+        }
+        Expr::Coalesce(args) => {
+            for arg in args {
+                columns.extend(collect_expr_columns(arg));
+            }
+        }
+        Expr::Case { branches, otherwise } => {
+            for (condition, result) in branches {
+                columns.extend(collect_condition_columns(condition));
+                columns.extend(collect_expr_columns(result));
+            }
+            if let Some(otherwise) = otherwise {
+                columns.extend(collect_expr_columns(otherwise));
+            }
+        }
+        Expr::Binary { left, right, .. } => {
+            columns.extend(collect_expr_columns(left));
+            columns.extend(collect_expr_columns(right));
+        }
+        Expr::Function { args, .. } => {
+            for arg in args {
+                columns.extend(collect_expr_columns(arg));
+            }
+        }
+    }
+    columns
+}
+
+/// Rows produced by a query together with the names of the columns they hold.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl QueryResult {
+    pub fn new(columns: Vec<String>, rows: Vec<Vec<Value>>) -> Self {
+        QueryResult { columns, rows }
+    }
+
+    /// Writes the result as RFC 4180 CSV: a header record followed by one record per row.
+    pub fn to_csv(&self, mut writer: impl Write) -> Result<(), DbError> {
+        if !self.columns.is_empty() {
+            let header: Vec<Value> = self.columns.iter().map(|c| Value::String(c.clone())).collect();
+            crate::csv::write_record(&mut writer, &header)?;
+        }
+        for row in &self.rows {
+            crate::csv::write_record(&mut writer, row)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Renders the rows as a JSON array of objects keyed by column name, in column order.
+    pub fn to_json(&self) -> Result<String, DbError> {
+        let mut objects = Vec::with_capacity(self.rows.len());
+        for row in &self.rows {
+            let mut fields = Vec::with_capacity(row.len());
+            for (column, value) in self.columns.iter().zip(row) {
+                let value = match value {
+                    Value::Int32(i) => serde_json::Value::from(*i),
+                    Value::Int64(i) => serde_json::Value::from(*i),
+                    Value::Float32(f) => serde_json::Value::from(f.0),
+                    Value::String(s) => serde_json::Value::from(s.as_str()),
+                    Value::Timestamp(ms) => serde_json::Value::from(Value::format_timestamp(*ms)),
+                    Value::Null => serde_json::Value::Null,
+                };
+                let key = serde_json::to_string(column).map_err(|e| DbError::SerializationError(e.to_string()))?;
+                fields.push(format!("{}:{}", key, value));
+            }
+            objects.push(format!("{{{}}}", fields.join(",")));
+        }
+        Ok(format!("[{}]", objects.join(",")))
+    }
+}
+
+impl QueryResult {
+    /// Renders a bordered table with a header row, sizing each column to its widest cell.
+    /// Cells wider than `max_width` display columns are cut short with an ellipsis.
+    pub fn to_table(&self, max_width: usize) -> String {
+        if self.columns.is_empty() {
+            return String::new();
+        }
+        let max_width = max_width.max(1);
+        let header: Vec<String> = self.columns.iter().map(|c| truncate_cell(c, max_width)).collect();
+        let cells: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|v| truncate_cell(&v.to_string(), max_width)).collect())
+            .collect();
+        let mut widths: Vec<usize> = header.iter().map(|h| UnicodeWidthStr::width(h.as_str())).collect();
+        for row in &cells {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(UnicodeWidthStr::width(cell.as_str()));
+            }
+        }
+
+        let border = format!(
+            "+{}+\n",
+            widths.iter().map(|w| "-".repeat(w + 2)).collect::<Vec<_>>().join("+")
+        );
+        let mut out = border.clone();
+        out.push_str(&format_table_row(&header, &widths, |_| false));
+        out.push_str(&border);
+        for (row, values) in cells.iter().zip(&self.rows) {
+            // Numbers are right-aligned, as in psql
+            let right_align = |i: usize| matches!(values.get(i), Some(Value::Int32(_)) | Some(Value::Int64(_)) | Some(Value::Float32(_)));
+            out.push_str(&format_table_row(row, &widths, right_align));
+        }
+        if !self.rows.is_empty() {
+            out.push_str(&border);
+        }
+        let count = self.rows.len();
+        out.push_str(&format!("({} {})", count, if count == 1 { "row" } else { "rows" }));
+        out
+    }
+}
+
+fn truncate_cell(text: &str, max_width: usize) -> String {
+    // Line breaks would split the table apart
+    let text = text.replace(['\n', '\r'], " ");
+    if UnicodeWidthStr::width(text.as_str()) <= max_width {
+        return text;
+    }
+    let mut out = String::new();
+    let mut width = 0;
+    for c in text.chars() {
+        let w = UnicodeWidthChar::width(c).unwrap_or(0);
+        if width + w > max_width - 1 {
+            break;
+        }
+        out.push(c);
+        width += w;
+    }
+    out.push('…');
+    out
+}
+
+fn format_table_row(cells: &[String], widths: &[usize], right_align: impl Fn(usize) -> bool) -> String {
+    let mut line = String::from("|");
+    for (i, (cell, width)) in cells.iter().zip(widths).enumerate() {
+        let padding = " ".repeat(width - UnicodeWidthStr::width(cell.as_str()));
+        if right_align(i) {
+            line.push_str(&format!(" {}{} |", padding, cell));
+        } else {
+            line.push_str(&format!(" {}{} |", cell, padding));
+        }
+    }
+    line.push('\n');
+    line
+}
+
+// Cells wider than this are truncated when a result is printed with `{}`
+pub const DEFAULT_MAX_CELL_WIDTH: usize = 40;
+
+impl fmt::Display for QueryResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_table(DEFAULT_MAX_CELL_WIDTH))
+    }
 }
\ No newline at end of file
@@ -1,8 +1,14 @@
-use crate::query::{Aggregation, Condition, Query};
-use crate::types::{DataType, DbError, Value};
+use crate::query::{
+    Aggregation, AlterAction, BinaryOp, Condition, ConflictAction, Expr, OnConflict, OrderKey, Privilege, Query, ScalarFunction,
+};
+use crate::schema::{Column, ColumnDefault, DefaultExpr, ForeignKey, IndexDef, IndexKind, OnDelete};
+use crate::storage::index::tokenize;
+use crate::transaction::IsolationLevel;
+use crate::types::{validate_column_name, validate_index_name, validate_table_name, DataType, DbError, Value};
 use ordered_float::OrderedFloat;
 
 pub fn parse_query(input: &str) -> Result<Query, DbError> {
+    let input = strip_comments(input)?;
     let input = input.trim();
     let parts = input.split_whitespace().collect::<Vec<_>>();
     if parts.is_empty() {
@@ -10,95 +16,718 @@ pub fn parse_query(input: &str) -> Result<Query, DbError> {
     }
 
     match parts[0].to_uppercase().as_str() {
+        "CREATE" if parts.len() > 1 && parts[1].eq_ignore_ascii_case("USER") => parse_create_user(input),
+        "CREATE" if parts.len() > 1 && ["FULLTEXT", "INDEX"].iter().any(|kw| parts[1].eq_ignore_ascii_case(kw)) => {
+            parse_create_index(input)
+        }
         "CREATE" => parse_create_table(input),
         "INSERT" => parse_insert(input),
         "SELECT" => parse_select(input),
         "DELETE" => parse_delete(input),
         "DROP" => parse_drop_table(input),
-        "START" => parse_start_transaction(input),
+        "ALTER" => parse_alter_table(input),
+        "COPY" => parse_copy(input),
+        "EXPORT" => parse_export(input),
+        "VACUUM" => parse_vacuum(input),
+        "VERIFY" => parse_verify(input),
+        "EXPLAIN" => parse_explain(input),
+        "START" | "BEGIN" => parse_start_transaction(input),
         "COMMIT" => parse_commit(input),
         "ROLLBACK" => parse_rollback(input),
+        "SAVEPOINT" => parse_savepoint(input),
+        "SET" => parse_set(input),
+        "SHOW" => parse_show(input),
+        "LOGIN" => parse_login(input),
+        "GRANT" | "REVOKE" => parse_grant(input),
+        "BACKUP" | "RESTORE" => parse_backup(input),
         _ => Err(DbError::QueryError(format!("Unknown command: {}", parts[0]))),
     }
 }
 
 fn parse_create_table(input: &str) -> Result<Query, DbError> {
-    let parts = input.split_whitespace().collect::<Vec<_>>();
+    let parts = split_words(input);
     if parts.len() < 4 || parts[1].to_uppercase() != "TABLE" {
         return Err(DbError::QueryError("Invalid CREATE TABLE syntax".to_string()));
     }
-    let table = parts[2].to_string();
+    validate_table_name(parts[2])?;
+    let table = unquote_identifier(parts[2]);
     let col_defs_start = input
         .find('(')
         .ok_or_else(|| DbError::QueryError("Missing column definitions".to_string()))?;
     let col_defs_end = input
         .rfind(')')
         .ok_or_else(|| DbError::QueryError("Missing closing parenthesis".to_string()))?;
-    let col_defs = input[col_defs_start + 1..col_defs_end]
-        .split(',')
-        .map(|s| s.trim())
-        .collect::<Vec<_>>();
+    let col_defs = split_top_level(&input[col_defs_start + 1..col_defs_end], ',');
     let mut columns = Vec::new();
     for col_def in col_defs {
-        let col_parts = col_def.split_whitespace().collect::<Vec<_>>();
-        if col_parts.len() != 2 {
-            return Err(DbError::QueryError("Invalid column definition".to_string()));
-        }
-        let data_type = match col_parts[1].to_uppercase().as_str() {
-            "INT" => DataType::Int32,
-            "FLOAT" => DataType::Float32,
-            "STRING" => DataType::String,
-            _ => return Err(DbError::QueryError(format!("Invalid data type: {}", col_parts[1]))),
-        };
-        columns.push((col_parts[0].to_string(), data_type));
+        columns.push(parse_column_def(col_def.trim())?);
     }
     Ok(Query::CreateTable { table, columns })
 }
 
+// CREATE INDEX name ON table (column, ...) | CREATE FULLTEXT INDEX [name] ON table (column)
+fn parse_create_index(input: &str) -> Result<Query, DbError> {
+    let syntax_error = || {
+        DbError::QueryError(
+            "Invalid CREATE INDEX syntax: expected CREATE INDEX <name> ON <table> (<column>, ...) or CREATE FULLTEXT INDEX [<name>] ON <table> (<column>)"
+                .to_string(),
+        )
+    };
+    let (_, rest) = split_off_word(input);
+    let (mut keyword, mut rest) = split_off_word(rest);
+    let kind = if keyword.eq_ignore_ascii_case("FULLTEXT") {
+        (keyword, rest) = split_off_word(rest);
+        IndexKind::FullText
+    } else {
+        IndexKind::Composite
+    };
+    if !keyword.eq_ignore_ascii_case("INDEX") {
+        return Err(syntax_error());
+    }
+    let on_pos = find_keyword(rest, "ON").ok_or_else(syntax_error)?;
+    let name = match rest[..on_pos].trim() {
+        "" => None,
+        name => {
+            validate_index_name(name)?;
+            Some(unquote_identifier(name))
+        }
+    };
+    let target = rest[on_pos + 2..].trim();
+    let open = target.find('(').ok_or_else(syntax_error)?;
+    if !target.ends_with(')') {
+        return Err(syntax_error());
+    }
+    let table = target[..open].trim();
+    if table.is_empty() {
+        return Err(syntax_error());
+    }
+    let columns: Vec<String> =
+        split_top_level(&target[open + 1..target.len() - 1], ',').into_iter().map(|c| c.trim().to_string()).collect();
+    if columns.iter().any(|c| c.is_empty()) {
+        return Err(syntax_error());
+    }
+    // Only a FULLTEXT index, one per column, can do without a name
+    let name = match (name, kind) {
+        (Some(name), _) => name,
+        (None, IndexKind::FullText) => format!("{}_{}_fulltext", unquote_identifier(table), unquote_identifier(&columns[0])),
+        (None, IndexKind::Composite) => return Err(DbError::QueryError("CREATE INDEX needs an index name".to_string())),
+    };
+    Ok(Query::CreateIndex {
+        table: table.to_string(),
+        index: IndexDef { name, columns, kind },
+    })
+}
+
+/// Splits input holding several `;`-terminated statements, dropping empty ones.
+pub fn split_statements(input: &str) -> Vec<&str> {
+    split_top_level(input, ';')
+        .into_iter()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Removes comments from outside quoted strings: `--` to the end of the line and `/* ... */`,
+/// which does not nest. A block comment becomes a space, so it still separates the words around
+/// it, and line breaks are kept, so positions in the result are on the same lines as in `input`.
+pub fn strip_comments(input: &str) -> Result<String, DbError> {
+    let mut stripped = String::with_capacity(input.len());
+    let mut quote: Option<char> = None;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '-') if chars.peek() == Some(&'-') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
+            (None, '/') if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    if c == '*' && chars.next_if_eq(&'/').is_some() {
+                        closed = true;
+                        break;
+                    }
+                    if c == '\n' {
+                        stripped.push('\n');
+                    }
+                }
+                if !closed {
+                    return Err(DbError::QueryError("Unterminated /* comment".to_string()));
+                }
+                stripped.push(' ');
+                continue;
+            }
+            _ => {}
+        }
+        stripped.push(c);
+    }
+    Ok(stripped)
+}
+
+/// Like `split_statements`, pairing each statement with the line of `input` it starts on,
+/// counted from 1.
+pub fn split_statements_with_lines(input: &str) -> Vec<(usize, &str)> {
+    let mut statements = Vec::new();
+    let mut offset = 0;
+    for part in split_top_level(input, ';') {
+        let start = offset + (part.len() - part.trim_start().len());
+        offset += part.len() + 1;
+        let statement = part.trim();
+        if !statement.is_empty() {
+            statements.push((input[..start].matches('\n').count() + 1, statement));
+        }
+    }
+    statements
+}
+
+/// Replaces each `?` placeholder outside quoted strings with the next parameter rendered as a
+/// SQL literal. Strings are quoted and escaped, so a parameter can never end its literal early.
+pub fn bind_params(sql: &str, params: &[Value]) -> Result<String, DbError> {
+    let mut bound = String::with_capacity(sql.len());
+    let mut params_iter = params.iter();
+    let mut quote: Option<char> = None;
+    for c in sql.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '?') => {
+                let param = params_iter.next().ok_or_else(|| {
+                    DbError::QueryError(format!("Statement has more placeholders than the {} parameters given", params.len()))
+                })?;
+                bound.push_str(&param.to_sql_literal());
+                continue;
+            }
+            _ => {}
+        }
+        bound.push(c);
+    }
+    if params_iter.next().is_some() {
+        return Err(DbError::QueryError(format!(
+            "Statement has fewer placeholders than the {} parameters given",
+            params.len()
+        )));
+    }
+    Ok(bound)
+}
+
+// Splits on `separator` outside of parentheses and quoted strings.
+fn split_top_level(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0i32;
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, c) if c == separator && depth == 0 => {
+                parts.push(&input[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+// Byte offsets where `keyword` appears, in any case, as a whole word outside quotes and
+// parentheses
+fn keyword_positions(input: &str, keyword: &str) -> Vec<usize> {
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let bytes = input.as_bytes();
+    let mut positions = Vec::new();
+    let mut depth = 0i32;
+    let mut quote: Option<char> = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, _) if depth == 0 && (i == 0 || !is_word(bytes[i - 1])) => {
+                let end = i + keyword.len();
+                if input.get(i..end).is_some_and(|word| word.eq_ignore_ascii_case(keyword))
+                    && bytes.get(end).is_none_or(|&b| !is_word(b))
+                {
+                    positions.push(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    positions
+}
+
+fn find_keyword(input: &str, keyword: &str) -> Option<usize> {
+    keyword_positions(input, keyword).first().copied()
+}
+
+// The first word of `input` and what follows it. Quoted text does not end a word, so a
+// double-quoted name with spaces is one word.
+fn split_off_word(input: &str) -> (&str, &str) {
+    let input = input.trim_start();
+    let mut quote: Option<char> = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, c) if c.is_whitespace() => return (&input[..i], input[i..].trim_start()),
+            _ => {}
+        }
+    }
+    (input, "")
+}
+
+fn split_words(input: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut rest = input.trim();
+    while !rest.is_empty() {
+        let (word, tail) = split_off_word(rest);
+        words.push(word);
+        rest = tail;
+    }
+    words
+}
+
+// The name a `CREATE TABLE` or `RENAME` declares: double quotes keep its case significant in
+// later statements but are not part of the name
+fn unquote_identifier(ident: &str) -> String {
+    ident.strip_prefix('"').and_then(|i| i.strip_suffix('"')).unwrap_or(ident).to_string()
+}
+
+/// The header of a result column selected as `column`: a column name, plain or qualified by
+/// its table, without its double quotes. Expressions keep the text they were written with.
+pub fn column_header(column: &str) -> String {
+    let is_identifier = |part: &str| match part.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+        Some(quoted) => !quoted.is_empty() && !quoted.contains('"'),
+        None => !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_'),
+    };
+    let parts: Vec<&str> = column.split('.').collect();
+    if parts.iter().all(|part| is_identifier(part)) {
+        parts.iter().map(|part| unquote_identifier(part)).collect::<Vec<_>>().join(".")
+    } else {
+        column.to_string()
+    }
+}
+
+// Splits a column definition into words, keeping quoted literals whole and making each
+// parenthesis its own token.
+fn tokenize_column_def(col_def: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    for c in col_def.chars() {
+        match (quote, c) {
+            (Some(q), c) => {
+                current.push(c);
+                if c == q {
+                    quote = None;
+                }
+            }
+            (None, '\'') | (None, '"') => {
+                current.push(c);
+                quote = Some(c);
+            }
+            (None, '(') | (None, ')') => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                tokens.push(c.to_string());
+            }
+            (None, c) if c.is_whitespace() => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            (None, c) => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+// Removes a `CHECK (condition)` clause from a column definition, returning the remaining
+// definition and the condition text.
+fn extract_check_clause(col_def: &str) -> Result<(String, Option<String>), DbError> {
+    let upper = col_def.to_ascii_uppercase();
+    let check_pos = match upper.find(" CHECK") {
+        Some(pos) => pos,
+        None => return Ok((col_def.to_string(), None)),
+    };
+    let open = col_def[check_pos..]
+        .find('(')
+        .map(|i| check_pos + i)
+        .ok_or_else(|| DbError::QueryError("CHECK requires a parenthesized condition".to_string()))?;
+    let mut depth = 0;
+    let mut close = None;
+    for (i, c) in col_def[open..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(open + i);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let close = close.ok_or_else(|| DbError::QueryError("Unbalanced parentheses in CHECK".to_string()))?;
+    let condition = col_def[open + 1..close].trim().to_string();
+    if condition.is_empty() {
+        return Err(DbError::QueryError("CHECK condition cannot be empty".to_string()));
+    }
+    let rest = format!("{} {}", &col_def[..check_pos], &col_def[close + 1..]);
+    Ok((rest, Some(condition)))
+}
+
+// Parses `name TYPE [NOT NULL] [UNIQUE] [PRIMARY KEY] [DEFAULT literal] [CHECK (condition)]
+// [ENCRYPTED] [REFERENCES parent(column) [ON DELETE RESTRICT|CASCADE]]`
+fn parse_column_def(col_def: &str) -> Result<Column, DbError> {
+    let (col_def, check) = extract_check_clause(col_def)?;
+    if let Some(check) = &check {
+        parse_condition(check)?;
+    }
+    let col_parts = tokenize_column_def(&col_def);
+    let col_def = col_def.trim();
+    if col_parts.len() < 2 {
+        return Err(DbError::QueryError(format!("Invalid column definition: {}", col_def)));
+    }
+    validate_column_name(&col_parts[0])?;
+    let mut column = Column::new(&unquote_identifier(&col_parts[0]), parse_data_type(&col_parts[1])?);
+    let modifiers = col_parts[2..].iter().map(|p| p.to_uppercase()).collect::<Vec<_>>();
+    let mut i = 0;
+    while i < modifiers.len() {
+        match (modifiers[i].as_str(), modifiers.get(i + 1).map(|s| s.as_str())) {
+            ("NOT", Some("NULL")) => {
+                column = column.not_null();
+                i += 2;
+            }
+            ("PRIMARY", Some("KEY")) => {
+                column = column.primary_key();
+                i += 2;
+            }
+            ("UNIQUE", _) => {
+                column = column.unique();
+                i += 1;
+            }
+            ("ENCRYPTED", _) => {
+                column = column.encrypted();
+                i += 1;
+            }
+            ("DEFAULT", Some("NOW"))
+                if modifiers.get(i + 2).map(|s| s.as_str()) == Some("(")
+                    && modifiers.get(i + 3).map(|s| s.as_str()) == Some(")") =>
+            {
+                column.default_value = Some(ColumnDefault::Expr(DefaultExpr::Now));
+                i += 4;
+            }
+            ("DEFAULT", Some(_)) => {
+                let default = parse_value(&col_parts[2 + i + 1])?.widen_to(&column.data_type);
+                column.default_value = Some(ColumnDefault::Literal(default));
+                i += 2;
+            }
+            ("REFERENCES", Some(_)) => {
+                let (table, open, col, close) = (
+                    &col_parts[2 + i + 1],
+                    modifiers.get(i + 2).map(|s| s.as_str()),
+                    col_parts.get(2 + i + 3),
+                    modifiers.get(i + 4).map(|s| s.as_str()),
+                );
+                let reference_column = match (open, col, close) {
+                    (Some("("), Some(col), Some(")")) => col.to_string(),
+                    _ => {
+                        return Err(DbError::QueryError(format!(
+                            "REFERENCES requires parent(column) in definition: {}",
+                            col_def
+                        )))
+                    }
+                };
+                column.references = Some(ForeignKey {
+                    reference_table: table.to_string(),
+                    reference_column,
+                    on_delete: OnDelete::Restrict,
+                });
+                i += 5;
+            }
+            ("ON", Some("DELETE")) => {
+                let fk = column.references.as_mut().ok_or_else(|| {
+                    DbError::QueryError("ON DELETE requires a REFERENCES clause".to_string())
+                })?;
+                fk.on_delete = match modifiers.get(i + 2).map(|s| s.as_str()) {
+                    Some("RESTRICT") => OnDelete::Restrict,
+                    Some("CASCADE") => OnDelete::Cascade,
+                    other => {
+                        return Err(DbError::QueryError(format!(
+                            "Unsupported ON DELETE action: {}",
+                            other.unwrap_or("")
+                        )))
+                    }
+                };
+                i += 3;
+            }
+            _ => {
+                return Err(DbError::QueryError(format!(
+                    "Unknown column constraint in definition: {}",
+                    col_def
+                )))
+            }
+        }
+    }
+    column.check = check;
+    Ok(column)
+}
+
 fn parse_insert(input: &str) -> Result<Query, DbError> {
     let parts = input.split_whitespace().collect::<Vec<_>>();
-    if parts.len() < 4 || parts[1].to_uppercase() != "INTO" || parts[3].to_uppercase() != "VALUES" {
+    if parts.len() < 4 || parts[1].to_uppercase() != "INTO" {
         return Err(DbError::QueryError("Invalid INSERT syntax".to_string()));
     }
-    let table = parts[2].to_string();
-    let values_start = input
-        .find("VALUES")
-        .ok_or_else(|| DbError::QueryError("Missing VALUES clause".to_string()))?
-        + 6;
-    let values_str = input[values_start..].trim();
-    let values = values_str[1..values_str.len() - 1]
-        .split(',')
-        .map(|s| s.trim())
-        .map(|s| {
-            if s.starts_with('"') && s.ends_with('"') {
-                Ok(Value::String(s[1..s.len() - 1].to_string()))
-            } else if s.contains('.') {
-                s.parse::<f32>()
-                    .map(|f| Value::Float32(OrderedFloat(f)))
-                    .map_err(|_| DbError::QueryError(format!("Invalid float value: {}", s)))
-            } else {
-                s.parse::<i32>()
-                    .map(|i| Value::Int32(i))
-                    .map_err(|_| DbError::QueryError(format!("Invalid integer value: {}", s)))
+    let values_pos = find_keyword(input, "VALUES")
+        .ok_or_else(|| DbError::QueryError("Missing VALUES clause".to_string()))?;
+    let into_end = find_keyword(input, "INTO").unwrap_or(0) + 4;
+    let target = input[into_end..values_pos].trim();
+
+    // Optional column list: INSERT INTO t (a, b) VALUES (...)
+    let (table, columns) = match target.find('(') {
+        Some(open) => {
+            let list = target[open..]
+                .strip_prefix('(')
+                .and_then(|s| s.strip_suffix(')'))
+                .ok_or_else(|| DbError::QueryError("Invalid INSERT column list".to_string()))?;
+            let columns = split_top_level(list, ',').into_iter().map(|c| c.trim().to_string()).collect::<Vec<_>>();
+            (target[..open].trim().to_string(), columns)
+        }
+        None => (target.to_string(), Vec::new()),
+    };
+    if split_words(&table).len() != 1 {
+        return Err(DbError::QueryError("Invalid INSERT syntax".to_string()));
+    }
+
+    // One or more rows: VALUES (...), (...)
+    let mut rest = input[values_pos + 6..].trim();
+    let mut rows = Vec::new();
+    loop {
+        let close = rest
+            .starts_with('(')
+            .then(|| closing_paren(rest))
+            .flatten()
+            .ok_or_else(|| DbError::QueryError("VALUES must be enclosed in parentheses".to_string()))?;
+        let values = split_top_level(&rest[1..close], ',')
+            .into_iter()
+            .map(|s| parse_value(s.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.push(values);
+        rest = rest[close + 1..].trim_start();
+        match rest.strip_prefix(',') {
+            Some(next) => rest = next.trim_start(),
+            None => break,
+        }
+    }
+    let on_conflict = match rest.trim_end() {
+        "" => None,
+        clause => Some(parse_on_conflict(clause)?),
+    };
+    Ok(Query::Insert { table, columns, rows, on_conflict })
+}
+
+// Position of the parenthesis closing the one `input` starts with, skipping quoted strings
+fn closing_paren(input: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
             }
-        })
-        .collect::<Result<Vec<_>, _>>()?;
-    Ok(Query::Insert { table, values })
+            _ => {}
+        }
+    }
+    None
 }
 
-fn parse_select(input: &str) -> Result<Query, DbError> {
-    let columns_end = input
-        .find("FROM")
+// ON CONFLICT (column) DO NOTHING | DO UPDATE SET column = expr, ...
+fn parse_on_conflict(input: &str) -> Result<OnConflict, DbError> {
+    let invalid = || DbError::QueryError(format!("Invalid ON CONFLICT clause: {}", input));
+    let upper = input.to_ascii_uppercase();
+    let rest = upper
+        .strip_prefix("ON")
+        .filter(|rest| rest.starts_with(char::is_whitespace))
+        .and_then(|rest| rest.trim_start().strip_prefix("CONFLICT"))
+        .ok_or_else(invalid)?;
+    let rest = input[input.len() - rest.len()..].trim_start();
+    let close = rest.starts_with('(').then(|| closing_paren(rest)).flatten().ok_or_else(invalid)?;
+    let column = rest[1..close].trim();
+    if split_words(column).len() != 1 || split_top_level(column, ',').len() != 1 {
+        return Err(DbError::QueryError(format!("ON CONFLICT takes exactly one column: {}", input)));
+    }
+
+    let action = rest[close + 1..].trim();
+    let words = action.split_whitespace().map(|w| w.to_uppercase()).collect::<Vec<_>>();
+    let action = match words.iter().map(|w| w.as_str()).collect::<Vec<_>>().as_slice() {
+        ["DO", "NOTHING"] => ConflictAction::DoNothing,
+        ["DO", "UPDATE", "SET", ..] => {
+            let set_pos = action.to_ascii_uppercase().find("SET").ok_or_else(invalid)? + 3;
+            let assignments = split_top_level(&action[set_pos..], ',')
+                .into_iter()
+                .map(|assignment| {
+                    let (target, expr) = assignment.split_once('=').ok_or_else(invalid)?;
+                    let target = target.trim();
+                    if target.is_empty() {
+                        return Err(invalid());
+                    }
+                    let expr = match parse_expr(expr)? {
+                        Expr::Column(name) if name.get(..9).is_some_and(|p| p.eq_ignore_ascii_case("EXCLUDED.")) => {
+                            Expr::Column(format!("EXCLUDED.{}", &name[9..]))
+                        }
+                        expr => expr,
+                    };
+                    Ok((target.to_string(), expr))
+                })
+                .collect::<Result<Vec<_>, DbError>>()?;
+            ConflictAction::DoUpdate(assignments)
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(OnConflict { column: column.to_string(), action })
+}
+
+// COPY table FROM 'file.csv'
+fn parse_copy(input: &str) -> Result<Query, DbError> {
+    let parts = split_words(input);
+    if parts.len() < 4 || parts[2].to_uppercase() != "FROM" {
+        return Err(DbError::QueryError("Invalid COPY syntax: expected COPY <table> FROM '<file>'".to_string()));
+    }
+    let from_pos = input
+        .to_ascii_uppercase()
+        .find(" FROM ")
         .ok_or_else(|| DbError::QueryError("Missing FROM clause".to_string()))?;
+    let path = match parse_value(input[from_pos + 6..].trim())? {
+        Value::String(path) if !path.is_empty() => path,
+        _ => return Err(DbError::QueryError("COPY requires a quoted file name".to_string())),
+    };
+    Ok(Query::CopyFrom {
+        table: parts[1].to_string(),
+        path,
+    })
+}
+
+// VACUUM table
+fn parse_vacuum(input: &str) -> Result<Query, DbError> {
+    match split_words(input)[..] {
+        [_, table] => Ok(Query::Vacuum { table: table.to_string() }),
+        _ => Err(DbError::QueryError("Invalid VACUUM syntax: expected VACUUM <table>".to_string())),
+    }
+}
+
+// VERIFY table
+fn parse_verify(input: &str) -> Result<Query, DbError> {
+    match split_words(input)[..] {
+        [_, table] => Ok(Query::Verify { table: table.to_string() }),
+        _ => Err(DbError::QueryError("Invalid VERIFY syntax: expected VERIFY <table>".to_string())),
+    }
+}
+
+// EXPORT TABLE table TO 'file.parquet'
+fn parse_export(input: &str) -> Result<Query, DbError> {
+    let parts = split_words(input);
+    if parts.len() < 5 || parts[1].to_uppercase() != "TABLE" || parts[3].to_uppercase() != "TO" {
+        return Err(DbError::QueryError(
+            "Invalid EXPORT syntax: expected EXPORT TABLE <table> TO '<file>'".to_string(),
+        ));
+    }
+    let to_pos = input
+        .to_ascii_uppercase()
+        .find(" TO ")
+        .ok_or_else(|| DbError::QueryError("Missing TO clause".to_string()))?;
+    let path = match parse_value(input[to_pos + 4..].trim())? {
+        Value::String(path) if !path.is_empty() => path,
+        _ => return Err(DbError::QueryError("EXPORT requires a quoted file name".to_string())),
+    };
+    Ok(Query::ExportTable {
+        table: parts[2].to_string(),
+        path,
+    })
+}
+
+// BACKUP [INCREMENTAL] TO '<dir>' | RESTORE FROM '<dir>'
+fn parse_backup(input: &str) -> Result<Query, DbError> {
+    let (command, rest) = split_off_word(input);
+    let (mut keyword, mut path) = split_off_word(rest);
+    let restore = command.eq_ignore_ascii_case("RESTORE");
+    let incremental = !restore && keyword.eq_ignore_ascii_case("INCREMENTAL");
+    if incremental {
+        (keyword, path) = split_off_word(path);
+    }
+    let expected = if restore { "FROM" } else { "[INCREMENTAL] TO" };
+    let syntax_error = || {
+        DbError::QueryError(format!(
+            "Invalid {0} syntax: expected {0} {1} '<directory>'",
+            command.to_ascii_uppercase(),
+            expected
+        ))
+    };
+    if !keyword.eq_ignore_ascii_case(if restore { "FROM" } else { "TO" }) {
+        return Err(syntax_error());
+    }
+    let path = match parse_value(path.trim()).map_err(|_| syntax_error())? {
+        Value::String(path) if !path.is_empty() => path,
+        _ => return Err(syntax_error()),
+    };
+    Ok(if restore { Query::Restore { path } } else { Query::Backup { path, incremental } })
+}
+
+fn parse_select(input: &str) -> Result<Query, DbError> {
+    // ORDER BY ends the statement, so everything before it parses as if it were absent
+    let (input, order_by) = match find_order_by(input) {
+        Some(pos) => (input[..pos].trim_end(), parse_order_by(&input[pos..])?),
+        None => (input, Vec::new()),
+    };
+    let (input, group_by) = match find_group_by(input) {
+        Some(pos) => (input[..pos].trim_end(), parse_group_by(&input[pos..])?),
+        None => (input, Vec::new()),
+    };
+    let Some(columns_end) = find_keyword(input, "FROM") else {
+        if !order_by.is_empty() || !group_by.is_empty() {
+            let clause = if order_by.is_empty() { "GROUP BY" } else { "ORDER BY" };
+            return Err(DbError::QueryError(format!("{} requires a FROM clause", clause)));
+        }
+        return parse_select_without_from(input);
+    };
     let columns_str = input[6..columns_end].trim();
     let from_clause = input[columns_end + 4..].trim();
     
     // Check for JOIN
-    if from_clause.to_uppercase().contains(" JOIN ") {
+    if find_keyword(from_clause, "JOIN").is_some() {
+        if !order_by.is_empty() {
+            return Err(DbError::QueryError("ORDER BY is not supported on joins".to_string()));
+        }
+        if !group_by.is_empty() {
+            return Err(DbError::QueryError("GROUP BY is not supported on joins".to_string()));
+        }
         return parse_join(input);
     }
 
-    let from_end = input.find("WHERE").unwrap_or(input.len());
+    let from_end = find_keyword(input, "WHERE").unwrap_or(input.len());
     let table = input[columns_end + 4..from_end].trim().to_string();
     let condition = if from_end < input.len() {
         Some(parse_condition(&input[from_end + 5..].trim())?)
@@ -109,24 +738,23 @@ fn parse_select(input: &str) -> Result<Query, DbError> {
     let columns = if columns_str == "*" {
         Vec::new() // Will be expanded in planner
     } else {
-        columns_str
-            .split(',')
+        split_top_level(columns_str, ',')
+            .into_iter()
             .map(|s| s.trim().to_string())
             .collect::<Vec<_>>()
     };
 
-    if columns.iter().any(|c| {
-        c.to_uppercase().contains("COUNT")
-            || c.to_uppercase().contains("SUM")
-            || c.to_uppercase().contains("AVG")
-            || c.to_uppercase().contains("MIN")
-            || c.to_uppercase().contains("MAX")
-    }) {
+    if !group_by.is_empty() || columns.iter().any(|c| is_aggregate(c)) {
+        if columns.is_empty() {
+            return Err(DbError::QueryError("SELECT * cannot be grouped; name the GROUP BY columns".to_string()));
+        }
         let aggregations = columns
             .iter()
             .map(|c| {
                 let c_upper = c.to_uppercase();
-                if c_upper.starts_with("COUNT") {
+                Ok(if c_upper.starts_with("GROUP_CONCAT") {
+                    parse_group_concat(c)?
+                } else if c_upper.starts_with("COUNT") {
                     Aggregation::Count
                 } else if c_upper.starts_with("SUM") {
                     Aggregation::Sum(c[4..c.len() - 1].to_string())
@@ -137,27 +765,128 @@ fn parse_select(input: &str) -> Result<Query, DbError> {
                 } else if c_upper.starts_with("MAX") {
                     Aggregation::Max(c[4..c.len() - 1].to_string())
                 } else {
-                    Aggregation::Count // Fallback
-                }
+                    // Checked against the GROUP BY columns once names are resolved
+                    Aggregation::GroupKey(c.clone())
+                })
             })
-            .collect();
+            .collect::<Result<_, DbError>>()?;
+        if !order_by.is_empty() {
+            return Err(DbError::QueryError("ORDER BY is not supported on aggregates".to_string()));
+        }
         Ok(Query::SelectAggregate {
             table,
             aggregations,
             condition,
+            group_by,
         })
     } else {
         Ok(Query::Select {
-            table,
+            table: Some(table),
             columns,
             condition,
+            order_by,
+        })
+    }
+}
+
+// Position of the ORDER keyword starting an `ORDER BY` clause
+fn find_order_by(input: &str) -> Option<usize> {
+    keyword_positions(input, "ORDER")
+        .into_iter()
+        .find(|&pos| split_off_word(&input[pos + 5..]).0.eq_ignore_ascii_case("BY"))
+}
+
+// Position of the GROUP keyword starting a `GROUP BY` clause
+fn find_group_by(input: &str) -> Option<usize> {
+    keyword_positions(input, "GROUP")
+        .into_iter()
+        .find(|&pos| split_off_word(&input[pos + 5..]).0.eq_ignore_ascii_case("BY"))
+}
+
+// GROUP BY column, ...
+fn parse_group_by(input: &str) -> Result<Vec<String>, DbError> {
+    let (_, rest) = split_off_word(input);
+    let (_, columns) = split_off_word(rest);
+    let columns: Vec<String> = split_top_level(columns, ',').into_iter().map(|c| c.trim().to_string()).collect();
+    if let Some(column) = columns.iter().find(|c| c.is_empty() || split_words(c).len() != 1) {
+        return Err(DbError::QueryError(format!("Invalid GROUP BY column: '{}'", column)));
+    }
+    Ok(columns)
+}
+
+// ORDER BY column [ASC|DESC] [NULLS FIRST|LAST], ...
+fn parse_order_by(input: &str) -> Result<Vec<OrderKey>, DbError> {
+    let (_, rest) = split_off_word(input);
+    let (_, keys) = split_off_word(rest);
+    if keys.is_empty() {
+        return Err(DbError::QueryError("ORDER BY requires at least one column".to_string()));
+    }
+    split_top_level(keys, ',')
+        .into_iter()
+        .map(|key| {
+            let words = split_words(key);
+            let modifiers = words.iter().skip(1).map(|w| w.to_ascii_uppercase()).collect::<Vec<_>>();
+            let modifiers = modifiers.iter().map(|m| m.as_str()).collect::<Vec<_>>();
+            let (descending, nulls) = match modifiers.as_slice() {
+                [] => (false, None),
+                ["ASC"] => (false, None),
+                ["DESC"] => (true, None),
+                ["NULLS", nulls] => (false, Some(*nulls)),
+                ["ASC", "NULLS", nulls] => (false, Some(*nulls)),
+                ["DESC", "NULLS", nulls] => (true, Some(*nulls)),
+                _ => return Err(DbError::QueryError(format!("Invalid ORDER BY key: {}", key.trim()))),
+            };
+            let column = words.first().ok_or_else(|| DbError::QueryError("Empty ORDER BY key".to_string()))?;
+            let mut order_key = OrderKey::new(column, descending);
+            match nulls {
+                None => {}
+                Some("FIRST") => order_key.nulls_first = true,
+                Some("LAST") => order_key.nulls_first = false,
+                Some(other) => return Err(DbError::QueryError(format!("Expected NULLS FIRST or NULLS LAST, got NULLS {}", other))),
+            }
+            Ok(order_key)
         })
+        .collect()
+}
+
+// SELECT expr, ... evaluated once, without reading a table
+fn parse_select_without_from(input: &str) -> Result<Query, DbError> {
+    let columns_str = input[6..].trim();
+    if columns_str.is_empty() || columns_str == "*" || find_keyword(input, "WHERE").is_some() {
+        return Err(DbError::QueryError("Missing FROM clause".to_string()));
+    }
+    let columns = split_top_level(columns_str, ',')
+        .into_iter()
+        .map(|s| s.trim().to_string())
+        .collect::<Vec<_>>();
+    if columns.iter().any(|c| is_aggregate(c)) {
+        return Err(DbError::QueryError("Aggregates require a FROM clause".to_string()));
+    }
+    Ok(Query::Select {
+        table: None,
+        columns,
+        condition: None,
+        order_by: Vec::new(),
+    })
+}
+
+// GROUP_CONCAT(column) or GROUP_CONCAT(column, 'separator')
+fn parse_group_concat(input: &str) -> Result<Aggregation, DbError> {
+    let invalid = || DbError::QueryError(format!("Invalid GROUP_CONCAT syntax: {}", input));
+    let open = input.find('(').ok_or_else(invalid)?;
+    let args = input[open + 1..].strip_suffix(')').ok_or_else(invalid)?;
+    match split_top_level(args, ',').as_slice() {
+        [column] if !column.trim().is_empty() => Ok(Aggregation::GroupConcat(column.trim().to_string(), None)),
+        [column, separator] => match parse_value(separator.trim())? {
+            Value::String(separator) => Ok(Aggregation::GroupConcat(column.trim().to_string(), Some(separator))),
+            _ => Err(DbError::QueryError(format!("GROUP_CONCAT separator must be a quoted string: {}", input))),
+        },
+        _ => Err(invalid()),
     }
 }
 
 fn parse_join(input: &str) -> Result<Query, DbError> {
-    let columns_end = input
-        .find("FROM")
+    let columns_end = find_keyword(input, "FROM")
         .ok_or_else(|| DbError::QueryError("Missing FROM clause".to_string()))?;
     let columns_str = input[6..columns_end].trim();
     let columns = columns_str
@@ -166,20 +895,20 @@ fn parse_join(input: &str) -> Result<Query, DbError> {
         .collect::<Vec<_>>();
 
     let from_clause = input[columns_end + 4..].trim();
-    let join_pos = from_clause.to_uppercase().find(" JOIN ").ok_or_else(|| {
+    let join_pos = find_keyword(from_clause, "JOIN").ok_or_else(|| {
         DbError::QueryError("Missing JOIN clause".to_string())
     })?;
-    let on_pos = from_clause.to_uppercase().find(" ON ").ok_or_else(|| {
+    let on_pos = find_keyword(from_clause, "ON").ok_or_else(|| {
         DbError::QueryError("Missing ON clause".to_string())
     })?;
-    let where_pos = from_clause.to_uppercase().find(" WHERE ");
+    let where_pos = find_keyword(from_clause, "WHERE");
 
     let left_table = from_clause[..join_pos].trim().to_string();
-    let right_table = from_clause[join_pos + 6..on_pos].trim().to_string();
+    let right_table = from_clause[join_pos + 4..on_pos].trim().to_string();
     let on_clause = if let Some(wp) = where_pos {
-        from_clause[on_pos + 4..wp].trim()
+        from_clause[on_pos + 2..wp].trim()
     } else {
-        from_clause[on_pos + 4..].trim()
+        from_clause[on_pos + 2..].trim()
     };
 
     let on_parts = on_clause.split('=').map(|s| s.trim()).collect::<Vec<_>>();
@@ -190,7 +919,7 @@ fn parse_join(input: &str) -> Result<Query, DbError> {
     let right_column = on_parts[1].split('.').last().unwrap().to_string();
 
     let condition = if let Some(wp) = where_pos {
-        Some(parse_condition(&from_clause[wp + 6..].trim())?)
+        Some(parse_condition(from_clause[wp + 5..].trim())?)
     } else {
         None
     };
@@ -206,22 +935,29 @@ fn parse_join(input: &str) -> Result<Query, DbError> {
 }
 
 fn parse_delete(input: &str) -> Result<Query, DbError> {
-    let parts = input.split_whitespace().collect::<Vec<_>>();
+    let parts = split_words(input);
     if parts.len() < 3 || parts[1].to_uppercase() != "FROM" {
         return Err(DbError::QueryError("Invalid DELETE syntax".to_string()));
     }
     let table = parts[2].to_string();
-    let condition = if input.to_uppercase().contains("WHERE") {
-        let where_pos = input.to_uppercase().find("WHERE").unwrap();
-        Some(parse_condition(&input[where_pos + 5..].trim())?)
-    } else {
-        None
+    let condition = match find_keyword(input, "WHERE") {
+        Some(where_pos) => Some(parse_condition(input[where_pos + 5..].trim())?),
+        None => None,
     };
     Ok(Query::Delete { table, condition })
 }
 
 fn parse_drop_table(input: &str) -> Result<Query, DbError> {
-    let parts = input.split_whitespace().collect::<Vec<_>>();
+    let parts = split_words(input);
+    // DROP INDEX name ON table
+    if parts.len() > 1 && parts[1].eq_ignore_ascii_case("INDEX") {
+        return match parts[..] {
+            [_, _, name, on, table] if on.eq_ignore_ascii_case("ON") => {
+                Ok(Query::DropIndex { table: table.to_string(), name: unquote_identifier(name) })
+            }
+            _ => Err(DbError::QueryError("Invalid DROP INDEX syntax: expected DROP INDEX <name> ON <table>".to_string())),
+        };
+    }
     if parts.len() != 3 || parts[1].to_uppercase() != "TABLE" {
         return Err(DbError::QueryError("Invalid DROP TABLE syntax".to_string()));
     }
@@ -229,8 +965,37 @@ fn parse_drop_table(input: &str) -> Result<Query, DbError> {
     Ok(Query::DropTable { table })
 }
 
+fn parse_alter_table(input: &str) -> Result<Query, DbError> {
+    let parts = split_words(input);
+    if parts.len() < 4 || parts[1].to_uppercase() != "TABLE" {
+        return Err(DbError::QueryError("Invalid ALTER TABLE syntax".to_string()));
+    }
+    let table = parts[2].to_string();
+    let action = match parts[3].to_uppercase().as_str() {
+        "DROP" if parts.len() == 6 && parts[4].to_uppercase() == "COLUMN" => {
+            AlterAction::DropColumn(parts[5].to_string())
+        }
+        "DROP" if parts.len() == 5 => AlterAction::DropColumn(parts[4].to_string()),
+        // RENAME TO new | RENAME [COLUMN] old TO new
+        "RENAME" if parts.len() == 6 && parts[4].eq_ignore_ascii_case("TO") => {
+            validate_table_name(parts[5])?;
+            AlterAction::RenameTable(unquote_identifier(parts[5]))
+        }
+        "RENAME" if parts.len() == 8 && parts[4].eq_ignore_ascii_case("COLUMN") && parts[6].eq_ignore_ascii_case("TO") => {
+            validate_column_name(parts[7])?;
+            AlterAction::RenameColumn { from: parts[5].to_string(), to: unquote_identifier(parts[7]) }
+        }
+        "RENAME" if parts.len() == 7 && parts[5].eq_ignore_ascii_case("TO") => {
+            validate_column_name(parts[6])?;
+            AlterAction::RenameColumn { from: parts[4].to_string(), to: unquote_identifier(parts[6]) }
+        }
+        _ => return Err(DbError::QueryError("Invalid ALTER TABLE syntax".to_string())),
+    };
+    Ok(Query::AlterTable { table, action })
+}
+
 fn parse_start_transaction(input: &str) -> Result<Query, DbError> {
-    if input.to_uppercase() == "START TRANSACTION" {
+    if matches!(input.to_uppercase().as_str(), "START TRANSACTION" | "BEGIN" | "BEGIN TRANSACTION") {
         Ok(Query::StartTransaction)
     } else {
         Err(DbError::QueryError("Invalid START TRANSACTION syntax".to_string()))
@@ -245,55 +1010,237 @@ fn parse_commit(input: &str) -> Result<Query, DbError> {
     }
 }
 
+// EXPLAIN SELECT ...
+fn parse_explain(input: &str) -> Result<Query, DbError> {
+    let inner = parse_query(&input["EXPLAIN".len()..])?;
+    match inner {
+        Query::Select { .. } | Query::SelectAggregate { .. } | Query::Join { .. } => {
+            Ok(Query::Explain(Box::new(inner)))
+        }
+        _ => Err(DbError::QueryError("EXPLAIN supports only SELECT queries".to_string())),
+    }
+}
+
+// ROLLBACK | ROLLBACK TO [SAVEPOINT] name
 fn parse_rollback(input: &str) -> Result<Query, DbError> {
-    if input.to_uppercase() == "ROLLBACK" {
-        Ok(Query::Rollback)
-    } else {
-        Err(DbError::QueryError("Invalid ROLLBACK syntax".to_string()))
+    let parts = input.split_whitespace().collect::<Vec<_>>();
+    let upper = input.to_uppercase();
+    match upper.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["ROLLBACK"] => Ok(Query::Rollback),
+        ["ROLLBACK", "TO", _] => Ok(Query::RollbackToSavepoint(parts[2].to_string())),
+        ["ROLLBACK", "TO", "SAVEPOINT", _] => Ok(Query::RollbackToSavepoint(parts[3].to_string())),
+        _ => Err(DbError::QueryError("Invalid ROLLBACK syntax".to_string())),
     }
 }
 
-fn parse_condition(input: &str) -> Result<Condition, DbError> {
-    let input = input.trim();
-    if input.contains(" AND ") {
-        let parts = input.split(" AND ").collect::<Vec<_>>();
-        if parts.len() != 2 {
-            return Err(DbError::QueryError("Invalid AND condition syntax".to_string()));
+// SET TRANSACTION ISOLATION LEVEL READ COMMITTED | SERIALIZABLE
+// SET TRANSACTION ISOLATION LEVEL ... | SET max_result_rows = n | SET statement_timeout = ms
+fn parse_set(input: &str) -> Result<Query, DbError> {
+    let upper = input.to_uppercase().replace('=', " = ");
+    match upper.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["SET", "TRANSACTION", "ISOLATION", "LEVEL", level @ ..] => match level {
+            ["READ", "COMMITTED"] => Ok(Query::SetIsolationLevel(IsolationLevel::ReadCommitted)),
+            ["SERIALIZABLE"] => Ok(Query::SetIsolationLevel(IsolationLevel::Serializable)),
+            _ => Err(DbError::QueryError(format!(
+                "Unsupported isolation level '{}': expected READ COMMITTED or SERIALIZABLE",
+                level.join(" ")
+            ))),
+        },
+        _ => {
+            // SET name = value, or SET name TO value
+            let syntax_error = || DbError::QueryError("Invalid SET syntax: expected SET <name> = <value>".to_string());
+            let rest = input["SET".len()..].trim_start();
+            let (name, value) = match rest.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => {
+                    let (name, value) = rest.split_once(char::is_whitespace).ok_or_else(syntax_error)?;
+                    let value = value.trim_start();
+                    match value.split_once(char::is_whitespace) {
+                        Some((to, value)) if to.eq_ignore_ascii_case("TO") => (name, value.trim()),
+                        _ => return Err(syntax_error()),
+                    }
+                }
+            };
+            if !is_setting_name(name) || value.is_empty() {
+                return Err(syntax_error());
+            }
+            // A quoted value is the text inside the quotes
+            let value = match parse_value(value) {
+                Ok(Value::String(text)) => text,
+                _ => value.to_string(),
+            };
+            Ok(Query::Set { name: name.to_ascii_lowercase(), value })
         }
-        let left = parse_condition(parts[0])?;
-        let right = parse_condition(parts[1])?;
-        return Ok(Condition::And(Box::new(left), Box::new(right)));
     }
-    if input.contains(" OR ") {
-        let parts = input.split(" OR ").collect::<Vec<_>>();
-        if parts.len() != 2 {
-            return Err(DbError::QueryError("Invalid OR condition syntax".to_string()));
+}
+
+// SHOW name, or SHOW TRANSACTION ISOLATION LEVEL for transaction_isolation
+fn parse_show(input: &str) -> Result<Query, DbError> {
+    match input.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [_, name] if is_setting_name(name) => Ok(Query::Show(name.to_ascii_lowercase())),
+        [_, words @ ..] if words.join(" ").eq_ignore_ascii_case("TRANSACTION ISOLATION LEVEL") => {
+            Ok(Query::Show("transaction_isolation".to_string()))
         }
-        let left = parse_condition(parts[0])?;
-        let right = parse_condition(parts[1])?;
-        return Ok(Condition::Or(Box::new(left), Box::new(right)));
+        _ => Err(DbError::QueryError("Invalid SHOW syntax: expected SHOW <name>".to_string())),
     }
+}
 
+fn is_setting_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// SAVEPOINT name
+fn parse_savepoint(input: &str) -> Result<Query, DbError> {
+    match input.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [_, name] if name.chars().all(|c| c.is_alphanumeric() || c == '_') => Ok(Query::Savepoint(name.to_string())),
+        _ => Err(DbError::QueryError("Invalid SAVEPOINT syntax: expected SAVEPOINT <name>".to_string())),
+    }
+}
+
+// CREATE USER name PASSWORD 'secret'
+fn parse_create_user(input: &str) -> Result<Query, DbError> {
+    let (username, password) = parse_credentials(&input["CREATE".len()..].trim_start()["USER".len()..])
+        .ok_or_else(|| DbError::QueryError("Invalid CREATE USER syntax: expected CREATE USER <name> PASSWORD '<password>'".to_string()))?;
+    Ok(Query::CreateUser { username, password })
+}
+
+// LOGIN name PASSWORD 'secret'
+fn parse_login(input: &str) -> Result<Query, DbError> {
+    let (username, password) = parse_credentials(&input["LOGIN".len()..])
+        .ok_or_else(|| DbError::QueryError("Invalid LOGIN syntax: expected LOGIN <name> PASSWORD '<password>'".to_string()))?;
+    Ok(Query::Login { username, password })
+}
+
+// GRANT op ON table|* TO role | GRANT ROLE role TO user, and REVOKE ... FROM ...
+fn parse_grant(input: &str) -> Result<Query, DbError> {
     let parts = input.split_whitespace().collect::<Vec<_>>();
-    if parts.len() != 3 {
+    let upper = input.to_uppercase();
+    let upper_parts = upper.split_whitespace().collect::<Vec<_>>();
+    let revoke = upper_parts[0] == "REVOKE";
+    let preposition = if revoke { "FROM" } else { "TO" };
+    let privilege = match upper_parts.as_slice() {
+        [_, "ROLE", _, p, _] if *p == preposition => Privilege::Role {
+            role: parts[2].to_string(),
+            username: parts[4].to_string(),
+        },
+        [_, op, "ON", _, p, _] if *p == preposition => {
+            let operation = match *op {
+                "SELECT" => "select",
+                "INSERT" => "insert",
+                "DELETE" => "delete",
+                "CREATE" => "create_table",
+                "DROP" => "drop_table",
+                "ALTER" => "alter_table",
+                _ => return Err(DbError::QueryError(format!("Cannot grant {}", parts[1]))),
+            };
+            // * grants the operation on every table
+            let permission = match parts[3] {
+                "*" => operation.to_string(),
+                table => format!("{}:{}", operation, table),
+            };
+            Privilege::Permission { permission, role: parts[5].to_string() }
+        }
+        _ => {
+            return Err(DbError::QueryError(format!(
+                "Invalid {0} syntax: expected {0} <operation> ON <table> {1} <role> or {0} ROLE <role> {1} <user>",
+                upper_parts[0], preposition
+            )))
+        }
+    };
+    Ok(if revoke { Query::Revoke(privilege) } else { Query::Grant(privilege) })
+}
+
+// Parses `name PASSWORD 'secret'`
+fn parse_credentials(input: &str) -> Option<(String, String)> {
+    let input = input.trim();
+    let (username, rest) = input.split_once(char::is_whitespace)?;
+    let rest = rest.trim_start();
+    let keyword = rest.get(.."PASSWORD".len())?;
+    if !keyword.eq_ignore_ascii_case("PASSWORD") {
+        return None;
+    }
+    let literal = rest["PASSWORD".len()..].trim();
+    if literal.len() < 2 || !literal.starts_with('\'') || !literal.ends_with('\'') {
+        return None;
+    }
+    match parse_value(literal) {
+        Ok(Value::String(password)) => Some((username.to_string(), password)),
+        _ => None,
+    }
+}
+
+pub fn parse_condition(input: &str) -> Result<Condition, DbError> {
+    let input = input.trim();
+    for keyword in ["AND", "OR"] {
+        match keyword_positions(input, keyword).as_slice() {
+            [] => {}
+            [pos] => {
+                let left = parse_condition(&input[..*pos])?;
+                let right = parse_condition(&input[pos + keyword.len()..])?;
+                return Ok(if keyword == "AND" {
+                    Condition::And(Box::new(left), Box::new(right))
+                } else {
+                    Condition::Or(Box::new(left), Box::new(right))
+                });
+            }
+            _ => return Err(DbError::QueryError(format!("Invalid {} condition syntax", keyword))),
+        }
+    }
+
+    // [NOT] EXISTS (SELECT ...)
+    let (first, rest) = split_off_word(input);
+    let (negated, exists, subquery) = if first.eq_ignore_ascii_case("NOT") {
+        let (second, subquery) = split_off_word(rest);
+        (true, second.eq_ignore_ascii_case("EXISTS"), subquery)
+    } else {
+        (false, first.eq_ignore_ascii_case("EXISTS"), rest)
+    };
+    if exists {
+        let subquery = subquery
+            .strip_prefix('(')
+            .filter(|_| closing_paren(subquery) == Some(subquery.len() - 1))
+            .and_then(|s| s.strip_suffix(')'))
+            .ok_or_else(|| DbError::QueryError(format!("EXISTS needs a parenthesized subquery: {}", input)))?;
+        return match parse_query(subquery)? {
+            query @ (Query::Select { .. } | Query::SelectAggregate { .. } | Query::Join { .. }) => {
+                Ok(Condition::Exists { subquery: Box::new(query), negated })
+            }
+            _ => Err(DbError::QueryError(format!("EXISTS needs a SELECT subquery: {}", input))),
+        };
+    }
+
+    // The value may itself contain spaces, as in TIMESTAMP '...'
+    let (column, rest) = split_off_word(input);
+    let (operator, value) = split_off_word(rest);
+    if value.is_empty() {
         return Err(DbError::QueryError(format!(
             "Invalid condition syntax: expected 3 parts, got {}",
-            parts.len()
+            split_words(input).len()
         )));
     }
-    let column = parts[0].to_string();
-    let operator = parts[1];
-    let value = if parts[2].starts_with('"') && parts[2].ends_with('"') {
-        Value::String(parts[2][1..parts[2].len() - 1].to_string())
-    } else if parts[2].contains('.') {
-        Value::Float32(OrderedFloat(parts[2].parse::<f32>().map_err(|_| {
-            DbError::QueryError(format!("Invalid float value: {}", parts[2]))
-        })?))
-    } else {
-        Value::Int32(parts[2].parse::<i32>().map_err(|_| {
-            DbError::QueryError(format!("Invalid integer value: {}", parts[2]))
-        })?)
-    };
+    let column = column.to_string();
+    if operator.eq_ignore_ascii_case("IS") {
+        let negated = match value.split_whitespace().map(|w| w.to_ascii_uppercase()).collect::<Vec<_>>().as_slice() {
+            [null] if null == "NULL" => false,
+            [not, null] if not == "NOT" && null == "NULL" => true,
+            _ => return Err(DbError::QueryError(format!("Expected IS NULL or IS NOT NULL: {}", input))),
+        };
+        return Ok(Condition::IsNull { column, negated });
+    }
+    // col MATCH 'words' | col MATCH ANY 'words'
+    if operator.eq_ignore_ascii_case("MATCH") {
+        let (first, text) = split_off_word(value);
+        let (any, text) = if first.eq_ignore_ascii_case("ANY") { (true, text) } else { (false, value) };
+        let terms = match parse_value(text.trim())? {
+            Value::String(text) => tokenize(&text),
+            _ => return Err(DbError::QueryError(format!("MATCH requires a quoted string: {}", input))),
+        };
+        if terms.is_empty() {
+            return Err(DbError::QueryError(format!("MATCH needs at least one word: {}", input)));
+        }
+        return Ok(Condition::Match { column, terms, any });
+    }
+    let value = parse_value(value)?;
 
     match operator {
         "=" => Ok(Condition::Equal(column, value)),
@@ -303,4 +1250,262 @@ fn parse_condition(input: &str) -> Result<Condition, DbError> {
         ">=" => Ok(Condition::GreaterThanOrEqual(column, value)),
         _ => Err(DbError::QueryError(format!("Invalid operator: {}", operator))),
     }
+}
+
+fn is_aggregate(column: &str) -> bool {
+    let upper = column.trim().to_uppercase();
+    ["COUNT", "SUM", "AVG", "MIN", "MAX", "GROUP_CONCAT"]
+        .iter()
+        .any(|name| upper.starts_with(name) && upper[name.len()..].trim_start().starts_with('('))
+}
+
+fn parse_data_type(input: &str) -> Result<DataType, DbError> {
+    match input.to_uppercase().as_str() {
+        "INT" => Ok(DataType::Int32),
+        "BIGINT" => Ok(DataType::Int64),
+        "FLOAT" => Ok(DataType::Float32),
+        "STRING" | "TEXT" => Ok(DataType::String),
+        "TIMESTAMP" => Ok(DataType::Timestamp),
+        _ => Err(DbError::QueryError(format!("Invalid data type: {}", input))),
+    }
+}
+
+fn parse_value(input: &str) -> Result<Value, DbError> {
+    let is_quoted = |q: char| input.len() >= 2 && input.starts_with(q) && input.ends_with(q);
+    if is_quoted('\'') {
+        // A doubled quote inside the literal stands for a single one
+        Ok(Value::String(input[1..input.len() - 1].replace("''", "'")))
+    } else if is_quoted('"') {
+        Ok(Value::String(input[1..input.len() - 1].to_string()))
+    } else if input.eq_ignore_ascii_case("NULL") {
+        Ok(Value::Null)
+    } else if is_now_call(input) {
+        Ok(Value::now())
+    } else if input.len() > 9 && input.get(..9).is_some_and(|kw| kw.eq_ignore_ascii_case("TIMESTAMP")) {
+        // TIMESTAMP '2024-01-01T00:00:00Z'
+        match parse_value(input[9..].trim())? {
+            Value::String(text) => Value::parse_timestamp(&text).map_err(|e| DbError::QueryError(e.to_string())),
+            _ => Err(DbError::QueryError(format!("TIMESTAMP requires a quoted literal: {}", input))),
+        }
+    } else {
+        parse_number(input)
+    }
+}
+
+// A numeric literal: an optional sign, then digits that single underscores may group, as in
+// `1_000`. A decimal point (`1.5`, `.5`, `5.`) or an exponent (`1e10`, `1.5e-3`) makes it a
+// FLOAT; otherwise it is an INT, or a BIGINT beyond INT's range.
+fn parse_number(input: &str) -> Result<Value, DbError> {
+    let invalid = || DbError::QueryError(format!("Invalid numeric literal: {}", input));
+    let unsigned = input.strip_prefix(['-', '+']).unwrap_or(input);
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (unsigned, None),
+    };
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (mantissa, None),
+    };
+    // Empty, or digit groups each separated by exactly one underscore
+    let digits = |part: &str| part.is_empty() || part.split('_').all(|group| !group.is_empty() && group.bytes().all(|b| b.is_ascii_digit()));
+    let exponent_digits = exponent.map(|e| e.strip_prefix(['-', '+']).unwrap_or(e));
+    if (whole.is_empty() && fraction.is_none_or(str::is_empty))
+        || !digits(whole)
+        || !fraction.is_none_or(digits)
+        || !exponent_digits.is_none_or(|e| !e.is_empty() && digits(e))
+    {
+        return Err(invalid());
+    }
+    let text: String = input.chars().filter(|&c| c != '_').collect();
+    if fraction.is_some() || exponent.is_some() {
+        match text.parse::<f32>() {
+            Ok(f) if f.is_finite() => Ok(Value::Float32(OrderedFloat(f))),
+            _ => Err(DbError::QueryError(format!("FLOAT literal out of range: {}", input))),
+        }
+    } else if let Ok(i) = text.parse::<i32>() {
+        Ok(Value::Int32(i))
+    } else {
+        text.parse::<i64>()
+            .map(Value::Int64)
+            .map_err(|_| DbError::QueryError(format!("Integer literal out of range: {}", input)))
+    }
+}
+
+// NOW(), with any spacing inside the parentheses
+fn is_now_call(input: &str) -> bool {
+    input.get(..3).is_some_and(|name| name.eq_ignore_ascii_case("NOW"))
+        && input[3..].trim_start().strip_prefix('(').is_some_and(|rest| rest.trim() == ")")
+}
+
+// The rest of `CASE WHEN cond THEN expr ... [ELSE expr] END`. A CASE nested in a branch must
+// be parenthesized, or its keywords would be taken for the outer one's.
+fn parse_case(input: &str) -> Result<Expr, DbError> {
+    let invalid = |reason: &str| DbError::QueryError(format!("Invalid CASE expression ({}): CASE {}", reason, input));
+    let ends = keyword_positions(input, "END");
+    let body = match ends.as_slice() {
+        [end] if input[end + 3..].trim().is_empty() => &input[..*end],
+        _ => return Err(invalid("expected a single END at the end")),
+    };
+    let whens = keyword_positions(body, "WHEN");
+    let thens = keyword_positions(body, "THEN");
+    let elses = keyword_positions(body, "ELSE");
+    if whens.is_empty() || whens[0] != 0 {
+        return Err(invalid("expected WHEN after CASE"));
+    }
+    if thens.len() != whens.len() || elses.len() > 1 {
+        return Err(invalid("expected one THEN for each WHEN and at most one ELSE"));
+    }
+    let else_pos = elses.first().copied();
+    let mut branches = Vec::with_capacity(whens.len());
+    for (i, (&when, &then)) in whens.iter().zip(&thens).enumerate() {
+        let end = whens.get(i + 1).copied().or(else_pos).unwrap_or(body.len());
+        if !(when < then && then < end) {
+            return Err(invalid("WHEN, THEN and ELSE out of order"));
+        }
+        let condition = parse_condition(&body[when + 4..then])?;
+        branches.push((condition, parse_expr(&body[then + 4..end])?));
+    }
+    let otherwise = match else_pos {
+        Some(pos) if pos > *thens.last().unwrap_or(&0) => Some(Box::new(parse_expr(&body[pos + 4..])?)),
+        Some(_) => return Err(invalid("ELSE must come after the last branch")),
+        None => None,
+    };
+    Ok(Expr::Case { branches, otherwise })
+}
+
+// The operator an expression splits at, with its byte offset: the last `+` or `-` outside
+// parentheses and quotes, else the last `*`, `/` or `%`, so that operators of equal precedence
+// group from the left. A sign with no operand before it, or the one of an exponent, is not one.
+fn find_binary_operator(input: &str) -> Option<(usize, BinaryOp)> {
+    let mut additive = None;
+    let mut multiplicative = None;
+    let mut depth = 0i32;
+    let mut quote: Option<char> = None;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, '+' | '-' | '*' | '/' | '%') if depth == 0 => {
+                let before = input[..i].trim_end();
+                let follows_operand = before
+                    .chars()
+                    .last()
+                    .is_some_and(|last| last.is_alphanumeric() || matches!(last, '_' | ')' | '\'' | '"' | '.'));
+                let word_start = before.rfind(|ch: char| !(ch.is_alphanumeric() || ch == '_' || ch == '.')).map_or(0, |p| p + 1);
+                let exponent = matches!(c, '+' | '-')
+                    && before.len() == i
+                    && before.ends_with(['e', 'E'])
+                    && before[word_start..].starts_with(|ch: char| ch.is_ascii_digit() || ch == '.');
+                if follows_operand && !exponent {
+                    match c {
+                        '+' => additive = Some((i, BinaryOp::Add)),
+                        '-' => additive = Some((i, BinaryOp::Subtract)),
+                        '*' => multiplicative = Some((i, BinaryOp::Multiply)),
+                        '/' => multiplicative = Some((i, BinaryOp::Divide)),
+                        _ => multiplicative = Some((i, BinaryOp::Modulo)),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    additive.or(multiplicative)
+}
+
+// `NAME(args)` for one of the built-in scalar functions
+fn parse_function_call(input: &str) -> Result<Option<Expr>, DbError> {
+    let Some(open) = input.find('(') else { return Ok(None) };
+    let name = input[..open].trim_end();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') || closing_paren(input) != Some(input.len() - 1) {
+        return Ok(None);
+    }
+    let function = ScalarFunction::from_name(name)
+        .ok_or_else(|| DbError::QueryError(format!("Unsupported function {} in expression: {}", name, input)))?;
+    let args_text = input[open + 1..input.len() - 1].trim();
+    let args = if args_text.is_empty() {
+        Vec::new()
+    } else {
+        split_top_level(args_text, ',').into_iter().map(parse_expr).collect::<Result<Vec<_>, _>>()?
+    };
+    if args.len() != 1 {
+        return Err(DbError::QueryError(format!("{} takes 1 argument, got {}: {}", function.name(), args.len(), input)));
+    }
+    Ok(Some(Expr::Function { function, args }))
+}
+
+pub fn parse_expr(input: &str) -> Result<Expr, DbError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(DbError::QueryError("Empty expression".to_string()));
+    }
+
+    // CASE branches hold operators of their own, so an operand that is a CASE is parenthesized
+    let (first, rest) = split_off_word(input);
+    if first.eq_ignore_ascii_case("CASE") {
+        return parse_case(rest);
+    }
+    if let Some((pos, op)) = find_binary_operator(input) {
+        return Ok(Expr::Binary {
+            op,
+            left: Box::new(parse_expr(&input[..pos])?),
+            right: Box::new(parse_expr(&input[pos + 1..])?),
+        });
+    }
+
+    let upper = input.to_uppercase();
+    if upper.starts_with("CAST") && upper[4..].trim_start().starts_with('(') && input.ends_with(')') {
+        let open = input
+            .find('(')
+            .ok_or_else(|| DbError::QueryError("Invalid CAST syntax".to_string()))?;
+        let inner = &input[open + 1..input.len() - 1];
+        let as_pos = inner
+            .to_uppercase()
+            .rfind(" AS ")
+            .ok_or_else(|| DbError::QueryError("CAST requires AS <type>".to_string()))?;
+        let expr = parse_expr(&inner[..as_pos])?;
+        let target = parse_data_type(inner[as_pos + 4..].trim())?;
+        return Ok(Expr::Cast {
+            expr: Box::new(expr),
+            target,
+        });
+    }
+
+    if input.starts_with('(') && closing_paren(input) == Some(input.len() - 1) {
+        return parse_expr(&input[1..input.len() - 1]);
+    }
+
+    for name in ["COALESCE", "IFNULL"] {
+        if upper.starts_with(name) && upper[name.len()..].trim_start().starts_with('(') && input.ends_with(')') {
+            let open = input.find('(').unwrap_or_default();
+            let args = split_top_level(&input[open + 1..input.len() - 1], ',')
+                .into_iter()
+                .map(parse_expr)
+                .collect::<Result<Vec<_>, _>>()?;
+            if name == "IFNULL" && args.len() != 2 {
+                return Err(DbError::QueryError(format!("IFNULL takes 2 arguments, got {}: {}", args.len(), input)));
+            }
+            return Ok(Expr::Coalesce(args));
+        }
+    }
+
+    // A leading sign left over from `find_binary_operator` belongs to a numeric literal.
+    // Double quotes mark an identifier whose case must match, so they stay part of the name.
+    let unsigned = input.strip_prefix(['-', '+']).unwrap_or(input);
+    let first = unsigned.chars().next().unwrap_or_default();
+    if first == '\'' || first.is_ascii_digit() || first == '.' || upper == "NULL"
+        || upper.starts_with("TIMESTAMP '")
+        || is_now_call(input)
+    {
+        return Ok(Expr::Literal(parse_value(input)?));
+    }
+    if let Some(call) = parse_function_call(input)? {
+        return Ok(call);
+    }
+    if !first.is_alphabetic() && first != '_' && first != '"' {
+        return Err(DbError::QueryError(format!("Unsupported expression: {}", input)));
+    }
+    Ok(Expr::Column(input.to_string()))
 }
\ No newline at end of file
@@ -1,30 +1,314 @@
-use crate::query::{Aggregation, Condition, Query};
+use crate::metrics::QueryMetrics;
+use crate::query::cache::{normalize_statement, PlanCache, DEFAULT_PLAN_CACHE_CAPACITY};
+use crate::query::catalog::{self, catalog_table};
+use crate::query::parser::{column_header, parse_query};
+use crate::query::{Aggregation, AlterAction, Condition, OrderKey, Query, QueryResult};
 use crate::schema::Table;
-use crate::storage::StorageManager;
-use crate::types::{DbError, Value};
+use crate::storage::{SharedStorage, StorageManager};
+use crate::transaction::{IsolationLevel, Transaction, WriteOp};
+use crate::types::{DbError, SecurityContext, Value};
 use crate::DataType;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use rayon::prelude::*;
 
+// Rows decoded per column before a SELECT filters and projects them
+const SCAN_BATCH_ROWS: usize = 1024;
+/// Batches with fewer rows than this are filtered on the calling thread, since handing a few
+/// rows to rayon costs more than it saves; `benches/parallel_threshold.rs` compares the two
+/// around it.
+pub const DEFAULT_PARALLEL_THRESHOLD: usize = 1024;
+/// Environment variable holding the initial `max_result_rows` of every engine; unset or 0 means
+/// no cap.
+pub const MAX_RESULT_ROWS_ENV: &str = "VDDB_MAX_RESULT_ROWS";
+/// Environment variable holding the initial `statement_timeout` of every engine, in
+/// milliseconds; unset or 0 means no limit.
+pub const STATEMENT_TIMEOUT_ENV: &str = "VDDB_STATEMENT_TIMEOUT";
+
+/// Settings the engine owns, read with SHOW and changed with SET. All but `durability` belong
+/// to the session; `durability` applies to the whole database.
+pub const SETTINGS: &[&str] = &["autocommit", "durability", "max_result_rows", "statement_timeout", "transaction_isolation"];
+
+#[derive(Debug)]
 pub struct QueryEngine {
-    storage: Arc<Mutex<StorageManager>>,
+    storage: SharedStorage,
+    // Open transaction started by BEGIN; its writes are buffered until COMMIT
+    transaction: Option<Transaction>,
+    default_isolation_level: IsolationLevel,
+    // When set, every query is checked against it before running; `None` trusts the caller
+    security: Option<SecurityContext>,
+    parallel_threshold: usize,
+    // SELECT and JOIN fail rather than return more rows than this
+    max_result_rows: Option<usize>,
+    statement_timeout: Option<Duration>,
+    // When the running statement's timeout runs out
+    deadline: Option<Instant>,
+    // Set from another thread (or a signal handler) to stop the running statement
+    cancel: Arc<AtomicBool>,
+    // A SELECT stops once it has this many rows; set while an EXISTS subquery runs
+    row_limit: Option<usize>,
+    // When off, a read or write outside a transaction begins one, left open for COMMIT
+    autocommit: bool,
+    // Statements already parsed by `prepare`, dropped when DDL changes a table they name
+    plan_cache: PlanCache,
+    metrics: QueryMetrics,
 }
 
 impl QueryEngine {
-    pub fn new(storage: Arc<Mutex<StorageManager>>) -> Self {
-        QueryEngine { storage }
+    pub fn new(storage: SharedStorage) -> Self {
+        QueryEngine {
+            storage,
+            transaction: None,
+            default_isolation_level: IsolationLevel::default(),
+            security: None,
+            parallel_threshold: DEFAULT_PARALLEL_THRESHOLD,
+            max_result_rows: env_limit(MAX_RESULT_ROWS_ENV).map(|rows| rows as usize),
+            statement_timeout: env_limit(STATEMENT_TIMEOUT_ENV).map(Duration::from_millis),
+            deadline: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+            row_limit: None,
+            autocommit: true,
+            plan_cache: PlanCache::new(DEFAULT_PLAN_CACHE_CAPACITY),
+            metrics: QueryMetrics::new(),
+        }
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    pub fn default_isolation_level(&self) -> IsolationLevel {
+        self.default_isolation_level
+    }
+
+    pub fn set_default_isolation_level(&mut self, level: IsolationLevel) {
+        self.default_isolation_level = level;
+    }
+
+    pub fn security_context(&self) -> Option<&SecurityContext> {
+        self.security.as_ref()
+    }
+
+    pub fn set_security_context(&mut self, security: Option<SecurityContext>) {
+        self.security = security;
+    }
+
+    pub fn parallel_threshold(&self) -> usize {
+        self.parallel_threshold
+    }
+
+    /// SELECT filters batches of at least `threshold` rows in parallel and smaller ones serially;
+    /// 0 always runs in parallel, `usize::MAX` never does.
+    pub fn set_parallel_threshold(&mut self, threshold: usize) {
+        self.parallel_threshold = threshold;
+    }
+
+    pub fn max_result_rows(&self) -> Option<usize> {
+        self.max_result_rows
+    }
+
+    /// Caps the rows a SELECT or JOIN may return: one producing more fails with
+    /// `ResourceExhausted` as soon as it passes the cap, before the rest are read. `None`
+    /// removes the cap. Defaults to `VDDB_MAX_RESULT_ROWS`.
+    pub fn set_max_result_rows(&mut self, max_rows: Option<usize>) {
+        self.max_result_rows = max_rows;
+    }
+
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.statement_timeout
+    }
+
+    /// Limits how long a SELECT or JOIN may run: past it, the statement stops at the next batch
+    /// of rows and fails with `TimeoutError`, returning nothing. `None` allows any time. Defaults
+    /// to `VDDB_STATEMENT_TIMEOUT`.
+    pub fn set_statement_timeout(&mut self, timeout: Option<Duration>) {
+        self.statement_timeout = timeout;
+    }
+
+    /// A flag that stops the running SELECT or JOIN when set: it fails with `Cancelled` at the
+    /// next batch of rows, returning nothing. The flag is cleared once each statement ends, so
+    /// one set while nothing runs stops the next statement instead.
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancel)
+    }
+
+    /// The current value of session setting `name`, one of `SETTINGS`, as SHOW prints it.
+    pub fn setting(&self, name: &str) -> Result<String, DbError> {
+        let limit = |limit: Option<u64>| limit.unwrap_or(0).to_string();
+        match name {
+            "autocommit" => Ok(if self.autocommit { "on" } else { "off" }.to_string()),
+            "durability" => Ok(self.storage.read().unwrap().durability().to_string()),
+            "max_result_rows" => Ok(limit(self.max_result_rows.map(|rows| rows as u64))),
+            "statement_timeout" => Ok(limit(self.statement_timeout.map(|timeout| timeout.as_millis() as u64))),
+            "transaction_isolation" => {
+                let level = self.transaction.as_ref().map_or(self.default_isolation_level, |tx| tx.isolation_level());
+                Ok(match level {
+                    IsolationLevel::ReadCommitted => "read committed",
+                    IsolationLevel::Serializable => "serializable",
+                }
+                .to_string())
+            }
+            _ => Err(unknown_setting(name)),
+        }
+    }
+
+    /// Changes setting `name`, as `SET name = value` does. Limits take a number, 0 meaning
+    /// none; `transaction_isolation` applies like SET TRANSACTION ISOLATION LEVEL. Turning
+    /// `autocommit` on leaves a transaction already open to be ended as usual. `durability`
+    /// takes full, normal or off and changes the level for every session.
+    pub fn set_setting(&mut self, name: &str, value: &str) -> Result<(), DbError> {
+        let invalid = |expected: &str| DbError::ConfigurationError(format!("{} must be {}, got '{}'", name, expected, value));
+        let limit = |expected: &str| match value.trim().parse::<u64>() {
+            Ok(0) => Ok(None),
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(invalid(expected)),
+        };
+        match name {
+            "autocommit" => {
+                self.autocommit = match value.trim().to_ascii_lowercase().as_str() {
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return Err(invalid("on or off")),
+                }
+            }
+            "durability" => {
+                let durability = value.parse().map_err(|_| invalid("full, normal or off"))?;
+                self.storage.write().unwrap().set_durability(durability)?;
+            }
+            "max_result_rows" => self.max_result_rows = limit("a row count")?.map(|rows| rows as usize),
+            "statement_timeout" => self.statement_timeout = limit("milliseconds")?.map(Duration::from_millis),
+            "transaction_isolation" => {
+                let level = match value.trim().to_ascii_lowercase().replace('_', " ").as_str() {
+                    "read committed" => IsolationLevel::ReadCommitted,
+                    "serializable" => IsolationLevel::Serializable,
+                    _ => return Err(invalid("read committed or serializable")),
+                };
+                match self.transaction.as_mut() {
+                    Some(tx) => tx.set_isolation_level(level)?,
+                    None => self.default_isolation_level = level,
+                }
+            }
+            _ => return Err(unknown_setting(name)),
+        }
+        Ok(())
+    }
+
+    /// Hits and misses of `prepare` since the engine was created.
+    pub fn plan_cache_stats(&self) -> (u64, u64) {
+        self.plan_cache.stats()
     }
 
-    pub fn execute(&mut self, query: Query) -> Result<Vec<Vec<Value>>, DbError> {
+    /// Keeps the plans of at most `capacity` statements; 0 turns the cache off.
+    pub fn set_plan_cache_capacity(&mut self, capacity: usize) {
+        self.plan_cache.set_capacity(capacity);
+    }
+
+    /// Parses `sql` with its table and column names resolved, reusing the plan of an earlier
+    /// statement with the same text (ignoring layout) unless DDL run through this engine has
+    /// since changed one of its tables.
+    pub fn prepare(&mut self, sql: &str) -> Result<Query, DbError> {
+        let key = normalize_statement(sql);
+        if let Some(query) = self.plan_cache.get(&key) {
+            self.metrics.record_cache_hits(1, 0);
+            return Ok(query);
+        }
+        self.metrics.record_cache_hits(0, 1);
+        let mut query = parse_query(sql)?;
+        self.resolve_names(&mut query);
+        self.plan_cache.insert(key, query.clone());
+        Ok(query)
+    }
+
+    /// Runs a query and labels its rows with the result column names.
+    pub fn execute_query(&mut self, query: Query) -> Result<QueryResult, DbError> {
+        let columns = self.result_columns(&query)?;
+        let restores = matches!(query, Query::Restore { .. });
+        let changed_tables = ddl_tables(&query);
+        // Invalidated even when the statement fails, in case it got partway
+        let outcome = self.execute(query);
+        self.cancel.store(false, Ordering::SeqCst);
+        if restores {
+            self.plan_cache.clear();
+        }
+        for table in &changed_tables {
+            self.plan_cache.invalidate_table(table);
+        }
+        Ok(QueryResult::new(columns, outcome?))
+    }
+
+    /// Like `execute_query`, then records the statement in the audit log as `sql`, whether it
+    /// succeeded or not.
+    pub fn execute_audited(&mut self, sql: &str, query: Query) -> Result<QueryResult, DbError> {
+        let outcome = self.execute_query(query.clone());
+        let user = self.security.as_ref().and_then(|s| s.current_user.as_ref()).map(|u| u.username.clone());
+        self.storage
+            .read()
+            .map_err(|e| DbError::ConcurrencyError(e.to_string()))?
+            .audit_log()
+            .record(user.as_deref(), sql, &query, outcome.as_ref())?;
+        outcome
+    }
+
+    fn result_columns(&self, query: &Query) -> Result<Vec<String>, DbError> {
         match query {
+            Query::Select { table: Some(table), columns, .. } if columns.is_empty() => {
+                if let Some(table_def) = catalog_table(table) {
+                    return Ok(table_def.columns.into_iter().map(|c| c.name).collect());
+                }
+                let storage_guard = self.storage.read().unwrap();
+                Ok(storage_guard
+                    .schema()
+                    .get_table(table)
+                    .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table)))?
+                    .columns
+                    .iter()
+                    .map(|c| c.name.clone())
+                    .collect())
+            }
+            Query::Select { columns, .. } | Query::Join { columns, .. } => {
+                Ok(columns.iter().map(|column| column_header(column)).collect())
+            }
+            Query::SelectAggregate { aggregations, .. } => Ok(aggregations.iter().map(|a| a.label()).collect()),
+            Query::Explain(_) => Ok(vec!["QUERY PLAN".to_string()]),
+            Query::Show(name) => Ok(vec![name.clone()]),
+            Query::Delete { .. } => Ok(vec!["DELETED".to_string()]),
+            Query::Insert { on_conflict: Some(_), .. } => Ok(vec!["INSERTED".to_string(), "UPDATED".to_string()]),
+            Query::ExportTable { .. } => Ok(vec!["EXPORTED".to_string()]),
+            Query::Vacuum { .. } => Ok(vec!["RECLAIMED_BYTES".to_string()]),
+            Query::Backup { .. } => Ok(vec!["BACKED_UP_BYTES".to_string()]),
+            Query::Restore { .. } => Ok(vec!["RESTORED_BYTES".to_string()]),
+            Query::Verify { .. } => Ok(vec!["COLUMN".to_string(), "ROWS".to_string(), "STATUS".to_string()]),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    pub fn execute(&mut self, mut query: Query) -> Result<Vec<Vec<Value>>, DbError> {
+        self.deadline = self.statement_timeout.map(|timeout| Instant::now() + timeout);
+        self.resolve_names(&mut query);
+        if let Some(security) = &self.security {
+            security.authorize(&query)?;
+        }
+        self.evaluate_exists(&mut query)?;
+        if !self.autocommit && self.transaction.is_none() && opens_transaction(&query) {
+            let storage_guard = self.storage.read().unwrap();
+            self.transaction = Some(Transaction::begin(self.default_isolation_level, &storage_guard));
+        }
+        self.widen_literals(&mut query)?;
+        match query {
+            Query::Select { table: None, columns, .. } => Ok(vec![evaluate_without_table(&columns)?]),
             Query::Select {
-                table,
+                table: Some(table),
                 columns,
                 condition,
+                order_by,
             } => {
+                if let Some(table_def) = catalog_table(&table) {
+                    return self.execute_catalog_select(&table_def, &columns, condition, &order_by);
+                }
                 let columns = if columns.is_empty() {
-                    let storage_guard = self.storage.lock().unwrap();
+                    let storage_guard = self.storage.read().unwrap();
                     storage_guard
                         .schema()
                         .get_table(&table)
@@ -36,13 +320,14 @@ impl QueryEngine {
                 } else {
                     columns
                 };
-                self.execute_select(&table, &columns, condition)
+                self.execute_select(&table, &columns, condition, &order_by)
             }
             Query::SelectAggregate {
                 table,
                 aggregations,
                 condition,
-            } => self.execute_aggregate(&table, &aggregations, condition),
+                group_by,
+            } => self.execute_aggregate(&table, &aggregations, condition, &group_by),
             Query::Join {
                 left_table,
                 right_table,
@@ -58,34 +343,811 @@ impl QueryEngine {
                 &columns,
                 condition,
             ),
-            Query::Insert { table, values } => {
-                self.storage.lock().unwrap().insert_row(&table, values)?;
+            Query::Insert { table, columns, rows, on_conflict } => {
+                match (self.transaction.as_mut(), on_conflict) {
+                    (Some(tx), on_conflict) => {
+                        // Row-level constraints fail the statement now; UNIQUE and foreign keys are checked at COMMIT
+                        let storage_guard = self.storage.read().unwrap();
+                        let mut checked = Vec::with_capacity(rows.len());
+                        for values in rows {
+                            let row = storage_guard.schema().complete_row(&table, &columns, values)?;
+                            storage_guard.schema().validate_row(&table, &row)?;
+                            checked.push(row);
+                        }
+                        if let Some(on_conflict) = on_conflict {
+                            let counts = tx.record_upsert(&storage_guard, &table, checked, on_conflict)?;
+                            return Ok(vec![vec![Value::Int32(counts.inserted as i32), Value::Int32(counts.updated as i32)]]);
+                        }
+                        for row in checked {
+                            tx.record_write(WriteOp::Insert { table: table.clone(), row });
+                        }
+                    }
+                    (None, Some(on_conflict)) => {
+                        // Updates rewrite the table, which needs the storage lock exclusively
+                        let counts = self.storage.write().unwrap().upsert_rows(&table, &columns, rows, &on_conflict)?;
+                        self.checkpoint_if_due()?;
+                        return Ok(vec![vec![Value::Int32(counts.inserted as i32), Value::Int32(counts.updated as i32)]]);
+                    }
+                    (None, None) => {
+                        // Only the table itself is locked, so reads and inserts elsewhere carry on
+                        self.storage.read().unwrap().insert_rows(&table, &columns, rows)?;
+                        self.checkpoint_if_due()?;
+                    }
+                }
                 Ok(vec![])
             }
+            Query::CreateTable { .. }
+            | Query::CreateIndex { .. }
+            | Query::DropIndex { .. }
+            | Query::DropTable { .. }
+            | Query::AlterTable { .. }
+            | Query::Vacuum { .. }
+            | Query::CopyFrom { .. }
+            | Query::Restore { .. }
+            | Query::CreateUser { .. }
+            | Query::Grant(_)
+            | Query::Revoke(_)
+                if self.transaction.is_some() =>
+            {
+                Err(DbError::TransactionError(
+                    "Schema changes, COPY, RESTORE and user management cannot run inside a transaction".to_string(),
+                ))
+            }
             Query::CreateTable { table, columns } => {
                 let table_def = Table {
                     name: table.clone(),
-                    columns: columns
-                        .into_iter()
-                        .map(|(name, data_type)| crate::schema::Column { name, data_type })
-                        .collect(),
+                    columns,
                     row_count: 0,
+                    indexes: Vec::new(),
+                    next_rowid: 1,
                 };
-                self.storage.lock().unwrap().create_table(&table_def)?;
+                self.storage.write().unwrap().create_table(&table_def)?;
                 Ok(vec![])
             }
-            Query::Delete { table, condition } => {
-                self.storage.lock().unwrap().delete_rows(&table, condition.as_ref())?;
+            Query::CreateIndex { table, index } => {
+                self.storage.write().unwrap().create_index(&table, index)?;
+                Ok(vec![])
+            }
+            Query::DropIndex { table, name } => {
+                self.storage.write().unwrap().drop_index(&table, &name)?;
+                Ok(vec![])
+            }
+            Query::CopyFrom { table, path } => {
+                self.storage.read().unwrap().import_csv(&table, &path)?;
+                self.checkpoint_if_due()?;
                 Ok(vec![])
             }
+            Query::Vacuum { table } => {
+                let reclaimed = self.storage.write().unwrap().vacuum(&table)?;
+                Ok(vec![vec![Value::Int32(reclaimed.min(i32::MAX as u64) as i32)]])
+            }
+            Query::Verify { table } => {
+                let report = self.storage.read().unwrap().verify_integrity(&table)?;
+                Ok(report
+                    .into_iter()
+                    .map(|(column, rows)| match rows {
+                        Ok(rows) => vec![Value::String(column), Value::Int32(rows as i32), Value::String("ok".to_string())],
+                        Err(e) => vec![Value::String(column), Value::Null, Value::String(e.to_string())],
+                    })
+                    .collect())
+            }
+            Query::ExportTable { table, path } => {
+                // Exports committed rows; writes pending in an open transaction are not included
+                let exported = self.storage.read().unwrap().export_parquet(&table, &path)?;
+                Ok(vec![vec![Value::Int32(exported as i32)]])
+            }
+            Query::Backup { path, incremental } => {
+                // Like EXPORT, only committed rows are copied
+                let mut storage_guard = self.storage.write().unwrap();
+                let path = std::path::Path::new(&path);
+                let bytes = if incremental { storage_guard.backup_incremental(path)? } else { storage_guard.backup(path)? };
+                Ok(vec![vec![Value::Int64(bytes as i64)]])
+            }
+            Query::Restore { path } => {
+                let bytes = self.storage.write().unwrap().restore(std::path::Path::new(&path))?;
+                Ok(vec![vec![Value::Int64(bytes as i64)]])
+            }
+            Query::Delete { table, condition } => {
+                let mut storage_guard = self.storage.write().unwrap();
+                let deleted = match self.transaction.as_mut() {
+                    Some(tx) => tx.record_delete(&storage_guard, &table, condition)?,
+                    None => storage_guard.delete_rows(&table, condition.as_ref())?,
+                };
+                Ok(vec![vec![Value::Int32(deleted as i32)]])
+            }
             Query::DropTable { table } => {
-                self.storage.lock().unwrap().drop_table(&table)?;
+                self.storage.write().unwrap().drop_table(&table)?;
+                Ok(vec![])
+            }
+            Query::AlterTable { table, action } => {
+                let mut storage_guard = self.storage.write().unwrap();
+                match action {
+                    AlterAction::DropColumn(column) => storage_guard.drop_column(&table, &column)?,
+                    AlterAction::RenameTable(new_name) => storage_guard.rename_table(&table, &new_name)?,
+                    AlterAction::RenameColumn { from, to } => storage_guard.rename_column(&table, &from, &to)?,
+                }
+                Ok(vec![])
+            }
+            Query::StartTransaction => {
+                if self.transaction.is_some() {
+                    return Err(DbError::TransactionError("A transaction is already in progress".to_string()));
+                }
+                let storage_guard = self.storage.read().unwrap();
+                self.transaction = Some(Transaction::begin(self.default_isolation_level, &storage_guard));
+                Ok(vec![])
+            }
+            Query::Commit => {
+                let tx = self
+                    .transaction
+                    .take()
+                    .ok_or_else(|| DbError::TransactionError("No transaction in progress".to_string()))?;
+                tx.apply_writes(&mut self.storage.write().unwrap())?;
+                Ok(vec![])
+            }
+            Query::Rollback => {
+                // Nothing reached storage, so dropping the buffered writes undoes the transaction
+                self.transaction
+                    .take()
+                    .ok_or_else(|| DbError::TransactionError("No transaction in progress".to_string()))?;
+                Ok(vec![])
+            }
+            // Inside a transaction this applies to it; otherwise to the transactions begun afterwards
+            Query::SetIsolationLevel(level) => {
+                match self.transaction.as_mut() {
+                    Some(tx) => tx.set_isolation_level(level)?,
+                    None => self.default_isolation_level = level,
+                }
+                Ok(vec![])
+            }
+            Query::Set { name, value } => {
+                self.set_setting(&name, &value)?;
+                Ok(vec![])
+            }
+            Query::Show(name) => Ok(vec![vec![Value::String(self.setting(&name)?)]]),
+            Query::Savepoint(name) => {
+                self.transaction
+                    .as_mut()
+                    .ok_or_else(|| DbError::TransactionError("SAVEPOINT requires an open transaction".to_string()))?
+                    .savepoint(&name);
+                Ok(vec![])
+            }
+            Query::RollbackToSavepoint(name) => {
+                self.transaction
+                    .as_mut()
+                    .ok_or_else(|| DbError::TransactionError("No transaction in progress".to_string()))?
+                    .rollback_to_savepoint(&name)?;
                 Ok(vec![])
             }
-            Query::StartTransaction | Query::Commit | Query::Rollback => {
+            Query::Explain(query) => Ok(self
+                .explain(&query)?
+                .into_iter()
+                .map(|line| vec![Value::String(line)])
+                .collect()),
+            Query::CreateUser { username, password } => {
+                self.storage.write().unwrap().users_mut().create_user(&username, &password)?;
                 Ok(vec![])
             }
+            // Logging in turns on permission checks for an engine that had none
+            Query::Login { username, password } => {
+                let storage_guard = self.storage.read().unwrap();
+                let user = storage_guard.users().authenticate(&username, &password)?;
+                let security = self.security.get_or_insert_with(SecurityContext::new);
+                security.current_user = Some(user);
+                security.permissions = storage_guard.users().permissions().clone();
+                Ok(vec![])
+            }
+            // Applies to this session at once; other sessions see it at their next LOGIN
+            Query::Grant(privilege) => {
+                let mut storage_guard = self.storage.write().unwrap();
+                storage_guard.users_mut().grant(&privilege)?;
+                refresh_security(self.security.as_mut(), &storage_guard);
+                Ok(vec![])
+            }
+            Query::Revoke(privilege) => {
+                let mut storage_guard = self.storage.write().unwrap();
+                storage_guard.users_mut().revoke(&privilege)?;
+                refresh_security(self.security.as_mut(), &storage_guard);
+                Ok(vec![])
+            }
+        }
+    }
+
+    // Inserts share the storage lock, so one that fills a table's buffer flushes it but leaves
+    // truncating the WAL to a checkpoint run here, once the lock can be taken exclusively
+    fn checkpoint_if_due(&self) -> Result<(), DbError> {
+        if self.storage.read().unwrap().checkpoint_due() {
+            let mut storage_guard = self.storage.write().unwrap();
+            // Another statement may have run it meanwhile
+            if storage_guard.checkpoint_due() {
+                storage_guard.checkpoint()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes, one line per plan step, how `query` would be executed. Nested steps are
+    /// indented under the step that consumes their output.
+    fn explain(&self, query: &Query) -> Result<Vec<String>, DbError> {
+        let storage_guard = self.storage.read().unwrap();
+        let table_def = |name: &str| {
+            let mut table = storage_guard
+                .schema()
+                .get_table(name)
+                .cloned()
+                .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", name)))?;
+            table.row_count = storage_guard.row_count(name) as u64;
+            Ok::<_, DbError>(table)
+        };
+        // Every read is a column scan; a pushed-down predicate skips blocks by their min/max and
+        // drops non-matching values during the scan
+        let scan = |table: &Table, columns: &[String], condition: Option<&Condition>| {
+            let mut line = format!(
+                "Column scan on {} reading [{}] (est. {} rows)",
+                table.name,
+                columns.join(", "),
+                table.row_count
+            );
+            if let Some(cond) = condition {
+                line.push_str(&format!(", skipping blocks by min/max on {}", cond));
+            }
+            line
+        };
+
+        match query {
+            Query::Select { table: None, columns, .. } => {
+                Ok(vec![format!("Project [{}]", columns.join(", ")), "  -> Single row without a table".to_string()])
+            }
+            Query::Select { table: Some(table), columns, condition, order_by } => {
+                if let Some(table) = catalog_table(table) {
+                    let projection = if columns.is_empty() {
+                        table.columns.iter().map(|c| c.name.clone()).collect()
+                    } else {
+                        columns.clone()
+                    };
+                    let mut plan = vec![format!("Project [{}]", projection.join(", "))];
+                    if let Some(cond) = condition {
+                        plan.push(format!("  -> Filter: {}", cond));
+                    }
+                    plan.push(format!("{}-> Catalog read of {} built from the schema", "  ".repeat(plan.len()), table.name));
+                    return Ok(with_sort(plan, order_by));
+                }
+                let table = table_def(table)?;
+                let projection = if columns.is_empty() {
+                    table.columns.iter().map(|c| c.name.clone()).collect()
+                } else {
+                    columns.clone()
+                };
+                let mut read = Vec::new();
+                for expr in &projection {
+                    let mut expr_columns: Vec<String> =
+                        crate::query::collect_expr_columns(&parse_projection(expr, &table)?).into_iter().collect();
+                    expr_columns.sort();
+                    read.extend(expr_columns);
+                }
+                if let Some(cond) = condition {
+                    let mut cond_columns: Vec<String> = crate::query::collect_condition_columns(cond).into_iter().collect();
+                    cond_columns.sort();
+                    read.extend(cond_columns);
+                }
+                read.extend(order_by.iter().map(|key| key.column.clone()));
+                let mut seen = std::collections::HashSet::new();
+                read.retain(|c| seen.insert(c.clone()));
+
+                let mut plan = vec![format!("Project [{}]", projection.join(", "))];
+                let lookup = index_lookup(&storage_guard, &table.name, condition.as_ref()).map(|(kind, _, _)| kind);
+                match (condition, lookup) {
+                    (Some(cond), Some(kind)) => {
+                        plan.push(format!("  -> Filter: {}", cond));
+                        plan.push(format!("    -> {} index lookup on {} reading [{}]", kind, table.name, read.join(", ")));
+                    }
+                    (Some(cond), None) => {
+                        plan.push(format!("  -> Parallel filter: {}", cond));
+                        plan.push(format!("    -> {}", scan(&table, &read, crate::query::pushdown_predicate(cond))));
+                    }
+                    (None, _) => plan.push(format!("  -> {}", scan(&table, &read, None))),
+                }
+                Ok(with_sort(plan, order_by))
+            }
+            Query::SelectAggregate { table, aggregations, condition, group_by } => {
+                let table = table_def(table)?;
+                // COUNT(*) reads no column of its own
+                let mut read = Vec::new();
+                for column in group_by.iter().map(String::as_str).chain(aggregations.iter().filter_map(|agg| agg.column())) {
+                    if !read.iter().any(|c| c == column) {
+                        read.push(column.to_string());
+                    }
+                }
+                let count_only = read.is_empty();
+                let labels = aggregations.iter().map(|a| a.label()).collect::<Vec<_>>();
+                let mut plan = vec![if group_by.is_empty() {
+                    format!("Aggregate [{}] (1 row)", labels.join(", "))
+                } else {
+                    format!("Aggregate [{}] grouped by [{}]", labels.join(", "), group_by.join(", "))
+                }];
+                let lookup = index_lookup(&storage_guard, &table.name, condition.as_ref()).map(|(kind, _, _)| kind);
+                match (condition, lookup) {
+                    (Some(cond), Some(kind)) if count_only && index_counts(cond) => {
+                        plan.push(format!("  -> {} index count on {} where {}", kind, table.name, cond));
+                    }
+                    (Some(cond), lookup) => {
+                        let mut cond_columns: Vec<String> = crate::query::collect_condition_columns(cond).into_iter().collect();
+                        cond_columns.sort();
+                        for col in cond_columns {
+                            if !read.contains(&col) {
+                                read.push(col);
+                            }
+                        }
+                        plan.push(format!("  -> Filter: {}", cond));
+                        match lookup {
+                            Some(kind) => {
+                                plan.push(format!("    -> {} index lookup on {} reading [{}]", kind, table.name, read.join(", ")))
+                            }
+                            None => plan.push(format!("    -> {}", scan(&table, &read, crate::query::pushdown_predicate(cond)))),
+                        }
+                    }
+                    (None, _) if count_only => plan.push(format!("  -> Row count of {} (est. {} rows)", table.name, table.row_count)),
+                    (None, _) => plan.push(format!("  -> {}", scan(&table, &read, None))),
+                }
+                Ok(plan)
+            }
+            Query::Join { left_table, right_table, left_column, right_column, columns, condition } => {
+                let left = table_def(left_table)?;
+                let right = table_def(right_table)?;
+                let mut left_read = vec![left_column.clone()];
+                let mut right_read = vec![right_column.clone()];
+                for col in columns {
+                    let (read, name) = match col.split_once('.') {
+                        Some((t, c)) if t == right_table => (&mut right_read, c),
+                        Some((_, c)) => (&mut left_read, c),
+                        None => (&mut left_read, col.as_str()),
+                    };
+                    if !read.iter().any(|r| r == name) {
+                        read.push(name.to_string());
+                    }
+                }
+                Ok(vec![
+                    format!("Project [{}]", columns.join(", ")),
+                    format!(
+                        "  -> Nested loop join on {}.{} = {}.{}, parallel over {} rows (est. at most {} rows)",
+                        left_table,
+                        left_column,
+                        right_table,
+                        right_column,
+                        left_table,
+                        left.row_count * right.row_count
+                    ),
+                    format!("    -> {}", scan(&left, &left_read, condition.as_ref())),
+                    format!("    -> {}", scan(&right, &right_read, condition.as_ref())),
+                ])
+            }
+            _ => Err(DbError::QueryError("EXPLAIN supports only SELECT queries".to_string())),
+        }
+    }
+
+    // Runs the EXISTS subqueries in the WHERE of `query` and folds their answers into it, so
+    // that only column tests are left to evaluate per row. A WHERE that comes out always true is
+    // dropped; one that comes out always false is replaced by a test no row passes.
+    fn evaluate_exists(&mut self, query: &mut Query) -> Result<(), DbError> {
+        let (table, condition) = match query {
+            Query::Select { table: Some(table), condition, .. }
+            | Query::SelectAggregate { table, condition, .. }
+            | Query::Delete { table, condition } => (table.as_str(), condition),
+            Query::Join { condition: Some(cond), .. } if !cond.subqueries().is_empty() => {
+                return Err(DbError::QueryError("EXISTS is not supported in the WHERE of a join".to_string()))
+            }
+            _ => return Ok(()),
+        };
+        if condition.as_ref().is_none_or(|cond| cond.subqueries().is_empty()) {
+            return Ok(());
+        }
+        let folded = match condition.take() {
+            Some(cond) => self.fold_exists(cond)?,
+            None => return Ok(()),
+        };
+        *condition = match folded {
+            Folded::Always(true) => None,
+            Folded::Where(cond) => Some(cond),
+            Folded::Always(false) => {
+                // `c IS NULL AND c IS NOT NULL` holds for no row, whatever its values
+                let column = match catalog_table(table) {
+                    Some(table_def) => table_def.columns.first().map(|c| c.name.clone()),
+                    None => {
+                        let storage_guard = self.storage.read().unwrap();
+                        storage_guard.schema().get_table(table).and_then(|t| t.columns.first()).map(|c| c.name.clone())
+                    }
+                };
+                let column = column.unwrap_or_else(|| crate::schema::ROWID.to_string());
+                Some(Condition::And(
+                    Box::new(Condition::IsNull { column: column.clone(), negated: false }),
+                    Box::new(Condition::IsNull { column, negated: true }),
+                ))
+            }
+        };
+        Ok(())
+    }
+
+    // Replaces each EXISTS in `condition` by its answer, simplifying the ANDs and ORs it decides.
+    // A decided side of an AND or OR leaves the other side's subqueries unrun.
+    fn fold_exists(&mut self, condition: Condition) -> Result<Folded, DbError> {
+        match condition {
+            Condition::Exists { subquery, negated } => Ok(Folded::Always(self.exists(*subquery)? != negated)),
+            Condition::And(left, right) => match self.fold_exists(*left)? {
+                Folded::Always(false) => Ok(Folded::Always(false)),
+                Folded::Always(true) => self.fold_exists(*right),
+                Folded::Where(left) => Ok(match self.fold_exists(*right)? {
+                    Folded::Always(false) => Folded::Always(false),
+                    Folded::Always(true) => Folded::Where(left),
+                    Folded::Where(right) => Folded::Where(Condition::And(Box::new(left), Box::new(right))),
+                }),
+            },
+            Condition::Or(left, right) => match self.fold_exists(*left)? {
+                Folded::Always(true) => Ok(Folded::Always(true)),
+                Folded::Always(false) => self.fold_exists(*right),
+                Folded::Where(left) => Ok(match self.fold_exists(*right)? {
+                    Folded::Always(true) => Folded::Always(true),
+                    Folded::Always(false) => Folded::Where(left),
+                    Folded::Where(right) => Folded::Where(Condition::Or(Box::new(left), Box::new(right))),
+                }),
+            },
+            cond => Ok(Folded::Where(cond)),
+        }
+    }
+
+    // Whether `subquery` returns any row. It runs like any statement, names, permissions and
+    // all, but a SELECT stops at its first row; the outer statement keeps its own deadline.
+    fn exists(&mut self, subquery: Query) -> Result<bool, DbError> {
+        let (deadline, row_limit) = (self.deadline, self.row_limit.replace(1));
+        let rows = self.execute(subquery);
+        self.deadline = deadline;
+        self.row_limit = row_limit;
+        Ok(!rows?.is_empty())
+    }
+
+    // Rewrites the table and column names in `query` to the names the schema declares them
+    // with, so unquoted identifiers match in any case and storage can key by the declared names.
+    // Names that resolve to nothing are left for execution to report.
+    fn resolve_names(&self, query: &mut Query) {
+        if let Query::Explain(inner) = query {
+            return self.resolve_names(inner);
+        }
+        let storage_guard = self.storage.read().unwrap();
+        let schema = storage_guard.schema();
+        let resolve_table = |name: &mut String| {
+            let table = schema.get_table(name)?;
+            *name = table.name.clone();
+            Some(table)
+        };
+        let resolve_column = |table: &Table, name: &mut String| {
+            if let Some(column) = table.get_column(name) {
+                *name = column.name.clone();
+            }
+        };
+        match query {
+            Query::Select { table: Some(table), condition, order_by, .. } => {
+                let catalog = catalog_table(table);
+                let Some(table) = (match &catalog {
+                    Some(catalog) => Some(catalog),
+                    None => resolve_table(table),
+                }) else {
+                    return;
+                };
+                for key in order_by.iter_mut() {
+                    resolve_column(table, &mut key.column);
+                }
+                if let Some(cond) = condition {
+                    crate::query::resolve_condition(cond, &[table]);
+                }
+            }
+            Query::SelectAggregate { table, aggregations, condition, group_by } => {
+                if let Some(table) = resolve_table(table) {
+                    for column in aggregations.iter_mut().filter_map(|agg| agg.column_mut()).chain(group_by.iter_mut()) {
+                        resolve_column(table, column);
+                    }
+                    if let Some(cond) = condition {
+                        crate::query::resolve_condition(cond, &[table]);
+                    }
+                }
+            }
+            Query::Join { left_table, right_table, left_column, right_column, columns, condition } => {
+                let (Some(left), Some(right)) = (resolve_table(left_table), resolve_table(right_table)) else {
+                    return;
+                };
+                resolve_column(left, left_column);
+                resolve_column(right, right_column);
+                // Unqualified columns belong to the left table
+                for column in columns.iter_mut() {
+                    let resolved = match column.split_once('.') {
+                        Some(_) => crate::query::resolve_column(column, &[left, right]),
+                        None => left.get_column(column).map(|c| c.name.clone()),
+                    };
+                    if let Some(resolved) = resolved {
+                        *column = resolved;
+                    }
+                }
+                if let Some(cond) = condition {
+                    crate::query::resolve_condition(cond, &[left, right]);
+                }
+            }
+            Query::Insert { table, columns, on_conflict, .. } => {
+                let Some(table) = resolve_table(table) else { return };
+                for column in columns.iter_mut() {
+                    resolve_column(table, column);
+                }
+                if let Some(on_conflict) = on_conflict {
+                    resolve_column(table, &mut on_conflict.column);
+                    if let crate::query::ConflictAction::DoUpdate(assignments) = &mut on_conflict.action {
+                        for (column, expr) in assignments.iter_mut() {
+                            resolve_column(table, column);
+                            match expr {
+                                crate::query::Expr::Column(name) if name.starts_with("EXCLUDED.") => {
+                                    if let Some(column) = table.get_column(&name["EXCLUDED.".len()..]) {
+                                        *name = format!("EXCLUDED.{}", column.name);
+                                    }
+                                }
+                                expr => crate::query::resolve_expr(expr, &[table]),
+                            }
+                        }
+                    }
+                }
+            }
+            Query::Delete { table, condition } => {
+                if let (Some(table), Some(cond)) = (resolve_table(table), condition) {
+                    crate::query::resolve_condition(cond, &[table]);
+                }
+            }
+            Query::AlterTable { table, action } => {
+                let Some(table) = resolve_table(table) else { return };
+                if let AlterAction::DropColumn(column) | AlterAction::RenameColumn { from: column, .. } = action {
+                    resolve_column(table, column);
+                }
+            }
+            Query::CreateIndex { table, index } => {
+                let Some(table) = resolve_table(table) else { return };
+                for column in index.columns.iter_mut() {
+                    resolve_column(table, column);
+                }
+            }
+            Query::DropTable { table }
+            | Query::DropIndex { table, .. }
+            | Query::Vacuum { table }
+            | Query::Verify { table }
+            | Query::CopyFrom { table, .. }
+            | Query::ExportTable { table, .. } => {
+                resolve_table(table);
+            }
+            // A permission on one table names it as declared, so it matches the resolved queries
+            Query::Grant(crate::query::Privilege::Permission { permission, .. })
+            | Query::Revoke(crate::query::Privilege::Permission { permission, .. }) => {
+                if let Some((operation, table)) = permission.split_once(':') {
+                    let mut table = table.to_string();
+                    if resolve_table(&mut table).is_some() {
+                        *permission = format!("{}:{}", operation, table);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Widens INT literals in the query's WHERE to the BIGINT columns they are compared with
+    fn widen_literals(&self, query: &mut Query) -> Result<(), DbError> {
+        let storage_guard = self.storage.read().unwrap();
+        let schema = storage_guard.schema();
+        match query {
+            Query::Select { table: Some(table), condition: Some(cond), .. }
+            | Query::SelectAggregate { table, condition: Some(cond), .. }
+            | Query::Delete { table, condition: Some(cond) } => {
+                let catalog = catalog_table(table);
+                if let Some(table_def) = schema.get_table(table).or(catalog.as_ref()) {
+                    crate::query::widen_condition(cond, &[table_def])?;
+                }
+                Ok(())
+            }
+            Query::Join { left_table, right_table, condition: Some(cond), .. } => {
+                let tables: Vec<&Table> = [left_table, right_table].iter().filter_map(|t| schema.get_table(t)).collect();
+                crate::query::widen_condition(cond, &tables)
+            }
+            Query::Explain(inner) => {
+                drop(storage_guard);
+                self.widen_literals(inner)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks `query` the way running it would (permissions, tables, columns, literal and
+    /// aggregate types, and for INSERT the completed row) without scanning or changing any rows.
+    /// Returns what the statement resolved to, one line each: its tables, columns and condition.
+    pub fn validate(&self, query: &Query) -> Result<Vec<String>, DbError> {
+        if let Query::Explain(inner) = query {
+            return self.validate(inner);
+        }
+        for subquery in query.condition().map_or_else(Vec::new, |cond| cond.subqueries()) {
+            self.validate(subquery)?;
+        }
+        let mut query = query.clone();
+        self.resolve_names(&mut query);
+        let query = &query;
+        if let Some(security) = &self.security {
+            security.authorize(query)?;
+        }
+        let storage_guard = self.storage.read().map_err(|e| DbError::ConcurrencyError(e.to_string()))?;
+        let schema = storage_guard.schema();
+        let table_def = |name: &str| {
+            schema
+                .get_table(name)
+                .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", name)))
+        };
+        let column_def = |table: &Table, name: &str| {
+            table
+                .get_column(name)
+                .cloned()
+                .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table.name, name)))
+        };
+        let condition_line = |condition: &Option<Condition>| condition.as_ref().map(|cond| format!("Condition: {}", cond));
+
+        let mut lines = Vec::new();
+        match query {
+            Query::Select { table: None, columns, .. } => {
+                for expr in columns {
+                    let mut expr = crate::query::parser::parse_expr(expr)?;
+                    reject_column_refs(&expr)?;
+                    crate::query::widen_expr(&mut expr, &[])?;
+                }
+                lines.push(format!("Columns: {}", columns.join(", ")));
+            }
+            Query::Select { table: Some(table), columns, condition, order_by } => {
+                let catalog = catalog_table(table);
+                let table = match &catalog {
+                    Some(catalog) => catalog,
+                    None => table_def(table)?,
+                };
+                for key in order_by {
+                    column_def(table, &key.column)?;
+                }
+                let projection = if columns.is_empty() {
+                    table.columns.iter().map(|c| c.name.clone()).collect()
+                } else {
+                    columns.clone()
+                };
+                for expr in &projection {
+                    for col in crate::query::collect_expr_columns(&parse_projection(expr, table)?) {
+                        column_def(table, &col)?;
+                    }
+                }
+                if let Some(cond) = condition {
+                    validate_condition(&[table], cond)?;
+                }
+                lines.push(format!("Table: {}", table.name));
+                lines.push(format!("Columns: {}", projection.join(", ")));
+                lines.extend(condition_line(condition));
+                if !order_by.is_empty() {
+                    lines.push(format!("Order: {}", order_by.iter().map(|key| key.to_string()).collect::<Vec<_>>().join(", ")));
+                }
+            }
+            Query::SelectAggregate { table, aggregations, condition, group_by } => {
+                let table = table_def(table)?;
+                for column in group_by {
+                    column_def(table, column)?;
+                }
+                check_group_keys(aggregations, group_by)?;
+                for agg in aggregations {
+                    let Some(column) = agg.column() else { continue };
+                    let data_type = column_def(table, column)?.data_type;
+                    if agg.result_type(&data_type).is_none() {
+                        return Err(DbError::InvalidData(format!("{} not supported for type {:?}", agg.label(), data_type)));
+                    }
+                }
+                if let Some(cond) = condition {
+                    validate_condition(&[table], cond)?;
+                }
+                lines.push(format!("Table: {}", table.name));
+                lines.push(format!("Columns: {}", aggregations.iter().map(|a| a.label()).collect::<Vec<_>>().join(", ")));
+                lines.extend(condition_line(condition));
+                if !group_by.is_empty() {
+                    lines.push(format!("Group by: {}", group_by.join(", ")));
+                }
+            }
+            Query::Join { left_table, right_table, left_column, right_column, columns, condition } => {
+                let left = table_def(left_table)?;
+                let right = table_def(right_table)?;
+                let (left_type, right_type) = (&column_def(left, left_column)?.data_type, &column_def(right, right_column)?.data_type);
+                if left_type != right_type {
+                    return Err(DbError::TypeMismatch(format!(
+                        "Cannot join {}.{} ({}) with {}.{} ({})",
+                        left.name,
+                        left_column,
+                        left_type.sql_name(),
+                        right.name,
+                        right_column,
+                        right_type.sql_name()
+                    )));
+                }
+                for col in columns {
+                    match col.split_once('.') {
+                        Some((t, c)) if t == right_table => column_def(right, c)?,
+                        Some((t, c)) if t == left_table => column_def(left, c)?,
+                        Some((t, _)) => return Err(DbError::InvalidData(format!("Table {} is not part of the join", t))),
+                        None => column_def(left, col)?,
+                    };
+                }
+                if let Some(cond) = condition {
+                    validate_condition(&[left, right], cond)?;
+                }
+                lines.push(format!("Tables: {}, {}", left.name, right.name));
+                lines.push(format!("Columns: {}", columns.join(", ")));
+                lines.extend(condition_line(condition));
+            }
+            Query::Insert { table, columns, rows, on_conflict } => {
+                let table = table_def(table)?;
+                for values in rows {
+                    let row = schema.complete_row(&table.name, columns, values.clone())?;
+                    schema.validate_row(&table.name, &row)?;
+                }
+                if let Some(on_conflict) = on_conflict {
+                    column_def(table, &on_conflict.column)?;
+                    if let crate::query::ConflictAction::DoUpdate(assignments) = &on_conflict.action {
+                        for (column, _) in assignments {
+                            column_def(table, column)?;
+                        }
+                    }
+                }
+                lines.push(format!("Table: {}", table.name));
+                lines.push(format!("Columns: {}", table.columns.iter().map(|c| c.name.clone()).collect::<Vec<_>>().join(", ")));
+            }
+            Query::Delete { table, condition } => {
+                let table = table_def(table)?;
+                if let Some(cond) = condition {
+                    validate_condition(&[table], cond)?;
+                }
+                lines.push(format!("Table: {}", table.name));
+                lines.extend(condition_line(condition));
+            }
+            Query::AlterTable { table, action } => {
+                let table = table_def(table)?;
+                match action {
+                    AlterAction::DropColumn(column) | AlterAction::RenameColumn { from: column, .. } => {
+                        column_def(table, column)?;
+                    }
+                    AlterAction::RenameTable(new_name) if schema.get_table(new_name).is_some_and(|t| t.name != table.name) => {
+                        return Err(DbError::InvalidData(format!("Table {} already exists", new_name)));
+                    }
+                    AlterAction::RenameTable(_) => {}
+                }
+                lines.push(format!("Table: {}", table.name));
+            }
+            Query::CreateIndex { table, index } => {
+                let table = table_def(table)?;
+                for column in &index.columns {
+                    column_def(table, column)?;
+                }
+                lines.push(format!("Table: {}", table.name));
+                lines.push(format!("Columns: {}", index.columns.join(", ")));
+            }
+            Query::DropIndex { table, name } => {
+                let table = table_def(table)?;
+                let index = storage_guard.list_indexes(&table.name)?.into_iter().find(|i| i.name.eq_ignore_ascii_case(name));
+                let index = index.ok_or_else(|| DbError::IndexError(format!("Index {} not found on {}", name, table.name)))?;
+                lines.push(format!("Table: {}", table.name));
+                lines.push(format!("Index: {}", index.name));
+            }
+            Query::CreateTable { table, .. } => {
+                if schema.get_table(table).is_some() {
+                    return Err(DbError::InvalidData(format!("Table {} already exists", table)));
+                }
+                lines.push(format!("Table: {}", table));
+            }
+            Query::DropTable { table }
+            | Query::Vacuum { table }
+            | Query::Verify { table }
+            | Query::CopyFrom { table, .. }
+            | Query::ExportTable { table, .. } => {
+                lines.push(format!("Table: {}", table_def(table)?.name));
+            }
+            // Transaction control and user management have nothing to resolve against the schema
+            _ => {}
         }
+        Ok(lines)
     }
 
     fn execute_select(
@@ -93,9 +1155,10 @@ impl QueryEngine {
         table: &str,
         columns: &[String],
         condition: Option<Condition>,
+        order_by: &[OrderKey],
     ) -> Result<Vec<Vec<Value>>, DbError> {
         let table_def = {
-            let storage_guard = self.storage.lock().unwrap();
+            let storage_guard = self.storage.read().unwrap();
             storage_guard
                 .schema()
                 .get_table(table)
@@ -103,17 +1166,28 @@ impl QueryEngine {
                 .clone()
         };
 
-        for col in columns {
-            if !table_def.columns.iter().any(|c| c.name == *col) {
-                return Err(DbError::InvalidData(format!("Column {}.{} not found", table, col)));
+        // Sort keys are projected after the selected columns and cut off once rows are sorted
+        let projections = columns
+            .iter()
+            .map(|col| parse_projection(col, &table_def))
+            .chain(order_by.iter().map(|key| Ok(crate::query::Expr::Column(key.column.clone()))))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut required_columns = Vec::new();
+        for expr in &projections {
+            for col in crate::query::collect_expr_columns(expr) {
+                if table_def.get_column(&col).is_none() {
+                    return Err(DbError::InvalidData(format!("Column {}.{} not found", table, col)));
+                }
+                if !required_columns.contains(&col) {
+                    required_columns.push(col);
+                }
             }
         }
-
-        let mut required_columns = columns.to_vec();
         if let Some(ref cond) = condition {
             let condition_columns = crate::query::collect_condition_columns(cond);
             for col in condition_columns {
-                if !table_def.columns.iter().any(|c| c.name == col) {
+                if table_def.get_column(&col).is_none() {
                     return Err(DbError::InvalidData(format!("Column {}.{} not found in condition", table, col)));
                 }
                 if !required_columns.contains(&col) {
@@ -122,37 +1196,157 @@ impl QueryEngine {
             }
         }
 
-        let mut storage_guard = self.storage.lock().unwrap();
-        let mut column_values = HashMap::new();
-        let mut min_row_count = usize::MAX;
-        for col in &required_columns {
-            let values = storage_guard.read_column(table, col, condition.as_ref())?;
-            min_row_count = min_row_count.min(values.len());
-            column_values.insert(col.clone(), values);
+        // Constant projections still yield one row per table row
+        if required_columns.is_empty() {
+            if let Some(first) = table_def.columns.first() {
+                required_columns.push(first.name.clone());
+            }
         }
 
-        // Parallelize row filtering and collection
-        let result: Result<Vec<Vec<Value>>, DbError> = (0..min_row_count)
-            .into_par_iter()
-            .filter_map(|i| {
-                if let Some(ref cond) = condition {
-                    match crate::query::evaluator::evaluate_condition_row(cond, &column_values, i) {
-                        Ok(true) => Some(Ok(columns
-                            .iter()
-                            .map(|col| column_values.get(col).unwrap()[i].clone())
-                            .collect())),
-                        Ok(false) => None,
-                        Err(e) => Some(Err(e)),
-                    }
-                } else {
-                    Some(Ok(columns
-                        .iter()
-                        .map(|col| column_values.get(col).unwrap()[i].clone())
-                        .collect()))
+        // Storage returns only rows passing the pushed-down part; the full condition is still checked below
+        let pushdown = condition.as_ref().and_then(crate::query::pushdown_predicate);
+        let storage_guard = self.storage.read().unwrap();
+        let tx = self.transaction.as_ref();
+        let overlay = tx.is_some_and(|tx| tx.writes_to(table));
+        // Uncommitted writes are not in the indexes, so they rule out an index lookup
+        let lookup = if overlay { None } else { index_lookup(&storage_guard, table, condition.as_ref()) };
+        if let Some(cond) = &condition {
+            let path = match &lookup {
+                Some((kind, column, _)) => format!("{} index lookup on {}", kind.to_lowercase(), column),
+                None => "parallel column scan".to_string(),
+            };
+            crate::logging::log_query(&format!("SELECT FROM {} WHERE {} via {}", table, cond, path), None);
+        }
+        type Scan<'a> = Box<dyn Iterator<Item = Result<Value, DbError>> + 'a>;
+        // Held until the scans finish, so inserts into the table wait for them
+        let table_guard;
+        let mut scans: Vec<(String, Scan)> = if let Some((_, _, positions)) = lookup {
+            storage_guard
+                .read_rows_at(table, &required_columns, &positions)?
+                .into_iter()
+                .map(|(col, values)| (col, Box::new(values.into_iter().map(Ok)) as Scan))
+                .collect()
+        } else if pushdown.is_some() || overlay {
+            read_visible_columns(&storage_guard, tx, table, &required_columns, pushdown)?
+                .into_iter()
+                .map(|(col, values)| (col, Box::new(values.into_iter().map(Ok)) as Scan))
+                .collect()
+        } else {
+            table_guard = storage_guard.read_table(table)?;
+            required_columns
+                .iter()
+                .map(|col| (col.clone(), Box::new(table_guard.read_column_iter(col)) as Scan))
+                .collect()
+        };
+
+        // Rows are decoded, filtered and projected a batch at a time, in parallel within each
+        // batch that reaches the threshold
+        let parallel_threshold = self.parallel_threshold;
+        let max_rows = self.max_result_rows;
+        let deadline = self.deadline;
+        let cancel = Arc::clone(&self.cancel);
+        let mut result = Vec::new();
+        loop {
+            check_running(deadline, &cancel)?;
+            let mut column_values = HashMap::with_capacity(scans.len());
+            let mut batch_len = usize::MAX;
+            for (col, scan) in scans.iter_mut() {
+                let values = scan.by_ref().take(SCAN_BATCH_ROWS).collect::<Result<Vec<_>, _>>()?;
+                batch_len = batch_len.min(values.len());
+                column_values.insert(col.clone(), values);
+            }
+            if batch_len == usize::MAX || batch_len == 0 {
+                break;
+            }
+
+            let project_row = |i: usize| -> Result<Vec<Value>, DbError> {
+                projections
+                    .iter()
+                    .map(|expr| crate::query::evaluator::evaluate_expr(expr, &column_values, i))
+                    .collect()
+            };
+            let keep_row = |i: usize| match &condition {
+                Some(cond) => match crate::query::evaluator::evaluate_condition_row(cond, &column_values, i) {
+                    Ok(true) => Some(project_row(i)),
+                    Ok(false) => None,
+                    Err(e) => Some(Err(e)),
+                },
+                None => Some(project_row(i)),
+            };
+            let batch: Vec<Vec<Value>> = if batch_len < parallel_threshold {
+                (0..batch_len).filter_map(keep_row).collect::<Result<_, _>>()?
+            } else {
+                // Gathered in row order before any error is looked at: collecting straight into
+                // a Result would return whichever failing row a thread reached first
+                let rows: Vec<Result<Vec<Value>, DbError>> = (0..batch_len).into_par_iter().filter_map(keep_row).collect();
+                rows.into_iter().collect::<Result<_, _>>()?
+            };
+            result.extend(batch);
+            check_result_rows(result.len(), max_rows)?;
+            if let Some(limit) = self.row_limit.filter(|&limit| result.len() >= limit) {
+                result.truncate(limit);
+                break;
+            }
+            if batch_len < SCAN_BATCH_ROWS {
+                break;
+            }
+        }
+        sort_rows(&mut result, order_by);
+        Ok(result)
+    }
+
+    // Answers a SELECT on an information_schema table from the schema; catalog tables are
+    // small, so rows are filtered serially
+    fn execute_catalog_select(
+        &self,
+        table_def: &Table,
+        columns: &[String],
+        condition: Option<Condition>,
+        order_by: &[OrderKey],
+    ) -> Result<Vec<Vec<Value>>, DbError> {
+        let columns = if columns.is_empty() {
+            table_def.columns.iter().map(|c| c.name.clone()).collect()
+        } else {
+            columns.to_vec()
+        };
+        let projections = columns
+            .iter()
+            .map(|col| parse_projection(col, table_def))
+            .chain(order_by.iter().map(|key| Ok(crate::query::Expr::Column(key.column.clone()))))
+            .collect::<Result<Vec<_>, _>>()?;
+        for expr in &projections {
+            for col in crate::query::collect_expr_columns(expr) {
+                if table_def.get_column(&col).is_none() {
+                    return Err(DbError::InvalidData(format!("Column {}.{} not found", table_def.name, col)));
                 }
-            })
-            .collect();
-        result
+            }
+        }
+        if let Some(ref cond) = condition {
+            for col in crate::query::collect_condition_columns(cond) {
+                if table_def.get_column(&col).is_none() {
+                    return Err(DbError::InvalidData(format!("Column {}.{} not found in condition", table_def.name, col)));
+                }
+            }
+        }
+
+        let column_values = catalog::read_catalog(&self.storage.read().unwrap(), table_def);
+        let row_count = column_values.values().next().map_or(0, Vec::len);
+        let mut result = Vec::new();
+        for i in 0..row_count {
+            if let Some(cond) = &condition {
+                if !crate::query::evaluator::evaluate_condition_row(cond, &column_values, i)? {
+                    continue;
+                }
+            }
+            result.push(
+                projections
+                    .iter()
+                    .map(|expr| crate::query::evaluator::evaluate_expr(expr, &column_values, i))
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+        }
+        sort_rows(&mut result, order_by);
+        Ok(result)
     }
 
     fn execute_aggregate(
@@ -160,9 +1354,11 @@ impl QueryEngine {
         table: &str,
         aggregations: &[Aggregation],
         condition: Option<Condition>,
+        group_by: &[String],
     ) -> Result<Vec<Vec<Value>>, DbError> {
+        check_group_keys(aggregations, group_by)?;
         let table_def = {
-            let storage_guard = self.storage.lock().unwrap();
+            let storage_guard = self.storage.read().unwrap();
             storage_guard
                 .schema()
                 .get_table(table)
@@ -170,72 +1366,162 @@ impl QueryEngine {
                 .clone()
         };
 
-        let mut storage_guard = self.storage.lock().unwrap();
-        let mut results = Vec::new();
-        for agg in aggregations {
-            let column = match agg {
-                Aggregation::Count => "ID".to_string(),
-                Aggregation::Sum(col) | Aggregation::Avg(col) | Aggregation::Min(col) | Aggregation::Max(col) => col.clone(),
+        let new_accumulators = || {
+            aggregations
+                .iter()
+                .map(|agg| {
+                    let column = match agg.column() {
+                        Some(column) => Some(
+                            table_def
+                                .get_column(column)
+                                .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table, column)))?,
+                        ),
+                        None => None,
+                    };
+                    Accumulator::new(agg, column)
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let mut accumulators = new_accumulators()?;
+        // Aggregates over the same column share its scan: each row's value is read once and fed
+        // to all of them. Results stay in the order the aggregates were written.
+        let mut by_column: Vec<(Option<&str>, Vec<usize>)> = Vec::new();
+        for (i, agg) in aggregations.iter().enumerate() {
+            match by_column.iter_mut().find(|(col, _)| *col == agg.column()) {
+                Some((_, indices)) => indices.push(i),
+                None => by_column.push((agg.column(), vec![i])),
+            }
+        }
+        let mut required_columns: Vec<String> =
+            by_column.iter().filter_map(|(col, _)| col.map(str::to_string)).collect();
+        for col in group_by {
+            if table_def.get_column(col).is_none() {
+                return Err(DbError::InvalidData(format!("Column {}.{} not found in GROUP BY", table, col)));
+            }
+            if !required_columns.contains(col) {
+                required_columns.push(col.clone());
+            }
+        }
+        if let Some(ref cond) = condition {
+            for col in crate::query::collect_condition_columns(cond) {
+                if table_def.get_column(&col).is_none() {
+                    return Err(DbError::InvalidData(format!("Column {}.{} not found in condition", table, col)));
+                }
+                if !required_columns.contains(&col) {
+                    required_columns.push(col);
+                }
+            }
+        }
+
+        let storage_guard = self.storage.read().unwrap();
+        let tx = self.transaction.as_ref();
+        let overlay = tx.is_some_and(|tx| tx.writes_to(table));
+        // Uncommitted writes are not in the indexes, so they rule out an index lookup
+        let lookup = if overlay { None } else { index_lookup(&storage_guard, table, condition.as_ref()) };
+        if let Some(cond) = &condition {
+            let path = match &lookup {
+                Some((kind, column, _)) => format!("{} index lookup on {}", kind.to_lowercase(), column),
+                None => "column scan".to_string(),
             };
-            let col_def = table_def
-                .get_column(&column)
-                .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table, column)))?;
-            let values = storage_guard.read_column(table, &column, condition.as_ref())?;
-
-            let result = match agg {
-                Aggregation::Count => Value::Int32(values.len() as i32),
-                Aggregation::Sum(_) => {
-                    if col_def.data_type != DataType::Float32 && col_def.data_type != DataType::Int32 {
-                        return Err(DbError::InvalidData(format!(
-                            "SUM not supported for type {:?}", col_def.data_type
-                        )));
+            let labels = aggregations.iter().map(|a| a.label()).collect::<Vec<_>>();
+            crate::logging::log_query(&format!("SELECT {} FROM {} WHERE {} via {}", labels.join(", "), table, cond, path), None);
+        }
+
+        // COUNT(*) alone needs only how many rows match, not any values: the table's row count
+        // without a condition, or the number of rows an exact index lookup found
+        if group_by.is_empty() && by_column.iter().all(|(col, _)| col.is_none()) {
+            let rows = match (&condition, &lookup, tx) {
+                (None, _, Some(tx)) if overlay => Some(tx.count_matching(&storage_guard, table, None)?),
+                (None, _, _) => Some(storage_guard.table_size(table).0 as usize),
+                (Some(cond), Some((_, _, positions)), _) if index_counts(cond) => Some(positions.len()),
+                _ => None,
+            };
+            if let Some(rows) = rows {
+                for acc in accumulators.iter_mut() {
+                    acc.count_rows(rows);
+                }
+                let results = accumulators.into_iter().map(Accumulator::finish).collect::<Result<Vec<_>, _>>()?;
+                return Ok(vec![results]);
+            }
+        }
+
+        // Columns are streamed a batch at a time into running totals, so an aggregate over a
+        // column larger than memory never holds more than a batch of it. An index narrows the
+        // rows read to the ones it found; the full condition is still checked on them.
+        let pushdown = condition.as_ref().and_then(crate::query::pushdown_predicate);
+        type Scan<'a> = Box<dyn Iterator<Item = Result<Value, DbError>> + 'a>;
+        // Held until the scans finish, so inserts into the table wait for them
+        let table_guard;
+        let mut scans: Vec<(String, Scan)> = if let Some((_, _, positions)) = lookup {
+            storage_guard
+                .read_rows_at(table, &required_columns, &positions)?
+                .into_iter()
+                .map(|(col, values)| (col, Box::new(values.into_iter().map(Ok)) as Scan))
+                .collect()
+        } else if pushdown.is_some() || overlay {
+            read_visible_columns(&storage_guard, tx, table, &required_columns, pushdown)?
+                .into_iter()
+                .map(|(col, values)| (col, Box::new(values.into_iter().map(Ok)) as Scan))
+                .collect()
+        } else {
+            table_guard = storage_guard.read_table(table)?;
+            required_columns
+                .iter()
+                .map(|col| (col.clone(), Box::new(table_guard.read_column_iter(col)) as Scan))
+                .collect()
+        };
+        // Each group's key and running totals, in the order groups first appear. Without GROUP BY
+        // there is a single group, which yields a row even when no row matches.
+        let mut groups: Vec<(Vec<Value>, Vec<Accumulator>)> = Vec::new();
+        let mut group_positions: HashMap<Vec<Value>, usize> = HashMap::new();
+        if group_by.is_empty() {
+            groups.push((Vec::new(), accumulators));
+            group_positions.insert(Vec::new(), 0);
+        }
+        loop {
+            check_running(self.deadline, &self.cancel)?;
+            let mut column_values = HashMap::with_capacity(scans.len());
+            let mut batch_len = usize::MAX;
+            for (col, scan) in scans.iter_mut() {
+                let values = scan.by_ref().take(SCAN_BATCH_ROWS).collect::<Result<Vec<_>, _>>()?;
+                batch_len = batch_len.min(values.len());
+                column_values.insert(col.clone(), values);
+            }
+            if batch_len == usize::MAX || batch_len == 0 {
+                break;
+            }
+            for i in 0..batch_len {
+                if let Some(ref cond) = condition {
+                    if !crate::query::evaluator::evaluate_condition_row(cond, &column_values, i)? {
+                        continue;
                     }
-                    values.iter().fold(Value::Float32(ordered_float::OrderedFloat(0.0)), |acc, v| {
-                        match (acc.clone(), v) {
-                            (Value::Float32(a), Value::Float32(b)) => Value::Float32(a + b),
-                            (Value::Float32(a), Value::Int32(b)) => {
-                                Value::Float32(a + ordered_float::OrderedFloat(*b as f32))
-                            }
-                            _ => acc,
-                        }
-                    })
                 }
-                Aggregation::Avg(_) => {
-                    if col_def.data_type != DataType::Float32 && col_def.data_type != DataType::Int32 {
-                        return Err(DbError::InvalidData(format!(
-                            "AVG not supported for type {:?}", col_def.data_type
-                        )));
+                let key: Vec<Value> = group_by.iter().map(|col| column_values[col][i].clone()).collect();
+                let position = match group_positions.get(&key) {
+                    Some(&position) => position,
+                    None => {
+                        check_result_rows(groups.len() + 1, self.max_result_rows)?;
+                        group_positions.insert(key.clone(), groups.len());
+                        groups.push((key, new_accumulators()?));
+                        groups.len() - 1
                     }
-                    let sum = values.iter().fold(Value::Float32(ordered_float::OrderedFloat(0.0)), |acc, v| {
-                        match (acc.clone(), v) {
-                            (Value::Float32(a), Value::Float32(b)) => Value::Float32(a + b),
-                            (Value::Float32(a), Value::Int32(b)) => {
-                                Value::Float32(a + ordered_float::OrderedFloat(*b as f32))
-                            }
-                            _ => acc,
-                        }
-                    });
-                    match sum {
-                        Value::Float32(s) if values.len() > 0 => {
-                            Value::Float32(ordered_float::OrderedFloat(s.0 / values.len() as f32))
-                        }
-                        _ => Value::Float32(ordered_float::OrderedFloat(0.0)),
+                };
+                let accumulators = &mut groups[position].1;
+                for (col, indices) in &by_column {
+                    let value = col.map(|col| &column_values[col][i]);
+                    for &k in indices {
+                        accumulators[k].update(value)?;
                     }
                 }
-                Aggregation::Min(_) => values
-                    .iter()
-                    .min_by(|a, b| a.cmp(b))
-                    .cloned()
-                    .unwrap_or(Value::Float32(ordered_float::OrderedFloat(0.0))),
-                Aggregation::Max(_) => values
-                    .iter()
-                    .max_by(|a, b| a.cmp(b))
-                    .cloned()
-                    .unwrap_or(Value::Float32(ordered_float::OrderedFloat(0.0))),
-            };
-            results.push(result);
+            }
+            if batch_len < SCAN_BATCH_ROWS {
+                break;
+            }
         }
-        Ok(vec![results])
+        groups
+            .into_iter()
+            .map(|(_, accumulators)| accumulators.into_iter().map(Accumulator::finish).collect::<Result<Vec<_>, _>>())
+            .collect()
     }
 
     fn execute_join(
@@ -247,9 +1533,10 @@ impl QueryEngine {
         columns: &[String],
         condition: Option<Condition>,
     ) -> Result<Vec<Vec<Value>>, DbError> {
-        let mut storage_guard = self.storage.lock().unwrap();
-        let left_values = storage_guard.read_column(left_table, left_column, condition.as_ref())?;
-        let right_values = storage_guard.read_column(right_table, right_column, condition.as_ref())?;
+        let storage_guard = self.storage.read().unwrap();
+        let tx = self.transaction.as_ref();
+        let left_values = read_visible_column(&storage_guard, tx, left_table, left_column, condition.as_ref())?;
+        let right_values = read_visible_column(&storage_guard, tx, right_table, right_column, condition.as_ref())?;
 
         let mut column_values = HashMap::new();
         let mut min_row_count_left = usize::MAX;
@@ -261,7 +1548,7 @@ impl QueryEngine {
             } else {
                 (left_table, col.as_str())
             };
-            let values = storage_guard.read_column(table, col_name, condition.as_ref())?;
+            let values = read_visible_column(&storage_guard, tx, table, col_name, condition.as_ref())?;
             if table == right_table {
                 min_row_count_right = min_row_count_right.min(values.len());
             } else {
@@ -270,33 +1557,491 @@ impl QueryEngine {
             column_values.insert(col.clone(), values);
         }
 
-        // Parallelize the join operation
-        let result: Result<Vec<Vec<Value>>, DbError> = (0..min_row_count_left)
-            .into_par_iter()
-            .flat_map(|i| {
-                let left_val = &left_values[i];
-                (0..min_row_count_right)
-                    .filter_map(|j| {
-                        if left_val == &right_values[j] {
-                            Some(columns.iter().map(|col| {
-                                let values = column_values.get(col).unwrap();
-                                let index = if col.starts_with(right_table) { j } else { i };
-                                if index < values.len() {
-                                    Ok(values[index].clone())
-                                } else {
-                                    Err(DbError::InvalidData(format!(
-                                        "Index {} out of bounds for column {} (len: {})",
-                                        index, col, values.len()
-                                    )))
-                                }
-                            }).collect::<Result<Vec<Value>, DbError>>())
-                        } else {
-                            None
+        // Joined a batch of left rows at a time, in parallel within the batch, so the row cap is
+        // checked before the next batch is produced
+        let deadline = self.deadline;
+        let cancel = Arc::clone(&self.cancel);
+        let mut result = Vec::new();
+        for start in (0..min_row_count_left).step_by(SCAN_BATCH_ROWS) {
+            let end = (start + SCAN_BATCH_ROWS).min(min_row_count_left);
+            let batch: Result<Vec<Vec<Value>>, DbError> = (start..end)
+                .into_par_iter()
+                .flat_map(|i| {
+                    // Each left row scans every right row, so the deadline is checked per left row
+                    if let Err(e) = check_running(deadline, &cancel) {
+                        return vec![Err(e)];
+                    }
+                    let left_val = &left_values[i];
+                    (0..min_row_count_right)
+                        .filter_map(|j| {
+                            if left_val == &right_values[j] {
+                                Some(columns.iter().map(|col| {
+                                    let values = column_values.get(col).unwrap();
+                                    let index = if col.starts_with(right_table) { j } else { i };
+                                    if index < values.len() {
+                                        Ok(values[index].clone())
+                                    } else {
+                                        Err(DbError::InvalidData(format!(
+                                            "Index {} out of bounds for column {} (len: {})",
+                                            index, col, values.len()
+                                        )))
+                                    }
+                                }).collect::<Result<Vec<Value>, DbError>>())
+                            } else {
+                                None
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            result.extend(batch?);
+            check_result_rows(result.len(), self.max_result_rows)?;
+        }
+        Ok(result)
+    }
+}
+
+// A positive number from environment variable `name`
+// Whether `query` begins a transaction when autocommit is off: the reads and writes that can run
+// inside one. Schema changes cannot, so they still run on their own.
+fn opens_transaction(query: &Query) -> bool {
+    matches!(
+        query,
+        Query::Select { table: Some(_), .. }
+            | Query::SelectAggregate { .. }
+            | Query::Join { .. }
+            | Query::Insert { .. }
+            | Query::Delete { .. }
+    )
+}
+
+fn unknown_setting(name: &str) -> DbError {
+    DbError::ConfigurationError(format!("Unknown setting {}: expected one of {}", name, SETTINGS.join(", ")))
+}
+
+// Tables whose definition `query` changes, under every name it may be cached by
+fn ddl_tables(query: &Query) -> Vec<String> {
+    match query {
+        Query::AlterTable { table, action: AlterAction::RenameTable(new_name) } => vec![table.clone(), new_name.clone()],
+        Query::CreateTable { table, .. }
+        | Query::DropTable { table }
+        | Query::AlterTable { table, .. }
+        | Query::CreateIndex { table, .. }
+        | Query::DropIndex { table, .. } => vec![table.clone()],
+        _ => Vec::new(),
+    }
+}
+
+fn env_limit(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|value| value.trim().parse::<u64>().ok()).filter(|&value| value > 0)
+}
+
+// Running state of one aggregate, fed a row at a time
+struct Accumulator<'a> {
+    agg: &'a Aggregation,
+    data_type: DataType,
+    state: AggregateState,
+}
+
+enum AggregateState {
+    Count(usize),
+    IntSum(i64),
+    FloatSum(ordered_float::OrderedFloat<f32>),
+    // Summed as f64 so that neither wide integers nor many rows overflow
+    Avg { sum: f64, count: usize },
+    Extreme(Option<Value>),
+    Concat(Option<String>),
+    // The group's GROUP BY value, the same in every row of the group
+    Key(Option<Value>),
+}
+
+impl<'a> Accumulator<'a> {
+    // Fails up front when the aggregate does not apply to the column's type
+    fn new(agg: &'a Aggregation, column: Option<&crate::schema::Column>) -> Result<Self, DbError> {
+        let data_type = column.map_or(DataType::Int32, |c| c.data_type.clone());
+        let unsupported = || DbError::InvalidData(format!("{} not supported for type {:?}", agg.label(), data_type));
+        let state = match agg {
+            Aggregation::Count => AggregateState::Count(0),
+            // Integers add up exactly as BIGINT; only a total beyond its range fails
+            Aggregation::Sum(_) => match agg.result_type(&data_type) {
+                Some(DataType::Int64) => AggregateState::IntSum(0),
+                Some(DataType::Float32) => AggregateState::FloatSum(ordered_float::OrderedFloat(0.0)),
+                _ => return Err(unsupported()),
+            },
+            Aggregation::Avg(_) => match agg.result_type(&data_type) {
+                Some(DataType::Float32) => AggregateState::Avg { sum: 0.0, count: 0 },
+                _ => return Err(unsupported()),
+            },
+            Aggregation::Min(_) | Aggregation::Max(_) => AggregateState::Extreme(None),
+            Aggregation::GroupConcat(..) => {
+                if data_type != DataType::String {
+                    return Err(DbError::TypeMismatch(format!(
+                        "{} needs a TEXT column, not {}",
+                        agg.label(),
+                        data_type.sql_name()
+                    )));
+                }
+                AggregateState::Concat(None)
+            }
+            Aggregation::GroupKey(_) => AggregateState::Key(None),
+        };
+        Ok(Accumulator { agg, data_type, state })
+    }
+
+    // Adds `rows` rows at once; only COUNT(*) can be fed without their values
+    fn count_rows(&mut self, rows: usize) {
+        if let AggregateState::Count(count) = &mut self.state {
+            *count += rows;
+        }
+    }
+
+    // Adds one matching row: `value` is the row's value of the aggregated column, None for COUNT(*)
+    fn update(&mut self, value: Option<&Value>) -> Result<(), DbError> {
+        let value = match (&mut self.state, value) {
+            (AggregateState::Count(count), _) => {
+                *count += 1;
+                return Ok(());
+            }
+            (AggregateState::Key(key), Some(value)) => {
+                key.get_or_insert_with(|| value.clone());
+                return Ok(());
+            }
+            // Column aggregates ignore NULLs and NaNs
+            (_, Some(value)) if !value.is_null() && !value.is_nan() => value,
+            _ => return Ok(()),
+        };
+        match &mut self.state {
+            AggregateState::Count(_) | AggregateState::Key(_) => {}
+            AggregateState::IntSum(total) => {
+                let n = match value {
+                    Value::Int32(i) => *i as i64,
+                    Value::Int64(i) => *i,
+                    _ => return Ok(()),
+                };
+                *total = total.checked_add(n).ok_or_else(|| {
+                    DbError::InvalidData(format!("{} overflows the BIGINT range", self.agg.label()))
+                })?;
+            }
+            AggregateState::FloatSum(total) => {
+                if let Value::Float32(f) = value {
+                    *total += *f;
+                }
+            }
+            AggregateState::Avg { sum, count } => {
+                *sum += value.as_f64().unwrap_or(0.0);
+                *count += 1;
+            }
+            AggregateState::Extreme(extreme) => {
+                // Value::cmp treats values of different types as equal, so one stray value
+                // could otherwise decide the result
+                if value.data_type() != self.data_type {
+                    return Err(DbError::TypeMismatch(format!(
+                        "{} found {} in a {} column",
+                        self.agg.label(),
+                        value.to_sql_literal(),
+                        self.data_type.sql_name()
+                    )));
+                }
+                let replace = match extreme {
+                    None => true,
+                    Some(current) if matches!(self.agg, Aggregation::Min(_)) => value < current,
+                    Some(current) => value >= current,
+                };
+                if replace {
+                    *extreme = Some(value.clone());
+                }
+            }
+            AggregateState::Concat(joined) => {
+                if let Value::String(s) = value {
+                    match joined {
+                        Some(joined) => {
+                            if let Aggregation::GroupConcat(_, separator) = self.agg {
+                                joined.push_str(separator.as_deref().unwrap_or(","));
+                            }
+                            joined.push_str(s);
                         }
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .collect();
-        result
+                        None => *joined = Some(s.clone()),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, DbError> {
+        Ok(match self.state {
+            AggregateState::Count(count) => Value::Int32(count as i32),
+            AggregateState::IntSum(total) => Value::Int64(total),
+            AggregateState::FloatSum(total) => Value::Float32(total),
+            AggregateState::Avg { sum, count } => {
+                let avg = if count == 0 { 0.0 } else { sum / count as f64 };
+                Value::Float32(ordered_float::OrderedFloat(avg as f32))
+            }
+            // No non-NULL input gives NULL rather than a zero of some other type
+            AggregateState::Extreme(extreme) => extreme.unwrap_or(Value::Null),
+            // Values are joined in row order; no rows gives NULL
+            AggregateState::Concat(joined) => joined.map_or(Value::Null, Value::String),
+            AggregateState::Key(key) => key.unwrap_or(Value::Null),
+        })
+    }
+}
+
+// A WHERE after its EXISTS tests have been answered
+enum Folded {
+    Always(bool),
+    Where(Condition),
+}
+
+// Fails once the running statement has been cancelled or has passed its deadline
+fn check_running(deadline: Option<Instant>, cancel: &AtomicBool) -> Result<(), DbError> {
+    if cancel.load(Ordering::SeqCst) {
+        return Err(DbError::Cancelled("Statement cancelled by request".to_string()));
+    }
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(DbError::TimeoutError(
+            "Statement cancelled after running past statement_timeout".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_result_rows(rows: usize, max_rows: Option<usize>) -> Result<(), DbError> {
+    match max_rows {
+        Some(max_rows) if rows > max_rows => Err(DbError::ResourceExhausted(format!(
+            "Query returns more than max_result_rows ({}) rows; add a WHERE clause or raise the limit",
+            max_rows
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Sorts rows that end with one value per key of `order_by`, then drops those values. The sort
+// is stable, so rows equal on every key keep the order they were read in.
+fn sort_rows(rows: &mut [Vec<Value>], order_by: &[OrderKey]) {
+    if order_by.is_empty() {
+        return;
+    }
+    let key_start = rows.first().map_or(0, |row| row.len() - order_by.len());
+    rows.sort_by(|a, b| {
+        order_by
+            .iter()
+            .zip(a[key_start..].iter().zip(&b[key_start..]))
+            .map(|(key, (a, b))| key.compare(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    for row in rows.iter_mut() {
+        row.truncate(key_start);
+    }
+}
+
+// Puts a sort step for `order_by` between a SELECT's projection and the steps beneath it
+fn with_sort(plan: Vec<String>, order_by: &[OrderKey]) -> Vec<String> {
+    if order_by.is_empty() {
+        return plan;
+    }
+    let mut plan = plan.into_iter();
+    let mut sorted: Vec<String> = plan.next().into_iter().collect();
+    sorted.push(format!(
+        "  -> Sort: {}",
+        order_by.iter().map(|key| key.to_string()).collect::<Vec<_>>().join(", ")
+    ));
+    sorted.extend(plan.map(|line| format!("  {}", line)));
+    sorted
+}
+
+// Parses a projection of a SELECT on `table`, naming its columns as the table declares them.
+fn parse_projection(expr: &str, table: &Table) -> Result<crate::query::Expr, DbError> {
+    let mut expr = crate::query::parser::parse_expr(expr)?;
+    crate::query::resolve_expr(&mut expr, &[table]);
+    crate::query::widen_expr(&mut expr, &[table])?;
+    Ok(expr)
+}
+
+// Evaluates the projections of a SELECT without FROM as one row.
+fn evaluate_without_table(columns: &[String]) -> Result<Vec<Value>, DbError> {
+    let no_columns = HashMap::new();
+    columns
+        .iter()
+        .map(|column| {
+            let mut expr = crate::query::parser::parse_expr(column)?;
+            reject_column_refs(&expr)?;
+            crate::query::widen_expr(&mut expr, &[])?;
+            crate::query::evaluator::evaluate_expr(&expr, &no_columns, 0)
+        })
+        .collect()
+}
+
+// A column selected next to aggregates has one value per group only if the query groups by it
+fn check_group_keys(aggregations: &[Aggregation], group_by: &[String]) -> Result<(), DbError> {
+    for agg in aggregations {
+        if let Aggregation::GroupKey(column) = agg {
+            if !group_by.contains(column) {
+                return Err(DbError::QueryError(format!(
+                    "{} must appear in GROUP BY or be used in an aggregate",
+                    column
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn reject_column_refs(expr: &crate::query::Expr) -> Result<(), DbError> {
+    match crate::query::collect_expr_columns(expr).into_iter().next() {
+        Some(column) => Err(DbError::QueryError(format!("Column {} cannot be used without FROM", column))),
+        None => Ok(()),
+    }
+}
+
+// Every column a condition tests must exist in one of `tables` (`Table.column` picks one), and
+// a comparison's literal must have that column's type, or be a number for a numeric column,
+// since other mismatched types never match.
+fn validate_condition(tables: &[&Table], condition: &Condition) -> Result<(), DbError> {
+    let (column, literal) = match condition {
+        Condition::Equal(col, v)
+        | Condition::GreaterThan(col, v)
+        | Condition::LessThan(col, v)
+        | Condition::LessThanOrEqual(col, v)
+        | Condition::GreaterThanOrEqual(col, v) => (col, Some(v)),
+        Condition::IsNull { column, .. } | Condition::Match { column, .. } => (column, None),
+        // Checked against its own table by `validate`
+        Condition::Exists { .. } => return Ok(()),
+        Condition::And(left, right) | Condition::Or(left, right) => {
+            validate_condition(tables, left)?;
+            return validate_condition(tables, right);
+        }
+    };
+    let col_def = match column.split_once('.') {
+        Some((table, name)) => tables.iter().filter(|t| t.name == table).find_map(|t| t.get_column(name)),
+        None => tables.iter().find_map(|t| t.get_column(column)),
+    }
+    .ok_or_else(|| DbError::InvalidData(format!("Column {} not found in condition", column)))?;
+    if matches!(condition, Condition::Match { .. }) && col_def.data_type != DataType::String {
+        return Err(DbError::ValidationError(format!(
+            "MATCH needs a TEXT column, but {} is {:?}",
+            column, col_def.data_type
+        )));
+    }
+    let numeric = |data_type: &DataType| matches!(data_type, DataType::Int32 | DataType::Int64 | DataType::Float32);
+    match literal {
+        Some(value) if numeric(&col_def.data_type) && numeric(&value.data_type()) => Ok(()),
+        Some(value) if !value.clone().widen_to(&col_def.data_type).matches_type(&col_def.data_type) => Err(DbError::TypeMismatch(format!(
+            "Cannot compare {} of type {:?} with {}",
+            column,
+            col_def.data_type,
+            value.to_sql_literal()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Reads columns as the current statement sees them: committed data, overlaid with the open
+// transaction's own writes when it has touched the table. A single-column `predicate` is
+// pushed into the scan of committed data; the overlay is returned unfiltered.
+// Row numbers that may answer a WHERE, taken from an index instead of a scan: a single equality
+// on a UNIQUE or PRIMARY KEY column uses the constraint's value map, a single MATCH a FULLTEXT
+// index, and AND-ed equalities covering the leading columns of a composite index that index.
+// Returned with the kind of index used and its column or name; `None` when no index applies.
+// The caller still checks the whole condition against the rows.
+fn index_lookup(
+    storage: &StorageManager,
+    table: &str,
+    condition: Option<&Condition>,
+) -> Option<(&'static str, String, Vec<usize>)> {
+    let condition = condition?;
+    match condition {
+        Condition::Equal(column, value) => {
+            if let Some(positions) = storage.unique_lookup(table, column, value) {
+                return Some(("Unique", column.clone(), positions));
+            }
+        }
+        Condition::Match { column, terms, any } => {
+            return storage
+                .fulltext_lookup(table, column, terms, *any)
+                .map(|positions| ("Full-text", column.clone(), positions));
+        }
+        _ => {}
+    }
+    let mut equalities = Vec::new();
+    collect_equalities(condition, &mut equalities);
+    storage
+        .composite_lookup(table, &equalities)
+        .map(|(index, positions)| ("Composite", index, positions))
+}
+
+// Whether the rows an index lookup finds for `condition` are exactly the rows it holds for: true
+// for a single `column = value`, which a unique or composite index answers in full
+fn index_counts(condition: &Condition) -> bool {
+    matches!(condition, Condition::Equal(_, value) if !value.is_null())
+}
+
+// The `column = value` tests every row passing `condition` satisfies: those reached through
+// ANDs alone, since a branch of an OR need not hold
+fn collect_equalities(condition: &Condition, equalities: &mut Vec<(String, Value)>) {
+    match condition {
+        Condition::Equal(column, value) => equalities.push((column.clone(), value.clone())),
+        Condition::And(left, right) => {
+            collect_equalities(left, equalities);
+            collect_equalities(right, equalities);
+        }
+        _ => {}
+    }
+}
+
+fn read_visible_columns(
+    storage: &StorageManager,
+    tx: Option<&Transaction>,
+    table: &str,
+    columns: &[String],
+    predicate: Option<&Condition>,
+) -> Result<HashMap<String, Vec<Value>>, DbError> {
+    match tx {
+        Some(tx) if tx.writes_to(table) => {
+            let mut visible = tx.visible_columns(storage, table)?;
+            let mut selected = HashMap::with_capacity(columns.len());
+            for column in columns {
+                if selected.contains_key(column) {
+                    continue;
+                }
+                let values = visible
+                    .remove(column)
+                    .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table, column)))?;
+                selected.insert(column.clone(), values);
+            }
+            Ok(selected)
+        }
+        _ => match predicate {
+            Some(predicate) => storage.read_columns_where(table, columns, predicate),
+            None => storage.read_columns(table, columns, None),
+        },
+    }
+}
+
+fn read_visible_column(
+    storage: &StorageManager,
+    tx: Option<&Transaction>,
+    table: &str,
+    column: &str,
+    condition: Option<&Condition>,
+) -> Result<Vec<Value>, DbError> {
+    match tx {
+        Some(tx) if tx.writes_to(table) => tx
+            .visible_columns(storage, table)?
+            .remove(column)
+            .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table, column))),
+        _ => storage.read_column(table, column, condition),
+    }
+}
+
+// Picks up the logged-in user's current roles and every role's permissions after a GRANT or REVOKE
+fn refresh_security(security: Option<&mut SecurityContext>, storage: &StorageManager) {
+    if let Some(security) = security {
+        if let Some(user) = security.current_user.as_mut() {
+            if let Some(stored) = storage.users().get(&user.username) {
+                user.roles = stored.roles.clone();
+            }
+        }
+        security.permissions = storage.users().permissions().clone();
     }
 }
\ No newline at end of file
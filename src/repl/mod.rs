@@ -1,41 +1,67 @@
 use crate::{
-    query::parser::parse_query,
+    query::parser::{parse_query, split_statements, split_statements_with_lines, strip_comments},
     query::Query,
-    query::planner::QueryEngine,
+    query::planner::{QueryEngine, SETTINGS},
     schema::Schema,
-    storage::StorageManager,
+    storage::SharedStorage,
     transaction::{Transaction, TransactionManager},
-    types::{DbError, Value},
+    types::{DbError, SecurityContext, Value},
     plugins::PluginManager,
 };
 use rustyline::{Editor, Config, CompletionType, error::ReadlineError};
-use std::sync::{Arc, Mutex};
+use rustyline::validate::{ValidationContext, ValidationResult};
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::fmt;
 use ordered_float::OrderedFloat;
 
-pub struct QueryResult(pub Vec<Vec<Value>>);
+pub use crate::query::QueryResult;
 
-impl fmt::Display for QueryResult {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for row in &self.0 {
-            for (i, value) in row.iter().enumerate() {
-                if i > 0 {
-                    write!(f, ", ")?;
-                }
-                write!(f, "{}", value)?;
-            }
-            writeln!(f)?;
+/// Environment variable naming the file the shell's history is loaded from at start and saved
+/// to on exit; defaults to `HISTORY_FILE` in the home directory.
+pub const HISTORY_FILE_ENV: &str = "VDDB_HISTORY_FILE";
+/// Environment variable holding how many lines the history keeps, oldest dropped first.
+pub const HISTORY_SIZE_ENV: &str = "VDDB_HISTORY_SIZE";
+pub const HISTORY_FILE: &str = ".vddb_history";
+pub const DEFAULT_HISTORY_SIZE: usize = 1000;
+/// Session settings the shell keeps itself, next to the query engine's `SETTINGS`.
+pub const SHELL_SETTINGS: &[&str] =
+    &["format", "timing", "max_width", "safe_delete", "prompt", "prompt_transaction", "prompt_failed"];
+
+// What the shell prompts with: normally, while a transaction is open, and while one is open
+// after a statement in it failed
+#[derive(Debug, Clone)]
+struct Prompts {
+    idle: String,
+    transaction: String,
+    failed: String,
+}
+
+impl Default for Prompts {
+    fn default() -> Self {
+        Prompts {
+            idle: "vddb> ".to_string(),
+            transaction: "vddb*> ".to_string(),
+            failed: "vddb!> ".to_string(),
         }
-        Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Int32(i) => write!(f, "{}", i),
+            Value::Int64(i) => write!(f, "{}", i),
             Value::Float32(f32) => write!(f, "{}", f32.0),
             Value::String(s) => write!(f, "{}", s),
+            Value::Timestamp(ms) => write!(f, "{}", Value::format_timestamp(*ms)),
+            Value::Null => write!(f, "NULL"),
         }
     }
 }
@@ -43,28 +69,62 @@ impl fmt::Display for Value {
 pub struct Repl {
     editor: Editor<ReplHelper, rustyline::history::FileHistory>,
     schema: Schema,
-    storage: Arc<Mutex<StorageManager>>,
+    storage: SharedStorage,
     tx_manager: TransactionManager,
     plugin_manager: PluginManager,
     query_engine: QueryEngine,
+    format: OutputFormat,
+    max_cell_width: usize,
+    // Ask before running a DELETE without a WHERE clause
+    confirm_deletes: bool,
+    // Print how long each statement took
+    timing: bool,
+    prompts: Prompts,
+    // Whether the last statement run failed, shown in the prompt while a transaction is open
+    statement_failed: bool,
+    // Where the history is saved on exit; None keeps it for the session only
+    history_path: Option<PathBuf>,
 }
 
 impl Repl {
     pub fn new(
         schema: Schema,
-        storage: Arc<Mutex<StorageManager>>,
+        storage: SharedStorage,
         tx_manager: TransactionManager,
         plugin_manager: PluginManager,
     ) -> Result<Self, DbError> {
+        let history_size = match std::env::var(HISTORY_SIZE_ENV) {
+            Ok(size) => size.trim().parse::<usize>().map_err(|_| {
+                DbError::ConfigurationError(format!("Invalid {} '{}'", HISTORY_SIZE_ENV, size))
+            })?,
+            Err(_) => DEFAULT_HISTORY_SIZE,
+        };
         let config = Config::builder()
             .history_ignore_space(true)
+            // Repeating the line just run adds nothing to the history
+            .history_ignore_dups(true)
+            .and_then(|builder| builder.max_history_size(history_size))
+            .map_err(|e| DbError::ConfigurationError(e.to_string()))?
             .completion_type(CompletionType::List)
             .build();
         
         let mut editor = Editor::with_config(config).map_err(|e| DbError::QueryError(e.to_string()))?;
-        editor.set_helper(Some(ReplHelper::new()));
+        editor.set_helper(Some(ReplHelper::new(storage.clone())));
+        let history_path = std::env::var_os(HISTORY_FILE_ENV)
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE)));
+        if let Some(path) = history_path.as_ref().filter(|path| path.exists()) {
+            // A history that cannot be read only costs the recalled lines
+            if let Err(e) = editor.load_history(path) {
+                log::warn!("Cannot load history from {}: {}", path.display(), e);
+            }
+        }
         
-        let query_engine = QueryEngine::new(storage.clone());
+        let mut query_engine = QueryEngine::new(storage.clone());
+        // Once a user exists, nothing runs until someone logs in
+        if !storage.read().map_err(|e| DbError::ConcurrencyError(e.to_string()))?.users().is_empty() {
+            query_engine.set_security_context(Some(SecurityContext::new()));
+        }
         
         Ok(Self {
             editor,
@@ -73,31 +133,124 @@ impl Repl {
             tx_manager,
             plugin_manager,
             query_engine,
+            format: OutputFormat::Table,
+            max_cell_width: crate::query::DEFAULT_MAX_CELL_WIDTH,
+            confirm_deletes: false,
+            timing: false,
+            prompts: Prompts::default(),
+            statement_failed: false,
+            history_path,
         })
     }
 
+    /// Checks every statement run from the shell against `security` from now on.
+    pub fn set_security_context(&mut self, security: Option<SecurityContext>) {
+        self.query_engine.set_security_context(security);
+    }
+
     pub fn run(&mut self) -> Result<(), DbError> {
         println!("VDDB Interactive Shell");
         println!("Type 'HELP' for help, 'EXIT' to quit");
+        println!("End SQL statements with ';' - they may span several lines");
+        // readline reads Ctrl-C at the prompt itself; while a statement runs it arrives as
+        // SIGINT, which now cancels the statement instead of killing the shell
+        signal_hook::flag::register(signal_hook::consts::SIGINT, self.query_engine.cancel_handle())?;
 
         loop {
-            match self.editor.readline("vddb> ") {
+            let prompt = self.prompt().to_string();
+            match self.editor.readline(&prompt) {
                 Ok(line) => {
-                    self.editor.add_history_entry(line.as_str());
-                    
-                    match line.trim().to_uppercase().as_str() {
-                        "EXIT" | "QUIT" => break,
+                    self.record_history(&line);
+                    // Shell commands are accepted with or without a trailing semicolon
+                    let command = line.trim().trim_end_matches(';').trim_end();
+
+                    // ASCII uppercasing keeps byte offsets, so `command` can be sliced by the matched prefix
+                    match command.to_ascii_uppercase().as_str() {
+                        "EXIT" | "QUIT" => {
+                            self.offer_commit();
+                            break;
+                        }
                         "HELP" => self.show_help(),
                         cmd if cmd.starts_with("PLUGIN ") => {
-                            if let Err(e) = self.handle_plugin_command(&line[7..]) {
+                            if let Err(e) = self.handle_plugin_command(&command[7..]) {
                                 eprintln!("Plugin error: {}", e);
                             }
                         }
-                        _ => {
-                            if let Err(e) = self.execute_query(&line) {
-                                eprintln!("Error: {}", e);
+                        cmd if cmd.starts_with("MIGRATE ") => {
+                            if let Err(e) = self.handle_migrate_command(command[8..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd.starts_with("\\MAXWIDTH") => {
+                            if let Err(e) = self.handle_setting_command("max_width", command[9..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd.starts_with("\\FORMAT") => {
+                            if let Err(e) = self.handle_setting_command("format", command[7..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd.starts_with("\\SAFEDELETE") => {
+                            if let Err(e) = self.handle_setting_command("safe_delete", command[11..].trim()) {
+                                self.report_error(&e);
                             }
                         }
+                        cmd if cmd == "\\SET" || cmd.starts_with("\\SET ") => {
+                            if let Err(e) = self.handle_set_command(command[4..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd.starts_with("\\AUDITREADS") => {
+                            if let Err(e) = self.handle_audit_reads_command(command[11..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        "\\DT" => {
+                            if let Err(e) = self.handle_describe_command("") {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd == "\\D" || cmd.starts_with("\\D ") => {
+                            if let Err(e) = self.handle_describe_command(command[2..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd == "\\DUMP" || cmd.starts_with("\\DUMP ") => {
+                            if let Err(e) = self.handle_dump_command(command[5..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd.starts_with("\\SOURCE") => {
+                            if let Err(e) = self.handle_source_command(command[7..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd.starts_with("\\VALIDATE") => {
+                            if let Err(e) = self.handle_validate_command(command[9..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        cmd if cmd.starts_with("\\COPY ") => {
+                            if let Err(e) = self.handle_copy_command(command[6..].trim()) {
+                                self.report_error(&e);
+                            }
+                        }
+                        // Comments go first, so a semicolon in one does not end a statement
+                        _ => match strip_comments(&line) {
+                            Ok(sql) => {
+                                for statement in split_statements(&sql) {
+                                    if let Err(e) = self.run_statement(statement) {
+                                        self.report_error(&e);
+                                        // Ctrl-C stops the rest of the line too
+                                        if matches!(e, DbError::Cancelled(_)) {
+                                            break;
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) => self.report_error(&e),
+                        },
                     }
                 }
                 Err(ReadlineError::Interrupted) => {
@@ -106,6 +259,7 @@ impl Repl {
                 }
                 Err(ReadlineError::Eof) => {
                     println!("^D");
+                    self.offer_commit();
                     break;
                 }
                 Err(err) => {
@@ -115,16 +269,116 @@ impl Repl {
             }
         }
 
+        self.shutdown();
         Ok(())
     }
 
-    fn execute_query(&mut self, query: &str) -> Result<(), DbError> {
-        let parsed = parse_query(query)?;
-        match self.query_engine.execute(parsed) {
-            Ok(results) => {
-                if !results.is_empty() {
-                    let query_result = QueryResult(results);
-                    println!("{}", query_result);
+    pub(crate) fn record_history(&mut self, line: &str) {
+        // Keep passwords out of the history
+        if !contains_password(line) {
+            if let Err(e) = self.editor.add_history_entry(line) {
+                log::warn!("Cannot add to history: {}", e);
+            }
+        }
+    }
+
+    // Writes the history to its file, keeping the newest lines up to the configured size
+    pub(crate) fn save_history(&mut self) -> Result<(), DbError> {
+        let Some(path) = &self.history_path else { return Ok(()) };
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        self.editor
+            .save_history(path)
+            .map_err(|e| DbError::IoError(std::io::Error::other(format!("Cannot save history to {}: {}", path.display(), e))))
+    }
+
+    // Asks whether to commit a transaction still open on the way out; any answer but yes leaves
+    // it for shutdown to roll back
+    fn offer_commit(&mut self) {
+        if !self.query_engine.in_transaction() {
+            return;
+        }
+        match self.confirm("A transaction is still open. Commit it before exiting? [y/N] ") {
+            Ok(true) => {
+                if let Err(e) = self.query_engine.execute_query(Query::Commit) {
+                    eprintln!("Error: COMMIT failed: {}", e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => self.report_error(&e),
+        }
+    }
+
+    // Flushes the database to disk on the way out. Failures are logged rather than returned so
+    // they never keep the shell from exiting.
+    fn shutdown(&mut self) {
+        if let Err(e) = self.save_history() {
+            log::error!("{}", e);
+        }
+        if self.query_engine.in_transaction() {
+            println!("Rolling back the open transaction");
+            if let Err(e) = self.query_engine.execute_query(Query::Rollback) {
+                log::error!("Rollback at shutdown failed: {}", e);
+            }
+        }
+        let outcome = self
+            .storage
+            .write()
+            .map_err(|e| DbError::ConcurrencyError(e.to_string()))
+            .and_then(|mut storage| storage.shutdown());
+        if let Err(e) = outcome {
+            log::error!("Shutdown failed: {}", e);
+            eprintln!("Error: shutdown failed: {}", e);
+        }
+        log::logger().flush();
+    }
+
+    /// The prompt for the next line. Like psql's, it shows whether a transaction is open, and
+    /// whether the last statement in it failed; the transaction itself stays usable.
+    pub(crate) fn prompt(&self) -> &str {
+        match (self.query_engine.in_transaction(), self.statement_failed) {
+            (false, _) => &self.prompts.idle,
+            (true, false) => &self.prompts.transaction,
+            (true, true) => &self.prompts.failed,
+        }
+    }
+
+    // Runs a statement typed in or read by \source, noting whether it failed for the prompt
+    pub(crate) fn run_statement(&mut self, sql: &str) -> Result<(), DbError> {
+        let outcome = self.execute_query(sql);
+        self.statement_failed = outcome.is_err();
+        outcome
+    }
+
+    pub(crate) fn execute_query(&mut self, query: &str) -> Result<(), DbError> {
+        let parsed = self.query_engine.prepare(query)?;
+        // The engine runs SET and SHOW for its own settings; the shell answers for the rest
+        match &parsed {
+            Query::Set { name, value } if !SETTINGS.contains(&name.as_str()) => return self.set_setting(name, value),
+            Query::Show(name) if !SETTINGS.contains(&name.as_str()) => {
+                let value = self.show_setting(name)?;
+                return self.print_result(&QueryResult::new(vec![name.clone()], vec![vec![Value::String(value)]]));
+            }
+            _ => {}
+        }
+        if let Query::Delete { table, condition: None } = &parsed {
+            if self.confirm_deletes && !self.confirm(&format!("Delete every row of {}? [y/N] ", table))? {
+                println!("DELETE cancelled");
+                return Ok(());
+            }
+        }
+        self.plugin_manager.before_query(query)?;
+        let start = std::time::Instant::now();
+        match self.query_engine.execute_audited(query, parsed) {
+            Ok(result) => {
+                let elapsed = start.elapsed();
+                if let Err(e) = self.plugin_manager.after_query(query, result.rows.len()) {
+                    eprintln!("Plugin error: {}", e);
+                }
+                self.print_result(&result)?;
+                if self.timing {
+                    println!("Time: {:.3} ms", elapsed.as_secs_f64() * 1000.0);
                 }
                 Ok(())
             }
@@ -132,6 +386,257 @@ impl Repl {
         }
     }
 
+    fn print_result(&self, result: &QueryResult) -> Result<(), DbError> {
+        match self.format {
+            OutputFormat::Table if !result.columns.is_empty() => {
+                println!("{}", result.to_table(self.max_cell_width))
+            }
+            OutputFormat::Table => {}
+            // Always emit valid JSON, even for an empty result
+            OutputFormat::Json if !result.columns.is_empty() => println!("{}", result.to_json()?),
+            OutputFormat::Json => {}
+        }
+        Ok(())
+    }
+
+    // Prints a failed statement or command to stderr, as `{"error": {"code", "message"}}` in
+    // the JSON format so scripts reading it need not parse the text
+    fn report_error(&self, error: &DbError) {
+        match self.format {
+            OutputFormat::Table => eprintln!("Error: {}", error),
+            OutputFormat::Json => eprintln!("{}", serde_json::json!({ "error": error.to_json() })),
+        }
+    }
+
+    fn confirm(&mut self, prompt: &str) -> Result<bool, DbError> {
+        match self.editor.readline(prompt) {
+            Ok(answer) => Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")),
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => Ok(false),
+            Err(e) => Err(DbError::QueryError(e.to_string())),
+        }
+    }
+
+    // \format, \maxwidth and \safedelete: print the setting, or change it to `arg`
+    fn handle_setting_command(&mut self, name: &str, arg: &str) -> Result<(), DbError> {
+        if arg.is_empty() {
+            println!("{} is {}", name, self.show_setting(name)?);
+            Ok(())
+        } else {
+            self.set_setting(name, arg)
+        }
+    }
+
+    // \set lists every setting; \set <name> <value> is SET name = value
+    fn handle_set_command(&mut self, args: &str) -> Result<(), DbError> {
+        if args.is_empty() {
+            let mut rows = Vec::new();
+            for name in SHELL_SETTINGS.iter().chain(SETTINGS) {
+                rows.push(vec![Value::String(name.to_string()), Value::String(self.show_setting(name)?)]);
+            }
+            return self.print_result(&QueryResult::new(vec!["name".to_string(), "value".to_string()], rows));
+        }
+        let (name, value) = args
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| DbError::QueryError("Usage: \\set [<name> <value>]".to_string()))?;
+        self.set_setting(&name.to_ascii_lowercase(), value.trim().trim_matches('\''))
+    }
+
+    /// The value of session setting `name` as SHOW prints it: one of `SHELL_SETTINGS` or of
+    /// the query engine's `SETTINGS`.
+    pub(crate) fn show_setting(&self, name: &str) -> Result<String, DbError> {
+        let on_off = |on: bool| if on { "on" } else { "off" }.to_string();
+        match name {
+            "format" => Ok(match self.format {
+                OutputFormat::Table => "table",
+                OutputFormat::Json => "json",
+            }
+            .to_string()),
+            "timing" => Ok(on_off(self.timing)),
+            "max_width" => Ok(self.max_cell_width.to_string()),
+            "safe_delete" => Ok(on_off(self.confirm_deletes)),
+            "prompt" => Ok(self.prompts.idle.clone()),
+            "prompt_transaction" => Ok(self.prompts.transaction.clone()),
+            "prompt_failed" => Ok(self.prompts.failed.clone()),
+            _ if SETTINGS.contains(&name) => self.query_engine.setting(name),
+            _ => Err(unknown_setting(name)),
+        }
+    }
+
+    /// Changes session setting `name`, as `SET name = value` does.
+    pub(crate) fn set_setting(&mut self, name: &str, value: &str) -> Result<(), DbError> {
+        let invalid = |expected: &str| DbError::ConfigurationError(format!("{} must be {}, got '{}'", name, expected, value));
+        let on_off = || match value.to_ascii_lowercase().as_str() {
+            "on" | "true" => Ok(true),
+            "off" | "false" => Ok(false),
+            _ => Err(invalid("on or off")),
+        };
+        match name {
+            "format" => {
+                self.format = match value.to_ascii_lowercase().as_str() {
+                    "table" => OutputFormat::Table,
+                    "json" => OutputFormat::Json,
+                    _ => return Err(invalid("table or json")),
+                }
+            }
+            "timing" => self.timing = on_off()?,
+            "max_width" => {
+                self.max_cell_width = match value.parse::<usize>() {
+                    Ok(width) if width > 1 => width,
+                    _ => return Err(invalid("a number greater than 1")),
+                }
+            }
+            "safe_delete" => self.confirm_deletes = on_off()?,
+            "prompt" => self.prompts.idle = value.to_string(),
+            "prompt_transaction" => self.prompts.transaction = value.to_string(),
+            "prompt_failed" => self.prompts.failed = value.to_string(),
+            _ if SETTINGS.contains(&name) => self.query_engine.set_setting(name, value)?,
+            _ => return Err(unknown_setting(name)),
+        }
+        Ok(())
+    }
+
+    // \copy <query> TO 'file.csv'
+    fn handle_copy_command(&mut self, args: &str) -> Result<(), DbError> {
+        let to_pos = args
+            .to_uppercase()
+            .rfind(" TO ")
+            .ok_or_else(|| DbError::QueryError("Usage: \\copy <query> TO 'file.csv'".to_string()))?;
+        let path = args[to_pos + 4..].trim().trim_matches(|c| c == '\'' || c == '"');
+        if path.is_empty() {
+            return Err(DbError::QueryError("\\copy requires a file name".to_string()));
+        }
+        let parsed = parse_query(&args[..to_pos])?;
+        let result = self.query_engine.execute_query(parsed)?;
+        let file = std::fs::File::create(path)?;
+        result.to_csv(std::io::BufWriter::new(file))?;
+        println!("COPY {}", result.rows.len());
+        Ok(())
+    }
+
+    // \source <file>: runs the file's statements as if typed in, stopping at the first failure
+    pub(crate) fn handle_source_command(&mut self, path: &str) -> Result<(), DbError> {
+        let path = path.trim_matches(|c| c == '\'' || c == '"');
+        if path.is_empty() {
+            return Err(DbError::QueryError("Usage: \\source <file>".to_string()));
+        }
+        let script = std::fs::read_to_string(path)?;
+        let script = strip_comments(&script)?;
+        for (line, statement) in split_statements_with_lines(&script) {
+            self.run_statement(statement)
+                .map_err(|e| DbError::QueryError(format!("{} line {}: {}", path, line, e)))?;
+        }
+        Ok(())
+    }
+
+    // \validate <statement>
+    fn handle_validate_command(&mut self, sql: &str) -> Result<(), DbError> {
+        if sql.is_empty() {
+            return Err(DbError::QueryError("Usage: \\validate <statement>".to_string()));
+        }
+        let parsed = parse_query(sql)?;
+        for line in self.query_engine.validate(&parsed)? {
+            println!("{}", line);
+        }
+        println!("VALID");
+        Ok(())
+    }
+
+    // \dt, or \d <table>; both read information_schema, so they need SELECT on it
+    fn handle_describe_command(&mut self, table: &str) -> Result<(), DbError> {
+        let table = self.declared_table_name(table)?;
+        let table = table.as_str();
+        let sql = if table.is_empty() {
+            format!("SELECT * FROM {}", crate::query::catalog::TABLES)
+        } else {
+            format!(
+                "SELECT column_name, data_type, is_nullable, column_default, column_key, check_clause, foreign_key, indexes FROM {} WHERE table_name = {}",
+                crate::query::catalog::COLUMNS,
+                Value::String(table.to_string()).to_sql_literal()
+            )
+        };
+        let result = self.query_engine.execute_query(parse_query(&sql)?)?;
+        if !table.is_empty() && result.rows.is_empty() {
+            return Err(DbError::InvalidData(format!("Table {} not found", table)));
+        }
+        self.print_result(&result)?;
+        if !table.is_empty() {
+            let sql = format!(
+                "SELECT index_name, index_type, column_names FROM {} WHERE table_name = {}",
+                crate::query::catalog::INDEXES,
+                Value::String(table.to_string()).to_sql_literal()
+            );
+            let indexes = self.query_engine.execute_query(parse_query(&sql)?)?;
+            if !indexes.rows.is_empty() {
+                println!("Indexes:");
+                self.print_result(&indexes)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The name `table` was declared with, matched the way queries match it; unknown names are kept
+    fn declared_table_name(&self, table: &str) -> Result<String, DbError> {
+        let storage = self.storage.read().map_err(|e| DbError::ConcurrencyError(e.to_string()))?;
+        Ok(storage.schema().get_table(table).map_or_else(|| table.to_string(), |t| t.name.clone()))
+    }
+
+    // MIGRATE <dir>
+    fn handle_migrate_command(&mut self, dir: &str) -> Result<(), DbError> {
+        let dir = dir.trim_matches(|c| c == '\'' || c == '"');
+        if dir.is_empty() {
+            return Err(DbError::MigrationError("Usage: MIGRATE <directory>".to_string()));
+        }
+        if self.query_engine.in_transaction() {
+            return Err(DbError::MigrationError("Cannot migrate while a transaction is open".to_string()));
+        }
+        let engine = &mut self.query_engine;
+        let outcome = crate::migrate::run_migrations(&self.storage, std::path::Path::new(dir), |sql| {
+            let query = engine.prepare(sql)?;
+            engine.execute_audited(sql, query)
+        });
+        if outcome.is_err() && self.query_engine.in_transaction() {
+            self.query_engine.execute_query(Query::Rollback)?;
+        }
+        let applied = outcome?;
+        if applied.is_empty() {
+            println!("No migrations to apply");
+        }
+        for name in applied {
+            println!("Applied {}", name);
+        }
+        Ok(())
+    }
+
+    // \auditreads [on|off]
+    fn handle_audit_reads_command(&mut self, arg: &str) -> Result<(), DbError> {
+        let mut storage = self.storage.write().map_err(|e| DbError::ConcurrencyError(e.to_string()))?;
+        match arg.to_lowercase().as_str() {
+            "" => println!("Read auditing is {}", if storage.audit_log().audits_reads() { "on" } else { "off" }),
+            "on" => storage.audit_log_mut().set_audit_reads(true),
+            "off" => storage.audit_log_mut().set_audit_reads(false),
+            _ => return Err(DbError::QueryError("\\auditreads expects on or off".to_string())),
+        }
+        Ok(())
+    }
+
+    // \dump [table]
+    fn handle_dump_command(&mut self, table: &str) -> Result<(), DbError> {
+        let table = self.declared_table_name(table)?;
+        let table = if table.is_empty() { None } else { Some(table.as_str()) };
+        if let Some(security) = self.query_engine.security_context() {
+            // Dumping reads every row, so it needs the same permission as SELECT
+            security.authorize(&Query::Select {
+                table: Some(table.unwrap_or_default().to_string()),
+                columns: Vec::new(),
+                condition: None,
+                order_by: Vec::new(),
+            })?;
+        }
+        let sql = self.storage.read().map_err(|e| DbError::ConcurrencyError(e.to_string()))?.dump_sql(table)?;
+        print!("{}", sql);
+        Ok(())
+    }
+
     fn handle_plugin_command(&mut self, cmd: &str) -> Result<(), DbError> {
         let parts: Vec<&str> = cmd.split_whitespace().collect();
         if parts.is_empty() {
@@ -141,6 +646,20 @@ impl Repl {
             }
             return Ok(());
         }
+        if parts[0].eq_ignore_ascii_case("LOAD") {
+            let path = cmd.trim()[4..].trim();
+            if path.is_empty() {
+                return Err(DbError::PluginError("Usage: PLUGIN LOAD <path to library>".to_string()));
+            }
+            let name = self.plugin_manager.load_plugin(std::path::Path::new(path))?;
+            println!("Loaded plugin {}", name);
+            return Ok(());
+        }
+        if parts[0].eq_ignore_ascii_case("UNLOAD") && parts.len() == 2 {
+            self.plugin_manager.unregister_plugin(parts[1])?;
+            println!("Unloaded plugin {}", parts[1]);
+            return Ok(());
+        }
 
         let plugin_name = parts[0];
         let command = parts.get(1).unwrap_or(&"");
@@ -162,28 +681,206 @@ impl Repl {
         println!("  DELETE - Remove data from tables");
         println!("  CREATE TABLE - Create a new table");
         println!("  DROP TABLE - Remove a table");
+        println!("  ALTER TABLE - Change a table's columns");
+        println!("  CREATE INDEX - Index columns for WHERE equalities on its first column, or first two, and so on");
+        println!("  CREATE FULLTEXT INDEX - Index the words of a TEXT column for MATCH");
+        println!("  DROP INDEX - Remove an index made by CREATE INDEX");
+        println!("  COPY - Import rows from a CSV file");
+        println!("  EXPORT TABLE - Write a whole table to a Parquet file");
+        println!("  VACUUM - Rewrite a table's files to reclaim space");
+        println!("  VERIFY - Check a table's files against their checksums");
+        println!("  BACKUP TO/RESTORE FROM - Copy the whole database to a directory, or replace it with such a copy");
+        println!("  BACKUP INCREMENTAL TO - Add the rows inserted since to a full backup");
+        println!("  EXPLAIN - Show how a SELECT would be executed");
+        println!("  BEGIN/COMMIT/ROLLBACK - Group INSERT and DELETE statements into a transaction");
+        println!("  SAVEPOINT/ROLLBACK TO - Undo part of an open transaction");
+        println!("  SET TRANSACTION ISOLATION LEVEL - READ COMMITTED or SERIALIZABLE");
+        println!("  SET statement_timeout = <ms> - Stop SELECTs and joins that run longer; 0 for no limit");
+        println!("  SET max_result_rows = <n> - Fail SELECTs and joins returning more rows; 0 for no limit");
+        println!("  SET autocommit = on|off - When off, reads and writes begin a transaction that waits for COMMIT");
+        println!("  SET format = table|json, SET timing = on|off, SET max_width = <n>, SET safe_delete = on|off");
+        println!("  SET prompt | prompt_transaction | prompt_failed = '<text>' - Prompt normally, in a transaction, after a failure in one");
+        println!("  SHOW <setting> - Print a session setting's value");
+        println!("  CREATE USER - Add a user; the first is an admin, and later sessions must log in");
+        println!("  LOGIN - Log in as a user for the rest of the session");
+        println!("  GRANT/REVOKE - Give roles permissions on tables, and users roles (admins only)");
+        println!("  \\format table|json - Choose how query results are printed");
+        println!("  \\maxwidth <n> - Truncate table cells wider than n characters");
+        println!("  \\safedelete on|off - Ask before a DELETE without WHERE removes every row");
+        println!("  \\set [<name> <value>] - List the session settings, or change one like SET");
+        println!("  \\auditreads on|off - Also record SELECTs in the audit log");
+        println!("  \\copy - Export a query result to CSV");
+        println!("  \\source <file> - Run the SQL statements in a file, stopping at the first error");
+        println!("  \\validate <statement> - Check a statement against the schema without running it");
+        println!("  \\dump [table] - Print SQL that recreates a table, or the whole database");
+        println!("  \\dt - List tables");
+        println!("  \\d <table> - Describe a table's columns, constraints and indexes");
+        println!("  MIGRATE <dir> - Apply the numbered .sql files in dir not yet applied, in order");
         println!("  PLUGIN - Manage plugins");
         println!("  HELP - Show this help message");
         println!("  EXIT/QUIT - Exit the shell");
+        println!("  Ctrl-C - Cancel the running statement and the rest of its line");
         println!("\nSQL Syntax Examples:");
         println!("  SELECT * FROM users WHERE age > 18");
-        println!("  INSERT INTO users (name, age) VALUES ('John', 25)");
+        println!("  SELECT title FROM articles WHERE body MATCH 'rust database'");
+        println!("  SELECT title FROM articles WHERE body MATCH ANY 'rust go'");
+        println!("  SELECT CAST('42' AS INT)");
+        println!("  INSERT INTO users (name, age) VALUES ('John', 25), ('Jane', 31)");
+        println!("  INSERT INTO users (id, name) VALUES (1, 'John') ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name");
         println!("  UPDATE users SET age = 26 WHERE name = 'John'");
         println!("  DELETE FROM users WHERE age < 18");
         println!("  CREATE TABLE users (id INT, name TEXT, age INT)");
         println!("  DROP TABLE users");
+        println!("  DROP INDEX orders_customer_status ON orders");
+        println!("  ALTER TABLE users DROP COLUMN age");
+        println!("  ALTER TABLE users RENAME COLUMN name TO full_name");
+        println!("  ALTER TABLE users RENAME TO members");
+        println!("  CREATE INDEX orders_customer_status ON orders (customer_id, status)");
+        println!("  CREATE FULLTEXT INDEX ON articles (body)");
+        println!("  COPY users FROM 'users.csv'");
+        println!("  EXPORT TABLE users TO 'users.parquet'");
+        println!("  VACUUM users");
+        println!("  VERIFY users");
+        println!("  BACKUP TO '/backups/2024-06-01'");
+        println!("  BACKUP INCREMENTAL TO '/backups/2024-06-01'");
+        println!("  RESTORE FROM '/backups/2024-06-01'");
+        println!("  EXPLAIN SELECT name FROM users WHERE age > 18");
+        println!("  \\copy SELECT * FROM users TO 'users.csv'");
+        println!("  \\dump users");
+        println!("  \\source schema.sql");
+        println!("  \\d users");
+        println!("  MIGRATE migrations");
+        println!("  SELECT table_name, column_name FROM information_schema.columns WHERE data_type = 'TEXT'");
+        println!("  \\validate DELETE FROM users WHERE age < 18");
+        println!("  CREATE USER alice PASSWORD 's3cret'");
+        println!("  LOGIN alice PASSWORD 's3cret'");
+        println!("  GRANT SELECT ON users TO analyst");
+        println!("  GRANT ROLE analyst TO alice");
         println!("\nPlugin Commands:");
         println!("  PLUGIN - List available plugins");
         println!("  PLUGIN <name> <args> - Execute a plugin");
+        println!("  PLUGIN LOAD <path> - Load a plugin from a shared library, also on later starts");
+        println!("  PLUGIN UNLOAD <name> - Unregister a plugin and stop loading it on start");
     }
 }
 
-struct ReplHelper;
+const SQL_KEYWORDS: &[&str] = &[
+    "SELECT", "FROM", "WHERE", "AND", "OR", "INSERT", "INTO", "VALUES", "CREATE", "TABLE", "DROP",
+    "DELETE", "ALTER", "COLUMN", "JOIN", "ON", "CAST", "AS", "COUNT", "SUM", "AVG", "MIN", "MAX",
+    "GROUP_CONCAT", "INT", "BIGINT", "FLOAT", "STRING", "TEXT", "NOT", "NULL", "PRIMARY", "KEY", "UNIQUE", "DEFAULT", "CHECK",
+    "REFERENCES", "CASCADE", "RESTRICT", "COPY", "MIGRATE", "START", "TRANSACTION", "COMMIT", "ROLLBACK",
+    "EXPLAIN", "BEGIN", "SAVEPOINT", "SET", "ISOLATION", "LEVEL", "SERIALIZABLE", "TIMESTAMP", "NOW", "USER", "LOGIN",
+    "PASSWORD", "GRANT", "REVOKE", "ROLE", "TO", "EXPORT", "VACUUM", "VERIFY",
+    "CONFLICT", "DO", "NOTHING", "UPDATE", "EXCLUDED", "RENAME", "IS", "ORDER", "BY", "ASC", "DESC", "NULLS",
+    "FIRST", "LAST", "BACKUP", "RESTORE", "INCREMENTAL", "FULLTEXT", "INDEX", "MATCH", "ANY",
+];
+
+// Keywords after which a table name is expected
+const TABLE_CONTEXT: &[&str] = &["FROM", "INTO", "TABLE", "JOIN", "REFERENCES", "COPY", "VACUUM", "VERIFY", "\\DUMP", "\\D"];
+
+struct ReplHelper {
+    storage: SharedStorage,
+    // Disabled when NO_COLOR is set (https://no-color.org)
+    color: bool,
+}
 
 impl ReplHelper {
-    fn new() -> Self {
-        Self
+    fn new(storage: SharedStorage) -> Self {
+        Self {
+            storage,
+            color: std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+}
+
+const KEYWORD_COLOR: &str = "\x1b[1;34m";
+const STRING_COLOR: &str = "\x1b[32m";
+const NUMBER_COLOR: &str = "\x1b[33m";
+const RESET_COLOR: &str = "\x1b[0m";
+
+/// Wraps SQL keywords, single-quoted strings and numeric literals in ANSI color escapes.
+pub(crate) fn highlight_sql(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() * 2);
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != '\'' {
+                i += 1;
+            }
+            i = (i + 1).min(chars.len());
+            let literal: String = chars[start..i].iter().collect();
+            out.push_str(&format!("{}{}{}", STRING_COLOR, literal, RESET_COLOR));
+        } else if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).is_some_and(|n| n.is_ascii_digit())) {
+            i += 1;
+            while i < chars.len() {
+                let digit_at = |j: usize| chars.get(j).is_some_and(|n| n.is_ascii_digit());
+                match chars[i] {
+                    c if c.is_ascii_digit() || c == '.' || c == '_' => i += 1,
+                    // An exponent, as in 1.5e-3
+                    'e' | 'E' if digit_at(i + 1) => i += 1,
+                    'e' | 'E' if matches!(chars.get(i + 1), Some('-' | '+')) && digit_at(i + 2) => i += 2,
+                    _ => break,
+                }
+            }
+            let number: String = chars[start..i].iter().collect();
+            out.push_str(&format!("{}{}{}", NUMBER_COLOR, number, RESET_COLOR));
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if SQL_KEYWORDS.contains(&word.to_uppercase().as_str()) {
+                out.push_str(&format!("{}{}{}", KEYWORD_COLOR, word, RESET_COLOR));
+            } else {
+                out.push_str(&word);
+            }
+        } else {
+            out.push(c);
+            i += 1;
+        }
     }
+    out
+}
+
+/// Completion candidates for the word ending at `pos`, returned with the byte offset where
+/// that word starts. Table and column names come from `schema`, so they track DDL.
+pub(crate) fn complete_sql(schema: &Schema, line: &str, pos: usize) -> (usize, Vec<String>) {
+    let before = &line[..pos];
+    let start = before
+        .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\\'))
+        .map_or(0, |i| i + before[i..].chars().next().map_or(1, |c| c.len_utf8()));
+    let prefix = &before[start..];
+    let previous = before[..start]
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ',')
+        .rfind(|w| !w.is_empty())
+        .map(|w| w.to_uppercase());
+
+    let mut tables: Vec<&str> = schema.tables().map(|t| t.name.as_str()).collect();
+    tables.sort_unstable();
+    let mut candidates: Vec<String> = Vec::new();
+    if previous.as_deref().is_some_and(|w| TABLE_CONTEXT.contains(&w)) {
+        candidates.extend(tables.iter().map(|t| t.to_string()));
+    } else {
+        // Columns of any table already named in the statement
+        let words: Vec<&str> = line
+            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .filter(|w| !w.is_empty())
+            .collect();
+        for table in schema.tables().filter(|t| words.contains(&t.name.as_str())) {
+            candidates.extend(table.columns.iter().map(|c| c.name.clone()));
+        }
+        let lowercase = !prefix.is_empty() && prefix.chars().all(|c| !c.is_uppercase());
+        candidates.extend(SQL_KEYWORDS.iter().map(|k| if lowercase { k.to_lowercase() } else { k.to_string() }));
+        candidates.extend(tables.iter().map(|t| t.to_string()));
+    }
+
+    let mut seen = HashSet::new();
+    candidates.retain(|c| c.to_lowercase().starts_with(&prefix.to_lowercase()) && seen.insert(c.clone()));
+    (start, candidates)
 }
 
 impl rustyline::Helper for ReplHelper {}
@@ -192,18 +889,69 @@ impl rustyline::hint::Hinter for ReplHelper {
     type Hint = String;
 }
 
-impl rustyline::validate::Validator for ReplHelper {}
+// Lines the shell handles itself rather than passing to the SQL parser
+fn unknown_setting(name: &str) -> DbError {
+    let names: Vec<&str> = SHELL_SETTINGS.iter().chain(SETTINGS).copied().collect();
+    DbError::ConfigurationError(format!("Unknown setting {}: expected one of {}", name, names.join(", ")))
+}
+
+fn contains_password(line: &str) -> bool {
+    line.to_uppercase().contains("PASSWORD")
+}
+
+fn is_shell_command(input: &str) -> bool {
+    let upper = input.trim().to_uppercase();
+    upper.starts_with('\\')
+        || upper.starts_with("PLUGIN")
+        || matches!(upper.trim_end_matches(';'), "EXIT" | "QUIT" | "HELP")
+}
+
+impl rustyline::validate::Validator for ReplHelper {
+    // Keeps reading lines until the statement is terminated by a semicolon outside a string
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        if input.trim().is_empty() || is_shell_command(input) {
+            return Ok(ValidationResult::Valid(None));
+        }
+        // Inside an unfinished /* comment, or one hiding the semicolon, the statement goes on
+        let Ok(input) = strip_comments(input) else {
+            return Ok(ValidationResult::Incomplete);
+        };
+        let quotes = input.chars().filter(|&c| c == '\'').count();
+        if quotes % 2 == 0 && input.trim_end().ends_with(';') {
+            Ok(ValidationResult::Valid(None))
+        } else {
+            Ok(ValidationResult::Incomplete)
+        }
+    }
+}
 
 impl rustyline::highlight::Highlighter for ReplHelper {
     fn highlight<'l>(&self, line: &'l str, _pos: usize) -> std::borrow::Cow<'l, str> {
-        std::borrow::Cow::Borrowed(line)
+        if self.color {
+            std::borrow::Cow::Owned(highlight_sql(line))
+        } else {
+            std::borrow::Cow::Borrowed(line)
+        }
     }
 
     fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
-        false
+        self.color
     }
 }
 
 impl rustyline::completion::Completer for ReplHelper {
     type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        match self.storage.read() {
+            Ok(storage) => Ok(complete_sql(storage.schema(), line, pos)),
+            Err(_) => Ok((pos, Vec::new())),
+        }
+    }
 }
\ No newline at end of file
@@ -14,10 +14,18 @@ pub struct BlockInfo {
     pub compression: CompressionType,
     pub serialized_size: Option<usize>,
     pub segment_path: Option<String>,
+    // Positions within the block holding NULL; the column file stores a placeholder there.
+    #[serde(default)]
+    pub nulls: Vec<usize>,
+    // XXH64 of the serialized block; absent for blocks written before checksums were added
+    #[serde(default)]
+    pub checksum: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BlockMetadata {
+    #[serde(default)]
+    pub table_name: String,
     pub column_name: String,
     pub data_type: DataType,
     pub blocks: Vec<BlockInfo>,
@@ -25,8 +33,9 @@ pub struct BlockMetadata {
 }
 
 impl BlockMetadata {
-    pub fn new(column_name: &str, data_type: DataType, data_dir: &str) -> Self {
+    pub fn new(table_name: &str, column_name: &str, data_type: DataType, data_dir: &str) -> Self {
         BlockMetadata {
+            table_name: table_name.to_string(),
             column_name: column_name.to_string(),
             data_type,
             blocks: Vec::new(),
@@ -34,8 +43,12 @@ impl BlockMetadata {
         }
     }
 
-    pub fn load(column_name: &str, data_type: DataType, data_dir: &str) -> Result<Self, DbError> {
-        let metadata_path = format!("{}/metadata/{}.json", data_dir, column_name);
+    pub fn path(data_dir: &str, table_name: &str, column_name: &str) -> String {
+        format!("{}/metadata/{}_{}.json", data_dir, table_name, column_name)
+    }
+
+    pub fn load(table_name: &str, column_name: &str, data_type: DataType, data_dir: &str) -> Result<Self, DbError> {
+        let metadata_path = Self::path(data_dir, table_name, column_name);
         if Path::new(&metadata_path).exists() {
             let contents = fs::read_to_string(&metadata_path)
                 .map_err(|e| DbError::IoError(e))?;
@@ -43,43 +56,33 @@ impl BlockMetadata {
                 .map_err(|e| DbError::SerializationError(e.to_string()))?;
             Ok(metadata)
         } else {
-            Ok(Self::new(column_name, data_type, data_dir))
+            Ok(Self::new(table_name, column_name, data_type, data_dir))
         }
     }
 
-    pub fn add_block(
-        &mut self,
-        min: Value,
-        max: Value,
-        offset: u64,
-        row_count: usize,
-        compression: CompressionType,
-        serialized_size: usize,
-        segment_path: &str,
-    ) -> Result<(), DbError> {
-        self.blocks.push(BlockInfo {
-            min,
-            max,
-            offset,
-            row_count,
-            compression,
-            serialized_size: Some(serialized_size),
-            segment_path: Some(segment_path.to_string()),
-        });
+    pub fn add_block(&mut self, block: BlockInfo) -> Result<(), DbError> {
+        self.blocks.push(block);
         self.save()?;
         Ok(())
     }
 
     pub fn save(&self) -> Result<(), DbError> {
         fs::create_dir_all(format!("{}/metadata", self.data_dir))?;
-        let metadata_path = format!("{}/metadata/{}.json", self.data_dir, self.column_name);
+        let metadata_path = Self::path(&self.data_dir, &self.table_name, &self.column_name);
         let contents = serde_json::to_string_pretty(self)
             .map_err(|e| DbError::SerializationError(e.to_string()))?;
-        fs::write(&metadata_path, contents)
+        // Write then rename, so a crash never leaves the block list half written
+        let tmp_path = format!("{}.tmp", metadata_path);
+        fs::write(&tmp_path, contents)
             .map_err(|e| DbError::IoError(e))?;
+        fs::rename(&tmp_path, &metadata_path)?;
         Ok(())
     }
 
+    pub fn row_count(&self) -> usize {
+        self.blocks.iter().map(|block| block.row_count).sum()
+    }
+
     pub fn get_blocks(&self, condition: Option<&Condition>) -> Vec<&BlockInfo> {
         self.blocks
             .iter()
@@ -1,15 +1,211 @@
-use crate::types::{DataType, DbError, Value};
+use crate::query::{collect_condition_columns, resolve_condition, widen_condition};
+use crate::query::evaluator::evaluate_condition_row;
+use crate::query::parser::parse_condition;
+use crate::types::{quote_identifier, DataType, DbError, Value};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use fs2::FileExt;
 
 pub mod metadata;
 
+pub const CATALOG_FILE: &str = "schema.json";
+/// Pseudo-column every table has: a BIGINT numbering its rows from 1 in insertion order. A
+/// row keeps its rowid until it is deleted, including across VACUUM, and a deleted rowid is
+/// never handed out again. `SELECT *` leaves it out; name it to read it.
+pub const ROWID: &str = "ROWID";
+
+static ROWID_COLUMN: std::sync::LazyLock<Column> = std::sync::LazyLock::new(|| Column::new(ROWID, DataType::Int64).not_null());
+/// Newest catalog file layout this build reads. Files written before the layout was versioned
+/// hold only the table map and count as version 0.
+pub const CATALOG_FORMAT_VERSION: u32 = 1;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Column {
     pub name: String,
     pub data_type: DataType,
+    #[serde(default = "default_nullable")]
+    pub is_nullable: bool,
+    #[serde(default)]
+    pub is_primary_key: bool,
+    #[serde(default)]
+    pub is_unique: bool,
+    // Condition text from `CHECK (...)`, evaluated against every row written to the table
+    #[serde(default)]
+    pub check: Option<String>,
+    #[serde(default)]
+    pub references: Option<ForeignKey>,
+    #[serde(default)]
+    pub default_value: Option<ColumnDefault>,
+    /// `ENCRYPTED`: blocks and logged values are stored encrypted under the key derived from
+    /// the `encryption_passphrase` setting. Encrypted blocks record no min/max, so range
+    /// conditions on the column read every block, and no index can be built on it: neither
+    /// the implicit ones on ID, Name and key columns nor any `CREATE INDEX`, range lookups
+    /// included. It cannot be a PRIMARY KEY, UNIQUE or REFERENCES column for the same reason.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// What a column's `DEFAULT` gives rows inserted without a value for it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ColumnDefault {
+    Literal(Value),
+    // Evaluated afresh for every inserted row
+    Expr(DefaultExpr),
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DefaultExpr {
+    Now,
+}
+
+impl DefaultExpr {
+    pub fn evaluate(&self) -> Value {
+        match self {
+            DefaultExpr::Now => Value::now(),
+        }
+    }
+
+    pub fn data_type(&self) -> DataType {
+        match self {
+            DefaultExpr::Now => DataType::Timestamp,
+        }
+    }
+
+    pub fn to_sql(&self) -> &'static str {
+        match self {
+            DefaultExpr::Now => "NOW()",
+        }
+    }
+}
+
+impl ColumnDefault {
+    /// The value for one inserted row.
+    pub fn evaluate(&self) -> Value {
+        match self {
+            ColumnDefault::Literal(value) => value.clone(),
+            ColumnDefault::Expr(expr) => expr.evaluate(),
+        }
+    }
+
+    pub fn to_sql(&self) -> String {
+        match self {
+            ColumnDefault::Literal(value) => value.to_sql_literal(),
+            ColumnDefault::Expr(expr) => expr.to_sql().to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum OnDelete {
+    Restrict,
+    Cascade,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ForeignKey {
+    pub reference_table: String,
+    pub reference_column: String,
+    pub on_delete: OnDelete,
+}
+
+/// Whether `ident`, a table or column name as written in a statement, names `name`. Unquoted
+/// identifiers match in any case; a double-quoted one must match exactly.
+pub fn identifier_matches(name: &str, ident: &str) -> bool {
+    match ident.strip_prefix('"').and_then(|i| i.strip_suffix('"')) {
+        Some(quoted) => name == quoted,
+        None => name.eq_ignore_ascii_case(ident),
+    }
+}
+
+fn default_nullable() -> bool {
+    true
+}
+
+impl Column {
+    pub fn new(name: &str, data_type: DataType) -> Self {
+        Column {
+            name: name.to_string(),
+            data_type,
+            is_nullable: true,
+            is_primary_key: false,
+            is_unique: false,
+            check: None,
+            references: None,
+            default_value: None,
+            encrypted: false,
+        }
+    }
+
+    pub fn not_null(mut self) -> Self {
+        self.is_nullable = false;
+        self
+    }
+
+    pub fn primary_key(mut self) -> Self {
+        self.is_primary_key = true;
+        self.is_nullable = false;
+        self
+    }
+
+    pub fn unique(mut self) -> Self {
+        self.is_unique = true;
+        self
+    }
+
+    pub fn encrypted(mut self) -> Self {
+        self.encrypted = true;
+        self
+    }
+
+    // Primary keys are implicitly NOT NULL
+    pub fn accepts_null(&self) -> bool {
+        self.is_nullable && !self.is_primary_key
+    }
+
+    // Primary keys are implicitly UNIQUE
+    pub fn requires_unique(&self) -> bool {
+        self.is_unique || self.is_primary_key
+    }
+
+    /// Whether storage keeps a hash index on the column without being asked: it does for
+    /// columns named ID or Name, unless they are encrypted.
+    pub fn has_implicit_index(&self) -> bool {
+        (self.name == "ID" || self.name == "Name") && !self.encrypted
+    }
+
+    /// The column definition as written in `CREATE TABLE`.
+    pub fn to_sql(&self) -> String {
+        let mut sql = format!("{} {}", quote_identifier(&self.name), self.data_type.sql_name());
+        if self.is_primary_key {
+            sql.push_str(" PRIMARY KEY");
+        } else if !self.is_nullable {
+            sql.push_str(" NOT NULL");
+        }
+        if self.is_unique {
+            sql.push_str(" UNIQUE");
+        }
+        if let Some(default) = &self.default_value {
+            sql.push_str(&format!(" DEFAULT {}", default.to_sql()));
+        }
+        if let Some(check) = &self.check {
+            sql.push_str(&format!(" CHECK ({})", check));
+        }
+        if self.encrypted {
+            sql.push_str(" ENCRYPTED");
+        }
+        if let Some(fk) = &self.references {
+            sql.push_str(&format!(
+                " REFERENCES {}({})",
+                quote_identifier(&fk.reference_table),
+                quote_identifier(&fk.reference_column)
+            ));
+            if fk.on_delete == OnDelete::Cascade {
+                sql.push_str(" ON DELETE CASCADE");
+            }
+        }
+        sql
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -17,12 +213,82 @@ pub struct Table {
     pub name: String,
     pub columns: Vec<Column>,
     pub row_count: u64,
+    // Indexes created with CREATE INDEX; the implicit ones on ID, Name and UNIQUE columns are not listed
+    #[serde(default)]
+    pub indexes: Vec<IndexDef>,
+    // Rowid the next inserted row gets; 0 in catalogs written before rowids existed, until
+    // storage numbers the rows already there
+    #[serde(default)]
+    pub next_rowid: u64,
+}
+
+impl Table {
+    /// The `CREATE TABLE` statement that recreates this table's definition.
+    pub fn create_sql(&self) -> String {
+        let columns = self.columns.iter().map(Column::to_sql).collect::<Vec<_>>();
+        format!("CREATE TABLE {} ({});", quote_identifier(&self.name), columns.join(", "))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum IndexKind {
+    // Inverted index over the words of a TEXT column, used by MATCH
+    FullText,
+    /// Keyed on the values of its columns, in order. Follows the left-prefix rule: it answers a
+    /// WHERE whose AND-ed equalities cover its first column, or its first two, and so on; an
+    /// equality on a later column alone, or any OR, cannot use it. One column is allowed.
+    Composite,
+}
+
+/// An index declared with `CREATE INDEX`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct IndexDef {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub kind: IndexKind,
+}
+
+impl IndexDef {
+    /// The `CREATE INDEX` statement that recreates this index on `table`.
+    pub fn to_sql(&self, table: &str) -> String {
+        let columns = self.columns.iter().map(|c| quote_identifier(c)).collect::<Vec<_>>();
+        let kind = match self.kind {
+            IndexKind::FullText => "FULLTEXT ",
+            IndexKind::Composite => "",
+        };
+        format!(
+            "CREATE {}INDEX {} ON {} ({});",
+            kind,
+            quote_identifier(&self.name),
+            quote_identifier(table),
+            columns.join(", ")
+        )
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Schema {
     pub tables: HashMap<String, Table>,
     pub data_dir: String,
+    // Bumped by every DDL statement and persisted with the tables
+    #[serde(default)]
+    pub version: u64,
+}
+
+// The catalog file: every table definition with its columns, constraints and declared indexes.
+// Implicit indexes are not listed, since they follow from the columns (ID and Name, and UNIQUE
+// or PRIMARY KEY ones).
+#[derive(Serialize, Deserialize)]
+struct CatalogFile {
+    format_version: u32,
+    schema_version: u64,
+    tables: HashMap<String, Table>,
+}
+
+#[derive(Deserialize)]
+struct CatalogHeader {
+    #[serde(default)]
+    format_version: u32,
 }
 
 impl Schema {
@@ -31,20 +297,95 @@ impl Schema {
         Ok(Schema {
             tables: HashMap::new(),
             data_dir: data_dir.to_string(),
+            version: 0,
         })
     }
 
-    pub fn add_table(&mut self, name: &str, columns: Vec<Column>) -> Result<(), DbError> {
-        if self.tables.contains_key(name) {
-            return Err(DbError::InvalidData(format!("Table {} already exists", name)));
+    /// Names differing only in case would make unquoted identifiers ambiguous, so a table may
+    /// not share its name with another table, nor a column with another of its columns.
+    pub fn add_table(&mut self, name: &str, mut columns: Vec<Column>) -> Result<(), DbError> {
+        if let Some(existing) = self.tables.values().find(|t| t.name.eq_ignore_ascii_case(name)) {
+            return Err(DbError::InvalidData(format!("Table {} already exists", existing.name)));
         }
         if columns.is_empty() {
             return Err(DbError::InvalidData("Table must have at least one column".to_string()));
         }
-        for col in &columns {
+        for (i, col) in columns.iter().enumerate() {
             if col.name.is_empty() {
                 return Err(DbError::InvalidData("Column name cannot be empty".to_string()));
             }
+            if columns[..i].iter().any(|c| c.name.eq_ignore_ascii_case(&col.name)) {
+                return Err(DbError::SchemaError(format!("Column {} appears twice in table {}", col.name, name)));
+            }
+            if col.name.eq_ignore_ascii_case(ROWID) {
+                return Err(DbError::SchemaError(format!("{} is reserved for the row identifier of every table", ROWID)));
+            }
+        }
+        // Foreign keys are stored with the names the parent declares
+        let own_names: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+        for fk in columns.iter_mut().filter_map(|c| c.references.as_mut()) {
+            let parent_columns = if identifier_matches(name, &fk.reference_table) {
+                fk.reference_table = name.to_string();
+                own_names.clone()
+            } else if let Some(parent) = self.get_table(&fk.reference_table) {
+                fk.reference_table = parent.name.clone();
+                parent.columns.iter().map(|c| c.name.clone()).collect()
+            } else {
+                continue;
+            };
+            if let Some(column) = parent_columns.iter().find(|c| identifier_matches(c, &fk.reference_column)) {
+                fk.reference_column = column.clone();
+            }
+        }
+        for col in &columns {
+            match &col.default_value {
+                Some(ColumnDefault::Literal(default)) => {
+                    if !default.matches_type(&col.data_type) {
+                        return Err(DbError::SchemaError(format!(
+                            "DEFAULT {} does not match type {:?} of {}.{}",
+                            default, col.data_type, name, col.name
+                        )));
+                    }
+                    if default.is_null() && !col.accepts_null() {
+                        return Err(DbError::SchemaError(format!(
+                            "DEFAULT NULL is not allowed for NOT NULL column {}.{}",
+                            name, col.name
+                        )));
+                    }
+                }
+                Some(ColumnDefault::Expr(expr)) if expr.data_type() != col.data_type => {
+                    return Err(DbError::SchemaError(format!(
+                        "DEFAULT {} returns {:?}, but {}.{} is {:?}",
+                        expr.to_sql(),
+                        expr.data_type(),
+                        name,
+                        col.name,
+                        col.data_type
+                    )));
+                }
+                _ => {}
+            }
+            if let Some(fk) = &col.references {
+                self.validate_foreign_key(name, &columns, col, fk)?;
+            }
+            // Keys are looked up by value, which an encrypted column does not keep in the clear
+            if col.encrypted && (col.requires_unique() || col.references.is_some()) {
+                return Err(DbError::SchemaError(format!(
+                    "ENCRYPTED column {}.{} cannot be a PRIMARY KEY, UNIQUE or REFERENCES another table",
+                    name, col.name
+                )));
+            }
+        }
+        for check in columns.iter().filter_map(|c| c.check.as_deref()) {
+            let condition = parse_condition(check)?;
+            for referenced in collect_condition_columns(&condition) {
+                if !columns.iter().any(|c| identifier_matches(&c.name, &referenced)) {
+                    return Err(DbError::SchemaError(format!(
+                        "CHECK ({}) references unknown column {}.{}",
+                        check, name, referenced
+                    )));
+                }
+            }
         }
         self.tables.insert(
             name.to_string(),
@@ -52,14 +393,232 @@ impl Schema {
                 name: name.to_string(),
                 columns,
                 row_count: 0,
+                indexes: Vec::new(),
+                next_rowid: 1,
             },
         );
-        self.save()?;
+        self.save_ddl()
+    }
+
+    /// Adds `index` to `table`, storing the column names as the table declares them. Index
+    /// names are unique per table, ignoring case.
+    pub fn add_index(&mut self, table: &str, mut index: IndexDef) -> Result<(), DbError> {
+        let table_def = self
+            .tables
+            .get_mut(table)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table)))?;
+        if let Some(existing) = table_def.indexes.iter().find(|i| i.name.eq_ignore_ascii_case(&index.name)) {
+            return Err(DbError::IndexError(format!("Index {} already exists on {}", existing.name, table)));
+        }
+        for name in index.columns.iter_mut() {
+            let column = table_def
+                .get_column(name)
+                .filter(|c| c.name != ROWID)
+                .ok_or_else(|| DbError::IndexError(format!("Column {}.{} not found", table, name)))?;
+            if column.encrypted {
+                return Err(DbError::IndexError(format!(
+                    "Index {} cannot cover {}.{}: encrypted columns cannot be indexed",
+                    index.name, table, column.name
+                )));
+            }
+            if index.kind == IndexKind::FullText && column.data_type != DataType::String {
+                return Err(DbError::IndexError(format!(
+                    "FULLTEXT index {} needs a TEXT column, but {}.{} is {:?}",
+                    index.name, table, column.name, column.data_type
+                )));
+            }
+            *name = column.name.clone();
+        }
+        if index.kind == IndexKind::FullText && index.columns.len() != 1 {
+            return Err(DbError::IndexError(format!("FULLTEXT index {} must cover exactly one column", index.name)));
+        }
+        if index.columns.is_empty() {
+            return Err(DbError::IndexError(format!("Index {} must cover at least one column", index.name)));
+        }
+        if let Some((_, column)) = index.columns.iter().enumerate().find(|(i, c)| index.columns[..*i].contains(c)) {
+            return Err(DbError::IndexError(format!("Column {} appears twice in index {}", column, index.name)));
+        }
+        table_def.indexes.push(index);
+        self.save_ddl()
+    }
+
+    /// Removes the index of `table` named `name` (ignoring case) and returns its definition.
+    pub fn remove_index(&mut self, table: &str, name: &str) -> Result<IndexDef, DbError> {
+        let table_def = self
+            .tables
+            .get_mut(table)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table)))?;
+        let pos = table_def
+            .indexes
+            .iter()
+            .position(|i| i.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| DbError::IndexError(format!("Index {} not found on {}", name, table)))?;
+        let index = table_def.indexes.remove(pos);
+        self.save_ddl()?;
+        Ok(index)
+    }
+
+    pub fn drop_table(&mut self, name: &str) -> Result<(), DbError> {
+        self.tables
+            .remove(name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", name)))?;
+        self.save_ddl()
+    }
+
+    fn validate_foreign_key(
+        &self,
+        table: &str,
+        columns: &[Column],
+        col: &Column,
+        fk: &ForeignKey,
+    ) -> Result<(), DbError> {
+        // A table may reference itself before it exists in the schema
+        let parent_columns = if fk.reference_table == table {
+            columns
+        } else {
+            &self
+                .get_table(&fk.reference_table)
+                .ok_or_else(|| {
+                    DbError::SchemaError(format!(
+                        "{}.{} references unknown table {}",
+                        table, col.name, fk.reference_table
+                    ))
+                })?
+                .columns
+        };
+        let parent_col = parent_columns
+            .iter()
+            .find(|c| identifier_matches(&c.name, &fk.reference_column))
+            .ok_or_else(|| {
+                DbError::SchemaError(format!(
+                    "{}.{} references unknown column {}.{}",
+                    table, col.name, fk.reference_table, fk.reference_column
+                ))
+            })?;
+        if parent_col.data_type != col.data_type {
+            return Err(DbError::SchemaError(format!(
+                "{}.{} and {}.{} have different types",
+                table, col.name, fk.reference_table, fk.reference_column
+            )));
+        }
+        if !parent_col.requires_unique() {
+            return Err(DbError::SchemaError(format!(
+                "{}.{} must be UNIQUE or a PRIMARY KEY to be referenced",
+                fk.reference_table, fk.reference_column
+            )));
+        }
         Ok(())
     }
 
+    /// Columns in any table holding a foreign key to `table`, as (child table, child column).
+    pub fn referencing_columns(&self, table: &str) -> Vec<(String, Column)> {
+        self.tables()
+            .flat_map(|t| {
+                t.columns
+                    .iter()
+                    .filter(|c| c.references.as_ref().is_some_and(|fk| fk.reference_table == table))
+                    .map(move |c| (t.name.clone(), c.clone()))
+            })
+            .collect()
+    }
+
+    pub fn drop_column(&mut self, table: &str, column: &str) -> Result<(), DbError> {
+        let table_def = self
+            .tables
+            .get_mut(table)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table)))?;
+        if table_def.get_column(column).is_none_or(|c| c.name == ROWID) {
+            return Err(DbError::InvalidData(format!("Column {}.{} not found", table, column)));
+        }
+        if table_def.columns.len() == 1 {
+            return Err(DbError::SchemaError(format!(
+                "Cannot drop {}.{}: a table must have at least one column",
+                table, column
+            )));
+        }
+        if let Some(index) = table_def.indexes.iter().find(|i| i.columns.iter().any(|c| c == column)) {
+            return Err(DbError::SchemaError(format!(
+                "Cannot drop {}.{}: index {} covers it, so drop the index first",
+                table, column, index.name
+            )));
+        }
+        table_def.columns.retain(|c| c.name != column);
+        self.save_ddl()
+    }
+
+    /// Renames a table, repointing foreign keys that reference it.
+    pub fn rename_table(&mut self, table: &str, new_name: &str) -> Result<(), DbError> {
+        if !self.tables.contains_key(table) {
+            return Err(DbError::InvalidData(format!("Table {} not found", table)));
+        }
+        if new_name.is_empty() {
+            return Err(DbError::InvalidData("Table name cannot be empty".to_string()));
+        }
+        // Changing only the case of the name is allowed
+        if let Some(other) = self.tables.values().find(|t| t.name != table && t.name.eq_ignore_ascii_case(new_name)) {
+            return Err(DbError::SchemaError(format!("Cannot rename {}: table {} already exists", table, other.name)));
+        }
+        if let Some(mut table_def) = self.tables.remove(table) {
+            table_def.name = new_name.to_string();
+            self.tables.insert(new_name.to_string(), table_def);
+        }
+        for col in self.tables.values_mut().flat_map(|t| t.columns.iter_mut()) {
+            if let Some(fk) = col.references.as_mut().filter(|fk| fk.reference_table == table) {
+                fk.reference_table = new_name.to_string();
+            }
+        }
+        self.save_ddl()
+    }
+
+    /// Renames a column, rewriting the table's CHECK conditions and any foreign keys that name it.
+    pub fn rename_column(&mut self, table: &str, column: &str, new_name: &str) -> Result<(), DbError> {
+        let table_def = self
+            .tables
+            .get_mut(table)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table)))?;
+        if table_def.get_column(column).is_none_or(|c| c.name == ROWID) {
+            return Err(DbError::InvalidData(format!("Column {}.{} not found", table, column)));
+        }
+        if new_name.is_empty() {
+            return Err(DbError::InvalidData("Column name cannot be empty".to_string()));
+        }
+        if new_name.eq_ignore_ascii_case(ROWID) {
+            return Err(DbError::SchemaError(format!("{} is reserved for the row identifier of every table", ROWID)));
+        }
+        if let Some(other) = table_def.columns.iter().find(|c| c.name != column && c.name.eq_ignore_ascii_case(new_name)) {
+            return Err(DbError::SchemaError(format!(
+                "Cannot rename {}.{}: column {} already exists",
+                table, column, other.name
+            )));
+        }
+        for col in table_def.columns.iter_mut() {
+            if col.name == column {
+                col.name = new_name.to_string();
+            }
+            if let Some(check) = col.check.as_mut() {
+                *check = rename_word(check, column, new_name);
+            }
+        }
+        for name in table_def.indexes.iter_mut().flat_map(|i| i.columns.iter_mut()).filter(|c| *c == column) {
+            *name = new_name.to_string();
+        }
+        for col in self.tables.values_mut().flat_map(|t| t.columns.iter_mut()) {
+            if let Some(fk) = col
+                .references
+                .as_mut()
+                .filter(|fk| fk.reference_table == table && fk.reference_column == column)
+            {
+                fk.reference_column = new_name.to_string();
+            }
+        }
+        self.save_ddl()
+    }
+
+    /// The table `name` identifies; see `identifier_matches`.
     pub fn get_table(&self, name: &str) -> Option<&Table> {
-        self.tables.get(name)
+        self.tables
+            .get(name)
+            .or_else(|| self.tables.values().find(|t| identifier_matches(&t.name, name)))
     }
 
     pub fn validate_row(&self, table: &str, values: &[Value]) -> Result<(), DbError> {
@@ -76,53 +635,211 @@ impl Schema {
         }
 
         for (value, col) in values.iter().zip(table_def.columns.iter()) {
+            if value.is_null() {
+                if !col.accepts_null() {
+                    return Err(DbError::ValidationError(format!(
+                        "Column {}.{} cannot be NULL",
+                        table, col.name
+                    )));
+                }
+                continue;
+            }
             if value.data_type() != col.data_type {
-                return Err(DbError::TypeMismatch);
+                return Err(DbError::TypeMismatch(format!(
+                    "Column {}.{} expects {}, got {} value {}",
+                    table,
+                    col.name,
+                    col.data_type.sql_name(),
+                    value.data_type().sql_name(),
+                    value.to_sql_literal()
+                )));
             }
         }
 
+        self.validate_checks(table_def, values)
+    }
+
+    fn validate_checks(&self, table_def: &Table, values: &[Value]) -> Result<(), DbError> {
+        let row: HashMap<String, Vec<Value>> = table_def
+            .columns
+            .iter()
+            .zip(values.iter())
+            .map(|(col, value)| (col.name.clone(), vec![value.clone()]))
+            .collect();
+        for check in table_def.columns.iter().filter_map(|c| c.check.as_deref()) {
+            let mut condition = parse_condition(check)?;
+            resolve_condition(&mut condition, &[table_def]);
+            widen_condition(&mut condition, &[table_def])?;
+            // A CHECK over a NULL is unknown rather than false, so it does not reject the row
+            let has_null = collect_condition_columns(&condition)
+                .iter()
+                .any(|col| row.get(col).is_some_and(|v| v[0].is_null()));
+            if !has_null && !evaluate_condition_row(&condition, &row, 0)? {
+                return Err(DbError::ValidationError(format!(
+                    "Row violates CHECK ({}) on table {}",
+                    check, table_def.name
+                )));
+            }
+        }
         Ok(())
     }
 
+    // Expands an INSERT with an explicit column list into a full row in table order,
+    // filling unlisted columns from their DEFAULT or NULL. An empty list means the values
+    // are positional.
+    pub fn complete_row(&self, table: &str, columns: &[String], values: Vec<Value>) -> Result<Vec<Value>, DbError> {
+        let table_def = self
+            .get_table(table)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table)))?;
+        if columns.is_empty() {
+            // A wrong number of values is left for validate_row to report
+            return Ok(values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| match table_def.columns.get(i) {
+                    Some(col) => value.widen_to(&col.data_type),
+                    None => value,
+                })
+                .collect());
+        }
+        if columns.len() != values.len() {
+            return Err(DbError::InvalidData(format!(
+                "Expected {} values, got {}",
+                columns.len(),
+                values.len()
+            )));
+        }
+        let mut row: Vec<Option<Value>> = vec![None; table_def.columns.len()];
+        for (name, value) in columns.iter().zip(values) {
+            let pos = table_def
+                .columns
+                .iter()
+                .position(|c| identifier_matches(&c.name, name))
+                .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table, name)))?;
+            row[pos] = Some(value);
+        }
+        row.into_iter()
+            .zip(table_def.columns.iter())
+            .map(|(value, col)| match (value, &col.default_value) {
+                (Some(value), _) => Ok(value.widen_to(&col.data_type)),
+                (None, Some(default)) => Ok(default.evaluate()),
+                (None, None) if col.accepts_null() => Ok(Value::Null),
+                (None, None) => Err(DbError::ValidationError(format!(
+                    "Column {}.{} requires a value: it is NOT NULL and has no DEFAULT",
+                    table, col.name
+                ))),
+            })
+            .collect()
+    }
+
     pub fn tables(&self) -> impl Iterator<Item = &Table> {
         self.tables.values()
     }
 
+    /// Schema version, incremented by every CREATE, DROP or ALTER TABLE.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Rewrites the catalog file. Written to a temporary file and renamed over the old one, so
+    /// a crash leaves either the old catalog or the new one, never a mix.
     pub fn save(&self) -> Result<(), DbError> {
-        let path = format!("{}/schema.json", self.data_dir);
-        let file = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&path)?;
-        file.lock_exclusive()?;
-        let json = serde_json::to_string_pretty(&self.tables)
+        let path = format!("{}/{}", self.data_dir, CATALOG_FILE);
+        let catalog = CatalogFile {
+            format_version: CATALOG_FORMAT_VERSION,
+            schema_version: self.version,
+            tables: self.tables.clone(),
+        };
+        let json = serde_json::to_string_pretty(&catalog)
             .map_err(|e| DbError::SerializationError(e.to_string()))?;
-        fs::write(&path, json)?;
-        fs2::FileExt::unlock(&file)?;
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, &path)?;
         Ok(())
     }
 
+    // Saves a change to the table definitions as a new schema version
+    fn save_ddl(&mut self) -> Result<(), DbError> {
+        self.version += 1;
+        self.save()
+    }
+
+    /// Reads the catalog file, failing with `VersionError` when a newer build wrote it.
     pub fn load(data_dir: &str) -> Result<Self, DbError> {
-        let path = format!("{}/schema.json", data_dir);
+        let path = format!("{}/{}", data_dir, CATALOG_FILE);
         if !std::path::Path::new(&path).exists() {
             return Self::new_schema(data_dir);
         }
         let json = fs::read_to_string(&path)?;
-        let tables: HashMap<String, Table> = serde_json::from_str(&json)
-            .map_err(|e| DbError::SerializationError(e.to_string()))?;
+        let invalid = |e: serde_json::Error| DbError::SerializationError(format!("Invalid {}: {}", path, e));
+        let header: CatalogHeader = serde_json::from_str(&json).map_err(invalid)?;
+        let (tables, version) = match header.format_version {
+            0 => (serde_json::from_str(&json).map_err(invalid)?, 0),
+            CATALOG_FORMAT_VERSION => {
+                let catalog: CatalogFile = serde_json::from_str(&json).map_err(invalid)?;
+                (catalog.tables, catalog.schema_version)
+            }
+            newer => {
+                return Err(DbError::VersionError(format!(
+                    "{} has catalog format {}, but this build reads up to {}",
+                    path, newer, CATALOG_FORMAT_VERSION
+                )))
+            }
+        };
         Ok(Schema {
             tables,
             data_dir: data_dir.to_string(),
+            version,
         })
     }
 }
 
 impl Table {
+    /// The column `name` identifies; see `identifier_matches`. `ROWID` gives the rowid
+    /// pseudo-column, which `columns` does not list.
     pub fn get_column(&self, name: &str) -> Option<&Column> {
-        self.columns.iter().find(|c| c.name == name)
+        self.columns
+            .iter()
+            .find(|c| c.name == name)
+            .or_else(|| self.columns.iter().find(|c| identifier_matches(&c.name, name)))
+            .or_else(|| identifier_matches(ROWID, name).then_some(&*ROWID_COLUMN))
     }
 
-    pub fn increment_row_count(&mut self) {
-        self.row_count += 1;
+    /// The columns storage keeps a file for: the declared ones, then the rowid.
+    pub fn stored_columns(&self) -> impl Iterator<Item = &Column> {
+        self.columns.iter().chain(std::iter::once(&*ROWID_COLUMN))
+    }
+}
+
+// Replaces each whole word `from` in `text` with `to`, leaving quoted literals alone
+fn rename_word(text: &str, from: &str, to: &str) -> String {
+    let mut renamed = String::with_capacity(text.len());
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+    let flush = |word: &mut String, renamed: &mut String| {
+        renamed.push_str(if word.eq_ignore_ascii_case(from) { to } else { word });
+        word.clear();
+    };
+    for c in text.chars() {
+        match (quote, c) {
+            (Some(q), c) => {
+                renamed.push(c);
+                if c == q {
+                    quote = None;
+                }
+            }
+            (None, '\'') | (None, '"') => {
+                flush(&mut word, &mut renamed);
+                renamed.push(c);
+                quote = Some(c);
+            }
+            (None, c) if c.is_whitespace() => {
+                flush(&mut word, &mut renamed);
+                renamed.push(c);
+            }
+            (None, c) => word.push(c),
+        }
     }
+    flush(&mut word, &mut renamed);
+    renamed
 }
\ No newline at end of file
@@ -0,0 +1,168 @@
+use crate::query::parser::{split_statements, strip_comments};
+use crate::query::planner::QueryEngine;
+use crate::storage::SharedStorage;
+use crate::types::{DbError, SecurityContext};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+/// How long a connection may wait between request lines before it is closed.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Longest request line accepted; a client that sends more is disconnected.
+pub const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+/// Serves SQL over TCP with a line protocol.
+///
+/// Each line a client sends holds one or more statements separated by `;`. Every statement
+/// gets one response line: its result as a JSON array of row objects (the REPL's JSON format,
+/// `[]` for statements without rows), or `{"error": {"code": "...", "message": "..."}}` with
+/// the code and message of `DbError::to_json`. Each connection has its own
+/// transaction, rolled back if the client disconnects before COMMIT, and must LOGIN first
+/// once users exist. Plugin query hooks do not run for server statements. Reads and inserts
+/// from different connections run concurrently; see `StorageManager` for what waits on what.
+pub struct Server {
+    listener: TcpListener,
+    storage: SharedStorage,
+    max_connections: usize,
+    idle_timeout: Option<Duration>,
+    active: Arc<AtomicUsize>,
+}
+
+// One of the `max_connections` slots, given back when the connection's thread ends, even by
+// a panic
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    pub fn bind(addr: SocketAddr, storage: SharedStorage) -> Result<Self, DbError> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| DbError::NetworkError(format!("Cannot listen on {}: {}", addr, e)))?;
+        Ok(Server {
+            listener,
+            storage,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            active: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Connections beyond `max_connections` get an error line and are closed.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Connections that send nothing for `idle_timeout` are closed, freeing their slot; `None`
+    /// lets them stay idle forever.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DbError> {
+        self.listener.local_addr().map_err(|e| DbError::NetworkError(e.to_string()))
+    }
+
+    /// Accepts connections forever, serving each on its own thread.
+    pub fn run(&self) -> Result<(), DbError> {
+        log::info!("Listening on {}", self.local_addr()?);
+        for stream in self.listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let taken = self.active.fetch_add(1, Ordering::SeqCst);
+            let slot = ConnectionSlot(Arc::clone(&self.active));
+            if taken >= self.max_connections {
+                let refused = DbError::NetworkError(format!("Too many connections (limit {})", self.max_connections));
+                let _ = writeln!(stream, "{}", error_response(&refused));
+                continue;
+            }
+            if let Err(e) = stream.set_read_timeout(self.idle_timeout) {
+                log::warn!("Failed to set the idle timeout of a connection: {}", e);
+                continue;
+            }
+            let storage = Arc::clone(&self.storage);
+            thread::spawn(move || {
+                let _slot = slot;
+                let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "unknown".to_string());
+                if let Err(e) = handle_connection(stream, storage) {
+                    log::warn!("Connection from {} closed: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Listens on `addr` and serves clients until the process exits.
+pub fn serve(addr: SocketAddr, storage: SharedStorage) -> Result<(), DbError> {
+    Server::bind(addr, storage)?.run()
+}
+
+fn handle_connection(stream: TcpStream, storage: SharedStorage) -> Result<(), DbError> {
+    let network_error = |e: std::io::Error| DbError::NetworkError(e.to_string());
+    let mut engine = QueryEngine::new(Arc::clone(&storage));
+    if !storage.read().map_err(|e| DbError::ConcurrencyError(e.to_string()))?.users().is_empty() {
+        engine.set_security_context(Some(SecurityContext::new()));
+    }
+
+    let mut writer = stream.try_clone().map_err(network_error)?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader
+            .by_ref()
+            .take(MAX_LINE_BYTES as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .map_err(network_error)?;
+        if read == 0 {
+            return Ok(()); // client disconnected
+        }
+        if line.len() > MAX_LINE_BYTES {
+            let too_long = DbError::NetworkError(format!("Request line longer than {} bytes", MAX_LINE_BYTES));
+            let _ = writeln!(writer, "{}", error_response(&too_long));
+            return Err(too_long);
+        }
+        let text = match std::str::from_utf8(&line) {
+            Ok(text) => text,
+            Err(_) => {
+                let malformed = DbError::NetworkError("Request is not valid UTF-8".to_string());
+                writeln!(writer, "{}", error_response(&malformed)).map_err(network_error)?;
+                continue;
+            }
+        };
+        let sql = match strip_comments(text) {
+            Ok(sql) => sql,
+            Err(e) => {
+                writeln!(writer, "{}", error_response(&e)).map_err(network_error)?;
+                continue;
+            }
+        };
+        for statement in split_statements(&sql) {
+            let response = engine
+                .prepare(statement)
+                .and_then(|query| engine.execute_audited(statement, query))
+                .and_then(|result| result.to_json())
+                .unwrap_or_else(|e| error_response(&e));
+            writeln!(writer, "{}", response).map_err(network_error)?;
+        }
+    }
+}
+
+fn error_response(error: &DbError) -> String {
+    serde_json::json!({ "error": error.to_json() }).to_string()
+}
\ No newline at end of file
@@ -26,7 +26,11 @@ impl Block {
         let data_type = values[0].data_type();
         for value in &values {
             if value.data_type() != data_type {
-                return Err(DbError::TypeMismatch);
+                return Err(DbError::TypeMismatch(format!(
+                    "Block of {} values cannot hold {}",
+                    data_type.sql_name(),
+                    value.to_sql_literal()
+                )));
             }
         }
         Ok(Block {
@@ -65,9 +69,11 @@ fn estimate_block_size(data_type: &DataType, compression: CompressionType) -> us
     match (data_type, compression) {
         (DataType::Int32, CompressionType::Rle) => 5, // 1 byte run length + 4 bytes value
         (DataType::Float32, CompressionType::Rle) => 5,
+        (DataType::Int64 | DataType::Timestamp, CompressionType::Rle) => 9,
         (DataType::String, CompressionType::Rle) => 9, // 1 byte run length + 8 bytes length + min 1 byte string
         (DataType::Int32, CompressionType::None) => 4,
         (DataType::Float32, CompressionType::None) => 4,
+        (DataType::Int64 | DataType::Timestamp, CompressionType::None) => 8,
         (DataType::String, CompressionType::None) => 9,
         (DataType::String, CompressionType::Dictionary) => 8, // At least one ID
         _ => 1, // Fallback for invalid combinations
@@ -0,0 +1,110 @@
+use crate::types::Value;
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_COLUMN_CACHE_CAPACITY: usize = 64;
+
+type ColumnKey = (String, String);
+
+/// Least-recently-used cache of decoded column files, keyed by (table, column).
+#[derive(Debug)]
+pub struct ColumnCache {
+    capacity: usize,
+    entries: HashMap<ColumnKey, Vec<Value>>,
+    // Keys ordered from least to most recently used
+    order: VecDeque<ColumnKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ColumnCache {
+    pub fn new(capacity: usize) -> Self {
+        ColumnCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, table: &str, column: &str) -> Option<Vec<Value>> {
+        let key = (table.to_string(), column.to_string());
+        match self.entries.get(&key) {
+            Some(values) => {
+                let values = values.clone();
+                self.touch(&key);
+                self.hits += 1;
+                Some(values)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, table: &str, column: &str, values: Vec<Value>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (table.to_string(), column.to_string());
+        if self.entries.insert(key.clone(), values).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        self.evict();
+    }
+
+    pub fn invalidate(&mut self, table: &str, column: &str) {
+        let key = (table.to_string(), column.to_string());
+        if self.entries.remove(&key).is_some() {
+            self.order.retain(|k| *k != key);
+        }
+    }
+
+    pub fn invalidate_table(&mut self, table: &str) {
+        self.entries.retain(|(t, _), _| t != table);
+        self.order.retain(|(t, _)| t != table);
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Hits and misses since the cache was created.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    fn touch(&mut self, key: &ColumnKey) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(pos).unwrap();
+            self.order.push_back(key);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(key) => {
+                    self.entries.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -2,12 +2,16 @@ use crate::schema::metadata::{BlockMetadata, BlockInfo};
 use crate::storage::block::Block;
 use crate::storage::buffer::BufferManager;
 use crate::storage::compression::compress;
+use crate::storage::encryption::{missing_key, ColumnCipher};
 use crate::types::{CompressionType, DbError, Value};
 use crate::schema::Column;
 use crate::query::Condition;
 use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct ColumnStore {
@@ -15,12 +19,16 @@ pub struct ColumnStore {
     pub metadata: BlockMetadata,
     pub data_dir: String,
     pub file_path: String, // Single file for this column
+    // Key for an encrypted column; reads and writes of one opened without it fail
+    cipher: Option<Arc<ColumnCipher>>,
+    // Blocks decoded since the store was opened, to check how much of the column a read touched
+    blocks_decoded: AtomicUsize,
 }
 
 impl ColumnStore {
-    pub fn new(column: &Column, data_dir: &str) -> Result<Self, DbError> {
-        let file_path = format!("{}/columns/{}.dat", data_dir, column.name);
-        let metadata = BlockMetadata::load(&column.name, column.data_type.clone(), data_dir)?;
+    pub fn new(table_name: &str, column: &Column, data_dir: &str) -> Result<Self, DbError> {
+        let file_path = Self::path(data_dir, table_name, &column.name);
+        let metadata = BlockMetadata::load(table_name, &column.name, column.data_type.clone(), data_dir)?;
         if !Path::new(&file_path).exists() {
             fs::create_dir_all(format!("{}/columns", data_dir))?;
             File::create(&file_path)?;
@@ -30,51 +38,188 @@ impl ColumnStore {
             metadata,
             data_dir: data_dir.to_string(),
             file_path,
+            cipher: None,
+            blocks_decoded: AtomicUsize::new(0),
         })
     }
 
+    /// Number of blocks decoded since the store was opened.
+    pub fn blocks_decoded(&self) -> usize {
+        self.blocks_decoded.load(Ordering::Relaxed)
+    }
+
+    /// Gives an encrypted column the key its blocks are encrypted under. Unencrypted columns ignore it.
+    pub fn set_cipher(&mut self, cipher: Option<Arc<ColumnCipher>>) {
+        if self.column.encrypted {
+            self.cipher = cipher;
+        }
+    }
+
+    // The key of an encrypted column, None for a plain one
+    fn cipher(&self) -> Result<Option<&ColumnCipher>, DbError> {
+        match (&self.cipher, self.column.encrypted) {
+            (_, false) => Ok(None),
+            (Some(cipher), true) => Ok(Some(cipher)),
+            (None, true) => Err(missing_key(&self.metadata.table_name, &self.column.name)),
+        }
+    }
+
+    pub fn path(data_dir: &str, table_name: &str, column_name: &str) -> String {
+        format!("{}/columns/{}_{}.dat", data_dir, table_name, column_name)
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.metadata.row_count()
+    }
+
+    /// Moves the column file and its block metadata to the paths for `table_name.column_name`.
+    pub fn rename(&mut self, table_name: &str, column_name: &str) -> Result<(), DbError> {
+        let file_path = Self::path(&self.data_dir, table_name, column_name);
+        let old_metadata_path = BlockMetadata::path(&self.data_dir, &self.metadata.table_name, &self.metadata.column_name);
+        fs::rename(&self.file_path, &file_path)?;
+        self.metadata.table_name = table_name.to_string();
+        self.metadata.column_name = column_name.to_string();
+        for block in self.metadata.blocks.iter_mut().filter(|b| b.segment_path.is_some()) {
+            block.segment_path = Some(file_path.clone());
+        }
+        self.metadata.save()?;
+        if Path::new(&old_metadata_path).exists() {
+            fs::remove_file(&old_metadata_path)?;
+        }
+        self.column.name = column_name.to_string();
+        self.file_path = file_path;
+        Ok(())
+    }
+
+    // Drops trailing blocks until at most `row_count` rows remain, returning the resulting count.
+    pub fn truncate_blocks(&mut self, row_count: usize) -> Result<usize, DbError> {
+        while self.row_count() > row_count {
+            self.metadata.blocks.pop();
+        }
+        self.metadata.save()?;
+        Ok(self.row_count())
+    }
+
+    /// Forces the column file and its block list to disk; appends only flush them to the OS.
+    pub fn sync(&self) -> Result<(), DbError> {
+        let mut paths = vec![self.file_path.clone()];
+        // The block list is only written once the column has a block
+        if !self.metadata.blocks.is_empty() {
+            paths.push(BlockMetadata::path(&self.data_dir, &self.metadata.table_name, &self.metadata.column_name));
+        }
+        for path in paths {
+            File::open(&path)
+                .and_then(|file| file.sync_all())
+                .map_err(|e| DbError::DurabilityError(format!("Cannot sync {}: {}", path, e)))?;
+        }
+        Ok(())
+    }
+
     pub fn append(
         &mut self,
         values: &[Value],
         compression: CompressionType,
     ) -> Result<u64, DbError> {
-        for value in values {
-            if value.data_type() != self.column.data_type {
-                return Err(DbError::TypeMismatch);
-            }
-        }
-        let block = Block::new(values.to_vec(), compression.clone())?;
-        let min = values.iter().min_by(|a, b| a.cmp(b)).cloned().unwrap_or(Value::Int32(0));
-        let max = values.iter().max_by(|a, b| a.cmp(b)).cloned().unwrap_or(Value::Int32(0));
-        let serialized = compress(&block.values, compression.clone())?;
-        let serialized_size = serialized.len();
-
+        let (serialized, mut block_info) = self.encode_block(values, compression)?;
         let mut file = OpenOptions::new()
-            .write(true)
             .append(true)
             .open(&self.file_path)?;
         let offset = file.seek(SeekFrom::End(0))?;
         file.write_all(&serialized)?;
         file.flush()?;
 
-        self.metadata.add_block(
+        block_info.offset = offset;
+        self.metadata.add_block(block_info)?;
+        Ok(offset)
+    }
+
+    /// Rewrites the column file to hold exactly `values`, in blocks of at most `block_rows`,
+    /// leaving out any bytes no block refers to. The new file is written beside the old one
+    /// and renamed over it, so readers of the path never see a partly written file.
+    pub fn rewrite(&mut self, values: &[Value], compression: CompressionType, block_rows: usize) -> Result<(), DbError> {
+        let tmp_path = format!("{}.tmp", self.file_path);
+        let mut file = File::create(&tmp_path)?;
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        for chunk in values.chunks(block_rows.max(1)) {
+            let (serialized, mut block_info) = self.encode_block(chunk, compression.clone())?;
+            file.write_all(&serialized)?;
+            block_info.offset = offset;
+            offset += serialized.len() as u64;
+            blocks.push(block_info);
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.file_path)?;
+        self.metadata.blocks = blocks;
+        self.metadata.save()
+    }
+
+    // Serializes one block; the caller fills in the offset it is written at
+    fn encode_block(&self, values: &[Value], compression: CompressionType) -> Result<(Vec<u8>, BlockInfo), DbError> {
+        for value in values {
+            if !value.matches_type(&self.column.data_type) {
+                return Err(DbError::TypeMismatch(format!(
+                    "Column {} stores {}, got {}",
+                    self.column.name,
+                    self.column.data_type.sql_name(),
+                    value.to_sql_literal()
+                )));
+            }
+        }
+        let nulls: Vec<usize> = values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| value.is_null())
+            .map(|(i, _)| i)
+            .collect();
+        let stored: Vec<Value> = values
+            .iter()
+            .map(|value| {
+                if value.is_null() {
+                    Value::placeholder(&self.column.data_type)
+                } else {
+                    value.clone()
+                }
+            })
+            .collect();
+        let block = Block::new(stored, compression.clone())?;
+        let cipher = self.cipher()?;
+        // Block ranges only cover non-null values; an all-null block has a NULL range. Encrypted
+        // blocks keep none, since the range would give their values away.
+        let (min, max) = match cipher {
+            Some(_) => (Value::Null, Value::Null),
+            None => (
+                values.iter().filter(|v| !v.is_null()).min().cloned().unwrap_or(Value::Null),
+                values.iter().filter(|v| !v.is_null()).max().cloned().unwrap_or(Value::Null),
+            ),
+        };
+        let mut serialized = compress(&block.values, compression.clone())?;
+        if let Some(cipher) = cipher {
+            serialized = cipher.encrypt(&serialized)?;
+        }
+        let serialized_size = serialized.len();
+        let block_info = BlockInfo {
             min,
             max,
-            offset,
-            values.len(),
+            offset: 0,
+            row_count: values.len(),
             compression,
-            serialized_size,
-            &self.file_path,
-        )?;
-        Ok(offset)
+            serialized_size: Some(serialized_size),
+            segment_path: Some(self.file_path.clone()),
+            nulls,
+            checksum: Some(checksum(&serialized)),
+        };
+        Ok((serialized, block_info))
     }
 
-    pub fn read(&self, condition: Option<&Condition>, buffer: &mut BufferManager) -> Result<Vec<Value>, DbError> {
-        let blocks = self.metadata.get_blocks(condition);
+    pub fn read(&self, condition: Option<&Condition>, buffer: &BufferManager) -> Result<Vec<Value>, DbError> {
+        // Encrypted blocks have no range to skip them by
+        let blocks = self.metadata.get_blocks(condition.filter(|_| !self.column.encrypted));
         let mut values = Vec::new();
         for block_info in blocks {
             match self.read_block(block_info, buffer) {
                 Ok(block) => values.extend(block.values),
+                Err(e @ DbError::SecurityError(_)) => return Err(e),
                 Err(e) => {
                     log::warn!("Failed to read block at offset {}: {}", block_info.offset, e);
                     continue;
@@ -84,7 +229,59 @@ impl ColumnStore {
         Ok(values)
     }
 
-    pub fn read_block(&self, block_info: &BlockInfo, _buffer: &mut BufferManager) -> Result<Block, DbError> {
+    pub fn read_block(&self, block_info: &BlockInfo, _buffer: &BufferManager) -> Result<Block, DbError> {
+        self.decode_block(block_info)
+    }
+
+    /// Streams the column block by block, followed by `pending` rows not yet flushed.
+    pub fn scan<'a>(&'a self, pending: &'a [Value]) -> ColumnScan<'a> {
+        ColumnScan {
+            store: Some(self),
+            next_block: 0,
+            current: Vec::new().into_iter(),
+            pending: pending.iter(),
+            error: None,
+        }
+    }
+
+    /// Checks every block against its stored checksum and confirms it decodes to the recorded
+    /// number of rows, returning the column's row count. Blocks written before checksums were
+    /// recorded are only checked for decoding. Problems are reported as `IntegrityError`, except
+    /// that an encrypted column read without its key fails with `SecurityError`.
+    pub fn verify(&self) -> Result<usize, DbError> {
+        let cipher = self.cipher()?;
+        let name = format!("{}.{}", self.metadata.table_name, self.column.name);
+        let mut rows = 0;
+        for block_info in &self.metadata.blocks {
+            let corrupt = |reason: String| {
+                DbError::IntegrityError(format!("{}: block at offset {} {}", name, block_info.offset, reason))
+            };
+            let data = self.read_raw_block(block_info).map_err(|e| corrupt(format!("cannot be read: {}", e)))?;
+            if let Some(expected) = block_info.checksum {
+                let actual = checksum(&data);
+                if actual != expected {
+                    return Err(corrupt(format!("has checksum {:016x}, expected {:016x}", actual, expected)));
+                }
+            }
+            let data = match cipher {
+                Some(cipher) => cipher.decrypt(&data).map_err(|e| corrupt(format!("does not decrypt: {}", e)))?,
+                None => data,
+            };
+            let block = Block::deserialize(&data, &self.column.data_type, block_info.compression.clone())
+                .map_err(|e| corrupt(format!("does not decode: {}", e)))?;
+            if block.values.len() != block_info.row_count {
+                return Err(corrupt(format!(
+                    "holds {} values, expected {}",
+                    block.values.len(),
+                    block_info.row_count
+                )));
+            }
+            rows += block_info.row_count;
+        }
+        Ok(rows)
+    }
+
+    fn read_raw_block(&self, block_info: &BlockInfo) -> Result<Vec<u8>, DbError> {
         let mut file = File::open(&self.file_path).map_err(|e| {
             DbError::IoError(std::io::Error::new(
                 e.kind(),
@@ -97,15 +294,81 @@ impl ColumnStore {
         })?;
         let mut data = vec![0u8; size];
         file.read_exact(&mut data)?;
-        Block::deserialize(&data, &self.column.data_type, block_info.compression.clone())
+        Ok(data)
     }
 
-    pub fn clear(&mut self) -> Result<(), DbError> {
-        self.metadata.blocks.clear();
-        self.metadata.save()?;
+    fn decode_block(&self, block_info: &BlockInfo) -> Result<Block, DbError> {
+        self.blocks_decoded.fetch_add(1, Ordering::Relaxed);
+        let mut data = self.read_raw_block(block_info)?;
+        if let Some(cipher) = self.cipher()? {
+            data = cipher.decrypt(&data)?;
+        }
+        let mut block = Block::deserialize(&data, &self.column.data_type, block_info.compression.clone())?;
+        for &i in &block_info.nulls {
+            if let Some(value) = block.values.get_mut(i) {
+                *value = Value::Null;
+            }
+        }
+        Ok(block)
+    }
+}
 
-        // Truncate the column file
-        File::create(&self.file_path)?;
-        Ok(())
+/// XXH64 of a block's bytes as written to the column file.
+pub fn checksum(data: &[u8]) -> u64 {
+    let mut hasher = twox_hash::XxHash64::with_seed(0);
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// Lazily decoded values of one column: only the block being read is held in memory.
+pub struct ColumnScan<'a> {
+    store: Option<&'a ColumnStore>,
+    next_block: usize,
+    current: std::vec::IntoIter<Value>,
+    pending: std::slice::Iter<'a, Value>,
+    error: Option<DbError>,
+}
+
+impl ColumnScan<'_> {
+    /// A scan that yields `error` and then ends, for columns that cannot be read at all.
+    pub fn failed(error: DbError) -> Self {
+        ColumnScan {
+            store: None,
+            next_block: 0,
+            current: Vec::new().into_iter(),
+            pending: [].iter(),
+            error: Some(error),
+        }
+    }
+}
+
+impl Iterator for ColumnScan<'_> {
+    type Item = Result<Value, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            return Some(Err(error));
+        }
+        loop {
+            if let Some(value) = self.current.next() {
+                return Some(Ok(value));
+            }
+            let store = match self.store {
+                Some(store) if self.next_block < store.metadata.blocks.len() => store,
+                _ => break,
+            };
+            let block_info = &store.metadata.blocks[self.next_block];
+            self.next_block += 1;
+            match store.decode_block(block_info) {
+                Ok(block) => self.current = block.values.into_iter(),
+                Err(e) => {
+                    // A block that cannot be decoded ends the scan rather than shifting later rows
+                    self.store = None;
+                    self.pending = [].iter();
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.pending.next().cloned().map(Ok)
     }
 }
\ No newline at end of file
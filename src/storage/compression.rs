@@ -11,10 +11,12 @@ pub fn compress(values: &[Value], compression: CompressionType) -> Result<Vec<u8
                 match value {
                     Value::Int32(i) => buffer.write_i32::<LittleEndian>(*i)?,
                     Value::Float32(f) => buffer.write_f32::<LittleEndian>(f.0)?,
+                    Value::Int64(i) | Value::Timestamp(i) => buffer.write_i64::<LittleEndian>(*i)?,
                     Value::String(s) => {
                         buffer.write_u64::<LittleEndian>(s.len() as u64)?;
                         buffer.extend_from_slice(s.as_bytes());
                     }
+                    Value::Null => return Err(null_not_encodable()),
                 }
             }
             Ok(buffer)
@@ -27,7 +29,8 @@ pub fn compress(values: &[Value], compression: CompressionType) -> Result<Vec<u8
             let mut current = &values[0];
             let mut count = 1;
             for value in values.iter().skip(1) {
-                if value == current {
+                // A run's length is stored in one byte, so longer runs are split
+                if value == current && count < 255 {
                     count += 1;
                 } else {
                     write_rle_value(&mut buffer, current, count)?;
@@ -74,14 +77,22 @@ fn write_rle_value(buffer: &mut Vec<u8>, value: &Value, count: usize) -> Result<
     match value {
         Value::Int32(i) => buffer.write_i32::<LittleEndian>(*i)?,
         Value::Float32(f) => buffer.write_f32::<LittleEndian>(f.0)?,
+        Value::Int64(i) | Value::Timestamp(i) => buffer.write_i64::<LittleEndian>(*i)?,
         Value::String(s) => {
             buffer.write_u64::<LittleEndian>(s.len() as u64)?;
             buffer.extend_from_slice(s.as_bytes());
         }
+        Value::Null => return Err(null_not_encodable()),
     }
     Ok(())
 }
 
+// Column files never hold NULL directly; ColumnStore swaps in a typed placeholder
+// and records null positions in the block metadata.
+fn null_not_encodable() -> DbError {
+    DbError::InvalidData("NULL values cannot be encoded in a column block".to_string())
+}
+
 pub fn decompress(data: &[u8], compression: CompressionType, data_type: &DataType) -> Result<Vec<Value>, DbError> {
     match compression {
         CompressionType::None => {
@@ -99,6 +110,16 @@ pub fn decompress(data: &[u8], compression: CompressionType, data_type: &DataTyp
                             .map_err(|e| DbError::SerializationError(e.to_string()))?;
                         values.push(Value::Float32(ordered_float::OrderedFloat(value)));
                     }
+                    DataType::Timestamp => {
+                        let value = cursor.read_i64::<LittleEndian>()
+                            .map_err(|e| DbError::SerializationError(e.to_string()))?;
+                        values.push(Value::Timestamp(value));
+                    }
+                    DataType::Int64 => {
+                        let value = cursor.read_i64::<LittleEndian>()
+                            .map_err(|e| DbError::SerializationError(e.to_string()))?;
+                        values.push(Value::Int64(value));
+                    }
                     DataType::String => {
                         let len = cursor.read_u64::<LittleEndian>()
                             .map_err(|e| DbError::SerializationError(e.to_string()))? as usize;
@@ -108,6 +129,7 @@ pub fn decompress(data: &[u8], compression: CompressionType, data_type: &DataTyp
                             .map_err(|e| DbError::SerializationError(e.to_string()))?;
                         values.push(Value::String(s));
                     }
+                    DataType::Null => return Err(null_not_encodable()),
                 }
             }
             Ok(values)
@@ -136,6 +158,20 @@ pub fn decompress(data: &[u8], compression: CompressionType, data_type: &DataTyp
                             values.push(Value::Float32(ordered_float::OrderedFloat(value)));
                         }
                     }
+                    DataType::Timestamp => {
+                        let value = cursor.read_i64::<LittleEndian>()
+                            .map_err(|e| DbError::SerializationError(e.to_string()))?;
+                        for _ in 0..count {
+                            values.push(Value::Timestamp(value));
+                        }
+                    }
+                    DataType::Int64 => {
+                        let value = cursor.read_i64::<LittleEndian>()
+                            .map_err(|e| DbError::SerializationError(e.to_string()))?;
+                        for _ in 0..count {
+                            values.push(Value::Int64(value));
+                        }
+                    }
                     DataType::String => {
                         let len = cursor.read_u64::<LittleEndian>()
                             .map_err(|e| DbError::SerializationError(e.to_string()))? as usize;
@@ -147,6 +183,7 @@ pub fn decompress(data: &[u8], compression: CompressionType, data_type: &DataTyp
                             values.push(Value::String(s.clone()));
                         }
                     }
+                    DataType::Null => return Err(null_not_encodable()),
                 }
             }
             Ok(values)
@@ -196,7 +233,9 @@ pub fn estimate_compressed_size(values: &[Value], compression: CompressionType)
         CompressionType::None => values.iter().map(|v| match v {
             Value::Int32(_) => 4,
             Value::Float32(_) => 4,
+            Value::Int64(_) | Value::Timestamp(_) => 8,
             Value::String(s) => 8 + s.len(),
+            Value::Null => 0,
         }).sum(),
         CompressionType::Rle => {
             if values.is_empty() {
@@ -210,7 +249,9 @@ pub fn estimate_compressed_size(values: &[Value], compression: CompressionType)
                     size += 1 + match current {
                         Value::Int32(_) => 4,
                         Value::Float32(_) => 4,
+                        Value::Int64(_) | Value::Timestamp(_) => 8,
                         Value::String(s) => 8 + s.len(),
+                        Value::Null => 0,
                     };
                     current = value;
                     _count = 1;
@@ -221,7 +262,9 @@ pub fn estimate_compressed_size(values: &[Value], compression: CompressionType)
             size + 1 + match current {
                 Value::Int32(_) => 4,
                 Value::Float32(_) => 4,
+                Value::Int64(_) | Value::Timestamp(_) => 8,
                 Value::String(s) => 8 + s.len(),
+                Value::Null => 0,
             }
         }
         CompressionType::Dictionary => {
@@ -0,0 +1,155 @@
+use crate::types::{DbError, Value};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// File in the data directory holding the salt the column key is derived with and a value
+/// encrypted under that key, to tell a wrong passphrase from a right one. The key itself and
+/// the passphrase are never written anywhere.
+pub const ENCRYPTION_FILE: &str = "encryption.json";
+
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+// Encrypted into the key file's check value when the key is first derived
+const KEY_CHECK: &[u8] = b"vddb column key";
+
+/// The `encryption_passphrase` setting. Kept out of `Debug` output so that logging a
+/// configuration does not leak it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+    pub fn new(passphrase: &str) -> Self {
+        Passphrase(passphrase.to_string())
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Passphrase(..)")
+    }
+}
+
+impl FromStr for Passphrase {
+    type Err = DbError;
+
+    fn from_str(text: &str) -> Result<Self, DbError> {
+        if text.is_empty() {
+            return Err(DbError::ConfigurationError("encryption_passphrase cannot be empty".to_string()));
+        }
+        Ok(Passphrase::new(text))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    salt: Vec<u8>,
+    check: Vec<u8>,
+}
+
+/// AES-256-GCM under a key derived with Argon2 from the passphrase and the database's salt.
+/// Every encryption draws a fresh nonce, stored ahead of the ciphertext.
+pub struct ColumnCipher {
+    cipher: Aes256Gcm,
+}
+
+impl fmt::Debug for ColumnCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ColumnCipher(..)")
+    }
+}
+
+impl ColumnCipher {
+    /// Derives the key of the database in `data_dir` from `passphrase`. The first call for a
+    /// database picks its salt and writes `ENCRYPTION_FILE`; later ones fail with
+    /// `SecurityError` unless given the same passphrase.
+    pub fn open(data_dir: &str, passphrase: &Passphrase) -> Result<Self, DbError> {
+        let path = Path::new(data_dir).join(ENCRYPTION_FILE);
+        if !path.exists() {
+            let mut salt = vec![0u8; SALT_LEN];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let cipher = ColumnCipher::derive(passphrase, &salt)?;
+            let key_file = KeyFile { check: cipher.encrypt(KEY_CHECK)?, salt };
+            let json = serde_json::to_string_pretty(&key_file).map_err(|e| DbError::SerializationError(e.to_string()))?;
+            fs::write(&path, json)?;
+            return Ok(cipher);
+        }
+        let key_file: KeyFile = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| DbError::SerializationError(format!("Malformed {}: {}", path.display(), e)))?;
+        let cipher = ColumnCipher::derive(passphrase, &key_file.salt)?;
+        match cipher.decrypt(&key_file.check) {
+            Ok(check) if check == KEY_CHECK => Ok(cipher),
+            _ => Err(DbError::SecurityError("Wrong encryption passphrase".to_string())),
+        }
+    }
+
+    fn derive(passphrase: &Passphrase, salt: &[u8]) -> Result<Self, DbError> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.0.as_bytes(), salt, &mut key)
+            .map_err(|e| DbError::SecurityError(format!("Cannot derive the column key: {}", e)))?;
+        Ok(ColumnCipher { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)) })
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, DbError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, data)
+            .map_err(|_| DbError::SecurityError("Encryption failed".to_string()))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// Fails with `SecurityError` when `data` was encrypted under another key or altered since.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, DbError> {
+        if data.len() < NONCE_LEN {
+            return Err(DbError::SecurityError("Encrypted data is truncated".to_string()));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| DbError::SecurityError("Cannot decrypt: wrong key or damaged data".to_string()))
+    }
+
+    /// One value of an encrypted column as the WAL logs it: a string holding the hex of the
+    /// encrypted value. NULLs are logged as they are.
+    pub fn seal_value(&self, value: &Value) -> Result<Value, DbError> {
+        if value.is_null() {
+            return Ok(Value::Null);
+        }
+        let plain = bincode::serialize(value).map_err(|e| DbError::from(*e))?;
+        let hex = self.encrypt(&plain)?.iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(Value::String(hex))
+    }
+
+    /// Reverses `seal_value`.
+    pub fn open_value(&self, value: &Value) -> Result<Value, DbError> {
+        let hex = match value {
+            Value::Null => return Ok(Value::Null),
+            Value::String(hex) if hex.len() % 2 == 0 && hex.is_ascii() => hex,
+            _ => return Err(DbError::SecurityError("Logged value of an encrypted column is not encrypted".to_string())),
+        };
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| DbError::SecurityError("Logged value of an encrypted column is not encrypted".to_string()))?;
+        bincode::deserialize(&self.decrypt(&bytes)?).map_err(|e| DbError::from(*e))
+    }
+}
+
+/// The error for touching an encrypted column of a database opened without its passphrase.
+pub fn missing_key(table_name: &str, column_name: &str) -> DbError {
+    DbError::SecurityError(format!(
+        "{}.{} is encrypted and no encryption_passphrase is configured",
+        table_name, column_name
+    ))
+}
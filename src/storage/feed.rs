@@ -0,0 +1,84 @@
+use crate::types::{DbError, Value};
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_FEED_CAPACITY: usize = 1024;
+
+/// A committed change to a table's rows. Rows hold every column in table order.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChangeRecord {
+    Insert { table: String, row: Vec<Value> },
+    // Rows removed by one DELETE, including ones removed from child tables by ON DELETE CASCADE
+    Delete { table: String, rows: Vec<Vec<Value>> },
+}
+
+struct Subscriber {
+    sender: Sender<Result<ChangeRecord, DbError>>,
+    capacity: usize,
+}
+
+/// Fans committed changes out to subscribers over bounded channels. A subscriber that falls
+/// `capacity` records behind is sent a `ReplicationError` and dropped, so a slow consumer
+/// can never make the database buffer without limit.
+#[derive(Default)]
+pub struct ChangeFeed {
+    subscribers: Vec<Subscriber>,
+    // Changes held back while a transaction or atomic import is being applied
+    batch: Option<Vec<ChangeRecord>>,
+}
+
+impl std::fmt::Debug for ChangeFeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChangeFeed").field("subscribers", &self.subscribers.len()).finish()
+    }
+}
+
+impl ChangeFeed {
+    pub fn subscribe(&mut self, capacity: usize) -> Receiver<Result<ChangeRecord, DbError>> {
+        // One slot beyond the capacity is kept free for the error that ends a lagging subscription
+        let (sender, receiver) = channel::bounded(capacity + 1);
+        self.subscribers.push(Subscriber { sender, capacity });
+        receiver
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    pub fn publish(&mut self, record: ChangeRecord) {
+        match self.batch.as_mut() {
+            Some(batch) => batch.push(record),
+            None => self.send(record),
+        }
+    }
+
+    pub fn begin_batch(&mut self) {
+        self.batch = Some(Vec::new());
+    }
+
+    /// Sends the changes held since `begin_batch`, or throws them away if the batch was rolled back.
+    pub fn end_batch(&mut self, committed: bool) {
+        if let Some(batch) = self.batch.take() {
+            if committed {
+                for record in batch {
+                    self.send(record);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, record: ChangeRecord) {
+        self.subscribers.retain(|subscriber| {
+            if subscriber.sender.len() >= subscriber.capacity {
+                log::warn!("Dropping change feed subscriber lagging {} records behind", subscriber.capacity);
+                let _ = subscriber.sender.try_send(Err(DbError::ReplicationError(format!(
+                    "Subscriber fell {} changes behind and was dropped",
+                    subscriber.capacity
+                ))));
+                return false;
+            }
+            // Fails only once the receiver is gone
+            subscriber.sender.try_send(Ok(record.clone())).is_ok()
+        });
+    }
+}
\ No newline at end of file
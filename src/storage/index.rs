@@ -1,8 +1,10 @@
+use crate::schema::{IndexDef, IndexKind};
 use crate::types::{DataType, DbError, Value};
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use bincode;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
 pub struct Index {
@@ -26,8 +28,11 @@ impl Index {
 
     pub fn append(&mut self, values: &[Value], offset: u64) -> Result<(), DbError> {
         for value in values {
+            if value.is_null() {
+                continue;
+            }
             if value.data_type() != self.data_type {
-                return Err(DbError::TypeMismatch);
+                return Err(self.type_mismatch(value));
             }
             self.map
                 .entry(value.clone())
@@ -40,14 +45,14 @@ impl Index {
 
     pub fn lookup(&self, value: &Value) -> Result<Vec<u64>, DbError> {
         if value.data_type() != self.data_type {
-            return Err(DbError::TypeMismatch);
+            return Err(self.type_mismatch(value));
         }
         Ok(self.map.get(value).cloned().unwrap_or_default())
     }
 
     pub fn range_lookup(&self, min: &Value, max: &Value) -> Result<Vec<u64>, DbError> {
-        if min.data_type() != self.data_type || max.data_type() != self.data_type {
-            return Err(DbError::TypeMismatch);
+        if let Some(value) = [min, max].into_iter().find(|v| v.data_type() != self.data_type) {
+            return Err(self.type_mismatch(value));
         }
         let mut offsets = Vec::new();
         for (value, offs) in &self.map {
@@ -58,6 +63,19 @@ impl Index {
         Ok(offsets)
     }
 
+    fn type_mismatch(&self, value: &Value) -> DbError {
+        DbError::TypeMismatch(format!("Index of {} values cannot hold {}", self.data_type.sql_name(), value.to_sql_literal()))
+    }
+
+    /// Moves the index file to `path`.
+    pub fn rename(&mut self, path: &str) -> Result<(), DbError> {
+        if std::path::Path::new(&self.path).exists() {
+            std::fs::rename(&self.path, path)?;
+        }
+        self.path = path.to_string();
+        Ok(())
+    }
+
     pub fn clear(&mut self) -> Result<(), DbError> {
         // Clear the in-memory map
         self.map.clear();
@@ -95,4 +113,170 @@ impl Index {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Splits text into the terms a full-text index holds and MATCH looks for: runs of letters and
+/// digits, lowercased. Everything else, whitespace and punctuation alike, separates terms.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// What a declared index maps to row positions, each list ascending.
+#[derive(Debug, Serialize, Deserialize)]
+enum IndexEntries {
+    // Term to the rows containing it
+    FullText(HashMap<String, Vec<u64>>),
+    // `levels[k]` maps the first k + 1 column values to the rows holding them. A row is left
+    // out of the levels from its first NULL on, since NULL never equals anything.
+    Composite(Vec<HashMap<Vec<Value>, Vec<u64>>>),
+}
+
+/// An index declared with `CREATE INDEX`, held in memory and saved to a file of its own. Rows
+/// are added in table order; `rows` says how many have been, so a copy that fell behind the
+/// table (after a crash, say) is easy to spot and rebuild.
+#[derive(Debug)]
+pub struct DeclaredIndex {
+    path: String,
+    rows: u64,
+    entries: IndexEntries,
+    // Changes not yet written; saved with the next checkpoint
+    dirty: bool,
+}
+
+impl DeclaredIndex {
+    /// Loads the index `def` from `path`. A missing or unreadable file starts it empty; the
+    /// index is derived data, so it is then rebuilt from the table rather than failing.
+    pub fn open(path: &str, def: &IndexDef) -> Result<Self, DbError> {
+        let (rows, entries) = match std::fs::read(path) {
+            Ok(contents) => match bincode::deserialize::<(u64, IndexEntries)>(&contents) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    log::warn!("Ignoring unreadable index file {}: {}", path, e);
+                    (0, Self::empty(def))
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, Self::empty(def)),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(DeclaredIndex {
+            path: path.to_string(),
+            rows,
+            entries,
+            dirty: false,
+        })
+    }
+
+    fn empty(def: &IndexDef) -> IndexEntries {
+        match def.kind {
+            IndexKind::FullText => IndexEntries::FullText(HashMap::new()),
+            IndexKind::Composite => IndexEntries::Composite(vec![HashMap::new(); def.columns.len()]),
+        }
+    }
+
+    /// Number of rows indexed, which is also the position the next row must have.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Indexes the row at `row`, the next position, whose values for the index columns are `key`.
+    pub fn add(&mut self, row: u64, key: &[&Value]) {
+        match &mut self.entries {
+            IndexEntries::FullText(postings) => {
+                // NULLs contain no terms
+                if let Some(Value::String(text)) = key.first() {
+                    let mut terms = tokenize(text);
+                    terms.sort();
+                    terms.dedup();
+                    for term in terms {
+                        postings.entry(term).or_default().push(row);
+                    }
+                }
+            }
+            IndexEntries::Composite(levels) => {
+                let known = key.iter().take_while(|value| !value.is_null()).count();
+                for (len, level) in (1..=known).zip(levels.iter_mut()) {
+                    let prefix = key[..len].iter().map(|value| (*value).clone()).collect();
+                    level.entry(prefix).or_default().push(row);
+                }
+            }
+        }
+        self.rows = row + 1;
+        self.dirty = true;
+    }
+
+    /// Replaces the contents with `columns`, each index column's values in row order, and saves.
+    pub fn rebuild(&mut self, columns: &[Vec<Value>]) -> Result<(), DbError> {
+        match &mut self.entries {
+            IndexEntries::FullText(postings) => postings.clear(),
+            IndexEntries::Composite(levels) => levels.iter_mut().for_each(HashMap::clear),
+        }
+        let row_count = columns.iter().map(Vec::len).min().unwrap_or(0);
+        for row in 0..row_count {
+            let key: Vec<&Value> = columns.iter().map(|values| &values[row]).collect();
+            self.add(row as u64, &key);
+        }
+        self.rows = row_count as u64;
+        self.dirty = true;
+        self.save()
+    }
+
+    /// Positions of the rows containing every one of `terms`, or any one of them when `any`,
+    /// in ascending order. `terms` must already be tokenized. `None` unless a FULLTEXT index.
+    pub fn match_terms(&self, terms: &[String], any: bool) -> Option<Vec<usize>> {
+        let IndexEntries::FullText(postings) = &self.entries else { return None };
+        let mut lists = terms.iter().map(|term| postings.get(term).map_or(&[][..], |rows| rows.as_slice()));
+        let rows: Vec<u64> = if any {
+            let mut rows: Vec<u64> = lists.flatten().copied().collect();
+            rows.sort_unstable();
+            rows.dedup();
+            rows
+        } else {
+            let first = lists.next().unwrap_or_default().to_vec();
+            lists.fold(first, |acc, list| acc.into_iter().filter(|row| list.binary_search(row).is_ok()).collect())
+        };
+        Some(rows.into_iter().map(|row| row as usize).collect())
+    }
+
+    /// Positions of the rows whose leading index columns equal `prefix`, in ascending order.
+    /// `None` unless a composite index with at least as many columns as `prefix` has values.
+    pub fn lookup_prefix(&self, prefix: &[Value]) -> Option<Vec<usize>> {
+        let IndexEntries::Composite(levels) = &self.entries else { return None };
+        let level = levels.get(prefix.len().checked_sub(1)?)?;
+        let rows = level.get(prefix).map_or(&[][..], |rows| rows.as_slice());
+        Some(rows.iter().map(|&row| row as usize).collect())
+    }
+
+    /// Writes pending changes, replacing the file atomically.
+    pub fn save(&mut self) -> Result<(), DbError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let serialized = bincode::serialize(&(self.rows, &self.entries))
+            .map_err(|e| DbError::SerializationError(e.to_string()))?;
+        let tmp_path = format!("{}.tmp", self.path);
+        std::fs::write(&tmp_path, serialized)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Moves the index file to `path`.
+    pub fn rename(&mut self, path: &str) -> Result<(), DbError> {
+        if std::path::Path::new(&self.path).exists() {
+            std::fs::rename(&self.path, path)?;
+        }
+        self.path = path.to_string();
+        Ok(())
+    }
+
+    /// Deletes the index file.
+    pub fn remove(self) -> Result<(), DbError> {
+        if std::path::Path::new(&self.path).exists() {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
@@ -1,346 +1,2455 @@
-use crate::schema::{Schema, Table};
+use crate::audit::AuditLog;
+use crate::logging::{log_backup, log_recovery};
+use crate::auth::UserStore;
+use crate::metrics::QueryMetrics;
+use crate::schema::metadata::BlockMetadata;
+use crate::schema::{Column, IndexDef, IndexKind, OnDelete, Schema, Table, CATALOG_FILE, ROWID};
 use crate::storage::{
     buffer::BufferManager,
-    column::ColumnStore,
-    index::Index,
+    cache::{ColumnCache, DEFAULT_COLUMN_CACHE_CAPACITY},
+    column::{ColumnScan, ColumnStore},
+    encryption::{missing_key, ColumnCipher, Passphrase},
+    feed::{ChangeFeed, ChangeRecord},
+    index::{DeclaredIndex, Index},
+    undo::UndoImage,
+    wal::{Durability, Wal, WalArchive, WalOperation, WalRecord, WalSegment},
 };
-use crate::types::{CompressionType, DbError, Value};
+use crate::query::{ConflictAction, Expr, OnConflict};
+use crate::types::{quote_identifier, CompressionType, DbError, Value};
 use crate::{Condition, DataType};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Rows per block when VACUUM repacks a column.
+pub const VACUUM_BLOCK_ROWS: usize = 8192;
+/// File in a full backup naming it, so incremental segments are added only to the backup they continue.
+pub const BACKUP_MANIFEST: &str = "backup.json";
+/// Directory in a full backup holding its incremental WAL segments, `000001.wal` onwards.
+pub const SEGMENTS_DIR: &str = "incremental";
+
+/// The storage manager as the engine, transactions, the REPL and server connections share it.
+pub type SharedStorage = Arc<RwLock<StorageManager>>;
+
+/// Each column's name with its verified row count or the damage found, in table order.
+pub type IntegrityReport = Vec<(String, Result<usize, DbError>)>;
+
+/// An index as `list_indexes` reports it. `kind` is one of the names `column_indexes` uses;
+/// implicit indexes are named `<table>_<column>_hash` or `<table>_<column>_key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexInfo {
+    pub name: String,
+    pub kind: &'static str,
+    pub columns: Vec<String>,
+    pub implicit: bool,
+}
+
+/// How many rows `upsert_rows` inserted and how many it updated. Rows DO NOTHING skipped are
+/// in neither count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpsertCounts {
+    pub inserted: usize,
+    pub updated: usize,
+}
 
 pub mod block;
 pub mod buffer;
+pub mod cache;
 pub mod column;
 pub mod compression;
+pub mod encryption;
+pub mod feed;
 pub mod index;
+pub mod undo;
+pub mod wal;
 
-// Standalone function to flush pending rows
-fn do_flush_pending_rows(
-    pending_rows: &mut HashMap<String, HashMap<String, Vec<Value>>>,
-    table_name: &str,
-    table_cols: &mut HashMap<String, ColumnStore>,
-    table_indexes: &mut HashMap<String, Index>,
-    table_def: &Table,
-) -> Result<(), DbError> {
-    let table_pending = pending_rows.remove(table_name).unwrap_or_default();
-    for col in &table_def.columns {
-        let col_name = &col.name;
-        let col_store = table_cols.get_mut(col_name).ok_or_else(|| {
-            DbError::InvalidData(format!("Column {}.{} not found", table_name, col_name))
-        })?;
-        let values = table_pending.get(col_name).cloned().unwrap_or_default();
-        if !values.is_empty() {
-            let compression = match col.data_type {
-                DataType::String => CompressionType::Dictionary,
-                _ => CompressionType::Rle,
-            };
-            let offset = col_store.append(&values, compression)?;
-            if let Some(index) = table_indexes.get_mut(col_name) {
-                index.append(&values, offset)?;
+/// One table's column files, indexes, pending rows and key maps. `StorageManager` keeps each
+/// table behind a lock of its own; see there for how the locks are taken.
+#[derive(Debug, Default)]
+pub struct TableStorage {
+    name: String,
+    pub columns: HashMap<String, ColumnStore>,
+    pub indexes: HashMap<String, Index>,
+    // Indexes created with CREATE INDEX, keyed by index name
+    declared_indexes: HashMap<String, DeclaredIndex>,
+    pending_rows: HashMap<String, Vec<Value>>,
+    // Values currently held by each UNIQUE / PRIMARY KEY column and the row holding each
+    unique_values: HashMap<String, HashMap<Value, usize>>,
+    // The catalog's copy is brought up to date at each checkpoint
+    next_rowid: u64,
+    // Rows were flushed since the column files were last synced
+    unsynced: bool,
+}
+
+impl TableStorage {
+    fn open(data_dir: &str, table: &Table, cipher: Option<&Arc<ColumnCipher>>) -> Result<Self, DbError> {
+        let mut storage = TableStorage {
+            name: table.name.clone(),
+            next_rowid: table.next_rowid,
+            ..TableStorage::default()
+        };
+        for col in table.stored_columns() {
+            let mut col_store = ColumnStore::new(&table.name, col, data_dir)?;
+            col_store.set_cipher(cipher.cloned());
+            storage.columns.insert(col.name.clone(), col_store);
+            if col.has_implicit_index() {
+                let index_path = format!("{}/indexes/{}_{}.idx", data_dir, table.name, col.name);
+                storage.indexes.insert(
+                    col.name.clone(),
+                    Index::new(&index_path, col.data_type.clone())?,
+                );
             }
         }
+        for index in &table.indexes {
+            let path = declared_index_path(data_dir, &table.name, index);
+            storage.declared_indexes.insert(index.name.clone(), DeclaredIndex::open(&path, index)?);
+        }
+        Ok(storage)
+    }
+
+    /// Rows in the table, including ones not yet flushed.
+    pub fn row_count(&self) -> usize {
+        self.durable_row_count() + self.pending_row_count()
+    }
+
+    fn durable_row_count(&self) -> usize {
+        self.columns.values().map(|c| c.row_count()).min().unwrap_or(0)
+    }
+
+    fn pending_row_count(&self) -> usize {
+        self.pending_rows.values().next().map_or(0, |values| values.len())
+    }
+
+    // `rowid` if given, else the table's next one; either way the next one handed out is higher
+    fn take_rowid(&mut self, rowid: Option<u64>) -> u64 {
+        let rowid = rowid.unwrap_or(self.next_rowid.max(1));
+        self.next_rowid = self.next_rowid.max(rowid + 1);
+        rowid
+    }
+
+    fn column(&self, column_name: &str) -> Result<&ColumnStore, DbError> {
+        self.columns
+            .get(column_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", self.name, column_name)))
+    }
+
+    // Appends the pending rows to the column files and the implicit indexes. Their WAL records
+    // stay until the next checkpoint.
+    fn flush(&mut self, table_def: &Table) -> Result<(), DbError> {
+        let table_pending = std::mem::take(&mut self.pending_rows);
+        for col in table_def.stored_columns() {
+            let col_store = self.columns.get_mut(&col.name).ok_or_else(|| {
+                DbError::InvalidData(format!("Column {}.{} not found", self.name, col.name))
+            })?;
+            let values = table_pending.get(&col.name).cloned().unwrap_or_default();
+            if !values.is_empty() {
+                let compression = match col.data_type {
+                    DataType::String => CompressionType::Dictionary,
+                    _ => CompressionType::Rle,
+                };
+                let offset = col_store.append(&values, compression)?;
+                if let Some(index) = self.indexes.get_mut(&col.name) {
+                    index.append(&values, offset)?;
+                }
+                self.unsynced = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams a column, including rows not yet flushed, decoding one block at a time. Unlike
+    /// `StorageManager::read_column` it bypasses the column cache, so scans of large tables stay
+    /// in bounded memory.
+    pub fn read_column_iter(&self, column_name: &str) -> ColumnScan<'_> {
+        let pending = self.pending_rows.get(column_name).map_or(&[][..], |values| values.as_slice());
+        match self.column(column_name) {
+            Ok(col_store) => col_store.scan(pending),
+            Err(e) => ColumnScan::failed(e),
+        }
+    }
+
+    fn declared_index(&self, index_name: &str) -> Option<&DeclaredIndex> {
+        self.declared_indexes.get(index_name)
+    }
+
+    // The declared index `index_name` if it holds every row; one behind the table must not
+    // answer lookups until it is rebuilt
+    fn current_index(&self, index_name: &str) -> Option<&DeclaredIndex> {
+        self.declared_index(index_name).filter(|index| index.rows() == self.row_count() as u64)
+    }
+
+    // Rows and bytes as `StorageManager::table_size` reports them
+    fn size(&self, data_dir: &str) -> (u64, u64) {
+        let bytes = self
+            .columns
+            .keys()
+            .filter_map(|col| fs::metadata(ColumnStore::path(data_dir, &self.name, col)).ok())
+            .map(|meta| meta.len())
+            .sum();
+        (self.row_count() as u64, bytes)
     }
-    Ok(())
 }
 
+/// Every table's storage, shared as `SharedStorage`.
+///
+/// Each table's columns, indexes, pending rows and key maps sit behind the table's own
+/// `RwLock` (see `TableStorage`). The `SharedStorage` lock around the whole manager guards the
+/// table map and the catalog. Reads and inserts take it shared and then lock just the tables
+/// they use, so they run alongside reads and inserts on other tables; the WAL and the change
+/// feed have small locks of their own for the moment an insert appends to them. DDL,
+/// checkpoints, DELETE and UPDATE, which rewrite column files and truncate the WAL, take it
+/// exclusively, and then no table lock can be held elsewhere.
+///
+/// A table's lock is only ever taken while holding the storage lock. An insert checks foreign
+/// keys by read-locking each referenced table briefly under its own table's write lock, and
+/// foreign keys never form a cycle, so table locks cannot deadlock. `benches/concurrent_reads.rs`
+/// measures statements on one table while another table is busy.
 #[derive(Debug)]
 pub struct StorageManager {
     data_dir: String,
-    pub columns: HashMap<String, HashMap<String, ColumnStore>>,
-    pub indexes: HashMap<String, HashMap<String, Index>>,
+    tables: HashMap<String, Arc<RwLock<TableStorage>>>,
     pub buffer: BufferManager,
+    // Row counts and next rowids here are the ones last saved; `current_schema` has the live ones
     schema: Schema,
-    pending_rows: HashMap<String, HashMap<String, Vec<Value>>>,
     max_rows_per_segment: usize,
+    wal: Mutex<Wal>,
+    wal_archive: WalArchive,
+    // A table flushed its pending rows on filling up, leaving the WAL for `checkpoint` to truncate
+    checkpoint_due: AtomicBool,
+    // Bumped on every change to a table's rows or definition; serializable transactions compare
+    // these at COMMIT to detect concurrent writers. In memory only, so they restart from 0.
+    table_versions: Mutex<HashMap<String, u64>>,
+    metrics: QueryMetrics,
+    // Decoded column files for unconditioned reads; pending rows are appended on top. Behind its
+    // own lock so that reads, which share the storage lock, can still fill it.
+    cache: Mutex<ColumnCache>,
+    users: UserStore,
+    audit_log: AuditLog,
+    change_feed: Mutex<ChangeFeed>,
+    // Key for ENCRYPTED columns, from `set_encryption_passphrase`
+    cipher: Option<Arc<ColumnCipher>>,
+    // The tables' files as they were before the `atomically` write in progress
+    undo: Option<UndoImage>,
 }
 
 impl StorageManager {
     pub fn new(data_dir: &str, schema: Schema) -> Result<Self, DbError> {
         fs::create_dir_all(format!("{}/columns", data_dir))?;
         fs::create_dir_all(format!("{}/indexes", data_dir))?;
-        let mut columns = HashMap::new();
-        let mut indexes = HashMap::new();
+        migrate_column_layout(data_dir, &schema)?;
+        let mut tables = HashMap::new();
         for table in schema.tables() {
-            let mut table_cols = HashMap::new();
-            let mut table_indexes = HashMap::new();
-            for col in &table.columns {
-                table_cols.insert(
-                    col.name.clone(),
-                    ColumnStore::new(col, data_dir)?,
-                );
-                if col.name == "ID" || col.name == "Name" {
-                    let index_path = format!("{}/indexes/{}_{}.idx", data_dir, table.name, col.name);
-                    table_indexes.insert(
-                        col.name.clone(),
-                        Index::new(&index_path, col.data_type.clone())?,
-                    );
-                }
-            }
-            columns.insert(table.name.clone(), table_cols);
-            indexes.insert(table.name.clone(), table_indexes);
+            let storage = TableStorage::open(data_dir, table, None)?;
+            tables.insert(table.name.clone(), Arc::new(RwLock::new(storage)));
         }
-        Ok(StorageManager {
+        let mut storage = StorageManager {
             data_dir: data_dir.to_string(),
-            columns,
-            indexes,
+            tables,
             buffer: BufferManager::new(100_000_000),
             schema,
-            pending_rows: HashMap::new(),
             max_rows_per_segment: 1000, // Increased for batching
-        })
+            wal: Mutex::new(Wal::open(data_dir)?),
+            wal_archive: WalArchive::open(data_dir)?,
+            checkpoint_due: AtomicBool::new(false),
+            table_versions: Mutex::new(HashMap::new()),
+            metrics: QueryMetrics::new(),
+            cache: Mutex::new(ColumnCache::new(DEFAULT_COLUMN_CACHE_CAPACITY)),
+            users: UserStore::open(data_dir)?,
+            audit_log: AuditLog::open(data_dir)?,
+            change_feed: Mutex::new(ChangeFeed::default()),
+            cipher: None,
+            undo: None,
+        };
+        let table_names: Vec<String> = storage.schema.tables.keys().cloned().collect();
+        for table_name in &table_names {
+            storage.number_rows(table_name)?;
+            let lock = storage.table_lock(table_name)?;
+            storage.rebuild_unique_values(&mut lock.write().unwrap())?;
+        }
+        if let Some(image) = UndoImage::find(data_dir)? {
+            log::warn!("Rolling back a write interrupted in {}", data_dir);
+            storage.restore_image(&image)?;
+            image.discard()?;
+        }
+        Ok(storage)
     }
 
-    pub fn data_dir(&self) -> &str {
-        &self.data_dir
+    // A clone of the lock of `table_name`'s storage, for methods that hold `&mut self` while
+    // they have the table locked
+    fn table_lock(&self, table_name: &str) -> Result<Arc<RwLock<TableStorage>>, DbError> {
+        self.tables
+            .get(table_name)
+            .cloned()
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))
     }
 
-    pub fn schema(&self) -> &Schema {
-        &self.schema
+    /// Locks `table_name` for reading, e.g. to stream its columns with
+    /// `TableStorage::read_column_iter`. Inserts into the table wait until the guard is dropped.
+    /// Drop it before calling other methods that read the same table.
+    pub fn read_table(&self, table_name: &str) -> Result<RwLockReadGuard<'_, TableStorage>, DbError> {
+        let lock = self
+            .tables
+            .get(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?;
+        Ok(lock.read().unwrap())
     }
 
-    pub fn create_table(&mut self, table: &Table) -> Result<(), DbError> {
-        let mut table_cols = HashMap::new();
-        let mut table_indexes = HashMap::new();
-        for col in &table.columns {
-            table_cols.insert(
-                col.name.clone(),
-                ColumnStore::new(col, &self.data_dir)?,
-            );
-            if col.name == "ID" || col.name == "Name" {
-                let index_path = format!("{}/indexes/{}_{}.idx", self.data_dir, table.name, col.name);
-                table_indexes.insert(
-                    col.name.clone(),
-                    Index::new(&index_path, col.data_type.clone())?,
-                );
-            }
+    pub(crate) fn write_table(&self, table_name: &str) -> Result<RwLockWriteGuard<'_, TableStorage>, DbError> {
+        let lock = self
+            .tables
+            .get(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?;
+        Ok(lock.write().unwrap())
+    }
+
+    fn table_def(&self, table_name: &str) -> Result<&Table, DbError> {
+        self.schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))
+    }
+
+    // Gives the rows of a table from a catalog written before rowids existed the rowids 1 to n
+    fn number_rows(&mut self, table_name: &str) -> Result<(), DbError> {
+        if self.schema.get_table(table_name).is_none_or(|t| t.next_rowid > 0) {
+            return Ok(());
         }
-        self.columns.insert(table.name.clone(), table_cols);
-        self.indexes.insert(table.name.clone(), table_indexes);
-        self.schema.add_table(&table.name, table.columns.clone())?;
-        Ok(())
+        let lock = self.table_lock(table_name)?;
+        let mut table = lock.write().unwrap();
+        let rows = table.columns.iter().filter(|(name, _)| *name != ROWID).map(|(_, c)| c.row_count()).min().unwrap_or(0);
+        if let Some(rowids) = table.columns.get_mut(ROWID).filter(|c| c.row_count() == 0 && rows > 0) {
+            let values: Vec<Value> = (1..=rows as i64).map(Value::Int64).collect();
+            rowids.append(&values, CompressionType::Rle)?;
+        }
+        table.next_rowid = rows as u64 + 1;
+        if let Some(table_def) = self.schema.tables.get_mut(table_name) {
+            table_def.next_rowid = rows as u64 + 1;
+        }
+        self.schema.save()
     }
 
-    pub fn insert_row(&mut self, table_name: &str, row: Vec<Value>) -> Result<(), DbError> {
-        // Validate and get references
-        let table_def = self.schema.get_table(table_name).ok_or_else(|| {
-            DbError::InvalidData(format!("Table {} not found", table_name))
-        })?.clone();
-        self.schema.validate_row(table_name, &row)?;
+    /// Restores a consistent state after a crash: columns left ahead of their siblings by a
+    /// torn flush are trimmed back, then WAL records not yet in the column files are replayed.
+    pub fn recover(&mut self) -> Result<usize, DbError> {
+        let records = self.wal.get_mut().unwrap().read_records()?;
+        let table_names: Vec<String> = self.schema.tables.keys().cloned().collect();
+        for table_name in &table_names {
+            self.repair_torn_columns(table_name)?;
+        }
 
-        // Check for duplicate ID
-        {
-            let table_indexes = self.indexes.get_mut(table_name).ok_or_else(|| {
-                DbError::InvalidData(format!("Table {} not found", table_name))
-            })?;
-            if let Some(id_index) = table_indexes.get("ID") {
-                let id_value = &row[0];
-                let existing = id_index.lookup(id_value)?;
-                if !existing.is_empty() {
-                    return Err(DbError::InvalidData(format!("Duplicate ID: {:?}", id_value)));
+        let mut replayed = 0;
+        for record in records {
+            match record.operation {
+                WalOperation::Insert => {
+                    let table_def = match self.schema.get_table(&record.table) {
+                        Some(table_def) => table_def.clone(),
+                        None => {
+                            log::warn!("Skipping WAL record for unknown table {}", record.table);
+                            continue;
+                        }
+                    };
+                    let lock = self.table_lock(&record.table)?;
+                    let mut table = lock.write().unwrap();
+                    let next_row = table.row_count();
+                    let row_index = record.row_index as usize;
+                    let (row, rowid) = split_rowid(&table_def, record.row);
+                    if row_index < next_row {
+                        // Already in the column files, though the catalog may not count its rowid
+                        if let Some(rowid) = rowid {
+                            table.take_rowid(Some(rowid));
+                        }
+                        continue;
+                    }
+                    if row_index > next_row {
+                        return Err(DbError::RecoveryError(format!(
+                            "WAL gap for table {}: expected row {}, found {}",
+                            record.table, next_row, row_index
+                        )));
+                    }
+                    let row = self.open_row(&table_def, row)?;
+                    self.schema.validate_row(&record.table, &row)?;
+                    let rowid = table.take_rowid(rowid);
+                    for (value, col) in row.into_iter().zip(table_def.columns.iter()) {
+                        table.pending_rows.entry(col.name.clone()).or_default().push(value);
+                    }
+                    table.pending_rows.entry(ROWID.to_string()).or_default().push(Value::Int64(rowid as i64));
+                    replayed += 1;
                 }
             }
         }
 
-        // Buffer the row
-        let table_pending = self.pending_rows.entry(table_name.to_string()).or_insert_with(HashMap::new);
-        for (value, col) in row.into_iter().zip(table_def.columns.iter()) {
-            let col_name = &col.name;
-            let col_values = table_pending.entry(col_name.clone()).or_insert_with(Vec::new);
-            col_values.push(value);
+        // Saves the recounted rows and rowids to the catalog
+        self.checkpoint()?;
+        for lock in self.tables.values() {
+            let mut table = lock.write().unwrap();
+            self.rebuild_unique_values(&mut table)?;
+            self.rebuild_declared_indexes(&mut table, true)?;
         }
+        if replayed > 0 {
+            log::info!("Recovered {} rows from WAL in {}", replayed, self.data_dir);
+        }
+        Ok(replayed)
+    }
 
-        // Flush if buffer is full
-        if table_pending.values().next().map_or(0, |v| v.len()) >= self.max_rows_per_segment {
-            let mut table_cols = self.columns.get_mut(table_name).ok_or_else(|| {
-                DbError::InvalidData(format!("Table {} not found", table_name))
-            })?;
-            let mut table_indexes = self.indexes.get_mut(table_name).ok_or_else(|| {
-                DbError::InvalidData(format!("Table {} not found", table_name))
-            })?;
-            do_flush_pending_rows(
-                &mut self.pending_rows,
-                table_name,
-                &mut table_cols,
-                &mut table_indexes,
-                &table_def,
-            )?;
-        }
-
-        // Increment row count
-        if let Some(table) = self.schema.get_table(table_name) {
-            let mut table = table.clone();
-            table.increment_row_count();
-            self.schema.tables.insert(table_name.to_string(), table);
-            self.schema.save()?;
+    /// Flushes every table's pending rows to the column files and truncates the WAL.
+    pub fn checkpoint(&mut self) -> Result<(), DbError> {
+        for lock in self.tables.values() {
+            let mut table = lock.write().unwrap();
+            if table.pending_row_count() > 0 {
+                let table_def = self.table_def(&table.name)?;
+                self.flush_table(table_def, &mut table)?;
+            }
+        }
+        // Once the WAL is truncated the flushed rows exist only in the column files, and the
+        // row counts and next rowids inserts left unsaved only in the catalog
+        let durability = self.wal.get_mut().unwrap().durability();
+        for lock in self.tables.values() {
+            let mut table = lock.write().unwrap();
+            for index in table.declared_indexes.values_mut() {
+                index.save()?;
+            }
+            if table.unsynced && durability != Durability::Off {
+                for col_store in table.columns.values() {
+                    col_store.sync()?;
+                }
+            }
+            table.unsynced = false;
         }
+        self.schema = self.current_schema();
+        self.schema.save()?;
+        let wal = self.wal.get_mut().unwrap();
+        let frames = wal.contents()?;
+        self.wal_archive.append(&frames)?;
+        wal.truncate()?;
+        *self.checkpoint_due.get_mut() = false;
         Ok(())
     }
 
-    pub fn read_column(
-        &mut self,
-        table_name: &str,
-        column_name: &str,
-        condition: Option<&Condition>,
-    ) -> Result<Vec<Value>, DbError> {
-        let col_store = self
-            .columns
-            .get(table_name)
-            .and_then(|cols| cols.get(column_name))
-            .ok_or_else(|| {
-                DbError::InvalidData(format!("Column {}.{} not found", table_name, column_name))
-            })?;
-        let mut values = col_store.read(condition, &mut self.buffer)?;
+    /// Whether a table has flushed rows whose WAL records only a `checkpoint` truncates. Inserts
+    /// cannot checkpoint themselves under the shared storage lock, so the statement that made
+    /// one due runs it once that lock is released.
+    pub fn checkpoint_due(&self) -> bool {
+        self.checkpoint_due.load(Ordering::Relaxed)
+    }
 
-        // Append pending rows
-        if let Some(table_pending) = self.pending_rows.get(table_name) {
-            if let Some(pending_values) = table_pending.get(column_name) {
-                values.extend(pending_values.iter().cloned());
+    /// Runs `write`, which may replace the files of several of `tables`, so that it takes effect
+    /// completely or not at all, across a crash too. The files of `tables` are first saved in an
+    /// `UndoImage`, which is dropped once everything `write` did is durable. If `write` fails,
+    /// they are put back and its error returned; if the process dies first, the next open puts
+    /// them back. Inside another `atomically` write, `tables` join the image that one keeps and
+    /// it decides the outcome.
+    pub(crate) fn atomically<T>(
+        &mut self,
+        tables: &[String],
+        write: impl FnOnce(&mut Self) -> Result<T, DbError>,
+    ) -> Result<T, DbError> {
+        if let Some(image) = self.undo.as_mut() {
+            image.extend(&self.schema, tables)?;
+            return write(self);
+        }
+        // Pending rows would be in the WAL rather than the image
+        self.checkpoint()?;
+        let sync = self.durability() != Durability::Off;
+        self.undo = Some(UndoImage::create(&self.data_dir, &self.schema, tables, sync)?);
+        let result = write(self).and_then(|value| {
+            self.checkpoint()?;
+            if let Some(image) = &self.undo {
+                image.discard()?;
+            }
+            Ok(value)
+        });
+        let image = self.undo.take();
+        match (result, image) {
+            (Err(error), Some(image)) => {
+                log::warn!("Rolling back a write to {}: {}", tables.join(", "), error);
+                match self.restore_image(&image).and_then(|()| image.discard()) {
+                    Ok(()) => Err(error),
+                    Err(e) => Err(DbError::RecoveryError(format!(
+                        "{}; rolling it back failed too, so it is rolled back when the database is next opened: {}",
+                        error, e
+                    ))),
+                }
             }
+            (result, _) => result,
         }
-
-        Ok(values)
     }
 
-    pub fn delete_rows(&mut self, table_name: &str, condition: Option<&Condition>) -> Result<(), DbError> {
-        let table_def = self
-            .schema
-            .get_table(table_name)
-            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?;
-        let columns = table_def.columns.clone();
-
-        let mut column_values = HashMap::new();
-        let mut min_row_count = usize::MAX;
-        for col in &columns {
-            let values = self.read_column(table_name, &col.name, None)?;
-            min_row_count = min_row_count.min(values.len());
-            column_values.insert(col.name.clone(), values);
+    // Puts the tables `image` covers back as it saved them, on disk and in memory
+    fn restore_image(&mut self, image: &UndoImage) -> Result<(), DbError> {
+        // Everything in the WAL was written after the image was taken
+        self.wal.get_mut().unwrap().truncate()?;
+        image.restore()?;
+        self.schema = Schema::load(&self.data_dir)?;
+        for table_name in image.tables() {
+            let table_def = self.table_def(table_name)?;
+            let restored = TableStorage::open(&self.data_dir, table_def, self.cipher.as_ref())?;
+            let lock = self.table_lock(table_name)?;
+            let mut table = lock.write().unwrap();
+            *table = restored;
+            for col_name in table_def.stored_columns().map(|c| c.name.clone()).collect::<Vec<_>>() {
+                self.rebuild_index(&mut table, &col_name)?;
+            }
+            self.rebuild_unique_values(&mut table)?;
+            self.rebuild_declared_indexes(&mut table, false)?;
+            self.cache.lock().unwrap().invalidate_table(table_name);
+            self.bump_version(&table);
         }
+        *self.checkpoint_due.get_mut() = false;
+        // A checkpoint during the write may have archived rows that are now gone
+        self.wal_archive.invalidate("A write was rolled back")
+    }
 
-        let keep_indices = match condition {
-            Some(cond) => {
-                let cond_columns = crate::query::collect_condition_columns(cond);
-                for col in cond_columns {
-                    if !column_values.contains_key(&col) {
-                        let values = self.read_column(table_name, &col, None)?;
-                        min_row_count = min_row_count.min(values.len());
-                        column_values.insert(col, values);
-                    }
-                }
-                let mut indices = Vec::new();
-                for i in 0..min_row_count {
-                    if !crate::query::evaluator::evaluate_condition_row(cond, &column_values, i)? {
-                        indices.push(i);
-                    }
-                }
-                indices
+    /// Leaves the data directory complete without the WAL: checkpoints, forces every column
+    /// file and the WAL to disk, rewrites the catalog and records each table's final size.
+    /// Called on a clean exit; the storage manager stays usable afterwards.
+    pub fn shutdown(&mut self) -> Result<(), DbError> {
+        self.checkpoint()?;
+        for lock in self.tables.values() {
+            for col_store in lock.read().unwrap().columns.values() {
+                col_store.sync()?;
             }
-            None => {
-                let table_cols = self.columns.get_mut(table_name).ok_or_else(|| {
-                    DbError::InvalidData(format!("Table {} not found", table_name))
-                })?;
-                let table_indexes = self.indexes.get_mut(table_name).ok_or_else(|| {
-                    DbError::InvalidData(format!("Table {} not found", table_name))
-                })?;
-                for col in &columns {
-                    let col_store = table_cols.get_mut(&col.name).unwrap();
-                    col_store.clear()?;
-                    if let Some(index) = table_indexes.get_mut(&col.name) {
-                        index.clear()?;
-                    }
-                }
-                self.pending_rows.remove(table_name);
-                if let Some(table) = self.schema.get_table(table_name) {
-                    let mut table = table.clone();
-                    table.row_count = 0;
-                    self.schema.tables.insert(table_name.to_string(), table);
-                    self.schema.save()?;
+        }
+        self.wal.get_mut().unwrap().sync()?;
+        self.schema.save()?;
+        for lock in self.tables.values() {
+            self.record_table_size(&lock.read().unwrap());
+        }
+        log::info!("Shut down storage in {}", self.data_dir);
+        Ok(())
+    }
+
+    fn flush_table(&self, table_def: &Table, table: &mut TableStorage) -> Result<(), DbError> {
+        table.flush(table_def)?;
+        self.cache.lock().unwrap().invalidate_table(&table_def.name);
+        self.record_table_size(table);
+        Ok(())
+    }
+
+    fn repair_torn_columns(&mut self, table_name: &str) -> Result<(), DbError> {
+        let lock = self.table_lock(table_name)?;
+        let mut table = lock.write().unwrap();
+        let table = &mut *table;
+        let durable = table.durable_row_count();
+        let mut repaired = Vec::new();
+        for (col_name, col_store) in table.columns.iter_mut() {
+            if col_store.row_count() > durable {
+                if col_store.truncate_blocks(durable)? != durable {
+                    return Err(DbError::RecoveryError(format!(
+                        "Column {}.{} cannot be trimmed to {} rows",
+                        table_name, col_name, durable
+                    )));
                 }
-                return Ok(());
+                repaired.push(col_name.clone());
             }
-        };
+        }
+        for col_name in repaired {
+            log::warn!("Trimmed torn flush from column {}.{}", table_name, col_name);
+            self.cache.lock().unwrap().invalidate(table_name, &col_name);
+            self.rebuild_index(table, &col_name)?;
+        }
+        Ok(())
+    }
 
-        let table_cols = self.columns.get_mut(table_name).ok_or_else(|| {
-            DbError::InvalidData(format!("Table {} not found", table_name))
-        })?;
-        let table_indexes = self.indexes.get_mut(table_name).ok_or_else(|| {
-            DbError::InvalidData(format!("Table {} not found", table_name))
-        })?;
+    // Refills the implicit index of `col_name`, if it has one, from the column file
+    fn rebuild_index(&self, table: &mut TableStorage, col_name: &str) -> Result<(), DbError> {
+        let (Some(col_store), Some(index)) = (table.columns.get(col_name), table.indexes.get_mut(col_name)) else {
+            return Ok(());
+        };
+        index.clear()?;
+        for block_info in &col_store.metadata.blocks {
+            let block = col_store.read_block(block_info, &self.buffer)?;
+            index.append(&block.values, block_info.offset)?;
+        }
+        Ok(())
+    }
 
-        for col in &columns {
-            let col_store = table_cols.get_mut(&col.name).unwrap();
-            let values = column_values
-                .get(&col.name)
-                .cloned()
-                .unwrap_or_else(|| col_store.read(None, &mut self.buffer).unwrap_or_default());
-            let filtered_values: Vec<Value> = keep_indices
+    fn rebuild_unique_values(&self, table: &mut TableStorage) -> Result<(), DbError> {
+        let unique_columns: Vec<String> = match self.schema.get_table(&table.name) {
+            Some(table_def) => table_def
+                .columns
                 .iter()
-                .filter(|&&i| i < values.len())
-                .map(|&i| values[i].clone())
-                .collect();
-            col_store.clear()?;
-            if !filtered_values.is_empty() {
-                let compression = match col.data_type {
-                    DataType::String => CompressionType::Dictionary,
-                    _ => CompressionType::Rle,
-                };
-                col_store.append(&filtered_values, compression)?;
+                .filter(|c| c.requires_unique())
+                .map(|c| c.name.clone())
+                .collect(),
+            None => return Ok(()),
+        };
+        let mut table_sets = HashMap::new();
+        for col_name in unique_columns {
+            let values = self.read_locked_column(table, &col_name, None)?;
+            let positions = values.into_iter().enumerate().filter(|(_, v)| !v.is_null()).map(|(row, v)| (v, row));
+            table_sets.insert(col_name, positions.collect());
+        }
+        table.unique_values = table_sets;
+        Ok(())
+    }
+
+    // Rebuilds the declared indexes of `table` from its rows; with `stale_only`, just the ones
+    // that do not hold every row
+    fn rebuild_declared_indexes(&self, table: &mut TableStorage, stale_only: bool) -> Result<(), DbError> {
+        let index_defs: Vec<IndexDef> = match self.schema.get_table(&table.name) {
+            Some(table_def) => table_def.indexes.clone(),
+            None => return Ok(()),
+        };
+        let rows = table.row_count() as u64;
+        for index_def in index_defs {
+            let stale = table.declared_index(&index_def.name).is_some_and(|index| index.rows() != rows);
+            if stale_only && !stale {
+                continue;
             }
-            if let Some(index) = table_indexes.get_mut(&col.name) {
-                index.clear()?;
-                if !filtered_values.is_empty() {
-                    index.append(&filtered_values, 0)?;
+            let mut columns = Vec::with_capacity(index_def.columns.len());
+            for column in &index_def.columns {
+                columns.push(self.read_locked_column(table, column, None)?);
+            }
+            if let Some(index) = table.declared_indexes.get_mut(&index_def.name) {
+                index.rebuild(&columns)?;
+                if stale_only {
+                    log::warn!("Rebuilt stale index {} on {}", index_def.name, table.name);
                 }
             }
         }
-        self.pending_rows.remove(table_name);
+        Ok(())
+    }
 
-        if let Some(table) = self.schema.get_table(table_name) {
-            let mut table = table.clone();
-            table.row_count = keep_indices.len() as u64;
-            self.schema.tables.insert(table_name.to_string(), table);
-            self.schema.save()?;
+    // `batch` holds the values rows inserted alongside this one give each unique column
+    fn check_unique(&self, table_def: &Table, table: &TableStorage, row: &[Value], batch: &HashMap<String, HashSet<Value>>) -> Result<(), DbError> {
+        for (value, col) in row.iter().zip(table_def.columns.iter()) {
+            if value.is_null() {
+                continue; // NULLs never conflict
+            }
+            if table.unique_values.get(&col.name).is_some_and(|set| set.contains_key(value))
+                || batch.get(&col.name).is_some_and(|set| set.contains(value))
+            {
+                return Err(DbError::ValidationError(format!(
+                    "Duplicate value {} for unique column {}.{}",
+                    value, table_def.name, col.name
+                )));
+            }
         }
         Ok(())
     }
 
-    pub fn drop_table(&mut self, table_name: &str) -> Result<(), DbError> {
-        if !self.schema.tables.contains_key(table_name) {
-            return Err(DbError::InvalidData(format!("Table {} not found", table_name)));
+    // A row may reference one inserted before it in the same batch; see `check_unique` for
+    // `batch`. Other referenced tables are read-locked only for the lookup.
+    fn check_foreign_keys(&self, table_def: &Table, table: &TableStorage, row: &[Value], batch: &HashMap<String, HashSet<Value>>) -> Result<(), DbError> {
+        for (value, col) in row.iter().zip(table_def.columns.iter()) {
+            let fk = match &col.references {
+                Some(fk) if !value.is_null() => fk,
+                _ => continue,
+            };
+            // Referenced columns are always unique, so their value sets double as a key lookup
+            let holds = |referenced: &TableStorage| {
+                referenced.unique_values.get(&fk.reference_column).is_some_and(|set| set.contains_key(value))
+            };
+            let exists = if fk.reference_table == table_def.name {
+                holds(table) || batch.get(&fk.reference_column).is_some_and(|set| set.contains(value))
+            } else {
+                self.tables.get(&fk.reference_table).is_some_and(|lock| holds(&lock.read().unwrap()))
+            };
+            if !exists {
+                return Err(DbError::ValidationError(format!(
+                    "{}.{} = {} has no matching row in {}.{}",
+                    table_def.name, col.name, value, fk.reference_table, fk.reference_column
+                )));
+            }
         }
+        Ok(())
+    }
 
-        let table_cols = self.columns.remove(table_name).ok_or_else(|| {
-            DbError::InvalidData(format!("Table {} not found", table_name))
-        })?;
-        for (col_name, _) in table_cols {
-            let file_path = format!("{}/columns/{}.dat", self.data_dir, col_name);
-            if Path::new(&file_path).exists() {
-                fs::remove_file(&file_path)?;
+    pub fn data_dir(&self) -> &str {
+        &self.data_dir
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.wal.lock().unwrap().durability()
+    }
+
+    /// How far writes are forced to disk from now on; see `Durability`. Moving to a stricter
+    /// level syncs the WAL at once, so writes made under the old level are covered too.
+    pub fn set_durability(&mut self, durability: Durability) -> Result<(), DbError> {
+        let wal = self.wal.get_mut().unwrap();
+        let stricter = matches!(
+            (wal.durability(), durability),
+            (Durability::Off, Durability::Normal | Durability::Full) | (Durability::Normal, Durability::Full)
+        );
+        wal.set_durability(durability);
+        if stricter {
+            wal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Derives the key of ENCRYPTED columns from `passphrase`; see `ColumnCipher::open`. Call it
+    /// before `recover`, which decrypts the logged values of encrypted columns. Without it,
+    /// reading or writing an encrypted column fails with `SecurityError`.
+    pub fn set_encryption_passphrase(&mut self, passphrase: &Passphrase) -> Result<(), DbError> {
+        self.set_cipher(Some(Arc::new(ColumnCipher::open(&self.data_dir, passphrase)?)));
+        Ok(())
+    }
+
+    fn set_cipher(&mut self, cipher: Option<Arc<ColumnCipher>>) {
+        for lock in self.tables.values() {
+            for col_store in lock.write().unwrap().columns.values_mut() {
+                col_store.set_cipher(cipher.clone());
             }
         }
+        self.cipher = cipher;
+    }
 
-        let table_indexes = self.indexes.remove(table_name).ok_or_else(|| {
-            DbError::InvalidData(format!("Table {} not found", table_name))
-        })?;
-        for (col_name, _) in table_indexes {
-            let index_path = format!("{}/indexes/{}_{}.idx", self.data_dir, table_name, col_name);
-            if Path::new(&index_path).exists() {
-                fs::remove_file(&index_path)?;
+    // `row` as the WAL logs it, with the values of encrypted columns encrypted
+    fn seal_row(&self, table_def: &Table, row: &[Value]) -> Result<Vec<Value>, DbError> {
+        row.iter()
+            .zip(&table_def.columns)
+            .map(|(value, col)| match (col.encrypted, &self.cipher) {
+                (false, _) => Ok(value.clone()),
+                (true, Some(cipher)) => cipher.seal_value(value),
+                (true, None) => Err(missing_key(&table_def.name, &col.name)),
+            })
+            .collect()
+    }
+
+    // Reverses `seal_row` for a row read back from the WAL
+    fn open_row(&self, table_def: &Table, row: Vec<Value>) -> Result<Vec<Value>, DbError> {
+        row.into_iter()
+            .zip(&table_def.columns)
+            .map(|(value, col)| match (col.encrypted, &self.cipher) {
+                (false, _) => Ok(value),
+                (true, Some(cipher)) => cipher.open_value(&value),
+                (true, None) => Err(missing_key(&table_def.name, &col.name)),
+            })
+            .collect()
+    }
+
+    /// The catalog as last saved, so each table's row count and next rowid are those of the
+    /// last checkpoint; see `current_schema` and `row_count`.
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// The catalog with each table's live row count and next rowid.
+    pub fn current_schema(&self) -> Schema {
+        let mut schema = self.schema.clone();
+        for (table_name, table_def) in schema.tables.iter_mut() {
+            if let Some(lock) = self.tables.get(table_name) {
+                let table = lock.read().unwrap();
+                table_def.row_count = table.row_count() as u64;
+                table_def.next_rowid = table.next_rowid;
             }
         }
+        schema
+    }
 
-        let metadata_dir = format!("{}/metadata", self.data_dir);
-        for col in self.schema.get_table(table_name).unwrap().columns.iter() {
-            let metadata_path = format!("{}/{}.json", metadata_dir, col.name);
-            if Path::new(&metadata_path).exists() {
-                fs::remove_file(&metadata_path)?;
+    // Saves the catalog with the live row counts and next rowids, so no table may be locked
+    fn save_catalog(&mut self) -> Result<(), DbError> {
+        self.schema = self.current_schema();
+        self.schema.save()
+    }
+
+    pub fn users(&self) -> &UserStore {
+        &self.users
+    }
+
+    pub fn users_mut(&mut self) -> &mut UserStore {
+        &mut self.users
+    }
+
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    pub fn audit_log_mut(&mut self) -> &mut AuditLog {
+        &mut self.audit_log
+    }
+
+    /// Streams every committed insert and delete to the returned receiver. A subscriber more
+    /// than `capacity` records behind receives a `ReplicationError` and is dropped.
+    pub fn subscribe_changes(&mut self, capacity: usize) -> crossbeam::channel::Receiver<Result<ChangeRecord, DbError>> {
+        self.change_feed.get_mut().unwrap().subscribe(capacity)
+    }
+
+    // Holds back change records until `end_change_batch`, for work that may still be rolled back
+    pub(crate) fn begin_change_batch(&mut self) {
+        self.change_feed.get_mut().unwrap().begin_batch();
+    }
+
+    pub(crate) fn end_change_batch(&mut self, committed: bool) {
+        self.change_feed.get_mut().unwrap().end_batch(committed);
+    }
+
+    pub fn table_version(&self, table_name: &str) -> u64 {
+        self.table_versions.lock().unwrap().get(table_name).copied().unwrap_or(0)
+    }
+
+    // Every change to a table's rows passes through here, so it also drops the table's cached
+    // columns and refreshes the size gauges
+    fn bump_version(&self, table: &TableStorage) {
+        *self.table_versions.lock().unwrap().entry(table.name.clone()).or_insert(0) += 1;
+        self.cache.lock().unwrap().invalidate_table(&table.name);
+        self.record_table_size(table);
+    }
+
+    // `bump_version` by name, for a table that may have just been dropped or renamed
+    fn bump_version_of(&self, table_name: &str) {
+        match self.tables.get(table_name) {
+            Some(lock) => self.bump_version(&lock.read().unwrap()),
+            None => {
+                *self.table_versions.lock().unwrap().entry(table_name.to_string()).or_insert(0) += 1;
+                self.cache.lock().unwrap().invalidate_table(table_name);
+                self.metrics.record_table_size(table_name, 0, 0);
             }
         }
+    }
+
+    fn record_table_size(&self, table: &TableStorage) {
+        let (rows, bytes) = table.size(&self.data_dir);
+        self.metrics.record_table_size(&table.name, rows, bytes);
+    }
+
+    /// Rows in `table_name`, including ones not yet flushed, and the bytes its column files
+    /// take on disk.
+    pub fn table_size(&self, table_name: &str) -> (u64, u64) {
+        self.tables.get(table_name).map_or((0, 0), |lock| lock.read().unwrap().size(&self.data_dir))
+    }
+
+    /// Rows in `table_name`, including ones not yet flushed. Unlike the catalog's count in
+    /// `schema`, it is never behind.
+    pub fn row_count(&self, table_name: &str) -> usize {
+        self.tables.get(table_name).map_or(0, |lock| lock.read().unwrap().row_count())
+    }
+
+    pub fn create_table(&mut self, table: &Table) -> Result<(), DbError> {
+        if let Some(col) = table.columns.iter().find(|c| c.encrypted && self.cipher.is_none()) {
+            return Err(missing_key(&table.name, &col.name));
+        }
+        // Let the schema reject the definition before any files are created
+        self.schema.add_table(&table.name, table.columns.clone())?;
+        let mut storage = TableStorage::open(&self.data_dir, self.table_def(&table.name)?, self.cipher.as_ref())?;
+        self.rebuild_unique_values(&mut storage)?;
+        self.bump_version(&storage);
+        self.tables.insert(table.name.clone(), Arc::new(RwLock::new(storage)));
+        Ok(())
+    }
 
-        self.pending_rows.remove(table_name);
-        self.schema.tables.remove(table_name);
-        self.schema.save()?;
+    /// `CREATE INDEX`: records `index` on `table_name` and builds it from the rows already there.
+    pub fn create_index(&mut self, table_name: &str, index: IndexDef) -> Result<(), DbError> {
+        let path = declared_index_path(&self.data_dir, table_name, &index);
+        let declared = DeclaredIndex::open(&path, &index)?;
+        let name = index.name.clone();
+        self.schema.add_index(table_name, index)?;
+        let mut table = self.write_table(table_name)?;
+        table.declared_indexes.insert(name, declared);
+        self.rebuild_declared_indexes(&mut table, false)?;
+        self.bump_version(&table);
         Ok(())
     }
+
+    /// `DROP INDEX`: removes the index `name` of `table_name` and its file. Lookups stop using
+    /// it at once. The indexes a table keeps implicitly cannot be dropped: those backing a
+    /// UNIQUE or PRIMARY KEY constraint, and those on ID and Name columns.
+    pub fn drop_index(&mut self, table_name: &str, name: &str) -> Result<(), DbError> {
+        if let Some(implicit) = self.list_indexes(table_name)?.into_iter().find(|i| i.implicit && i.name.eq_ignore_ascii_case(name)) {
+            let reason = if implicit.kind == "unique" { "backs a UNIQUE or PRIMARY KEY constraint" } else { "is kept for every ID and Name column" };
+            return Err(DbError::IndexError(format!(
+                "Cannot drop index {}: it {} on {}.{}",
+                implicit.name, reason, table_name, implicit.columns[0]
+            )));
+        }
+        let index = self.schema.remove_index(table_name, name)?;
+        let declared = self.write_table(table_name)?.declared_indexes.remove(&index.name);
+        if let Some(declared) = declared {
+            declared.remove()?;
+        }
+        self.bump_version_of(table_name);
+        Ok(())
+    }
+
+    /// Every index of `table_name`, implicit ones first in column order, then those from
+    /// CREATE INDEX in the order they were created.
+    pub fn list_indexes(&self, table_name: &str) -> Result<Vec<IndexInfo>, DbError> {
+        let table_def = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?;
+        let table = self.read_table(table_name)?;
+        let mut listed = Vec::new();
+        for col in &table_def.columns {
+            let implicit = |suffix: &str, kind: &'static str| IndexInfo {
+                name: format!("{}_{}_{}", table_def.name, col.name, suffix),
+                kind,
+                columns: vec![col.name.clone()],
+                implicit: true,
+            };
+            if table.indexes.contains_key(&col.name) {
+                listed.push(implicit("hash", "hash"));
+            }
+            if table.unique_values.contains_key(&col.name) {
+                listed.push(implicit("key", "unique"));
+            }
+        }
+        listed.extend(table_def.indexes.iter().map(|i| IndexInfo {
+            name: i.name.clone(),
+            kind: match i.kind {
+                IndexKind::FullText => "fulltext",
+                IndexKind::Composite => "composite",
+            },
+            columns: i.columns.clone(),
+            implicit: false,
+        }));
+        Ok(listed)
+    }
+
+    /// Rows of `table_name` whose `column_name` contains all of `terms`, or any of them when
+    /// `any`, found through a FULLTEXT index on the column. `None` when the column has no such
+    /// index or the index does not cover every row.
+    pub fn fulltext_lookup(&self, table_name: &str, column_name: &str, terms: &[String], any: bool) -> Option<Vec<usize>> {
+        let table_def = self.schema.get_table(table_name)?;
+        let table = self.read_table(table_name).ok()?;
+        table_def
+            .indexes
+            .iter()
+            .filter(|i| i.kind == IndexKind::FullText && i.columns[0] == column_name)
+            .find_map(|i| table.current_index(&i.name)?.match_terms(terms, any))
+    }
+
+    /// Rows of `table_name` holding every value in `equalities`, given as (column, value), found
+    /// through the composite index whose leading columns they cover the most of (see
+    /// `IndexKind::Composite`). Returns the index used with the rows, or `None` when no index
+    /// applies. The rows match the covered prefix only; other equalities still need checking.
+    pub fn composite_lookup(&self, table_name: &str, equalities: &[(String, Value)]) -> Option<(String, Vec<usize>)> {
+        let table_def = self.schema.get_table(table_name)?;
+        let table = self.read_table(table_name).ok()?;
+        let value_of = |column: &String| equalities.iter().find(|(c, _)| c == column).map(|(_, v)| v.clone());
+        table_def
+            .indexes
+            .iter()
+            .filter(|i| i.kind == IndexKind::Composite)
+            .filter_map(|i| {
+                let prefix: Vec<Value> = i.columns.iter().map_while(value_of).collect();
+                (!prefix.is_empty()).then_some((i, prefix))
+            })
+            .max_by_key(|(_, prefix)| prefix.len())
+            .and_then(|(i, prefix)| {
+                let rows = table.current_index(&i.name)?.lookup_prefix(&prefix)?;
+                Some((i.name.clone(), rows))
+            })
+    }
+
+    /// Inserts a row. With an empty `columns` list the values are positional; otherwise they
+    /// map onto the named columns and the rest take their DEFAULT (or NULL).
+    pub fn insert_row(&self, table_name: &str, columns: &[String], values: Vec<Value>) -> Result<(), DbError> {
+        self.insert_row_as(table_name, columns, values, None)
+    }
+
+    /// Inserts `rows`, each mapped onto `columns` as in `insert_row`, as one batch. Every row is
+    /// checked, against the table and against the rows before it, before any is written, so a
+    /// failing row leaves the table unchanged. The batch reaches the WAL with a single flush and
+    /// the catalog once. Returns the number of rows inserted.
+    pub fn insert_rows(&self, table_name: &str, columns: &[String], rows: Vec<Vec<Value>>) -> Result<usize, DbError> {
+        let table_def = self.table_def(table_name)?;
+        let mut table = self.write_table(table_name)?;
+        let rows = self.check_rows(table_def, &table, columns, rows).map_err(|(_, e)| e)?;
+        let inserted = rows.len();
+        self.append_rows(table_def, &mut table, rows, None)?;
+        Ok(inserted)
+    }
+
+    // Inserts a row with the given rowid, as replaying a logged insert does, or with the next
+    // one when `None`
+    fn insert_row_as(
+        &self,
+        table_name: &str,
+        columns: &[String],
+        values: Vec<Value>,
+        rowid: Option<u64>,
+    ) -> Result<(), DbError> {
+        let table_def = self.table_def(table_name)?;
+        let mut table = self.write_table(table_name)?;
+        let rows = self.check_rows(table_def, &table, columns, vec![values]).map_err(|(_, e)| e)?;
+        self.append_rows(table_def, &mut table, rows, rowid)
+    }
+
+    // Completes each row and checks its types, constraints, keys and references, counting the
+    // rows before it in the batch as already inserted. A failure comes with the failing row's
+    // position in `rows`.
+    fn check_rows(
+        &self,
+        table_def: &Table,
+        table: &TableStorage,
+        columns: &[String],
+        rows: Vec<Vec<Value>>,
+    ) -> Result<Vec<Vec<Value>>, (usize, DbError)> {
+        let table_name = &table_def.name;
+        let id_index = table.indexes.get("ID");
+        // Values the batch gives each unique column, and the ID column when indexed
+        let mut batch_keys: HashMap<String, HashSet<Value>> = HashMap::new();
+        let mut checked = Vec::with_capacity(rows.len());
+        for (i, values) in rows.into_iter().enumerate() {
+            let check = |batch_keys: &HashMap<String, HashSet<Value>>| -> Result<Vec<Value>, DbError> {
+                let row = self.schema.complete_row(table_name, columns, values)?;
+                self.schema.validate_row(table_name, &row)?;
+                // NULLs are never indexed, so they cannot collide
+                if let Some(id_index) = id_index.filter(|_| !row[0].is_null()) {
+                    let taken = batch_keys.get("ID").is_some_and(|ids| ids.contains(&row[0]));
+                    if taken || !id_index.lookup(&row[0])?.is_empty() {
+                        return Err(DbError::InvalidData(format!("Duplicate ID: {:?}", row[0])));
+                    }
+                }
+                self.check_unique(table_def, table, &row, batch_keys)?;
+                self.check_foreign_keys(table_def, table, &row, batch_keys)?;
+                Ok(row)
+            };
+            let row = check(&batch_keys).map_err(|e| (i, e))?;
+            for (value, col) in row.iter().zip(&table_def.columns) {
+                let keyed = (col.name == "ID" && id_index.is_some()) || table.unique_values.contains_key(&col.name);
+                if keyed && !value.is_null() {
+                    batch_keys.entry(col.name.clone()).or_default().insert(value.clone());
+                }
+            }
+            checked.push(row);
+        }
+        Ok(checked)
+    }
+
+    // Appends rows that passed `check_rows`. Rowids continue from `first_rowid` when given, as
+    // when replaying logged inserts, and from the table's next rowid otherwise. The row count
+    // and next rowid reach the catalog with the next checkpoint; until then recovery recounts
+    // them from the WAL.
+    fn append_rows(&self, table_def: &Table, table: &mut TableStorage, rows: Vec<Vec<Value>>, first_rowid: Option<u64>) -> Result<(), DbError> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let table_name = &table_def.name;
+
+        // Log the rows, each with its rowid last, before any can reach a column file
+        let first_index = table.row_count();
+        let sealed = rows.iter().map(|row| self.seal_row(table_def, row)).collect::<Result<Vec<_>, _>>()?;
+        let mut rowids = Vec::with_capacity(rows.len());
+        let mut records = Vec::with_capacity(rows.len());
+        for (i, mut logged) in sealed.into_iter().enumerate() {
+            let rowid = table.take_rowid(first_rowid.map(|rowid| rowid + i as u64));
+            logged.push(Value::Int64(rowid as i64));
+            records.push(WalRecord::insert(table_name, (first_index + i) as u64, &logged));
+            rowids.push(rowid);
+        }
+        self.wal.lock().unwrap().append(&records)?;
+
+        // Buffer the rows
+        let mut change_feed = self.change_feed.lock().unwrap();
+        for ((i, row), rowid) in rows.into_iter().enumerate().zip(rowids) {
+            let row_index = first_index + i;
+            if change_feed.has_subscribers() {
+                change_feed.publish(ChangeRecord::Insert {
+                    table: table_name.to_string(),
+                    row: row.clone(),
+                });
+            }
+            for index_def in &table_def.indexes {
+                let key: Option<Vec<&Value>> = index_def
+                    .columns
+                    .iter()
+                    .map(|name| table_def.columns.iter().position(|c| c.name == *name).map(|pos| &row[pos]))
+                    .collect();
+                // An index behind the table stays behind until rebuilt; lookups skip it meanwhile
+                if let (Some(index), Some(key)) = (table.declared_indexes.get_mut(&index_def.name), key) {
+                    if index.rows() == row_index as u64 {
+                        index.add(row_index as u64, &key);
+                    }
+                }
+            }
+            for (value, col) in row.iter().zip(table_def.columns.iter()) {
+                if let Some(set) = table.unique_values.get_mut(&col.name).filter(|_| !value.is_null()) {
+                    set.insert(value.clone(), row_index);
+                }
+            }
+            for (value, col) in row.into_iter().zip(table_def.columns.iter()) {
+                table.pending_rows.entry(col.name.clone()).or_default().push(value);
+            }
+            table.pending_rows.entry(ROWID.to_string()).or_default().push(Value::Int64(rowid as i64));
+        }
+        drop(change_feed);
+
+        // Flush if the buffer is full. The WAL spans every table, so truncating it is left to
+        // a checkpoint, which needs the storage lock exclusively.
+        if table.pending_row_count() >= self.max_rows_per_segment {
+            self.flush_table(table_def, table)?;
+            self.checkpoint_due.store(true, Ordering::Relaxed);
+        }
+        self.bump_version(table);
+        Ok(())
+    }
+
+    /// Inserts `rows` like `insert_rows`, except that a row whose value for the `ON CONFLICT`
+    /// column is already taken is either skipped (DO NOTHING) or updates the row holding the
+    /// value instead (DO UPDATE). The assignments see that row's columns by name and the
+    /// proposed row's as `EXCLUDED.<column>`. Rows are taken in order, so one can conflict with
+    /// a row inserted or updated earlier in the call. The updates are written in a single
+    /// rewrite of the table, which happens together with the inserts as one `atomically` write.
+    /// The conflict column must be UNIQUE or a PRIMARY KEY.
+    pub fn upsert_rows(
+        &mut self,
+        table_name: &str,
+        columns: &[String],
+        rows: Vec<Vec<Value>>,
+        on_conflict: &OnConflict,
+    ) -> Result<UpsertCounts, DbError> {
+        let table_def = self.table_def(table_name)?.clone();
+        let conflict_pos = conflict_column(&table_def, on_conflict)?;
+        let names: Vec<String> = table_def.columns.iter().map(|c| c.name.clone()).collect();
+        let mut counts = UpsertCounts::default();
+        let mut inserts: Vec<Vec<Value>> = Vec::new();
+        // The conflict value of each row in `inserts`
+        let mut inserted_keys: HashMap<Value, usize> = HashMap::new();
+        // Every row of the table, read for the first update, and which row holds each conflict value
+        let mut existing: Option<HashMap<String, Vec<Value>>> = None;
+        let mut existing_keys: HashMap<Value, usize> = HashMap::new();
+        // The rows updated, as they were before the call
+        let mut old_rows: BTreeMap<usize, Vec<Value>> = BTreeMap::new();
+
+        for values in rows {
+            let proposed = self.schema.complete_row(table_name, columns, values)?;
+            let key = proposed[conflict_pos].clone();
+            let existing_row = match &existing {
+                _ if key.is_null() => None,
+                Some(_) => existing_keys.get(&key).copied(),
+                None => self.unique_lookup(table_name, &on_conflict.column, &key).and_then(|rows| rows.first().copied()),
+            };
+            let assignments = match &on_conflict.action {
+                _ if existing_row.is_none() && !inserted_keys.contains_key(&key) => {
+                    if !key.is_null() {
+                        inserted_keys.insert(key, inserts.len());
+                    }
+                    inserts.push(proposed);
+                    continue;
+                }
+                ConflictAction::DoNothing => continue,
+                ConflictAction::DoUpdate(assignments) => assignments,
+            };
+            counts.updated += 1;
+
+            let Some(pos) = existing_row else {
+                // The row being updated is one this call inserts
+                let i = inserted_keys.remove(&key).unwrap_or_default();
+                inserts[i] = updated_row(&table_def, &inserts[i], &proposed, assignments)?;
+                if !inserts[i][conflict_pos].is_null() {
+                    inserted_keys.insert(inserts[i][conflict_pos].clone(), i);
+                }
+                continue;
+            };
+            if existing.is_none() {
+                // Rewriting columns invalidates WAL row positions, so make pending rows durable first
+                self.checkpoint()?;
+                let column_values = self.read_columns(table_name, &names, None)?;
+                existing_keys = column_values[&on_conflict.column]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, v)| !v.is_null())
+                    .map(|(row, v)| (v.clone(), row))
+                    .collect();
+                existing = Some(column_values);
+            }
+            let column_values = existing.as_mut().unwrap();
+            let old_row: Vec<Value> = names.iter().map(|name| column_values[name][pos].clone()).collect();
+            let new_row = updated_row(&table_def, &old_row, &proposed, assignments)?;
+            existing_keys.remove(&old_row[conflict_pos]);
+            if !new_row[conflict_pos].is_null() {
+                existing_keys.insert(new_row[conflict_pos].clone(), pos);
+            }
+            for (name, value) in names.iter().zip(new_row) {
+                if let Some(values) = column_values.get_mut(name) {
+                    values[pos] = value;
+                }
+            }
+            old_rows.entry(pos).or_insert(old_row);
+        }
+
+        let Some(column_values) = existing else {
+            if !inserts.is_empty() {
+                counts.inserted = self.insert_rows(table_name, &[], inserts)?;
+            }
+            return Ok(counts);
+        };
+
+        // The updated rows must pass every check a newly inserted one would
+        let new_rows: Vec<Vec<Value>> = old_rows
+            .keys()
+            .map(|&pos| names.iter().map(|name| column_values[name][pos].clone()).collect())
+            .collect();
+        let table = self.read_table(table_name)?;
+        for new_row in &new_rows {
+            self.schema.validate_row(table_name, new_row)?;
+            self.check_foreign_keys(&table_def, &table, new_row, &HashMap::new())?;
+        }
+        drop(table);
+        for col in table_def.columns.iter().filter(|c| c.requires_unique()) {
+            let mut seen = HashSet::new();
+            if let Some(value) = column_values[&col.name].iter().filter(|v| !v.is_null()).find(|v| !seen.insert(*v)) {
+                return Err(DbError::ValidationError(format!(
+                    "Duplicate value {} for unique column {}.{}",
+                    value, table_name, col.name
+                )));
+            }
+        }
+        for (child_table, child_col) in self.schema.referencing_columns(table_name) {
+            let referenced = child_col.references.as_ref().map(|fk| fk.reference_column.clone()).unwrap_or_default();
+            let Some(pos) = names.iter().position(|name| *name == referenced) else { continue };
+            if old_rows.values().zip(&new_rows).any(|(old_row, new_row)| old_row[pos] != new_row[pos]) {
+                return Err(DbError::ValidationError(format!(
+                    "Cannot change {}.{}: {}.{} references it",
+                    table_name, referenced, child_table, child_col.name
+                )));
+            }
+        }
+
+        let subscribed = self.change_feed.get_mut().unwrap().has_subscribers();
+        counts.inserted = self.atomically(&[table_name.to_string()], |storage| {
+            storage.replace_rows(table_name, column_values)?;
+            if inserts.is_empty() {
+                return Ok(0);
+            }
+            storage.insert_rows(table_name, &[], inserts)
+        })?;
+        if subscribed {
+            let change_feed = self.change_feed.get_mut().unwrap();
+            change_feed.publish(ChangeRecord::Delete { table: table_name.to_string(), rows: old_rows.into_values().collect() });
+            for row in new_rows {
+                change_feed.publish(ChangeRecord::Insert { table: table_name.to_string(), row });
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Loads rows from a CSV file whose header names the target columns; columns missing from
+    /// the header take their DEFAULT or NULL. The rows are inserted as one batch (see
+    /// `insert_rows`), so a row that fails leaves the table unchanged. Returns the number of
+    /// rows imported.
+    pub fn import_csv(&self, table_name: &str, path: &str) -> Result<usize, DbError> {
+        let table_def = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?
+            .clone();
+        let contents = fs::read_to_string(path)?;
+        let mut records = crate::csv::parse_records(&contents)?.into_iter();
+        let header = records
+            .next()
+            .ok_or_else(|| DbError::InvalidData(format!("{} is empty", path)))?;
+        let mut columns = Vec::new();
+        for name in &header.fields {
+            let name = name.as_deref().unwrap_or("").trim();
+            let col = table_def
+                .get_column(name)
+                .ok_or_else(|| DbError::InvalidData(format!("line 1: column {}.{} not found", table_name, name)))?;
+            columns.push(col.clone());
+        }
+
+        // Parse everything up front so malformed input never leaves a partial import
+        let mut lines = Vec::new();
+        let mut rows = Vec::new();
+        for record in records {
+            if record.fields.len() != columns.len() {
+                return Err(DbError::InvalidData(format!(
+                    "line {}: expected {} fields, got {}",
+                    record.line,
+                    columns.len(),
+                    record.fields.len()
+                )));
+            }
+            let values = record
+                .fields
+                .iter()
+                .zip(&columns)
+                .map(|(field, col)| {
+                    crate::csv::parse_field(field.as_deref(), &col.data_type)
+                        .map_err(|e| DbError::InvalidData(format!("line {}: column {}: {}", record.line, col.name, e)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            lines.push(record.line);
+            rows.push(values);
+        }
+
+        let column_names: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+        let mut table = self.write_table(table_name)?;
+        let rows = self
+            .check_rows(&table_def, &table, &column_names, rows)
+            .map_err(|(i, e)| DbError::InvalidData(format!("line {}: {}", lines[i], e)))?;
+        let imported = rows.len();
+        self.append_rows(&table_def, &mut table, rows, None)?;
+        log::info!("Imported {} rows into {} from {}", imported, table_name, path);
+        Ok(imported)
+    }
+
+    /// Writes every row of `table_name`, including rows not yet flushed, to a Parquet file at
+    /// `path`. Returns the number of rows exported. See `crate::export::write_parquet` for the
+    /// type mapping.
+    pub fn export_parquet(&self, table_name: &str, path: &str) -> Result<usize, DbError> {
+        let table_def = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?
+            .clone();
+        let table = self.read_table(table_name)?;
+        let mut columns = Vec::with_capacity(table_def.columns.len());
+        for col in &table_def.columns {
+            columns.push(self.read_locked_column(&table, &col.name, None)?);
+        }
+        drop(table);
+        crate::export::write_parquet(path, &table_def, &columns)?;
+        let exported = columns.first().map_or(0, |c| c.len());
+        log::info!("Exported {} rows from {} to {}", exported, table_name, path);
+        Ok(exported)
+    }
+
+    /// Renders `CREATE TABLE`, `INSERT` and `CREATE INDEX` statements that recreate `table_name`, or every
+    /// table when `None`. Referenced tables are emitted before the tables that point at them.
+    pub fn dump_sql(&self, table_name: Option<&str>) -> Result<String, DbError> {
+        let tables = match table_name {
+            Some(name) => vec![self
+                .schema
+                .get_table(name)
+                .cloned()
+                .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", name)))?],
+            None => self.tables_in_dependency_order(),
+        };
+
+        let mut sql = String::new();
+        for table in tables {
+            sql.push_str(&table.create_sql());
+            sql.push('\n');
+            let table_storage = self.read_table(&table.name)?;
+            let mut columns = Vec::with_capacity(table.columns.len());
+            for col in &table.columns {
+                columns.push(self.read_locked_column(&table_storage, &col.name, None)?);
+            }
+            drop(table_storage);
+            let row_count = columns.first().map_or(0, |c| c.len());
+            for row in 0..row_count {
+                let values = columns.iter().map(|c| c[row].to_sql_literal()).collect::<Vec<_>>();
+                sql.push_str(&format!("INSERT INTO {} VALUES ({});\n", quote_identifier(&table.name), values.join(", ")));
+            }
+            for index in &table.indexes {
+                sql.push_str(&index.to_sql(&table.name));
+                sql.push('\n');
+            }
+        }
+        Ok(sql)
+    }
+
+    // Sorted by name, except that a table waits until every table it references is emitted
+    fn tables_in_dependency_order(&self) -> Vec<Table> {
+        let mut remaining: Vec<Table> = self.schema.tables().cloned().collect();
+        remaining.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut ordered: Vec<Table> = Vec::with_capacity(remaining.len());
+        while !remaining.is_empty() {
+            let ready = remaining.iter().position(|t| {
+                t.columns.iter().filter_map(|c| c.references.as_ref()).all(|fk| {
+                    fk.reference_table == t.name || ordered.iter().any(|o| o.name == fk.reference_table)
+                })
+            });
+            // add_table rejects dangling references, so a cycle is the only way to stall
+            ordered.push(remaining.remove(ready.unwrap_or(0)));
+        }
+        ordered
+    }
+
+    /// Flushes pending rows, then copies every file of the database except the audit log into
+    /// `dest`, which must not exist yet, returning the bytes copied. `restore_backup` puts the
+    /// copy back.
+    pub fn backup_to(&mut self, dest: &Path) -> Result<u64, DbError> {
+        self.checkpoint()?;
+        let archive = Path::new(self.wal_archive.dir()).to_path_buf();
+        copy_dir(Path::new(&self.data_dir), dest, &[dest, self.audit_log.path(), &archive])
+    }
+
+    /// `BACKUP TO`: writes a full copy of the database to `dest` and returns its size in bytes.
+    /// Writers need the storage lock the caller holds throughout, so the copy is a single
+    /// point in time even while other sessions are writing. `dest` must not exist and must lie
+    /// outside the data directory. From here on the WAL is archived for `BACKUP INCREMENTAL`.
+    pub fn backup(&mut self, dest: &Path) -> Result<u64, DbError> {
+        let id = dest.display().to_string();
+        if dest.exists() {
+            return Err(DbError::BackupError(format!("{} already exists", id)));
+        }
+        if is_within(dest, Path::new(&self.data_dir))? {
+            return Err(DbError::BackupError(format!("{} is inside the data directory", id)));
+        }
+        log_backup(&id, "started");
+        let manifest = BackupManifest {
+            id: format!(
+                "{}-{}",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+                rand::distributions::DistString::sample_string(&rand::distributions::Alphanumeric, &mut rand::thread_rng(), 8)
+            ),
+        };
+        let outcome = self.backup_to(dest).and_then(|bytes| {
+            let json = serde_json::to_string_pretty(&manifest).map_err(|e| DbError::SerializationError(e.to_string()))?;
+            fs::write(dest.join(BACKUP_MANIFEST), &json)?;
+            self.wal_archive.start(&manifest.id, self.schema.version())?;
+            Ok(bytes + json.len() as u64)
+        });
+        match outcome {
+            Ok(bytes) => {
+                log_backup(&id, &format!("completed full backup {}, {} bytes", manifest.id, bytes));
+                Ok(bytes)
+            }
+            Err(e) => {
+                log_backup(&id, &format!("failed: {}", e));
+                // Leave no partial copy that could later be mistaken for a backup
+                let _ = fs::remove_dir_all(dest);
+                Err(DbError::BackupError(format!("{}: {}", id, e)))
+            }
+        }
+    }
+
+    /// `BACKUP INCREMENTAL TO`: adds to the full backup in `dest` the next WAL segment, holding
+    /// the rows inserted since the backup's previous segment (or since the full backup itself).
+    /// Returns the segment's size in bytes. Fails with `BackupError` when `dest` is not the
+    /// latest full backup or when a change the WAL does not record, such as a DELETE or a
+    /// schema change, has happened since; see `WalArchive`.
+    pub fn backup_incremental(&mut self, dest: &Path) -> Result<u64, DbError> {
+        let id = dest.display().to_string();
+        let manifest = read_manifest(dest)
+            .map_err(|e| DbError::BackupError(format!("{} is not a full backup: {}", id, e)))?;
+        self.checkpoint()?;
+        let segment = self.wal_archive.next_segment(&manifest.id, self.schema.version())?;
+        let segments_dir = dest.join(SEGMENTS_DIR);
+        fs::create_dir_all(&segments_dir)?;
+        let path = segments_dir.join(format!("{:06}.wal", segment.sequence));
+        let bytes = bincode::serialize(&segment).map_err(|e| DbError::from(*e))?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &bytes)?;
+        fs::rename(&tmp_path, &path)?;
+        self.wal_archive.finish_segment()?;
+        log_backup(
+            &id,
+            &format!("completed incremental segment {}, {} rows, {} bytes", segment.sequence, segment.records.len(), bytes.len()),
+        );
+        Ok(bytes.len() as u64)
+    }
+
+    /// `RESTORE FROM`: replaces the database with the full backup in `src`, made by `BACKUP TO`,
+    /// then replays its incremental segments in sequence order. Returns the bytes restored.
+    /// `src` is checked to hold a catalog and an unbroken run of segments before anything is
+    /// removed; a missing segment is a `BackupError`.
+    pub fn restore(&mut self, src: &Path) -> Result<u64, DbError> {
+        let id = src.display().to_string();
+        if !src.join(CATALOG_FILE).is_file() {
+            return Err(DbError::RecoveryError(format!("{} is not a backup: it has no {}", id, CATALOG_FILE)));
+        }
+        if is_within(src, Path::new(&self.data_dir))? {
+            return Err(DbError::RecoveryError(format!("{} is inside the data directory", id)));
+        }
+        let segments = read_segments(src)?;
+        log_recovery(&id, "started");
+        let outcome = dir_size(src).and_then(|bytes| {
+            self.restore_backup(src)?;
+            for segment in segments {
+                self.replay_segment(segment)?;
+            }
+            self.checkpoint()?;
+            Ok(bytes)
+        });
+        match &outcome {
+            Ok(bytes) => log_recovery(&id, &format!("completed, {} bytes", bytes)),
+            Err(e) => log_recovery(&id, &format!("failed: {}", e)),
+        }
+        outcome.map_err(|e| match e {
+            DbError::BackupError(_) => e,
+            e => DbError::RecoveryError(format!("{}: {}", id, e)),
+        })
+    }
+
+    // Inserts a segment's rows, which must continue each table exactly where it ends
+    fn replay_segment(&mut self, segment: WalSegment) -> Result<(), DbError> {
+        for record in segment.records {
+            match record.operation {
+                WalOperation::Insert => {
+                    let Some(table_def) = self.schema.get_table(&record.table).cloned() else {
+                        return Err(DbError::BackupError(format!(
+                            "Segment {} inserts into unknown table {}",
+                            segment.sequence, record.table
+                        )));
+                    };
+                    let next_row = self.row_count(&record.table);
+                    let row_index = record.row_index as usize;
+                    if row_index < next_row {
+                        continue; // archived twice by a checkpoint interrupted before truncating the WAL
+                    }
+                    if row_index > next_row {
+                        return Err(DbError::BackupError(format!(
+                            "Segment {} does not follow on for table {}: expected row {}, found {}",
+                            segment.sequence, record.table, next_row, row_index
+                        )));
+                    }
+                    let (row, rowid) = split_rowid(&table_def, record.row);
+                    let row = self.open_row(&table_def, row)?;
+                    self.insert_row_as(&record.table, &[], row, rowid)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the database's files with a copy made by `backup_to`, then reloads the schema,
+    /// columns, indexes and users from them. Change feed subscribers, the audit log, the cache
+    /// settings, the durability level and the encryption key carry over.
+    pub fn restore_backup(&mut self, backup: &Path) -> Result<(), DbError> {
+        let data_dir = Path::new(&self.data_dir).to_path_buf();
+        for entry in fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            if path == backup || path == self.audit_log.path() {
+                continue;
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+        copy_dir(backup, &data_dir, &[&backup.join(BACKUP_MANIFEST), &backup.join(SEGMENTS_DIR)])?;
+
+        let mut restored = StorageManager::new(&self.data_dir, Schema::load(&self.data_dir)?)?;
+        restored.set_cipher(self.cipher.clone());
+        restored.recover()?;
+        restored.cache.lock().unwrap().set_capacity(self.cache.lock().unwrap().capacity());
+        restored.max_rows_per_segment = self.max_rows_per_segment;
+        restored.wal.get_mut().unwrap().set_durability(self.durability());
+        std::mem::swap(&mut restored.change_feed, &mut self.change_feed);
+        std::mem::swap(&mut restored.audit_log, &mut self.audit_log);
+        std::mem::swap(&mut restored.metrics, &mut self.metrics);
+        std::mem::swap(&mut restored.table_versions, &mut self.table_versions);
+        *self = restored;
+        // Serializable transactions begun before the restore must not commit over it
+        for lock in self.tables.values() {
+            self.bump_version(&lock.read().unwrap());
+        }
+        Ok(())
+    }
+
+    /// Sets how many decoded columns the read cache holds, evicting the least recently used
+    /// ones if it is already over the new capacity. Zero disables the cache.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache.lock().unwrap().set_capacity(capacity);
+    }
+
+    /// Read cache hits and misses since the storage manager was opened.
+    pub fn cache_stats(&self) -> (u64, u64) {
+        self.cache.lock().unwrap().stats()
+    }
+
+    /// Reads a column, including rows not yet flushed. Reads without a condition collect a
+    /// column scan and are served from the column cache when possible; with a condition,
+    /// blocks are skipped by min/max on disk.
+    pub fn read_column(
+        &self,
+        table_name: &str,
+        column_name: &str,
+        condition: Option<&Condition>,
+    ) -> Result<Vec<Value>, DbError> {
+        let table = self.read_table(table_name)?;
+        self.read_locked_column(&table, column_name, condition)
+    }
+
+    // `read_column` on a table the caller already holds locked
+    fn read_locked_column(
+        &self,
+        table: &TableStorage,
+        column_name: &str,
+        condition: Option<&Condition>,
+    ) -> Result<Vec<Value>, DbError> {
+        let table_name = &table.name;
+        let col_store = table.column(column_name)?;
+        let mut values = match condition {
+            Some(_) => col_store.read(condition, &self.buffer)?,
+            None => {
+                let cached = self.cache.lock().unwrap().get(table_name, column_name);
+                match cached {
+                    Some(values) => {
+                        self.metrics.record_cache_hits(1, 0);
+                        values
+                    }
+                    None => {
+                        self.metrics.record_cache_hits(0, 1);
+                        // Pending rows are appended below, so only the flushed blocks are scanned and cached
+                        let values = col_store.scan(&[]).collect::<Result<Vec<_>, _>>()?;
+                        self.cache.lock().unwrap().insert(table_name, column_name, values.clone());
+                        values
+                    }
+                }
+            }
+        };
+
+        // Append pending rows
+        if let Some(pending_values) = table.pending_rows.get(column_name) {
+            values.extend(pending_values.iter().cloned());
+        }
+
+        Ok(values)
+    }
+
+    /// Reads several columns of one table in a single call, keyed by column name. Each distinct
+    /// column is read once however often it is listed, so condition and projected columns can
+    /// be requested together.
+    pub fn read_columns(
+        &self,
+        table_name: &str,
+        column_names: &[String],
+        condition: Option<&Condition>,
+    ) -> Result<HashMap<String, Vec<Value>>, DbError> {
+        let table = self.read_table(table_name)?;
+        let mut columns = HashMap::with_capacity(column_names.len());
+        for name in column_names {
+            if !columns.contains_key(name) {
+                let values = self.read_locked_column(&table, name, condition)?;
+                columns.insert(name.clone(), values);
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Row numbers holding `value` in a UNIQUE or PRIMARY KEY column, found in the value map kept
+    /// for the constraint instead of by a scan. `None` when the column has no such map.
+    pub fn unique_lookup(&self, table_name: &str, column_name: &str, value: &Value) -> Option<Vec<usize>> {
+        let table = self.read_table(table_name).ok()?;
+        let positions = table.unique_values.get(column_name)?;
+        Some(positions.get(value).into_iter().copied().collect())
+    }
+
+    /// The indexes kept for a column: `hash` for the value index of ID and Name columns,
+    /// `unique` for the value map of a UNIQUE or PRIMARY KEY column, `fulltext` for a FULLTEXT
+    /// index and `composite` for each index from CREATE INDEX the column is part of.
+    pub fn column_indexes(&self, table_name: &str, column_name: &str) -> Vec<&'static str> {
+        let mut indexes = Vec::new();
+        if let Ok(table) = self.read_table(table_name) {
+            if table.indexes.contains_key(column_name) {
+                indexes.push("hash");
+            }
+            if table.unique_values.contains_key(column_name) {
+                indexes.push("unique");
+            }
+        }
+        for index in self.schema.get_table(table_name).map_or(&[][..], |t| t.indexes.as_slice()) {
+            match index.kind {
+                IndexKind::FullText if index.columns[0] == column_name => indexes.push("fulltext"),
+                IndexKind::Composite if index.columns.iter().any(|c| c == column_name) => indexes.push("composite"),
+                _ => {}
+            }
+        }
+        indexes
+    }
+
+    /// Reads `column_names` for the rows at `positions`, ascending row numbers that count rows
+    /// not yet flushed after the flushed ones. Only blocks holding one of the rows are decoded.
+    pub fn read_rows_at(
+        &self,
+        table_name: &str,
+        column_names: &[String],
+        positions: &[usize],
+    ) -> Result<HashMap<String, Vec<Value>>, DbError> {
+        let table = self.read_table(table_name)?;
+        let mut columns = HashMap::with_capacity(column_names.len());
+        for name in column_names {
+            if columns.contains_key(name) {
+                continue;
+            }
+            let col_store = table.column(name)?;
+            let mut values = Vec::with_capacity(positions.len());
+            let mut wanted = positions.iter().copied().peekable();
+            let mut block_start = 0;
+            for block_info in &col_store.metadata.blocks {
+                let block_end = block_start + block_info.row_count;
+                if wanted.peek().is_some_and(|&pos| pos < block_end) {
+                    let block = col_store.read_block(block_info, &self.buffer)?;
+                    while let Some(pos) = wanted.next_if(|&pos| pos < block_end) {
+                        values.extend(pos.checked_sub(block_start).and_then(|i| block.values.get(i)).cloned());
+                    }
+                }
+                block_start = block_end;
+            }
+            let pending = table.pending_rows.get(name).map_or(&[][..], |v| v.as_slice());
+            values.extend(wanted.filter_map(|pos| pending.get(pos.checked_sub(block_start)?).cloned()));
+            columns.insert(name.clone(), values);
+        }
+        Ok(columns)
+    }
+
+    /// Reads `column_names` for only the rows where `predicate` holds, including rows not yet
+    /// flushed. The predicate must test a single column: blocks its min/max rule out are never
+    /// decoded, non-matching values are dropped as each block is scanned, and the other columns
+    /// are decoded only for blocks holding a match.
+    pub fn read_columns_where(
+        &self,
+        table_name: &str,
+        column_names: &[String],
+        predicate: &Condition,
+    ) -> Result<HashMap<String, Vec<Value>>, DbError> {
+        let mut predicate_columns = crate::query::collect_condition_columns(predicate).into_iter();
+        let predicate_column = match (predicate_columns.next(), predicate_columns.next()) {
+            (Some(column), None) => column,
+            _ => {
+                return Err(DbError::QueryError(format!(
+                    "Cannot push {} into a column scan: it must test exactly one column",
+                    predicate
+                )))
+            }
+        };
+        let table = self.read_table(table_name)?;
+        let predicate_store = table.column(&predicate_column)?;
+
+        // Positions of matching rows within each block that survives min/max pruning; encrypted
+        // blocks have no range, so every one is scanned
+        let mut matches: Vec<(usize, Vec<usize>)> = Vec::new();
+        let mut columns: HashMap<String, Vec<Value>> =
+            column_names.iter().map(|name| (name.clone(), Vec::new())).collect();
+        for (i, block_info) in predicate_store.metadata.blocks.iter().enumerate() {
+            let pruned = !predicate_store.column.encrypted
+                && !crate::query::evaluator::evaluate_condition_block(predicate, &predicate_column, block_info);
+            if pruned {
+                continue;
+            }
+            let block = predicate_store.read_block(block_info, &self.buffer)?;
+            let positions: Vec<usize> = block
+                .values
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| crate::query::evaluator::evaluate_condition_value(predicate, v))
+                .map(|(pos, _)| pos)
+                .collect();
+            if positions.is_empty() {
+                continue;
+            }
+            if let Some(values) = columns.get_mut(&predicate_column) {
+                values.extend(positions.iter().map(|&pos| block.values[pos].clone()));
+            }
+            matches.push((i, positions));
+        }
+
+        for (name, values) in columns.iter_mut() {
+            if *name == predicate_column {
+                continue;
+            }
+            let col_store = table.column(name)?;
+            let aligned = col_store.metadata.blocks.len() == predicate_store.metadata.blocks.len()
+                && col_store
+                    .metadata
+                    .blocks
+                    .iter()
+                    .zip(&predicate_store.metadata.blocks)
+                    .all(|(a, b)| a.row_count == b.row_count);
+            if aligned {
+                for (i, positions) in &matches {
+                    let block = col_store.read_block(&col_store.metadata.blocks[*i], &self.buffer)?;
+                    values.extend(positions.iter().map(|&pos| block.values[pos].clone()));
+                }
+            } else {
+                // Blocks written before a rewrite may not line up; fall back to row numbers
+                let mut block_start = Vec::with_capacity(predicate_store.metadata.blocks.len());
+                let mut start = 0;
+                for block_info in &predicate_store.metadata.blocks {
+                    block_start.push(start);
+                    start += block_info.row_count;
+                }
+                let all = col_store.read(None, &self.buffer)?;
+                for (i, positions) in &matches {
+                    values.extend(positions.iter().filter_map(|&pos| all.get(block_start[*i] + pos).cloned()));
+                }
+            }
+        }
+
+        if let Some(pending_values) = table.pending_rows.get(&predicate_column) {
+            let positions: Vec<usize> = (0..pending_values.len())
+                .filter(|&i| crate::query::evaluator::evaluate_condition_value(predicate, &pending_values[i]))
+                .collect();
+            for (name, values) in columns.iter_mut() {
+                if let Some(pending) = table.pending_rows.get(name) {
+                    values.extend(positions.iter().filter_map(|&i| pending.get(i).cloned()));
+                }
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Deletes the rows matching `condition` (every row when `None`) and returns how many
+    /// matched. Rows removed from child tables by ON DELETE CASCADE are not counted.
+    pub fn delete_rows(&mut self, table_name: &str, condition: Option<&Condition>) -> Result<usize, DbError> {
+        let table_def = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?;
+        let columns = table_def.columns.clone();
+
+        // Rewriting columns invalidates WAL row positions, so make pending rows durable first
+        self.checkpoint()?;
+
+        let row_count = self.row_count(table_name);
+        let delete_mask = match condition {
+            Some(cond) => {
+                let condition_columns: Vec<String> = crate::query::collect_condition_columns(cond).into_iter().collect();
+                if let Some(col) = condition_columns.iter().find(|col| *col != ROWID && !columns.iter().any(|c| &c.name == *col)) {
+                    return Err(DbError::InvalidData(format!("Column {}.{} not found", table_name, col)));
+                }
+                let column_values = self.read_columns(table_name, &condition_columns, None)?;
+                (0..row_count)
+                    .map(|i| crate::query::evaluator::evaluate_condition_row(cond, &column_values, i))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            None => vec![true; row_count],
+        };
+        let deleted = delete_mask.iter().filter(|&&matched| matched).count();
+
+        // Resolve foreign keys before touching any file so a RESTRICT leaves everything intact
+        let mut plan = HashMap::new();
+        self.plan_delete(table_name, delete_mask, &mut plan)?;
+        plan.retain(|_, mask| mask.contains(&true));
+        if plan.is_empty() {
+            return Ok(deleted);
+        }
+        // A cascade rewrites several tables, which must not be left with only some rewritten
+        let tables: Vec<String> = plan.keys().cloned().collect();
+        let subscribed = self.change_feed.get_mut().unwrap().has_subscribers();
+        let changes = self.atomically(&tables, |storage| {
+            let mut changes = Vec::new();
+            for (table, mask) in plan {
+                let keep_indices: Vec<usize> = (0..mask.len()).filter(|&i| !mask[i]).collect();
+                if subscribed {
+                    changes.push(ChangeRecord::Delete { rows: storage.read_rows(&table, &mask)?, table: table.clone() });
+                }
+                storage.retain_rows(&table, &keep_indices)?;
+            }
+            Ok(changes)
+        })?;
+        let change_feed = self.change_feed.get_mut().unwrap();
+        for change in changes {
+            change_feed.publish(change);
+        }
+        Ok(deleted)
+    }
+
+    // Marks rows of `table_name` for deletion and follows foreign keys into child tables:
+    // RESTRICT rejects the delete while CASCADE marks the referencing rows as well.
+    fn plan_delete(
+        &mut self,
+        table_name: &str,
+        delete_mask: Vec<bool>,
+        plan: &mut HashMap<String, Vec<bool>>,
+    ) -> Result<(), DbError> {
+        let planned = plan
+            .entry(table_name.to_string())
+            .or_insert_with(|| vec![false; delete_mask.len()]);
+        let newly_deleted: Vec<usize> = (0..delete_mask.len())
+            .filter(|&i| delete_mask[i] && !planned[i])
+            .collect();
+        for &i in &newly_deleted {
+            planned[i] = true;
+        }
+        if newly_deleted.is_empty() {
+            return Ok(());
+        }
+
+        for (child_table, child_col) in self.schema.referencing_columns(table_name) {
+            let fk = match &child_col.references {
+                Some(fk) => fk.clone(),
+                None => continue,
+            };
+            let parent_values = self.read_column(table_name, &fk.reference_column, None)?;
+            let removed: HashSet<Value> = newly_deleted
+                .iter()
+                .filter_map(|&i| parent_values.get(i))
+                .filter(|v| !v.is_null())
+                .cloned()
+                .collect();
+            if removed.is_empty() {
+                continue;
+            }
+            let child_values = self.read_column(&child_table, &child_col.name, None)?;
+            let already_deleted = plan.get(&child_table);
+            let child_mask: Vec<bool> = child_values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| removed.contains(v) && !already_deleted.is_some_and(|m| m[i]))
+                .collect();
+            if let Some(i) = child_mask.iter().position(|&hit| hit) {
+                match fk.on_delete {
+                    OnDelete::Restrict => {
+                        return Err(DbError::ValidationError(format!(
+                            "Cannot delete from {}: {}.{} still references {}",
+                            table_name, child_table, child_col.name, child_values[i]
+                        )));
+                    }
+                    OnDelete::Cascade => self.plan_delete(&child_table, child_mask, plan)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whole rows of `table_name` at the positions set in `mask`
+    fn read_rows(&mut self, table_name: &str, mask: &[bool]) -> Result<Vec<Vec<Value>>, DbError> {
+        let columns = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?
+            .columns
+            .clone();
+        let mut column_values = Vec::with_capacity(columns.len());
+        for col in &columns {
+            column_values.push(self.read_column(table_name, &col.name, None)?);
+        }
+        Ok((0..mask.len())
+            .filter(|&i| mask[i])
+            .map(|i| column_values.iter().map(|values| values.get(i).cloned().unwrap_or(Value::Null)).collect())
+            .collect())
+    }
+
+    // Rewrites every column of a table, the rowid included, keeping only the rows at `keep_indices`.
+    fn retain_rows(&mut self, table_name: &str, keep_indices: &[usize]) -> Result<(), DbError> {
+        let columns: Vec<Column> = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?
+            .stored_columns()
+            .cloned()
+            .collect();
+        let mut column_values = HashMap::new();
+        for col in &columns {
+            let values = self.read_column(table_name, &col.name, None)?;
+            let filtered_values: Vec<Value> = keep_indices
+                .iter()
+                .filter(|&&i| i < values.len())
+                .map(|&i| values[i].clone())
+                .collect();
+            column_values.insert(col.name.clone(), filtered_values);
+        }
+        self.replace_rows(table_name, column_values)
+    }
+
+    /// Rewrites every column of `table_name` to hold exactly `column_values`, as one
+    /// `atomically` write: each column file is replaced by a rename and the catalog saved once
+    /// all of them are. Pending rows must be checkpointed first, or recovery would replay them
+    /// from the WAL. Without a `ROWID` entry the rows keep the rowids they have, which needs
+    /// the row count unchanged.
+    pub(crate) fn replace_rows(
+        &mut self,
+        table_name: &str,
+        mut column_values: HashMap<String, Vec<Value>>,
+    ) -> Result<(), DbError> {
+        let columns: Vec<Column> = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?
+            .stored_columns()
+            .cloned()
+            .collect();
+        if !column_values.contains_key(ROWID) {
+            let rowids = self.read_column(table_name, ROWID, None)?;
+            if column_values.values().any(|values| values.len() != rowids.len()) {
+                return Err(DbError::InvalidData(format!(
+                    "Cannot rewrite {} with a different row count without its rowids",
+                    table_name
+                )));
+            }
+            column_values.insert(ROWID.to_string(), rowids);
+        }
+        self.atomically(&[table_name.to_string()], |storage| {
+            let lock = storage.table_lock(table_name)?;
+            let mut guard = lock.write().unwrap();
+            let table = &mut *guard;
+            for col in &columns {
+                let col_store = table.columns.get_mut(&col.name).unwrap();
+                let values = column_values.remove(&col.name).unwrap_or_default();
+                let compression = match col.data_type {
+                    DataType::String => CompressionType::Dictionary,
+                    _ => CompressionType::Rle,
+                };
+                col_store.rewrite(&values, compression, VACUUM_BLOCK_ROWS)?;
+                storage.rebuild_index(table, &col.name)?;
+            }
+            table.pending_rows.clear();
+            table.unsynced = true;
+            storage.bump_version(table);
+            storage.rebuild_unique_values(table)?;
+            storage.rebuild_declared_indexes(table, false)?;
+            drop(guard);
+
+            storage.save_catalog()?;
+            storage.wal_archive.invalidate(&format!("Rows of {} were rewritten", table_name))
+        })
+    }
+
+    /// Rewrites every column file of `table_name` to hold only its live rows, packed into blocks
+    /// of `VACUUM_BLOCK_ROWS`, then rebuilds the table's indexes. Reclaims bytes left behind by
+    /// trimmed torn flushes and merges the small blocks many checkpoints leave. Each file is
+    /// replaced by an atomic rename, and rows keep their rowids. Returns the number of bytes
+    /// reclaimed.
+    pub fn vacuum(&mut self, table_name: &str) -> Result<u64, DbError> {
+        let columns: Vec<Column> = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?
+            .stored_columns()
+            .cloned()
+            .collect();
+        self.checkpoint()?;
+        let (_, bytes_before) = self.table_size(table_name);
+
+        let lock = self.table_lock(table_name)?;
+        let mut guard = lock.write().unwrap();
+        for col in &columns {
+            let values = self.read_locked_column(&guard, &col.name, None)?;
+            let compression = match col.data_type {
+                DataType::String => CompressionType::Dictionary,
+                _ => CompressionType::Rle,
+            };
+            let table = &mut *guard;
+            let col_store = table
+                .columns
+                .get_mut(&col.name)
+                .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table_name, col.name)))?;
+            col_store.rewrite(&values, compression, VACUUM_BLOCK_ROWS)?;
+            self.rebuild_index(table, &col.name)?;
+        }
+        self.bump_version(&guard);
+        drop(guard);
+
+        self.save_catalog()?;
+        let (_, bytes_after) = self.table_size(table_name);
+        let reclaimed = bytes_before.saturating_sub(bytes_after);
+        log::info!("Vacuumed {}: reclaimed {} bytes", table_name, reclaimed);
+        Ok(reclaimed)
+    }
+
+    /// Checks each column file of `table_name`, in table order: every block must match its
+    /// checksum and decode, and every column must hold as many rows as the first readable one.
+    /// Returns each column's flushed row count, or the `IntegrityError` describing its damage.
+    pub fn verify_integrity(&self, table_name: &str) -> Result<IntegrityReport, DbError> {
+        let table_def = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?;
+        let table = self.read_table(table_name)?;
+        let mut report: IntegrityReport = table_def
+            .columns
+            .iter()
+            .map(|col| {
+                let rows = match table.columns.get(&col.name) {
+                    Some(col_store) => col_store.verify(),
+                    None => Err(DbError::IntegrityError(format!(
+                        "{}.{}: column file is missing",
+                        table_name, col.name
+                    ))),
+                };
+                (col.name.clone(), rows)
+            })
+            .collect();
+
+        let reference = report.iter().find_map(|(name, rows)| rows.as_ref().ok().map(|&n| (name.clone(), n)));
+        if let Some((reference_name, expected)) = reference {
+            for (name, rows) in report.iter_mut() {
+                if let Ok(n) = rows {
+                    if *n != expected {
+                        *rows = Err(DbError::IntegrityError(format!(
+                            "{}.{}: holds {} rows but {}.{} holds {}",
+                            table_name, name, n, table_name, reference_name, expected
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    pub fn drop_column(&mut self, table_name: &str, column_name: &str) -> Result<(), DbError> {
+        let table_def = self
+            .schema
+            .get_table(table_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?;
+        if table_def.get_column(column_name).is_none() {
+            return Err(DbError::InvalidData(format!("Column {}.{} not found", table_name, column_name)));
+        }
+        if let Some((child_table, child_col)) = self
+            .schema
+            .referencing_columns(table_name)
+            .into_iter()
+            .find(|(_, c)| c.references.as_ref().is_some_and(|fk| fk.reference_column == column_name))
+        {
+            return Err(DbError::SchemaError(format!(
+                "Cannot drop {}.{}: referenced by {}.{}",
+                table_name, column_name, child_table, child_col.name
+            )));
+        }
+        if self.read_table(table_name)?.indexes.contains_key(column_name) {
+            return Err(DbError::SchemaError(format!(
+                "Cannot drop indexed column {}.{}",
+                table_name, column_name
+            )));
+        }
+
+        // Pending rows and WAL records still carry the column, so make them durable first
+        self.checkpoint()?;
+        self.schema.drop_column(table_name, column_name)?;
+
+        {
+            let mut table = self.write_table(table_name)?;
+            table.columns.remove(column_name);
+            table.unique_values.remove(column_name);
+        }
+        let file_path = ColumnStore::path(&self.data_dir, table_name, column_name);
+        if Path::new(&file_path).exists() {
+            fs::remove_file(&file_path)?;
+        }
+        let metadata_path = BlockMetadata::path(&self.data_dir, table_name, column_name);
+        if Path::new(&metadata_path).exists() {
+            fs::remove_file(&metadata_path)?;
+        }
+        self.bump_version_of(table_name);
+        Ok(())
+    }
+
+    /// Renames a table and moves its column, metadata and index files. Foreign keys and grants
+    /// naming the table follow the new name.
+    pub fn rename_table(&mut self, table_name: &str, new_name: &str) -> Result<(), DbError> {
+        if !self.schema.tables.contains_key(table_name) {
+            return Err(DbError::InvalidData(format!("Table {} not found", table_name)));
+        }
+        // Pending rows and WAL records carry the old name, so make them durable first
+        self.checkpoint()?;
+        self.schema.rename_table(table_name, new_name)?;
+
+        let lock = self.table_lock(table_name)?;
+        {
+            let mut guard = lock.write().unwrap();
+            let table = &mut *guard;
+            table.name = new_name.to_string();
+            for (col_name, col_store) in table.columns.iter_mut() {
+                col_store.rename(new_name, col_name)?;
+            }
+            for (col_name, index) in table.indexes.iter_mut() {
+                index.rename(&format!("{}/indexes/{}_{}.idx", self.data_dir, new_name, col_name))?;
+            }
+            for index_def in self.schema.get_table(new_name).map_or(&[][..], |t| t.indexes.as_slice()) {
+                if let Some(index) = table.declared_indexes.get_mut(&index_def.name) {
+                    index.rename(&declared_index_path(&self.data_dir, new_name, index_def))?;
+                }
+            }
+        }
+        self.tables.remove(table_name);
+        self.tables.insert(new_name.to_string(), lock);
+        self.users.rename_table(table_name, new_name)?;
+
+        // Serializable transactions that read the old name see it change
+        self.bump_version_of(table_name);
+        let version = self.table_version(table_name);
+        self.table_versions.get_mut().unwrap().insert(new_name.to_string(), version);
+        self.bump_version_of(new_name);
+        Ok(())
+    }
+
+    /// Renames a column and moves its files. CHECK conditions and foreign keys naming it follow
+    /// the new name. Only columns named ID or Name are indexed, so the index follows the name.
+    pub fn rename_column(&mut self, table_name: &str, column_name: &str, new_name: &str) -> Result<(), DbError> {
+        self.checkpoint()?;
+        self.schema.rename_column(table_name, column_name, new_name)?;
+
+        let mut guard = self.write_table(table_name)?;
+        let table = &mut *guard;
+        let mut col_store = table
+            .columns
+            .remove(column_name)
+            .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table_name, column_name)))?;
+        col_store.rename(table_name, new_name)?;
+        table.columns.insert(new_name.to_string(), col_store);
+        if let Some(set) = table.unique_values.remove(column_name) {
+            table.unique_values.insert(new_name.to_string(), set);
+        }
+
+        if table.indexes.remove(column_name).is_some() {
+            let index_path = format!("{}/indexes/{}_{}.idx", self.data_dir, table_name, column_name);
+            if Path::new(&index_path).exists() {
+                fs::remove_file(&index_path)?;
+            }
+        }
+        let col_store = &table.columns[new_name];
+        if col_store.column.has_implicit_index() {
+            let index_path = format!("{}/indexes/{}_{}.idx", self.data_dir, table_name, new_name);
+            if Path::new(&index_path).exists() {
+                fs::remove_file(&index_path)?;
+            }
+            let mut index = Index::new(&index_path, col_store.column.data_type.clone())?;
+            for block_info in &col_store.metadata.blocks {
+                let block = col_store.read_block(block_info, &self.buffer)?;
+                index.append(&block.values, block_info.offset)?;
+            }
+            table.indexes.insert(new_name.to_string(), index);
+        }
+        self.bump_version(table);
+        Ok(())
+    }
+
+    pub fn drop_table(&mut self, table_name: &str) -> Result<(), DbError> {
+        if !self.schema.tables.contains_key(table_name) {
+            return Err(DbError::InvalidData(format!("Table {} not found", table_name)));
+        }
+        if let Some((child_table, child_col)) = self
+            .schema
+            .referencing_columns(table_name)
+            .into_iter()
+            .find(|(child_table, _)| child_table != table_name)
+        {
+            return Err(DbError::SchemaError(format!(
+                "Cannot drop {}: referenced by {}.{}",
+                table_name, child_table, child_col.name
+            )));
+        }
+
+        // Its pending rows go with it
+        let lock = self.table_lock(table_name)?;
+        self.tables.remove(table_name);
+        let table = std::mem::take(&mut *lock.write().unwrap());
+        for col_name in table.columns.keys() {
+            let file_path = ColumnStore::path(&self.data_dir, table_name, col_name);
+            if Path::new(&file_path).exists() {
+                fs::remove_file(&file_path)?;
+            }
+        }
+
+        for col_name in table.indexes.keys() {
+            let index_path = format!("{}/indexes/{}_{}.idx", self.data_dir, table_name, col_name);
+            if Path::new(&index_path).exists() {
+                fs::remove_file(&index_path)?;
+            }
+        }
+
+        for index in table.declared_indexes.into_values() {
+            index.remove()?;
+        }
+
+        for col in self.schema.get_table(table_name).unwrap().stored_columns() {
+            let metadata_path = BlockMetadata::path(&self.data_dir, table_name, &col.name);
+            if Path::new(&metadata_path).exists() {
+                fs::remove_file(&metadata_path)?;
+            }
+        }
+
+        self.schema.drop_table(table_name)?;
+        // Drop the table's WAL records so a recreated table cannot replay them
+        self.checkpoint()?;
+        self.bump_version_of(table_name);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupManifest {
+    id: String,
+}
+
+// Splits a logged row into its values and the rowid after them; rows logged before rowids
+// existed have none
+/// The position in `table_def` of the `ON CONFLICT` column, which must be UNIQUE or a PRIMARY KEY.
+pub(crate) fn conflict_column(table_def: &Table, on_conflict: &OnConflict) -> Result<usize, DbError> {
+    let pos = table_def
+        .columns
+        .iter()
+        .position(|c| c.name == on_conflict.column)
+        .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table_def.name, on_conflict.column)))?;
+    if !table_def.columns[pos].requires_unique() {
+        return Err(DbError::ValidationError(format!(
+            "ON CONFLICT ({}) requires a UNIQUE or PRIMARY KEY column, but {}.{} is neither",
+            on_conflict.column, table_def.name, on_conflict.column
+        )));
+    }
+    Ok(pos)
+}
+
+/// `old_row` with the DO UPDATE `assignments` applied. They see its columns by name and the
+/// columns of the `proposed` row as `EXCLUDED.<column>`.
+pub(crate) fn updated_row(
+    table_def: &Table,
+    old_row: &[Value],
+    proposed: &[Value],
+    assignments: &[(String, Expr)],
+) -> Result<Vec<Value>, DbError> {
+    let mut scope: HashMap<String, Vec<Value>> = HashMap::new();
+    for ((col, old), new) in table_def.columns.iter().zip(old_row).zip(proposed) {
+        scope.insert(col.name.clone(), vec![old.clone()]);
+        scope.insert(format!("EXCLUDED.{}", col.name), vec![new.clone()]);
+    }
+    let mut new_row = old_row.to_vec();
+    for (column, expr) in assignments {
+        let pos = table_def
+            .columns
+            .iter()
+            .position(|c| c.name == *column)
+            .ok_or_else(|| DbError::InvalidData(format!("Column {}.{} not found", table_def.name, column)))?;
+        new_row[pos] = crate::query::evaluator::evaluate_expr(expr, &scope, 0)?.widen_to(&table_def.columns[pos].data_type);
+    }
+    Ok(new_row)
+}
+
+fn split_rowid(table_def: &Table, mut row: Vec<Value>) -> (Vec<Value>, Option<u64>) {
+    match row.last() {
+        Some(Value::Int64(rowid)) if row.len() == table_def.columns.len() + 1 => {
+            let rowid = *rowid as u64;
+            row.pop();
+            (row, Some(rowid))
+        }
+        _ => (row, None),
+    }
+}
+
+fn read_manifest(backup: &Path) -> Result<BackupManifest, DbError> {
+    let json = fs::read_to_string(backup.join(BACKUP_MANIFEST))?;
+    serde_json::from_str(&json).map_err(|e| DbError::SerializationError(e.to_string()))
+}
+
+// The incremental segments of `backup`, checked to continue it without a gap
+fn read_segments(backup: &Path) -> Result<Vec<WalSegment>, DbError> {
+    let dir = backup.join(SEGMENTS_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let manifest = read_manifest(backup)
+        .map_err(|e| DbError::BackupError(format!("{} has segments but no readable {}: {}", backup.display(), BACKUP_MANIFEST, e)))?;
+    let mut paths: Vec<_> = fs::read_dir(&dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+        .collect();
+    paths.sort();
+    let mut segments = Vec::with_capacity(paths.len());
+    for (expected, path) in (1..).zip(paths) {
+        let segment: WalSegment = bincode::deserialize(&fs::read(&path)?)
+            .map_err(|e| DbError::BackupError(format!("Unreadable segment {}: {}", path.display(), e)))?;
+        if segment.base != manifest.id {
+            return Err(DbError::BackupError(format!(
+                "Segment {} belongs to backup {}, not {}",
+                path.display(),
+                segment.base,
+                manifest.id
+            )));
+        }
+        if segment.sequence != expected {
+            return Err(DbError::BackupError(format!(
+                "Segment {} is missing: found segment {} after {}",
+                expected,
+                segment.sequence,
+                expected - 1
+            )));
+        }
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+// Renames column and metadata files named after the column alone, as written before they were
+// scoped per table, to their per-table names. Tables sharing a column name then shared its files,
+// which cannot be told apart, so such a directory fails with `VersionError`.
+fn migrate_column_layout(data_dir: &str, schema: &Schema) -> Result<(), DbError> {
+    let mut owners: HashMap<&str, Vec<(&str, &Column)>> = HashMap::new();
+    let mut current = HashSet::new();
+    for table in schema.tables() {
+        for col in table.stored_columns() {
+            owners.entry(&col.name).or_default().push((&table.name, col));
+            current.insert(ColumnStore::path(data_dir, &table.name, &col.name));
+            current.insert(BlockMetadata::path(data_dir, &table.name, &col.name));
+        }
+    }
+    for (column_name, tables) in owners {
+        let old_data = format!("{}/columns/{}.dat", data_dir, column_name);
+        let old_metadata = format!("{}/metadata/{}.json", data_dir, column_name);
+        // A file in the old layout can carry the per-table name of another column
+        let legacy = |path: &str| !current.contains(path) && Path::new(path).exists();
+        if !legacy(&old_data) && !legacy(&old_metadata) {
+            continue;
+        }
+        let [(table_name, column)] = tables[..] else {
+            let names: Vec<&str> = tables.iter().map(|(table_name, _)| *table_name).collect();
+            return Err(DbError::VersionError(format!(
+                "{} predates per-table column files, and tables {} shared the files of column {}",
+                data_dir,
+                names.join(", "),
+                column_name
+            )));
+        };
+        let new_data = ColumnStore::path(data_dir, table_name, column_name);
+        // A build without this migration opened the directory with empty per-table files,
+        // which the old ones replace; a per-table file holding rows means both layouts were written
+        let new_rows = fs::metadata(&new_data).map(|m| m.len() > 0).unwrap_or(false)
+            || BlockMetadata::load(table_name, column_name, column.data_type.clone(), data_dir)?.row_count() > 0;
+        if new_rows {
+            return Err(DbError::VersionError(format!(
+                "Column {}.{} has files in both the layout before per-table column files and the current one",
+                table_name, column_name
+            )));
+        }
+        if legacy(&old_data) {
+            fs::rename(&old_data, &new_data)?;
+        }
+        if legacy(&old_metadata) {
+            let mut metadata: BlockMetadata = serde_json::from_str(&fs::read_to_string(&old_metadata)?)
+                .map_err(|e| DbError::SerializationError(format!("Malformed {}: {}", old_metadata, e)))?;
+            metadata.table_name = table_name.to_string();
+            metadata.data_dir = data_dir.to_string();
+            metadata.save()?;
+            fs::remove_file(&old_metadata)?;
+        }
+        log::info!("Moved the files of {}.{} to the per-table layout", table_name, column_name);
+    }
+    Ok(())
+}
+
+fn declared_index_path(data_dir: &str, table_name: &str, index: &IndexDef) -> String {
+    let extension = match index.kind {
+        IndexKind::FullText => "fts",
+        IndexKind::Composite => "cidx",
+    };
+    format!("{}/indexes/{}_{}.{}", data_dir, table_name, index.name, extension)
+}
+
+// Copies the files under `from` into `to`, creating directories as needed and leaving out the
+// paths in `skip`. Returns the bytes copied
+fn copy_dir(from: &Path, to: &Path, skip: &[&Path]) -> Result<u64, DbError> {
+    fs::create_dir_all(to)?;
+    let mut bytes = 0;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        if skip.contains(&path.as_path()) {
+            continue;
+        }
+        let target = to.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            bytes += copy_dir(&path, &target, skip)?;
+        } else {
+            bytes += fs::copy(&path, &target)?;
+        }
+    }
+    Ok(bytes)
+}
+
+fn dir_size(dir: &Path) -> Result<u64, DbError> {
+    let mut bytes = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        bytes += if entry.file_type()?.is_dir() { dir_size(&entry.path())? } else { entry.metadata()?.len() };
+    }
+    Ok(bytes)
+}
+
+// Whether `path`, which need not exist, is `dir` or inside it, however either is spelled
+fn is_within(path: &Path, dir: &Path) -> Result<bool, DbError> {
+    let dir = fs::canonicalize(dir)?;
+    let mut existing = std::path::absolute(path)?;
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match (existing.file_name().map(|name| name.to_os_string()), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                rest.push(name);
+                existing = parent.to_path_buf();
+            }
+            _ => return Ok(false),
+        }
+    }
+    let resolved = rest.into_iter().rev().fold(fs::canonicalize(&existing)?, |path, name| path.join(name));
+    Ok(resolved.starts_with(&dir))
 }
\ No newline at end of file
@@ -0,0 +1,232 @@
+use crate::schema::metadata::BlockMetadata;
+use crate::schema::{Schema, CATALOG_FILE};
+use crate::storage::column::ColumnStore;
+use crate::types::DbError;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Directory in the data directory holding the undo image of a write in progress.
+pub const UNDO_DIR: &str = "undo";
+
+// Names the tables an image covers, with the stored columns each had
+const TABLES_FILE: &str = "tables.json";
+
+/// The column files, block lists and catalog of some tables as they were before a write that
+/// replaces several files at once, such as a COMMIT or a DELETE cascading into other tables.
+/// The image is complete once it is renamed to `UNDO_DIR`, and the write is committed once the
+/// directory is removed again; a database opened with one still there rolls the write back.
+///
+/// Files are saved as hard links. Column files are only ever appended to or replaced by a
+/// rename, and block lists and the catalog only replaced, so a link keeps the bytes a file had
+/// and the blocks its block list names.
+#[derive(Debug)]
+pub struct UndoImage {
+    data_dir: String,
+    tables: HashMap<String, Vec<String>>,
+    sync: bool,
+}
+
+impl UndoImage {
+    /// Saves the files of `tables` as they are now; rows still pending in the WAL are not in
+    /// them, so checkpoint first. With `sync`, the image is on disk before this returns.
+    pub fn create(data_dir: &str, schema: &Schema, tables: &[String], sync: bool) -> Result<Self, DbError> {
+        let dir = undo_dir(data_dir);
+        if Path::new(&dir).exists() {
+            return Err(DbError::RecoveryError(format!(
+                "{} holds an interrupted write; reopen the database to roll it back",
+                dir
+            )));
+        }
+        let staging = format!("{}.tmp", dir);
+        remove_dir_if_present(&staging)?;
+        for sub_dir in ["columns", "metadata"] {
+            fs::create_dir_all(format!("{}/{}", staging, sub_dir))?;
+        }
+        link_if_present(&format!("{}/{}", data_dir, CATALOG_FILE), &format!("{}/{}", staging, CATALOG_FILE))?;
+        let mut image = UndoImage { data_dir: data_dir.to_string(), tables: HashMap::new(), sync };
+        image.link_tables(&staging, schema, tables)?;
+        image.save_tables(&staging)?;
+        if sync {
+            for sub_dir in ["columns", "metadata"] {
+                sync_dir(&format!("{}/{}", staging, sub_dir))?;
+            }
+            sync_dir(&staging)?;
+        }
+        fs::rename(&staging, &dir)?;
+        if sync {
+            sync_dir(data_dir)?;
+        }
+        Ok(image)
+    }
+
+    /// The image an interrupted write left in `data_dir`, if any. A half-built image is removed.
+    pub fn find(data_dir: &str) -> Result<Option<Self>, DbError> {
+        let dir = undo_dir(data_dir);
+        remove_dir_if_present(&format!("{}.tmp", dir))?;
+        let tables_path = format!("{}/{}", dir, TABLES_FILE);
+        let tables = match fs::read_to_string(&tables_path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| DbError::SerializationError(format!("Invalid {}: {}", tables_path, e)))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(UndoImage { data_dir: data_dir.to_string(), tables, sync: true }))
+    }
+
+    /// The tables the image covers.
+    pub fn tables(&self) -> impl Iterator<Item = &String> {
+        self.tables.keys()
+    }
+
+    /// Saves the files of those of `tables` the image does not cover yet, for a write that
+    /// turns out to reach further than it was begun for.
+    pub fn extend(&mut self, schema: &Schema, tables: &[String]) -> Result<(), DbError> {
+        let new_tables: Vec<String> = tables.iter().filter(|t| !self.tables.contains_key(*t)).cloned().collect();
+        if new_tables.is_empty() {
+            return Ok(());
+        }
+        let dir = undo_dir(&self.data_dir);
+        self.link_tables(&dir, schema, &new_tables)?;
+        if self.sync {
+            for sub_dir in ["columns", "metadata"] {
+                sync_dir(&format!("{}/{}", dir, sub_dir))?;
+            }
+        }
+        // The table list is replaced by a rename, so the extra tables count only once linked
+        self.save_tables(&dir)
+    }
+
+    /// Puts every saved file back, leaving the image in place. The catalog is restored too,
+    /// so the caller reloads it and reopens the covered tables. Indexes are not saved and need
+    /// rebuilding.
+    pub fn restore(&self) -> Result<(), DbError> {
+        let dir = undo_dir(&self.data_dir);
+        for (table, columns) in &self.tables {
+            for column in columns {
+                for (saved, live) in [
+                    (ColumnStore::path(&dir, table, column), ColumnStore::path(&self.data_dir, table, column)),
+                    (BlockMetadata::path(&dir, table, column), BlockMetadata::path(&self.data_dir, table, column)),
+                ] {
+                    if Path::new(&saved).exists() {
+                        replace_with_link(&saved, &live)?;
+                    } else {
+                        // The column had no blocks yet
+                        remove_file_if_present(&live)?;
+                    }
+                }
+            }
+        }
+        let saved_catalog = format!("{}/{}", dir, CATALOG_FILE);
+        if Path::new(&saved_catalog).exists() {
+            replace_with_link(&saved_catalog, &format!("{}/{}", self.data_dir, CATALOG_FILE))?;
+        }
+        if self.sync {
+            for sub_dir in ["columns", "metadata"] {
+                let sub_dir = format!("{}/{}", self.data_dir, sub_dir);
+                if Path::new(&sub_dir).is_dir() {
+                    sync_dir(&sub_dir)?;
+                }
+            }
+            sync_dir(&self.data_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the image, committing the write it was kept for, or finishing its rollback.
+    pub fn discard(&self) -> Result<(), DbError> {
+        let dir = undo_dir(&self.data_dir);
+        // Renamed away first, so a crash partway through never leaves an image missing files.
+        // Once renamed the image no longer counts, and whatever of it is left is removed later.
+        let discarded = format!("{}.tmp", dir);
+        remove_dir_if_present(&discarded)?;
+        fs::rename(&dir, &discarded)?;
+        if self.sync {
+            sync_dir(&self.data_dir)?;
+        }
+        if let Err(e) = fs::remove_dir_all(&discarded) {
+            log::warn!("Cannot remove {}: {}", discarded, e);
+        }
+        Ok(())
+    }
+
+    fn link_tables(&mut self, dir: &str, schema: &Schema, tables: &[String]) -> Result<(), DbError> {
+        for table_name in tables {
+            let table = schema
+                .get_table(table_name)
+                .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table_name)))?;
+            let columns: Vec<String> = table.stored_columns().map(|c| c.name.clone()).collect();
+            for column in &columns {
+                link_if_present(
+                    &ColumnStore::path(&self.data_dir, table_name, column),
+                    &ColumnStore::path(dir, table_name, column),
+                )?;
+                link_if_present(
+                    &BlockMetadata::path(&self.data_dir, table_name, column),
+                    &BlockMetadata::path(dir, table_name, column),
+                )?;
+            }
+            self.tables.insert(table_name.clone(), columns);
+        }
+        Ok(())
+    }
+
+    fn save_tables(&self, dir: &str) -> Result<(), DbError> {
+        let path = format!("{}/{}", dir, TABLES_FILE);
+        let json = serde_json::to_string(&self.tables).map_err(|e| DbError::SerializationError(e.to_string()))?;
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, json)?;
+        if self.sync {
+            File::open(&tmp_path)?.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        if self.sync {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+fn undo_dir(data_dir: &str) -> String {
+    format!("{}/{}", data_dir, UNDO_DIR)
+}
+
+fn link_if_present(from: &str, to: &str) -> Result<(), DbError> {
+    // Left by an earlier attempt to extend the image
+    remove_file_if_present(to)?;
+    match fs::hard_link(from, to) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+// Points `live` at the saved file's bytes in one rename
+fn replace_with_link(saved: &str, live: &str) -> Result<(), DbError> {
+    let tmp_path = format!("{}.undo", live);
+    remove_file_if_present(&tmp_path)?;
+    fs::hard_link(saved, &tmp_path)?;
+    fs::rename(&tmp_path, live)?;
+    Ok(())
+}
+
+fn remove_file_if_present(path: &str) -> Result<(), DbError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+fn remove_dir_if_present(path: &str) -> Result<(), DbError> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+// Makes the entries of `dir` durable, e.g. a file just renamed into it
+fn sync_dir(dir: &str) -> Result<(), DbError> {
+    File::open(dir)
+        .and_then(|file| file.sync_all())
+        .map_err(|e| DbError::DurabilityError(format!("Cannot sync {}: {}", dir, e)))
+}
@@ -0,0 +1,340 @@
+use crate::types::{DbError, Value};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum WalOperation {
+    Insert,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub operation: WalOperation,
+    pub table: String,
+    pub row_index: u64,
+    pub row: Vec<Value>,
+}
+
+impl WalRecord {
+    pub fn insert(table: &str, row_index: u64, row: &[Value]) -> Self {
+        WalRecord {
+            operation: WalOperation::Insert,
+            table: table.to_string(),
+            row_index,
+            row: row.to_vec(),
+        }
+    }
+}
+
+/// Directory inside `wal` holding the records kept for incremental backups.
+pub const ARCHIVE_DIR: &str = "archive";
+
+/// Longest a WAL write stays unsynced under `Durability::Normal` while writes keep coming.
+pub const NORMAL_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far writes are forced to disk, the `durability` setting. A process crash loses
+/// nothing at any level, since every write has reached the OS by the time it returns; the
+/// levels differ in what a power loss or OS crash can cost.
+///
+/// - `Full`: the WAL is fsync'd before every write returns, and column files before a
+///   checkpoint drops the WAL records for their rows. Nothing that returned is lost.
+/// - `Normal`: the WAL is fsync'd by the first write after `NORMAL_SYNC_INTERVAL` has passed,
+///   and column files at every checkpoint. A crash can lose the writes since the last sync,
+///   but recovery always finds the files consistent.
+/// - `Off`: nothing is fsync'd until shutdown. Fastest; a crash can lose any write since the
+///   last clean shutdown, including rows a checkpoint has already taken out of the WAL.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    Full,
+    #[default]
+    Normal,
+    Off,
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Full => write!(f, "full"),
+            Durability::Normal => write!(f, "normal"),
+            Durability::Off => write!(f, "off"),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = DbError;
+
+    fn from_str(text: &str) -> Result<Self, DbError> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "full" => Ok(Durability::Full),
+            "normal" => Ok(Durability::Normal),
+            "off" => Ok(Durability::Off),
+            _ => Err(DbError::ConfigurationError(format!(
+                "Invalid durability '{}': expected full, normal or off",
+                text
+            ))),
+        }
+    }
+}
+
+// Records are framed as a little-endian u32 length followed by the bincode payload,
+// so a record torn by a crash mid-append is detected and ignored on replay.
+#[derive(Debug)]
+pub struct Wal {
+    path: String,
+    file: File,
+    durability: Durability,
+    last_sync: Instant,
+}
+
+impl Wal {
+    pub fn open(data_dir: &str) -> Result<Self, DbError> {
+        let wal_dir = format!("{}/wal", data_dir);
+        fs::create_dir_all(&wal_dir)?;
+        let path = format!("{}/wal.log", wal_dir);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        Ok(Wal {
+            path,
+            file,
+            durability: Durability::default(),
+            last_sync: Instant::now(),
+        })
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Appends `records` in order with a single write and flush.
+    pub fn append(&mut self, records: &[WalRecord]) -> Result<(), DbError> {
+        let mut frames = Vec::new();
+        for record in records {
+            let payload = bincode::serialize(record).map_err(|e| DbError::from(*e))?;
+            frames.extend((payload.len() as u32).to_le_bytes());
+            frames.extend(payload);
+        }
+        self.file.write_all(&frames)?;
+        self.file.flush()?;
+        let due = match self.durability {
+            Durability::Full => true,
+            Durability::Normal => self.last_sync.elapsed() >= NORMAL_SYNC_INTERVAL,
+            Durability::Off => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_records(&mut self) -> Result<Vec<WalRecord>, DbError> {
+        Ok(decode_records(&self.contents()?, &self.path))
+    }
+
+    /// The log's framed records as stored.
+    pub fn contents(&mut self) -> Result<Vec<u8>, DbError> {
+        let mut contents = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    pub fn truncate(&mut self) -> Result<(), DbError> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    /// Forces the log's contents and length to disk.
+    pub fn sync(&mut self) -> Result<(), DbError> {
+        self.file
+            .sync_all()
+            .map_err(|e| DbError::DurabilityError(format!("Cannot sync {}: {}", self.path, e)))?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+// Decodes framed records, stopping at the first torn or unreadable one
+fn decode_records(contents: &[u8], path: &str) -> Vec<WalRecord> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos + 4 <= contents.len() {
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&contents[pos..pos + 4]);
+        let len = u32::from_le_bytes(len_bytes) as usize;
+        if pos + 4 + len > contents.len() {
+            log::warn!("Ignoring torn WAL record at byte {} in {}", pos, path);
+            break;
+        }
+        match bincode::deserialize::<WalRecord>(&contents[pos + 4..pos + 4 + len]) {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                log::warn!("Ignoring unreadable WAL record at byte {} in {}: {}", pos, path, e);
+                break;
+            }
+        }
+        pos += 4 + len;
+    }
+    records
+}
+
+/// The records checkpointed since the previous incremental backup (or since the full backup,
+/// for the first), numbered so that restore can tell when one is missing.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalSegment {
+    /// Id of the full backup the segment follows on from
+    pub base: String,
+    /// 1 for the first incremental backup after the full one, then counting up
+    pub sequence: u64,
+    pub records: Vec<WalRecord>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ArchiveState {
+    base: String,
+    next_sequence: u64,
+    // Schema version at the full backup; records cannot be replayed across a schema change
+    schema_version: u64,
+    // Set by the first change the WAL does not record, after which only a full backup will do
+    broken: Option<String>,
+}
+
+/// Keeps the WAL records each checkpoint would discard, from the last full `BACKUP TO` on, in
+/// `wal/archive` so that an incremental backup can copy just those. Inactive until the first
+/// full backup.
+///
+/// The WAL only records inserts. Once rows are rewritten (DELETE, an upsert that updates,
+/// a rolled back transaction) the archive can no longer bring a full backup up to date, and
+/// incremental backups fail until the next full one.
+#[derive(Debug)]
+pub struct WalArchive {
+    dir: String,
+    state: Option<ArchiveState>,
+}
+
+impl WalArchive {
+    pub fn open(data_dir: &str) -> Result<Self, DbError> {
+        let dir = format!("{}/wal/{}", data_dir, ARCHIVE_DIR);
+        let state_path = format!("{}/state.json", dir);
+        let state = match fs::read_to_string(&state_path) {
+            Ok(json) => Some(
+                serde_json::from_str(&json)
+                    .map_err(|e| DbError::SerializationError(format!("Invalid {}: {}", state_path, e)))?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(WalArchive { dir, state })
+    }
+
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Discards anything archived and starts archiving for the full backup `base`.
+    pub fn start(&mut self, base: &str, schema_version: u64) -> Result<(), DbError> {
+        if std::path::Path::new(&self.dir).exists() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        fs::create_dir_all(&self.dir)?;
+        self.state = Some(ArchiveState {
+            base: base.to_string(),
+            next_sequence: 1,
+            schema_version,
+            broken: None,
+        });
+        self.save_state()
+    }
+
+    /// Keeps `frames`, the WAL's contents about to be truncated by a checkpoint.
+    pub fn append(&mut self, frames: &[u8]) -> Result<(), DbError> {
+        if frames.is_empty() || self.state.as_ref().is_none_or(|state| state.broken.is_some()) {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new().append(true).create(true).open(self.records_path())?;
+        file.write_all(frames)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Records that a change the WAL does not capture happened, ending the incremental chain.
+    pub fn invalidate(&mut self, reason: &str) -> Result<(), DbError> {
+        match self.state.as_mut() {
+            Some(state) if state.broken.is_none() => {
+                state.broken = Some(reason.to_string());
+                let _ = fs::remove_file(self.records_path());
+                self.save_state()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The next segment, holding everything archived so far, if the chain is unbroken and
+    /// continues the full backup `base` at `schema_version`. `finish_segment` empties the
+    /// archive once the segment is safely stored.
+    pub fn next_segment(&self, base: &str, schema_version: u64) -> Result<WalSegment, DbError> {
+        let state = self.state.as_ref().ok_or_else(|| {
+            DbError::BackupError("No full backup has been taken; run BACKUP TO first".to_string())
+        })?;
+        if state.base != base {
+            return Err(DbError::BackupError(format!(
+                "Backup {} is not the latest full backup ({})",
+                base, state.base
+            )));
+        }
+        if let Some(reason) = &state.broken {
+            return Err(DbError::BackupError(format!("{} since the full backup; take a new full backup", reason)));
+        }
+        if state.schema_version != schema_version {
+            return Err(DbError::BackupError(
+                "The schema changed since the full backup; take a new full backup".to_string(),
+            ));
+        }
+        let records_path = self.records_path();
+        let contents = match fs::read(&records_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(WalSegment {
+            base: state.base.clone(),
+            sequence: state.next_sequence,
+            records: decode_records(&contents, &records_path),
+        })
+    }
+
+    pub fn finish_segment(&mut self) -> Result<(), DbError> {
+        if let Some(state) = self.state.as_mut() {
+            state.next_sequence += 1;
+            let _ = fs::remove_file(self.records_path());
+            self.save_state()?;
+        }
+        Ok(())
+    }
+
+    fn records_path(&self) -> String {
+        format!("{}/records.wal", self.dir)
+    }
+
+    fn save_state(&self) -> Result<(), DbError> {
+        let Some(state) = &self.state else { return Ok(()) };
+        let json = serde_json::to_string_pretty(state).map_err(|e| DbError::SerializationError(e.to_string()))?;
+        let path = format!("{}/state.json", self.dir);
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
\ No newline at end of file
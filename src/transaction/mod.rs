@@ -1,83 +1,513 @@
-use crate::query::{Query, planner::QueryEngine};
-use crate::storage::StorageManager;
-use crate::types::DbError;
+use crate::query::evaluator::evaluate_condition_row;
+use crate::query::{Condition, ConflictAction, OnConflict, Query, QueryResult, planner::QueryEngine};
+use crate::schema::{OnDelete, Table, ROWID};
+use crate::storage::{conflict_column, updated_row, SharedStorage, StorageManager, UpsertCounts};
+use crate::types::{DbError, SecurityContext};
 use crate::Value;
 use serde::{Deserialize, Serialize};
-use std::fs::{self, File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
-#[derive(Clone, Serialize, Deserialize)]
+static NEXT_TX_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How much a transaction is shielded from others running at the same time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IsolationLevel {
+    /// Reads see committed data plus the transaction's own writes.
+    #[default]
+    ReadCommitted,
+    /// Read Committed, and COMMIT fails if another transaction wrote to one of the same
+    /// tables after this one began.
+    Serializable,
+}
+
+impl fmt::Display for IsolationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsolationLevel::ReadCommitted => write!(f, "READ COMMITTED"),
+            IsolationLevel::Serializable => write!(f, "SERIALIZABLE"),
+        }
+    }
+}
+
+/// A write buffered by an open transaction until COMMIT.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WriteOp {
+    // A complete row, with defaults already filled in
+    Insert { table: String, row: Vec<Value> },
+    Delete { table: String, condition: Option<Condition> },
+    // Complete rows of one INSERT ... ON CONFLICT
+    Upsert { table: String, rows: Vec<Vec<Value>>, on_conflict: OnConflict },
+}
+
+impl WriteOp {
+    pub fn table(&self) -> &str {
+        match self {
+            WriteOp::Insert { table, .. } | WriteOp::Delete { table, .. } | WriteOp::Upsert { table, .. } => table,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Transaction {
     id: u64,
     queries: Vec<Query>,
+    writes: Vec<WriteOp>,
+    // Savepoint names with the number of writes buffered when each was taken, oldest first
+    savepoints: Vec<(String, usize)>,
+    isolation_level: IsolationLevel,
+    // Table versions as of BEGIN, compared at COMMIT under Serializable
+    start_versions: HashMap<String, u64>,
 }
 
 impl Transaction {
+    pub fn new() -> Self {
+        Transaction {
+            id: NEXT_TX_ID.fetch_add(1, Ordering::Relaxed),
+            queries: Vec::new(),
+            writes: Vec::new(),
+            savepoints: Vec::new(),
+            isolation_level: IsolationLevel::default(),
+            start_versions: HashMap::new(),
+        }
+    }
+
+    /// Starts a transaction at `isolation_level`, remembering which table versions it began from.
+    pub fn begin(isolation_level: IsolationLevel, storage: &StorageManager) -> Self {
+        let mut tx = Transaction::new();
+        tx.isolation_level = isolation_level;
+        tx.start_versions = storage
+            .schema()
+            .tables
+            .keys()
+            .map(|table| (table.clone(), storage.table_version(table)))
+            .collect();
+        tx
+    }
+
+    pub fn isolation_level(&self) -> IsolationLevel {
+        self.isolation_level
+    }
+
+    pub fn set_isolation_level(&mut self, level: IsolationLevel) -> Result<(), DbError> {
+        if !self.writes.is_empty() {
+            return Err(DbError::TransactionError(
+                "SET TRANSACTION must come before the transaction's first write".to_string(),
+            ));
+        }
+        self.isolation_level = level;
+        Ok(())
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     pub fn add_query(&mut self, query: Query) {
         self.queries.push(query);
     }
+
+    pub fn record_write(&mut self, write: WriteOp) {
+        self.writes.push(write);
+    }
+
+    pub fn writes(&self) -> &[WriteOp] {
+        &self.writes
+    }
+
+    pub fn savepoint(&mut self, name: &str) {
+        self.savepoints.push((name.to_string(), self.writes.len()));
+    }
+
+    /// Discards the writes made since the most recent savepoint called `name`, along with
+    /// any savepoints taken after it. The savepoint itself stays usable.
+    pub fn rollback_to_savepoint(&mut self, name: &str) -> Result<(), DbError> {
+        let position = self
+            .savepoints
+            .iter()
+            .rposition(|(savepoint, _)| savepoint == name)
+            .ok_or_else(|| DbError::TransactionError(format!("Savepoint {} does not exist", name)))?;
+        let write_count = self.savepoints[position].1;
+        self.savepoints.truncate(position + 1);
+        self.writes.truncate(write_count);
+        Ok(())
+    }
+
+    pub fn writes_to(&self, table: &str) -> bool {
+        self.writes.iter().any(|w| w.table() == table)
+    }
+
+    /// The columns of `table` as seen from inside this transaction: committed rows with the
+    /// buffered inserts and deletes replayed on top. Buffered rows have a NULL rowid until
+    /// COMMIT gives them one.
+    pub fn visible_columns(
+        &self,
+        storage: &StorageManager,
+        table: &str,
+    ) -> Result<HashMap<String, Vec<Value>>, DbError> {
+        let mut columns = read_table(storage, table)?;
+        let table_def = storage.schema().get_table(table);
+        let names: Vec<String> = table_def.map(|t| t.columns.iter().map(|c| c.name.clone()).collect()).unwrap_or_default();
+        for write in self.writes.iter().filter(|w| w.table() == table) {
+            match write {
+                WriteOp::Insert { row, .. } => {
+                    for (name, value) in names.iter().zip(row) {
+                        columns.entry(name.clone()).or_default().push(value.clone());
+                    }
+                    columns.entry(ROWID.to_string()).or_default().push(Value::Null);
+                }
+                WriteOp::Delete { condition, .. } => {
+                    let row_count = columns.values().next().map_or(0, |c| c.len());
+                    let keep = match condition {
+                        Some(cond) => (0..row_count)
+                            .map(|i| evaluate_condition_row(cond, &columns, i).map(|matched| !matched))
+                            .collect::<Result<Vec<_>, _>>()?,
+                        None => vec![false; row_count],
+                    };
+                    for values in columns.values_mut() {
+                        let mut keep = keep.iter();
+                        values.retain(|_| *keep.next().unwrap_or(&true));
+                    }
+                }
+                WriteOp::Upsert { rows, on_conflict, .. } => {
+                    if let Some(table_def) = table_def {
+                        upsert_columns(&mut columns, table_def, rows, on_conflict)?;
+                    }
+                }
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Buffers an upsert of the complete `rows` into `table` and returns how many rows it
+    /// inserts and updates as the transaction sees the table. At COMMIT it is applied with
+    /// `upsert_rows`, against the rows committed by then.
+    pub fn record_upsert(
+        &mut self,
+        storage: &StorageManager,
+        table: &str,
+        rows: Vec<Vec<Value>>,
+        on_conflict: OnConflict,
+    ) -> Result<UpsertCounts, DbError> {
+        let table_def = storage
+            .schema()
+            .get_table(table)
+            .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table)))?;
+        let mut columns = self.visible_columns(storage, table)?;
+        let counts = upsert_columns(&mut columns, table_def, &rows, &on_conflict)?;
+        self.record_write(WriteOp::Upsert { table: table.to_string(), rows, on_conflict });
+        Ok(counts)
+    }
+
+    /// Buffers a delete of the rows of `table` matching `condition` and returns how many it
+    /// removes. Foreign keys are followed as `delete_rows` does at COMMIT: rows referencing a
+    /// removed one are buffered for deletion too under ON DELETE CASCADE, so the transaction
+    /// sees them gone, and under RESTRICT nothing is buffered and `ValidationError` returned.
+    pub fn record_delete(
+        &mut self,
+        storage: &StorageManager,
+        table: &str,
+        condition: Option<Condition>,
+    ) -> Result<usize, DbError> {
+        let buffered = self.writes.len();
+        let result = self.record_cascading_delete(storage, table, condition);
+        if result.is_err() {
+            self.writes.truncate(buffered);
+        }
+        result
+    }
+
+    fn record_cascading_delete(
+        &mut self,
+        storage: &StorageManager,
+        table: &str,
+        condition: Option<Condition>,
+    ) -> Result<usize, DbError> {
+        let columns = self.visible_columns(storage, table)?;
+        let row_count = columns.values().next().map_or(0, |c| c.len());
+        let mut removed_rows = Vec::new();
+        for i in 0..row_count {
+            if condition.as_ref().map_or(Ok(true), |cond| evaluate_condition_row(cond, &columns, i))? {
+                removed_rows.push(i);
+            }
+        }
+        self.record_write(WriteOp::Delete { table: table.to_string(), condition });
+        if removed_rows.is_empty() {
+            return Ok(0);
+        }
+
+        // The parent rows are already gone from view, so a table referencing itself terminates
+        for (child_table, child_col) in storage.schema().referencing_columns(table) {
+            let Some(fk) = child_col.references.as_ref() else { continue };
+            let removed: HashSet<&Value> = removed_rows
+                .iter()
+                .filter_map(|&i| columns.get(&fk.reference_column).and_then(|values| values.get(i)))
+                .filter(|v| !v.is_null())
+                .collect();
+            let child_columns = self.visible_columns(storage, &child_table)?;
+            let Some(referenced) = child_columns
+                .get(&child_col.name)
+                .and_then(|values| values.iter().find(|v| removed.contains(v)))
+            else {
+                continue;
+            };
+            match fk.on_delete {
+                OnDelete::Restrict => {
+                    return Err(DbError::ValidationError(format!(
+                        "Cannot delete from {}: {}.{} still references {}",
+                        table, child_table, child_col.name, referenced
+                    )));
+                }
+                OnDelete::Cascade => {
+                    let matches_removed = removed
+                        .into_iter()
+                        .map(|value| Condition::Equal(child_col.name.clone(), value.clone()))
+                        .reduce(|a, b| Condition::Or(Box::new(a), Box::new(b)));
+                    self.record_cascading_delete(storage, &child_table, matches_removed)?;
+                }
+            }
+        }
+        Ok(removed_rows.len())
+    }
+
+    /// How many rows of `table` visible to this transaction match `condition`.
+    pub fn count_matching(
+        &self,
+        storage: &StorageManager,
+        table: &str,
+        condition: Option<&Condition>,
+    ) -> Result<usize, DbError> {
+        let columns = self.visible_columns(storage, table)?;
+        let row_count = columns.values().next().map_or(0, |c| c.len());
+        match condition {
+            Some(cond) => {
+                let mut matched = 0;
+                for i in 0..row_count {
+                    if evaluate_condition_row(cond, &columns, i)? {
+                        matched += 1;
+                    }
+                }
+                Ok(matched)
+            }
+            None => Ok(row_count),
+        }
+    }
+
+    /// Applies the buffered writes to storage as one `StorageManager::atomically` write. Either
+    /// all of them take effect or, if any write fails, every table they touched is restored
+    /// before the error is returned. A crash partway through leaves none of them.
+    pub fn apply_writes(self, storage: &mut StorageManager) -> Result<(), DbError> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        if self.isolation_level == IsolationLevel::Serializable {
+            for write in &self.writes {
+                let table = write.table();
+                if self.start_versions.get(table).copied().unwrap_or(0) != storage.table_version(table) {
+                    return Err(DbError::ConcurrencyError(format!(
+                        "Transaction {} aborted: {} was modified by a concurrent transaction",
+                        self.id, table
+                    )));
+                }
+            }
+        }
+        let tables: Vec<String> = affected_tables(storage, &self.writes).into_iter().collect();
+
+        // Subscribers only hear about the transaction once all of it has been applied
+        storage.begin_change_batch();
+        let result = storage.atomically(&tables, |storage| apply(storage, self.writes));
+        storage.end_change_batch(result.is_ok());
+        result
+    }
+}
+
+// Applies `writes` in order, stopping at the first that fails
+fn apply(storage: &mut StorageManager, writes: Vec<WriteOp>) -> Result<(), DbError> {
+    let mut writes = writes.into_iter().peekable();
+    while let Some(write) = writes.next() {
+        match write {
+            WriteOp::Insert { table, row } => {
+                // Consecutive inserts into one table are applied as a batch
+                let mut rows = vec![row];
+                while let Some(WriteOp::Insert { row, .. }) =
+                    writes.next_if(|next| matches!(next, WriteOp::Insert { table: next_table, .. } if *next_table == table))
+                {
+                    rows.push(row);
+                }
+                storage.insert_rows(&table, &[], rows)?;
+            }
+            WriteOp::Delete { table, condition } => {
+                storage.delete_rows(&table, condition.as_ref())?;
+            }
+            WriteOp::Upsert { table, rows, on_conflict } => {
+                storage.upsert_rows(&table, &[], rows, &on_conflict)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Applies an upsert of `rows` to `columns`, a table as `visible_columns` holds it, the way
+// `upsert_rows` applies one to storage. Constraints are left for COMMIT to check.
+fn upsert_columns(
+    columns: &mut HashMap<String, Vec<Value>>,
+    table_def: &Table,
+    rows: &[Vec<Value>],
+    on_conflict: &OnConflict,
+) -> Result<UpsertCounts, DbError> {
+    let conflict_pos = conflict_column(table_def, on_conflict)?;
+    let mut counts = UpsertCounts::default();
+    for proposed in rows {
+        let key = &proposed[conflict_pos];
+        let existing = columns
+            .get(&on_conflict.column)
+            .filter(|_| !key.is_null())
+            .and_then(|values| values.iter().position(|v| v == key));
+        match (existing, &on_conflict.action) {
+            (None, _) => {
+                for (col, value) in table_def.columns.iter().zip(proposed) {
+                    columns.entry(col.name.clone()).or_default().push(value.clone());
+                }
+                columns.entry(ROWID.to_string()).or_default().push(Value::Null);
+                counts.inserted += 1;
+            }
+            (Some(_), ConflictAction::DoNothing) => {}
+            (Some(row), ConflictAction::DoUpdate(assignments)) => {
+                let old_row: Vec<Value> = table_def
+                    .columns
+                    .iter()
+                    .map(|col| columns.get(&col.name).and_then(|values| values.get(row)).cloned().unwrap_or(Value::Null))
+                    .collect();
+                let new_row = updated_row(table_def, &old_row, proposed, assignments)?;
+                for (col, value) in table_def.columns.iter().zip(new_row) {
+                    if let Some(values) = columns.get_mut(&col.name) {
+                        values[row] = value;
+                    }
+                }
+                counts.updated += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Every column of `table`, the rowid included
+fn read_table(storage: &StorageManager, table: &str) -> Result<HashMap<String, Vec<Value>>, DbError> {
+    let names: Vec<String> = storage
+        .schema()
+        .get_table(table)
+        .ok_or_else(|| DbError::InvalidData(format!("Table {} not found", table)))?
+        .stored_columns()
+        .map(|c| c.name.clone())
+        .collect();
+    storage.read_columns(table, &names, None)
+}
+
+// Tables written directly, plus every table a DELETE could cascade into
+fn affected_tables(storage: &StorageManager, writes: &[WriteOp]) -> HashSet<String> {
+    let mut tables: HashSet<String> = HashSet::new();
+    let mut pending: Vec<String> = writes.iter().map(|w| w.table().to_string()).collect();
+    while let Some(table) = pending.pop() {
+        if tables.insert(table.clone()) {
+            pending.extend(storage.schema().referencing_columns(&table).into_iter().map(|(child, _)| child));
+        }
+    }
+    tables
 }
 
 #[derive(Debug)]
 pub struct TransactionManager {
-    storage: Arc<Mutex<StorageManager>>,
-    next_tx_id: u64,
-    wal: File,
+    // Kept across calls so a BEGIN in one batch stays open until a later COMMIT or ROLLBACK
+    query_engine: QueryEngine,
 }
 
 impl TransactionManager {
-    pub fn new(storage: Arc<Mutex<StorageManager>>) -> Result<Self, DbError> {
-        let data_dir = {
-            let storage_guard = storage.lock().unwrap();
-            storage_guard.data_dir().to_string()
-        };
-        let wal_dir = format!("{}/wal", data_dir);
-        fs::create_dir_all(&wal_dir)?;
-        let wal = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(format!("{}/wal.log", wal_dir))
-            .map_err(|e| DbError::IoError(e))?;
+    pub fn new(storage: SharedStorage) -> Result<Self, DbError> {
         Ok(TransactionManager {
-            storage,
-            next_tx_id: 1,
-            wal,
+            query_engine: QueryEngine::new(storage),
         })
     }
 
     pub fn begin_transaction(&mut self) -> Transaction {
-        let tx = Transaction {
-            id: self.next_tx_id,
-            queries: Vec::new(),
-        };
-        self.next_tx_id += 1;
-        tx
+        Transaction::new()
+    }
+
+    /// Isolation level for transactions started with BEGIN from now on.
+    pub fn isolation_level(&self) -> IsolationLevel {
+        self.query_engine.default_isolation_level()
+    }
+
+    pub fn set_isolation_level(&mut self, level: IsolationLevel) {
+        self.query_engine.set_default_isolation_level(level);
+    }
+
+    /// Row count from which SELECT filters a batch in parallel; see `QueryEngine::set_parallel_threshold`.
+    pub fn set_parallel_threshold(&mut self, threshold: usize) {
+        self.query_engine.set_parallel_threshold(threshold);
+    }
+
+    /// Rows a SELECT or JOIN may return; see `QueryEngine::set_max_result_rows`.
+    pub fn set_max_result_rows(&mut self, max_rows: Option<usize>) {
+        self.query_engine.set_max_result_rows(max_rows);
     }
 
-    pub fn commit_transaction(&mut self, mut tx: Transaction) -> Result<Vec<Vec<Value>>, DbError> {
-        let tx_data = bincode::serialize(&tx).map_err(|e| DbError::from(*e))?;
-        self.wal.write_all(&tx_data)?;
-        self.wal.flush()?;
+    /// Time a SELECT or JOIN may run; see `QueryEngine::set_statement_timeout`.
+    pub fn set_statement_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.query_engine.set_statement_timeout(timeout);
+    }
+
+    /// Flag that stops the running statement; see `QueryEngine::cancel_handle`.
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        self.query_engine.cancel_handle()
+    }
+
+    /// Checks every later query against `security`; `None` runs queries unchecked.
+    pub fn set_security_context(&mut self, security: Option<SecurityContext>) {
+        self.query_engine.set_security_context(security);
+    }
+
+    pub fn security_context(&self) -> Option<&SecurityContext> {
+        self.query_engine.security_context()
+    }
 
+    /// Parses `sql`, reusing a cached plan; see `QueryEngine::prepare`.
+    pub fn prepare(&mut self, sql: &str) -> Result<Query, DbError> {
+        self.query_engine.prepare(sql)
+    }
+
+    /// Whether a BEGIN run through this manager is still open.
+    pub fn in_transaction(&self) -> bool {
+        self.query_engine.in_transaction()
+    }
+
+    pub fn commit_transaction(&mut self, tx: Transaction) -> Result<Vec<Vec<Value>>, DbError> {
+        let results = self.commit_transaction_results(tx)?;
+        Ok(results.into_iter().flat_map(|result| result.rows).collect())
+    }
+
+    /// Like `commit_transaction`, but keeps each row-producing query's result and headers
+    /// separate. A query that could produce rows keeps its headers even when it matched none.
+    pub fn commit_transaction_results(&mut self, mut tx: Transaction) -> Result<Vec<QueryResult>, DbError> {
         let mut results = Vec::new();
-        let mut query_engine = QueryEngine::new(Arc::clone(&self.storage));
         for query in tx.queries.drain(..) {
-            let result = query_engine.execute(query)?;
-            if !result.is_empty() {
+            let result = self.query_engine.execute_query(query)?;
+            if !result.columns.is_empty() {
                 results.push(result);
             }
         }
-
-        self.wal.set_len(0)?;
-        self.wal.seek(SeekFrom::Start(0))?;
-        Ok(results.into_iter().flatten().collect())
+        Ok(results)
     }
 
     pub fn rollback_transaction(&mut self, _tx: Transaction) -> Result<(), DbError> {
-        self.wal.set_len(0)?;
-        self.wal.seek(SeekFrom::Start(0))?;
+        // Queries are only executed on commit, so discarding the transaction is enough
         Ok(())
     }
 }
\ No newline at end of file
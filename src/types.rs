@@ -1,3 +1,5 @@
+use crate::auth::ADMIN_ROLE;
+use crate::query::Query;
 use ordered_float::OrderedFloat;
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -9,6 +11,23 @@ pub enum DataType {
     Int32,
     Float32,
     String,
+    Timestamp,
+    Null,
+    // Last so that values already encoded by bincode keep their variant index
+    Int64,
+}
+
+impl DataType {
+    /// The type's name in `CREATE TABLE`.
+    pub fn sql_name(&self) -> &'static str {
+        match self {
+            DataType::Int32 => "INT",
+            DataType::Int64 => "BIGINT",
+            DataType::Float32 => "FLOAT",
+            DataType::Timestamp => "TIMESTAMP",
+            DataType::String | DataType::Null => "TEXT",
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -16,14 +35,28 @@ pub enum Value {
     Int32(i32),
     Float32(OrderedFloat<f32>),
     String(String),
+    // Milliseconds since the Unix epoch, UTC
+    Timestamp(i64),
+    Null,
+    // BIGINT; also what SUM over integer columns returns
+    Int64(i64),
 }
 
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
             (Value::Int32(a), Value::Int32(b)) => a.partial_cmp(b),
+            (Value::Int64(a), Value::Int64(b)) => a.partial_cmp(b),
             (Value::Float32(a), Value::Float32(b)) => a.partial_cmp(b),
             (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+            (Value::Timestamp(a), Value::Timestamp(b)) => a.partial_cmp(b),
+            // Mixed numbers compare by value, the way `int_col > 2.5` reads
+            (Value::Int32(_) | Value::Int64(_) | Value::Float32(_), Value::Int32(_) | Value::Int64(_) | Value::Float32(_)) => {
+                self.as_f64()?.partial_cmp(&other.as_f64()?)
+            }
+            (Value::Null, Value::Null) => Some(std::cmp::Ordering::Equal),
+            (Value::Null, _) => Some(std::cmp::Ordering::Less),
+            (_, Value::Null) => Some(std::cmp::Ordering::Greater),
             _ => None,
         }
     }
@@ -33,8 +66,13 @@ impl Ord for Value {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         match (self, other) {
             (Value::Int32(a), Value::Int32(b)) => a.cmp(b),
+            (Value::Int64(a), Value::Int64(b)) => a.cmp(b),
             (Value::Float32(a), Value::Float32(b)) => a.cmp(b),
             (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Timestamp(a), Value::Timestamp(b)) => a.cmp(b),
+            (Value::Null, Value::Null) => std::cmp::Ordering::Equal,
+            (Value::Null, _) => std::cmp::Ordering::Less,
+            (_, Value::Null) => std::cmp::Ordering::Greater,
             _ => std::cmp::Ordering::Equal,
         }
     }
@@ -44,8 +82,95 @@ impl Value {
     pub fn data_type(&self) -> DataType {
         match self {
             Value::Int32(_) => DataType::Int32,
+            Value::Int64(_) => DataType::Int64,
             Value::Float32(_) => DataType::Float32,
             Value::String(_) => DataType::String,
+            Value::Timestamp(_) => DataType::Timestamp,
+            Value::Null => DataType::Null,
+        }
+    }
+
+    /// The current time, as `NOW()` evaluates it.
+    pub fn now() -> Value {
+        Value::Timestamp(chrono::Utc::now().timestamp_millis())
+    }
+
+    /// Parses an RFC 3339 timestamp such as `2024-01-01T00:00:00Z`, converting it to UTC.
+    pub fn parse_timestamp(text: &str) -> Result<Value, DbError> {
+        chrono::DateTime::parse_from_rfc3339(text.trim())
+            .map(|ts| Value::Timestamp(ts.timestamp_millis()))
+            .map_err(|e| DbError::InvalidData(format!("Invalid timestamp '{}': {}", text, e)))
+    }
+
+    /// Renders a timestamp in RFC 3339 form, with milliseconds only when they are non-zero.
+    pub fn format_timestamp(millis: i64) -> String {
+        match chrono::DateTime::from_timestamp_millis(millis) {
+            Some(ts) => ts.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+            None => millis.to_string(),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Whether the value is a FLOAT NaN. NaN is not a number to aggregate or rank: SUM, AVG,
+    /// MIN and MAX skip it like NULL, and ORDER BY puts it after every number in either direction.
+    pub fn is_nan(&self) -> bool {
+        matches!(self, Value::Float32(f) if f.0.is_nan())
+    }
+
+    /// Whether the value can be stored in a column of `data_type`; NULL fits any column.
+    /// The value of an INT, BIGINT or FLOAT as an f64; `None` for anything else.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int32(i) => Some(*i as f64),
+            Value::Int64(i) => Some(*i as f64),
+            Value::Float32(f) => Some(f.0 as f64),
+            _ => None,
+        }
+    }
+
+    pub fn matches_type(&self, data_type: &DataType) -> bool {
+        self.is_null() || self.data_type() == *data_type
+    }
+
+    /// The value as it is stored in a column of `data_type`: INT values widen to BIGINT and to
+    /// FLOAT, so that plain integer literals can be used with those columns. Others are
+    /// unchanged; nothing narrows, so a FLOAT never silently becomes an INT.
+    pub fn widen_to(self, data_type: &DataType) -> Value {
+        match (self, data_type) {
+            (Value::Int32(i), DataType::Int64) => Value::Int64(i as i64),
+            (Value::Int32(i), DataType::Float32) => Value::Float32(OrderedFloat(i as f32)),
+            (value, _) => value,
+        }
+    }
+
+    /// Placeholder written to column files in place of NULL; the block metadata records
+    /// which positions are actually null.
+    pub fn placeholder(data_type: &DataType) -> Value {
+        match data_type {
+            DataType::Int32 => Value::Int32(0),
+            DataType::Int64 => Value::Int64(0),
+            DataType::Float32 => Value::Float32(OrderedFloat(0.0)),
+            DataType::String => Value::String(String::new()),
+            DataType::Timestamp => Value::Timestamp(0),
+            DataType::Null => Value::Null,
+        }
+    }
+
+    /// Renders the value as a SQL literal the parser reads back unchanged, doubling quotes
+    /// inside strings.
+    pub fn to_sql_literal(&self) -> String {
+        match self {
+            Value::Int32(i) => i.to_string(),
+            Value::Int64(i) => i.to_string(),
+            // Keep a decimal point so the literal parses back as a float
+            Value::Float32(f) if f.0.is_finite() && f.0.fract() == 0.0 => format!("{:.1}", f.0),
+            Value::Float32(f) => f.0.to_string(),
+            Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+            Value::Timestamp(ms) => format!("TIMESTAMP '{}'", Value::format_timestamp(*ms)),
+            Value::Null => "NULL".to_string(),
         }
     }
 
@@ -53,6 +178,7 @@ impl Value {
         match self {
             Value::Int32(i) => i.to_le_bytes().to_vec(),
             Value::Float32(f) => f.0.to_le_bytes().to_vec(),
+            Value::Int64(i) | Value::Timestamp(i) => i.to_le_bytes().to_vec(),
             Value::String(s) => {
                 let bytes = s.as_bytes();
                 let len = bytes.len() as u32;
@@ -60,6 +186,7 @@ impl Value {
                 result.extend(bytes);
                 result
             }
+            Value::Null => Vec::new(),
         }
     }
 
@@ -83,6 +210,24 @@ impl Value {
                     Err(DbError::SerializationError("Insufficient bytes for Float32".to_string()))
                 }
             }
+            DataType::Timestamp => {
+                if bytes.len() >= 8 {
+                    let mut array = [0u8; 8];
+                    array.copy_from_slice(&bytes[..8]);
+                    Ok(Value::Timestamp(i64::from_le_bytes(array)))
+                } else {
+                    Err(DbError::SerializationError("Insufficient bytes for Timestamp".to_string()))
+                }
+            }
+            DataType::Int64 => {
+                if bytes.len() >= 8 {
+                    let mut array = [0u8; 8];
+                    array.copy_from_slice(&bytes[..8]);
+                    Ok(Value::Int64(i64::from_le_bytes(array)))
+                } else {
+                    Err(DbError::SerializationError("Insufficient bytes for Int64".to_string()))
+                }
+            }
             DataType::String => {
                 if bytes.len() >= 4 {
                     let mut len_array = [0u8; 4];
@@ -99,6 +244,7 @@ impl Value {
                     Err(DbError::SerializationError("Insufficient bytes for String length".to_string()))
                 }
             }
+            DataType::Null => Ok(Value::Null),
         }
     }
 
@@ -106,7 +252,66 @@ impl Value {
         match self {
             Value::Int32(_) => 4,
             Value::Float32(_) => 4,
-            Value::String(s) => 4 + s.as_bytes().len(),
+            Value::String(s) => 4 + s.len(),
+            Value::Int64(_) | Value::Timestamp(_) => 8,
+            Value::Null => 0,
+        }
+    }
+
+    pub fn cast(&self, target: &DataType) -> Result<Value, DbError> {
+        match (self, target) {
+            (Value::Null, _) => Ok(Value::Null),
+            (Value::Int32(i), DataType::Int32) => Ok(Value::Int32(*i)),
+            (Value::Int32(i), DataType::Float32) => Ok(Value::Float32(OrderedFloat(*i as f32))),
+            (Value::Int32(i), DataType::Int64) => Ok(Value::Int64(*i as i64)),
+            (Value::Int64(i), DataType::Int64) => Ok(Value::Int64(*i)),
+            (Value::Int64(i), DataType::Int32) => i32::try_from(*i)
+                .map(Value::Int32)
+                .map_err(|_| DbError::InvalidData(format!("Cannot cast {} to Int32: out of range", i))),
+            (Value::Int64(i), DataType::Float32) => Ok(Value::Float32(OrderedFloat(*i as f32))),
+            (Value::Float32(f), DataType::Int64) => {
+                let truncated = f.0.trunc();
+                // i64::MAX rounds up to 2^63 as an f32, which is already out of range
+                if !truncated.is_finite() || truncated < i64::MIN as f32 || truncated >= i64::MAX as f32 {
+                    return Err(DbError::InvalidData(format!("Cannot cast {} to Int64: out of range", f.0)));
+                }
+                Ok(Value::Int64(truncated as i64))
+            }
+            (Value::Float32(f), DataType::Float32) => Ok(Value::Float32(*f)),
+            (Value::Float32(f), DataType::Int32) => {
+                let truncated = f.0.trunc();
+                if !truncated.is_finite() || truncated < i32::MIN as f32 || truncated > i32::MAX as f32 {
+                    return Err(DbError::InvalidData(format!("Cannot cast {} to Int32: out of range", f.0)));
+                }
+                Ok(Value::Int32(truncated as i32))
+            }
+            (Value::Int32(_), DataType::String) | (Value::Int64(_), DataType::String) | (Value::Float32(_), DataType::String) => {
+                Ok(Value::String(self.to_string()))
+            }
+            (Value::Timestamp(ms), DataType::Timestamp) => Ok(Value::Timestamp(*ms)),
+            (Value::Timestamp(ms), DataType::String) => Ok(Value::String(Value::format_timestamp(*ms))),
+            (Value::String(s), DataType::Timestamp) => Value::parse_timestamp(s),
+            (Value::Timestamp(_), _) | (_, DataType::Timestamp) => Err(DbError::InvalidData(format!(
+                "Cannot cast {} to {:?}",
+                self, target
+            ))),
+            (Value::String(s), DataType::String) => Ok(Value::String(s.clone())),
+            (Value::String(s), DataType::Int32) => s
+                .trim()
+                .parse::<i32>()
+                .map(Value::Int32)
+                .map_err(|_| DbError::InvalidData(format!("Cannot cast '{}' to Int32", s))),
+            (Value::String(s), DataType::Int64) => s
+                .trim()
+                .parse::<i64>()
+                .map(Value::Int64)
+                .map_err(|_| DbError::InvalidData(format!("Cannot cast '{}' to Int64", s))),
+            (Value::String(s), DataType::Float32) => s
+                .trim()
+                .parse::<f32>()
+                .map(|f| Value::Float32(OrderedFloat(f)))
+                .map_err(|_| DbError::InvalidData(format!("Cannot cast '{}' to Float32", s))),
+            (_, DataType::Null) => Ok(Value::Null),
         }
     }
 }
@@ -122,7 +327,7 @@ pub enum CompressionType {
 pub enum DbError {
     IoError(std::io::Error),
     SerializationError(String),
-    TypeMismatch,
+    TypeMismatch(String),
     InvalidData(String),
     TransactionError(String),
     QueryError(String),
@@ -141,6 +346,7 @@ pub enum DbError {
     CacheError(String),
     NetworkError(String),
     TimeoutError(String),
+    Cancelled(String),
     BackupError(String),
     RecoveryError(String),
     ReplicationError(String),
@@ -199,6 +405,214 @@ pub enum DbError {
     DataConfidentialityViolationError(String),
 }
 
+/// Broad kind of a `DbError`, for callers that handle errors by what went wrong rather than by
+/// the exact variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCategory {
+    /// Reading or writing files, the network, backups or the log
+    Io,
+    /// A statement that is malformed or does not fit the data, including constraint violations
+    Query,
+    /// Tables, indexes, migrations and the catalog format
+    Schema,
+    /// Transaction misuse and lock failures
+    Transaction,
+    /// Authentication, permissions, auditing and data policies
+    Security,
+    /// Limits: result caps, timeouts, cancellation and capacity
+    Resource,
+    /// Everything else, usually a bug or corrupt data
+    Internal,
+}
+
+impl DbError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            DbError::IoError(_)
+            | DbError::SerializationError(_)
+            | DbError::StorageError(_)
+            | DbError::NetworkError(_)
+            | DbError::BackupError(_)
+            | DbError::RecoveryError(_)
+            | DbError::ReplicationError(_)
+            | DbError::DurabilityError(_)
+            | DbError::AvailabilityError(_)
+            | DbError::DataDurabilityError(_)
+            | DbError::DataAvailabilityError(_)
+            | DbError::DataDurabilityViolationError(_)
+            | DbError::DataAvailabilityViolationError(_) => ErrorCategory::Io,
+            DbError::TypeMismatch(_)
+            | DbError::InvalidData(_)
+            | DbError::QueryError(_)
+            | DbError::ValidationError(_)
+            | DbError::ConfigurationError(_) => ErrorCategory::Query,
+            DbError::SchemaError(_) | DbError::IndexError(_) | DbError::MigrationError(_) | DbError::VersionError(_) => {
+                ErrorCategory::Schema
+            }
+            DbError::TransactionError(_) | DbError::ConcurrencyError(_) | DbError::ConsistencyError(_) => {
+                ErrorCategory::Transaction
+            }
+            DbError::SecurityError(_)
+            | DbError::AuthenticationError(_)
+            | DbError::AuthorizationError(_)
+            | DbError::AuditError(_)
+            | DbError::ComplianceError(_)
+            | DbError::ConfidentialityError(_)
+            | DbError::PrivacyError(_)
+            | DbError::GovernanceError(_)
+            | DbError::PolicyError(_)
+            | DbError::ComplianceViolationError(_)
+            | DbError::AuditViolationError(_)
+            | DbError::SecurityViolationError(_)
+            | DbError::DataProtectionError(_)
+            | DbError::DataRetentionError(_)
+            | DbError::DataDisposalError(_)
+            | DbError::DataClassificationError(_)
+            | DbError::DataGovernanceError(_)
+            | DbError::DataPrivacyError(_)
+            | DbError::DataSecurityError(_)
+            | DbError::DataComplianceError(_)
+            | DbError::DataAuditError(_)
+            | DbError::DataConfidentialityError(_)
+            | DbError::DataPrivacyViolationError(_)
+            | DbError::DataSecurityViolationError(_)
+            | DbError::DataComplianceViolationError(_)
+            | DbError::DataAuditViolationError(_)
+            | DbError::DataConfidentialityViolationError(_) => ErrorCategory::Security,
+            DbError::ResourceExhausted(_)
+            | DbError::TimeoutError(_)
+            | DbError::Cancelled(_)
+            | DbError::PerformanceError(_)
+            | DbError::CapacityError(_)
+            | DbError::DataPerformanceError(_)
+            | DbError::DataCapacityError(_)
+            | DbError::DataPerformanceViolationError(_)
+            | DbError::DataCapacityViolationError(_) => ErrorCategory::Resource,
+            DbError::PluginError(_)
+            | DbError::MetricsError(_)
+            | DbError::CacheError(_)
+            | DbError::MaintenanceError(_)
+            | DbError::MonitoringError(_)
+            | DbError::AlertError(_)
+            | DbError::IntegrityError(_)
+            | DbError::DataQualityError(_)
+            | DbError::DataLineageError(_)
+            | DbError::DataMonitoringError(_)
+            | DbError::DataAlertError(_)
+            | DbError::DataMetricsError(_)
+            | DbError::DataIntegrityError(_)
+            | DbError::DataMonitoringViolationError(_)
+            | DbError::DataAlertViolationError(_)
+            | DbError::DataMetricsViolationError(_)
+            | DbError::DataIntegrityViolationError(_) => ErrorCategory::Internal,
+        }
+    }
+
+    /// A stable, machine-readable name for the variant, e.g. `TYPE_MISMATCH`. Unlike the
+    /// message it never changes wording, so clients can match on it.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DbError::IoError(_) => "IO_ERROR",
+            DbError::SerializationError(_) => "SERIALIZATION_ERROR",
+            DbError::TypeMismatch(_) => "TYPE_MISMATCH",
+            DbError::InvalidData(_) => "INVALID_DATA",
+            DbError::TransactionError(_) => "TRANSACTION_ERROR",
+            DbError::QueryError(_) => "QUERY_ERROR",
+            DbError::SecurityError(_) => "SECURITY_ERROR",
+            DbError::ValidationError(_) => "VALIDATION_ERROR",
+            DbError::ConcurrencyError(_) => "CONCURRENCY_ERROR",
+            DbError::ResourceExhausted(_) => "RESOURCE_EXHAUSTED",
+            DbError::ConfigurationError(_) => "CONFIGURATION_ERROR",
+            DbError::AuthenticationError(_) => "AUTHENTICATION_ERROR",
+            DbError::AuthorizationError(_) => "AUTHORIZATION_ERROR",
+            DbError::PluginError(_) => "PLUGIN_ERROR",
+            DbError::MetricsError(_) => "METRICS_ERROR",
+            DbError::SchemaError(_) => "SCHEMA_ERROR",
+            DbError::StorageError(_) => "STORAGE_ERROR",
+            DbError::IndexError(_) => "INDEX_ERROR",
+            DbError::CacheError(_) => "CACHE_ERROR",
+            DbError::NetworkError(_) => "NETWORK_ERROR",
+            DbError::TimeoutError(_) => "TIMEOUT_ERROR",
+            DbError::Cancelled(_) => "CANCELLED",
+            DbError::BackupError(_) => "BACKUP_ERROR",
+            DbError::RecoveryError(_) => "RECOVERY_ERROR",
+            DbError::ReplicationError(_) => "REPLICATION_ERROR",
+            DbError::ConsistencyError(_) => "CONSISTENCY_ERROR",
+            DbError::VersionError(_) => "VERSION_ERROR",
+            DbError::MigrationError(_) => "MIGRATION_ERROR",
+            DbError::MaintenanceError(_) => "MAINTENANCE_ERROR",
+            DbError::MonitoringError(_) => "MONITORING_ERROR",
+            DbError::AlertError(_) => "ALERT_ERROR",
+            DbError::AuditError(_) => "AUDIT_ERROR",
+            DbError::ComplianceError(_) => "COMPLIANCE_ERROR",
+            DbError::PerformanceError(_) => "PERFORMANCE_ERROR",
+            DbError::CapacityError(_) => "CAPACITY_ERROR",
+            DbError::AvailabilityError(_) => "AVAILABILITY_ERROR",
+            DbError::DurabilityError(_) => "DURABILITY_ERROR",
+            DbError::IntegrityError(_) => "INTEGRITY_ERROR",
+            DbError::ConfidentialityError(_) => "CONFIDENTIALITY_ERROR",
+            DbError::PrivacyError(_) => "PRIVACY_ERROR",
+            DbError::GovernanceError(_) => "GOVERNANCE_ERROR",
+            DbError::PolicyError(_) => "POLICY_ERROR",
+            DbError::ComplianceViolationError(_) => "COMPLIANCE_VIOLATION_ERROR",
+            DbError::AuditViolationError(_) => "AUDIT_VIOLATION_ERROR",
+            DbError::SecurityViolationError(_) => "SECURITY_VIOLATION_ERROR",
+            DbError::DataProtectionError(_) => "DATA_PROTECTION_ERROR",
+            DbError::DataRetentionError(_) => "DATA_RETENTION_ERROR",
+            DbError::DataDisposalError(_) => "DATA_DISPOSAL_ERROR",
+            DbError::DataClassificationError(_) => "DATA_CLASSIFICATION_ERROR",
+            DbError::DataQualityError(_) => "DATA_QUALITY_ERROR",
+            DbError::DataLineageError(_) => "DATA_LINEAGE_ERROR",
+            DbError::DataGovernanceError(_) => "DATA_GOVERNANCE_ERROR",
+            DbError::DataPrivacyError(_) => "DATA_PRIVACY_ERROR",
+            DbError::DataSecurityError(_) => "DATA_SECURITY_ERROR",
+            DbError::DataComplianceError(_) => "DATA_COMPLIANCE_ERROR",
+            DbError::DataAuditError(_) => "DATA_AUDIT_ERROR",
+            DbError::DataMonitoringError(_) => "DATA_MONITORING_ERROR",
+            DbError::DataAlertError(_) => "DATA_ALERT_ERROR",
+            DbError::DataMetricsError(_) => "DATA_METRICS_ERROR",
+            DbError::DataPerformanceError(_) => "DATA_PERFORMANCE_ERROR",
+            DbError::DataCapacityError(_) => "DATA_CAPACITY_ERROR",
+            DbError::DataAvailabilityError(_) => "DATA_AVAILABILITY_ERROR",
+            DbError::DataDurabilityError(_) => "DATA_DURABILITY_ERROR",
+            DbError::DataIntegrityError(_) => "DATA_INTEGRITY_ERROR",
+            DbError::DataConfidentialityError(_) => "DATA_CONFIDENTIALITY_ERROR",
+            DbError::DataPrivacyViolationError(_) => "DATA_PRIVACY_VIOLATION_ERROR",
+            DbError::DataSecurityViolationError(_) => "DATA_SECURITY_VIOLATION_ERROR",
+            DbError::DataComplianceViolationError(_) => "DATA_COMPLIANCE_VIOLATION_ERROR",
+            DbError::DataAuditViolationError(_) => "DATA_AUDIT_VIOLATION_ERROR",
+            DbError::DataMonitoringViolationError(_) => "DATA_MONITORING_VIOLATION_ERROR",
+            DbError::DataAlertViolationError(_) => "DATA_ALERT_VIOLATION_ERROR",
+            DbError::DataMetricsViolationError(_) => "DATA_METRICS_VIOLATION_ERROR",
+            DbError::DataPerformanceViolationError(_) => "DATA_PERFORMANCE_VIOLATION_ERROR",
+            DbError::DataCapacityViolationError(_) => "DATA_CAPACITY_VIOLATION_ERROR",
+            DbError::DataAvailabilityViolationError(_) => "DATA_AVAILABILITY_VIOLATION_ERROR",
+            DbError::DataDurabilityViolationError(_) => "DATA_DURABILITY_VIOLATION_ERROR",
+            DbError::DataIntegrityViolationError(_) => "DATA_INTEGRITY_VIOLATION_ERROR",
+            DbError::DataConfidentialityViolationError(_) => "DATA_CONFIDENTIALITY_VIOLATION_ERROR",
+        }
+    }
+
+    /// `{"code": ..., "message": ...}`, with the message as `Display` writes it.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "code": self.code(), "message": self.to_string() })
+    }
+
+    /// Whether running the same statement again unchanged may succeed: after a timeout, an
+    /// unavailable resource or an interrupted read or write. A `ConcurrencyError` is not, since
+    /// it reports a lock poisoned for good.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DbError::TimeoutError(_) | DbError::AvailabilityError(_) | DbError::DataAvailabilityError(_) => true,
+            DbError::IoError(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl From<std::io::Error> for DbError {
     fn from(err: std::io::Error) -> DbError {
         error!("IO Error: {}", err);
@@ -227,7 +641,7 @@ impl fmt::Display for DbError {
         match self {
             DbError::IoError(e) => write!(f, "IO Error: {}", e),
             DbError::SerializationError(s) => write!(f, "Serialization Error: {}", s),
-            DbError::TypeMismatch => write!(f, "Type Mismatch"),
+            DbError::TypeMismatch(s) => write!(f, "Type Mismatch: {}", s),
             DbError::InvalidData(s) => write!(f, "Invalid Data: {}", s),
             DbError::TransactionError(s) => write!(f, "Transaction Error: {}", s),
             DbError::QueryError(s) => write!(f, "Query Error: {}", s),
@@ -246,6 +660,7 @@ impl fmt::Display for DbError {
             DbError::CacheError(s) => write!(f, "Cache Error: {}", s),
             DbError::NetworkError(s) => write!(f, "Network Error: {}", s),
             DbError::TimeoutError(s) => write!(f, "Timeout Error: {}", s),
+            DbError::Cancelled(s) => write!(f, "Cancelled: {}", s),
             DbError::BackupError(s) => write!(f, "Backup Error: {}", s),
             DbError::RecoveryError(s) => write!(f, "Recovery Error: {}", s),
             DbError::ReplicationError(s) => write!(f, "Replication Error: {}", s),
@@ -317,6 +732,9 @@ pub struct User {
 pub struct SecurityContext {
     pub current_user: Option<User>,
     pub permissions: HashMap<String, Vec<String>>,
+    // Lets a session that has not logged in run read-only queries
+    #[serde(default)]
+    pub allow_anonymous_reads: bool,
 }
 
 impl SecurityContext {
@@ -324,13 +742,41 @@ impl SecurityContext {
         SecurityContext {
             current_user: None,
             permissions: HashMap::new(),
+            allow_anonymous_reads: false,
+        }
+    }
+
+    /// Checks that the current user may run `query`: a bare permission such as "select" covers
+    /// every table, "select:users" only that one. GRANT, REVOKE and database-wide SETs need the admin role. Without
+    /// a logged-in user only read-only queries are allowed, and only when `allow_anonymous_reads` is set.
+    pub fn authorize(&self, query: &Query) -> Result<(), DbError> {
+        let operation = match query.operation() {
+            Some(operation) => operation,
+            None => return Ok(()),
+        };
+        let tables = query.tables();
+        let allowed = if operation == "grant" || operation == "configure" {
+            self.current_user.as_ref().is_some_and(|user| user.roles.iter().any(|role| role == ADMIN_ROLE))
+        } else {
+            self.has_permission(operation)
+                || (!tables.is_empty()
+                    && tables.iter().all(|table| self.has_permission(&format!("{}:{}", operation, table))))
+        };
+        match &self.current_user {
+            Some(_) if allowed => Ok(()),
+            Some(user) => Err(DbError::AuthorizationError(format!(
+                "User {} is not allowed to {}",
+                user.username, operation
+            ))),
+            None if self.allow_anonymous_reads && query.is_read_only() => Ok(()),
+            None => Err(DbError::AuthorizationError(format!("Log in to {}", operation))),
         }
     }
 
     pub fn has_permission(&self, operation: &str) -> bool {
         if let Some(user) = &self.current_user {
             user.roles.iter().any(|role| {
-                self.permissions
+                role == ADMIN_ROLE || self.permissions
                     .get(role)
                     .map(|perms| perms.contains(&operation.to_string()))
                     .unwrap_or(false)
@@ -341,8 +787,11 @@ impl SecurityContext {
     }
 }
 
+/// Makes user-supplied text safe to show inside a SQL snippet in messages and logs.
+///
+/// This is display-only: it drops `;` and comment markers, so it changes the text and must
+/// never be applied to values that get stored. Bind those with `Database::execute_params`.
 pub fn sanitize_sql(input: &str) -> String {
-    // Basic SQL injection prevention
     input.replace("'", "''")
          .replace(";", "")
          .replace("--", "")
@@ -350,22 +799,65 @@ pub fn sanitize_sql(input: &str) -> String {
          .replace("*/", "")
 }
 
-pub fn validate_table_name(name: &str) -> Result<(), DbError> {
-    if name.is_empty() {
-        return Err(DbError::ValidationError("Table name cannot be empty".to_string()));
-    }
-    if !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
-        return Err(DbError::ValidationError("Table name contains invalid characters".to_string()));
+// Words the parser reads as part of a statement, so an unquoted name cannot be one of them
+const RESERVED_WORDS: &[&str] = &[
+    "ALTER", "AND", "AS", "BY", "CHECK", "CREATE", "DEFAULT", "DELETE", "DROP", "FROM", "GROUP", "INSERT", "INTO", "IS",
+    "JOIN", "KEY", "NOT", "NULL", "ON", "OR", "ORDER", "PRIMARY", "REFERENCES", "SELECT", "SET", "TABLE", "TO",
+    "UNIQUE", "VALUES", "WHERE",
+];
+
+/// Whether `name` can be written in a statement without double quotes: letters, digits and
+/// underscores only, and not a reserved word.
+pub fn is_plain_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !RESERVED_WORDS.iter().any(|word| word.eq_ignore_ascii_case(name))
+}
+
+/// `name` as a statement has to write it: double-quoted unless it is a plain identifier.
+pub fn quote_identifier(name: &str) -> String {
+    if is_plain_identifier(name) {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name)
     }
-    Ok(())
 }
 
-pub fn validate_column_name(name: &str) -> Result<(), DbError> {
-    if name.is_empty() {
-        return Err(DbError::ValidationError("Column name cannot be empty".to_string()));
+// Checks a name as written in a statement. A double-quoted name may be a reserved word and hold
+// spaces or punctuation, but not control characters, double quotes or path separators, since
+// names end up in file names.
+fn validate_identifier(kind: &str, name: &str) -> Result<(), DbError> {
+    let quoted = name.len() >= 2 && name.starts_with('"') && name.ends_with('"');
+    let unquoted = if quoted { &name[1..name.len() - 1] } else { name };
+    if unquoted.is_empty() {
+        return Err(DbError::ValidationError(format!("{} name cannot be empty", kind)));
     }
-    if !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
-        return Err(DbError::ValidationError("Column name contains invalid characters".to_string()));
+    if quoted {
+        if unquoted.chars().any(|c| c.is_control() || matches!(c, '"' | '/' | '\\')) {
+            return Err(DbError::ValidationError(format!("{} name contains invalid characters", kind)));
+        }
+    } else if !unquoted.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(DbError::ValidationError(format!("{} name contains invalid characters", kind)));
+    } else if !is_plain_identifier(unquoted) {
+        return Err(DbError::ValidationError(format!(
+            "{} name {} is a reserved word; write it as \"{}\"",
+            kind, unquoted, unquoted
+        )));
     }
     Ok(())
+}
+
+/// Checks a table name as written in a statement, quotes included.
+pub fn validate_table_name(name: &str) -> Result<(), DbError> {
+    validate_identifier("Table", name)
+}
+
+/// Checks a column name as written in a statement, quotes included.
+pub fn validate_column_name(name: &str) -> Result<(), DbError> {
+    validate_identifier("Column", name)
+}
+
+/// Checks an index name as written in a statement, quotes included.
+pub fn validate_index_name(name: &str) -> Result<(), DbError> {
+    validate_identifier("Index", name)
 }
\ No newline at end of file